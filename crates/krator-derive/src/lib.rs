@@ -1,7 +1,20 @@
-//! A crate for deriving state machine traits in Kubelet. Right now this crate only consists of a
-//! derive macro for the `TransitionTo` trait. In addition to the `derive` attribute, this macro
-//! also requires the use of a custom attribute called `transition_to` that specifies the types that
-//! can be transitioned to. Not specifying this attribute will result in a compile time error.
+//! A crate for deriving state machine traits in Kubelet. This crate consists of a derive macro for
+//! the `TransitionTo` trait, and one for the `Transitions` trait used to draw state machines. In
+//! addition to the `derive` attribute, the `TransitionTo` macro also requires the use of a custom
+//! attribute called `transition_to` that specifies the types that can be transitioned to. Not
+//! specifying this attribute will result in a compile time error. The `Transitions` macro reads the
+//! same attribute, which states that end the state machine leave out.
+//!
+//! The `state_machine` attribute checks that every state of a module can be reached from the
+//! entry states it is given, following the `TransitionTo` impls and `transition_to` attributes of
+//! the module, and fails to compile if a state can't be, such as a debug state that was left in:
+//!
+//! ```ignore
+//! #[krator::state_machine(entry = Tagged, entry = Released)]
+//! mod states {
+//!     // `impl State<MooseState> for ...` and `TransitionTo` for each state
+//! }
+//! ```
 
 extern crate proc_macro;
 
@@ -11,9 +24,11 @@ use syn::{
     parse::{Parse, ParseStream},
     parse_macro_input,
     token::Comma,
-    Attribute, DeriveInput, Error, Generics, Ident, Path, Result,
+    Attribute, DeriveInput, Error, Generics, Ident, ItemMod, Path, Result,
 };
 
+mod state_machine;
+
 const ATTRIBUTE_NAME: &str = "transition_to";
 
 struct Transitions {
@@ -94,3 +109,74 @@ fn generate_impl(transitions: Transitions, generics: Generics, name: Ident) -> T
 
     token_stream
 }
+
+/// Derives `krator::diagram::Transitions` from the `transition_to` attribute,
+/// to draw the state machine of the state. States without the attribute end
+/// the state machine.
+#[proc_macro_derive(Transitions, attributes(transition_to))]
+pub fn derive_transitions(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let name = input.ident;
+    let generics = input.generics;
+
+    let mut transitions = get_transitions(input.attrs);
+    if transitions.len() > 1 {
+        let message = format!(
+            "Multiple `{}` attributes found for `{}`. Please specify only one attribute",
+            ATTRIBUTE_NAME, name
+        );
+        return TokenStream::from(Error::new(name.span(), message).to_compile_error());
+    }
+    let targets = transitions
+        .pop()
+        .map(|transitions| transitions.all)
+        .unwrap_or_default();
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let expanded = quote! {
+        #[automatically_derived]
+        impl#impl_generics krator::diagram::Transitions for #name#ty_generics #where_clause {
+            fn transitions() -> Vec<krator::diagram::StateNode> {
+                vec![#(krator::diagram::StateNode::of::<#targets>()),*]
+            }
+        }
+    };
+    TokenStream::from(expanded)
+}
+
+/// Fails to compile if a state of the module can't be reached from any of the
+/// entry states given with `entry = <state>`. This must be applied to an
+/// inline module holding the states.
+#[proc_macro_attribute]
+pub fn state_machine(args: TokenStream, input: TokenStream) -> TokenStream {
+    let entries = parse_macro_input!(args as state_machine::Entries);
+    let module = parse_macro_input!(input as ItemMod);
+
+    if module.content.is_none() {
+        let message = "`state_machine` must be applied to an inline module holding the states";
+        return TokenStream::from(Error::new_spanned(&module, message).to_compile_error());
+    }
+
+    let errors = state_machine::unreachable_states(&module, &entries.all)
+        .into_iter()
+        .map(|state| {
+            let message = format!(
+                "State `{}` can't be reached from the entry state{} {}. Transition to it from another state, or remove it",
+                state,
+                if entries.all.len() > 1 { "s" } else { "" },
+                entries
+                    .all
+                    .iter()
+                    .map(|entry| format!("`{}`", entry))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            Error::new(state.span(), message).to_compile_error()
+        });
+    let expanded = quote! {
+        #module
+        #(#errors)*
+    };
+    TokenStream::from(expanded)
+}
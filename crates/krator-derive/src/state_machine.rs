@@ -0,0 +1,210 @@
+//! Finding the states of a module that its state machine never reaches.
+//!
+//! States are the types the module implements `State` for, and transitions
+//! are the module's `TransitionTo` impls and `transition_to` attributes.
+//! States are told apart by the last segment of their path, without generic
+//! parameters, as a macro can't resolve types. Transitions made with
+//! `Transition::next_unchecked` aren't seen, so states only reached that way
+//! must be listed as entry states too.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use syn::{
+    parse::{Parse, ParseStream},
+    GenericArgument, Ident, Item, ItemMod, Path, PathArguments, Result, Token, Type,
+};
+
+use crate::get_transitions;
+
+const ENTRY: &str = "entry";
+
+/// The entry states given to the `state_machine` attribute, such as
+/// `entry = Registered, entry = Terminated`
+pub(crate) struct Entries {
+    pub(crate) all: Vec<Ident>,
+}
+
+impl Parse for Entries {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut all = vec![];
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            if key != ENTRY {
+                return Err(syn::Error::new(
+                    key.span(),
+                    format!("Expected `{} = <state>`", ENTRY),
+                ));
+            }
+            input.parse::<Token![=]>()?;
+            let path: Path = input.parse()?;
+            all.push(last_ident(&path)?);
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        if all.is_empty() {
+            return Err(input.error(format!(
+                "Please specify the entry state with `{} = <state>`",
+                ENTRY
+            )));
+        }
+        Ok(Entries { all })
+    }
+}
+
+/// The states of the module that can't be reached from any of the entry
+/// states, in the order they are implemented
+pub(crate) fn unreachable_states(module: &ItemMod, entries: &[Ident]) -> Vec<Ident> {
+    let items: &[Item] = match &module.content {
+        Some((_, items)) => items,
+        None => return vec![],
+    };
+
+    let mut states: Vec<Ident> = vec![];
+    let mut transitions: HashMap<Ident, Vec<Ident>> = HashMap::new();
+    for item in items {
+        match item {
+            Item::Impl(item) => {
+                let (trait_name, trait_args) = match &item.trait_ {
+                    Some((_, path, _)) => match path.segments.last() {
+                        Some(segment) => (&segment.ident, &segment.arguments),
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                let state = match type_ident(&item.self_ty) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                if trait_name == "State" {
+                    if !states.contains(&state) {
+                        states.push(state);
+                    }
+                } else if trait_name == "TransitionTo" {
+                    if let Some(target) = first_type_argument(trait_args).and_then(type_ident) {
+                        transitions.entry(state).or_default().push(target);
+                    }
+                }
+            }
+            Item::Struct(item) => {
+                add_attribute_transitions(&mut transitions, &item.ident, &item.attrs)
+            }
+            Item::Enum(item) => {
+                add_attribute_transitions(&mut transitions, &item.ident, &item.attrs)
+            }
+            _ => (),
+        }
+    }
+
+    let mut reached: HashSet<Ident> = entries.iter().cloned().collect();
+    let mut unvisited: VecDeque<Ident> = entries.iter().cloned().collect();
+    while let Some(state) = unvisited.pop_front() {
+        for target in transitions.get(&state).into_iter().flatten() {
+            if reached.insert(target.clone()) {
+                unvisited.push_back(target.clone());
+            }
+        }
+    }
+
+    states
+        .into_iter()
+        .filter(|state| !reached.contains(state))
+        .collect()
+}
+
+fn add_attribute_transitions(
+    transitions: &mut HashMap<Ident, Vec<Ident>>,
+    state: &Ident,
+    attrs: &[syn::Attribute],
+) {
+    let targets = get_transitions(attrs.to_vec())
+        .into_iter()
+        .flat_map(|transitions| transitions.all)
+        .filter_map(|path| last_ident(&path).ok());
+    transitions
+        .entry(state.clone())
+        .or_default()
+        .extend(targets);
+}
+
+fn last_ident(path: &Path) -> Result<Ident> {
+    path.segments
+        .last()
+        .map(|segment| segment.ident.clone())
+        .ok_or_else(|| syn::Error::new_spanned(path, "Expected the path of a state"))
+}
+
+fn type_ident(ty: &Type) -> Option<Ident> {
+    match ty {
+        Type::Path(ty) => last_ident(&ty.path).ok(),
+        _ => None,
+    }
+}
+
+fn first_type_argument(arguments: &PathArguments) -> Option<&Type> {
+    match arguments {
+        PathArguments::AngleBracketed(arguments) => {
+            arguments.args.iter().find_map(|argument| match argument {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use syn::parse_quote;
+
+    fn unreachable(module: ItemMod, entries: Entries) -> Vec<String> {
+        unreachable_states(&module, &entries.all)
+            .iter()
+            .map(|state| state.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn states_are_reached_through_impls_and_attributes() {
+        let module: ItemMod = parse_quote! {
+            mod states {
+                struct Tagged;
+                impl State<MooseState> for Tagged {}
+                impl TransitionTo<Roam> for Tagged {}
+
+                #[derive(TransitionTo)]
+                #[transition_to(Eat)]
+                struct Roam;
+                impl State<MooseState> for Roam {}
+
+                #[derive(TransitionTo)]
+                #[transition_to(super::states::Roam)]
+                struct Eat;
+                impl krator::State<MooseState> for Eat {}
+
+                struct Released;
+                impl State<MooseState> for Released {}
+
+                struct Debugging<P>(P);
+                impl<P> State<MooseState> for Debugging<P> {}
+                impl<P> TransitionTo<Roam> for Debugging<P> {}
+            }
+        };
+        assert_eq!(
+            unreachable(module.clone(), parse_quote!(entry = Tagged)),
+            vec!["Released", "Debugging"]
+        );
+        assert_eq!(
+            unreachable(module, parse_quote!(entry = Tagged, entry = Released)),
+            vec!["Debugging"]
+        );
+    }
+
+    #[test]
+    fn entry_states_are_required() {
+        assert!(syn::parse_str::<Entries>("").is_err());
+        assert!(syn::parse_str::<Entries>("start = Tagged").is_err());
+        assert!(syn::parse_str::<Entries>("entry = Tagged,").is_ok());
+    }
+}
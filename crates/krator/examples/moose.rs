@@ -1,6 +1,7 @@
 use k8s_openapi::Metadata;
 use krator::{
-    Manifest, ObjectState, ObjectStatus, Operator, OperatorRuntime, State, Transition, TransitionTo,
+    state_machine_to_mermaid, Manifest, ObjectState, ObjectStatus, Operator, OperatorRuntime,
+    State, Transition, TransitionTo, Transitions,
 };
 use kube::api::ListParams;
 use kube_derive::CustomResource;
@@ -81,7 +82,10 @@ impl ObjectState for MooseState {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Transitions)]
+// Transitions are listed to draw the state machine, even when TransitionTo is
+// implemented explicitly.
+#[transition_to(Roam)]
 /// Moose was tagged for tracking.
 struct Tagged;
 
@@ -118,7 +122,7 @@ impl State<MooseState> for Tagged {
 impl TransitionTo<Roam> for Tagged {}
 
 // Derive TransitionTo
-#[derive(Debug, Default, TransitionTo)]
+#[derive(Debug, Default, TransitionTo, Transitions)]
 // Specify valid next states.
 #[transition_to(Eat)]
 /// Moose is roaming the wilderness.
@@ -183,7 +187,7 @@ impl State<MooseState> for Roam {
     }
 }
 
-#[derive(Debug, Default, TransitionTo)]
+#[derive(Debug, Default, TransitionTo, Transitions)]
 #[transition_to(Sleep)]
 /// Moose is eating.
 struct Eat;
@@ -214,7 +218,7 @@ impl State<MooseState> for Eat {
     }
 }
 
-#[derive(Debug, Default, TransitionTo)]
+#[derive(Debug, Default, TransitionTo, Transitions)]
 #[transition_to(Roam)]
 /// Moose is sleeping.
 struct Sleep;
@@ -243,7 +247,7 @@ impl State<MooseState> for Sleep {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Transitions)]
 /// Moose was released from our care.
 struct Released;
 
@@ -341,6 +345,10 @@ async fn main() -> anyhow::Result<()> {
     let tracker = MooseTracker::new();
 
     info!("crd:\n{}", serde_yaml::to_string(&Moose::crd()).unwrap());
+    info!(
+        "state machine:\n{}",
+        state_machine_to_mermaid::<Tagged, MooseState>()
+    );
 
     // Only track mooses in Glacier NP
     let params = ListParams::default().labels("nps.gov/park=glacier");
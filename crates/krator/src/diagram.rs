@@ -0,0 +1,217 @@
+//! Drawing state machines as Mermaid state diagrams, which GitHub renders in
+//! Markdown.
+//!
+//! The transitions between states are checked with `TransitionTo`, which
+//! can't be listed once the program is compiled, so states list the states
+//! they can transition to by implementing [`Transitions`] too. With the
+//! `derive` feature, `#[derive(Transitions)]` implements it from the same
+//! `transition_to` attribute that `#[derive(TransitionTo)]` reads, or with
+//! no transitions for states without the attribute, which end the state
+//! machine. The diagram is then drawn by following the transitions from the
+//! initial state:
+//!
+//! ```
+//! use krator::diagram::{StateNode, Transitions};
+//!
+//! struct Tagged;
+//! struct Roam;
+//!
+//! impl Transitions for Tagged {
+//!     fn transitions() -> Vec<StateNode> {
+//!         vec![StateNode::of::<Roam>()]
+//!     }
+//! }
+//!
+//! impl Transitions for Roam {
+//!     fn transitions() -> Vec<StateNode> {
+//!         vec![]
+//!     }
+//! }
+//!
+//! assert_eq!(
+//!     krator::diagram::mermaid::<Tagged>(),
+//!     "stateDiagram-v2\n    [*] --> Tagged\n    Tagged --> Roam\n    Roam --> [*]\n"
+//! );
+//! ```
+//!
+//! The diagram can be pasted into crate docs or a README in a `mermaid`
+//! code block.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+use crate::state::{short_type_name, ResourceState, State};
+
+/// Lists the states a state can transition to, to draw its state machine.
+pub trait Transitions {
+    /// The states this state can transition to. States that end the state
+    /// machine return none.
+    fn transitions() -> Vec<StateNode>;
+}
+
+/// A state in the diagram of a state machine, and how to find the states it
+/// can transition to.
+#[derive(Clone, Copy)]
+pub struct StateNode {
+    name: &'static str,
+    transitions: fn() -> Vec<StateNode>,
+}
+
+impl StateNode {
+    /// The node of the state `T`, named like [`State::name`] names it.
+    pub fn of<T: Transitions + ?Sized>() -> Self {
+        StateNode {
+            name: short_type_name(std::any::type_name::<T>()),
+            transitions: T::transitions,
+        }
+    }
+
+    /// The name of the state.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The states the state can transition to.
+    pub fn transitions(&self) -> Vec<StateNode> {
+        (self.transitions)()
+    }
+}
+
+/// Draw the state machine starting in state `S` as a Mermaid
+/// `stateDiagram-v2`, following the transitions of each state.
+pub fn state_machine_to_mermaid<S, PS>() -> String
+where
+    S: State<PS> + Transitions,
+    PS: ResourceState,
+{
+    mermaid::<S>()
+}
+
+/// Draw the state machine starting in state `S` as a Mermaid
+/// `stateDiagram-v2`. This is [`state_machine_to_mermaid`] for states that
+/// don't implement `State` themselves.
+pub fn mermaid<S: Transitions + ?Sized>() -> String {
+    let initial = StateNode::of::<S>();
+    let mut diagram = String::from("stateDiagram-v2\n");
+    // Writing to a string doesn't fail
+    let _ = writeln!(diagram, "    [*] --> {}", initial.name);
+
+    let mut seen = HashSet::new();
+    seen.insert(initial.name);
+    let mut unvisited = vec![initial];
+    while !unvisited.is_empty() {
+        let node = unvisited.remove(0);
+        let transitions = (node.transitions)();
+        if transitions.is_empty() {
+            let _ = writeln!(diagram, "    {} --> [*]", node.name);
+        }
+        for next in transitions {
+            let _ = writeln!(diagram, "    {} --> {}", node.name, next.name);
+            if seen.insert(next.name) {
+                unvisited.push(next);
+            }
+        }
+    }
+    diagram
+}
+
+/// The graph of the state machine starting in state `S`: the name of each
+/// state it can reach, and the names of the states that state can
+/// transition to.
+pub fn graph<S: Transitions + ?Sized>() -> BTreeMap<&'static str, Vec<&'static str>> {
+    let mut graph = BTreeMap::new();
+    let mut unvisited = vec![StateNode::of::<S>()];
+    while let Some(node) = unvisited.pop() {
+        if graph.contains_key(node.name) {
+            continue;
+        }
+        let transitions = (node.transitions)();
+        graph.insert(
+            node.name,
+            transitions.iter().map(|next| next.name).collect(),
+        );
+        unvisited.extend(transitions);
+    }
+    graph
+}
+
+#[cfg(test)]
+// The states are only named, and never constructed
+#[allow(dead_code)]
+mod test {
+    use super::*;
+
+    struct Registered;
+    struct ImagePull<P>(std::marker::PhantomData<P>);
+    struct Running;
+    struct Error;
+    struct Completed;
+    struct Wasi;
+
+    impl Transitions for Registered {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<ImagePull<Wasi>>(), StateNode::of::<Error>()]
+        }
+    }
+
+    impl<P> Transitions for ImagePull<P> {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Running>(), StateNode::of::<Error>()]
+        }
+    }
+
+    impl Transitions for Running {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Completed>(), StateNode::of::<Error>()]
+        }
+    }
+
+    impl Transitions for Error {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Registered>()]
+        }
+    }
+
+    impl Transitions for Completed {
+        fn transitions() -> Vec<StateNode> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn state_machines_are_drawn_from_their_initial_state() {
+        assert_eq!(
+            mermaid::<Registered>(),
+            "stateDiagram-v2
+    [*] --> Registered
+    Registered --> ImagePull
+    Registered --> Error
+    ImagePull --> Running
+    ImagePull --> Error
+    Error --> Registered
+    Running --> Completed
+    Running --> Error
+    Completed --> [*]
+"
+        );
+    }
+
+    #[test]
+    fn states_are_drawn_once_however_they_are_reached() {
+        let diagram = mermaid::<Error>();
+        assert_eq!(diagram.matches("Running --> Completed").count(), 1);
+        assert_eq!(diagram.matches("Completed --> [*]").count(), 1);
+        assert!(diagram.starts_with("stateDiagram-v2\n    [*] --> Error\n"));
+    }
+
+    #[test]
+    fn graphs_list_the_transitions_of_each_reachable_state() {
+        let graph = graph::<Running>();
+        assert_eq!(
+            graph.keys().copied().collect::<Vec<_>>(),
+            vec!["Completed", "Error", "ImagePull", "Registered", "Running"]
+        );
+        assert_eq!(graph["Registered"], vec!["ImagePull", "Error"]);
+        assert!(graph["Completed"].is_empty());
+    }
+}
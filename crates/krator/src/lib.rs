@@ -7,15 +7,20 @@ mod object;
 mod operator;
 mod runtime;
 
+pub mod snapshot;
+
 #[cfg(feature = "admission-webhook")]
 pub mod admission;
 
+pub mod diagram;
 pub mod state;
 
+pub use diagram::state_machine_to_mermaid;
 pub use manifest::Manifest;
 pub use object::{ObjectState, ObjectStatus};
 pub use operator::Operator;
 pub use runtime::OperatorRuntime;
+pub use snapshot::StateSnapshot;
 pub use state::{SharedState, State, Transition, TransitionTo};
 
 #[cfg(feature = "derive")]
@@ -40,6 +40,20 @@ pub trait ObjectState: 'static + Sync + Send {
     type SharedState: 'static + Sync + Send;
     /// Clean up any resources when this object is deleted.
     async fn async_drop(self, shared: &mut Self::SharedState);
+
+    /// The fields of this object state to save in snapshots, so that they
+    /// can be restored when the object's state machine is resumed after the
+    /// operator restarts. Defaults to none.
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restore the fields saved by `snapshot` when resuming the object's
+    /// state machine. This should leave the object state unchanged if it
+    /// returns an error, as the state machine is then started over.
+    fn restore(&mut self, _snapshot: serde_json::Value) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 /// Interface for types which represent the Kubernetes status of an object.
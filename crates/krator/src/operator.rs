@@ -45,7 +45,8 @@ pub trait Operator: 'static + Sync + Send {
     /// Create a reference to state shared between state machines.
     async fn shared_state(&self) -> SharedState<<Self::ObjectState as ObjectState>::SharedState>;
 
-    /// Called before the state machine is run.
+    /// Called before the state machine is run. This isn't called again when
+    /// the state machine is resumed from a snapshot.
     async fn registration_hook(
         &self,
         mut _manifest: Manifest<Self::Manifest>,
@@ -53,6 +54,18 @@ pub trait Operator: 'static + Sync + Send {
         Ok(())
     }
 
+    /// Get the state named `name` in a snapshot, to resume an object's state
+    /// machine in when the operator restarts. Returning `None` starts the
+    /// state machine over from the initial state, which is the default.
+    fn resume_state(&self, _name: &str) -> Option<Box<dyn State<Self::ObjectState>>> {
+        None
+    }
+
+    /// Called each time an object's state machine enters a state, including
+    /// the state it is resumed in, with the name of the state. This must not
+    /// block, as the state machine waits for it.
+    fn state_entered(&self, _manifest: &Manifest<Self::Manifest>, _state: &'static str) {}
+
     #[cfg(feature = "admission-webhook")]
     /// Invoked when object is created or modified. Can mutate the and / or deny the request.
     async fn admission_hook(
@@ -1,10 +1,13 @@
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use futures::stream::BoxStream;
 use futures::{StreamExt, TryStreamExt};
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Notify;
+use tokio::sync::{watch, Notify};
 use tracing::{debug, error, info, warn};
 
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
@@ -20,24 +23,33 @@ use crate::manifest::Manifest;
 use crate::object::ObjectKey;
 use crate::object::ObjectState;
 use crate::operator::Operator;
-use crate::state::{run_to_completion, SharedState};
+use crate::snapshot::SnapshotStore;
+use crate::state::{run_states, SharedState, State};
+
+/// How often the main loop sends a heartbeat while it has no events to
+/// handle
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Accepts a type implementing the `Operator` trait and watches
 /// for resources of the associated `Manifest` type, running the
 /// associated state machine for each. Optionally filter by
 /// `kube::api::ListParams`.
 pub struct OperatorRuntime<O: Operator> {
-    client: Client,
+    client: watch::Receiver<Client>,
     handlers: HashMap<ObjectKey, Sender<Event<O::Manifest>>>,
     operator: Arc<O>,
     list_params: ListParams,
     signal: Option<Arc<AtomicBool>>,
+    snapshots: Option<SnapshotStore>,
+    heartbeats: Option<watch::Sender<Instant>>,
 }
 
 impl<O: Operator> OperatorRuntime<O> {
     /// Create new runtime with optional ListParams.
     pub fn new(kubeconfig: &kube::Config, operator: O, params: Option<ListParams>) -> Self {
-        let client = Client::new(kubeconfig.clone());
+        // The sender is dropped, so the client is never replaced unless
+        // `with_client_updates` is used
+        let (_, client) = watch::channel(Client::new(kubeconfig.clone()));
         let list_params = params.unwrap_or_default();
         OperatorRuntime {
             client,
@@ -45,9 +57,36 @@ impl<O: Operator> OperatorRuntime<O> {
             operator: Arc::new(operator),
             list_params,
             signal: None,
+            snapshots: None,
+            heartbeats: None,
         }
     }
 
+    /// Save the states objects are in to `dir`, and resume their state
+    /// machines in them when the runtime restarts. See
+    /// [`crate::snapshot`].
+    pub fn with_snapshots(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.snapshots = Some(SnapshotStore::new(dir));
+        self
+    }
+
+    /// Use the latest client sent on `clients` rather than one built from
+    /// the kubeconfig, such as when the client certificate is rotated. The
+    /// watch on objects is restarted with each new client, and object tasks
+    /// patch statuses and delete objects with the latest client.
+    pub fn with_client_updates(mut self, clients: watch::Receiver<Client>) -> Self {
+        self.client = clients;
+        self
+    }
+
+    /// Send the time on `heartbeats` each time the main loop handles an
+    /// event, and every ten seconds while it waits for one, so that it can
+    /// be told apart from a loop that is stuck handling an event.
+    pub fn with_heartbeats(mut self, heartbeats: watch::Sender<Instant>) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
     /// Dispatch event to the matching resource's task.
     /// If no task is found, `self.start_object` is called to start a task for
     /// the new object.
@@ -123,13 +162,14 @@ impl<O: Operator> OperatorRuntime<O> {
 
         let deleted = Arc::new(Notify::new());
 
-        let (manifest, object_state) = match initial_event {
+        let (manifest, mut object_state) = match initial_event {
             Event::Applied(manifest) => {
                 let object_state = self.operator.initialize_object_state(&manifest).await?;
                 (manifest, object_state)
             }
             _ => return Err(anyhow::anyhow!("Got non-apply event when starting pod")),
         };
+        let resumed_state = self.resume_state(&manifest, &mut object_state).await;
 
         let (manifest_tx, manifest_rx) = Manifest::new(manifest);
         let reflector_deleted = Arc::clone(&deleted);
@@ -186,11 +226,16 @@ impl<O: Operator> OperatorRuntime<O> {
             }
         });
 
+        let task = ObjectTask {
+            manifest: manifest_rx,
+            object_state,
+            resumed_state,
+            snapshots: self.snapshots.clone(),
+        };
         tokio::spawn(run_object_task::<O>(
             self.client.clone(),
-            manifest_rx,
+            task,
             self.operator.shared_state().await,
-            object_state,
             deleted,
             Arc::clone(&self.operator),
         ));
@@ -198,6 +243,60 @@ impl<O: Operator> OperatorRuntime<O> {
         Ok(sender)
     }
 
+    /// Get the state to resume an object's state machine in from its
+    /// snapshot, restoring its object state, if it has a snapshot that the
+    /// operator can resume.
+    async fn resume_state(
+        &self,
+        manifest: &O::Manifest,
+        object_state: &mut O::ObjectState,
+    ) -> Option<Box<dyn State<O::ObjectState>>> {
+        let snapshots = self.snapshots.as_ref()?;
+        let uid = manifest.metadata().uid.as_ref()?;
+        let snapshot = match snapshots.load(uid).await {
+            Ok(snapshot) => snapshot?,
+            Err(e) => {
+                warn!(
+                    "Unable to load snapshot of object {} in namespace {:?}: {:?}",
+                    manifest.name(),
+                    manifest.namespace(),
+                    e
+                );
+                return None;
+            }
+        };
+        let state = match self.operator.resume_state(&snapshot.state) {
+            Some(state) => state,
+            None => {
+                debug!(
+                    "Object {} in namespace {:?} can't be resumed in state {}, starting over.",
+                    manifest.name(),
+                    manifest.namespace(),
+                    snapshot.state
+                );
+                return None;
+            }
+        };
+        if let Some(fields) = snapshot.object_state {
+            if let Err(e) = object_state.restore(fields) {
+                warn!(
+                    "Unable to restore state of object {} in namespace {:?}, starting over: {:?}",
+                    manifest.name(),
+                    manifest.namespace(),
+                    e
+                );
+                return None;
+            }
+        }
+        info!(
+            "Resuming object {} in namespace {:?} in state {:?}.",
+            manifest.name(),
+            manifest.namespace(),
+            state
+        );
+        Some(state)
+    }
+
     /// Resyncs the queue given the list of objects. Objects that exist in
     /// the queue but no longer exist in the list will be deleted
     async fn resync(&mut self, objects: Vec<O::Manifest>) -> anyhow::Result<()> {
@@ -205,6 +304,15 @@ impl<O: Operator> OperatorRuntime<O> {
         // in our map, but not in the list)
         let current_objects: HashSet<ObjectKey> = objects.iter().map(|obj| obj.into()).collect();
         let objects_in_state: HashSet<ObjectKey> = self.handlers.keys().cloned().collect();
+        if let Some(ref snapshots) = self.snapshots {
+            let uids: HashSet<String> = objects
+                .iter()
+                .filter_map(|obj| obj.metadata().uid.clone())
+                .collect();
+            if let Err(e) = snapshots.retain(&uids).await {
+                warn!("Unable to remove snapshots of deleted objects: {:?}", e);
+            }
+        }
         for key in objects_in_state.difference(&current_objects) {
             let mut manifest: O::Manifest = Default::default();
             {
@@ -222,12 +330,44 @@ impl<O: Operator> OperatorRuntime<O> {
         Ok(())
     }
 
+    fn beat(&self) {
+        if let Some(heartbeats) = &self.heartbeats {
+            // No one is listening once every receiver was dropped
+            let _ = heartbeats.send(Instant::now());
+        }
+    }
+
+    /// Watches objects with the latest client.
+    fn watch(&self) -> BoxStream<'static, watcher::Result<Event<O::Manifest>>> {
+        let api = Api::<O::Manifest>::all(self.client.borrow().clone());
+        watcher(api, self.list_params.clone()).boxed()
+    }
+
     /// Listens for updates to objects and forwards them to queue.
     pub async fn main_loop(&mut self) {
-        let api = Api::<O::Manifest>::all(self.client.clone());
-        let mut informer = watcher(api, self.list_params.clone()).boxed();
+        let mut clients = self.client.clone();
+        let mut replaceable = true;
+        let mut informer = self.watch();
+        let mut heartbeats = tokio::time::interval(HEARTBEAT_INTERVAL);
         loop {
-            match informer.try_next().await {
+            self.beat();
+            let event = tokio::select! {
+                event = informer.try_next() => event,
+                _ = heartbeats.tick() => continue,
+                replaced = clients.changed(), if replaceable => {
+                    match replaced {
+                        Ok(()) => {
+                            // The new watch starts with a restart, which
+                            // resyncs any objects changed in between
+                            info!("Kubernetes client was replaced. Restarting watch...");
+                            informer = self.watch();
+                        }
+                        Err(_) => replaceable = false,
+                    }
+                    continue;
+                }
+            };
+            match event {
                 Ok(Some(event)) => {
                     if let Some(ref signal) = self.signal {
                         if matches!(event, kube_runtime::watcher::Event::Applied(_))
@@ -276,39 +416,60 @@ impl<O: Operator> OperatorRuntime<O> {
     }
 }
 
-async fn run_object_task<O: Operator>(
-    client: Client,
+/// What the task running the state machine of a single object starts with
+struct ObjectTask<O: Operator> {
     manifest: Manifest<O::Manifest>,
+    object_state: O::ObjectState,
+    /// The state to resume the state machine in, if the object's snapshot
+    /// could be resumed
+    resumed_state: Option<Box<dyn State<O::ObjectState>>>,
+    snapshots: Option<SnapshotStore>,
+}
+
+async fn run_object_task<O: Operator>(
+    client: watch::Receiver<Client>,
+    task: ObjectTask<O>,
     shared: SharedState<<O::ObjectState as ObjectState>::SharedState>,
-    mut object_state: O::ObjectState,
     deleted: Arc<Notify>,
     operator: Arc<O>,
 ) {
-    debug!("Running registration hook.");
-    let state: O::InitialState = Default::default();
-    let (namespace, name) = {
+    let ObjectTask {
+        manifest,
+        mut object_state,
+        resumed_state,
+        snapshots,
+    } = task;
+    let (namespace, name, uid) = {
         let m = manifest.latest();
-        match operator.registration_hook(manifest.clone()).await {
-            Ok(()) => debug!("Running hook complete."),
-            Err(e) => {
-                error!(
-                    "Operator registration hook for object {} in namespace {:?} failed: {:?}",
-                    m.name(),
-                    m.namespace(),
-                    e
-                );
-                return;
+        (m.namespace(), m.name(), m.metadata().uid.clone())
+    };
+    let state = match resumed_state {
+        // Resumed objects were registered before the operator restarted
+        Some(state) => state,
+        None => {
+            debug!("Running registration hook.");
+            match operator.registration_hook(manifest.clone()).await {
+                Ok(()) => debug!("Running hook complete."),
+                Err(e) => {
+                    error!(
+                        "Operator registration hook for object {} in namespace {:?} failed: {:?}",
+                        name, namespace, e
+                    );
+                    return;
+                }
             }
+            let state: O::InitialState = Default::default();
+            Box::new(state)
         }
-        (m.namespace(), m.name())
     };
 
+    let entered = |state: &'static str| operator.state_entered(&manifest, state);
     tokio::select! {
-        _ = run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()) => (),
+        _ = run_states(&client, state, shared.clone(), &mut object_state, manifest.clone(), snapshots.as_ref(), &entered) => (),
         _ = deleted.notified() => {
             let state: O::DeletedState = Default::default();
             debug!("Object {} in namespace {:?} terminated. Jumping to state {:?}.", name, &namespace, state);
-            run_to_completion(&client, state, shared.clone(), &mut object_state, manifest.clone()).await;
+            run_states(&client, Box::new(state), shared.clone(), &mut object_state, manifest.clone(), None, &entered).await;
         }
     }
 
@@ -330,6 +491,16 @@ async fn run_object_task<O: Operator>(
         ),
     }
 
+    if let (Some(snapshots), Some(uid)) = (snapshots, uid) {
+        if let Err(e) = snapshots.remove(&uid).await {
+            warn!(
+                "Unable to remove snapshot of object {} in namespace {:?}: {:?}",
+                name, namespace, e
+            );
+        }
+    }
+
+    let client = client.borrow().clone();
     let api_client: Api<O::Manifest> = match namespace {
         Some(ref namespace) => kube::Api::namespaced(client, namespace),
         None => kube::Api::all(client),
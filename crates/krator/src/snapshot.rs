@@ -0,0 +1,167 @@
+//! Saving the states objects are in, so that their state machines can be
+//! resumed where they left off when the operator restarts.
+//!
+//! When snapshots are enabled with [`crate::OperatorRuntime::with_snapshots`],
+//! the name of each resumable state an object enters is written to a JSON
+//! file named after the object's UID, along with the fields of its object
+//! state that [`crate::ObjectState::snapshot`] returns. When the operator
+//! restarts and sees the object again, [`crate::Operator::resume_state`] is
+//! asked for the state of that name, and the object's state machine is
+//! resumed in it rather than started over. Snapshots are removed once their
+//! objects are deregistered.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// The state an object's state machine was last checkpointed in
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateSnapshot {
+    /// The UID of the object, so that an object recreated with the same name
+    /// isn't resumed in the state of the one it replaced
+    pub uid: String,
+    /// The name of the last resumable state the object entered
+    pub state: String,
+    /// The fields of the object's state that can be saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_state: Option<serde_json::Value>,
+}
+
+/// A directory holding the snapshots of objects, one file per object
+#[derive(Clone, Debug)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    /// Create a store keeping snapshots in `dir`, which is created when the
+    /// first snapshot is saved.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SnapshotStore { dir: dir.into() }
+    }
+
+    fn path(&self, uid: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", uid))
+    }
+
+    /// Save the snapshot of an object, replacing any it had.
+    pub async fn save(&self, snapshot: &StateSnapshot) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.path(&snapshot.uid);
+        // Written next to the snapshot and renamed over it, so that a
+        // snapshot is never left half written
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(snapshot)?).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    /// Load the snapshot of the object with `uid`, if it has one.
+    pub async fn load(&self, uid: &str) -> anyhow::Result<Option<StateSnapshot>> {
+        let contents = match tokio::fs::read(self.path(uid)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot: StateSnapshot = serde_json::from_slice(&contents)?;
+        Ok(Some(snapshot).filter(|snapshot| snapshot.uid == uid))
+    }
+
+    /// Remove the snapshot of the object with `uid`, if it has one.
+    pub async fn remove(&self, uid: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.path(uid)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the snapshots of objects other than those with the given UIDs,
+    /// such as those deleted while the operator wasn't running.
+    pub async fn retain(&self, uids: &HashSet<String>) -> anyhow::Result<()> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let uid = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.split('.').next().unwrap_or_default(),
+                None => continue,
+            };
+            if !uids.contains(uid) {
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    warn!("Unable to remove stale snapshot {:?}: {:?}", path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store() -> SnapshotStore {
+        SnapshotStore::new(
+            std::env::temp_dir().join(format!("krator-snapshots-{}", rand::random::<u64>())),
+        )
+    }
+
+    fn snapshot(uid: &str) -> StateSnapshot {
+        StateSnapshot {
+            uid: uid.to_owned(),
+            state: "Completed".to_owned(),
+            object_state: Some(serde_json::json!({ "errors": 2 })),
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_saved_and_loaded_by_uid() {
+        let store = store();
+        assert_eq!(store.load("a").await.unwrap(), None);
+
+        store.save(&snapshot("a")).await.unwrap();
+        assert_eq!(store.load("a").await.unwrap(), Some(snapshot("a")));
+        assert_eq!(store.load("b").await.unwrap(), None);
+
+        store.remove("a").await.unwrap();
+        assert_eq!(store.load("a").await.unwrap(), None);
+        store.remove("a").await.unwrap();
+
+        tokio::fs::remove_dir_all(&store.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_of_objects_that_are_gone_are_removed() {
+        let store = store();
+        store.retain(&HashSet::new()).await.unwrap();
+
+        store.save(&snapshot("a")).await.unwrap();
+        store.save(&snapshot("b")).await.unwrap();
+        let keep: HashSet<String> = vec!["b".to_owned()].into_iter().collect();
+        store.retain(&keep).await.unwrap();
+        assert_eq!(store.load("a").await.unwrap(), None);
+        assert_eq!(store.load("b").await.unwrap(), Some(snapshot("b")));
+
+        tokio::fs::remove_dir_all(&store.dir).await.unwrap();
+    }
+
+    #[test]
+    fn object_state_is_left_out_when_there_is_none() {
+        let snapshot = StateSnapshot {
+            uid: "a".to_owned(),
+            state: "Running".to_owned(),
+            object_state: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&snapshot).unwrap(),
+            r#"{"uid":"a","state":"Running"}"#
+        );
+    }
+}
@@ -7,6 +7,7 @@ use serde::de::DeserializeOwned;
 use tracing::{debug, error, trace, warn};
 
 use crate::object::ObjectStatus;
+use crate::snapshot::{SnapshotStore, StateSnapshot};
 use crate::Manifest;
 // Re-export for compatibility.
 pub use crate::object::ObjectState as ResourceState;
@@ -73,6 +74,39 @@ pub trait State<S: ResourceState>: Sync + Send + 'static + std::fmt::Debug {
 
     /// Provider supplies JSON status patch to apply when entering this state.
     async fn status(&self, state: &mut S, manifest: &S::Manifest) -> anyhow::Result<S::Status>;
+
+    /// The name this state is saved under in snapshots, and resumed by with
+    /// `Operator::resume_state`. Defaults to the name of the state's type,
+    /// without its path or generic parameters.
+    fn name(&self) -> &'static str {
+        short_type_name(std::any::type_name::<Self>())
+    }
+
+    /// Whether an object's state machine can be resumed in this state after
+    /// the operator restarts. States that rely on what is only kept in
+    /// memory, such as running processes, should return `false`, and
+    /// objects in them are resumed at the last resumable state they entered
+    /// instead. Defaults to `true`.
+    fn is_resumable(&self) -> bool {
+        true
+    }
+
+    /// The `TypeId` of the state's type, so that the state a transition
+    /// leads to can be checked, such as in tests. This should not be
+    /// overridden.
+    fn state_type_id(&self) -> std::any::TypeId {
+        std::any::TypeId::of::<Self>()
+    }
+}
+
+/// The name of a type without its path or generic parameters, such as
+/// `Registered` for `kubelet::state::common::registered::Registered<P>`.
+pub(crate) fn short_type_name(type_name: &'static str) -> &'static str {
+    let without_generics = type_name.split('<').next().unwrap_or(type_name);
+    without_generics
+        .rsplit("::")
+        .next()
+        .unwrap_or(without_generics)
 }
 
 /// Iteratively evaluate state machine until it returns Complete.
@@ -86,31 +120,78 @@ pub async fn run_to_completion<S: ResourceState>(
     S::Manifest: Resource + Meta + DeserializeOwned,
     S::Status: ObjectStatus,
 {
-    let (name, namespace, api) = {
+    let (_, client) = tokio::sync::watch::channel(client.clone());
+    run_states(
+        &client,
+        Box::new(state),
+        shared,
+        object_state,
+        manifest,
+        None,
+        &|_| (),
+    )
+    .await
+}
+
+/// Evaluate the state machine from `state` until it returns Complete, saving
+/// a snapshot to `snapshots` each time it enters a resumable state and
+/// passing the name of each state it enters to `entered`. Statuses are
+/// patched with the latest client sent on `client`.
+pub(crate) async fn run_states<S: ResourceState>(
+    client: &tokio::sync::watch::Receiver<kube::Client>,
+    mut state: Box<dyn State<S>>,
+    shared: SharedState<S::SharedState>,
+    object_state: &mut S,
+    manifest: Manifest<S::Manifest>,
+    snapshots: Option<&SnapshotStore>,
+    entered: &(dyn Fn(&'static str) + Send + Sync),
+) where
+    S::Manifest: Resource + Meta + DeserializeOwned,
+    S::Status: ObjectStatus,
+{
+    let (name, namespace, uid) = {
         let initial_manifest = manifest.latest();
         let namespace = initial_manifest.namespace();
         let name = initial_manifest.name();
-
-        let api: Api<S::Manifest> = match namespace {
-            Some(ref namespace) => Api::namespaced(client.clone(), namespace),
-            None => Api::all(client.clone()),
-        };
-        (name, namespace, api)
+        let uid = initial_manifest.meta().uid.clone();
+        (name, namespace, uid)
+    };
+    let api = || -> Api<S::Manifest> {
+        let client = client.borrow().clone();
+        match namespace {
+            Some(ref namespace) => Api::namespaced(client, namespace),
+            None => Api::all(client),
+        }
     };
-
-    let mut state: Box<dyn State<S>> = Box::new(state);
 
     loop {
         debug!(
             "Object {} in namespace {:?} entering state {:?}",
             &name, &namespace, state
         );
+        entered(state.name());
+
+        if let (Some(snapshots), Some(uid)) = (snapshots, &uid) {
+            if state.is_resumable() {
+                let snapshot = StateSnapshot {
+                    uid: uid.clone(),
+                    state: state.name().to_owned(),
+                    object_state: object_state.snapshot(),
+                };
+                if let Err(e) = snapshots.save(&snapshot).await {
+                    warn!(
+                        "Object {} in namespace {:?} unable to save snapshot: {:?}",
+                        &name, &namespace, e
+                    );
+                }
+            }
+        }
 
         let latest_manifest = manifest.latest();
 
         match state.status(object_state, &latest_manifest).await {
             Ok(status) => {
-                patch_status(&api, &name, status).await;
+                patch_status(&api(), &name, status).await;
             }
             Err(e) => {
                 warn!(
@@ -157,7 +238,7 @@ pub async fn run_to_completion<S: ResourceState>(
                         &name, &namespace, e
                     );
                     let status = S::Status::failed(&format!("{:?}", e));
-                    patch_status(&api, &name, status).await;
+                    patch_status(&api(), &name, status).await;
                     break;
                 }
             },
@@ -187,3 +268,20 @@ pub async fn patch_status<R: Resource + Clone + DeserializeOwned, S: ObjectStatu
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn states_are_named_without_their_path_or_generics() {
+        assert_eq!(short_type_name("moose::Tagged"), "Tagged");
+        assert_eq!(
+            short_type_name(
+                "kubelet::state::common::registered::Registered<wasi_provider::WasiProvider>"
+            ),
+            "Registered"
+        );
+        assert_eq!(short_type_name("Released"), "Released");
+    }
+}
@@ -1,5 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=proto/pluginregistration/v1/pluginregistration.proto");
+    println!("cargo:rerun-if-changed=proto/deviceplugin/v1beta1/deviceplugin.proto");
 
     let builder = tonic_build::configure()
         .format(true)
@@ -12,8 +13,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // let builder = builder.build_server(false);
 
     builder.compile(
-        &["proto/pluginregistration/v1/pluginregistration.proto"],
-        &["proto/pluginregistration/v1"],
+        &[
+            "proto/pluginregistration/v1/pluginregistration.proto",
+            "proto/deviceplugin/v1beta1/deviceplugin.proto",
+        ],
+        &["proto/pluginregistration/v1", "proto/deviceplugin/v1beta1"],
     )?;
     Ok(())
 }
@@ -0,0 +1,30 @@
+//! Authentication of Kubelet server clients
+//!
+//! Clients authenticate with a client certificate, or with a bearer token
+//! that one of the authenticators here validates: the [`OidcAuthenticator`]
+//! for ID tokens, or the [`TokenReviewAuthenticator`] for tokens the API
+//! server issued. Either way, the request is made on behalf of the user in a
+//! [`UserInfo`], which authorization decisions are based on. Requests are then
+//! authorized by the [`WebhookAuthorizer`], if it is enabled.
+
+mod oidc;
+mod token_review;
+mod webhook;
+
+pub(crate) use oidc::OidcAuthenticator;
+pub(crate) use token_review::TokenReviewAuthenticator;
+pub(crate) use webhook::{RequestAttributes, WebhookAuthorizer};
+
+#[cfg(test)]
+pub(crate) use token_review::test::{service_account, FakeTokenReviewer};
+#[cfg(test)]
+pub(crate) use webhook::test::FakeAccessReviewer;
+
+/// The user that a request was authenticated as
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct UserInfo {
+    /// The name of the user
+    pub username: String,
+    /// The groups the user belongs to
+    pub groups: Vec<String>,
+}
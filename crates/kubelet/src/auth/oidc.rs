@@ -0,0 +1,549 @@
+//! Authentication with OpenID Connect ID tokens
+//!
+//! Tokens are JWTs signed by the provider. The signing keys are found through
+//! the provider's discovery document and cached, and fetched again when a
+//! token is signed with a key that isn't cached, so that keys can be rotated
+//! by the provider.
+
+use std::time::{Duration, Instant};
+
+use ring::signature::{self, RsaParameters, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::UserInfo;
+use crate::config::OidcConfig;
+
+/// The shortest time between fetches of the provider's keys, so that tokens
+/// signed with unknown keys can't be used to flood the provider with requests
+const MIN_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Validates ID tokens issued by an OpenID Connect provider
+pub(crate) struct OidcAuthenticator {
+    config: OidcConfig,
+    client: reqwest::Client,
+    keys: Mutex<KeyCache>,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    keys: Vec<Jwk>,
+    fetched: Option<Instant>,
+}
+
+/// The parts of the provider's discovery document that are needed
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A public key of the provider
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    /// The modulus and exponent of RSA keys
+    n: Option<String>,
+    e: Option<String>,
+    /// The curve and point of elliptic curve keys
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    aud: Audience,
+    exp: f64,
+    nbf: Option<f64>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::One(a) => a == audience,
+            Audience::Many(a) => a.iter().any(|a| a == audience),
+        }
+    }
+}
+
+/// A token split into its parts
+struct Token {
+    header: Header,
+    claims: Claims,
+    /// The encoded header and claims, which the signature is over
+    message: String,
+    signature: Vec<u8>,
+}
+
+impl Token {
+    fn parse(token: &str) -> anyhow::Result<Self> {
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => return Err(anyhow::anyhow!("token is not a JWT")),
+        };
+        Ok(Token {
+            header: serde_json::from_slice(&decode(header)?)
+                .map_err(|e| anyhow::anyhow!("invalid token header: {}", e))?,
+            claims: serde_json::from_slice(&decode(claims)?)
+                .map_err(|e| anyhow::anyhow!("invalid token claims: {}", e))?,
+            message: format!("{}.{}", header, claims),
+            signature: decode(signature)?,
+        })
+    }
+}
+
+impl OidcAuthenticator {
+    /// Creates an authenticator for the provider in `config`. The provider
+    /// isn't contacted until a token needs validating.
+    pub(crate) fn new(config: &OidcConfig) -> anyhow::Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(ca_file) = &config.ca_file {
+            let pem = std::fs::read(ca_file)
+                .map_err(|e| anyhow::anyhow!("unable to read {}: {}", ca_file.display(), e))?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(OidcAuthenticator {
+            config: config.clone(),
+            client: client.build()?,
+            keys: Mutex::new(KeyCache::default()),
+        })
+    }
+
+    /// Validates an ID token, returning the user it was issued to
+    pub(crate) async fn authenticate(&self, token: &str) -> anyhow::Result<UserInfo> {
+        let token = Token::parse(token)?;
+        let keys = self.signing_keys(&token.header).await?;
+        if !keys.iter().any(|key| {
+            verify(
+                key,
+                &token.header.alg,
+                token.message.as_bytes(),
+                &token.signature,
+            )
+            .is_ok()
+        }) {
+            return Err(anyhow::anyhow!("invalid token signature"));
+        }
+        self.user_info(token.claims, chrono::Utc::now().timestamp() as f64)
+    }
+
+    /// Returns the keys the token could have been signed with, fetching the
+    /// provider's keys again if none of the cached ones match
+    async fn signing_keys(&self, header: &Header) -> anyhow::Result<Vec<Jwk>> {
+        let mut cache = self.keys.lock().await;
+        let mut keys = matching_keys(&cache.keys, header);
+        let refresh_due = cache
+            .fetched
+            .map(|fetched| fetched.elapsed() >= MIN_KEY_REFRESH_INTERVAL)
+            .unwrap_or(true);
+        if keys.is_empty() && refresh_due {
+            cache.keys = self.fetch_keys().await?;
+            cache.fetched = Some(Instant::now());
+            keys = matching_keys(&cache.keys, header);
+        }
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!(
+                "token is not signed with a key of the issuer"
+            ));
+        }
+        Ok(keys)
+    }
+
+    async fn fetch_keys(&self) -> anyhow::Result<Vec<Jwk>> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer_url.trim_end_matches('/')
+        );
+        debug!("Fetching OIDC discovery document from {}", discovery_url);
+        let discovery: Discovery = self
+            .client
+            .get(&discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if discovery.issuer != self.config.issuer_url {
+            return Err(anyhow::anyhow!(
+                "OIDC provider at {} is for issuer {}",
+                self.config.issuer_url,
+                discovery.issuer
+            ));
+        }
+        debug!("Fetching OIDC signing keys from {}", discovery.jwks_uri);
+        let keys: JwkSet = self
+            .client
+            .get(&discovery.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(keys.keys)
+    }
+
+    /// Checks the claims of a token with a valid signature, and reads the
+    /// user from them
+    fn user_info(&self, claims: Claims, now: f64) -> anyhow::Result<UserInfo> {
+        if claims.iss != self.config.issuer_url {
+            return Err(anyhow::anyhow!("token issued by {}", claims.iss));
+        }
+        if !claims.aud.contains(&self.config.client_id) {
+            return Err(anyhow::anyhow!(
+                "token not issued for {}",
+                self.config.client_id
+            ));
+        }
+        if claims.exp <= now {
+            return Err(anyhow::anyhow!("token has expired"));
+        }
+        if claims.nbf.map(|nbf| nbf > now).unwrap_or(false) {
+            return Err(anyhow::anyhow!("token is not valid yet"));
+        }
+
+        let claim = &self.config.username_claim;
+        let username = match claims.other.get(claim) {
+            Some(serde_json::Value::String(username)) if !username.is_empty() => username.clone(),
+            _ => return Err(anyhow::anyhow!("token has no {} claim", claim)),
+        };
+        // Like the Kubernetes API server, only accept email addresses as user
+        // names if the provider has verified them
+        if claim == "email"
+            && claims.other.get("email_verified") == Some(&serde_json::Value::Bool(false))
+        {
+            return Err(anyhow::anyhow!("token email address is not verified"));
+        }
+        let groups = match self
+            .config
+            .groups_claim
+            .as_ref()
+            .and_then(|claim| claims.other.get(claim))
+        {
+            None => vec![],
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .map(|group| {
+                    group
+                        .as_str()
+                        .map(str::to_owned)
+                        .ok_or_else(|| anyhow::anyhow!("token groups must be strings"))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Some(_) => return Err(anyhow::anyhow!("token groups must be strings")),
+        };
+        Ok(UserInfo { username, groups })
+    }
+}
+
+/// Returns the keys that can verify signatures in a token with `header`
+fn matching_keys(keys: &[Jwk], header: &Header) -> Vec<Jwk> {
+    keys.iter()
+        .filter(|key| key.usage.as_deref().unwrap_or("sig") == "sig")
+        .filter(|key| header.kid.is_none() || key.kid == header.kid)
+        .cloned()
+        .collect()
+}
+
+/// Verifies `signature` over `message` with `key`, using the algorithm named
+/// by the token
+fn verify(key: &Jwk, alg: &str, message: &[u8], signature: &[u8]) -> anyhow::Result<()> {
+    if key.alg.as_deref().map(|a| a != alg).unwrap_or(false) {
+        return Err(anyhow::anyhow!("key is not for {}", alg));
+    }
+    match alg {
+        "RS256" => verify_rsa(
+            key,
+            &signature::RSA_PKCS1_2048_8192_SHA256,
+            message,
+            signature,
+        ),
+        "RS384" => verify_rsa(
+            key,
+            &signature::RSA_PKCS1_2048_8192_SHA384,
+            message,
+            signature,
+        ),
+        "RS512" => verify_rsa(
+            key,
+            &signature::RSA_PKCS1_2048_8192_SHA512,
+            message,
+            signature,
+        ),
+        "PS256" => verify_rsa(
+            key,
+            &signature::RSA_PSS_2048_8192_SHA256,
+            message,
+            signature,
+        ),
+        "PS384" => verify_rsa(
+            key,
+            &signature::RSA_PSS_2048_8192_SHA384,
+            message,
+            signature,
+        ),
+        "PS512" => verify_rsa(
+            key,
+            &signature::RSA_PSS_2048_8192_SHA512,
+            message,
+            signature,
+        ),
+        "ES256" => verify_ec(
+            key,
+            "P-256",
+            &signature::ECDSA_P256_SHA256_FIXED,
+            message,
+            signature,
+        ),
+        "ES384" => verify_ec(
+            key,
+            "P-384",
+            &signature::ECDSA_P384_SHA384_FIXED,
+            message,
+            signature,
+        ),
+        _ => Err(anyhow::anyhow!("unsupported token algorithm {}", alg)),
+    }
+}
+
+fn verify_rsa(
+    key: &Jwk,
+    params: &RsaParameters,
+    message: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<()> {
+    if key.kty != "RSA" {
+        return Err(anyhow::anyhow!("key is not an RSA key"));
+    }
+    let components = RsaPublicKeyComponents {
+        n: decode(key_parameter(&key.n)?)?,
+        e: decode(key_parameter(&key.e)?)?,
+    };
+    components
+        .verify(params, message, signature)
+        .map_err(|_| anyhow::anyhow!("invalid signature"))
+}
+
+fn verify_ec(
+    key: &Jwk,
+    curve: &str,
+    algorithm: &'static signature::EcdsaVerificationAlgorithm,
+    message: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<()> {
+    if key.kty != "EC" || key.crv.as_deref() != Some(curve) {
+        return Err(anyhow::anyhow!("key is not a {} key", curve));
+    }
+    // The uncompressed encoding of the point
+    let mut point = vec![4];
+    point.extend(decode(key_parameter(&key.x)?)?);
+    point.extend(decode(key_parameter(&key.y)?)?);
+    UnparsedPublicKey::new(algorithm, point)
+        .verify(message, signature)
+        .map_err(|_| anyhow::anyhow!("invalid signature"))
+}
+
+fn key_parameter(parameter: &Option<String>) -> anyhow::Result<&str> {
+    parameter
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("key is missing parameters"))
+}
+
+fn decode(part: &str) -> anyhow::Result<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| anyhow::anyhow!("invalid base64url encoding: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+    use warp::Filter;
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// A provider serving a discovery document and a single ES256 key
+    struct TestProvider {
+        issuer_url: String,
+        key_pair: EcdsaKeyPair,
+    }
+
+    impl TestProvider {
+        async fn start() -> Self {
+            let key_pair = new_key_pair();
+            let point = key_pair.public_key().as_ref();
+            let jwks = json!({
+                "keys": [{
+                    "kty": "EC",
+                    "kid": "test-key",
+                    "alg": "ES256",
+                    "use": "sig",
+                    "crv": "P-256",
+                    "x": encode(&point[1..33]),
+                    "y": encode(&point[33..]),
+                }]
+            });
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer_url = format!("http://{}", listener.local_addr().unwrap());
+            let discovery = json!({
+                "issuer": issuer_url,
+                "jwks_uri": format!("{}/keys", issuer_url),
+            });
+            let routes = warp::path!(".well-known" / "openid-configuration")
+                .map(move || warp::reply::json(&discovery))
+                .or(warp::path!("keys").map(move || warp::reply::json(&jwks)));
+            tokio::spawn(
+                warp::serve(routes)
+                    .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+            );
+            TestProvider {
+                issuer_url,
+                key_pair,
+            }
+        }
+
+        fn authenticator(&self) -> OidcAuthenticator {
+            OidcAuthenticator::new(&OidcConfig {
+                issuer_url: self.issuer_url.clone(),
+                client_id: "kubernetes".to_owned(),
+                username_claim: "email".to_owned(),
+                groups_claim: Some("groups".to_owned()),
+                ca_file: None,
+            })
+            .unwrap()
+        }
+
+        /// Returns claims for a valid token
+        fn claims(&self) -> serde_json::Value {
+            json!({
+                "iss": self.issuer_url,
+                "aud": "kubernetes",
+                "exp": chrono::Utc::now().timestamp() + 60,
+                "sub": "CgR0ZXN0",
+                "email": "dev@krustlet.test",
+                "email_verified": true,
+                "groups": ["devs", "ops"],
+            })
+        }
+
+        fn token(&self, claims: &serde_json::Value) -> String {
+            sign(&self.key_pair, claims)
+        }
+    }
+
+    fn new_key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, claims: &serde_json::Value) -> String {
+        let header = json!({"alg": "ES256", "typ": "JWT", "kid": "test-key"});
+        let message = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        let signature = key_pair
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .unwrap();
+        format!("{}.{}", message, encode(signature.as_ref()))
+    }
+
+    #[tokio::test]
+    async fn valid_tokens_are_authenticated() {
+        let provider = TestProvider::start().await;
+        let token = provider.token(&provider.claims());
+        let user = provider.authenticator().authenticate(&token).await.unwrap();
+        assert_eq!(
+            user,
+            UserInfo {
+                username: "dev@krustlet.test".to_owned(),
+                groups: vec!["devs".to_owned(), "ops".to_owned()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_signed_with_other_keys_are_rejected() {
+        let provider = TestProvider::start().await;
+        let token = sign(&new_key_pair(), &provider.claims());
+        assert!(provider.authenticator().authenticate(&token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tokens_with_invalid_claims_are_rejected() {
+        let provider = TestProvider::start().await;
+        let authenticator = provider.authenticator();
+        let invalid = vec![
+            ("iss", json!("https://other.krustlet.test")),
+            ("aud", json!(["other-client"])),
+            ("exp", json!(chrono::Utc::now().timestamp() - 1)),
+            ("nbf", json!(chrono::Utc::now().timestamp() + 60)),
+            ("email_verified", json!(false)),
+        ];
+        for (claim, value) in invalid {
+            let mut claims = provider.claims();
+            claims[claim] = value;
+            let token = provider.token(&claims);
+            assert!(
+                authenticator.authenticate(&token).await.is_err(),
+                "token with {} claim was accepted",
+                claim
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn audiences_may_be_lists() {
+        let provider = TestProvider::start().await;
+        let mut claims = provider.claims();
+        claims["aud"] = json!(["other-client", "kubernetes"]);
+        let token = provider.token(&claims);
+        assert!(provider.authenticator().authenticate(&token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn malformed_tokens_are_rejected() {
+        let provider = TestProvider::start().await;
+        let authenticator = provider.authenticator();
+        for token in &["", "not-a-token", "a.b.c", "a.b.c.d"] {
+            assert!(authenticator.authenticate(token).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Authentication of bearer tokens by the API server
+//!
+//! Tokens that aren't ID tokens of an OpenID Connect provider, such as
+//! service account tokens, are sent to the API server in a `TokenReview`,
+//! which says whether the token is valid and who it belongs to. Results are
+//! cached for a short time, keyed by a hash of the token rather than the
+//! token itself, so that a client making many requests with the same token
+//! doesn't need a review for each of them.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec, TokenReviewStatus};
+use kube::api::{Api, PostParams};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::debug;
+
+use super::UserInfo;
+
+/// How long a token that was found valid is cached for
+const AUTHENTICATED_TTL: Duration = Duration::from_secs(2 * 60);
+/// How long a token that was found invalid is cached for
+const UNAUTHENTICATED_TTL: Duration = Duration::from_secs(30);
+
+/// Reviews bearer tokens. This is the API server, except in tests.
+#[async_trait]
+pub(crate) trait TokenReviewer: Send + Sync {
+    /// Returns the API server's review of `token`
+    async fn review(&self, token: &str) -> anyhow::Result<TokenReviewStatus>;
+}
+
+#[async_trait]
+impl TokenReviewer for kube::Client {
+    async fn review(&self, token: &str) -> anyhow::Result<TokenReviewStatus> {
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let reviews: Api<TokenReview> = Api::all(self.clone());
+        let review = reviews.create(&PostParams::default(), &review).await?;
+        Ok(review.status.unwrap_or_default())
+    }
+}
+
+/// Reviews with the latest client, which is replaced when the client
+/// certificate is rotated
+#[async_trait]
+impl TokenReviewer for watch::Receiver<kube::Client> {
+    async fn review(&self, token: &str) -> anyhow::Result<TokenReviewStatus> {
+        let client = self.borrow().clone();
+        client.review(token).await
+    }
+}
+
+/// Authenticates bearer tokens with `TokenReview`s
+pub(crate) struct TokenReviewAuthenticator {
+    reviewer: Box<dyn TokenReviewer>,
+    tokens: Mutex<TokenCache>,
+}
+
+impl TokenReviewAuthenticator {
+    /// Creates an authenticator that has the API server review tokens, with
+    /// the latest client sent on `clients`
+    pub(crate) fn new(clients: watch::Receiver<kube::Client>) -> Self {
+        Self::with_reviewer(clients)
+    }
+
+    /// Creates an authenticator that has `reviewer` review tokens
+    pub(crate) fn with_reviewer(reviewer: impl TokenReviewer + 'static) -> Self {
+        TokenReviewAuthenticator {
+            reviewer: Box::new(reviewer),
+            tokens: Mutex::new(TokenCache::default()),
+        }
+    }
+
+    /// Returns the user `token` belongs to, or an error if it isn't valid or
+    /// couldn't be reviewed
+    pub(crate) async fn authenticate(&self, token: &str) -> anyhow::Result<UserInfo> {
+        let key = Sha256::digest(token.as_bytes()).to_vec();
+        if let Some(user) = self.tokens().get(&key, Instant::now()) {
+            return user.ok_or_else(|| anyhow::anyhow!("token was not authenticated"));
+        }
+
+        let status = self.reviewer.review(token).await?;
+        let user = match status.user {
+            Some(k8s_openapi::api::authentication::v1::UserInfo {
+                username: Some(username),
+                groups,
+                ..
+            }) if status.authenticated == Some(true) => Some(UserInfo {
+                username,
+                groups: groups.unwrap_or_default(),
+            }),
+            _ => {
+                debug!("TokenReview did not authenticate token: {:?}", status.error);
+                None
+            }
+        };
+        self.tokens().insert(key, user.clone(), Instant::now());
+        user.ok_or_else(|| anyhow::anyhow!("token was not authenticated"))
+    }
+
+    fn tokens(&self) -> std::sync::MutexGuard<'_, TokenCache> {
+        self.tokens
+            .lock()
+            .expect("token cache lock should not be poisoned")
+    }
+}
+
+/// Recently reviewed tokens, by their hash, with who they belong to and when
+/// the review expires
+#[derive(Default)]
+struct TokenCache {
+    tokens: HashMap<Vec<u8>, (Option<UserInfo>, Instant)>,
+}
+
+impl TokenCache {
+    fn get(&self, key: &[u8], now: Instant) -> Option<Option<UserInfo>> {
+        self.tokens
+            .get(key)
+            .filter(|(_, expiry)| *expiry > now)
+            .map(|(user, _)| user.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, user: Option<UserInfo>, now: Instant) {
+        self.tokens.retain(|_, (_, expiry)| *expiry > now);
+        let ttl = if user.is_some() {
+            AUTHENTICATED_TTL
+        } else {
+            UNAUTHENTICATED_TTL
+        };
+        self.tokens.insert(key, (user, now + ttl));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Authenticates a single token as a single user, and counts reviews
+    pub(crate) struct FakeTokenReviewer {
+        pub token: &'static str,
+        pub user: UserInfo,
+        pub reviews: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl TokenReviewer for FakeTokenReviewer {
+        async fn review(&self, token: &str) -> anyhow::Result<TokenReviewStatus> {
+            self.reviews.fetch_add(1, Ordering::SeqCst);
+            if token != self.token {
+                return Ok(TokenReviewStatus {
+                    authenticated: Some(false),
+                    error: Some("invalid bearer token".to_owned()),
+                    ..Default::default()
+                });
+            }
+            Ok(TokenReviewStatus {
+                authenticated: Some(true),
+                user: Some(k8s_openapi::api::authentication::v1::UserInfo {
+                    username: Some(self.user.username.clone()),
+                    groups: Some(self.user.groups.clone()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        }
+    }
+
+    pub(crate) fn service_account() -> UserInfo {
+        UserInfo {
+            username: "system:serviceaccount:monitoring:prometheus".to_owned(),
+            groups: vec!["system:serviceaccounts".to_owned()],
+        }
+    }
+
+    #[tokio::test]
+    async fn reviews_are_cached() {
+        let reviews = Arc::new(AtomicUsize::new(0));
+        let authenticator = TokenReviewAuthenticator::with_reviewer(FakeTokenReviewer {
+            token: "valid",
+            user: service_account(),
+            reviews: reviews.clone(),
+        });
+
+        for _ in 0..2 {
+            let user = authenticator.authenticate("valid").await.unwrap();
+            assert_eq!(user, service_account());
+            assert!(authenticator.authenticate("invalid").await.is_err());
+        }
+        assert_eq!(reviews.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reviews_expire() {
+        let mut cache = TokenCache::default();
+        let now = Instant::now();
+        cache.insert(vec![1], Some(service_account()), now);
+        cache.insert(vec![2], None, now);
+
+        assert_eq!(cache.get(&[1], now), Some(Some(service_account())));
+        assert_eq!(cache.get(&[2], now), Some(None));
+        assert_eq!(cache.get(&[3], now), None);
+        assert_eq!(
+            cache.get(&[1], now + UNAUTHENTICATED_TTL),
+            Some(Some(service_account()))
+        );
+        assert_eq!(cache.get(&[2], now + UNAUTHENTICATED_TTL), None);
+        assert_eq!(cache.get(&[1], now + AUTHENTICATED_TTL), None);
+    }
+}
@@ -0,0 +1,332 @@
+//! Authorization of Kubelet server requests by the API server
+//!
+//! Each request is described to the API server in a `SubjectAccessReview`,
+//! the same way other kubelets do: as the request's verb on a subresource of
+//! this node, such as `get` on `nodes/log`. Decisions are cached for a short
+//! time, so that repeated requests, such as a client polling logs, don't each
+//! need a review. Denied requests keep the reason the API server gave, so
+//! that clients can be told why they were denied.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec, SubjectAccessReviewStatus,
+};
+use kube::api::{Api, PostParams};
+use tokio::sync::watch;
+use tracing::debug;
+
+use super::UserInfo;
+
+/// How long a decision to allow a request is cached for
+const ALLOWED_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long a decision to deny a request is cached for
+const DENIED_TTL: Duration = Duration::from_secs(30);
+
+/// What a request is asking to do
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RequestAttributes {
+    /// The user the request was authenticated as
+    pub user: UserInfo,
+    /// The verb of the request, such as `get` or `create`
+    pub verb: String,
+    /// The subresource of the node the request is for, such as `log` or
+    /// `proxy`
+    pub subresource: String,
+}
+
+impl RequestAttributes {
+    /// Describes a request with the given HTTP method and path like the
+    /// Kubernetes kubelet does. Paths without a specific subresource are for
+    /// the `proxy` subresource.
+    pub(crate) fn new(user: UserInfo, method: &http::Method, path: &str) -> Self {
+        let verb = match *method {
+            http::Method::POST => "create",
+            http::Method::PUT => "update",
+            http::Method::PATCH => "patch",
+            http::Method::DELETE => "delete",
+            _ => "get",
+        };
+        let subresource = match path.trim_start_matches('/').split('/').next() {
+            Some("stats") => "stats",
+            Some("metrics") => "metrics",
+            Some("logs") => "log",
+            Some("spec") => "spec",
+            _ => "proxy",
+        };
+        RequestAttributes {
+            user,
+            verb: verb.to_owned(),
+            subresource: subresource.to_owned(),
+        }
+    }
+}
+
+/// Whether a request is allowed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Decision {
+    /// Whether the request is allowed
+    pub allowed: bool,
+    /// Why the request was allowed or denied, if the API server said
+    pub reason: Option<String>,
+}
+
+/// Reviews access to the subresources of nodes. This is the API server,
+/// except in tests.
+#[async_trait]
+pub(crate) trait AccessReviewer: Send + Sync {
+    /// Returns the API server's review of `review`
+    async fn review(
+        &self,
+        review: SubjectAccessReview,
+    ) -> anyhow::Result<SubjectAccessReviewStatus>;
+}
+
+#[async_trait]
+impl AccessReviewer for kube::Client {
+    async fn review(
+        &self,
+        review: SubjectAccessReview,
+    ) -> anyhow::Result<SubjectAccessReviewStatus> {
+        let reviews: Api<SubjectAccessReview> = Api::all(self.clone());
+        let review = reviews.create(&PostParams::default(), &review).await?;
+        Ok(review.status.unwrap_or_default())
+    }
+}
+
+/// Reviews with the latest client, which is replaced when the client
+/// certificate is rotated
+#[async_trait]
+impl AccessReviewer for watch::Receiver<kube::Client> {
+    async fn review(
+        &self,
+        review: SubjectAccessReview,
+    ) -> anyhow::Result<SubjectAccessReviewStatus> {
+        let client = self.borrow().clone();
+        client.review(review).await
+    }
+}
+
+/// Authorizes requests with `SubjectAccessReview`s
+pub(crate) struct WebhookAuthorizer {
+    reviewer: Box<dyn AccessReviewer>,
+    node_name: String,
+    decisions: Mutex<DecisionCache>,
+}
+
+impl WebhookAuthorizer {
+    /// Creates an authorizer for requests to the node `node_name` that has
+    /// the API server review them, with the latest client sent on `clients`
+    pub(crate) fn new(clients: watch::Receiver<kube::Client>, node_name: String) -> Self {
+        Self::with_reviewer(clients, node_name)
+    }
+
+    /// Creates an authorizer for requests to the node `node_name` that has
+    /// `reviewer` review them
+    pub(crate) fn with_reviewer(
+        reviewer: impl AccessReviewer + 'static,
+        node_name: String,
+    ) -> Self {
+        WebhookAuthorizer {
+            reviewer: Box::new(reviewer),
+            node_name,
+            decisions: Mutex::new(DecisionCache::default()),
+        }
+    }
+
+    /// Returns whether the API server allows the request
+    pub(crate) async fn authorize(
+        &self,
+        attributes: &RequestAttributes,
+    ) -> anyhow::Result<Decision> {
+        if let Some(decision) = self.decisions().get(attributes, Instant::now()) {
+            return Ok(decision);
+        }
+
+        let review = SubjectAccessReview {
+            spec: SubjectAccessReviewSpec {
+                user: Some(attributes.user.username.clone()),
+                groups: Some(attributes.user.groups.clone()),
+                resource_attributes: Some(ResourceAttributes {
+                    verb: Some(attributes.verb.clone()),
+                    group: Some(String::new()),
+                    version: Some("v1".to_owned()),
+                    resource: Some("nodes".to_owned()),
+                    subresource: Some(attributes.subresource.clone()),
+                    name: Some(self.node_name.clone()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let status = self.reviewer.review(review).await?;
+        let decision = Decision {
+            allowed: status.allowed,
+            reason: status.reason.filter(|reason| !reason.is_empty()),
+        };
+        debug!("SubjectAccessReview of {:?}: {:?}", attributes, decision);
+        self.decisions()
+            .insert(attributes.clone(), decision.clone(), Instant::now());
+        Ok(decision)
+    }
+
+    fn decisions(&self) -> std::sync::MutexGuard<'_, DecisionCache> {
+        self.decisions
+            .lock()
+            .expect("authorization cache lock should not be poisoned")
+    }
+}
+
+/// Recent authorization decisions, with when they expire
+#[derive(Default)]
+struct DecisionCache {
+    decisions: HashMap<RequestAttributes, (Decision, Instant)>,
+}
+
+impl DecisionCache {
+    fn get(&self, attributes: &RequestAttributes, now: Instant) -> Option<Decision> {
+        self.decisions
+            .get(attributes)
+            .filter(|(_, expiry)| *expiry > now)
+            .map(|(decision, _)| decision.clone())
+    }
+
+    fn insert(&mut self, attributes: RequestAttributes, decision: Decision, now: Instant) {
+        self.decisions.retain(|_, (_, expiry)| *expiry > now);
+        let ttl = if decision.allowed {
+            ALLOWED_TTL
+        } else {
+            DENIED_TTL
+        };
+        self.decisions.insert(attributes, (decision, now + ttl));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Allows requests for the given subresources only, and counts reviews
+    pub(crate) struct FakeAccessReviewer {
+        pub allowed_subresources: Vec<&'static str>,
+        pub reviews: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AccessReviewer for FakeAccessReviewer {
+        async fn review(
+            &self,
+            review: SubjectAccessReview,
+        ) -> anyhow::Result<SubjectAccessReviewStatus> {
+            self.reviews.fetch_add(1, Ordering::SeqCst);
+            let subresource = review
+                .spec
+                .resource_attributes
+                .and_then(|attributes| attributes.subresource)
+                .unwrap_or_default();
+            let allowed = self.allowed_subresources.contains(&subresource.as_str());
+            Ok(SubjectAccessReviewStatus {
+                allowed,
+                reason: Some(if allowed {
+                    String::new()
+                } else {
+                    format!("no RBAC policy matched nodes/{}", subresource)
+                }),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn user() -> UserInfo {
+        UserInfo {
+            username: "kube-apiserver".to_owned(),
+            groups: vec!["system:masters".to_owned()],
+        }
+    }
+
+    #[test]
+    fn requests_are_described_like_other_kubelets() {
+        let attributes =
+            RequestAttributes::new(user(), &http::Method::GET, "/containerLogs/ns/pod/c");
+        assert_eq!(attributes.verb, "get");
+        assert_eq!(attributes.subresource, "proxy");
+
+        let attributes = RequestAttributes::new(user(), &http::Method::POST, "/exec/ns/pod/c");
+        assert_eq!(attributes.verb, "create");
+        assert_eq!(attributes.subresource, "proxy");
+
+        let attributes = RequestAttributes::new(user(), &http::Method::GET, "/stats/summary");
+        assert_eq!(attributes.subresource, "stats");
+
+        let attributes = RequestAttributes::new(user(), &http::Method::GET, "/metrics/resource");
+        assert_eq!(attributes.subresource, "metrics");
+    }
+
+    #[tokio::test]
+    async fn decisions_keep_the_reason_and_are_cached() {
+        let reviews = Arc::new(AtomicUsize::new(0));
+        let authorizer = WebhookAuthorizer::with_reviewer(
+            FakeAccessReviewer {
+                allowed_subresources: vec!["stats"],
+                reviews: reviews.clone(),
+            },
+            "krustlet".to_owned(),
+        );
+        let allowed = RequestAttributes::new(user(), &http::Method::GET, "/stats/summary");
+        let denied = RequestAttributes::new(user(), &http::Method::POST, "/exec/ns/pod/c");
+
+        for _ in 0..2 {
+            let decision = authorizer.authorize(&allowed).await.unwrap();
+            assert_eq!(
+                decision,
+                Decision {
+                    allowed: true,
+                    reason: None
+                }
+            );
+            let decision = authorizer.authorize(&denied).await.unwrap();
+            assert!(!decision.allowed);
+            assert_eq!(
+                decision.reason.as_deref(),
+                Some("no RBAC policy matched nodes/proxy")
+            );
+        }
+        assert_eq!(reviews.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn decisions_expire() {
+        let mut cache = DecisionCache::default();
+        let now = Instant::now();
+        let allowed = RequestAttributes::new(user(), &http::Method::GET, "/stats");
+        let denied = RequestAttributes::new(user(), &http::Method::POST, "/exec/ns/pod/c");
+        let decision = |allowed| Decision {
+            allowed,
+            reason: None,
+        };
+        cache.insert(allowed.clone(), decision(true), now);
+        cache.insert(denied.clone(), decision(false), now);
+
+        assert_eq!(cache.get(&allowed, now), Some(decision(true)));
+        assert_eq!(cache.get(&denied, now), Some(decision(false)));
+        assert_eq!(cache.get(&allowed, now + DENIED_TTL), Some(decision(true)));
+        assert_eq!(cache.get(&denied, now + DENIED_TTL), None);
+        assert_eq!(cache.get(&allowed, now + ALLOWED_TTL), None);
+
+        let other_user = RequestAttributes::new(
+            UserInfo {
+                username: "mallory".to_owned(),
+                groups: vec![],
+            },
+            &http::Method::GET,
+            "/stats",
+        );
+        assert_eq!(cache.get(&other_user, now), None);
+    }
+}
@@ -35,6 +35,16 @@ impl Default for ExponentialBackoffStrategy {
 }
 
 impl ExponentialBackoffStrategy {
+    /// Gets a backoff strategy that starts at `base_duration` and doubles
+    /// until hitting `cap`.
+    pub fn new(base_duration: Duration, cap: Duration) -> Self {
+        Self {
+            base_duration,
+            cap,
+            last_duration: Duration::from_secs(0),
+        }
+    }
+
     fn capped_next_duration(&self) -> Duration {
         let next_duration = if self.last_duration == Duration::from_secs(0) {
             self.base_duration
@@ -1,7 +1,9 @@
 use std::{convert::TryFrom, env, path::Path, str};
 
 use futures::{StreamExt, TryStreamExt};
-use k8s_openapi::api::certificates::v1beta1::CertificateSigningRequest;
+use k8s_openapi::api::certificates::v1beta1::{
+    CertificateSigningRequest, CertificateSigningRequestCondition,
+};
 use kube::api::{Api, ListParams, PostParams};
 use kube::config::Kubeconfig;
 use kube::Config;
@@ -10,6 +12,7 @@ use rcgen::{
     Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType,
     PKCS_ECDSA_P256_SHA256,
 };
+use thiserror::Error;
 use tokio::fs::{read, write};
 use tracing::{debug, info};
 
@@ -17,7 +20,39 @@ use crate::config::Config as KubeletConfig;
 use crate::kubeconfig::exists as kubeconfig_exists;
 use crate::kubeconfig::KUBECONFIG;
 
+mod rotation;
+
+pub(crate) use rotation::{rotate_client_certificate, rotate_serving_certificate};
+
 const APPROVED_TYPE: &str = "Approved";
+const DENIED_TYPE: &str = "Denied";
+const FAILED_TYPE: &str = "Failed";
+
+/// A CSR for a certificate was denied, or the signer failed to sign it
+#[derive(Debug, Error)]
+#[error("CSR {csr_name} was {condition}: {message}")]
+pub(crate) struct CsrDenied {
+    /// The name of the CSR
+    pub csr_name: String,
+    /// The condition the CSR was given, `Denied` or `Failed`
+    pub condition: String,
+    /// Why the CSR was denied, as given by whoever denied it
+    pub message: String,
+}
+
+impl CsrDenied {
+    fn new(csr_name: &str, condition: &CertificateSigningRequestCondition) -> Self {
+        CsrDenied {
+            csr_name: csr_name.to_owned(),
+            condition: condition.type_.clone(),
+            message: condition
+                .message
+                .clone()
+                .or_else(|| condition.reason.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
 
 /// Bootstrap the cluster with TLS certificates but only if no existing kubeconfig can be found.
 pub async fn bootstrap<K: AsRef<Path>>(
@@ -41,7 +76,6 @@ async fn bootstrap_auth<K: AsRef<Path>>(
             .await
             .map_err(|e| anyhow::anyhow!("Unable to load config from host: {}", e))
     } else {
-        // TODO: if configured, kubelet automatically requests renewal of the certificate when it is close to expiry
         let original_kubeconfig = env::var(KUBECONFIG)?;
         debug!(
             "No existing kubeconfig found, loading bootstrap config from {:?}",
@@ -51,7 +85,6 @@ async fn bootstrap_auth<K: AsRef<Path>>(
         let conf = kube::Config::infer().await?;
         let client = kube::Client::try_from(conf)?;
 
-        let cert_bundle = gen_auth_cert(config)?;
         let bootstrap_config = read_from(&bootstrap_file).await?;
         let named_cluster = bootstrap_config
             .clusters
@@ -70,79 +103,9 @@ async fn bootstrap_auth<K: AsRef<Path>>(
                 )
             })?;
 
-        let csrs: Api<CertificateSigningRequest> = Api::all(client);
-        let csr_json = serde_json::json!({
-          "apiVersion": "certificates.k8s.io/v1beta1",
-          "kind": "CertificateSigningRequest",
-          "metadata": {
-            "name": config.node_name,
-          },
-          "spec": {
-            "request": base64::encode(cert_bundle.serialize_request_pem()?.as_bytes()),
-            "signerName": "kubernetes.io/kube-apiserver-client-kubelet",
-            "usages": [
-              "digital signature",
-              "key encipherment",
-              "client auth"
-            ]
-          }
-        });
-
-        let post_data = serde_json::from_value(csr_json)
-            .expect("Invalid CSR JSON, this is a programming error");
-
-        csrs.create(&PostParams::default(), &post_data).await?;
-
-        // Wait for CSR signing
-        let inf = watcher(
-            csrs,
-            ListParams::default().fields(&format!("metadata.name={}", config.node_name)),
-        );
-
-        let mut watcher = inf.boxed();
-        let mut generated_kubeconfig = Vec::new();
-        let mut got_cert = false;
-        let start = std::time::Instant::now();
-        while let Some(event) = watcher.try_next().await? {
-            let status = match event {
-                Event::Applied(m) => m.status.unwrap(),
-                Event::Restarted(mut certs) => {
-                    // We should only ever get one cert for this node, so error in any circumstance we don't
-                    if certs.len() > 1 {
-                        return Err(anyhow::anyhow!("On watch restart, got more than 1 authentication CSR. This means something is in an incorrect state"));
-                    }
-                    certs.remove(0).status.unwrap()
-                }
-                Event::Deleted(_) => {
-                    return Err(anyhow::anyhow!(
-                        "Authentication CSR was deleted before it was approved"
-                    ))
-                }
-            };
-
-            if let Some(cert) = status.certificate {
-                if let Some(v) = status.conditions {
-                    if v.into_iter().any(|c| c.type_.as_str() == APPROVED_TYPE) {
-                        generated_kubeconfig = gen_kubeconfig(
-                            ca_data,
-                            server,
-                            cert,
-                            cert_bundle.serialize_private_key_pem(),
-                        )?;
-                        got_cert = true;
-                        break;
-                    }
-                }
-            }
-
-            info!("Got modified event, but CSR for authentication certs is not currently approved, {:?} elapsed", start.elapsed());
-        }
-
-        if !got_cert {
-            return Err(anyhow::anyhow!(
-                "Authentication certificates were never approved"
-            ));
-        }
+        let (certificate, private_key) =
+            request_client_certificate(config, client, &config.node_name).await?;
+        let generated_kubeconfig = gen_kubeconfig(ca_data, server, certificate, private_key)?;
 
         write(&original_kubeconfig, &generated_kubeconfig).await?;
         // Set environment variable back to original value
@@ -165,10 +128,112 @@ async fn bootstrap_tls(
         return Ok(());
     }
 
-    let cert_bundle = gen_tls_cert(config)?;
-
     let csr_name = format!("{}-tls", config.hostname);
     let client = kube::Client::try_from(kubeconfig)?;
+    let (certificate, private_key) =
+        request_serving_certificate(config, client, &csr_name, &notify).await?;
+
+    debug!(
+        "Got certificate from API, writing cert to {:?} and private key to {:?}",
+        config.server_config.cert_file, config.server_config.private_key_file
+    );
+    write(&config.server_config.cert_file, &certificate).await?;
+    write(&config.server_config.private_key_file, &private_key).await?;
+
+    notify(completed_csr_approval("TLS"));
+
+    Ok(())
+}
+
+/// Requests a client certificate for the node from the cluster with a CSR of
+/// the given name, returning the PEM encoded certificate and private key once
+/// the CSR has been approved. Fails with a [`CsrDenied`] if the CSR is
+/// denied.
+pub(crate) async fn request_client_certificate(
+    config: &KubeletConfig,
+    client: kube::Client,
+    csr_name: &str,
+) -> anyhow::Result<(String, String)> {
+    let cert_bundle = gen_auth_cert(config)?;
+
+    let csrs: Api<CertificateSigningRequest> = Api::all(client);
+    let csr_json = serde_json::json!({
+      "apiVersion": "certificates.k8s.io/v1beta1",
+      "kind": "CertificateSigningRequest",
+      "metadata": {
+        "name": csr_name,
+      },
+      "spec": {
+        "request": base64::encode(cert_bundle.serialize_request_pem()?.as_bytes()),
+        "signerName": "kubernetes.io/kube-apiserver-client-kubelet",
+        "usages": [
+          "digital signature",
+          "key encipherment",
+          "client auth"
+        ]
+      }
+    });
+
+    let post_data =
+        serde_json::from_value(csr_json).expect("Invalid CSR JSON, this is a programming error");
+
+    csrs.create(&PostParams::default(), &post_data).await?;
+
+    // Wait for CSR signing
+    let inf = watcher(
+        csrs,
+        ListParams::default().fields(&format!("metadata.name={}", csr_name)),
+    );
+
+    let mut watcher = inf.boxed();
+    let start = std::time::Instant::now();
+    while let Some(event) = watcher.try_next().await? {
+        let status = match event {
+            Event::Applied(m) => m.status.unwrap(),
+            Event::Restarted(mut certs) => {
+                // We should only ever get one cert for this node, so error in any circumstance we don't
+                if certs.len() > 1 {
+                    return Err(anyhow::anyhow!("On watch restart, got more than 1 authentication CSR. This means something is in an incorrect state"));
+                }
+                certs.remove(0).status.unwrap()
+            }
+            Event::Deleted(_) => {
+                return Err(anyhow::anyhow!(
+                    "Authentication CSR was deleted before it was approved"
+                ))
+            }
+        };
+
+        let conditions = status.conditions.unwrap_or_default();
+        if let Some(denied) = denied_condition(&conditions) {
+            return Err(CsrDenied::new(csr_name, denied).into());
+        }
+        if let Some(cert) = status.certificate {
+            if conditions.iter().any(|c| c.type_.as_str() == APPROVED_TYPE) {
+                let certificate = std::str::from_utf8(&cert.0)?.to_owned();
+                return Ok((certificate, cert_bundle.serialize_private_key_pem()));
+            }
+        }
+
+        info!("Got modified event, but CSR for authentication certs is not currently approved, {:?} elapsed", start.elapsed());
+    }
+
+    Err(anyhow::anyhow!(
+        "Authentication certificates were never approved"
+    ))
+}
+
+/// Requests a serving certificate from the cluster with a CSR of the given
+/// name, returning the PEM encoded certificate and private key once the CSR
+/// has been approved. Fails with a [`CsrDenied`] if the CSR is denied.
+pub(crate) async fn request_serving_certificate(
+    config: &KubeletConfig,
+    client: kube::Client,
+    csr_name: &str,
+    notify: impl Fn(String),
+) -> anyhow::Result<(String, String)> {
+    let cert_bundle = gen_tls_cert(config)?;
+
     let csrs: Api<CertificateSigningRequest> = Api::all(client);
     let csr_json = serde_json::json!({
         "apiVersion": "certificates.k8s.io/v1beta1",
@@ -192,7 +257,7 @@ async fn bootstrap_tls(
 
     csrs.create(&PostParams::default(), &post_data).await?;
 
-    notify(awaiting_user_csr_approval("TLS", &csr_name));
+    notify(awaiting_user_csr_approval("TLS", csr_name));
 
     // Wait for CSR signing
     let inf = watcher(
@@ -221,13 +286,15 @@ async fn bootstrap_tls(
             }
         };
 
+        let conditions = status.conditions.unwrap_or_default();
+        if let Some(denied) = denied_condition(&conditions) {
+            return Err(CsrDenied::new(csr_name, denied).into());
+        }
         if let Some(cert) = status.certificate {
-            if let Some(v) = status.conditions {
-                if v.into_iter().any(|c| c.type_.as_str() == APPROVED_TYPE) {
-                    certificate = std::str::from_utf8(&cert.0)?.to_owned();
-                    got_cert = true;
-                    break;
-                }
+            if conditions.iter().any(|c| c.type_.as_str() == APPROVED_TYPE) {
+                certificate = std::str::from_utf8(&cert.0)?.to_owned();
+                got_cert = true;
+                break;
             }
         }
         info!("Got modified event, but CSR for serving certs is not currently approved, {:?} remaining", start.elapsed());
@@ -239,17 +306,17 @@ async fn bootstrap_tls(
         ));
     }
 
-    let private_key = cert_bundle.serialize_private_key_pem();
-    debug!(
-        "Got certificate from API, writing cert to {:?} and private key to {:?}",
-        config.server_config.cert_file, config.server_config.private_key_file
-    );
-    write(&config.server_config.cert_file, &certificate).await?;
-    write(&config.server_config.private_key_file, &private_key).await?;
-
-    notify(completed_csr_approval("TLS"));
+    Ok((certificate, cert_bundle.serialize_private_key_pem()))
+}
 
-    Ok(())
+/// The condition saying a CSR was denied, or that the signer failed to sign
+/// it, if it has one
+fn denied_condition(
+    conditions: &[CertificateSigningRequestCondition],
+) -> Option<&CertificateSigningRequestCondition> {
+    conditions
+        .iter()
+        .find(|c| c.type_ == DENIED_TYPE || c.type_ == FAILED_TYPE)
 }
 
 fn awaiting_user_csr_approval(cert_description: &str, csr_name: &str) -> String {
@@ -309,10 +376,13 @@ fn gen_tls_cert(config: &KubeletConfig) -> anyhow::Result<Certificate> {
 
     params.alg = &PKCS_ECDSA_P256_SHA256;
 
-    params.subject_alt_names = vec![
-        SanType::DnsName(config.hostname.clone()),
-        SanType::IpAddress(config.node_ip),
-    ];
+    params.subject_alt_names = vec![SanType::DnsName(config.hostname.clone())];
+    params.subject_alt_names.extend(
+        config
+            .node_ips
+            .iter()
+            .map(|node_ip| SanType::IpAddress(*node_ip)),
+    );
 
     Ok(Certificate::from_params(params)?)
 }
@@ -320,7 +390,7 @@ fn gen_tls_cert(config: &KubeletConfig) -> anyhow::Result<Certificate> {
 fn gen_kubeconfig(
     ca_data: String,
     server: String,
-    client_cert_data: k8s_openapi::ByteString,
+    client_cert: String,
     client_key: String,
 ) -> anyhow::Result<Vec<u8>> {
     let json = serde_json::json!({
@@ -337,7 +407,7 @@ fn gen_kubeconfig(
         "users":[{
             "name": "krustlet",
             "user": {
-                "client-certificate-data": client_cert_data,
+                "client-certificate-data": base64::encode(client_cert.as_bytes()),
                 "client-key-data": base64::encode(client_key.as_bytes())
             }
         }],
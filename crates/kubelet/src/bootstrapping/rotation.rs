@@ -0,0 +1,554 @@
+//! Renewal of the serving and client certificates while the kubelet is
+//! running
+//!
+//! A new serving certificate is requested with a CSR once 80% of the current
+//! one's lifetime has passed, or once it is within the configured threshold
+//! of expiring, and replaces the one the server uses without restarting it.
+//! If the CSR is denied, an event is recorded on the node, and renewal is
+//! tried again with an increasing backoff.
+//!
+//! The client certificate in the kubeconfig is renewed the same way once 80%
+//! of its lifetime has passed. The renewed certificate replaces the current
+//! one wherever the kubeconfig keeps it, and a client using it is sent to
+//! every component that talks to the API server.
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use tokio::fs::{read, rename, write};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use super::{request_client_certificate, request_serving_certificate, CsrDenied};
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+use crate::config::Config as KubeletConfig;
+use crate::kubeconfig::path as kubeconfig_path;
+use crate::pod::event::{record_node_event, EventType};
+use crate::webserver::{certificate_validity, tls_config_with_certificate, SharedTlsConfig};
+
+/// How long to wait before trying again the first time renewing the
+/// certificate fails
+const RETRY_BASE_INTERVAL: Duration = Duration::from_secs(30);
+/// The longest to wait before trying again if renewing the certificate keeps
+/// failing
+const RETRY_MAX_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// The reason of the event recorded when the renewal CSR is denied
+const CSR_DENIED: &str = "ServingCertificateDenied";
+/// The reason of the event recorded when renewing the client certificate
+/// fails
+const ROTATION_FAILED: &str = "NodeCertificateRotationFailed";
+
+/// Renews the serving certificate when 80% of its lifetime has passed, or
+/// when it is within `threshold` of expiring if that is set, replacing the
+/// certificate the server uses with the renewed one. This runs until the
+/// kubelet exits.
+pub(crate) async fn rotate_serving_certificate(
+    config: KubeletConfig,
+    clients: watch::Receiver<kube::Client>,
+    tls_config: SharedTlsConfig,
+    threshold: Option<chrono::Duration>,
+) {
+    let mut backoff = ExponentialBackoffStrategy::new(RETRY_BASE_INTERVAL, RETRY_MAX_INTERVAL);
+    loop {
+        let (not_before, not_after) = match read(&config.server_config.cert_file)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|certificate| certificate_validity(&certificate))
+        {
+            Ok(validity) => validity,
+            Err(e) => {
+                let retry = backoff.next_duration();
+                warn!(
+                    "Unable to read validity of serving certificate {:?}, retrying in {:?}: {:?}",
+                    config.server_config.cert_file, retry, e
+                );
+                tokio::time::sleep(retry).await;
+                continue;
+            }
+        };
+        let wait = renewal_wait(not_before, not_after, threshold, Utc::now());
+        info!(
+            "Serving certificate expires at {}, renewing in {:?}",
+            not_after, wait
+        );
+        tokio::time::sleep(wait).await;
+
+        let client = clients.borrow().clone();
+        match renew(&config, client.clone(), &tls_config).await {
+            Ok(()) => {
+                info!("Renewed serving certificate");
+                backoff.reset();
+            }
+            Err(e) => {
+                let retry = backoff.next_duration();
+                warn!(
+                    "Unable to renew serving certificate, retrying in {:?}: {:?}",
+                    retry, e
+                );
+                if let Some(denied) = e.downcast_ref::<CsrDenied>() {
+                    let message = format!(
+                        "Serving certificate renewal was {}, retrying in {:?}: {}",
+                        denied.condition.to_lowercase(),
+                        retry,
+                        denied
+                    );
+                    if let Err(e) = record_node_event(
+                        &client,
+                        &config.node_name,
+                        EventType::Warning,
+                        CSR_DENIED,
+                        &message,
+                    )
+                    .await
+                    {
+                        warn!("Unable to record denied serving certificate: {:?}", e);
+                    }
+                }
+                tokio::time::sleep(retry).await;
+            }
+        }
+    }
+}
+
+/// Renews the client certificate of the kubeconfig's current user when 80% of
+/// its lifetime has passed, replacing it where the kubeconfig keeps it and
+/// sending a client that uses the renewed certificate on `clients`. Failed
+/// renewals are retried with an increasing backoff, recording an event on the
+/// node each time, so that they are noticed well before the certificate
+/// expires. This runs until the kubelet exits.
+pub(crate) async fn rotate_client_certificate(
+    config: KubeletConfig,
+    clients: watch::Sender<kube::Client>,
+) {
+    let mut backoff = ExponentialBackoffStrategy::new(RETRY_BASE_INTERVAL, RETRY_MAX_INTERVAL);
+    let mut client = clients.borrow().clone();
+    loop {
+        let (not_before, not_after) = match ClientCredentials::load()
+            .await
+            .and_then(|credentials| certificate_validity(&credentials.certificate))
+        {
+            Ok(validity) => validity,
+            Err(e) => {
+                let retry = backoff.next_duration();
+                warn!(
+                    "Unable to read validity of client certificate, retrying in {:?}: {:?}",
+                    retry, e
+                );
+                tokio::time::sleep(retry).await;
+                continue;
+            }
+        };
+        let wait = renewal_wait(not_before, not_after, None, Utc::now());
+        info!(
+            "Client certificate expires at {}, renewing in {:?}",
+            not_after, wait
+        );
+        tokio::time::sleep(wait).await;
+
+        match renew_client_certificate(&config, client.clone()).await {
+            Ok(renewed) => {
+                info!("Renewed client certificate");
+                backoff.reset();
+                client = renewed.clone();
+                // This only fails once every component has exited
+                let _ = clients.send(renewed);
+            }
+            Err(e) => {
+                let retry = backoff.next_duration();
+                warn!(
+                    "Unable to renew client certificate, retrying in {:?}: {:?}",
+                    retry, e
+                );
+                let message = format!(
+                    "Client certificate expiring at {} could not be renewed, retrying in {:?}: {:#}",
+                    not_after, retry, e
+                );
+                if let Err(e) = record_node_event(
+                    &client,
+                    &config.node_name,
+                    EventType::Warning,
+                    ROTATION_FAILED,
+                    &message,
+                )
+                .await
+                {
+                    warn!(
+                        "Unable to record failed client certificate rotation: {:?}",
+                        e
+                    );
+                }
+                tokio::time::sleep(retry).await;
+            }
+        }
+    }
+}
+
+/// Returns how long to wait before renewing a certificate valid from
+/// `not_before` until `not_after`: until `threshold` before it expires, or
+/// until 80% of its lifetime has passed if there is no threshold
+fn renewal_wait(
+    not_before: DateTime<Utc>,
+    not_after: DateTime<Utc>,
+    threshold: Option<chrono::Duration>,
+    now: DateTime<Utc>,
+) -> Duration {
+    let renew_at = match threshold {
+        Some(threshold) => not_after - threshold,
+        None => not_before + (not_after - not_before) / 5 * 4,
+    };
+    (renew_at - now)
+        .to_std()
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+async fn renew(
+    config: &KubeletConfig,
+    client: kube::Client,
+    tls_config: &SharedTlsConfig,
+) -> anyhow::Result<()> {
+    // CSRs can't be reused, so each renewal needs a new name
+    let csr_name = format!("{}-tls-{}", config.hostname, Utc::now().timestamp());
+    let (certificate, private_key) =
+        request_serving_certificate(config, client, &csr_name, |message| info!("{}", message))
+            .await?;
+    // Check the new certificate can be used before replacing the old one
+    let renewed = tls_config_with_certificate(
+        &config.server_config,
+        certificate.as_bytes(),
+        private_key.as_bytes(),
+    )?;
+
+    debug!(
+        "Got renewed certificate from API, writing cert to {:?} and private key to {:?}",
+        config.server_config.cert_file, config.server_config.private_key_file
+    );
+    write(&config.server_config.cert_file, &certificate).await?;
+    write(&config.server_config.private_key_file, &private_key).await?;
+    *tls_config.write().await = renewed;
+    Ok(())
+}
+
+async fn renew_client_certificate(
+    config: &KubeletConfig,
+    client: kube::Client,
+) -> anyhow::Result<kube::Client> {
+    let credentials = ClientCredentials::load().await?;
+    // CSRs can't be reused, so each renewal needs a new name
+    let csr_name = format!("{}-{}", config.node_name, Utc::now().timestamp());
+    let (certificate, private_key) = request_client_certificate(config, client, &csr_name).await?;
+    // Check the new certificate can be used before replacing the old one
+    let renewed = credentials.client(&certificate, &private_key).await?;
+
+    debug!(
+        "Got renewed client certificate from API, writing it to {:?}",
+        credentials.path
+    );
+    credentials.replace(&certificate, &private_key).await?;
+    Ok(renewed)
+}
+
+/// The client certificate of the kubeconfig's current user, and where it and
+/// its private key are kept
+struct ClientCredentials {
+    /// The path to the kubeconfig
+    path: PathBuf,
+    /// The kubeconfig, as read by the client
+    kubeconfig: Kubeconfig,
+    /// The kubeconfig, with the fields the client doesn't read kept
+    document: serde_yaml::Value,
+    /// The name of the current user
+    user: String,
+    /// The PEM encoded client certificate
+    certificate: Vec<u8>,
+    /// The file the certificate is in, if it isn't in the kubeconfig
+    certificate_file: Option<PathBuf>,
+    /// The file the private key is in, if it isn't in the kubeconfig
+    private_key_file: Option<PathBuf>,
+}
+
+impl ClientCredentials {
+    /// Reads the client certificate from the kubeconfig the kubelet uses
+    async fn load() -> anyhow::Result<Self> {
+        let path = kubeconfig_path().ok_or_else(|| anyhow::anyhow!("Unable to find kubeconfig"))?;
+        Self::load_from(path).await
+    }
+
+    async fn load_from(path: PathBuf) -> anyhow::Result<Self> {
+        let raw = read(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to read kubeconfig {:?}: {}", path, e))?;
+        let kubeconfig: Kubeconfig = serde_yaml::from_slice(&raw)?;
+        let document = serde_yaml::from_slice(&raw)?;
+        let user = kubeconfig
+            .contexts
+            .iter()
+            .find(|context| context.name == kubeconfig.current_context)
+            .map(|context| context.context.user.clone())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unable to find current context {:?} in kubeconfig",
+                    kubeconfig.current_context
+                )
+            })?;
+        let auth_info = kubeconfig
+            .auth_infos
+            .iter()
+            .find(|auth_info| auth_info.name == user)
+            .map(|auth_info| auth_info.auth_info.clone())
+            .ok_or_else(|| anyhow::anyhow!("Unable to find user {:?} in kubeconfig", user))?;
+
+        let (certificate, certificate_file) = match (
+            auth_info.client_certificate_data,
+            auth_info.client_certificate,
+        ) {
+            (Some(data), _) => (base64::decode(data)?, None),
+            (None, Some(file)) => (read(&file).await?, Some(PathBuf::from(file))),
+            (None, None) => {
+                return Err(anyhow::anyhow!(
+                    "User {:?} in kubeconfig doesn't authenticate with a client certificate",
+                    user
+                ))
+            }
+        };
+        let private_key_file = match auth_info.client_key_data {
+            Some(_) => None,
+            None => auth_info.client_key.map(PathBuf::from),
+        };
+
+        Ok(ClientCredentials {
+            path,
+            kubeconfig,
+            document,
+            user,
+            certificate,
+            certificate_file,
+            private_key_file,
+        })
+    }
+
+    /// Returns a client that authenticates with the given PEM encoded
+    /// certificate and private key
+    async fn client(&self, certificate: &str, private_key: &str) -> anyhow::Result<kube::Client> {
+        let mut kubeconfig = self.kubeconfig.clone();
+        for named in kubeconfig.auth_infos.iter_mut() {
+            if named.name == self.user {
+                named.auth_info.client_certificate = None;
+                named.auth_info.client_certificate_data =
+                    Some(base64::encode(certificate.as_bytes()));
+                named.auth_info.client_key = None;
+                named.auth_info.client_key_data = Some(base64::encode(private_key.as_bytes()));
+            }
+        }
+        let config =
+            kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?;
+        Ok(kube::Client::try_from(config)?)
+    }
+
+    /// Replaces the certificate and private key, in their files or in the
+    /// kubeconfig, with the given PEM encoded ones
+    async fn replace(&self, certificate: &str, private_key: &str) -> anyhow::Result<()> {
+        let mut document = self.document.clone();
+        let mut kubeconfig_changed = false;
+        match &self.private_key_file {
+            Some(file) => write_atomically(file, private_key.as_bytes()).await?,
+            None => {
+                self.set_user_field(&mut document, "client-key-data", private_key)?;
+                kubeconfig_changed = true;
+            }
+        }
+        match &self.certificate_file {
+            Some(file) => write_atomically(file, certificate.as_bytes()).await?,
+            None => {
+                self.set_user_field(&mut document, "client-certificate-data", certificate)?;
+                kubeconfig_changed = true;
+            }
+        }
+        if kubeconfig_changed {
+            write_atomically(&self.path, &serde_yaml::to_vec(&document)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets a field of the current user in the kubeconfig to `pem`, base64
+    /// encoded
+    fn set_user_field(
+        &self,
+        document: &mut serde_yaml::Value,
+        field: &str,
+        pem: &str,
+    ) -> anyhow::Result<()> {
+        let user = document
+            .get_mut("users")
+            .and_then(|users| users.as_sequence_mut())
+            .and_then(|users| {
+                users.iter_mut().find(|user| {
+                    user.get("name").and_then(|name| name.as_str()) == Some(self.user.as_str())
+                })
+            })
+            .and_then(|user| user.get_mut("user"))
+            .and_then(|user| user.as_mapping_mut())
+            .ok_or_else(|| anyhow::anyhow!("Unable to find user {:?} in kubeconfig", self.user))?;
+        user.insert(field.into(), base64::encode(pem.as_bytes()).into());
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` by writing `contents` to a file beside it and
+/// renaming that over it, so that the file is never seen partly written
+async fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    write(&temporary, contents).await?;
+    if let Ok(metadata) = tokio::fs::metadata(path).await {
+        tokio::fs::set_permissions(&temporary, metadata.permissions()).await?;
+    }
+    rename(&temporary, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kubeconfig(user: &str) -> String {
+        format!(
+            r#"apiVersion: v1
+kind: Config
+clusters:
+- name: krustlet
+  cluster:
+    server: https://127.0.0.1:6443
+    tls-server-name: kubernetes
+contexts:
+- name: krustlet
+  context:
+    cluster: krustlet
+    user: krustlet
+current-context: krustlet
+users:
+- name: admin
+  user:
+    token: not-this-one
+- name: krustlet
+  user:
+{}
+"#,
+            user
+        )
+    }
+
+    #[tokio::test]
+    async fn client_certificates_in_the_kubeconfig_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kubeconfig");
+        let user = format!(
+            "    client-certificate-data: {}\n    client-key-data: {}",
+            base64::encode("old cert"),
+            base64::encode("old key")
+        );
+        write(&path, kubeconfig(&user)).await.unwrap();
+
+        let credentials = ClientCredentials::load_from(path.clone()).await.unwrap();
+        assert_eq!(credentials.certificate, b"old cert");
+        credentials.replace("new cert", "new key").await.unwrap();
+
+        let replaced = ClientCredentials::load_from(path).await.unwrap();
+        assert_eq!(replaced.certificate, b"new cert");
+        let user = &replaced.document["users"][1]["user"];
+        assert_eq!(
+            user["client-key-data"].as_str(),
+            Some(base64::encode("new key").as_str())
+        );
+        // Fields the client doesn't read are kept
+        assert_eq!(
+            replaced.document["clusters"][0]["cluster"]["tls-server-name"].as_str(),
+            Some("kubernetes")
+        );
+        assert_eq!(
+            replaced.document["users"][0]["user"]["token"].as_str(),
+            Some("not-this-one")
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificate_files_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kubeconfig");
+        let certificate_file = dir.path().join("client.crt");
+        let private_key_file = dir.path().join("client.key");
+        write(&certificate_file, "old cert").await.unwrap();
+        write(&private_key_file, "old key").await.unwrap();
+        let user = format!(
+            "    client-certificate: {}\n    client-key: {}",
+            certificate_file.display(),
+            private_key_file.display()
+        );
+        let original = kubeconfig(&user);
+        write(&path, &original).await.unwrap();
+
+        let credentials = ClientCredentials::load_from(path.clone()).await.unwrap();
+        assert_eq!(credentials.certificate, b"old cert");
+        credentials.replace("new cert", "new key").await.unwrap();
+
+        assert_eq!(read(&certificate_file).await.unwrap(), b"new cert");
+        assert_eq!(read(&private_key_file).await.unwrap(), b"new key");
+        assert_eq!(read(&path).await.unwrap(), original.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn users_without_client_certificates_are_not_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kubeconfig");
+        write(&path, kubeconfig("    token: abc")).await.unwrap();
+        assert!(ClientCredentials::load_from(path).await.is_err());
+    }
+
+    #[test]
+    fn certificates_are_renewed_at_the_threshold() {
+        let now = Utc::now();
+        let issued = now - chrono::Duration::days(300);
+        let threshold = Some(chrono::Duration::days(30));
+        assert_eq!(
+            renewal_wait(issued, now + chrono::Duration::days(31), threshold, now),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert_eq!(
+            renewal_wait(issued, now + chrono::Duration::days(29), threshold, now),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            renewal_wait(issued, now - chrono::Duration::days(1), threshold, now),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn certificates_are_renewed_after_80_percent_of_their_lifetime() {
+        let now = Utc::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(
+            renewal_wait(now, now + chrono::Duration::days(10), None, now),
+            day * 8
+        );
+        assert_eq!(
+            renewal_wait(
+                now - chrono::Duration::days(5),
+                now + chrono::Duration::days(5),
+                None,
+                now
+            ),
+            day * 3
+        );
+        assert_eq!(
+            renewal_wait(
+                now - chrono::Duration::days(9),
+                now + chrono::Duration::days(1),
+                None,
+                now
+            ),
+            Duration::from_secs(0)
+        );
+    }
+}
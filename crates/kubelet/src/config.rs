@@ -5,14 +5,21 @@
 //!
 //! * [`Config::default_config`] - use the defaults for everything
 //! * [`Config::new_from_file`] - use the values in the specified file
+//! * [`Config::new_from_kubelet_config_file`] - use the values in the specified
+//!   `KubeletConfiguration` file
 //! * [`Config::new_from_flags`] - use the values specified on the command line or in
 //!   environment variables (requires you to turn on the "cli" feature)
 //! * [`Config::new_from_file_and_flags`] - use the values specified on the command line
 //!   or in environment variables, but falling back to the specified configuration file
 //!   (requires you to turn on the "cli" feature)
+//!
+//! When using flags, a file in the standard Kubernetes `KubeletConfiguration`
+//! format (see [`KubeletConfig`]) can also be given with the `--config` flag.
+//! Flags and environment variables take precedence over the values in it.
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[cfg(any(feature = "cli", feature = "docs"))]
 use std::iter::FromIterator;
@@ -22,11 +29,52 @@ use structopt::StructOpt;
 
 use std::collections::HashMap;
 
+use k8s_openapi::api::core::v1::Taint;
 use serde::Deserialize;
 
+use crate::cpu_manager::CpuManagerPolicy;
+use crate::feature_gate::FeatureGates;
+use crate::memory_manager::MemoryManagerPolicy;
+use crate::topology_manager::TopologyManagerPolicy;
+
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
+/// The default minimum interval between image pull progress updates
+pub(crate) const DEFAULT_PULL_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// The default interval at which the conditions of a running pod's readiness
+/// gates are checked
+pub(crate) const DEFAULT_READINESS_GATE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_NODE_STATUS_UPDATE_FREQUENCY: Duration = Duration::from_secs(5 * 60);
+/// The default duration of the node's lease, which is renewed every quarter
+/// of it
+const DEFAULT_NODE_LEASE_DURATION: Duration = Duration::from_secs(40);
+/// The default interval at which CPU usage is checked against CPU limits
+const DEFAULT_CPU_LIMIT_TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// The default interval at which the ephemeral storage used by pods is
+/// checked against their limits
+const DEFAULT_EPHEMERAL_STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// By default pods are given their own termination grace periods when the
+/// node shuts down
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(0);
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS: Duration = Duration::from_secs(0);
+/// By default each kind of stream may have this many open at once, which
+/// is plenty for people, but not for runaway clients
+const DEFAULT_MAX_LOG_FOLLOWS: usize = 256;
+const DEFAULT_MAX_EXEC_SESSIONS: usize = 128;
+const DEFAULT_MAX_PORT_FORWARDS: usize = 128;
+/// How long exec and attach sessions may go without traffic by default, as
+/// for other kubelets
+const DEFAULT_STREAMING_CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(4 * 60 * 60);
+const DEFAULT_CONTAINER_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_CONTAINER_LOG_MAX_FILES: usize = 5;
+/// The default number of images reported in the node's status, as for
+/// other kubelets
+const DEFAULT_NODE_STATUS_MAX_IMAGES: usize = 50;
+const DEFAULT_OIDC_USERNAME_CLAIM: &str = "sub";
+/// The API version of the `KubeletConfiguration` files that can be loaded
+const KUBELET_CONFIG_API_VERSION: &str = "kubelet.config.k8s.io/v1beta1";
+const KUBELET_CONFIG_KIND: &str = "KubeletConfiguration";
 
 /// The configuration needed for a kubelet to run properly.
 ///
@@ -38,30 +86,133 @@ const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
 /// of the default values set.
 #[derive(Clone, Debug)]
 pub struct Config {
-    /// The ip address the node is exposed on
+    /// The ip address the node is exposed on. On dual-stack nodes, this is
+    /// the primary one.
     pub node_ip: IpAddr,
+    /// The ip addresses the node is exposed on, with `node_ip` first. There
+    /// is at most one of each IP family.
+    pub node_ips: Vec<IpAddr>,
     /// The hostname of the node
     pub hostname: String,
     /// The node's name
     pub node_name: String,
+    /// The ID the node's cloud provider knows it by, set as the node's
+    /// `spec.providerID` when it registers if it has none
+    pub provider_id: Option<String>,
     /// The Kubelet server configuration
     pub server_config: ServerConfig,
     /// The directory where the Kubelet will store data
     pub data_dir: PathBuf,
-    /// Labels to add when registering the node in the cluster
+    /// Labels to add when registering the node in the cluster. If the node
+    /// already exists, they are added to it, and take the place of labels
+    /// with the same keys.
     pub node_labels: HashMap<String, String>,
+    /// Taints to add when registering the node in the cluster. Providers
+    /// can replace them with taints of their own with the same key and
+    /// effect.
+    pub register_with_taints: Vec<Taint>,
     /// The maximum pods for this kubelet (reported to apiserver)
     pub max_pods: u16,
     /// The location of the tls bootstrapping file
     pub bootstrap_file: PathBuf,
+    /// Whether to request a new client certificate from the cluster when
+    /// 80% of the lifetime of the one in the kubeconfig has passed
+    pub rotate_certificates: bool,
     /// Whether to allow modules to be loaded directly from local
     /// filesystem paths, as well as from registries
     pub allow_local_modules: bool,
+    /// Whether to back secret volumes, and projected volumes with secrets or
+    /// service account tokens, with memory, so that their files are never
+    /// written to the node's disk
+    pub secrets_in_memory: bool,
+    /// Whether to register the node again if it is deleted from the API
+    /// server while the kubelet runs. Turn this off if deleting the node
+    /// should take it out of the cluster until the kubelet restarts.
+    pub reregister_node: bool,
+    /// Whether to delete the node from the API server once its pods have
+    /// been drained when the kubelet shuts down, rather than leaving it
+    /// `NotReady`
+    pub deregister_on_shutdown: bool,
     /// Registries that should be accessed using HTTP instead of
     /// HTTPS.
     pub insecure_registries: Option<Vec<String>>,
+    /// The proxy to use when pulling from registries. If not set, the
+    /// standard proxy environment variables are used.
+    pub registry_proxy: Option<String>,
+    /// The PEM encoded public key used to verify the signatures of pulled
+    /// modules. If not set, signatures are not verified.
+    pub image_verification_key_file: Option<PathBuf>,
+    /// The minimum interval between updates to a container's status while
+    /// its image is being pulled
+    pub pull_progress_interval: Duration,
+    /// The interval at which the conditions of a running pod's readiness
+    /// gates are checked
+    pub readiness_gate_poll_interval: Duration,
+    /// The Docker config file whose `credHelpers` and `credsStore` select the
+    /// credential helpers used to authenticate to registries. If not set,
+    /// credential helpers are not used.
+    pub docker_config_file: Option<PathBuf>,
+    /// How often the node's status is updated when nothing it reports has
+    /// changed
+    pub node_status_update_frequency: Duration,
+    /// How long the node's lease lasts. It is renewed every quarter of this.
+    pub node_lease_duration: Duration,
+    /// The hard eviction thresholds, by eviction signal. For example,
+    /// `memory.available` mapped to `100Mi`
+    pub eviction_hard: HashMap<String, String>,
+    /// The resources reserved for the host's own processes, which aren't
+    /// allocatable to pods. For example, `memory` mapped to `256Mi`. Only
+    /// `cpu`, `memory` and `ephemeral-storage` can be reserved.
+    pub system_reserved: HashMap<String, String>,
+    /// The resources reserved for the kubelet, which aren't allocatable to
+    /// pods, like [`Config::system_reserved`]
+    pub kube_reserved: HashMap<String, String>,
+    /// Whether each feature gate is enabled. Features are disabled unless
+    /// they are enabled here
+    pub feature_gates: FeatureGates,
+    /// The memory limit, in bytes, of containers that do not set
+    /// `resources.limits.memory`. If not set, their memory is unlimited.
+    pub default_container_memory_limit: Option<u64>,
+    /// How often the CPU usage of containers with CPU limits is checked.
+    /// Containers over their limit are paused for one interval.
+    pub cpu_limit_tick_interval: Duration,
+    /// How the CPU manager assigns CPUs to containers
+    pub cpu_manager_policy: CpuManagerPolicy,
+    /// How the memory manager places the memory of containers on NUMA nodes
+    pub memory_manager_policy: MemoryManagerPolicy,
+    /// How the topology manager aligns the resources of pods on NUMA nodes
+    pub topology_manager_policy: TopologyManagerPolicy,
+    /// How often the ephemeral storage used by pods with ephemeral storage
+    /// limits is measured. Pods over their limit are evicted.
+    pub ephemeral_storage_check_interval: Duration,
+    /// How long the node waits for its pods to stop when it shuts down. A
+    /// pod is given the smaller of this and its own termination grace
+    /// period. If zero, pods are given their own termination grace periods.
+    pub shutdown_grace_period: Duration,
+    /// The part of `shutdown_grace_period` kept for system-critical pods,
+    /// which are stopped after the other pods
+    pub shutdown_grace_period_critical_pods: Duration,
+    /// Whether to delay shutdowns of the host with a systemd-logind
+    /// inhibitor lock until the node's pods have been drained. This needs
+    /// the `systemd` feature.
+    pub shutdown_inhibitor: bool,
+    /// The size, in bytes, a container's log file can grow to before it is
+    /// rotated
+    pub container_log_max_size: u64,
+    /// The most log files, including the one being written, to keep for
+    /// each container. The oldest are deleted when logs are rotated.
+    pub container_log_max_files: usize,
+    /// The most images to report in the node's status, the largest first.
+    /// `None` reports every image in the module store.
+    pub node_status_max_images: Option<usize>,
     /// The directory kubelet should watch for new plugin sockets
     pub plugins_dir: PathBuf,
+    /// The directory device plugins register in, through the kubelet socket
+    /// created there
+    pub device_plugins_dir: PathBuf,
+    /// The file to record each state pods' state machines enter in, to
+    /// replay them later. States aren't recorded if this is `None`.
+    pub record_transitions: Option<PathBuf>,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -74,6 +225,84 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// Path to the CA certificates that client certificates must be signed
+    /// by. If set, clients may authenticate with client certificates.
+    pub client_ca_file: Option<PathBuf>,
+    /// Whether to request a new TLS certificate from the cluster when 80% of
+    /// the current one's lifetime has passed
+    pub rotate_certificates: bool,
+    /// How many days before the TLS certificate expires to request a new one
+    /// from the cluster. If set, the certificate is renewed then rather than
+    /// when 80% of its lifetime has passed, even if `rotate_certificates` is
+    /// false.
+    pub renewal_threshold_days: Option<u64>,
+    /// The OpenID Connect provider that bearer tokens are validated against.
+    /// If set, every request must present a client certificate or a valid
+    /// token.
+    pub oidc: Option<OidcConfig>,
+    /// Whether bearer tokens are validated by the API server with
+    /// `TokenReview`s
+    pub authentication_token_webhook: bool,
+    /// Whether requests from clients that don't authenticate are answered.
+    /// If not, they are only answered for `/healthz` and `/readyz`.
+    pub anonymous_auth: bool,
+    /// How authenticated requests are authorized
+    pub authorization_mode: AuthorizationMode,
+    /// The port that `/healthz`, `/readyz`, `/pods` and the node's resource
+    /// usage are served on without TLS or authentication. 0 disables it.
+    pub read_only_port: u16,
+    /// The most logs that may be followed at once
+    pub max_log_follows: usize,
+    /// The most exec and attach sessions that may be open at once
+    pub max_exec_sessions: usize,
+    /// The most port forwarding connections that may be open at once
+    pub max_port_forwards: usize,
+    /// How long exec and attach sessions may go without traffic in either
+    /// direction before they are closed. 0 never closes them.
+    pub streaming_connection_idle_timeout: Duration,
+}
+
+/// How requests to the Kubelet server are authorized.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthorizationMode {
+    /// Every request is allowed
+    AlwaysAllow,
+    /// Each request is allowed only if the API server allows it in response
+    /// to a `SubjectAccessReview`
+    Webhook,
+}
+
+impl std::str::FromStr for AuthorizationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "AlwaysAllow" => Ok(AuthorizationMode::AlwaysAllow),
+            "Webhook" => Ok(AuthorizationMode::Webhook),
+            _ => Err(anyhow::anyhow!(
+                "unknown authorization mode {:?}: expected AlwaysAllow or Webhook",
+                s
+            )),
+        }
+    }
+}
+
+/// The configuration for authenticating Kubelet server clients with OpenID
+/// Connect ID tokens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OidcConfig {
+    /// The URL of the provider, which must match the `iss` claim of tokens
+    pub issuer_url: String,
+    /// The client ID that tokens must be issued for in their `aud` claim
+    pub client_id: String,
+    /// The claim to use as the user name
+    pub username_claim: String,
+    /// The claim to use as the user's groups. If not set, users have no
+    /// groups.
+    pub groups_claim: Option<String>,
+    /// Path to the CA certificates that the provider's certificate is signed
+    /// by. If not set, the host's root certificates are used.
+    pub ca_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -84,19 +313,25 @@ struct ConfigBuilder {
     #[serde(
         default,
         rename = "nodeIP",
-        deserialize_with = "try_deserialize_ip_addr"
+        deserialize_with = "try_deserialize_ip_addrs"
     )]
-    pub node_ip: Option<anyhow::Result<IpAddr>>,
+    pub node_ip: Option<anyhow::Result<Vec<IpAddr>>>,
     #[serde(default, rename = "hostname")]
     pub hostname: Option<String>,
     #[serde(default, rename = "nodeName")]
     pub node_name: Option<String>,
+    #[serde(default, rename = "providerID")]
+    pub provider_id: Option<String>,
     #[serde(default, rename = "dataDir")]
     pub data_dir: Option<PathBuf>,
     #[serde(default, rename = "bootstrapFile")]
     pub bootstrap_file: Option<PathBuf>,
+    #[serde(default, rename = "rotateClientCertificates")]
+    pub rotate_certificates: Option<bool>,
     #[serde(default, rename = "nodeLabels")]
     pub node_labels: Option<HashMap<String, String>>,
+    #[serde(default, rename = "registerWithTaints")]
+    pub register_with_taints: Option<Vec<Taint>>,
     #[serde(default, rename = "maxPods", deserialize_with = "try_deserialize_u16")]
     pub max_pods: Option<anyhow::Result<u16>>,
     #[serde(
@@ -115,12 +350,100 @@ struct ConfigBuilder {
     pub server_tls_cert_file: Option<PathBuf>,
     #[serde(default, rename = "tlsPrivateKeyFile")]
     pub server_tls_private_key_file: Option<PathBuf>,
+    #[serde(default, rename = "clientCAFile")]
+    pub server_client_ca_file: Option<PathBuf>,
+    #[serde(default, rename = "rotateCertificates")]
+    pub server_rotate_certificates: Option<bool>,
+    #[serde(default, rename = "renewalThresholdDays")]
+    pub server_renewal_threshold_days: Option<u64>,
+    #[serde(default, rename = "oidcIssuerUrl")]
+    pub server_oidc_issuer_url: Option<String>,
+    #[serde(default, rename = "oidcClientId")]
+    pub server_oidc_client_id: Option<String>,
+    #[serde(default, rename = "oidcUsernameClaim")]
+    pub server_oidc_username_claim: Option<String>,
+    #[serde(default, rename = "oidcGroupsClaim")]
+    pub server_oidc_groups_claim: Option<String>,
+    #[serde(default, rename = "oidcCAFile")]
+    pub server_oidc_ca_file: Option<PathBuf>,
+    #[serde(default, rename = "authenticationTokenWebhook")]
+    pub server_authentication_token_webhook: Option<bool>,
+    #[serde(default, rename = "anonymousAuth")]
+    pub server_anonymous_auth: Option<bool>,
+    #[serde(default, rename = "authorizationMode")]
+    pub server_authorization_mode: Option<String>,
+    #[serde(default, rename = "readOnlyPort")]
+    pub server_read_only_port: Option<u16>,
+    #[serde(default, rename = "maxLogFollows")]
+    pub server_max_log_follows: Option<usize>,
+    #[serde(default, rename = "maxExecSessions")]
+    pub server_max_exec_sessions: Option<usize>,
+    #[serde(default, rename = "maxPortForwards")]
+    pub server_max_port_forwards: Option<usize>,
+    #[serde(default, rename = "streamingConnectionIdleTimeoutSeconds")]
+    pub server_streaming_connection_idle_timeout: Option<u64>,
     #[serde(default, rename = "allowLocalModules")]
     pub allow_local_modules: Option<bool>,
+    #[serde(default, rename = "secretsInMemory")]
+    pub secrets_in_memory: Option<bool>,
+    #[serde(default, rename = "reregisterNode")]
+    pub reregister_node: Option<bool>,
+    #[serde(default, rename = "deregisterOnShutdown")]
+    pub deregister_on_shutdown: Option<bool>,
     #[serde(default, rename = "insecureRegistries")]
     pub insecure_registries: Option<Vec<String>>,
+    #[serde(default, rename = "registryProxy")]
+    pub registry_proxy: Option<String>,
+    #[serde(default, rename = "imageVerificationKeyFile")]
+    pub image_verification_key_file: Option<PathBuf>,
+    #[serde(default, rename = "pullProgressIntervalSeconds")]
+    pub pull_progress_interval: Option<u64>,
+    #[serde(default, rename = "readinessGatePollIntervalSeconds")]
+    pub readiness_gate_poll_interval: Option<u64>,
+    #[serde(default, rename = "dockerConfigFile")]
+    pub docker_config_file: Option<PathBuf>,
+    #[serde(default, rename = "nodeStatusUpdateFrequencySeconds")]
+    pub node_status_update_frequency: Option<u64>,
+    #[serde(default, rename = "nodeLeaseDurationSeconds")]
+    pub node_lease_duration: Option<u64>,
+    #[serde(default, rename = "evictionHard")]
+    pub eviction_hard: Option<HashMap<String, String>>,
+    #[serde(default, rename = "systemReserved")]
+    pub system_reserved: Option<HashMap<String, String>>,
+    #[serde(default, rename = "kubeReserved")]
+    pub kube_reserved: Option<HashMap<String, String>>,
+    #[serde(default, rename = "featureGates")]
+    pub feature_gates: Option<HashMap<String, bool>>,
+    #[serde(default, rename = "defaultContainerMemoryLimit")]
+    pub default_container_memory_limit: Option<String>,
+    #[serde(default, rename = "cpuLimitTickIntervalMilliseconds")]
+    pub cpu_limit_tick_interval: Option<u64>,
+    #[serde(default, rename = "cpuManagerPolicy")]
+    pub cpu_manager_policy: Option<String>,
+    #[serde(default, rename = "memoryManagerPolicy")]
+    pub memory_manager_policy: Option<String>,
+    #[serde(default, rename = "topologyManagerPolicy")]
+    pub topology_manager_policy: Option<String>,
+    #[serde(default, rename = "ephemeralStorageCheckIntervalSeconds")]
+    pub ephemeral_storage_check_interval: Option<u64>,
+    #[serde(default, rename = "shutdownGracePeriodSeconds")]
+    pub shutdown_grace_period: Option<u64>,
+    #[serde(default, rename = "shutdownGracePeriodCriticalPodsSeconds")]
+    pub shutdown_grace_period_critical_pods: Option<u64>,
+    #[serde(default, rename = "shutdownInhibitor")]
+    pub shutdown_inhibitor: Option<bool>,
+    #[serde(default, rename = "containerLogMaxSize")]
+    pub container_log_max_size: Option<String>,
+    #[serde(default, rename = "containerLogMaxFiles")]
+    pub container_log_max_files: Option<usize>,
+    #[serde(default, rename = "nodeStatusMaxImages")]
+    pub node_status_max_images: Option<i32>,
     #[serde(default, rename = "pluginsDir")]
     pub plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "devicePluginsDir")]
+    pub device_plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "recordTransitions")]
+    pub record_transitions: Option<PathBuf>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -130,7 +453,9 @@ struct ConfigBuilderFallbacks {
     cert_path: fn(data_dir: &PathBuf) -> PathBuf,
     key_path: fn(data_dir: &PathBuf) -> PathBuf,
     plugins_dir: fn(data_dir: &PathBuf) -> PathBuf,
+    device_plugins_dir: fn(data_dir: &PathBuf) -> PathBuf,
     node_ip: fn(hostname: &mut String, preferred_ip_family: &IpAddr) -> IpAddr,
+    check_node_ip: fn(ip: &IpAddr) -> anyhow::Result<()>,
 }
 
 impl Config {
@@ -145,17 +470,51 @@ impl Config {
         let cert_file = default_cert_path(&data_dir);
         let private_key_file = default_key_path(&data_dir);
         let plugins_dir = default_plugins_path(&data_dir);
+        let device_plugins_dir = default_device_plugins_path(&data_dir);
+        let node_ip = default_node_ip(&mut hostname.clone(), preferred_ip_family)?;
         Ok(Config {
-            node_ip: default_node_ip(&mut hostname.clone(), preferred_ip_family)?,
+            node_ip,
+            node_ips: vec![node_ip],
             node_name: sanitize_hostname(&hostname),
+            provider_id: None,
             node_labels: HashMap::new(),
+            register_with_taints: vec![],
             hostname,
             data_dir,
             max_pods: DEFAULT_MAX_PODS,
             bootstrap_file: PathBuf::from(BOOTSTRAP_FILE),
+            rotate_certificates: false,
             allow_local_modules: false,
+            secrets_in_memory: false,
+            reregister_node: true,
+            deregister_on_shutdown: false,
             insecure_registries: None,
+            registry_proxy: None,
+            image_verification_key_file: None,
+            pull_progress_interval: DEFAULT_PULL_PROGRESS_INTERVAL,
+            readiness_gate_poll_interval: DEFAULT_READINESS_GATE_POLL_INTERVAL,
+            docker_config_file: None,
+            node_status_update_frequency: DEFAULT_NODE_STATUS_UPDATE_FREQUENCY,
+            node_lease_duration: DEFAULT_NODE_LEASE_DURATION,
+            eviction_hard: HashMap::new(),
+            system_reserved: HashMap::new(),
+            kube_reserved: HashMap::new(),
+            feature_gates: FeatureGates::default(),
+            default_container_memory_limit: None,
+            cpu_limit_tick_interval: DEFAULT_CPU_LIMIT_TICK_INTERVAL,
+            cpu_manager_policy: CpuManagerPolicy::None,
+            memory_manager_policy: MemoryManagerPolicy::None,
+            topology_manager_policy: TopologyManagerPolicy::None,
+            ephemeral_storage_check_interval: DEFAULT_EPHEMERAL_STORAGE_CHECK_INTERVAL,
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
+            shutdown_grace_period_critical_pods: DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS,
+            shutdown_inhibitor: false,
+            container_log_max_size: DEFAULT_CONTAINER_LOG_MAX_SIZE,
+            container_log_max_files: DEFAULT_CONTAINER_LOG_MAX_FILES,
+            node_status_max_images: Some(DEFAULT_NODE_STATUS_MAX_IMAGES),
             plugins_dir,
+            device_plugins_dir,
+            record_transitions: None,
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -164,6 +523,18 @@ impl Config {
                 port: DEFAULT_PORT,
                 cert_file,
                 private_key_file,
+                client_ca_file: None,
+                rotate_certificates: false,
+                renewal_threshold_days: None,
+                oidc: None,
+                authentication_token_webhook: false,
+                anonymous_auth: false,
+                authorization_mode: AuthorizationMode::AlwaysAllow,
+                read_only_port: 0,
+                max_log_follows: DEFAULT_MAX_LOG_FOLLOWS,
+                max_exec_sessions: DEFAULT_MAX_EXEC_SESSIONS,
+                max_port_forwards: DEFAULT_MAX_PORT_FORWARDS,
+                streaming_connection_idle_timeout: DEFAULT_STREAMING_CONNECTION_IDLE_TIMEOUT,
             },
         })
     }
@@ -175,7 +546,9 @@ impl Config {
             cert_path: default_cert_path,
             key_path: default_key_path,
             plugins_dir: default_plugins_path,
+            device_plugins_dir: |data_dir| default_device_plugins_path(data_dir),
             node_ip: |hn, ip| default_node_ip(hn, ip).expect("unable to get default node IP"),
+            check_node_ip: check_local_ip,
             bootstrap_file: || PathBuf::from(BOOTSTRAP_FILE),
         };
         ConfigBuilder::build(builder, fallbacks).unwrap()
@@ -189,14 +562,32 @@ impl Config {
         Config::new_from_builder(builder)
     }
 
+    /// Parses the specified `KubeletConfiguration` file and sets the proper
+    /// defaults. If the file cannot be loaded, this function panics.
+    pub fn new_from_kubelet_config_file(filename: PathBuf) -> Self {
+        let builder = KubeletConfig::from_file(filename)
+            .and_then(KubeletConfig::into_builder)
+            .unwrap();
+        Config::new_from_builder(builder)
+    }
+
     /// Parses all command line flags and sets the proper defaults. The version
     /// of your application should be passed to set the proper version for the CLI
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
     pub fn new_from_flags(version: &str) -> Self {
+        Config::new_from_args(version, std::env::args_os())
+    }
+
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    fn new_from_args<I, T>(version: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
         let app = Opts::clap().version(version);
-        let opts = Opts::from_clap(&app.get_matches());
-        let builder = ConfigBuilder::from_opts(opts);
+        let opts = Opts::from_clap(&app.get_matches_from(args));
+        let builder = ConfigBuilder::from_opts_and_kubelet_config(opts);
         Config::new_from_builder(builder)
     }
 
@@ -213,26 +604,50 @@ impl Config {
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
     pub fn new_from_file_and_flags(version: &str, config_file_path: Option<PathBuf>) -> Self {
+        Config::new_from_file_and_args(version, config_file_path, std::env::args_os())
+    }
+
+    /// Like [`Config::new_from_file_and_flags`], but parses the given
+    /// arguments rather than the arguments the process was started with. The
+    /// first argument is the name of the program.
+    ///
+    /// This allows applications to handle arguments of their own, such as
+    /// subcommands, before passing the rest on.
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+    pub fn new_from_file_and_args<I, T>(
+        version: &str,
+        config_file_path: Option<PathBuf>,
+        args: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
         match config_file_path {
             None => {
                 let default_path = default_config_file_path();
                 if default_path.exists() {
-                    Config::new_from_file_and_flags_impl(version, default_path)
+                    Config::new_from_file_and_args_impl(version, default_path, args)
                 } else {
-                    Config::new_from_flags(version)
+                    Config::new_from_args(version, args)
                 }
             }
-            Some(path) => Config::new_from_file_and_flags_impl(version, path),
+            Some(path) => Config::new_from_file_and_args_impl(version, path, args),
         }
     }
 
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
-    fn new_from_file_and_flags_impl(version: &str, config_file_path: PathBuf) -> Self {
+    fn new_from_file_and_args_impl<I, T>(version: &str, config_file_path: PathBuf, args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
         // TODO: reduce duplication
         let app = Opts::clap().version(version);
-        let opts = Opts::from_clap(&app.get_matches());
-        let cli_builder = ConfigBuilder::from_opts(opts);
+        let opts = Opts::from_clap(&app.get_matches_from(args));
+        let cli_builder = ConfigBuilder::from_opts_and_kubelet_config(opts);
 
         let config_file_builder = ConfigBuilder::from_config_file(config_file_path);
 
@@ -257,34 +672,104 @@ impl ConfigBuilder {
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
     fn from_opts(opts: Opts) -> Self {
-        let node_labels: Vec<(String, String)> = opts
-            .node_labels
-            .iter()
-            .filter_map(|i| split_one_label(i))
-            .collect();
-
         ConfigBuilder {
-            node_ip: ok_result_of(opts.node_ip),
+            node_ip: Some(opts.node_ip).filter(|ips| !ips.is_empty()).map(Ok),
             node_name: opts.node_name,
-            node_labels: if node_labels.is_empty() {
+            provider_id: opts.provider_id,
+            node_labels: if opts.node_labels.is_empty() {
                 None
             } else {
-                Some(HashMap::from_iter(node_labels))
+                Some(HashMap::from_iter(opts.node_labels))
             },
+            register_with_taints: Some(opts.register_with_taints).filter(|t| !t.is_empty()),
             bootstrap_file: Some(opts.bootstrap_file),
+            rotate_certificates: opts.rotate_certificates,
             hostname: opts.hostname,
             data_dir: opts.data_dir,
             max_pods: ok_result_of(opts.max_pods),
             allow_local_modules: opts.allow_local_modules,
+            secrets_in_memory: opts.secrets_in_memory,
+            reregister_node: opts.reregister_node,
+            deregister_on_shutdown: opts.deregister_on_shutdown,
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
+            registry_proxy: opts.registry_proxy,
+            image_verification_key_file: opts.image_verification_key_file,
+            pull_progress_interval: opts.pull_progress_interval,
+            readiness_gate_poll_interval: opts.readiness_gate_poll_interval,
+            docker_config_file: opts.docker_config_file,
+            node_status_update_frequency: opts.node_status_update_frequency,
+            node_lease_duration: opts.node_lease_duration,
+            eviction_hard: if opts.eviction_hard.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(opts.eviction_hard))
+            },
+            system_reserved: if opts.system_reserved.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(opts.system_reserved))
+            },
+            kube_reserved: if opts.kube_reserved.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(opts.kube_reserved))
+            },
+            feature_gates: if opts.feature_gates.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(opts.feature_gates))
+            },
+            default_container_memory_limit: opts.default_container_memory_limit,
+            cpu_limit_tick_interval: opts.cpu_limit_tick_interval,
+            cpu_manager_policy: opts.cpu_manager_policy,
+            memory_manager_policy: opts.memory_manager_policy,
+            topology_manager_policy: opts.topology_manager_policy,
+            ephemeral_storage_check_interval: opts.ephemeral_storage_check_interval,
+            shutdown_grace_period: opts.shutdown_grace_period,
+            shutdown_grace_period_critical_pods: opts.shutdown_grace_period_critical_pods,
+            shutdown_inhibitor: opts.shutdown_inhibitor,
+            container_log_max_size: opts.container_log_max_size,
+            container_log_max_files: opts.container_log_max_files,
+            node_status_max_images: opts.node_status_max_images,
             plugins_dir: opts.plugins_dir,
+            device_plugins_dir: opts.device_plugins_dir,
+            record_transitions: opts.record_transitions,
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            server_client_ca_file: opts.client_ca_file,
+            server_rotate_certificates: opts.rotate_server_certificates,
+            server_renewal_threshold_days: opts.renewal_threshold_days,
+            server_oidc_issuer_url: opts.oidc_issuer_url,
+            server_oidc_client_id: opts.oidc_client_id,
+            server_oidc_username_claim: opts.oidc_username_claim,
+            server_oidc_groups_claim: opts.oidc_groups_claim,
+            server_oidc_ca_file: opts.oidc_ca_file,
+            server_authentication_token_webhook: opts.authentication_token_webhook,
+            server_anonymous_auth: opts.anonymous_auth,
+            server_authorization_mode: opts.authorization_mode,
+            server_read_only_port: opts.read_only_port,
+            server_max_log_follows: opts.max_log_follows,
+            server_max_exec_sessions: opts.max_exec_sessions,
+            server_max_port_forwards: opts.max_port_forwards,
+            server_streaming_connection_idle_timeout: opts.streaming_connection_idle_timeout,
         }
     }
 
+    /// Creates a builder from the flags, falling back to the values in the
+    /// `KubeletConfiguration` file given with `--config`, if any
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    fn from_opts_and_kubelet_config(opts: Opts) -> Self {
+        let kubelet_config_builder = match &opts.config {
+            Some(path) => KubeletConfig::from_file(path)
+                .and_then(KubeletConfig::into_builder)
+                .expect("unable to load kubelet configuration file"),
+            None => ConfigBuilder::default(),
+        };
+        kubelet_config_builder.with_override(ConfigBuilder::from_opts(opts))
+    }
+
     fn from_config_file(config_file_path: PathBuf) -> anyhow::Result<ConfigBuilder> {
         if !config_file_path.exists() {
             return Ok(ConfigBuilder::default());
@@ -305,7 +790,9 @@ impl ConfigBuilder {
         ConfigBuilder {
             node_ip: other.node_ip.or(self.node_ip),
             node_name: other.node_name.or(self.node_name),
+            provider_id: other.provider_id.or(self.provider_id),
             node_labels: other.node_labels.or(self.node_labels),
+            register_with_taints: other.register_with_taints.or(self.register_with_taints),
             hostname: other.hostname.or(self.hostname),
             data_dir: other.data_dir.or(self.data_dir),
             max_pods: other.max_pods.or(self.max_pods),
@@ -313,12 +800,93 @@ impl ConfigBuilder {
             server_port: other.server_port.or(self.server_port),
             server_tls_cert_file: other.server_tls_cert_file.or(self.server_tls_cert_file),
             bootstrap_file: other.bootstrap_file.or(self.bootstrap_file),
+            rotate_certificates: other.rotate_certificates.or(self.rotate_certificates),
             allow_local_modules: other.allow_local_modules.or(self.allow_local_modules),
+            secrets_in_memory: other.secrets_in_memory.or(self.secrets_in_memory),
+            reregister_node: other.reregister_node.or(self.reregister_node),
+            deregister_on_shutdown: other.deregister_on_shutdown.or(self.deregister_on_shutdown),
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
+            registry_proxy: other.registry_proxy.or(self.registry_proxy),
+            image_verification_key_file: other
+                .image_verification_key_file
+                .or(self.image_verification_key_file),
+            pull_progress_interval: other.pull_progress_interval.or(self.pull_progress_interval),
+            readiness_gate_poll_interval: other
+                .readiness_gate_poll_interval
+                .or(self.readiness_gate_poll_interval),
+            docker_config_file: other.docker_config_file.or(self.docker_config_file),
+            node_status_update_frequency: other
+                .node_status_update_frequency
+                .or(self.node_status_update_frequency),
+            node_lease_duration: other.node_lease_duration.or(self.node_lease_duration),
+            eviction_hard: other.eviction_hard.or(self.eviction_hard),
+            system_reserved: other.system_reserved.or(self.system_reserved),
+            kube_reserved: other.kube_reserved.or(self.kube_reserved),
+            feature_gates: other.feature_gates.or(self.feature_gates),
+            default_container_memory_limit: other
+                .default_container_memory_limit
+                .or(self.default_container_memory_limit),
+            cpu_limit_tick_interval: other
+                .cpu_limit_tick_interval
+                .or(self.cpu_limit_tick_interval),
+            cpu_manager_policy: other.cpu_manager_policy.or(self.cpu_manager_policy),
+            memory_manager_policy: other.memory_manager_policy.or(self.memory_manager_policy),
+            topology_manager_policy: other
+                .topology_manager_policy
+                .or(self.topology_manager_policy),
+            ephemeral_storage_check_interval: other
+                .ephemeral_storage_check_interval
+                .or(self.ephemeral_storage_check_interval),
+            shutdown_grace_period: other.shutdown_grace_period.or(self.shutdown_grace_period),
+            shutdown_grace_period_critical_pods: other
+                .shutdown_grace_period_critical_pods
+                .or(self.shutdown_grace_period_critical_pods),
+            shutdown_inhibitor: other.shutdown_inhibitor.or(self.shutdown_inhibitor),
+            container_log_max_size: other.container_log_max_size.or(self.container_log_max_size),
+            container_log_max_files: other
+                .container_log_max_files
+                .or(self.container_log_max_files),
+            node_status_max_images: other.node_status_max_images.or(self.node_status_max_images),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
+            device_plugins_dir: other.device_plugins_dir.or(self.device_plugins_dir),
+            record_transitions: other.record_transitions.or(self.record_transitions),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
+            server_client_ca_file: other.server_client_ca_file.or(self.server_client_ca_file),
+            server_rotate_certificates: other
+                .server_rotate_certificates
+                .or(self.server_rotate_certificates),
+            server_renewal_threshold_days: other
+                .server_renewal_threshold_days
+                .or(self.server_renewal_threshold_days),
+            server_oidc_issuer_url: other.server_oidc_issuer_url.or(self.server_oidc_issuer_url),
+            server_oidc_client_id: other.server_oidc_client_id.or(self.server_oidc_client_id),
+            server_oidc_username_claim: other
+                .server_oidc_username_claim
+                .or(self.server_oidc_username_claim),
+            server_oidc_groups_claim: other
+                .server_oidc_groups_claim
+                .or(self.server_oidc_groups_claim),
+            server_oidc_ca_file: other.server_oidc_ca_file.or(self.server_oidc_ca_file),
+            server_authentication_token_webhook: other
+                .server_authentication_token_webhook
+                .or(self.server_authentication_token_webhook),
+            server_anonymous_auth: other.server_anonymous_auth.or(self.server_anonymous_auth),
+            server_authorization_mode: other
+                .server_authorization_mode
+                .or(self.server_authorization_mode),
+            server_read_only_port: other.server_read_only_port.or(self.server_read_only_port),
+            server_max_log_follows: other.server_max_log_follows.or(self.server_max_log_follows),
+            server_max_exec_sessions: other
+                .server_max_exec_sessions
+                .or(self.server_max_exec_sessions),
+            server_max_port_forwards: other
+                .server_max_port_forwards
+                .or(self.server_max_port_forwards),
+            server_streaming_connection_idle_timeout: other
+                .server_streaming_connection_idle_timeout
+                .or(self.server_streaming_connection_idle_timeout),
         }
     }
 
@@ -331,9 +899,12 @@ impl ConfigBuilder {
         let plugins_dir = self
             .plugins_dir
             .unwrap_or_else(|| (fallbacks.plugins_dir)(&data_dir));
+        let device_plugins_dir = self
+            .device_plugins_dir
+            .unwrap_or_else(|| (fallbacks.device_plugins_dir)(&data_dir));
         let server_addr = self
             .server_addr
-            .unwrap_or(Ok(empty_ip_addr))
+            .transpose()
             .map_err(|e| invalid_config_value_error(e, "server address"))?;
         let server_tls_cert_file = self
             .server_tls_cert_file
@@ -345,10 +916,32 @@ impl ConfigBuilder {
             .server_port
             .unwrap_or(Ok(DEFAULT_PORT))
             .map_err(|e| invalid_config_value_error(e, "server port"))?;
-        let node_ip = self
-            .node_ip
-            .unwrap_or_else(|| Ok((fallbacks.node_ip)(&mut hostname.clone(), &server_addr)))
-            .map_err(|e| invalid_config_value_error(e, "node IP"))?;
+        let node_ips = match self.node_ip {
+            Some(node_ips) => {
+                let node_ips = node_ips
+                    .and_then(validate_node_ips)
+                    .map_err(|e| invalid_config_value_error(e, "node IP"))?;
+                for node_ip in &node_ips {
+                    (fallbacks.check_node_ip)(node_ip)
+                        .map_err(|e| invalid_config_value_error(e, "node IP"))?;
+                }
+                node_ips
+            }
+            None => vec![(fallbacks.node_ip)(
+                &mut hostname.clone(),
+                &server_addr.unwrap_or(empty_ip_addr),
+            )],
+        };
+        let node_ip = node_ips[0];
+        // Nodes with an IPv6 address listen on both families, so that the API
+        // server reaches them over whichever it prefers
+        let server_addr = server_addr.unwrap_or_else(|| {
+            if node_ips.iter().any(IpAddr::is_ipv6) {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                empty_ip_addr
+            }
+        });
         let node_name = self
             .node_name
             .unwrap_or_else(|| sanitize_hostname(&hostname));
@@ -356,21 +949,236 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let node_status_update_frequency = match self.node_status_update_frequency {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid node status update frequency in configuration file: must be at least 1 second"
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => DEFAULT_NODE_STATUS_UPDATE_FREQUENCY,
+        };
+        let node_lease_duration = match self.node_lease_duration {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid node lease duration in configuration file: must be at least 1 second"
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => DEFAULT_NODE_LEASE_DURATION,
+        };
+        let node_labels = self.node_labels.unwrap_or_default();
+        for (key, value) in &node_labels {
+            validate_label(key, value).map_err(|e| invalid_config_value_error(e, "node label"))?;
+        }
+        let register_with_taints = self.register_with_taints.unwrap_or_default();
+        for taint in &register_with_taints {
+            validate_taint(taint).map_err(|e| invalid_config_value_error(e, "taint"))?;
+        }
+        let eviction_hard = self.eviction_hard.unwrap_or_default();
+        validate_eviction_thresholds(&eviction_hard)?;
+        let system_reserved = self.system_reserved.unwrap_or_default();
+        validate_reserved(&system_reserved, "system reserved")?;
+        let kube_reserved = self.kube_reserved.unwrap_or_default();
+        validate_reserved(&kube_reserved, "kube reserved")?;
+        let default_container_memory_limit = self
+            .default_container_memory_limit
+            .map(|q| crate::resources::parse_quantity(&q))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "default container memory limit"))?;
+        let cpu_limit_tick_interval = match self.cpu_limit_tick_interval {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid CPU limit tick interval in configuration file: must be at least 1 millisecond"
+                ))
+            }
+            Some(millis) => Duration::from_millis(millis),
+            None => DEFAULT_CPU_LIMIT_TICK_INTERVAL,
+        };
+        let cpu_manager_policy = self
+            .cpu_manager_policy
+            .map(|policy| policy.parse())
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "CPU manager policy"))?
+            .unwrap_or_default();
+        let memory_manager_policy = self
+            .memory_manager_policy
+            .map(|policy| policy.parse())
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "memory manager policy"))?
+            .unwrap_or_default();
+        let topology_manager_policy = self
+            .topology_manager_policy
+            .map(|policy| policy.parse())
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "topology manager policy"))?
+            .unwrap_or_default();
+        let readiness_gate_poll_interval = match self.readiness_gate_poll_interval {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid readiness gate poll interval in configuration file: must be at least 1 second"
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => DEFAULT_READINESS_GATE_POLL_INTERVAL,
+        };
+        let ephemeral_storage_check_interval = match self.ephemeral_storage_check_interval {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid ephemeral storage check interval in configuration file: must be at least 1 second"
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => DEFAULT_EPHEMERAL_STORAGE_CHECK_INTERVAL,
+        };
+        let shutdown_grace_period = self
+            .shutdown_grace_period
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD);
+        let shutdown_grace_period_critical_pods = self
+            .shutdown_grace_period_critical_pods
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS);
+        if shutdown_grace_period_critical_pods > shutdown_grace_period {
+            return Err(anyhow::anyhow!(
+                "invalid shutdown grace period for critical pods in configuration file: must not be longer than the shutdown grace period"
+            ));
+        }
+        let shutdown_inhibitor = self.shutdown_inhibitor.unwrap_or(false);
+        if shutdown_inhibitor && !cfg!(feature = "systemd") {
+            return Err(anyhow::anyhow!(
+                "the shutdown inhibitor was enabled, but krustlet was built without the systemd feature"
+            ));
+        }
+        let container_log_max_size = match self
+            .container_log_max_size
+            .map(|q| crate::resources::parse_quantity(&q))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "container log max size"))?
+        {
+            Some(0) => {
+                return Err(anyhow::anyhow!(
+                    "invalid container log max size in configuration file: must be at least 1 byte"
+                ))
+            }
+            Some(size) => size,
+            None => DEFAULT_CONTAINER_LOG_MAX_SIZE,
+        };
+        let container_log_max_files = match self.container_log_max_files {
+            Some(files) if files < 2 => {
+                return Err(anyhow::anyhow!(
+                    "invalid container log max files in configuration file: must be at least 2"
+                ))
+            }
+            Some(files) => files,
+            None => DEFAULT_CONTAINER_LOG_MAX_FILES,
+        };
+        let node_status_max_images = match self.node_status_max_images {
+            Some(-1) => None,
+            Some(images) if images < -1 => {
+                return Err(anyhow::anyhow!(
+                    "invalid node status max images in configuration file: must be -1 or more"
+                ))
+            }
+            Some(images) => Some(images as usize),
+            None => Some(DEFAULT_NODE_STATUS_MAX_IMAGES),
+        };
+        let authorization_mode = self
+            .server_authorization_mode
+            .map(|mode| mode.parse())
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "authorization mode"))?
+            .unwrap_or(AuthorizationMode::AlwaysAllow);
+        let oidc = match (self.server_oidc_issuer_url, self.server_oidc_client_id) {
+            (Some(issuer_url), Some(client_id)) => Some(OidcConfig {
+                issuer_url,
+                client_id,
+                username_claim: self
+                    .server_oidc_username_claim
+                    .unwrap_or_else(|| DEFAULT_OIDC_USERNAME_CLAIM.to_owned()),
+                groups_claim: self.server_oidc_groups_claim,
+                ca_file: self.server_oidc_ca_file,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "invalid OIDC configuration: the issuer URL and client ID must be set together"
+                ))
+            }
+        };
 
         Ok(Config {
             node_ip,
+            node_ips,
             node_name,
-            node_labels: self.node_labels.unwrap_or_else(HashMap::new),
+            provider_id: self.provider_id.filter(|id| !id.is_empty()),
+            node_labels,
+            register_with_taints,
             hostname,
             data_dir,
             max_pods,
             bootstrap_file,
+            rotate_certificates: self.rotate_certificates.unwrap_or(false),
             allow_local_modules: self.allow_local_modules.unwrap_or(false),
+            secrets_in_memory: self.secrets_in_memory.unwrap_or(false),
+            reregister_node: self.reregister_node.unwrap_or(true),
+            deregister_on_shutdown: self.deregister_on_shutdown.unwrap_or(false),
             insecure_registries: self.insecure_registries,
+            registry_proxy: self.registry_proxy,
+            image_verification_key_file: self.image_verification_key_file,
+            pull_progress_interval: self
+                .pull_progress_interval
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PULL_PROGRESS_INTERVAL),
+            readiness_gate_poll_interval,
+            docker_config_file: self.docker_config_file,
+            node_status_update_frequency,
+            node_lease_duration,
+            eviction_hard,
+            system_reserved,
+            kube_reserved,
+            feature_gates: FeatureGates::new(self.feature_gates.unwrap_or_default()),
+            default_container_memory_limit,
+            cpu_limit_tick_interval,
+            cpu_manager_policy,
+            memory_manager_policy,
+            topology_manager_policy,
+            ephemeral_storage_check_interval,
+            shutdown_grace_period,
+            shutdown_grace_period_critical_pods,
+            shutdown_inhibitor,
+            container_log_max_size,
+            container_log_max_files,
+            node_status_max_images,
             plugins_dir,
+            device_plugins_dir,
+            record_transitions: self.record_transitions,
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
+                client_ca_file: self.server_client_ca_file,
+                rotate_certificates: self.server_rotate_certificates.unwrap_or(false),
+                renewal_threshold_days: self.server_renewal_threshold_days,
+                oidc,
+                authentication_token_webhook: self
+                    .server_authentication_token_webhook
+                    .unwrap_or(false),
+                anonymous_auth: self.server_anonymous_auth.unwrap_or(false),
+                authorization_mode,
+                read_only_port: self.server_read_only_port.unwrap_or(0),
+                max_log_follows: self
+                    .server_max_log_follows
+                    .unwrap_or(DEFAULT_MAX_LOG_FOLLOWS),
+                max_exec_sessions: self
+                    .server_max_exec_sessions
+                    .unwrap_or(DEFAULT_MAX_EXEC_SESSIONS),
+                max_port_forwards: self
+                    .server_max_port_forwards
+                    .unwrap_or(DEFAULT_MAX_PORT_FORWARDS),
+                streaming_connection_idle_timeout: self
+                    .server_streaming_connection_idle_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_STREAMING_CONNECTION_IDLE_TIMEOUT),
                 addr: server_addr,
                 port: server_port,
             },
@@ -378,6 +1186,284 @@ impl ConfigBuilder {
     }
 }
 
+/// A kubelet configuration file in the standard Kubernetes
+/// `KubeletConfiguration` format, such as:
+///
+/// ```yaml
+/// apiVersion: kubelet.config.k8s.io/v1beta1
+/// kind: KubeletConfiguration
+/// maxPods: 50
+/// nodeStatusUpdateFrequency: 20s
+/// nodeLeaseDurationSeconds: 40
+/// evictionHard:
+///   memory.available: 100Mi
+/// systemReserved:
+///   cpu: 100m
+///   memory: 256Mi
+/// featureGates:
+///   WasiSockets: true
+/// ```
+///
+/// Only the fields krustlet supports are read. Other fields, which are used
+/// by other kubelets, are ignored.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KubeletConfig {
+    /// The API version of the file. This must be `kubelet.config.k8s.io/v1beta1`
+    pub api_version: String,
+    /// The kind of the file. This must be `KubeletConfiguration`
+    pub kind: String,
+    /// The address the Kubelet server listens on
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    /// The port the Kubelet server listens on
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// The port read-only endpoints are served on without authentication,
+    /// or 0 to not serve them
+    #[serde(default)]
+    pub read_only_port: Option<u16>,
+    /// How long exec and attach sessions may go without traffic before they
+    /// are closed, as a duration such as `4h`, or 0 to never close them
+    #[serde(default)]
+    pub streaming_connection_idle_timeout: Option<String>,
+    /// The path to the Kubelet server's TLS certificate
+    #[serde(default)]
+    pub tls_cert_file: Option<PathBuf>,
+    /// The path to the Kubelet server's TLS private key
+    #[serde(default)]
+    pub tls_private_key_file: Option<PathBuf>,
+    /// Whether to request the Kubelet server's TLS certificate from the
+    /// cluster, and a new one when 80% of its lifetime has passed
+    #[serde(default, rename = "serverTLSBootstrap")]
+    pub server_tls_bootstrap: Option<bool>,
+    /// Whether to request a new client certificate from the cluster when
+    /// 80% of the current one's lifetime has passed
+    #[serde(default)]
+    pub rotate_certificates: Option<bool>,
+    /// How clients of the Kubelet server are authenticated
+    #[serde(default)]
+    pub authentication: KubeletAuthentication,
+    /// How requests to the Kubelet server are authorized
+    #[serde(default)]
+    pub authorization: KubeletAuthorization,
+    /// The maximum pods for this kubelet
+    #[serde(default)]
+    pub max_pods: Option<u16>,
+    /// The ID the node's cloud provider knows it by
+    #[serde(default, rename = "providerID")]
+    pub provider_id: Option<String>,
+    /// How often the node's status is updated when nothing it reports has
+    /// changed, as a duration such as `10s` or `1m30s`
+    #[serde(default)]
+    pub node_status_update_frequency: Option<String>,
+    /// How long the node's lease lasts, in seconds
+    #[serde(default)]
+    pub node_lease_duration_seconds: Option<u64>,
+    /// Taints to add when registering the node in the cluster
+    #[serde(default)]
+    pub register_with_taints: Vec<Taint>,
+    /// The hard eviction thresholds, by eviction signal
+    #[serde(default)]
+    pub eviction_hard: HashMap<String, String>,
+    /// The resources reserved for the host's own processes, by resource name
+    #[serde(default)]
+    pub system_reserved: HashMap<String, String>,
+    /// The resources reserved for the kubelet, by resource name
+    #[serde(default)]
+    pub kube_reserved: HashMap<String, String>,
+    /// Whether each feature gate is enabled, by feature name
+    #[serde(default)]
+    pub feature_gates: HashMap<String, bool>,
+    /// The size a container log file can grow to before it is rotated, as a
+    /// quantity such as `10Mi`
+    #[serde(default)]
+    pub container_log_max_size: Option<String>,
+    /// The most log files to keep for each container
+    #[serde(default)]
+    pub container_log_max_files: Option<usize>,
+    /// The most images to report in the node's status, or -1 to report
+    /// them all
+    #[serde(default)]
+    pub node_status_max_images: Option<i32>,
+    /// How the CPU manager assigns CPUs to containers, `none` or `static`
+    #[serde(default)]
+    pub cpu_manager_policy: Option<String>,
+    /// How the memory manager places the memory of containers, `None` or
+    /// `Static`
+    #[serde(default)]
+    pub memory_manager_policy: Option<String>,
+    /// How the topology manager aligns the resources of pods, `none`,
+    /// `best-effort`, `restricted` or `single-numa-node`
+    #[serde(default)]
+    pub topology_manager_policy: Option<String>,
+    /// How long the node waits for its pods to stop when it shuts down, as
+    /// a duration such as `30s`
+    #[serde(default)]
+    pub shutdown_grace_period: Option<String>,
+    /// The part of `shutdownGracePeriod` kept for system-critical pods
+    #[serde(default)]
+    pub shutdown_grace_period_critical_pods: Option<String>,
+}
+
+impl KubeletConfig {
+    /// Loads the `KubeletConfiguration` in the given YAML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path.as_ref()).map_err(|e| {
+            anyhow::anyhow!(
+                "unable to read kubelet configuration file {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Self::from_reader(file)
+    }
+
+    /// Parses a `KubeletConfiguration` from YAML
+    pub fn from_reader<R: std::io::Read>(reader: R) -> anyhow::Result<Self> {
+        let config: KubeletConfig = serde_yaml::from_reader(reader)
+            .map_err(|e| anyhow::anyhow!("unable to parse kubelet configuration: {}", e))?;
+        if config.api_version != KUBELET_CONFIG_API_VERSION || config.kind != KUBELET_CONFIG_KIND {
+            return Err(anyhow::anyhow!(
+                "unsupported kubelet configuration {}/{}: expected {} with apiVersion {}",
+                config.api_version,
+                config.kind,
+                KUBELET_CONFIG_KIND,
+                KUBELET_CONFIG_API_VERSION
+            ));
+        }
+        Ok(config)
+    }
+
+    fn into_builder(self) -> anyhow::Result<ConfigBuilder> {
+        let node_status_update_frequency = self
+            .node_status_update_frequency
+            .map(|d| {
+                parse_duration(&d).map_err(|e| {
+                    anyhow::anyhow!("invalid nodeStatusUpdateFrequency {:?}: {}", d, e)
+                })
+            })
+            .transpose()?;
+        let shutdown_grace_period = self
+            .shutdown_grace_period
+            .map(|d| {
+                parse_duration(&d)
+                    .map_err(|e| anyhow::anyhow!("invalid shutdownGracePeriod {:?}: {}", d, e))
+            })
+            .transpose()?;
+        let shutdown_grace_period_critical_pods = self
+            .shutdown_grace_period_critical_pods
+            .map(|d| {
+                parse_duration(&d).map_err(|e| {
+                    anyhow::anyhow!("invalid shutdownGracePeriodCriticalPods {:?}: {}", d, e)
+                })
+            })
+            .transpose()?;
+        let streaming_connection_idle_timeout = self
+            .streaming_connection_idle_timeout
+            .map(|d| {
+                parse_duration(&d).map_err(|e| {
+                    anyhow::anyhow!("invalid streamingConnectionIdleTimeout {:?}: {}", d, e)
+                })
+            })
+            .transpose()?;
+        Ok(ConfigBuilder {
+            server_addr: self.address.map(Ok),
+            server_port: self.port.map(Ok),
+            server_tls_cert_file: self.tls_cert_file,
+            server_tls_private_key_file: self.tls_private_key_file,
+            server_rotate_certificates: self.server_tls_bootstrap,
+            rotate_certificates: self.rotate_certificates,
+            server_client_ca_file: self.authentication.x509.client_ca_file,
+            server_authentication_token_webhook: self.authentication.webhook.enabled,
+            server_anonymous_auth: self.authentication.anonymous.enabled,
+            server_authorization_mode: self.authorization.mode,
+            server_read_only_port: self.read_only_port,
+            server_streaming_connection_idle_timeout: streaming_connection_idle_timeout
+                .map(|d| d.as_secs()),
+            max_pods: self.max_pods.map(Ok),
+            provider_id: self.provider_id,
+            node_status_update_frequency: node_status_update_frequency.map(|d| d.as_secs()),
+            node_lease_duration: self.node_lease_duration_seconds,
+            register_with_taints: Some(self.register_with_taints).filter(|t| !t.is_empty()),
+            eviction_hard: Some(self.eviction_hard).filter(|m| !m.is_empty()),
+            system_reserved: Some(self.system_reserved).filter(|m| !m.is_empty()),
+            kube_reserved: Some(self.kube_reserved).filter(|m| !m.is_empty()),
+            feature_gates: Some(self.feature_gates).filter(|m| !m.is_empty()),
+            container_log_max_size: self.container_log_max_size,
+            container_log_max_files: self.container_log_max_files,
+            node_status_max_images: self.node_status_max_images,
+            cpu_manager_policy: self.cpu_manager_policy,
+            memory_manager_policy: self.memory_manager_policy,
+            topology_manager_policy: self.topology_manager_policy,
+            shutdown_grace_period: shutdown_grace_period.map(|d| d.as_secs()),
+            shutdown_grace_period_critical_pods: shutdown_grace_period_critical_pods
+                .map(|d| d.as_secs()),
+            ..Default::default()
+        })
+    }
+}
+
+/// The `authentication` section of a `KubeletConfiguration` file
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KubeletAuthentication {
+    /// Authentication with client certificates
+    #[serde(default)]
+    pub x509: KubeletX509Authentication,
+    /// Authentication with bearer tokens validated by the API server
+    #[serde(default)]
+    pub webhook: KubeletWebhookAuthentication,
+    /// Requests from clients that don't authenticate
+    #[serde(default)]
+    pub anonymous: KubeletAnonymousAuthentication,
+}
+
+/// The `authentication.webhook` section of a `KubeletConfiguration` file
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KubeletWebhookAuthentication {
+    /// Whether bearer tokens are validated with `TokenReview`s
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+/// The `authentication.anonymous` section of a `KubeletConfiguration` file
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KubeletAnonymousAuthentication {
+    /// Whether requests from clients that don't authenticate are answered
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+/// The `authentication.x509` section of a `KubeletConfiguration` file
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KubeletX509Authentication {
+    /// The path to the CA certificates that client certificates must be
+    /// signed by
+    #[serde(default, rename = "clientCAFile")]
+    pub client_ca_file: Option<PathBuf>,
+}
+
+/// The `authorization` section of a `KubeletConfiguration` file
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KubeletAuthorization {
+    /// The authorization mode, `AlwaysAllow` or `Webhook`
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+/// Deserializes a list of IP addresses separated by ','
+fn try_deserialize_ip_addrs<'de, D>(d: D) -> Result<Option<anyhow::Result<Vec<IpAddr>>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    let addrs = s
+        .split(',')
+        .map(|addr| addr.trim().parse::<IpAddr>().map_err(anyhow::Error::new))
+        .collect();
+    Ok(Some(addrs))
+}
+
 fn try_deserialize_ip_addr<'de, D>(d: D) -> Result<Option<anyhow::Result<IpAddr>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -424,6 +1510,41 @@ pub struct Opts {
     )]
     port: Option<u16>,
 
+    #[structopt(
+        long = "read-only-port",
+        env = "KRUSTLET_READ_ONLY_PORT",
+        help = "The port to serve /healthz, /readyz, /pods, /stats/summary and /metrics/resource on without TLS or authentication. Defaults to 0, which disables it"
+    )]
+    read_only_port: Option<u16>,
+
+    #[structopt(
+        long = "max-log-follows",
+        env = "KRUSTLET_MAX_LOG_FOLLOWS",
+        help = "The most logs that may be followed at once. Requests to follow more are answered with 429 Too Many Requests. Defaults to 256"
+    )]
+    max_log_follows: Option<usize>,
+
+    #[structopt(
+        long = "max-exec-sessions",
+        env = "KRUSTLET_MAX_EXEC_SESSIONS",
+        help = "The most exec and attach sessions that may be open at once. Requests for more are answered with 429 Too Many Requests. Defaults to 128"
+    )]
+    max_exec_sessions: Option<usize>,
+
+    #[structopt(
+        long = "max-port-forwards",
+        env = "KRUSTLET_MAX_PORT_FORWARDS",
+        help = "The most port forwarding connections that may be open at once. Requests for more are answered with 429 Too Many Requests. Defaults to 128"
+    )]
+    max_port_forwards: Option<usize>,
+
+    #[structopt(
+        long = "streaming-connection-idle-timeout",
+        env = "KRUSTLET_STREAMING_CONNECTION_IDLE_TIMEOUT",
+        help = "The number of seconds exec and attach sessions may go without traffic before they are closed. 0 never closes them. Defaults to 14400 (4 hours)"
+    )]
+    streaming_connection_idle_timeout: Option<u64>,
+
     #[structopt(
         long = "max-pods",
         env = "MAX_PODS",
@@ -433,6 +1554,7 @@ pub struct Opts {
 
     #[structopt(
         long = "cert-file",
+        alias = "tls-cert-file",
         env = "KRUSTLET_CERT_FILE",
         help = "The path to kubelet TLS certificate. Defaults to $KRUSTLET_DATA_DIR/config/krustlet.crt"
     )]
@@ -440,23 +1562,103 @@ pub struct Opts {
 
     #[structopt(
         long = "private-key-file",
+        alias = "tls-private-key-file",
         env = "KRUSTLET_PRIVATE_KEY_FILE",
         help = "The path to kubelet TLS key. Defaults to $KRUSTLET_DATA_DIR/config/krustlet.key"
     )]
     private_key_file: Option<PathBuf>,
 
+    #[structopt(
+        long = "client-ca-file",
+        env = "KRUSTLET_CLIENT_CA_FILE",
+        help = "The path to the CA certificates that client certificates must be signed by. If set, clients of the kubelet server may authenticate with client certificates. Defaults to the CA certificates in the kubeconfig"
+    )]
+    client_ca_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "rotate-server-certificates",
+        env = "KRUSTLET_ROTATE_SERVER_CERTIFICATES",
+        help = "Whether to request a new kubelet TLS certificate from the cluster when 80% of the current one's lifetime has passed. Defaults to false"
+    )]
+    rotate_server_certificates: Option<bool>,
+
+    #[structopt(
+        long = "renewal-threshold-days",
+        env = "KRUSTLET_RENEWAL_THRESHOLD_DAYS",
+        help = "How many days before the kubelet TLS certificate expires to request a new one from the cluster. If not set, the certificate is not renewed"
+    )]
+    renewal_threshold_days: Option<u64>,
+
+    #[structopt(
+        long = "oidc-issuer-url",
+        env = "KRUSTLET_OIDC_ISSUER_URL",
+        help = "The URL of the OpenID Connect provider to validate bearer tokens against. If set, requests to the kubelet server with a valid ID token are authenticated as the user it names, and requests without a valid token or client certificate are rejected. Requires --oidc-client-id"
+    )]
+    oidc_issuer_url: Option<String>,
+
+    #[structopt(
+        long = "oidc-client-id",
+        env = "KRUSTLET_OIDC_CLIENT_ID",
+        help = "The client ID that ID tokens must be issued for"
+    )]
+    oidc_client_id: Option<String>,
+
+    #[structopt(
+        long = "oidc-username-claim",
+        env = "KRUSTLET_OIDC_USERNAME_CLAIM",
+        help = "The ID token claim to use as the user name. Defaults to sub"
+    )]
+    oidc_username_claim: Option<String>,
+
+    #[structopt(
+        long = "oidc-groups-claim",
+        env = "KRUSTLET_OIDC_GROUPS_CLAIM",
+        help = "The ID token claim to use as the user's groups. If not set, users authenticated with ID tokens have no groups"
+    )]
+    oidc_groups_claim: Option<String>,
+
+    #[structopt(
+        long = "oidc-ca-file",
+        env = "KRUSTLET_OIDC_CA_FILE",
+        help = "The path to the CA certificates that the OpenID Connect provider's certificate is signed by. Defaults to the host's root certificates"
+    )]
+    oidc_ca_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "authentication-token-webhook",
+        env = "KRUSTLET_AUTHENTICATION_TOKEN_WEBHOOK",
+        help = "Whether to validate the bearer tokens of kubelet server clients with TokenReviews. Defaults to false"
+    )]
+    authentication_token_webhook: Option<bool>,
+
+    #[structopt(
+        long = "anonymous-auth",
+        env = "KRUSTLET_ANONYMOUS_AUTH",
+        help = "Whether to answer requests to the kubelet server from clients that don't authenticate. If false, only /healthz and /readyz are answered for them. Defaults to false"
+    )]
+    anonymous_auth: Option<bool>,
+
+    #[structopt(
+        long = "authorization-mode",
+        env = "KRUSTLET_AUTHORIZATION_MODE",
+        help = "How requests to the kubelet server are authorized: AlwaysAllow, or Webhook to allow only requests that the API server allows in response to a SubjectAccessReview. Defaults to AlwaysAllow"
+    )]
+    authorization_mode: Option<String>,
+
     #[structopt(
         short = "n",
         long = "node-ip",
         env = "KRUSTLET_NODE_IP",
-        help = "The IP address of the node registered with the Kubernetes master. Defaults to the IP address of the host name in DNS as a best effort try at a default"
+        use_delimiter = true,
+        help = "The IP addresses of the node registered with the Kubernetes master, separated by ','. At most one IPv4 and one IPv6 address may be given, and the first is the node's primary address. Each must be assigned to one of the node's interfaces. Defaults to the address of the interface with the default route, or else the IP address of the host name in DNS"
     )]
-    node_ip: Option<IpAddr>,
+    node_ip: Vec<IpAddr>,
 
     #[structopt(
         long = "node-labels",
         env = "NODE_LABELS",
         use_delimiter = true,
+        parse(try_from_str = parse_node_label),
         help = "Labels to add when registering the node in the cluster.
         Labels must be key=value pairs separated by ','.
         Labels in the 'kubernetes.io' namespace must begin with an allowed prefix
@@ -467,7 +1669,16 @@ pub struct Opts {
         kubernetes.io/arch, kubernetes.io/hostname, kubernetes.io/instance-type,
         kubernetes.io/os)"
     )]
-    node_labels: Vec<String>,
+    node_labels: Vec<(String, String)>,
+
+    #[structopt(
+        long = "register-with-taints",
+        env = "KRUSTLET_REGISTER_WITH_TAINTS",
+        use_delimiter = true,
+        parse(try_from_str = parse_taint),
+        help = "Taints to add when registering the node in the cluster, as key=value:Effect or key:Effect separated by ',' (e.g. workload=wasm:NoSchedule)"
+    )]
+    register_with_taints: Vec<Taint>,
 
     #[structopt(
         long = "hostname",
@@ -483,6 +1694,13 @@ pub struct Opts {
     )]
     node_name: Option<String>,
 
+    #[structopt(
+        long = "provider-id",
+        env = "KRUSTLET_PROVIDER_ID",
+        help = "The ID the node's cloud provider knows it by, set as the node's spec.providerID when it registers if it has none"
+    )]
+    provider_id: Option<String>,
+
     #[structopt(
         long = "data-dir",
         env = "KRUSTLET_DATA_DIR",
@@ -498,6 +1716,13 @@ pub struct Opts {
     )]
     bootstrap_file: PathBuf,
 
+    #[structopt(
+        long = "rotate-certificates",
+        env = "KRUSTLET_ROTATE_CERTIFICATES",
+        help = "Whether to request a new client certificate from the cluster when 80% of the lifetime of the one in the kubeconfig has passed. Defaults to false"
+    )]
+    rotate_certificates: Option<bool>,
+
     #[structopt(
         long = "plugins-dir",
         env = "KRUSTLET_PLUGINS_DIR",
@@ -505,6 +1730,13 @@ pub struct Opts {
     )]
     plugins_dir: Option<PathBuf>,
 
+    #[structopt(
+        long = "device-plugins-dir",
+        env = "KRUSTLET_DEVICE_PLUGINS_DIR",
+        help = "The path to the directory device plugins register in. Defaults to $KRUSTLET_DATA_DIR/device-plugins"
+    )]
+    device_plugins_dir: Option<PathBuf>,
+
     #[structopt(
         long = "x-allow-local-modules",
         env = "KRUSTLET_ALLOW_LOCAL_MODULES",
@@ -512,12 +1744,217 @@ pub struct Opts {
     )]
     allow_local_modules: Option<bool>,
 
+    #[structopt(
+        long = "secrets-in-memory",
+        env = "KRUSTLET_SECRETS_IN_MEMORY",
+        help = "Whether to back secret volumes with memory, so that secrets are never written to disk. Defaults to false"
+    )]
+    secrets_in_memory: Option<bool>,
+
+    #[structopt(
+        long = "reregister-node",
+        env = "KRUSTLET_REREGISTER_NODE",
+        help = "Whether to register the node again if it is deleted while the kubelet runs. Defaults to true"
+    )]
+    reregister_node: Option<bool>,
+
+    #[structopt(
+        long = "deregister-on-shutdown",
+        env = "KRUSTLET_DEREGISTER_ON_SHUTDOWN",
+        help = "Whether to delete the node once its pods have been drained when the kubelet shuts down. Defaults to false"
+    )]
+    deregister_on_shutdown: Option<bool>,
+
     #[structopt(
         long = "insecure-registries",
         env = "KRUSTLET_INSECURE_REGISTRIES",
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "registry-proxy",
+        env = "KRUSTLET_REGISTRY_PROXY",
+        help = "The proxy URL to use when pulling from registries. Defaults to the HTTP_PROXY/HTTPS_PROXY environment variables"
+    )]
+    registry_proxy: Option<String>,
+
+    #[structopt(
+        long = "image-verification-key",
+        env = "KRUSTLET_IMAGE_VERIFICATION_KEY",
+        help = "The path to a PEM encoded public key. If set, pods only run modules with a cosign signature made with the matching private key"
+    )]
+    image_verification_key_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "pull-progress-interval",
+        env = "KRUSTLET_PULL_PROGRESS_INTERVAL",
+        help = "The minimum number of seconds between updates to a container's status while its image is being pulled. Defaults to 5"
+    )]
+    pull_progress_interval: Option<u64>,
+
+    #[structopt(
+        long = "readiness-gate-poll-interval",
+        env = "KRUSTLET_READINESS_GATE_POLL_INTERVAL",
+        help = "The number of seconds between checks of the conditions of a running pod's readiness gates. Defaults to 5"
+    )]
+    readiness_gate_poll_interval: Option<u64>,
+
+    #[structopt(
+        long = "docker-config",
+        env = "KRUSTLET_DOCKER_CONFIG",
+        help = "The path to a Docker config file. Registries are authenticated to using the credential helpers it configures in credHelpers and credsStore"
+    )]
+    docker_config_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "config",
+        env = "KRUSTLET_CONFIG",
+        help = "The path to a KubeletConfiguration file (kubelet.config.k8s.io/v1beta1). Flags and environment variables take precedence over its values"
+    )]
+    config: Option<PathBuf>,
+
+    #[structopt(
+        long = "node-status-update-frequency",
+        env = "KRUSTLET_NODE_STATUS_UPDATE_FREQUENCY",
+        help = "The number of seconds between updates to the node's status when nothing it reports has changed. Defaults to 300"
+    )]
+    node_status_update_frequency: Option<u64>,
+
+    #[structopt(
+        long = "node-lease-duration",
+        env = "KRUSTLET_NODE_LEASE_DURATION",
+        help = "The number of seconds the node's lease lasts. The lease is renewed every quarter of this. Defaults to 40"
+    )]
+    node_lease_duration: Option<u64>,
+
+    #[structopt(
+        long = "eviction-hard",
+        env = "KRUSTLET_EVICTION_HARD",
+        use_delimiter = true,
+        parse(try_from_str = parse_eviction_threshold),
+        help = "Hard eviction thresholds, as signal<quantity pairs separated by ',' (e.g. memory.available<100Mi)"
+    )]
+    eviction_hard: Vec<(String, String)>,
+
+    #[structopt(
+        long = "system-reserved",
+        env = "KRUSTLET_SYSTEM_RESERVED",
+        use_delimiter = true,
+        parse(try_from_str = parse_reserved_resource),
+        help = "Resources reserved for the host's own processes, as resource=quantity pairs separated by ',' (e.g. cpu=100m,memory=256Mi)"
+    )]
+    system_reserved: Vec<(String, String)>,
+
+    #[structopt(
+        long = "kube-reserved",
+        env = "KRUSTLET_KUBE_RESERVED",
+        use_delimiter = true,
+        parse(try_from_str = parse_reserved_resource),
+        help = "Resources reserved for the kubelet, as resource=quantity pairs separated by ',' (e.g. cpu=100m,memory=128Mi)"
+    )]
+    kube_reserved: Vec<(String, String)>,
+
+    #[structopt(
+        long = "feature-gates",
+        env = "KRUSTLET_FEATURE_GATES",
+        use_delimiter = true,
+        parse(try_from_str = parse_feature_gate),
+        help = "Feature gates to set, as name=bool pairs separated by ',' (e.g. WasiSockets=true)"
+    )]
+    feature_gates: Vec<(String, bool)>,
+
+    #[structopt(
+        long = "default-container-memory-limit",
+        env = "KRUSTLET_DEFAULT_CONTAINER_MEMORY_LIMIT",
+        help = "The memory limit (e.g. 256Mi) of containers that do not set resources.limits.memory. Defaults to unlimited"
+    )]
+    default_container_memory_limit: Option<String>,
+
+    #[structopt(
+        long = "cpu-limit-tick-interval",
+        env = "KRUSTLET_CPU_LIMIT_TICK_INTERVAL",
+        help = "The number of milliseconds between checks of container CPU usage against CPU limits. Defaults to 10"
+    )]
+    cpu_limit_tick_interval: Option<u64>,
+
+    #[structopt(
+        long = "cpu-manager-policy",
+        env = "KRUSTLET_CPU_MANAGER_POLICY",
+        help = "How CPUs are assigned to containers: none, or static to give pods whose QoS class is Guaranteed exclusive CPUs for the whole CPUs they request. Defaults to none"
+    )]
+    cpu_manager_policy: Option<String>,
+
+    #[structopt(
+        long = "memory-manager-policy",
+        env = "KRUSTLET_MEMORY_MANAGER_POLICY",
+        help = "How the memory of containers is placed on NUMA nodes: None, or Static to allocate the memory of pods with exclusive CPUs from the NUMA nodes of those CPUs. Defaults to None"
+    )]
+    memory_manager_policy: Option<String>,
+
+    #[structopt(
+        long = "topology-manager-policy",
+        env = "KRUSTLET_TOPOLOGY_MANAGER_POLICY",
+        help = "How the CPUs, memory and devices of pods are aligned on NUMA nodes: none, best-effort, restricted to only admit pods whose resources can be aligned on the fewest nodes that hold them, or single-numa-node to only admit pods whose resources fit on one node. Defaults to none"
+    )]
+    topology_manager_policy: Option<String>,
+
+    #[structopt(
+        long = "ephemeral-storage-check-interval",
+        env = "KRUSTLET_EPHEMERAL_STORAGE_CHECK_INTERVAL",
+        help = "The number of seconds between checks of the ephemeral storage used by pods against their ephemeral storage limits. Defaults to 10"
+    )]
+    ephemeral_storage_check_interval: Option<u64>,
+
+    #[structopt(
+        long = "shutdown-grace-period",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD",
+        help = "The number of seconds the node waits for its pods to stop when it shuts down. Pods are given the smaller of this and their own termination grace period. Defaults to 0, which gives pods their own termination grace periods"
+    )]
+    shutdown_grace_period: Option<u64>,
+
+    #[structopt(
+        long = "shutdown-grace-period-critical-pods",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS",
+        help = "The number of seconds of the shutdown grace period kept for system-critical pods, which are stopped after the other pods. Defaults to 0"
+    )]
+    shutdown_grace_period_critical_pods: Option<u64>,
+
+    #[structopt(
+        long = "shutdown-inhibitor",
+        env = "KRUSTLET_SHUTDOWN_INHIBITOR",
+        help = "Whether to delay shutdowns of the host with a systemd-logind inhibitor lock until the node's pods have been drained. Needs krustlet to be built with the systemd feature. Defaults to false"
+    )]
+    shutdown_inhibitor: Option<bool>,
+
+    #[structopt(
+        long = "container-log-max-size",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_SIZE",
+        help = "The size (e.g. 10Mi) a container log file can grow to before it is rotated. Defaults to 10Mi"
+    )]
+    container_log_max_size: Option<String>,
+
+    #[structopt(
+        long = "container-log-max-files",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_FILES",
+        help = "The most log files to keep for each container, including the one being written. Must be at least 2. Defaults to 5"
+    )]
+    container_log_max_files: Option<usize>,
+
+    #[structopt(
+        long = "node-status-max-images",
+        env = "KRUSTLET_NODE_STATUS_MAX_IMAGES",
+        allow_hyphen_values = true,
+        help = "The most images to report in the node's status, the largest first, or -1 to report them all. Defaults to 50"
+    )]
+    node_status_max_images: Option<i32>,
+
+    #[structopt(
+        long = "record-transitions",
+        env = "KRUSTLET_RECORD_TRANSITIONS",
+        help = "The file to record each state that pods' state machines enter in, to replay them with the replay command. If not set, states aren't recorded"
+    )]
+    record_transitions: Option<PathBuf>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -540,13 +1977,75 @@ fn sanitize_hostname(hostname: &str) -> String {
     hostname.to_lowercase()
 }
 
-// Attempt to get the node IP address in the following order (this follows the
-// same pattern as the Kubernetes kubelet):
-// 1. Lookup the IP from node name by DNS
-// 2. Try to get the IP from the network interface used as default gateway
-//    (unimplemented for now because it doesn't work across platforms)
-fn default_node_ip(hostname: &mut String, preferred_ip_family: &IpAddr) -> anyhow::Result<IpAddr> {
-    // NOTE: As of right now, we don't have cloud providers. In the future if
+/// Checks that the node's addresses, in the order they were given, are at
+/// most one IPv4 and one IPv6 address that can be reached
+fn validate_node_ips(node_ips: Vec<IpAddr>) -> anyhow::Result<Vec<IpAddr>> {
+    match node_ips.as_slice() {
+        [] => return Err(anyhow::anyhow!("at least one node IP must be given")),
+        [_] => (),
+        [first, second] if !is_same_ip_family(first, second) => (),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "at most one IPv4 and one IPv6 node IP may be given, not {:?}",
+                node_ips
+            ))
+        }
+    }
+    if let Some(ip) = node_ips
+        .iter()
+        .find(|ip| ip.is_unspecified() || ip.is_multicast())
+    {
+        return Err(anyhow::anyhow!("{} can't be used as a node IP", ip));
+    }
+    Ok(node_ips)
+}
+
+/// Checks that the address is assigned to one of the node's interfaces,
+/// which is the only way it can be bound
+fn check_local_ip(ip: &IpAddr) -> anyhow::Result<()> {
+    std::net::UdpSocket::bind((*ip, 0)).map_err(|e| {
+        anyhow::anyhow!(
+            "{} is not assigned to any of the node's interfaces: {}",
+            ip,
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Returns the address of the interface that the default route of the
+/// preferred IP family goes through, if it has one. Connecting a UDP socket
+/// only looks up its route, and sends nothing.
+fn default_route_ip(preferred_ip_family: &IpAddr) -> Option<IpAddr> {
+    // Documentation addresses, which are only routed by a default route
+    let (unspecified, remote) = match preferred_ip_family {
+        IpAddr::V4(_) => (
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        ),
+        IpAddr::V6(_) => (
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ),
+    };
+    let socket = std::net::UdpSocket::bind((unspecified, 0)).ok()?;
+    socket.connect((remote, 80)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    if ip.is_loopback() || ip.is_unspecified() {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+// Attempt to get the node IP address in the following order:
+// 1. Get the IP of the network interface used as default gateway
+// 2. Lookup the IP from node name by DNS
+fn default_node_ip(hostname: &mut String, preferred_ip_family: &IpAddr) -> anyhow::Result<IpAddr> {
+    if let Some(ip) = default_route_ip(preferred_ip_family) {
+        return Ok(ip);
+    }
+    // NOTE: As of right now, we don't have cloud providers. In the future if
     // that is the case, we will need to add logic for looking up the IP and
     // hostname using the cloud provider as they do in the kubelet
     // To use the local resolver, we need to add a port to the hostname. Doesn't
@@ -580,6 +2079,10 @@ fn default_plugins_path(data_dir: &PathBuf) -> PathBuf {
     data_dir.join("plugins")
 }
 
+fn default_device_plugins_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("device-plugins")
+}
+
 #[cfg(any(feature = "cli", feature = "docs"))]
 fn default_config_file_path() -> PathBuf {
     dirs::home_dir()
@@ -595,16 +2098,113 @@ fn is_same_ip_family(first: &IpAddr, second: &IpAddr) -> bool {
 }
 
 #[cfg(any(feature = "cli", feature = "docs"))]
-fn split_one_label(in_string: &str) -> Option<(String, String)> {
-    let mut splitter = in_string.splitn(2, '=');
+fn parse_node_label(source: &str) -> anyhow::Result<(String, String)> {
+    let mut splitter = source.splitn(2, '=');
+    match (splitter.next(), splitter.next()) {
+        (Some(key), Some(value)) => {
+            validate_label(key, value)?;
+            Ok((key.to_owned(), value.to_owned()))
+        }
+        _ => Err(anyhow::anyhow!(
+            "node label {:?} must be of the form key=value",
+            source
+        )),
+    }
+}
+
+/// Parses a taint of the form `key=value:Effect` or `key:Effect`
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn parse_taint(source: &str) -> anyhow::Result<Taint> {
+    let (key_value, effect) = match source.rfind(':') {
+        Some(index) => (&source[..index], &source[index + 1..]),
+        None => {
+            return Err(anyhow::anyhow!(
+                "taint {:?} must be of the form key=value:Effect or key:Effect",
+                source
+            ))
+        }
+    };
+    let mut splitter = key_value.splitn(2, '=');
+    let taint = Taint {
+        key: splitter.next().unwrap_or_default().to_owned(),
+        value: splitter.next().map(str::to_owned),
+        effect: effect.to_owned(),
+        time_added: None,
+    };
+    validate_taint(&taint)?;
+    Ok(taint)
+}
+
+/// The effects a taint can have
+const TAINT_EFFECTS: &[&str] = &["NoSchedule", "PreferNoSchedule", "NoExecute"];
+
+fn validate_taint(taint: &Taint) -> anyhow::Result<()> {
+    validate_label(&taint.key, taint.value.as_deref().unwrap_or_default())
+        .map_err(|e| anyhow::anyhow!("invalid taint {}: {}", taint.key, e))?;
+    if !TAINT_EFFECTS.contains(&taint.effect.as_str()) {
+        return Err(anyhow::anyhow!(
+            "invalid taint {}: unknown effect {:?}, must be one of {}",
+            taint.key,
+            taint.effect,
+            TAINT_EFFECTS.join(", ")
+        ));
+    }
+    Ok(())
+}
 
-    match splitter.next() {
-        Some("") | None => None,
-        Some(key) => match splitter.next() {
-            Some(val) => Some((key.to_string(), val.to_string())),
-            None => Some((key.to_string(), String::new())),
-        },
+/// Checks that a label has a valid key, which is a name of up to 63
+/// characters with an optional DNS subdomain prefix, and that its value is
+/// empty or a valid name
+fn validate_label(key: &str, value: &str) -> anyhow::Result<()> {
+    let (prefix, name) = match key.rfind('/') {
+        Some(index) => (Some(&key[..index]), &key[index + 1..]),
+        None => (None, key),
+    };
+    if let Some(prefix) = prefix {
+        if !is_dns_subdomain(prefix) {
+            return Err(anyhow::anyhow!(
+                "label key {:?} must have a DNS subdomain as its prefix",
+                key
+            ));
+        }
+    }
+    if !is_label_name(name) {
+        return Err(anyhow::anyhow!(
+            "label key {:?} must be at most 63 alphanumeric characters, '-', '_' or '.', starting and ending with an alphanumeric character",
+            key
+        ));
+    }
+    if !value.is_empty() && !is_label_name(value) {
+        return Err(anyhow::anyhow!(
+            "label value {:?} must be empty or at most 63 alphanumeric characters, '-', '_' or '.', starting and ending with an alphanumeric character",
+            value
+        ));
     }
+    Ok(())
+}
+
+fn is_label_name(name: &str) -> bool {
+    let starts_and_ends_alphanumeric = match (name.chars().next(), name.chars().last()) {
+        (Some(first), Some(last)) => first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric(),
+        _ => false,
+    };
+    name.len() <= 63
+        && starts_and_ends_alphanumeric
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn is_dns_subdomain(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|part| {
+            !part.is_empty()
+                && !part.starts_with('-')
+                && !part.ends_with('-')
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
 }
 
 fn invalid_config_value_error(e: anyhow::Error, value_name: &str) -> anyhow::Error {
@@ -616,6 +2216,105 @@ fn parse_comma_separated(source: String) -> Vec<String> {
     source.split(',').map(|s| s.trim().to_owned()).collect()
 }
 
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn parse_eviction_threshold(source: &str) -> anyhow::Result<(String, String)> {
+    let mut splitter = source.splitn(2, '<');
+    match (splitter.next(), splitter.next()) {
+        (Some(signal), Some(quantity)) => {
+            Ok((signal.trim().to_owned(), quantity.trim().to_owned()))
+        }
+        _ => Err(anyhow::anyhow!(
+            "eviction threshold {:?} must be of the form signal<quantity",
+            source
+        )),
+    }
+}
+
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn parse_reserved_resource(source: &str) -> anyhow::Result<(String, String)> {
+    let mut splitter = source.splitn(2, '=');
+    match (splitter.next(), splitter.next()) {
+        (Some(resource), Some(quantity)) if !resource.trim().is_empty() => {
+            Ok((resource.trim().to_owned(), quantity.trim().to_owned()))
+        }
+        _ => Err(anyhow::anyhow!(
+            "reserved resource {:?} must be of the form resource=quantity",
+            source
+        )),
+    }
+}
+
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn parse_feature_gate(source: &str) -> anyhow::Result<(String, bool)> {
+    let mut splitter = source.splitn(2, '=');
+    match (splitter.next(), splitter.next()) {
+        (Some(name), Some(enabled)) if !name.trim().is_empty() => {
+            let enabled = enabled.trim().parse().map_err(|_| {
+                anyhow::anyhow!("feature gate {} must be set to true or false", name)
+            })?;
+            Ok((name.trim().to_owned(), enabled))
+        }
+        _ => Err(anyhow::anyhow!(
+            "feature gate {:?} must be of the form name=bool",
+            source
+        )),
+    }
+}
+
+fn validate_eviction_thresholds(thresholds: &HashMap<String, String>) -> anyhow::Result<()> {
+    for (signal, quantity) in thresholds {
+        crate::node::EvictionThreshold::parse(signal, quantity).map_err(|e| {
+            anyhow::anyhow!("invalid eviction threshold in configuration file: {}", e)
+        })?;
+    }
+    Ok(())
+}
+
+fn validate_reserved(reserved: &HashMap<String, String>, name: &str) -> anyhow::Result<()> {
+    for (resource, quantity) in reserved {
+        crate::node::parse_reserved(resource, quantity)
+            .map_err(|e| invalid_config_value_error(e, name))?;
+    }
+    Ok(())
+}
+
+/// Parses a duration in the format used by Kubernetes configuration files,
+/// such as `10s`, `1m30s` or `500ms`
+fn parse_duration(source: &str) -> anyhow::Result<Duration> {
+    if source == "0" {
+        return Ok(Duration::from_secs(0));
+    }
+    let invalid = || anyhow::anyhow!("expected a duration such as 10s or 1m30s");
+    let mut millis: u64 = 0;
+    let mut rest = source;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_millis = match &rest[..unit_len] {
+            "h" => 60 * 60 * 1000,
+            "m" => 60 * 1000,
+            "s" => 1000,
+            "ms" => 1,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_len..];
+        millis = amount
+            .checked_mul(unit_millis)
+            .and_then(|m| millis.checked_add(m))
+            .ok_or_else(invalid)?;
+    }
+    Ok(Duration::from_millis(millis))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -627,11 +2326,13 @@ mod test {
     fn fallbacks() -> ConfigBuilderFallbacks {
         ConfigBuilderFallbacks {
             node_ip: |_, _| IpAddr::V4(std::net::Ipv4Addr::new(4, 4, 4, 4)),
+            check_node_ip: |_| Ok(()),
             hostname: || "fallback-hostname".to_owned(),
             data_dir: || PathBuf::from("/fallback/data/dir"),
             cert_path: |_| PathBuf::from("/fallback/cert/path"),
             key_path: |_| PathBuf::from("/fallback/key/path"),
             plugins_dir: |_| PathBuf::from("/fallback/plugins/dir"),
+            device_plugins_dir: |_| PathBuf::from("/fallback/device-plugins/dir"),
             bootstrap_file: || PathBuf::from("/fallback/bootstrap_file.txt"),
         }
     }
@@ -651,14 +2352,62 @@ mod test {
                 "label2": "val2"
             },
             "nodeName": "krusty-node",
+            "providerID": "aws:///us-east-1a/i-0123456789abcdef0",
             "tlsCertificateFile": "/my/secure/cert.pfx",
             "tlsPrivateKeyFile": "/the/key",
+            "clientCAFile": "/the/client/ca.crt",
+            "rotateCertificates": true,
+            "rotateClientCertificates": true,
+            "renewalThresholdDays": 30,
+            "oidcIssuerUrl": "https://dex.krustlet.test",
+            "oidcClientId": "kubernetes",
+            "oidcGroupsClaim": "groups",
+            "authenticationTokenWebhook": true,
+            "anonymousAuth": true,
+            "authorizationMode": "Webhook",
+            "readOnlyPort": 10255,
+            "maxLogFollows": 16,
+            "maxExecSessions": 8,
+            "maxPortForwards": 4,
+            "streamingConnectionIdleTimeoutSeconds": 600,
             "bootstrapFile": "/the/bootstrap/file.txt",
             "allowLocalModules": true,
+            "secretsInMemory": true,
+            "reregisterNode": false,
+            "deregisterOnShutdown": true,
             "insecureRegistries": [
                 "local",
                 "dev"
             ],
+            "registryProxy": "http://proxy.corp:3128",
+            "imageVerificationKeyFile": "/the/cosign.pub",
+            "pullProgressIntervalSeconds": 10,
+            "readinessGatePollIntervalSeconds": 15,
+            "dockerConfigFile": "/the/docker/config.json",
+            "nodeStatusUpdateFrequencySeconds": 20,
+            "nodeLeaseDurationSeconds": 60,
+            "evictionHard": {
+                "memory.available": "100Mi"
+            },
+            "systemReserved": {
+                "cpu": "100m",
+                "memory": "256Mi"
+            },
+            "kubeReserved": {
+                "ephemeral-storage": "1Gi"
+            },
+            "featureGates": {
+                "WasiSockets": true
+            },
+            "defaultContainerMemoryLimit": "256Mi",
+            "cpuLimitTickIntervalMilliseconds": 20,
+            "ephemeralStorageCheckIntervalSeconds": 30,
+            "shutdownGracePeriodSeconds": 60,
+            "shutdownGracePeriodCriticalPodsSeconds": 20,
+            "containerLogMaxSize": "1Mi",
+            "containerLogMaxFiles": 3,
+            "nodeStatusMaxImages": 10,
+            "recordTransitions": "/the/transitions.cbor",
             "pluginsDir": "/some/plugins"
         }"#,
         );
@@ -673,21 +2422,110 @@ mod test {
             config.server_config.private_key_file.to_string_lossy(),
             "/the/key"
         );
+        assert_eq!(
+            config.server_config.client_ca_file,
+            Some(PathBuf::from("/the/client/ca.crt"))
+        );
+        assert!(config.server_config.rotate_certificates);
+        assert!(config.rotate_certificates);
+        assert_eq!(config.server_config.renewal_threshold_days, Some(30));
+        assert_eq!(
+            config.server_config.oidc,
+            Some(OidcConfig {
+                issuer_url: "https://dex.krustlet.test".to_owned(),
+                client_id: "kubernetes".to_owned(),
+                username_claim: "sub".to_owned(),
+                groups_claim: Some("groups".to_owned()),
+                ca_file: None,
+            })
+        );
+        assert!(config.server_config.authentication_token_webhook);
+        assert!(config.server_config.anonymous_auth);
+        assert_eq!(
+            config.server_config.authorization_mode,
+            AuthorizationMode::Webhook
+        );
+        assert_eq!(config.server_config.read_only_port, 10255);
+        assert_eq!(config.server_config.max_log_follows, 16);
+        assert_eq!(config.server_config.max_exec_sessions, 8);
+        assert_eq!(config.server_config.max_port_forwards, 4);
+        assert_eq!(
+            config.server_config.streaming_connection_idle_timeout,
+            Duration::from_secs(600)
+        );
         assert_eq!(
             config.bootstrap_file.to_string_lossy(),
             "/the/bootstrap/file.txt"
         );
         assert_eq!(config.node_name, "krusty-node");
+        assert_eq!(
+            config.provider_id.as_deref(),
+            Some("aws:///us-east-1a/i-0123456789abcdef0")
+        );
         assert_eq!(config.hostname, "krusty-host");
         assert_eq!(config.data_dir.to_string_lossy(), "/krusty/data/dir");
         assert_eq!(format!("{}", config.node_ip), "173.183.193.2");
         assert_eq!(config.max_pods, 400);
         assert_eq!(config.allow_local_modules, true);
+        assert!(config.secrets_in_memory);
+        assert!(!config.reregister_node);
+        assert!(config.deregister_on_shutdown);
         assert_eq!(config.node_labels.len(), 2);
         assert_eq!(config.node_labels.get("label1"), Some(&("val1".to_owned())));
         assert_eq!(config.insecure_registries.clone().unwrap().len(), 2);
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
+        assert_eq!(
+            config.registry_proxy,
+            Some("http://proxy.corp:3128".to_owned())
+        );
+        assert_eq!(
+            config.image_verification_key_file,
+            Some(PathBuf::from("/the/cosign.pub"))
+        );
+        assert_eq!(config.pull_progress_interval, Duration::from_secs(10));
+        assert_eq!(config.readiness_gate_poll_interval, Duration::from_secs(15));
+        assert_eq!(
+            config.docker_config_file,
+            Some(PathBuf::from("/the/docker/config.json"))
+        );
+        assert_eq!(config.node_status_update_frequency, Duration::from_secs(20));
+        assert_eq!(config.node_lease_duration, Duration::from_secs(60));
+        assert_eq!(
+            config.eviction_hard.get("memory.available"),
+            Some(&"100Mi".to_owned())
+        );
+        assert_eq!(config.system_reserved.len(), 2);
+        assert_eq!(
+            config.system_reserved.get("memory"),
+            Some(&"256Mi".to_owned())
+        );
+        assert_eq!(
+            config.kube_reserved.get("ephemeral-storage"),
+            Some(&"1Gi".to_owned())
+        );
+        assert!(config.feature_gates.is_enabled("WasiSockets"));
+        assert_eq!(
+            config.default_container_memory_limit,
+            Some(256 * 1024 * 1024)
+        );
+        assert_eq!(config.cpu_limit_tick_interval, Duration::from_millis(20));
+        assert_eq!(
+            config.ephemeral_storage_check_interval,
+            Duration::from_secs(30)
+        );
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(60));
+        assert_eq!(
+            config.shutdown_grace_period_critical_pods,
+            Duration::from_secs(20)
+        );
+        assert_eq!(config.container_log_max_size, 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 3);
+        assert_eq!(config.node_status_max_images, Some(10));
+        assert_eq!(
+            config.record_transitions,
+            Some(PathBuf::from("/the/transitions.cbor"))
+        );
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
     }
 
@@ -743,12 +2581,65 @@ mod test {
             config.server_config.private_key_file.to_string_lossy(),
             "/fallback/key/path"
         );
+        assert_eq!(config.server_config.client_ca_file, None);
+        assert!(!config.server_config.rotate_certificates);
+        assert!(!config.rotate_certificates);
+        assert_eq!(config.server_config.renewal_threshold_days, None);
+        assert_eq!(config.server_config.oidc, None);
+        assert!(!config.server_config.authentication_token_webhook);
+        assert!(!config.server_config.anonymous_auth);
+        assert_eq!(
+            config.server_config.authorization_mode,
+            AuthorizationMode::AlwaysAllow
+        );
+        assert_eq!(config.server_config.read_only_port, 0);
+        assert_eq!(config.server_config.max_log_follows, 256);
+        assert_eq!(config.server_config.max_exec_sessions, 128);
+        assert_eq!(config.server_config.max_port_forwards, 128);
+        assert_eq!(
+            config.server_config.streaming_connection_idle_timeout,
+            Duration::from_secs(4 * 60 * 60)
+        );
         assert_eq!(config.node_name, "fallback-hostname");
+        assert_eq!(config.provider_id, None);
         assert_eq!(config.hostname, "fallback-hostname");
         assert_eq!(config.data_dir.to_string_lossy(), "/fallback/data/dir");
         assert_eq!(format!("{}", config.node_ip), "4.4.4.4");
         assert_eq!(config.allow_local_modules, false);
+        assert!(!config.secrets_in_memory);
+        assert!(config.reregister_node);
+        assert!(!config.deregister_on_shutdown);
         assert_eq!(config.insecure_registries, None);
+        assert_eq!(config.registry_proxy, None);
+        assert_eq!(config.image_verification_key_file, None);
+        assert_eq!(config.pull_progress_interval, Duration::from_secs(5));
+        assert_eq!(config.readiness_gate_poll_interval, Duration::from_secs(5));
+        assert_eq!(config.docker_config_file, None);
+        assert_eq!(
+            config.node_status_update_frequency,
+            Duration::from_secs(300)
+        );
+        assert_eq!(config.node_lease_duration, Duration::from_secs(40));
+        assert_eq!(config.eviction_hard.len(), 0);
+        assert!(config.system_reserved.is_empty());
+        assert!(config.kube_reserved.is_empty());
+        assert!(config.feature_gates.is_empty());
+        assert_eq!(config.default_container_memory_limit, None);
+        assert_eq!(config.cpu_limit_tick_interval, Duration::from_millis(10));
+        assert_eq!(
+            config.ephemeral_storage_check_interval,
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(0));
+        assert_eq!(
+            config.shutdown_grace_period_critical_pods,
+            Duration::from_secs(0)
+        );
+        assert!(!config.shutdown_inhibitor);
+        assert_eq!(config.container_log_max_size, 10 * 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 5);
+        assert_eq!(config.node_status_max_images, Some(50));
+        assert_eq!(config.record_transitions, None);
         assert_eq!(config.node_labels.len(), 0);
         assert_eq!(
             &config.plugins_dir.to_string_lossy(),
@@ -1007,4 +2898,500 @@ mod test {
             format!("Expected 'invalid type' but got '{}'", error.to_string())
         );
     }
+
+    #[test]
+    fn kubelet_config_inputs_are_respected() {
+        let kubelet_config = KubeletConfig::from_reader(
+            r#"
+apiVersion: kubelet.config.k8s.io/v1beta1
+kind: KubeletConfiguration
+address: 172.182.192.1
+port: 1234
+readOnlyPort: 10255
+streamingConnectionIdleTimeout: 30m
+tlsCertFile: /my/secure/cert.pfx
+tlsPrivateKeyFile: /the/key
+serverTLSBootstrap: true
+rotateCertificates: true
+authentication:
+  x509:
+    clientCAFile: /the/client/ca.crt
+  webhook:
+    enabled: true
+  anonymous:
+    enabled: false
+authorization:
+  mode: Webhook
+maxPods: 50
+providerID: azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/edge
+nodeStatusUpdateFrequency: 1m30s
+nodeLeaseDurationSeconds: 20
+evictionHard:
+  memory.available: 100Mi
+  nodefs.available: 10%
+systemReserved:
+  cpu: 200m
+kubeReserved:
+  memory: 128Mi
+featureGates:
+  WasiSockets: true
+  WasiHttp: false
+containerLogMaxSize: 20Mi
+containerLogMaxFiles: 10
+nodeStatusMaxImages: -1
+cpuManagerPolicy: static
+memoryManagerPolicy: Static
+topologyManagerPolicy: single-numa-node
+shutdownGracePeriod: 30s
+shutdownGracePeriodCriticalPods: 10s
+registerWithTaints:
+  - key: workload
+    value: wasm
+    effect: NoSchedule
+clusterDNS:
+  - 10.0.0.10
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let config = kubelet_config
+            .into_builder()
+            .unwrap()
+            .build(fallbacks())
+            .unwrap();
+        assert_eq!(config.server_config.port, 1234);
+        assert_eq!(format!("{}", config.server_config.addr), "172.182.192.1");
+        assert_eq!(
+            config.server_config.cert_file.to_string_lossy(),
+            "/my/secure/cert.pfx"
+        );
+        assert_eq!(
+            config.server_config.private_key_file.to_string_lossy(),
+            "/the/key"
+        );
+        assert_eq!(
+            config.server_config.client_ca_file,
+            Some(PathBuf::from("/the/client/ca.crt"))
+        );
+        assert!(config.server_config.rotate_certificates);
+        assert!(config.rotate_certificates);
+        assert!(config.server_config.authentication_token_webhook);
+        assert!(!config.server_config.anonymous_auth);
+        assert_eq!(
+            config.server_config.authorization_mode,
+            AuthorizationMode::Webhook
+        );
+        assert_eq!(config.server_config.read_only_port, 10255);
+        assert_eq!(
+            config.server_config.streaming_connection_idle_timeout,
+            Duration::from_secs(30 * 60)
+        );
+        assert_eq!(config.max_pods, 50);
+        assert_eq!(
+            config.provider_id.as_deref(),
+            Some("azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/edge")
+        );
+        assert_eq!(config.node_status_update_frequency, Duration::from_secs(90));
+        assert_eq!(config.node_lease_duration, Duration::from_secs(20));
+        assert_eq!(config.eviction_hard.len(), 2);
+        assert_eq!(
+            config.eviction_hard.get("nodefs.available"),
+            Some(&"10%".to_owned())
+        );
+        assert_eq!(config.system_reserved.get("cpu"), Some(&"200m".to_owned()));
+        assert_eq!(
+            config.kube_reserved.get("memory"),
+            Some(&"128Mi".to_owned())
+        );
+        assert!(config.feature_gates.is_enabled("WasiSockets"));
+        assert!(!config.feature_gates.is_enabled("WasiHttp"));
+        assert_eq!(config.container_log_max_size, 20 * 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 10);
+        assert_eq!(config.node_status_max_images, None);
+        assert_eq!(config.cpu_manager_policy, CpuManagerPolicy::Static);
+        assert_eq!(config.memory_manager_policy, MemoryManagerPolicy::Static);
+        assert_eq!(
+            config.topology_manager_policy,
+            TopologyManagerPolicy::SingleNumaNode
+        );
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(30));
+        assert_eq!(
+            config.shutdown_grace_period_critical_pods,
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.register_with_taints.len(), 1);
+        assert_eq!(config.register_with_taints[0].effect, "NoSchedule");
+        // Values not in the file fall back as usual
+        assert_eq!(config.hostname, "fallback-hostname");
+    }
+
+    #[test]
+    fn kubelet_config_values_are_overridden() {
+        let kubelet_config = KubeletConfig::from_reader(
+            r#"
+apiVersion: kubelet.config.k8s.io/v1beta1
+kind: KubeletConfiguration
+port: 1234
+maxPods: 50
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let overrides = builder_from_json_string(r#"{ "maxPods": 60 }"#).unwrap();
+        let config = kubelet_config
+            .into_builder()
+            .unwrap()
+            .with_override(overrides)
+            .build(fallbacks())
+            .unwrap();
+        assert_eq!(config.server_config.port, 1234);
+        assert_eq!(config.max_pods, 60);
+    }
+
+    #[test]
+    fn kubelet_config_kind_is_checked() {
+        let result = KubeletConfig::from_reader(
+            r#"
+apiVersion: v1
+kind: Pod
+"#
+            .as_bytes(),
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("unsupported kubelet configuration v1/Pod"));
+    }
+
+    #[test]
+    fn invalid_kubelet_config_duration_is_reported() {
+        let kubelet_config = KubeletConfig::from_reader(
+            r#"
+apiVersion: kubelet.config.k8s.io/v1beta1
+kind: KubeletConfiguration
+nodeStatusUpdateFrequency: often
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let error = kubelet_config.into_builder().unwrap_err();
+        assert!(error.to_string().contains("nodeStatusUpdateFrequency"));
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("1m30s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("0").unwrap(), Duration::from_secs(0));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10d").is_err());
+    }
+
+    #[test]
+    fn unknown_eviction_signals_are_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "evictionHard": {
+                "cpu.available": "1"
+            }
+        }"#,
+        );
+        let error = config_builder.unwrap().build(fallbacks()).unwrap_err();
+        assert!(error.to_string().contains("unknown signal cpu.available"));
+    }
+
+    #[test]
+    fn invalid_reserved_resources_are_reported() {
+        for reserved in &[r#"{ "pods": "10" }"#, r#"{ "memory": "lots" }"#] {
+            let config_builder =
+                builder_from_json_string(&format!(r#"{{ "systemReserved": {} }}"#, reserved));
+            let error = config_builder.unwrap().build(fallbacks()).unwrap_err();
+            assert!(
+                error.to_string().contains("invalid system reserved"),
+                "{}",
+                error
+            );
+        }
+        let config_builder = builder_from_json_string(r#"{ "kubeReserved": { "cpu": "1x" } }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn zero_node_status_update_frequency_is_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "nodeStatusUpdateFrequencySeconds": 0 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn zero_node_lease_duration_is_reported() {
+        let config_builder = builder_from_json_string(r#"{ "nodeLeaseDurationSeconds": 0 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn zero_cpu_limit_tick_interval_is_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "cpuLimitTickIntervalMilliseconds": 0 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn zero_ephemeral_storage_check_interval_is_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "ephemeralStorageCheckIntervalSeconds": 0 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn zero_readiness_gate_poll_interval_is_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "readinessGatePollIntervalSeconds": 0 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn critical_pod_shutdown_grace_periods_longer_than_the_total_are_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{ "shutdownGracePeriodSeconds": 10, "shutdownGracePeriodCriticalPodsSeconds": 20 }"#,
+        );
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn shutdown_inhibitors_need_the_systemd_feature() {
+        let config_builder = builder_from_json_string(r#"{ "shutdownInhibitor": true }"#);
+        let config = config_builder.unwrap().build(fallbacks());
+        if cfg!(feature = "systemd") {
+            assert!(config.unwrap().shutdown_inhibitor);
+        } else {
+            assert!(config.is_err());
+        }
+    }
+
+    #[test]
+    fn too_few_container_log_files_are_reported() {
+        let config_builder = builder_from_json_string(r#"{ "containerLogMaxFiles": 1 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn negative_node_status_max_images_other_than_minus_one_are_reported() {
+        let config_builder = builder_from_json_string(r#"{ "nodeStatusMaxImages": -2 }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn oidc_issuers_without_client_ids_are_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "oidcIssuerUrl": "https://dex.krustlet.test" }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn unknown_cpu_manager_policies_are_reported() {
+        let config_builder = builder_from_json_string(r#"{ "cpuManagerPolicy": "dynamic" }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn unknown_memory_manager_policies_are_reported() {
+        let config_builder = builder_from_json_string(r#"{ "memoryManagerPolicy": "static" }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn unknown_topology_manager_policies_are_reported() {
+        let config_builder =
+            builder_from_json_string(r#"{ "topologyManagerPolicy": "single-numa" }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn unknown_authorization_modes_are_reported() {
+        let config_builder = builder_from_json_string(r#"{ "authorizationMode": "Node" }"#);
+        assert!(config_builder.unwrap().build(fallbacks()).is_err());
+    }
+
+    #[test]
+    fn taints_are_read_from_the_config_file() {
+        let config = builder_from_json_string(
+            r#"{ "registerWithTaints": [{ "key": "workload", "value": "wasm", "effect": "NoSchedule" }] }"#,
+        )
+        .unwrap()
+        .build(fallbacks())
+        .unwrap();
+        assert_eq!(
+            config.register_with_taints,
+            vec![Taint {
+                key: "workload".to_owned(),
+                value: Some("wasm".to_owned()),
+                effect: "NoSchedule".to_owned(),
+                time_added: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_node_labels_and_taints_are_reported() {
+        for json in &[
+            r#"{ "nodeLabels": { "bad key": "value" } }"#,
+            r#"{ "nodeLabels": { "Example.com/workload": "wasm" } }"#,
+            r#"{ "nodeLabels": { "workload": "-wasm" } }"#,
+            r#"{ "registerWithTaints": [{ "key": "workload", "effect": "Sometimes" }] }"#,
+        ] {
+            let config_builder = builder_from_json_string(json);
+            assert!(
+                config_builder.unwrap().build(fallbacks()).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn label_keys_may_have_a_dns_subdomain_prefix() {
+        assert!(validate_label("workload-type", "wasm32-wasi").is_ok());
+        assert!(validate_label("krustlet.dev/workload_type", "").is_ok());
+        assert!(validate_label("krustlet.dev/", "wasm").is_err());
+        assert!(validate_label("/workload", "wasm").is_err());
+        assert!(validate_label("krustlet..dev/workload", "wasm").is_err());
+        assert!(validate_label(&"k".repeat(64), "wasm").is_err());
+        assert!(validate_label("workload", &"w".repeat(64)).is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn node_label_flags_must_be_key_value_pairs() {
+        assert_eq!(
+            parse_node_label("krustlet.dev/workload-type=wasm32-wasi").unwrap(),
+            (
+                "krustlet.dev/workload-type".to_owned(),
+                "wasm32-wasi".to_owned()
+            )
+        );
+        assert_eq!(
+            parse_node_label("empty=").unwrap(),
+            ("empty".to_owned(), String::new())
+        );
+        for malformed in &["novalue", "=value", "bad key=value", "key=bad value", ""] {
+            assert!(parse_node_label(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn taint_flags_are_parsed_with_or_without_values() {
+        let taint = parse_taint("workload=wasm:NoSchedule").unwrap();
+        assert_eq!(taint.key, "workload");
+        assert_eq!(taint.value.as_deref(), Some("wasm"));
+        assert_eq!(taint.effect, "NoSchedule");
+        let taint = parse_taint("dedicated:NoExecute").unwrap();
+        assert_eq!(taint.key, "dedicated");
+        assert_eq!(taint.value, None);
+        for malformed in &[
+            "workload=wasm",
+            "workload=wasm:Sometimes",
+            ":NoSchedule",
+            "bad key=wasm:NoSchedule",
+            "workload=wasm:",
+        ] {
+            assert!(parse_taint(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn reserved_resource_flags_must_be_resource_quantity_pairs() {
+        assert_eq!(
+            parse_reserved_resource("memory = 256Mi").unwrap(),
+            ("memory".to_owned(), "256Mi".to_owned())
+        );
+        for malformed in &["memory", "=256Mi", ""] {
+            assert!(parse_reserved_resource(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn dual_stack_node_ips_are_kept_in_order() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "nodeIP": "fd00::2, 173.183.193.2"
+        }"#,
+        );
+        let config = config_builder.unwrap().build(fallbacks()).unwrap();
+        assert_eq!(format!("{}", config.node_ip), "fd00::2");
+        assert_eq!(
+            config.node_ips,
+            vec![
+                "fd00::2".parse::<IpAddr>().unwrap(),
+                "173.183.193.2".parse::<IpAddr>().unwrap()
+            ]
+        );
+        // Dual-stack nodes listen on both families
+        assert_eq!(format!("{}", config.server_config.addr), "::");
+
+        let config_builder = builder_from_json_string(
+            r#"{
+            "listenerAddress": "172.182.192.1",
+            "nodeIP": "173.183.193.2"
+        }"#,
+        );
+        let config = config_builder.unwrap().build(fallbacks()).unwrap();
+        assert_eq!(config.node_ips, vec![config.node_ip]);
+        assert_eq!(format!("{}", config.server_config.addr), "172.182.192.1");
+    }
+
+    #[test]
+    fn node_ips_must_be_one_of_each_family() {
+        let ips =
+            |ips: &[&str]| -> Vec<IpAddr> { ips.iter().map(|ip| ip.parse().unwrap()).collect() };
+        assert!(validate_node_ips(ips(&["10.0.0.2"])).is_ok());
+        assert_eq!(
+            validate_node_ips(ips(&["10.0.0.2", "fd00::2"])).unwrap(),
+            ips(&["10.0.0.2", "fd00::2"])
+        );
+        assert_eq!(
+            validate_node_ips(ips(&["fd00::2", "10.0.0.2"])).unwrap(),
+            ips(&["fd00::2", "10.0.0.2"])
+        );
+        for invalid in &[
+            vec![],
+            ips(&["10.0.0.2", "10.0.0.3"]),
+            ips(&["fd00::2", "fd00::3"]),
+            ips(&["10.0.0.2", "fd00::2", "10.0.0.3"]),
+            ips(&["0.0.0.0"]),
+            ips(&["10.0.0.2", "ff02::1"]),
+        ] {
+            assert!(validate_node_ips(invalid.clone()).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn node_ips_must_be_assigned_to_the_node() {
+        let config_builder = builder_from_json_string(r#"{ "nodeIP": "173.183.193.2" }"#);
+        let mut fallbacks = fallbacks();
+        fallbacks.check_node_ip = |ip| Err(anyhow::anyhow!("{} is not local", ip));
+        assert!(config_builder.unwrap().build(fallbacks).is_err());
+        assert!(check_local_ip(&IpAddr::V4(Ipv4Addr::LOCALHOST)).is_ok());
+        assert!(check_local_ip(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).is_err());
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn node_ip_flags_are_separated_by_commas() {
+        let opts = Opts::from_iter_safe(&["krustlet", "--node-ip", "10.0.0.2,fd00::2"]).unwrap();
+        assert_eq!(
+            opts.node_ip,
+            vec![
+                "10.0.0.2".parse::<IpAddr>().unwrap(),
+                "fd00::2".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert!(Opts::from_iter_safe(&["krustlet", "--node-ip", "10.0.0.2,nope"]).is_err());
+    }
 }
@@ -3,6 +3,7 @@
 
 use crate::config::Config;
 use oci_distribution::client::{ClientConfig, ClientConfigSource, ClientProtocol};
+use oci_distribution::proxy::ProxyConfig;
 
 impl ClientConfigSource for Config {
     fn client_config(&self) -> ClientConfig {
@@ -10,7 +11,15 @@ impl ClientConfigSource for Config {
             None => ClientProtocol::default(),
             Some(registries) => ClientProtocol::HttpsExcept(registries.clone()),
         };
-        ClientConfig { protocol }
+        let proxy = match &self.registry_proxy {
+            None => ProxyConfig::default(),
+            Some(proxy) => ProxyConfig::new(proxy),
+        };
+        ClientConfig {
+            protocol,
+            proxy,
+            ..Default::default()
+        }
     }
 }
 
@@ -25,20 +34,64 @@ mod test {
         // to derive a node IP address
         Config {
             allow_local_modules: false,
+            secrets_in_memory: false,
+            reregister_node: true,
+            deregister_on_shutdown: false,
             bootstrap_file: std::path::PathBuf::from("/nope"),
+            rotate_certificates: false,
             data_dir: std::path::PathBuf::from("/nope"),
             hostname: "nope".to_owned(),
             insecure_registries: None,
+            registry_proxy: None,
+            image_verification_key_file: None,
+            pull_progress_interval: std::time::Duration::from_secs(5),
+            readiness_gate_poll_interval: std::time::Duration::from_secs(5),
+            docker_config_file: None,
+            node_status_update_frequency: std::time::Duration::from_secs(300),
+            node_lease_duration: std::time::Duration::from_secs(40),
+            eviction_hard: std::collections::HashMap::new(),
+            system_reserved: std::collections::HashMap::new(),
+            kube_reserved: std::collections::HashMap::new(),
+            feature_gates: crate::feature_gate::FeatureGates::default(),
+            default_container_memory_limit: None,
+            cpu_limit_tick_interval: std::time::Duration::from_millis(10),
+            cpu_manager_policy: crate::cpu_manager::CpuManagerPolicy::None,
+            memory_manager_policy: crate::memory_manager::MemoryManagerPolicy::None,
+            topology_manager_policy: crate::topology_manager::TopologyManagerPolicy::None,
+            ephemeral_storage_check_interval: std::time::Duration::from_secs(10),
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            shutdown_grace_period_critical_pods: std::time::Duration::from_secs(0),
+            shutdown_inhibitor: false,
+            container_log_max_size: 10 * 1024 * 1024,
+            container_log_max_files: 5,
+            node_status_max_images: Some(50),
+            record_transitions: None,
             plugins_dir: std::path::PathBuf::from("/nope"),
+            device_plugins_dir: std::path::PathBuf::from("/nope"),
             max_pods: 0,
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            node_ips: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
             node_labels: std::collections::HashMap::new(),
+            register_with_taints: vec![],
             node_name: "nope".to_owned(),
+            provider_id: None,
             server_config: crate::config::ServerConfig {
                 addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                client_ca_file: None,
+                rotate_certificates: false,
+                renewal_threshold_days: None,
+                oidc: None,
+                authentication_token_webhook: false,
+                anonymous_auth: false,
+                authorization_mode: crate::config::AuthorizationMode::AlwaysAllow,
+                read_only_port: 0,
+                max_log_follows: 256,
+                max_exec_sessions: 128,
+                max_port_forwards: 128,
+                streaming_connection_idle_timeout: std::time::Duration::from_secs(4 * 60 * 60),
             },
         }
     }
@@ -63,4 +116,19 @@ mod test {
             ClientProtocol::HttpsExcept(vec!["local".to_owned(), "dev".to_owned()]);
         assert_eq!(expected_protocol, client_config.protocol);
     }
+
+    #[test]
+    fn oci_config_respects_config_registry_proxy() {
+        let config = Config {
+            registry_proxy: Some("http://proxy.corp:3128".to_owned()),
+            ..empty_config()
+        };
+
+        let client_config = config.client_config();
+
+        assert_eq!(
+            ProxyConfig::new("http://proxy.corp:3128"),
+            client_config.proxy
+        );
+    }
 }
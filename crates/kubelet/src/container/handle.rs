@@ -1,6 +1,4 @@
-use std::io::SeekFrom;
-
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+use tokio::io::AsyncRead;
 
 use crate::container::ContainerMap;
 use crate::handle::StopHandler;
@@ -37,15 +35,36 @@ impl<H: StopHandler, F> Handle<H, F> {
         self.handle.stop().await
     }
 
-    /// Streams output from the running process into the given sender.
+    /// Whether the log of the previous instance of the container was kept.
+    pub(crate) fn has_previous<R>(&self) -> bool
+    where
+        F: HandleFactory<R>,
+    {
+        self.handle_factory.previous().is_some()
+    }
+
+    /// Streams output from the running process, or from the previous instance
+    /// if the request asked for it, into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
     pub(crate) async fn output<R>(&mut self, sender: Sender) -> anyhow::Result<()>
     where
-        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
         F: HandleFactory<R>,
     {
-        let mut handle = self.handle_factory.new_handle();
-        handle.seek(SeekFrom::Start(0)).await?;
+        let previous;
+        let handle_factory = if sender.previous() {
+            previous = self
+                .handle_factory
+                .previous()
+                .ok_or_else(|| anyhow::anyhow!("previous container log not found"))?;
+            &previous
+        } else {
+            &self.handle_factory
+        };
+        let handle = match sender.tail() {
+            Some(lines) => handle_factory.new_tail_handle(lines, sender.stream()),
+            None => handle_factory.new_handle(),
+        };
         tokio::spawn(stream(handle, sender));
         Ok(())
     }
@@ -0,0 +1,205 @@
+//! Lifecycle hooks of containers.
+//!
+//! A container's `postStart` hook runs once it has started, and its `preStop`
+//! hook before it is stopped. `exec` hooks run a command in the container, so
+//! they are left to providers to run. `httpGet` hooks are requests to the
+//! container, which [`http_get`] sends the same way for every provider.
+use k8s_openapi::api::core::v1::{HTTPGetAction, Handler};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use tracing::debug;
+
+use super::Container;
+use crate::pod::Pod;
+
+/// The host `httpGet` hooks are sent to when the pod has no IP yet
+const DEFAULT_HOOK_HOST: &str = "127.0.0.1";
+
+/// What a lifecycle hook does
+#[derive(Clone, Debug)]
+pub enum Hook {
+    /// Runs the command in the container
+    Exec(Vec<String>),
+    /// Sends a GET request to the container
+    HttpGet(HTTPGetAction),
+}
+
+impl Hook {
+    /// The hook the handler defines. Handlers that neither run a command nor
+    /// send a request, such as `tcpSocket` handlers, aren't supported.
+    pub fn from_handler(handler: &Handler) -> anyhow::Result<Self> {
+        match (&handler.exec, &handler.http_get) {
+            (Some(exec), _) => Ok(Hook::Exec(exec.command.clone().unwrap_or_default())),
+            (None, Some(http_get)) => Ok(Hook::HttpGet(http_get.clone())),
+            (None, None) => Err(anyhow::anyhow!("only exec and httpGet hooks are supported")),
+        }
+    }
+}
+
+/// Sends the GET request of a container's `httpGet` hook, failing if it
+/// can't be sent or is answered with an error status
+pub async fn http_get(
+    pod: &Pod,
+    container: &Container,
+    action: &HTTPGetAction,
+) -> anyhow::Result<()> {
+    let url = http_get_url(pod, container, action)?;
+    debug!("Sending hook request to {}", url);
+    let mut request = reqwest::Client::new().get(&url);
+    for header in action.http_headers.iter().flatten() {
+        request = request.header(header.name.as_str(), header.value.as_str());
+    }
+    let response = request.send().await?;
+    // As with other kubelets, redirects count as success
+    if response.status().as_u16() >= 400 {
+        return Err(anyhow::anyhow!(
+            "hook request to {} was answered with {}",
+            url,
+            response.status()
+        ));
+    }
+    Ok(())
+}
+
+fn http_get_url(
+    pod: &Pod,
+    container: &Container,
+    action: &HTTPGetAction,
+) -> anyhow::Result<String> {
+    let port = match &action.port {
+        IntOrString::Int(port) => *port,
+        IntOrString::String(name) => container
+            .ports()
+            .iter()
+            .flatten()
+            .find(|port| port.name.as_deref() == Some(name.as_str()))
+            .map(|port| port.container_port)
+            .ok_or_else(|| anyhow::anyhow!("container has no port named {}", name))?,
+    };
+    let scheme = action.scheme.as_deref().unwrap_or("HTTP").to_lowercase();
+    let host = action
+        .host
+        .as_deref()
+        .or_else(|| pod.pod_ip())
+        .unwrap_or(DEFAULT_HOOK_HOST);
+    let path = action.path.as_deref().unwrap_or_default();
+    let separator = if path.starts_with('/') { "" } else { "/" };
+    Ok(format!(
+        "{}://{}:{}{}{}",
+        scheme, host, port, separator, path
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, ContainerPort, ExecAction, HTTPHeader, Pod as KubePod,
+        PodStatus, TCPSocketAction,
+    };
+
+    fn container() -> Container {
+        Container::new(&KubeContainer {
+            name: "web".to_owned(),
+            ports: Some(vec![ContainerPort {
+                name: Some("http".to_owned()),
+                container_port: 8080,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        })
+    }
+
+    fn pod(pod_ip: Option<&str>) -> Pod {
+        Pod::from(KubePod {
+            status: Some(PodStatus {
+                pod_ip: pod_ip.map(str::to_owned),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn handlers_are_exec_or_http_get_hooks() {
+        let exec = Handler {
+            exec: Some(ExecAction {
+                command: Some(vec!["drain".to_owned()]),
+            }),
+            ..Default::default()
+        };
+        assert!(
+            matches!(Hook::from_handler(&exec), Ok(Hook::Exec(command)) if command == vec!["drain"])
+        );
+        let tcp_socket = Handler {
+            tcp_socket: Some(TCPSocketAction {
+                port: IntOrString::Int(8080),
+                host: None,
+            }),
+            ..Default::default()
+        };
+        assert!(Hook::from_handler(&tcp_socket).is_err());
+    }
+
+    #[test]
+    fn http_get_hooks_are_sent_to_the_pod_on_named_or_numbered_ports() {
+        let named = HTTPGetAction {
+            port: IntOrString::String("http".to_owned()),
+            path: Some("drain".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            http_get_url(&pod(Some("10.0.0.5")), &container(), &named).unwrap(),
+            "http://10.0.0.5:8080/drain"
+        );
+        let numbered = HTTPGetAction {
+            port: IntOrString::Int(9000),
+            host: Some("localhost".to_owned()),
+            scheme: Some("HTTPS".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            http_get_url(&pod(None), &container(), &numbered).unwrap(),
+            "https://localhost:9000/"
+        );
+        let missing = HTTPGetAction {
+            port: IntOrString::String("admin".to_owned()),
+            ..Default::default()
+        };
+        assert!(http_get_url(&pod(None), &container(), &missing).is_err());
+    }
+
+    #[tokio::test]
+    async fn http_get_hooks_fail_on_error_statuses() {
+        use warp::Filter;
+
+        let routes = warp::path("drain")
+            .and(warp::header::exact("x-hook", "preStop"))
+            .map(|| "drained")
+            .or(warp::path("fail").map(|| {
+                warp::reply::with_status("failed", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as i32;
+        tokio::spawn(
+            warp::serve(routes)
+                .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let action = |path: &str| HTTPGetAction {
+            port: IntOrString::Int(port),
+            path: Some(path.to_owned()),
+            http_headers: Some(vec![HTTPHeader {
+                name: "x-hook".to_owned(),
+                value: "preStop".to_owned(),
+            }]),
+            ..Default::default()
+        };
+        let pod = pod(Some("127.0.0.1"));
+        assert!(http_get(&pod, &container(), &action("/drain"))
+            .await
+            .is_ok());
+        assert!(http_get(&pod, &container(), &action("/fail"))
+            .await
+            .is_err());
+    }
+}
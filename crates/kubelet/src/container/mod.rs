@@ -6,11 +6,14 @@ use std::convert::TryInto;
 use std::fmt::Display;
 
 mod handle;
+pub mod hook;
 pub mod state;
 mod status;
 
 pub use handle::{Handle, HandleMap};
-pub use status::{make_initial_container_status, patch_container_status, Status};
+pub use status::{
+    make_initial_container_status, patch_container_status, ContainerStateInfo, Status,
+};
 
 /// Specifies how the store should check for module updates
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -200,6 +203,24 @@ impl Container {
         self.0.resources.as_ref()
     }
 
+    /// Get the memory limit of the container in bytes, if it has one.
+    pub fn memory_limit(&self) -> anyhow::Result<Option<u64>> {
+        self.resources()
+            .and_then(|r| r.limits.as_ref())
+            .and_then(|l| l.get("memory"))
+            .map(|q| crate::resources::parse_quantity(&q.0))
+            .transpose()
+    }
+
+    /// Get the CPU limit of the container in millicores, if it has one.
+    pub fn cpu_limit_millis(&self) -> anyhow::Result<Option<u64>> {
+        self.resources()
+            .and_then(|r| r.limits.as_ref())
+            .and_then(|l| l.get("cpu"))
+            .map(|q| crate::resources::parse_milli_quantity(&q.0))
+            .transpose()
+    }
+
     /// Get security context of container.
     pub fn security_context(&self) -> Option<&k8s_openapi::api::core::v1::SecurityContext> {
         self.0.security_context.as_ref()
@@ -133,6 +133,9 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                         timestamp: Utc::now(),
                         message: format!("Container exited with error: {:?}.", e),
                         failed: true,
+                        reason: None,
+                        started_at: None,
+                        exit_code: None,
                     };
                     patch_container_status(&api, &latest_pod, &container_name, &status)
                         .await
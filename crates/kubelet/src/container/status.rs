@@ -8,6 +8,13 @@ use k8s_openapi::api::core::v1::{
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use tracing::{debug, warn};
 
+/// The reason terminated containers that failed are reported with when they
+/// have none of their own
+const ERROR_REASON: &str = "Error";
+/// The reason terminated containers that succeeded are reported with when
+/// they have none of their own
+const COMPLETED_REASON: &str = "Completed";
+
 /// Status is a simplified version of the Kubernetes container status
 /// for use in providers. It allows for simple creation of the current status of
 /// a "container" (a running wasm process) without worrying about a bunch of
@@ -24,20 +31,44 @@ pub enum Status {
     },
     /// The container is running
     Running {
-        /// The timestamp of when this status was reported
+        /// The timestamp of when this status was reported, which is reported
+        /// as when the container started
         timestamp: DateTime<Utc>,
     },
     /// The container is terminated
     Terminated {
-        /// The timestamp of when this status was reported
+        /// The timestamp of when this status was reported, which is reported
+        /// as when the container finished
         timestamp: DateTime<Utc>,
         /// A human readable string describing the why it is in a terminating status
         message: String,
         /// Should be set to true if the process exited with an error
         failed: bool,
+        /// A brief CamelCase reason for the termination, such as `OOMKilled`.
+        /// If unset, the reason is `Error` for failed containers and
+        /// `Completed` for the others.
+        reason: Option<String>,
+        /// When the container started, if it did
+        started_at: Option<DateTime<Utc>>,
+        /// The code the container exited with. If unset, 1 is reported for
+        /// failed containers and 0 for the others.
+        exit_code: Option<i32>,
     },
 }
 
+/// When a container started and, once it has terminated, when it finished
+/// and what it exited with, as recorded by providers. Use
+/// [Status::with_state_info] to report it in a container's status.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerStateInfo {
+    /// When the container started
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the container finished
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The code the container exited with
+    pub exit_code: Option<i32>,
+}
+
 impl Status {
     /// Create `Status::Waiting` from message.
     pub fn waiting(message: &str) -> Self {
@@ -60,7 +91,45 @@ impl Status {
             timestamp: Utc::now(),
             message: message.to_string(),
             failed,
+            reason: None,
+            started_at: None,
+            exit_code: None,
+        }
+    }
+
+    /// Create `Status::Terminated` from message, failed `bool` and a brief
+    /// CamelCase reason, such as `OOMKilled`.
+    pub fn terminated_with_reason(message: &str, failed: bool, reason: &str) -> Self {
+        Status::Terminated {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+            failed,
+            reason: Some(reason.to_string()),
+            started_at: None,
+            exit_code: None,
+        }
+    }
+
+    /// Reports the times and exit code in `info` in place of those of the
+    /// status. What `info` doesn't have is left as it is.
+    pub fn with_state_info(mut self, info: &ContainerStateInfo) -> Self {
+        match &mut self {
+            Self::Waiting { .. } => (),
+            Self::Running { timestamp } => {
+                *timestamp = info.started_at.unwrap_or(*timestamp);
+            }
+            Self::Terminated {
+                timestamp,
+                started_at,
+                exit_code,
+                ..
+            } => {
+                *timestamp = info.finished_at.unwrap_or(*timestamp);
+                *started_at = info.started_at.or(*started_at);
+                *exit_code = info.exit_code.or(*exit_code);
+            }
         }
+        self
     }
 
     /// Convert the container status to a Kubernetes API compatible type
@@ -82,11 +151,23 @@ impl Status {
                 timestamp,
                 message,
                 failed,
+                reason,
+                started_at,
+                exit_code,
             } => {
                 state.terminated.replace(ContainerStateTerminated {
+                    started_at: started_at.map(Time),
                     finished_at: Some(Time(*timestamp)),
                     message: Some(message.clone()),
-                    exit_code: *failed as i32,
+                    exit_code: exit_code.unwrap_or(*failed as i32),
+                    reason: Some(reason.clone().unwrap_or_else(|| {
+                        if *failed {
+                            ERROR_REASON
+                        } else {
+                            COMPLETED_REASON
+                        }
+                        .to_owned()
+                    })),
                     ..Default::default()
                 });
             }
@@ -198,3 +279,63 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn recorded_times_and_exit_codes_are_reported() {
+        let started_at = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let finished_at = Utc.ymd(2021, 3, 1).and_hms(12, 5, 0);
+        let running = Status::running().with_state_info(&ContainerStateInfo {
+            started_at: Some(started_at),
+            ..Default::default()
+        });
+        let state = running.to_kubernetes("web").state.unwrap();
+        assert_eq!(state.running.unwrap().started_at, Some(Time(started_at)));
+
+        let info = ContainerStateInfo {
+            started_at: Some(started_at),
+            finished_at: Some(finished_at),
+            exit_code: Some(3),
+        };
+        let terminated = Status::terminated("Module exited with code 3", true)
+            .with_state_info(&info)
+            .to_kubernetes("web");
+        let terminated = terminated.state.unwrap().terminated.unwrap();
+        assert_eq!(terminated.started_at, Some(Time(started_at)));
+        assert_eq!(terminated.finished_at, Some(Time(finished_at)));
+        assert_eq!(terminated.exit_code, 3);
+
+        // Without a recorded exit code, failures exit with 1
+        let failed = Status::terminated("unable to run module", true).to_kubernetes("web");
+        let failed = failed.state.unwrap().terminated.unwrap();
+        assert_eq!(failed.exit_code, 1);
+        assert_eq!(failed.started_at, None);
+        assert_eq!(failed.reason.as_deref(), Some("Error"));
+        let reason = |status: Status| {
+            status
+                .to_kubernetes("web")
+                .state
+                .unwrap()
+                .terminated
+                .unwrap()
+                .reason
+        };
+        assert_eq!(
+            reason(Status::terminated("Module run completed", false)).as_deref(),
+            Some("Completed")
+        );
+        assert_eq!(
+            reason(Status::terminated_with_reason(
+                "out of memory",
+                true,
+                "OOMKilled"
+            ))
+            .as_deref(),
+            Some("OOMKilled")
+        );
+    }
+}
@@ -0,0 +1,683 @@
+//! The Kubelet CPU manager, which gives the containers of some pods exclusive
+//! use of CPUs, like the [CPU
+//! manager](https://kubernetes.io/docs/tasks/administer-cluster/cpu-management-policies/)
+//! of other kubelets.
+//!
+//! With the `none` policy, which is the default, containers run on any of the
+//! node's CPUs, and only their CPU limits constrain them. With the `static`
+//! policy, each pod whose QoS class is Guaranteed is assigned as many CPUs as
+//! the whole CPUs its containers request, and the threads running its
+//! containers are affined to those CPUs. The threads of other containers are
+//! affined to the shared pool, which is the CPUs not assigned to any pod, so
+//! that they never run on an assigned CPU. The lowest numbered CPU is always
+//! left in the shared pool. The containers of a pod share the CPUs assigned
+//! to it. The CPUs of a pod whose containers are resized while they run are
+//! resized with them (see [`CpuManager::resize`]).
+//!
+//! Threads are only affined on Linux, which is the only platform the `static`
+//! policy is supported on.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, warn};
+
+use crate::container::Container;
+use crate::memory_manager::NumaNode;
+use crate::pod::Pod;
+use crate::resources::{parse_milli_quantity, parse_quantity};
+use crate::topology_manager::{cpus_in, generate_hints, mask_of_cpus, HintProvider, TopologyHint};
+
+/// How the CPU manager assigns CPUs to containers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CpuManagerPolicy {
+    /// Containers run on any CPU
+    #[default]
+    None,
+    /// Pods whose QoS class is Guaranteed are assigned exclusive CPUs for
+    /// each whole CPU their containers request
+    Static,
+}
+
+impl std::str::FromStr for CpuManagerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CpuManagerPolicy::None),
+            "static" => Ok(CpuManagerPolicy::Static),
+            _ => Err(anyhow::anyhow!(
+                "unknown CPU manager policy {:?}: expected none or static",
+                s
+            )),
+        }
+    }
+}
+
+/// The OS ID of a thread
+type ThreadId = i32;
+
+/// Tracks the CPUs assigned to pods, and affines the threads running
+/// containers to the CPUs they may use
+pub struct CpuManager {
+    policy: CpuManagerPolicy,
+    /// The CPUs containers may run on, in order
+    cpus: Vec<usize>,
+    state: Mutex<CpuState>,
+}
+
+#[derive(Default)]
+struct CpuState {
+    /// The CPUs assigned to each pod, by UID
+    assignments: HashMap<String, Vec<usize>>,
+    /// The threads running containers, with the UID of each one's pod, which
+    /// are affined again whenever the CPUs they may use change
+    threads: HashMap<u64, (String, ThreadId)>,
+    next_thread: u64,
+}
+
+impl CpuState {
+    fn shared_cpus(&self, cpus: &[usize]) -> Vec<usize> {
+        cpus.iter()
+            .copied()
+            .filter(|cpu| !self.assignments.values().any(|a| a.contains(cpu)))
+            .collect()
+    }
+
+    /// The CPUs the containers of the pod with the given UID may run on
+    fn pod_cpus(&self, pod_uid: &str, cpus: &[usize]) -> Vec<usize> {
+        match self.assignments.get(pod_uid) {
+            Some(assigned) => assigned.clone(),
+            None => self.shared_cpus(cpus),
+        }
+    }
+}
+
+impl CpuManager {
+    /// Creates a CPU manager with the given policy, which assigns the CPUs
+    /// the kubelet itself may run on
+    pub fn new(policy: CpuManagerPolicy) -> anyhow::Result<Arc<Self>> {
+        #[cfg(target_os = "linux")]
+        let cpus = affinity(0)
+            .map_err(|e| anyhow::anyhow!("unable to read the CPUs the kubelet may use: {}", e))?;
+        #[cfg(not(target_os = "linux"))]
+        let cpus = {
+            if policy == CpuManagerPolicy::Static {
+                return Err(anyhow::anyhow!(
+                    "the static CPU manager policy is only supported on Linux"
+                ));
+            }
+            vec![]
+        };
+        Ok(Self::with_cpus(policy, cpus))
+    }
+
+    fn with_cpus(policy: CpuManagerPolicy, cpus: Vec<usize>) -> Arc<Self> {
+        Arc::new(CpuManager {
+            policy,
+            cpus,
+            state: Mutex::new(CpuState::default()),
+        })
+    }
+
+    /// The policy CPUs are assigned with
+    pub fn policy(&self) -> CpuManagerPolicy {
+        self.policy
+    }
+
+    /// Assigns `num_cpus` exclusive CPUs to the pod with the given UID,
+    /// returning them. A pod that already has CPUs keeps the ones it has, so
+    /// that its containers can restart on them. With the `none` policy, or if
+    /// `num_cpus` is 0, no CPUs are assigned. Fails if there aren't enough
+    /// CPUs left in the shared pool.
+    pub fn assign_cpus(&self, pod_uid: &str, num_cpus: usize) -> anyhow::Result<Vec<usize>> {
+        self.assign_aligned_cpus(pod_uid, num_cpus, &[])
+    }
+
+    /// Assigns CPUs like [`CpuManager::assign_cpus`], but takes them from
+    /// the `aligned` CPUs first, such as those the [topology
+    /// manager](crate::topology_manager) aligned the pod's resources on.
+    pub fn assign_aligned_cpus(
+        &self,
+        pod_uid: &str,
+        num_cpus: usize,
+        aligned: &[usize],
+    ) -> anyhow::Result<Vec<usize>> {
+        if self.policy == CpuManagerPolicy::None || num_cpus == 0 {
+            return Ok(vec![]);
+        }
+        let mut state = self.lock();
+        if let Some(assigned) = state.assignments.get(pod_uid) {
+            return Ok(assigned.clone());
+        }
+        // The lowest numbered CPU is kept for the shared pool
+        let mut available: Vec<usize> = state.shared_cpus(&self.cpus).into_iter().skip(1).collect();
+        available.sort_by_key(|cpu| !aligned.contains(cpu));
+        if available.len() < num_cpus {
+            return Err(anyhow::anyhow!(
+                "not enough CPUs available: requested {}, but only {} are left in the shared pool",
+                num_cpus,
+                available.len()
+            ));
+        }
+        let mut assigned: Vec<usize> = available.into_iter().take(num_cpus).collect();
+        assigned.sort_unstable();
+        debug!("Assigning CPUs {:?} to pod {}", assigned, pod_uid);
+        state
+            .assignments
+            .insert(pod_uid.to_owned(), assigned.clone());
+        self.update_threads(&state);
+        Ok(assigned)
+    }
+
+    /// Changes the number of exclusive CPUs assigned to the pod with the given
+    /// UID to `num_cpus`, such as when its containers are resized while they
+    /// run, and affines the threads running its containers to its new CPUs.
+    /// The pod keeps the CPUs it has that it still needs, and gives the rest
+    /// back to the shared pool. With the `none` policy, no CPUs are assigned.
+    /// Fails, leaving the pod's CPUs as they were, if there aren't enough CPUs
+    /// left in the shared pool.
+    pub fn resize(&self, pod_uid: &str, num_cpus: usize) -> anyhow::Result<Vec<usize>> {
+        if self.policy == CpuManagerPolicy::None {
+            return Ok(vec![]);
+        }
+        let mut state = self.lock();
+        let mut assigned = state.assignments.get(pod_uid).cloned().unwrap_or_default();
+        if assigned.len() == num_cpus {
+            return Ok(assigned);
+        }
+        if num_cpus < assigned.len() {
+            assigned.truncate(num_cpus);
+        } else {
+            let needed = num_cpus - assigned.len();
+            // The lowest numbered CPU is kept for the shared pool
+            let available: Vec<usize> = state.shared_cpus(&self.cpus).into_iter().skip(1).collect();
+            if available.len() < needed {
+                return Err(anyhow::anyhow!(
+                    "not enough CPUs available: {} more requested, but only {} are left in the shared pool",
+                    needed,
+                    available.len()
+                ));
+            }
+            assigned.extend(available.into_iter().take(needed));
+            assigned.sort_unstable();
+        }
+        debug!("Resizing the CPUs of pod {} to {:?}", pod_uid, assigned);
+        if assigned.is_empty() {
+            state.assignments.remove(pod_uid);
+        } else {
+            state
+                .assignments
+                .insert(pod_uid.to_owned(), assigned.clone());
+        }
+        self.update_threads(&state);
+        Ok(assigned)
+    }
+
+    /// Returns the CPUs assigned to the pod with the given UID to the shared
+    /// pool, such as once it is deleted
+    pub fn release(&self, pod_uid: &str) {
+        let mut state = self.lock();
+        if state.assignments.remove(pod_uid).is_some() {
+            debug!("Released the CPUs of pod {}", pod_uid);
+            self.update_threads(&state);
+        }
+    }
+
+    /// The CPUs that aren't assigned to any pod
+    pub fn shared_cpus(&self) -> Vec<usize> {
+        self.lock().shared_cpus(&self.cpus)
+    }
+
+    /// Returns the CPUs of the pod with the given UID, for the threads
+    /// running its containers to be affined to
+    pub fn pod(self: &Arc<Self>, pod_uid: &str) -> PodCpus {
+        PodCpus {
+            manager: self.clone(),
+            pod_uid: pod_uid.to_owned(),
+        }
+    }
+
+    fn update_threads(&self, state: &CpuState) {
+        for (pod_uid, thread) in state.threads.values() {
+            let cpus = state.pod_cpus(pod_uid, &self.cpus);
+            if let Err(e) = set_affinity(*thread, &cpus) {
+                warn!(
+                    "Unable to affine thread {} of pod {} to CPUs {:?}: {}",
+                    thread, pod_uid, cpus, e
+                );
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CpuState> {
+        self.state
+            .lock()
+            .expect("CPU manager lock should not be poisoned")
+    }
+}
+
+impl HintProvider for CpuManager {
+    fn topology_hints(
+        &self,
+        pod: &Pod,
+        topology: &[NumaNode],
+    ) -> anyhow::Result<HashMap<String, Vec<TopologyHint>>> {
+        let mut hints = HashMap::new();
+        if self.policy == CpuManagerPolicy::None {
+            return Ok(hints);
+        }
+        let num_cpus = exclusive_cpus(pod)?;
+        if num_cpus == 0 {
+            return Ok(hints);
+        }
+        let state = self.lock();
+        let cpu_hints = match state.assignments.get(pod.pod_uid()) {
+            Some(assigned) => vec![TopologyHint {
+                affinity: Some(mask_of_cpus(topology, assigned)),
+                preferred: true,
+            }],
+            None => {
+                let available: Vec<usize> =
+                    state.shared_cpus(&self.cpus).into_iter().skip(1).collect();
+                generate_hints(
+                    topology,
+                    |mask| {
+                        cpus_in(topology, mask)
+                            .iter()
+                            .filter(|cpu| available.contains(cpu))
+                            .count()
+                            >= num_cpus
+                    },
+                    |mask| cpus_in(topology, mask).len() >= num_cpus,
+                )
+            }
+        };
+        hints.insert("cpu".to_owned(), cpu_hints);
+        Ok(hints)
+    }
+}
+
+/// The CPUs that the containers of a pod may run on
+#[derive(Clone)]
+pub struct PodCpus {
+    manager: Arc<CpuManager>,
+    pod_uid: String,
+}
+
+impl PodCpus {
+    /// Affines the current thread to the CPUs assigned to the pod, or to the
+    /// shared pool if it has none, until the returned guard is dropped. The
+    /// guard must be dropped on the same thread, and affines it to all the
+    /// CPUs again, as threads may be reused to run other tasks. Failing to
+    /// affine the thread isn't fatal, as the container can still run.
+    pub fn pin_current_thread(&self) -> PinnedThread {
+        let manager = &self.manager;
+        if manager.policy == CpuManagerPolicy::None {
+            return PinnedThread {
+                manager: None,
+                thread: 0,
+                id: 0,
+            };
+        }
+        let thread = current_thread();
+        let mut state = manager.lock();
+        let id = state.next_thread;
+        state.next_thread += 1;
+        state.threads.insert(id, (self.pod_uid.clone(), thread));
+        let cpus = state.pod_cpus(&self.pod_uid, &manager.cpus);
+        if let Err(e) = set_affinity(thread, &cpus) {
+            warn!(
+                "Unable to affine thread {} of pod {} to CPUs {:?}: {}",
+                thread, self.pod_uid, cpus, e
+            );
+        }
+        PinnedThread {
+            manager: Some(manager.clone()),
+            thread,
+            id,
+        }
+    }
+}
+
+/// Keeps a thread affined to the CPUs of a pod until dropped
+pub struct PinnedThread {
+    manager: Option<Arc<CpuManager>>,
+    thread: ThreadId,
+    id: u64,
+}
+
+impl Drop for PinnedThread {
+    fn drop(&mut self) {
+        let manager = match &self.manager {
+            Some(manager) => manager,
+            None => return,
+        };
+        manager.lock().threads.remove(&self.id);
+        if let Err(e) = set_affinity(self.thread, &manager.cpus) {
+            warn!(
+                "Unable to restore the affinity of thread {}: {}",
+                self.thread, e
+            );
+        }
+    }
+}
+
+/// The number of exclusive CPUs the `static` policy assigns to the pod: the
+/// whole CPUs its containers request if its QoS class is Guaranteed, or 0
+/// otherwise. As init containers run before the other containers, the pod
+/// needs as many CPUs as the most any of them, or all the other containers,
+/// request.
+pub fn exclusive_cpus(pod: &Pod) -> anyhow::Result<usize> {
+    if !is_guaranteed_pod(pod)? {
+        return Ok(0);
+    }
+    let init_containers = pod.init_containers();
+    let containers = pod.containers();
+    let init = init_containers
+        .iter()
+        .map(whole_cpus)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let app = containers
+        .iter()
+        .map(whole_cpus)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(init
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+        .max(app.into_iter().sum()))
+}
+
+/// Whether the pod's QoS class is Guaranteed
+pub(crate) fn is_guaranteed_pod(pod: &Pod) -> anyhow::Result<bool> {
+    for container in pod.init_containers().iter().chain(pod.containers().iter()) {
+        if !is_guaranteed(container)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the container's CPU and memory are limited, with requests, if
+/// given, that are the same as the limits. A pod whose containers all are
+/// has the Guaranteed QoS class.
+fn is_guaranteed(container: &Container) -> anyhow::Result<bool> {
+    let resources = match container.resources() {
+        Some(resources) => resources,
+        None => return Ok(false),
+    };
+    let limits = match resources.limits.as_ref() {
+        Some(limits) => limits,
+        None => return Ok(false),
+    };
+    let requests = resources.requests.as_ref();
+    let cpu_limit = match limits.get("cpu") {
+        Some(limit) => parse_milli_quantity(&limit.0)?,
+        None => return Ok(false),
+    };
+    let memory_limit = match limits.get("memory") {
+        Some(limit) => parse_quantity(&limit.0)?,
+        None => return Ok(false),
+    };
+    if let Some(request) = requests.and_then(|r| r.get("cpu")) {
+        if parse_milli_quantity(&request.0)? != cpu_limit {
+            return Ok(false);
+        }
+    }
+    if let Some(request) = requests.and_then(|r| r.get("memory")) {
+        if parse_quantity(&request.0)? != memory_limit {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The CPUs the container requests if it requests whole CPUs, or 0 otherwise.
+/// This must only be called for containers that are Guaranteed, whose
+/// requests are their limits.
+fn whole_cpus(container: &Container) -> anyhow::Result<usize> {
+    let millis = container.cpu_limit_millis()?.unwrap_or(0);
+    if millis % 1000 == 0 {
+        Ok((millis / 1000) as usize)
+    } else {
+        Ok(0)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn current_thread() -> ThreadId {
+    unsafe { libc::syscall(libc::SYS_gettid) as ThreadId }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_thread() -> ThreadId {
+    0
+}
+
+/// The CPUs the thread may run on. A thread ID of 0 is the current thread
+#[cfg(target_os = "linux")]
+fn affinity(thread: ThreadId) -> std::io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(thread, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(thread: ThreadId, cpus: &[usize]) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(thread, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_thread: ThreadId, _cpus: &[usize]) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec, ResourceRequirements};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    fn container(requests: &[(&str, &str)], limits: &[(&str, &str)]) -> KubeContainer {
+        let quantities = |q: &[(&str, &str)]| {
+            Some(
+                q.iter()
+                    .map(|(r, q)| (r.to_string(), Quantity(q.to_string())))
+                    .collect(),
+            )
+            .filter(|q: &std::collections::BTreeMap<_, _>| !q.is_empty())
+        };
+        KubeContainer {
+            name: "container".to_owned(),
+            resources: Some(ResourceRequirements {
+                requests: quantities(requests),
+                limits: quantities(limits),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pod(init_containers: Vec<KubeContainer>, containers: Vec<KubeContainer>) -> Pod {
+        test_util::pod(
+            "pod",
+            PodSpec {
+                init_containers: Some(init_containers),
+                containers,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        crate::test_util::assert_parses(
+            &[
+                ("none", CpuManagerPolicy::None),
+                ("static", CpuManagerPolicy::Static),
+            ],
+            &["Static"],
+        );
+    }
+
+    #[test]
+    fn guaranteed_pods_get_their_whole_cpus() {
+        let guaranteed = container(&[], &[("cpu", "2"), ("memory", "1Gi")]);
+        let fractional = container(
+            &[("cpu", "500m"), ("memory", "1Gi")],
+            &[("cpu", "500m"), ("memory", "1Gi")],
+        );
+        let burstable = container(&[("cpu", "1")], &[("cpu", "2"), ("memory", "1Gi")]);
+
+        assert_eq!(
+            exclusive_cpus(&pod(vec![], vec![guaranteed.clone(), fractional.clone()])).unwrap(),
+            2
+        );
+        assert_eq!(
+            exclusive_cpus(&pod(vec![], vec![guaranteed.clone(), burstable])).unwrap(),
+            0
+        );
+        assert_eq!(
+            exclusive_cpus(&pod(vec![], vec![container(&[], &[])])).unwrap(),
+            0
+        );
+        // Init containers run alone, so only need more CPUs than all the
+        // other containers together to matter
+        let big_init = container(&[], &[("cpu", "3"), ("memory", "1Gi")]);
+        assert_eq!(
+            exclusive_cpus(&pod(vec![big_init], vec![guaranteed.clone()])).unwrap(),
+            3
+        );
+        assert_eq!(
+            exclusive_cpus(&pod(vec![fractional], vec![guaranteed.clone(), guaranteed])).unwrap(),
+            4
+        );
+    }
+
+    #[test]
+    fn the_none_policy_assigns_nothing() {
+        let manager = CpuManager::with_cpus(CpuManagerPolicy::None, vec![0, 1, 2, 3]);
+        assert!(manager.assign_cpus("pod", 2).unwrap().is_empty());
+        assert_eq!(manager.shared_cpus(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn the_static_policy_assigns_exclusive_cpus() {
+        let manager = CpuManager::with_cpus(CpuManagerPolicy::Static, vec![0, 1, 2, 3]);
+        assert_eq!(manager.assign_cpus("first", 2).unwrap(), vec![1, 2]);
+        // Pods keep the CPUs they already have
+        assert_eq!(manager.assign_cpus("first", 1).unwrap(), vec![1, 2]);
+        assert!(manager.assign_cpus("second", 0).unwrap().is_empty());
+        // The first CPU is always left in the shared pool
+        assert!(manager.assign_cpus("second", 2).is_err());
+        assert_eq!(manager.assign_cpus("second", 1).unwrap(), vec![3]);
+        assert_eq!(manager.shared_cpus(), vec![0]);
+
+        manager.release("first");
+        assert_eq!(manager.shared_cpus(), vec![0, 1, 2]);
+        assert_eq!(manager.assign_cpus("third", 2).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn pods_are_resized_with_the_cpus_they_keep() {
+        let manager = CpuManager::with_cpus(CpuManagerPolicy::Static, vec![0, 1, 2, 3, 4]);
+        assert_eq!(manager.assign_cpus("first", 2).unwrap(), vec![1, 2]);
+        assert_eq!(manager.assign_cpus("second", 1).unwrap(), vec![3]);
+        assert_eq!(manager.resize("first", 3).unwrap(), vec![1, 2, 4]);
+        // Growing past what the shared pool has left leaves the CPUs as they
+        // were
+        assert!(manager.resize("second", 2).is_err());
+        assert_eq!(manager.shared_cpus(), vec![0]);
+        assert_eq!(manager.resize("first", 1).unwrap(), vec![1]);
+        assert_eq!(manager.resize("second", 2).unwrap(), vec![2, 3]);
+        assert!(manager.resize("second", 0).unwrap().is_empty());
+        assert_eq!(manager.shared_cpus(), vec![0, 2, 3, 4]);
+        // Pods without CPUs get them by being resized
+        assert_eq!(manager.resize("third", 1).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn hints_prefer_nodes_with_enough_free_cpus() {
+        let topology = vec![
+            NumaNode {
+                id: 0,
+                cpus: vec![0, 1, 2],
+            },
+            NumaNode {
+                id: 1,
+                cpus: vec![3, 4, 5],
+            },
+        ];
+        let manager = CpuManager::with_cpus(CpuManagerPolicy::Static, (0..6).collect());
+        let guaranteed = pod(
+            vec![],
+            vec![container(&[], &[("cpu", "3"), ("memory", "1Gi")])],
+        );
+
+        // CPU 0 is kept for the shared pool, so only node 1 has 3 free CPUs
+        let hints = manager.topology_hints(&guaranteed, &topology).unwrap();
+        let node_1 = crate::topology_manager::NumaMask::new(vec![1]);
+        assert_eq!(
+            hints["cpu"][0],
+            TopologyHint {
+                affinity: Some(node_1),
+                preferred: true
+            }
+        );
+        assert!(!hints["cpu"][1].preferred);
+
+        // CPUs are taken from the aligned nodes first
+        let aligned = cpus_in(&topology, node_1);
+        assert_eq!(
+            manager.assign_aligned_cpus("pod", 3, &aligned).unwrap(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            manager.topology_hints(&guaranteed, &topology).unwrap()["cpu"],
+            vec![TopologyHint {
+                affinity: Some(node_1),
+                preferred: true
+            }]
+        );
+
+        let best_effort = pod(vec![], vec![container(&[], &[])]);
+        assert!(manager
+            .topology_hints(&best_effort, &topology)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn threads_are_affined_until_unpinned() {
+        let manager = CpuManager::new(CpuManagerPolicy::Static).unwrap();
+        let all = manager.cpus.clone();
+        let pod = manager.pod("pod");
+        std::thread::spawn(move || {
+            let pinned = pod.pin_current_thread();
+            assert_eq!(affinity(0).unwrap(), manager.shared_cpus());
+            assert_eq!(manager.lock().threads.len(), 1);
+            drop(pinned);
+            assert_eq!(affinity(0).unwrap(), all);
+            assert!(manager.lock().threads.is_empty());
+        })
+        .join()
+        .expect("thread should have been affined");
+    }
+}
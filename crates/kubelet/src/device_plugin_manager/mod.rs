@@ -0,0 +1,1037 @@
+//! The Kubelet device plugin manager. Used to advertise the devices of [device
+//! plugins](https://kubernetes.io/docs/concepts/extend-kubernetes/compute-storage-net/device-plugins/)
+//! as extended resources of the node, and to allocate them to containers.
+//!
+//! Device plugins register with the `Registration` service served at `kubelet.sock` in the device
+//! plugin directory, like with other kubelets, or through the [plugin
+//! registry](crate::plugin_watcher::PluginRegistry), which hands them to the
+//! [`DevicePluginManager`] as their [`PluginHandler`]. Either way, the plugin's `ListAndWatch`
+//! stream is consumed in the background, so that the node's capacity of the plugin's resource is
+//! kept up to date. When a container requests some of the resource, the plugin's `Allocate` RPC is
+//! called with the devices chosen for it, and the plugin's response is given to the provider as a
+//! [`ContainerAllocation`]. Devices whose plugin reports the NUMA nodes they are attached to are
+//! chosen from the nodes the [topology manager](crate::topology_manager) aligned the pod on.
+use crate::container::Container;
+use crate::device_plugin_api::v1beta1::{
+    device_plugin_client::DevicePluginClient,
+    registration_server::{Registration, RegistrationServer},
+    AllocateRequest, ContainerAllocateRequest, ContainerAllocateResponse, DevicePluginOptions,
+    Empty, ListAndWatchResponse, PreStartContainerRequest, RegisterRequest, API_VERSION,
+};
+use crate::grpc_sock;
+use crate::memory_manager::NumaNode;
+use crate::plugin_watcher::PluginHandler;
+use crate::pod::{Pod, PodKey};
+use crate::resources::parse_quantity;
+use crate::topology_manager::{generate_hints, HintProvider, NumaMask, TopologyHint};
+
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::{Api, PatchParams};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, error, info, warn};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The name of the socket, in the device plugin directory, that the registration service is
+/// served on
+const KUBELET_SOCKET: &str = "kubelet.sock";
+/// The health of devices that can be allocated
+const HEALTHY: &str = "Healthy";
+
+/// A path on the host made available in a container for its devices
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceMount {
+    /// The path on the host
+    pub host_path: PathBuf,
+    /// The path in the container
+    pub container_path: PathBuf,
+    /// Whether the container may only read the path
+    pub read_only: bool,
+}
+
+/// What a container is given to use the devices allocated to it, as directed by the device
+/// plugins that manage them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerAllocation {
+    /// Environment variables to set in the container
+    pub env: HashMap<String, String>,
+    /// Directories on the host to mount into the container
+    pub mounts: Vec<DeviceMount>,
+    /// Device files on the host to make available in the container
+    pub devices: Vec<DeviceMount>,
+    /// Annotations for the runtime that runs the container
+    pub annotations: HashMap<String, String>,
+}
+
+impl ContainerAllocation {
+    fn add(&mut self, response: ContainerAllocateResponse) {
+        self.env.extend(response.envs);
+        self.mounts
+            .extend(response.mounts.into_iter().map(|m| DeviceMount {
+                host_path: PathBuf::from(m.host_path),
+                container_path: PathBuf::from(m.container_path),
+                read_only: m.read_only,
+            }));
+        self.devices
+            .extend(response.devices.into_iter().map(|d| DeviceMount {
+                host_path: PathBuf::from(d.host_path),
+                container_path: PathBuf::from(d.container_path),
+                read_only: !d.permissions.contains('w'),
+            }));
+        self.annotations.extend(response.annotations);
+    }
+}
+
+/// A container, by the pod it is in and its name
+type ContainerKey = (PodKey, String);
+
+/// A connection to a registered device plugin
+struct PluginConnection {
+    client: DevicePluginClient<Channel>,
+    options: DevicePluginOptions,
+    /// Distinguishes this connection from earlier ones to the same plugin, whose streams may end
+    /// after the plugin has registered again
+    generation: u64,
+    watch: JoinHandle<()>,
+}
+
+/// The devices of each resource and which containers they are allocated to
+#[derive(Default)]
+struct DeviceState {
+    /// The devices of each resource, by ID, and whether they are healthy
+    devices: HashMap<String, BTreeMap<String, bool>>,
+    /// The NUMA nodes each device of each resource is attached to, for the devices whose plugin
+    /// reports them
+    numa_nodes: HashMap<String, HashMap<String, NumaMask>>,
+    /// The container each allocated device of each resource is allocated to
+    allocated: HashMap<String, HashMap<String, ContainerKey>>,
+    /// What each container with allocated devices was given for them
+    allocations: HashMap<ContainerKey, ContainerAllocation>,
+    generation: u64,
+}
+
+impl DeviceState {
+    /// Chooses `count` healthy devices of the resource that aren't allocated to other containers,
+    /// preferring those on the NUMA nodes of `affinity`, and allocates them to the container
+    fn reserve(
+        &mut self,
+        resource: &str,
+        count: usize,
+        container: &ContainerKey,
+        affinity: Option<NumaMask>,
+    ) -> anyhow::Result<Vec<String>> {
+        let numa_nodes = self.numa_nodes.get(resource);
+        let allocated = self.allocated.entry(resource.to_owned()).or_default();
+        let mut available: Vec<String> = self
+            .devices
+            .get(resource)
+            .into_iter()
+            .flatten()
+            .filter(|(id, healthy)| **healthy && !allocated.contains_key(*id))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if let Some(affinity) = affinity {
+            available.sort_by_key(|id| {
+                let nodes = numa_nodes.and_then(|n| n.get(id));
+                !nodes.map(|n| !n.and(affinity).is_empty()).unwrap_or(true)
+            });
+        }
+        available.truncate(count);
+        if available.len() < count {
+            return Err(anyhow::anyhow!(
+                "container {} requested {} of resource {}, but only {} are available",
+                container.1,
+                count,
+                resource,
+                available.len()
+            ));
+        }
+        for id in &available {
+            allocated.insert(id.clone(), container.clone());
+        }
+        Ok(available)
+    }
+
+    /// The healthy devices of the resource that aren't allocated to containers outside the pod,
+    /// with the NUMA nodes they are attached to, if known
+    fn available(&self, resource: &str, pod: &PodKey) -> Vec<Option<NumaMask>> {
+        let allocated = self.allocated.get(resource);
+        let numa_nodes = self.numa_nodes.get(resource);
+        self.devices
+            .get(resource)
+            .into_iter()
+            .flatten()
+            .filter(|(id, healthy)| {
+                **healthy
+                    && allocated
+                        .and_then(|a| a.get(*id))
+                        .map(|(p, _)| p == pod)
+                        .unwrap_or(true)
+            })
+            .map(|(id, _)| numa_nodes.and_then(|n| n.get(id)).copied())
+            .collect()
+    }
+
+    /// Frees the devices allocated to containers that match `release`
+    fn release(&mut self, release: impl Fn(&ContainerKey) -> bool) {
+        for devices in self.allocated.values_mut() {
+            devices.retain(|_, container| !release(container));
+        }
+        self.allocations.retain(|container, _| !release(container));
+    }
+
+    /// The node's capacity and allocatable amount of the resource, which are all of its devices
+    /// and its healthy ones
+    fn capacity(&self, resource: &str) -> (usize, usize) {
+        let devices = self.devices.get(resource);
+        let capacity = devices.map(|d| d.len()).unwrap_or(0);
+        let allocatable = devices
+            .map(|d| d.values().filter(|healthy| **healthy).count())
+            .unwrap_or(0);
+        (capacity, allocatable)
+    }
+}
+
+/// Manages the device plugins registered with this node, like the [device
+/// manager](https://github.com/kubernetes/kubernetes/tree/fd74333a971e2048b5fb2b692a9e043483d63fba/pkg/kubelet/cm/devicemanager)
+/// in kubelet. Clones share the same plugins and devices.
+#[derive(Clone)]
+pub struct DevicePluginManager {
+    plugin_dir: PathBuf,
+    client: kube::Client,
+    node_name: String,
+    plugins: Arc<RwLock<HashMap<String, PluginConnection>>>,
+    state: Arc<Mutex<DeviceState>>,
+}
+
+impl DevicePluginManager {
+    /// Returns a new device plugin manager for the node `node_name`, serving its registration
+    /// service in the given device plugin directory
+    pub fn new<P: AsRef<Path>>(plugin_dir: P, client: kube::Client, node_name: &str) -> Self {
+        DevicePluginManager {
+            plugin_dir: PathBuf::from(plugin_dir.as_ref()),
+            client,
+            node_name: node_name.to_owned(),
+            plugins: Arc::new(RwLock::new(HashMap::new())),
+            state: Arc::new(Mutex::new(DeviceState::default())),
+        }
+    }
+
+    /// Serves the registration service that device plugins register with. This will block
+    /// indefinitely or until the server stops. To stop serving, simply stop polling the future
+    pub async fn run(&self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.plugin_dir).await?;
+        // A socket left from a previous run would stop the new one from being created. Plugins
+        // watch for it being recreated to register again
+        let socket_path = self.plugin_dir.join(KUBELET_SOCKET);
+        match tokio::fs::remove_file(&socket_path).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let socket = grpc_sock::server::Socket::new(&socket_path)?;
+        info!(
+            "Serving device plugin registration at {}",
+            socket_path.display()
+        );
+
+        let serve = Server::builder()
+            .add_service(RegistrationServer::new(self.clone()))
+            .serve_with_incoming(socket);
+        #[cfg(target_family = "windows")]
+        let serve = tokio_compat_02::FutureExt::compat(serve);
+        serve.await?;
+        Ok(())
+    }
+
+    /// Allocates devices to the container for each resource it has a limit of that a device
+    /// plugin manages, returning what the container should be given to use them. Devices on the
+    /// NUMA nodes of `affinity` are chosen first. Containers that have already been allocated
+    /// devices get the same ones again, such as when they restart.
+    pub async fn allocate(
+        &self,
+        pod: &Pod,
+        container: &Container,
+        affinity: Option<NumaMask>,
+    ) -> anyhow::Result<ContainerAllocation> {
+        let key = (PodKey::from(pod), container.name().to_owned());
+        if let Some(allocation) = self.lock().allocations.get(&key) {
+            return Ok(allocation.clone());
+        }
+
+        let requests = device_requests(container)?;
+        let mut allocation = ContainerAllocation::default();
+        for (resource, count) in requests {
+            let response = self
+                .allocate_resource(&resource, count, &key, affinity)
+                .await;
+            match response {
+                Ok(response) => allocation.add(response),
+                Err(e) => {
+                    self.lock().release(|c| *c == key);
+                    return Err(e);
+                }
+            }
+        }
+        self.lock().allocations.insert(key, allocation.clone());
+        Ok(allocation)
+    }
+
+    /// Frees the devices allocated to the containers of the pod, such as once it is deleted
+    pub fn release(&self, pod: &PodKey) {
+        self.lock().release(|(p, _)| p == pod);
+    }
+
+    async fn allocate_resource(
+        &self,
+        resource: &str,
+        count: usize,
+        container: &ContainerKey,
+        affinity: Option<NumaMask>,
+    ) -> anyhow::Result<ContainerAllocateResponse> {
+        let (mut client, options) = match self.plugins.read().await.get(resource) {
+            Some(plugin) => (plugin.client.clone(), plugin.options.clone()),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "no device plugin is registered for resource {}",
+                    resource
+                ))
+            }
+        };
+        let device_ids = self.lock().reserve(resource, count, container, affinity)?;
+        debug!(
+            "Allocating devices {:?} of resource {} to container {} in pod {}",
+            device_ids,
+            resource,
+            container.1,
+            container.0.name()
+        );
+
+        if options.pre_start_required {
+            client
+                .pre_start_container(Request::new(PreStartContainerRequest {
+                    devices_i_ds: device_ids.clone(),
+                }))
+                .await
+                .map_err(|status| rpc_error("PreStartContainer", resource, status))?;
+        }
+        let response = client
+            .allocate(Request::new(AllocateRequest {
+                container_requests: vec![ContainerAllocateRequest {
+                    devices_i_ds: device_ids,
+                }],
+            }))
+            .await
+            .map_err(|status| rpc_error("Allocate", resource, status))?
+            .into_inner();
+        response
+            .container_responses
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "device plugin for resource {} returned no allocation",
+                    resource
+                )
+            })
+    }
+
+    /// Connects to the plugin for the resource at `endpoint`, and starts watching its devices.
+    /// If the plugin's options aren't given, they are asked for
+    async fn connect(
+        &self,
+        resource: String,
+        endpoint: PathBuf,
+        options: Option<DevicePluginOptions>,
+    ) -> anyhow::Result<()> {
+        debug!(
+            "Connecting to device plugin for resource {} at {}",
+            resource,
+            endpoint.display()
+        );
+        let channel = grpc_sock::client::socket_channel(&endpoint).await?;
+        let mut client = DevicePluginClient::new(channel);
+        let options = match options {
+            Some(options) => options,
+            None => client
+                .get_device_plugin_options(Request::new(Empty {}))
+                .await
+                .map_err(|status| rpc_error("GetDevicePluginOptions", &resource, status))?
+                .into_inner(),
+        };
+        let devices = client
+            .list_and_watch(Request::new(Empty {}))
+            .await
+            .map_err(|status| rpc_error("ListAndWatch", &resource, status))?
+            .into_inner();
+
+        let mut plugins = self.plugins.write().await;
+        let generation = {
+            let mut state = self.lock();
+            state.generation += 1;
+            state.generation
+        };
+        let watch = tokio::spawn(self.clone().watch(resource.clone(), generation, devices));
+        let connection = PluginConnection {
+            client,
+            options,
+            generation,
+            watch,
+        };
+        // A plugin that registers again, such as after restarting, replaces its old connection
+        if let Some(old) = plugins.insert(resource.clone(), connection) {
+            old.watch.abort();
+        }
+        info!("Registered device plugin for resource {}", resource);
+        Ok(())
+    }
+
+    /// Updates the devices of the resource from the plugin's `ListAndWatch` stream, until it ends,
+    /// which is when the plugin has stopped
+    async fn watch(
+        self,
+        resource: String,
+        generation: u64,
+        mut devices: Streaming<ListAndWatchResponse>,
+    ) {
+        loop {
+            match devices.message().await {
+                Ok(Some(response)) => {
+                    let numa_nodes = response
+                        .devices
+                        .iter()
+                        .filter_map(|d| {
+                            let nodes = d.topology.as_ref()?.nodes.iter();
+                            let mask = NumaMask::new(nodes.map(|n| n.id as usize));
+                            Some((d.id.clone(), mask)).filter(|_| !mask.is_empty())
+                        })
+                        .collect();
+                    let devices = response
+                        .devices
+                        .into_iter()
+                        .map(|d| {
+                            let healthy = d.health == HEALTHY;
+                            (d.id, healthy)
+                        })
+                        .collect();
+                    debug!("Devices of resource {} are now {:?}", resource, devices);
+                    {
+                        let mut state = self.lock();
+                        state.devices.insert(resource.clone(), devices);
+                        state.numa_nodes.insert(resource.clone(), numa_nodes);
+                    }
+                    self.update_node(&resource).await;
+                }
+                Ok(None) => break,
+                Err(status) => {
+                    let e = rpc_error("ListAndWatch", &resource, status);
+                    warn!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut plugins = self.plugins.write().await;
+        if plugins.get(&resource).map(|p| p.generation) == Some(generation) {
+            info!(
+                "Device plugin for resource {} has stopped, removing its devices",
+                resource
+            );
+            plugins.remove(&resource);
+            {
+                let mut state = self.lock();
+                state.devices.remove(&resource);
+                state.numa_nodes.remove(&resource);
+            }
+            self.update_node(&resource).await;
+        }
+    }
+
+    /// Removes the plugin for the resource, which removes its devices once its stream ends
+    async fn remove_plugin(&self, resource: &str) {
+        if let Some(plugin) = self.plugins.write().await.remove(resource) {
+            plugin.watch.abort();
+            self.lock().devices.remove(resource);
+            self.update_node(resource).await;
+        }
+    }
+
+    /// Sets the node's capacity and allocatable amount of the resource to its devices
+    async fn update_node(&self, resource: &str) {
+        let (capacity, allocatable) = self.lock().capacity(resource);
+        let patch = serde_json::json!({
+            "status": {
+                "capacity": { resource: capacity.to_string() },
+                "allocatable": { resource: allocatable.to_string() },
+            }
+        });
+        let nodes: Api<KubeNode> = Api::all(self.client.clone());
+        if let Err(e) = nodes
+            .patch_status(
+                &self.node_name,
+                &PatchParams::default(),
+                &kube::api::Patch::Merge(patch),
+            )
+            .await
+        {
+            error!(
+                "Unable to update node capacity of resource {}: {}",
+                resource, e
+            );
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DeviceState> {
+        self.state
+            .lock()
+            .expect("device state lock should not be poisoned")
+    }
+}
+
+#[async_trait]
+impl Registration for DevicePluginManager {
+    async fn register(&self, request: Request<RegisterRequest>) -> Result<Response<Empty>, Status> {
+        let request = request.into_inner();
+        debug!("Got device plugin registration request {:?}", request);
+        if request.version != API_VERSION {
+            return Err(Status::invalid_argument(format!(
+                "Unsupported device plugin API version {}, expected {}",
+                request.version, API_VERSION
+            )));
+        }
+        validate_resource_name(&request.resource_name)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        // The plugin can only be called once its registration has been answered
+        let manager = self.clone();
+        let endpoint = self.plugin_dir.join(&request.endpoint);
+        tokio::spawn(async move {
+            let resource = request.resource_name.clone();
+            if let Err(e) = manager
+                .connect(request.resource_name, endpoint, request.options)
+                .await
+            {
+                error!(
+                    "Unable to connect to device plugin for resource {}: {:?}",
+                    resource, e
+                );
+            }
+        });
+        Ok(Response::new(Empty {}))
+    }
+}
+
+#[async_trait]
+impl PluginHandler for DevicePluginManager {
+    async fn register(&self, name: &str, endpoint: &Path) -> anyhow::Result<()> {
+        validate_resource_name(name)?;
+        self.connect(name.to_owned(), endpoint.to_owned(), None)
+            .await
+    }
+
+    async fn deregister(&self, name: &str) {
+        self.remove_plugin(name).await
+    }
+}
+
+/// Whether the resource is an extended resource, which are the ones device plugins may manage.
+/// These have a domain other than `kubernetes.io`, such as `example.com/gpu`
+/// Devices are aligned on the NUMA nodes they are attached to. Resources whose plugin doesn't
+/// report the nodes of any device could be allocated from any node.
+impl HintProvider for DevicePluginManager {
+    fn topology_hints(
+        &self,
+        pod: &Pod,
+        topology: &[NumaNode],
+    ) -> anyhow::Result<HashMap<String, Vec<TopologyHint>>> {
+        // Init containers run alone, so the pod needs as many devices as the most any of them, or
+        // all the other containers, have a limit of
+        let mut requests: HashMap<String, usize> = HashMap::new();
+        for container in pod.containers() {
+            for (resource, count) in device_requests(&container)? {
+                *requests.entry(resource).or_default() += count;
+            }
+        }
+        for container in pod.init_containers() {
+            for (resource, count) in device_requests(&container)? {
+                let total = requests.entry(resource).or_default();
+                *total = (*total).max(count);
+            }
+        }
+
+        let key = PodKey::from(pod);
+        let state = self.lock();
+        let mut hints = HashMap::new();
+        for (resource, count) in requests {
+            let available = state.available(&resource, &key);
+            let all = state.devices.get(&resource);
+            let nodes = state.numa_nodes.get(&resource);
+            if nodes.map(|n| n.is_empty()).unwrap_or(true) {
+                continue;
+            }
+            let in_mask = |device: &Option<NumaMask>, mask: NumaMask| {
+                device.map(|d| !d.and(mask).is_empty()).unwrap_or(true)
+            };
+            let resource_hints = generate_hints(
+                topology,
+                |mask| available.iter().filter(|d| in_mask(d, mask)).count() >= count,
+                |mask| {
+                    all.into_iter()
+                        .flat_map(|devices| devices.keys())
+                        .filter(|id| in_mask(&nodes.and_then(|n| n.get(*id)).copied(), mask))
+                        .count()
+                        >= count
+                },
+            );
+            hints.insert(resource, resource_hints);
+        }
+        Ok(hints)
+    }
+}
+
+fn is_extended_resource(name: &str) -> bool {
+    match name.split_once('/') {
+        Some((domain, _)) => {
+            !(domain == "kubernetes.io"
+                || domain.ends_with(".kubernetes.io")
+                || domain == "requests")
+        }
+        None => false,
+    }
+}
+
+fn validate_resource_name(name: &str) -> anyhow::Result<()> {
+    if !is_extended_resource(name) {
+        return Err(anyhow::anyhow!(
+            "{} is not an extended resource name, such as example.com/device",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// The number of devices of each extended resource the container has a limit of. The limit is
+/// the number of devices to allocate, as extended resources can't be overcommitted
+fn device_requests(container: &Container) -> anyhow::Result<Vec<(String, usize)>> {
+    let limits = match container.resources().and_then(|r| r.limits.as_ref()) {
+        Some(limits) => limits,
+        None => return Ok(vec![]),
+    };
+    limits
+        .iter()
+        .filter(|(name, _)| is_extended_resource(name))
+        .map(|(name, quantity)| {
+            let count = parse_quantity(&quantity.0)?;
+            Ok((name.clone(), count as usize))
+        })
+        .filter(|r| !matches!(r, Ok((_, 0))))
+        .collect()
+}
+
+fn rpc_error(rpc: &str, resource: &str, status: Status) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} call to device plugin for resource {} failed with error code {} and message {}",
+        rpc,
+        resource,
+        status.code(),
+        status.message()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device_plugin_api::v1beta1::{
+        device_plugin_server::{DevicePlugin, DevicePluginServer},
+        registration_client::RegistrationClient,
+        AllocateResponse, ContainerPreferredAllocationResponse, Device, DeviceSpec, Mount,
+        PreStartContainerResponse, PreferredAllocationRequest, PreferredAllocationResponse,
+    };
+    use crate::pod::test_util::pod;
+    use futures::stream::{self, Stream, StreamExt};
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec, ResourceRequirements};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use std::pin::Pin;
+    use std::time::Duration;
+    #[cfg(target_family = "windows")]
+    use tokio_compat_02::FutureExt;
+
+    const RESOURCE: &str = "example.com/device";
+
+    fn mock_client() -> kube::Client {
+        kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:8080").unwrap(),
+        ))
+    }
+
+    fn container(limits: &[(&str, &str)]) -> Container {
+        Container::new(&KubeContainer {
+            name: "container".to_owned(),
+            resources: Some(ResourceRequirements {
+                limits: Some(
+                    limits
+                        .iter()
+                        .map(|(r, q)| (r.to_string(), Quantity(q.to_string())))
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// A device plugin serving the given devices, which makes the devices it is asked for available at
+    /// `/dev/<id>`
+    struct MockDevicePlugin {
+        devices: Vec<Device>,
+    }
+
+    #[async_trait]
+    impl DevicePlugin for MockDevicePlugin {
+        async fn get_device_plugin_options(
+            &self,
+            _request: Request<Empty>,
+        ) -> Result<Response<DevicePluginOptions>, Status> {
+            Ok(Response::new(DevicePluginOptions::default()))
+        }
+
+        type ListAndWatchStream =
+            Pin<Box<dyn Stream<Item = Result<ListAndWatchResponse, Status>> + Send + Sync>>;
+
+        async fn list_and_watch(
+            &self,
+            _request: Request<Empty>,
+        ) -> Result<Response<Self::ListAndWatchStream>, Status> {
+            let devices = ListAndWatchResponse {
+                devices: self.devices.clone(),
+            };
+            // The stream stays open after listing the devices, as the plugin is still running
+            let stream = stream::once(async { Ok(devices) }).chain(stream::pending());
+            Ok(Response::new(Box::pin(stream)))
+        }
+
+        async fn get_preferred_allocation(
+            &self,
+            _request: Request<PreferredAllocationRequest>,
+        ) -> Result<Response<PreferredAllocationResponse>, Status> {
+            Ok(Response::new(PreferredAllocationResponse {
+                container_responses: vec![ContainerPreferredAllocationResponse::default()],
+            }))
+        }
+
+        async fn allocate(
+            &self,
+            request: Request<AllocateRequest>,
+        ) -> Result<Response<AllocateResponse>, Status> {
+            let container_responses = request
+                .into_inner()
+                .container_requests
+                .into_iter()
+                .map(|r| ContainerAllocateResponse {
+                    envs: vec![("DEVICES".to_owned(), r.devices_i_ds.join(","))]
+                        .into_iter()
+                        .collect(),
+                    mounts: vec![Mount {
+                        container_path: "/lib/device".to_owned(),
+                        host_path: "/opt/device".to_owned(),
+                        read_only: true,
+                    }],
+                    devices: r
+                        .devices_i_ds
+                        .iter()
+                        .map(|id| DeviceSpec {
+                            container_path: format!("/dev/{}", id),
+                            host_path: format!("/dev/{}", id),
+                            permissions: "rw".to_owned(),
+                        })
+                        .collect(),
+                    annotations: vec![(
+                        "example.com/devices".to_owned(),
+                        r.devices_i_ds.len().to_string(),
+                    )]
+                    .into_iter()
+                    .collect(),
+                })
+                .collect();
+            Ok(Response::new(AllocateResponse {
+                container_responses,
+            }))
+        }
+
+        async fn pre_start_container(
+            &self,
+            _request: Request<PreStartContainerRequest>,
+        ) -> Result<Response<PreStartContainerResponse>, Status> {
+            Ok(Response::new(PreStartContainerResponse {}))
+        }
+    }
+
+    fn device(id: &str, health: &str) -> Device {
+        Device {
+            id: id.to_owned(),
+            health: health.to_owned(),
+            topology: None,
+        }
+    }
+
+    async fn serve_plugin(plugin: MockDevicePlugin, path: impl AsRef<Path>) {
+        let socket = grpc_sock::server::Socket::new(&path)
+            .expect("unable to setup server listening on socket");
+
+        tokio::spawn(async move {
+            let serv = Server::builder()
+                .add_service(DevicePluginServer::new(plugin))
+                .serve_with_incoming(socket);
+            #[cfg(target_family = "windows")]
+            let serv = serv.compat();
+            serv.await.expect("Unable to serve test plugin");
+        });
+    }
+
+    #[test]
+    fn test_extended_resources() {
+        assert!(is_extended_resource("example.com/gpu"));
+        assert!(is_extended_resource("vendor.io/device"));
+        assert!(!is_extended_resource("cpu"));
+        assert!(!is_extended_resource("kubernetes.io/device"));
+        assert!(!is_extended_resource("node.kubernetes.io/device"));
+        assert!(!is_extended_resource("requests/example.com"));
+    }
+
+    #[test]
+    fn test_device_requests() {
+        let requests = device_requests(&container(&[
+            ("cpu", "500m"),
+            (RESOURCE, "2"),
+            ("example.com/unused", "0"),
+        ]))
+        .expect("should be able to parse limits");
+        assert_eq!(requests, vec![(RESOURCE.to_owned(), 2)]);
+
+        assert!(device_requests(&container(&[(RESOURCE, "lots")])).is_err());
+    }
+
+    #[test]
+    fn test_reserve_and_release() {
+        let mut state = DeviceState::default();
+        state.devices.insert(
+            RESOURCE.to_owned(),
+            vec![
+                ("a".to_owned(), true),
+                ("b".to_owned(), false),
+                ("c".to_owned(), true),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(state.capacity(RESOURCE), (3, 2));
+
+        let first = (PodKey::new("default", "first"), "container".to_owned());
+        let second = (PodKey::new("default", "second"), "container".to_owned());
+        assert_eq!(
+            state.reserve(RESOURCE, 1, &first, None).unwrap(),
+            vec!["a".to_owned()]
+        );
+        // Unhealthy devices and those allocated to other containers are skipped
+        assert_eq!(
+            state.reserve(RESOURCE, 1, &second, None).unwrap(),
+            vec!["c".to_owned()]
+        );
+        assert!(state.reserve(RESOURCE, 1, &second, None).is_err());
+
+        state.release(|(pod, _)| *pod == first.0);
+        assert_eq!(
+            state.reserve(RESOURCE, 1, &second, None).unwrap(),
+            vec!["a".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_devices_are_aligned_on_numa_nodes() {
+        let topology = vec![
+            NumaNode {
+                id: 0,
+                cpus: vec![0],
+            },
+            NumaNode {
+                id: 1,
+                cpus: vec![1],
+            },
+        ];
+        let manager = DevicePluginManager::new("/tmp", mock_client(), "node");
+        {
+            let mut state = manager.lock();
+            state.devices.insert(
+                RESOURCE.to_owned(),
+                vec![("a".to_owned(), true), ("b".to_owned(), true)]
+                    .into_iter()
+                    .collect(),
+            );
+            state.numa_nodes.insert(
+                RESOURCE.to_owned(),
+                vec![
+                    ("a".to_owned(), NumaMask::new(vec![0])),
+                    ("b".to_owned(), NumaMask::new(vec![1])),
+                ]
+                .into_iter()
+                .collect(),
+            );
+        }
+        let pod = pod(
+            "pod",
+            PodSpec {
+                containers: vec![KubeContainer {
+                    name: "container".to_owned(),
+                    resources: Some(ResourceRequirements {
+                        limits: Some(
+                            vec![(RESOURCE.to_owned(), Quantity("1".to_owned()))]
+                                .into_iter()
+                                .collect(),
+                        ),
+                        requests: None,
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        );
+        let hints = manager.topology_hints(&pod, &topology).unwrap();
+        let preferred: Vec<(Vec<usize>, bool)> = hints[RESOURCE]
+            .iter()
+            .map(|h| (h.affinity.unwrap().nodes(), h.preferred))
+            .collect();
+        assert_eq!(
+            preferred,
+            vec![(vec![0], true), (vec![1], true), (vec![0, 1], false)]
+        );
+
+        let key = (PodKey::from(&pod), "container".to_owned());
+        assert_eq!(
+            manager
+                .lock()
+                .reserve(RESOURCE, 1, &key, Some(NumaMask::new(vec![1])))
+                .unwrap(),
+            vec!["b".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_and_allocate() {
+        let tempdir = tempfile::tempdir().expect("should be able to create tempdir");
+        let manager = DevicePluginManager::new(tempdir.path(), mock_client(), "node");
+        serve_plugin(
+            MockDevicePlugin {
+                devices: vec![
+                    device("a", HEALTHY),
+                    device("b", "Unhealthy"),
+                    device("c", HEALTHY),
+                ],
+            },
+            tempdir.path().join("example.sock"),
+        )
+        .await;
+        let registration = manager.clone();
+        tokio::spawn(async move { registration.run().await });
+
+        let container = container(&[(RESOURCE, "2")]);
+        let (pod, other) = (
+            pod("pod", PodSpec::default()),
+            pod("other", PodSpec::default()),
+        );
+        assert!(
+            manager.allocate(&pod, &container, None).await.is_err(),
+            "allocation should fail without a device plugin"
+        );
+
+        // Wait for the registration service to start
+        let socket = tempdir.path().join(KUBELET_SOCKET);
+        let channel = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(channel) = grpc_sock::client::socket_channel(&socket).await {
+                    return channel;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("registration service should have started");
+        RegistrationClient::new(channel)
+            .register(Request::new(RegisterRequest {
+                version: API_VERSION.to_owned(),
+                endpoint: "example.sock".to_owned(),
+                resource_name: RESOURCE.to_owned(),
+                options: None,
+            }))
+            .await
+            .expect("plugin should have registered");
+
+        // Wait for the plugin's devices to be listed
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while manager.lock().capacity(RESOURCE) != (3, 2) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("plugin's devices should have been listed");
+
+        let allocation = manager
+            .allocate(&pod, &container, None)
+            .await
+            .expect("devices should have been allocated");
+        assert_eq!(allocation.env.get("DEVICES").unwrap(), "a,c");
+        assert_eq!(
+            allocation.mounts,
+            vec![DeviceMount {
+                host_path: PathBuf::from("/opt/device"),
+                container_path: PathBuf::from("/lib/device"),
+                read_only: true,
+            }]
+        );
+        assert_eq!(allocation.devices.len(), 2);
+        assert!(allocation.devices.iter().all(|d| !d.read_only));
+        assert_eq!(
+            allocation.annotations.get("example.com/devices").unwrap(),
+            "2"
+        );
+
+        // The container gets the same devices again, leaving none for other pods
+        assert_eq!(
+            manager.allocate(&pod, &container, None).await.unwrap(),
+            allocation
+        );
+        assert!(manager.allocate(&other, &container, None).await.is_err());
+
+        manager.release(&PodKey::from(&pod));
+        assert!(manager.allocate(&other, &container, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_registration() {
+        let tempdir = tempfile::tempdir().expect("should be able to create tempdir");
+        let manager = DevicePluginManager::new(tempdir.path(), mock_client(), "node");
+        let request = |version: &str, resource: &str| {
+            Request::new(RegisterRequest {
+                version: version.to_owned(),
+                endpoint: "example.sock".to_owned(),
+                resource_name: resource.to_owned(),
+                options: None,
+            })
+        };
+        assert!(
+            Registration::register(&manager, request("v1alpha", RESOURCE))
+                .await
+                .is_err()
+        );
+        assert!(
+            Registration::register(&manager, request(API_VERSION, "cpu"))
+                .await
+                .is_err()
+        );
+    }
+}
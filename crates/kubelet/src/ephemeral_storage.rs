@@ -0,0 +1,479 @@
+//! Tracks the ephemeral storage pods use against their `ephemeral-storage`
+//! limits, like the eviction manager of other kubelets.
+//!
+//! Providers give the [`StorageTracker`] the directories a pod keeps its
+//! scratch files in, such as its container logs and emptyDir volumes, when
+//! the pod starts. The tracker measures how much those directories hold every
+//! check interval, and once a pod uses more than its limit, which is the sum
+//! of its containers' limits, or one of its emptyDir volumes holds more than
+//! its `sizeLimit`, it tells the provider, which evicts the pod and deletes
+//! its files. What pods without limits use is measured too, and what each
+//! pod used when it was last measured is reported in its stats (see
+//! [`crate::stats`]).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::container::Container;
+use crate::pod::Pod;
+use crate::resources::parse_quantity;
+
+/// The resource that limits the ephemeral storage of containers
+const EPHEMERAL_STORAGE: &str = "ephemeral-storage";
+
+/// The ephemeral storage of a pod that is tracked
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PodStorage {
+    /// The directories the pod keeps its scratch files in, such as its
+    /// container logs and the emptyDir volumes on the node's disk
+    pub dirs: Vec<PathBuf>,
+    /// The pod's limit in bytes, if it has one
+    pub limit: Option<u64>,
+    /// The emptyDir volumes that have a `sizeLimit`, with the limit in bytes
+    pub volume_limits: Vec<(PathBuf, u64)>,
+    /// The emptyDir volumes on the node's disk, by name in the pod's spec
+    pub volumes: Vec<(String, PathBuf)>,
+}
+
+impl PodStorage {
+    /// Whether the pod has any limits to track its storage against
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some() || !self.volume_limits.is_empty()
+    }
+}
+
+/// What the scratch directories of a pod held when they were last measured
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageUsage {
+    /// When the directories were measured
+    pub time: DateTime<Utc>,
+    /// The bytes all of the pod's scratch directories held, if all of them
+    /// could be measured
+    pub used_bytes: Option<u64>,
+    /// The bytes each of the pod's emptyDir volumes that could be measured
+    /// held, by name
+    pub volumes: Vec<(String, u64)>,
+}
+
+/// The ephemeral storage a pod has used beyond its limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageExceeded {
+    /// The bytes the pod's scratch directories, or the volume, held when
+    /// they were measured
+    pub usage: u64,
+    /// The limit in bytes
+    pub limit: u64,
+    /// The emptyDir volume that held more than its `sizeLimit`, if it wasn't
+    /// the pod as a whole that went over its limit
+    pub volume: Option<PathBuf>,
+    /// The directories the pod keeps its scratch files in, which should be
+    /// deleted once the pod is stopped
+    pub dirs: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for StorageExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.volume {
+            Some(_) => write!(f, "emptyDir usage exceeds the limit"),
+            None => write!(
+                f,
+                "Pod ephemeral local storage usage exceeds the total limit of containers {}. Usage: {}",
+                self.limit, self.usage
+            ),
+        }
+    }
+}
+
+/// Returns the ephemeral storage limit of the pod in bytes, if any of its
+/// containers have one. Init containers don't run alongside the others, so
+/// the limit is the larger of the sum of the app containers' limits and the
+/// largest init container limit.
+pub fn pod_limit(pod: &Pod) -> anyhow::Result<Option<u64>> {
+    let mut limit = None;
+    for container in pod.containers() {
+        if let Some(bytes) = container_limit(&container)? {
+            *limit.get_or_insert(0) += bytes;
+        }
+    }
+    for container in pod.init_containers() {
+        if let Some(bytes) = container_limit(&container)? {
+            let total = limit.get_or_insert(0);
+            *total = (*total).max(bytes);
+        }
+    }
+    Ok(limit)
+}
+
+fn container_limit(container: &Container) -> anyhow::Result<Option<u64>> {
+    container
+        .resources()
+        .and_then(|r| r.limits.as_ref())
+        .and_then(|l| l.get(EPHEMERAL_STORAGE))
+        .map(|quantity| parse_quantity(&quantity.0))
+        .transpose()
+}
+
+/// Returns the bytes used by the files in the directories, and in the
+/// directories under them. Symbolic links aren't followed, and directories
+/// that don't exist hold nothing.
+pub fn usage(dirs: &[PathBuf]) -> std::io::Result<u64> {
+    let mut total = 0;
+    for dir in dirs {
+        total += dir_usage(dir)?;
+    }
+    Ok(total)
+}
+
+fn dir_usage(path: &Path) -> std::io::Result<u64> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        total += dir_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// A pod whose ephemeral storage is tracked
+struct TrackedPod {
+    storage: PodStorage,
+    exceeded: oneshot::Sender<StorageExceeded>,
+    /// What the pod used when it was last measured
+    usage: Option<StorageUsage>,
+}
+
+/// Periodically measures the ephemeral storage of pods, and tells the
+/// providers of pods over their limits
+pub struct StorageTracker {
+    pods: Mutex<HashMap<String, TrackedPod>>,
+}
+
+impl StorageTracker {
+    /// Creates a tracker that measures the pods every `interval`, on a thread
+    /// of its own, until it's dropped
+    pub fn new(interval: Duration) -> Arc<Self> {
+        let tracker = Arc::new(StorageTracker {
+            pods: Mutex::new(HashMap::new()),
+        });
+        let weak = Arc::downgrade(&tracker);
+        std::thread::spawn(move || run(weak, interval));
+        tracker
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, TrackedPod>> {
+        self.pods
+            .lock()
+            .expect("ephemeral storage tracker lock should not be poisoned")
+    }
+
+    /// Starts tracking the storage of the pod with the UID against its
+    /// limits, if it has any. The returned receiver is sent what the pod used
+    /// the first time it's found over a limit, after which the pod is no
+    /// longer tracked.
+    pub fn track(&self, pod_uid: &str, storage: PodStorage) -> oneshot::Receiver<StorageExceeded> {
+        let (exceeded, receiver) = oneshot::channel();
+        debug!(
+            "Tracking ephemeral storage of pod {}: {:?}",
+            pod_uid, storage
+        );
+        self.lock().insert(
+            pod_uid.to_owned(),
+            TrackedPod {
+                storage,
+                exceeded,
+                usage: None,
+            },
+        );
+        receiver
+    }
+
+    /// What the pod with the UID used when it was last measured, if it is
+    /// tracked and has been measured
+    pub fn last_usage(&self, pod_uid: &str) -> Option<StorageUsage> {
+        self.lock().get(pod_uid)?.usage.clone()
+    }
+
+    /// Stops tracking the pod with the UID
+    pub fn release(&self, pod_uid: &str) {
+        self.lock().remove(pod_uid);
+    }
+
+    /// Measures every tracked pod once, telling the providers of the pods
+    /// over their limits
+    pub fn check(&self) {
+        let pods: Vec<(String, PodStorage)> = self
+            .lock()
+            .iter()
+            .map(|(uid, pod)| (uid.clone(), pod.storage.clone()))
+            .collect();
+        // The directories are measured without the lock, as that can take a
+        // while
+        for (uid, storage) in pods {
+            let measured = measure(&uid, &storage);
+            if let Some(pod) = self.lock().get_mut(&uid) {
+                pod.usage = Some(measured.usage(&storage));
+            }
+            if let Some(exceeded) = measured.exceeded(storage) {
+                if let Some(pod) = self.lock().remove(&uid) {
+                    info!(
+                        "Pod {} uses {} bytes of ephemeral storage, over its limit of {}",
+                        uid, exceeded.usage, exceeded.limit
+                    );
+                    pod.exceeded.send(exceeded).ok();
+                }
+            }
+        }
+    }
+}
+
+/// The bytes each of a pod's scratch directories held, if it could be
+/// measured
+struct Measurement {
+    time: DateTime<Utc>,
+    dirs: HashMap<PathBuf, Option<u64>>,
+}
+
+/// Measures each of the pod's scratch directories and volumes once
+fn measure(uid: &str, storage: &PodStorage) -> Measurement {
+    let time = Utc::now();
+    let mut dirs = HashMap::new();
+    let paths = storage
+        .dirs
+        .iter()
+        .chain(storage.volume_limits.iter().map(|(path, _)| path))
+        .chain(storage.volumes.iter().map(|(_, path)| path));
+    for path in paths {
+        if dirs.contains_key(path) {
+            continue;
+        }
+        let used = match usage(std::slice::from_ref(path)) {
+            Ok(used) => Some(used),
+            Err(e) => {
+                warn!(
+                    "Unable to measure ephemeral storage of pod {} in {:?}: {:?}",
+                    uid, path, e
+                );
+                None
+            }
+        };
+        dirs.insert(path.clone(), used);
+    }
+    Measurement { time, dirs }
+}
+
+impl Measurement {
+    fn used(&self, path: &Path) -> Option<u64> {
+        self.dirs.get(path).copied().flatten()
+    }
+
+    fn usage(&self, storage: &PodStorage) -> StorageUsage {
+        StorageUsage {
+            time: self.time,
+            used_bytes: storage.dirs.iter().map(|dir| self.used(dir)).sum(),
+            volumes: storage
+                .volumes
+                .iter()
+                .filter_map(|(name, path)| Some((name.clone(), self.used(path)?)))
+                .collect(),
+        }
+    }
+
+    /// What the pod used if it's over one of its limits
+    fn exceeded(&self, storage: PodStorage) -> Option<StorageExceeded> {
+        for (volume, limit) in &storage.volume_limits {
+            match self.used(volume) {
+                Some(used) if used > *limit => {
+                    return Some(StorageExceeded {
+                        usage: used,
+                        limit: *limit,
+                        volume: Some(volume.clone()),
+                        dirs: storage.dirs,
+                    })
+                }
+                _ => (),
+            }
+        }
+        let limit = storage.limit?;
+        let used: Option<u64> = storage.dirs.iter().map(|dir| self.used(dir)).sum();
+        match used {
+            Some(used) if used > limit => Some(StorageExceeded {
+                usage: used,
+                limit,
+                volume: None,
+                dirs: storage.dirs,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn run(tracker: Weak<StorageTracker>, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        match tracker.upgrade() {
+            Some(tracker) => tracker.check(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec, ResourceRequirements};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    fn container(limit: Option<&str>) -> KubeContainer {
+        KubeContainer {
+            name: "app".to_owned(),
+            resources: Some(ResourceRequirements {
+                limits: limit.map(|l| {
+                    vec![(EPHEMERAL_STORAGE.to_owned(), Quantity(l.to_owned()))]
+                        .into_iter()
+                        .collect()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pod(containers: Vec<KubeContainer>, init_containers: Vec<KubeContainer>) -> Pod {
+        test_util::pod(
+            "pod",
+            PodSpec {
+                containers,
+                init_containers: Some(init_containers),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn pod_limits_add_up_container_limits() {
+        let unlimited = pod(vec![container(None)], vec![]);
+        assert_eq!(pod_limit(&unlimited).unwrap(), None);
+
+        let limited = pod(
+            vec![
+                container(Some("1Mi")),
+                container(Some("2Mi")),
+                container(None),
+            ],
+            vec![container(Some("4Mi"))],
+        );
+        assert_eq!(pod_limit(&limited).unwrap(), Some(4 * 1024 * 1024));
+
+        let small_init = pod(
+            vec![container(Some("1Mi")), container(Some("2Mi"))],
+            vec![container(Some("1Mi"))],
+        );
+        assert_eq!(pod_limit(&small_init).unwrap(), Some(3 * 1024 * 1024));
+    }
+
+    #[tokio::test]
+    async fn pods_over_their_limit_are_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        std::fs::create_dir_all(logs.join("app")).unwrap();
+        std::fs::write(logs.join("app/0.log"), vec![0; 600]).unwrap();
+        let tracker = StorageTracker::new(Duration::from_secs(3600));
+        let dirs = vec![logs.clone(), dir.path().join("missing")];
+        let storage = PodStorage {
+            dirs: dirs.clone(),
+            limit: Some(1000),
+            ..Default::default()
+        };
+        let mut exceeded = tracker.track("pod", storage);
+
+        tracker.check();
+        assert!(exceeded.try_recv().is_err());
+
+        std::fs::write(logs.join("app/1.log"), vec![0; 600]).unwrap();
+        tracker.check();
+        assert_eq!(
+            exceeded.await.unwrap(),
+            StorageExceeded {
+                usage: 1200,
+                limit: 1000,
+                volume: None,
+                dirs,
+            }
+        );
+        assert!(tracker.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_dir_volumes_over_their_size_limit_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::write(cache.join("blob"), vec![0; 2048]).unwrap();
+        let tracker = StorageTracker::new(Duration::from_secs(3600));
+        let storage = PodStorage {
+            dirs: vec![cache.clone()],
+            limit: None,
+            volume_limits: vec![(cache.clone(), 1024)],
+            volumes: vec![("cache".to_owned(), cache.clone())],
+        };
+        let mut exceeded = tracker.track("pod", storage);
+
+        tracker.check();
+        let exceeded = exceeded.try_recv().unwrap();
+        assert_eq!(exceeded.volume, Some(cache));
+        assert_eq!(exceeded.to_string(), "emptyDir usage exceeds the limit");
+    }
+
+    #[test]
+    fn usage_of_pods_without_limits_is_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        let cache = dir.path().join("cache");
+        std::fs::create_dir(&logs).unwrap();
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::write(logs.join("0.log"), vec![0; 100]).unwrap();
+        std::fs::write(cache.join("blob"), vec![0; 300]).unwrap();
+        let tracker = StorageTracker::new(Duration::from_secs(3600));
+        let storage = PodStorage {
+            dirs: vec![logs, cache.clone()],
+            volumes: vec![("cache".to_owned(), cache)],
+            ..Default::default()
+        };
+        assert!(!storage.is_limited());
+        let _exceeded = tracker.track("pod", storage);
+        assert_eq!(tracker.last_usage("pod"), None);
+
+        let before = Utc::now();
+        tracker.check();
+        let usage = tracker.last_usage("pod").unwrap();
+        assert!(usage.time >= before);
+        assert_eq!(usage.used_bytes, Some(400));
+        assert_eq!(usage.volumes, vec![("cache".to_owned(), 300)]);
+        assert_eq!(tracker.last_usage("other"), None);
+    }
+
+    #[test]
+    fn released_pods_are_not_tracked() {
+        let tracker = StorageTracker::new(Duration::from_secs(3600));
+        let storage = PodStorage {
+            limit: Some(1000),
+            ..Default::default()
+        };
+        let _exceeded = tracker.track("pod", storage);
+        tracker.release("pod");
+        assert!(tracker.lock().is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//! Accounting of the extended resources advertised by the provider.
+//!
+//! A provider can advertise countable resources of its own, such as
+//! `example.com/wasm-gpu`, with
+//! [`Provider::node_resources`](crate::provider::Provider::node_resources).
+//! They are added to the node's capacity and allocatable resources when it
+//! registers and each time its status is updated. A pod is only admitted if
+//! what it requests of them is free, given what the pods admitted before it
+//! were given, and is otherwise failed with the reason `OutOf` followed by
+//! the resource's name. What a pod was given is returned to the pool when it
+//! is released, as it is deleted or completes.
+//!
+//! Extended resources managed by device plugins are allocated by the
+//! [`DevicePluginManager`](crate::device_plugin_manager::DevicePluginManager)
+//! instead.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::container::Container;
+use crate::pod::Pod;
+use crate::resources::parse_quantity;
+
+lazy_static::lazy_static! {
+    /// The extended resources of this node, and what admitted pods were given
+    static ref POOL: Mutex<Pool> = Mutex::new(Pool::default());
+}
+
+fn pool() -> std::sync::MutexGuard<'static, Pool> {
+    POOL.lock()
+        .expect("extended resource pool lock should not be poisoned")
+}
+
+/// A pod requested more of an extended resource than is free
+#[derive(Debug, Error, PartialEq)]
+#[error("pod requests {requested} of {resource}, but only {free} of {capacity} is free")]
+pub struct InsufficientResource {
+    /// The name of the resource
+    pub resource: String,
+    /// How much of it the pod requested
+    pub requested: u64,
+    /// How much of it was free
+    pub free: u64,
+    /// How much of it the node has
+    pub capacity: u64,
+}
+
+impl InsufficientResource {
+    /// The reason the pod is failed with, such as
+    /// `OutOfexample.com/wasm-gpu`, as other kubelets give
+    pub fn reason(&self) -> String {
+        format!("OutOf{}", self.resource)
+    }
+}
+
+/// The extended resources of the node, and what was given to each pod
+#[derive(Debug, Default)]
+struct Pool {
+    capacity: HashMap<String, u64>,
+    /// What each admitted pod was given, by pod UID
+    allocated: HashMap<String, HashMap<String, u64>>,
+}
+
+impl Pool {
+    /// How much of the resource isn't given to any pod
+    fn free(&self, resource: &str) -> u64 {
+        let capacity = self.capacity.get(resource).copied().unwrap_or(0);
+        let allocated: u64 = self
+            .allocated
+            .values()
+            .filter_map(|pod| pod.get(resource))
+            .sum();
+        capacity.saturating_sub(allocated)
+    }
+
+    /// Gives the pod with the UID what it requests, if all of it is free
+    fn admit(
+        &mut self,
+        pod_uid: &str,
+        requests: HashMap<String, u64>,
+    ) -> Result<(), InsufficientResource> {
+        // A pod admitted again is given what it requests now instead
+        let previous = self.allocated.remove(pod_uid);
+        let mut resources: Vec<_> = requests.iter().collect();
+        resources.sort();
+        for (resource, &requested) in resources {
+            let free = self.free(resource);
+            if requested > free {
+                if let Some(previous) = previous {
+                    self.allocated.insert(pod_uid.to_owned(), previous);
+                }
+                return Err(InsufficientResource {
+                    resource: resource.clone(),
+                    requested,
+                    free,
+                    capacity: self.capacity.get(resource).copied().unwrap_or(0),
+                });
+            }
+        }
+        if !requests.is_empty() {
+            self.allocated.insert(pod_uid.to_owned(), requests);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, pod_uid: &str) {
+        self.allocated.remove(pod_uid);
+    }
+}
+
+/// Sets the extended resources of the node, as the provider advertises them.
+/// Pods already admitted keep what they were given, even if the node now
+/// has less.
+pub(crate) fn set_capacity(capacity: &HashMap<String, u64>) {
+    pool().capacity = capacity.clone();
+}
+
+/// Admits the pod if what it requests of the node's extended resources is
+/// free, keeping it from other pods until the pod is released
+pub fn admit(pod: &Pod) -> Result<(), InsufficientResource> {
+    let mut pool = pool();
+    let requests = pod_requests(pod, &pool.capacity);
+    if !requests.is_empty() {
+        debug!(
+            "Pod {} requests extended resources {:?}",
+            pod.name(),
+            requests
+        );
+    }
+    pool.admit(pod.pod_uid(), requests)
+}
+
+/// Returns what the pod was given of the node's extended resources to the
+/// pool
+pub fn release(pod: &Pod) {
+    pool().release(pod.pod_uid());
+}
+
+/// What the pod requests of the resources the node advertises. As with
+/// other resources, init containers run one at a time before the others, so
+/// the pod needs the most that any of them requests or all its containers
+/// request together, whichever is greater.
+fn pod_requests(pod: &Pod, advertised: &HashMap<String, u64>) -> HashMap<String, u64> {
+    let mut requests = HashMap::new();
+    for container in pod.containers() {
+        for (resource, amount) in container_requests(&container, advertised) {
+            *requests.entry(resource).or_insert(0) += amount;
+        }
+    }
+    for container in pod.init_containers() {
+        for (resource, amount) in container_requests(&container, advertised) {
+            let request = requests.entry(resource).or_insert(0);
+            *request = (*request).max(amount);
+        }
+    }
+    requests.retain(|_, amount| *amount > 0);
+    requests
+}
+
+/// What the container requests of the resources the node advertises. Extended
+/// resources can't be overcommitted, so a container that only sets a limit
+/// requests as much.
+fn container_requests(
+    container: &Container,
+    advertised: &HashMap<String, u64>,
+) -> Vec<(String, u64)> {
+    let resources = match container.resources() {
+        Some(resources) => resources,
+        None => return vec![],
+    };
+    let limits = resources.limits.as_ref();
+    let requests = resources.requests.as_ref();
+    advertised
+        .keys()
+        .filter_map(|resource| {
+            let quantity = requests
+                .and_then(|r| r.get(resource))
+                .or_else(|| limits.and_then(|l| l.get(resource)))?;
+            match parse_quantity(&quantity.0) {
+                Ok(amount) => Some((resource.clone(), amount)),
+                Err(e) => {
+                    warn!(
+                        "Ignoring invalid request for {} of container {}: {:?}",
+                        resource,
+                        container.name(),
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use serde_json::json;
+
+    const GPU: &str = "example.com/wasm-gpu";
+
+    fn pod(uid: &str, containers: &[u64], init_containers: &[u64]) -> Pod {
+        let container = |(i, gpus): (usize, &u64)| {
+            json!({
+                "name": format!("container-{}", i),
+                "resources": { "limits": { GPU: gpus.to_string() } },
+            })
+        };
+        let spec = json!({
+            "containers": containers.iter().enumerate().map(container).collect::<Vec<_>>(),
+            "initContainers": init_containers.iter().enumerate().map(container).collect::<Vec<_>>(),
+        });
+        test_util::pod(uid, serde_json::from_value(spec).unwrap())
+    }
+
+    fn pool(gpus: u64) -> (Pool, HashMap<String, u64>) {
+        let capacity: HashMap<String, u64> = vec![(GPU.to_owned(), gpus)].into_iter().collect();
+        let pool = Pool {
+            capacity: capacity.clone(),
+            allocated: HashMap::new(),
+        };
+        (pool, capacity)
+    }
+
+    #[test]
+    fn pods_need_the_most_of_their_init_containers_or_all_their_containers() {
+        let (_, advertised) = pool(4);
+        let requests = pod_requests(&pod("a", &[1, 2], &[]), &advertised);
+        assert_eq!(requests.get(GPU), Some(&3));
+        let requests = pod_requests(&pod("a", &[1, 2], &[4]), &advertised);
+        assert_eq!(requests.get(GPU), Some(&4));
+        // Resources the node doesn't advertise are left to others
+        let requests = pod_requests(&pod("a", &[1], &[]), &HashMap::new());
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn pods_that_would_overcommit_a_resource_are_rejected() {
+        let (mut pool, advertised) = pool(4);
+        let first = pod("first", &[3], &[]);
+        let second = pod("second", &[2], &[]);
+        pool.admit(first.pod_uid(), pod_requests(&first, &advertised))
+            .unwrap();
+        let rejected = pool
+            .admit(second.pod_uid(), pod_requests(&second, &advertised))
+            .unwrap_err();
+        assert_eq!(
+            rejected,
+            InsufficientResource {
+                resource: GPU.to_owned(),
+                requested: 2,
+                free: 1,
+                capacity: 4,
+            }
+        );
+        assert_eq!(rejected.reason(), "OutOfexample.com/wasm-gpu");
+        assert_eq!(pool.free(GPU), 1);
+    }
+
+    #[test]
+    fn resources_of_released_pods_return_to_the_pool() {
+        let (mut pool, advertised) = pool(4);
+        let first = pod("first", &[3], &[]);
+        let second = pod("second", &[2], &[]);
+        pool.admit(first.pod_uid(), pod_requests(&first, &advertised))
+            .unwrap();
+        pool.release(first.pod_uid());
+        assert_eq!(pool.free(GPU), 4);
+        pool.admit(second.pod_uid(), pod_requests(&second, &advertised))
+            .unwrap();
+        assert_eq!(pool.free(GPU), 2);
+    }
+}
@@ -0,0 +1,111 @@
+//! Feature gates for experimental behavior
+//!
+//! Experimental features are disabled unless they are enabled through the
+//! `featureGates` configuration value, so that they can be rolled out without
+//! recompiling. Subsystems check their gate with [`FeatureGates::is_enabled`]
+//! on the gates in their [`Config`](crate::config::Config), or, where no
+//! configuration is at hand, with [`FeatureGate::is_enabled`], which checks the
+//! gates of the running kubelet.
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+lazy_static::lazy_static! {
+    static ref GLOBAL_FEATURE_GATES: RwLock<FeatureGates> = RwLock::new(FeatureGates::default());
+}
+
+/// Whether each feature gate is enabled, by feature name. Features without an
+/// entry are disabled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeatureGates {
+    gates: HashMap<String, bool>,
+}
+
+impl FeatureGates {
+    /// Creates feature gates from a map of feature names to whether they are
+    /// enabled
+    pub fn new(gates: HashMap<String, bool>) -> Self {
+        FeatureGates { gates }
+    }
+
+    /// Returns whether the named feature is enabled
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.gates.get(name).copied().unwrap_or(false)
+    }
+
+    /// Enables or disables the named feature
+    pub fn set(&mut self, name: &str, enabled: bool) {
+        self.gates.insert(name.to_owned(), enabled);
+    }
+
+    /// Returns whether no feature gates are set
+    pub fn is_empty(&self) -> bool {
+        self.gates.is_empty()
+    }
+
+    /// Makes these the gates checked by [`FeatureGate::is_enabled`]. The
+    /// kubelet does this with the gates in its configuration when it is
+    /// created.
+    pub fn install(&self) {
+        *GLOBAL_FEATURE_GATES
+            .write()
+            .expect("feature gate lock should not be poisoned") = self.clone();
+    }
+}
+
+impl From<HashMap<String, bool>> for FeatureGates {
+    fn from(gates: HashMap<String, bool>) -> Self {
+        FeatureGates::new(gates)
+    }
+}
+
+/// Checks the feature gates of the running kubelet
+pub struct FeatureGate;
+
+impl FeatureGate {
+    /// Returns whether the named feature is enabled in the installed feature
+    /// gates. All features are disabled until gates are installed.
+    pub fn is_enabled(name: &str) -> bool {
+        GLOBAL_FEATURE_GATES
+            .read()
+            .expect("feature gate lock should not be poisoned")
+            .is_enabled(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn features_are_disabled_by_default() {
+        let gates = FeatureGates::default();
+        assert!(!gates.is_enabled("WasiSockets"));
+    }
+
+    #[test]
+    fn features_can_be_enabled_and_disabled() {
+        let mut gates = FeatureGates::new(
+            vec![
+                ("WasiSockets".to_owned(), true),
+                ("WasiHttp".to_owned(), false),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert!(gates.is_enabled("WasiSockets"));
+        assert!(!gates.is_enabled("WasiHttp"));
+        gates.set("WasiHttp", true);
+        assert!(gates.is_enabled("WasiHttp"));
+    }
+
+    #[test]
+    fn installed_gates_are_checked_globally() {
+        let mut gates = FeatureGates::default();
+        gates.set("GlobalFeatureGateTest", true);
+        assert!(!FeatureGate::is_enabled("GlobalFeatureGateTest"));
+        gates.install();
+        assert!(FeatureGate::is_enabled("GlobalFeatureGateTest"));
+        FeatureGates::default().install();
+        assert!(!FeatureGate::is_enabled("GlobalFeatureGateTest"));
+    }
+}
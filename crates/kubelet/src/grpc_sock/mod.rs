@@ -3,11 +3,8 @@
 //! (as it isn't in standard due to backwards compatibility guarantees). This is our own package for
 //! now, but if it is useful we could publish it as its own crate
 
-// Right now we only use the server for testing purposes. If we choose to publish this as its own
-// crate, we should remove this attribute
 #[cfg_attr(target_family = "unix", path = "unix/mod.rs")]
 #[cfg_attr(target_family = "windows", path = "windows/mod.rs")]
-#[cfg(test)]
 pub mod server;
 
 pub mod client;
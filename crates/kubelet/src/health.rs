@@ -0,0 +1,383 @@
+//! Health checks of the kubelet's components.
+//!
+//! Each [`HealthCheck`] checks one component, and is registered by name in
+//! [`HealthChecks`]. The kubelet server runs them for `/healthz` and
+//! `/readyz`, which fail with 500 Internal Server Error if any of them fail,
+//! and list the result of each check, as other Kubernetes components do:
+//!
+//! ```text
+//! [+]apiserver-connectivity ok
+//! [-]pod-worker failed: reason withheld
+//! [+]provider ok
+//! [+]disk ok
+//! healthz check failed
+//! ```
+//!
+//! Passing checks are only listed with `?verbose`, and are otherwise
+//! answered with `ok`. Checks can be skipped with `?exclude=<name>`, which
+//! may be repeated. Why a check failed is logged rather than answered, as
+//! anyone may ask for the kubelet's health.
+//!
+//! The kubelet registers these checks:
+//!
+//! * `apiserver-connectivity`, which fails when the node's lease hasn't been
+//!   renewed for as long as the lease lasts
+//! * `pod-worker`, which fails when the loop that hands pod events to the
+//!   pods' state machines hasn't run for [`POD_WORKER_MAX_AGE`]
+//! * `provider`, which fails when [`Provider::healthy`] does
+//! * `disk`, which fails when a file can't be written to the data directory
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::provider::Provider;
+
+/// The name of the check that the node keeps renewing its lease
+pub const APISERVER_CONNECTIVITY: &str = "apiserver-connectivity";
+/// The name of the check that pod events are handed to state machines
+pub const POD_WORKER: &str = "pod-worker";
+/// The name of the check of the provider
+pub const PROVIDER: &str = "provider";
+/// The name of the check that the data directory can be written to
+pub const DISK: &str = "disk";
+
+/// How long the loop handing pod events to state machines can go without
+/// running before it is considered stuck. It runs at least every
+/// ten seconds while it isn't.
+pub const POD_WORKER_MAX_AGE: Duration = Duration::from_secs(3 * 60);
+
+/// The file written to the data directory to check that it can be written
+const DISK_PROBE_FILE: &str = ".healthz";
+
+/// Checks the health of a component of the kubelet
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// The name of the check, which it is listed and excluded by
+    fn name(&self) -> &str;
+
+    /// Returns an error saying why the component isn't healthy, if it isn't
+    async fn check(&self) -> anyhow::Result<()>;
+}
+
+/// The health checks of the kubelet, which are run together
+#[derive(Clone, Default)]
+pub struct HealthChecks {
+    checks: Vec<Arc<dyn HealthCheck>>,
+}
+
+impl HealthChecks {
+    /// Creates a registry with no checks, which is always healthy
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `check`, which is run after the checks added before it
+    pub fn register(&mut self, check: impl HealthCheck + 'static) {
+        self.checks.push(Arc::new(check));
+    }
+
+    /// The names of the checks, in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.checks.iter().map(|check| check.name()).collect()
+    }
+
+    /// Runs every check that isn't named in `exclude`, one after another
+    pub async fn run(&self, exclude: &[String]) -> HealthReport {
+        let mut results = Vec::with_capacity(self.checks.len());
+        for check in &self.checks {
+            let name = check.name().to_owned();
+            let outcome = if exclude.contains(&name) {
+                Outcome::Excluded
+            } else {
+                match check.check().await {
+                    Ok(()) => Outcome::Passed,
+                    Err(e) => {
+                        warn!("Health check {} failed: {:?}", name, e);
+                        Outcome::Failed(e.to_string())
+                    }
+                }
+            };
+            results.push(CheckResult { name, outcome });
+        }
+        let unknown_exclusions = exclude
+            .iter()
+            .filter(|name| !self.checks.iter().any(|check| check.name() == *name))
+            .cloned()
+            .collect();
+        HealthReport {
+            results,
+            unknown_exclusions,
+        }
+    }
+}
+
+/// How a check went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The component is healthy
+    Passed,
+    /// The component isn't healthy, for this reason
+    Failed(String),
+    /// The check was excluded, and not run
+    Excluded,
+}
+
+/// The outcome of a named check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    /// The name of the check
+    pub name: String,
+    /// How the check went
+    pub outcome: Outcome,
+}
+
+/// The outcomes of running health checks
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// The outcome of each check, in the order they ran
+    pub results: Vec<CheckResult>,
+    /// Names that were excluded that no check has
+    pub unknown_exclusions: Vec<String>,
+}
+
+impl HealthReport {
+    /// Whether none of the checks failed
+    pub fn healthy(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|result| matches!(result.outcome, Outcome::Failed(_)))
+    }
+
+    /// The report as the answer to `endpoint`, such as `healthz`. Every check
+    /// is listed if `verbose` is set or a check failed, without the reasons
+    /// checks failed.
+    pub fn render(&self, endpoint: &str, verbose: bool) -> String {
+        let healthy = self.healthy();
+        if healthy && !verbose {
+            return "ok".to_owned();
+        }
+        let mut lines: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| match &result.outcome {
+                Outcome::Passed => format!("[+]{} ok", result.name),
+                Outcome::Excluded => format!("[+]{} excluded: ok", result.name),
+                Outcome::Failed(_) => format!("[-]{} failed: reason withheld", result.name),
+            })
+            .collect();
+        if !self.unknown_exclusions.is_empty() {
+            let names: Vec<String> = self
+                .unknown_exclusions
+                .iter()
+                .map(|name| format!("{:?}", name))
+                .collect();
+            lines.push(format!(
+                "warn: some health checks cannot be excluded: no matches for {}",
+                names.join(", ")
+            ));
+        }
+        lines.push(format!(
+            "{} check {}",
+            endpoint,
+            if healthy { "passed" } else { "failed" }
+        ));
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Fails when a component hasn't sent a heartbeat for too long, such as a
+/// loop that should keep running
+pub struct HeartbeatCheck {
+    name: String,
+    heartbeats: watch::Receiver<Instant>,
+    max_age: Duration,
+}
+
+impl HeartbeatCheck {
+    /// Creates a check named `name` that fails when no heartbeat has been
+    /// sent on the returned sender for longer than `max_age`. The check
+    /// counts as a heartbeat, so that components have `max_age` to start.
+    pub fn new(name: impl Into<String>, max_age: Duration) -> (watch::Sender<Instant>, Self) {
+        let (sender, heartbeats) = watch::channel(Instant::now());
+        let check = HeartbeatCheck {
+            name: name.into(),
+            heartbeats,
+            max_age,
+        };
+        (sender, check)
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HeartbeatCheck {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let age = self.heartbeats.borrow().elapsed();
+        if age > self.max_age {
+            return Err(anyhow::anyhow!(
+                "the last heartbeat was {}s ago, more than the {}s allowed",
+                age.as_secs(),
+                self.max_age.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Fails when the provider says it isn't healthy
+pub(crate) struct ProviderCheck<P> {
+    provider: Arc<P>,
+}
+
+impl<P: Provider> ProviderCheck<P> {
+    pub(crate) fn new(provider: Arc<P>) -> Self {
+        ProviderCheck { provider }
+    }
+}
+
+#[async_trait]
+impl<P: Provider> HealthCheck for ProviderCheck<P> {
+    fn name(&self) -> &str {
+        PROVIDER
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        self.provider.healthy().await
+    }
+}
+
+/// Fails when a file can't be written to a directory, such as when its disk
+/// is full or has been mounted read-only
+pub struct DiskCheck {
+    dir: PathBuf,
+}
+
+impl DiskCheck {
+    /// Creates a check that `dir` can be written to
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DiskCheck { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for DiskCheck {
+    fn name(&self) -> &str {
+        DISK
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let probe = self.dir.join(DISK_PROBE_FILE);
+        tokio::fs::write(&probe, b"ok")
+            .await
+            .map_err(|e| anyhow::anyhow!("unable to write to {}: {}", self.dir.display(), e))?;
+        tokio::fs::remove_file(&probe).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct FixedCheck {
+        name: &'static str,
+        healthy: bool,
+    }
+
+    #[async_trait]
+    impl HealthCheck for FixedCheck {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn check(&self) -> anyhow::Result<()> {
+            if self.healthy {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("{} is broken", self.name))
+            }
+        }
+    }
+
+    fn checks(pod_worker_healthy: bool) -> HealthChecks {
+        let mut checks = HealthChecks::new();
+        checks.register(FixedCheck {
+            name: APISERVER_CONNECTIVITY,
+            healthy: true,
+        });
+        checks.register(FixedCheck {
+            name: POD_WORKER,
+            healthy: pod_worker_healthy,
+        });
+        checks
+    }
+
+    #[tokio::test]
+    async fn healthy_checks_are_only_listed_when_verbose() {
+        let report = checks(true).run(&[]).await;
+        assert!(report.healthy());
+        assert_eq!(report.render("healthz", false), "ok");
+        assert_eq!(
+            report.render("healthz", true),
+            "[+]apiserver-connectivity ok\n[+]pod-worker ok\nhealthz check passed\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_checks_are_listed_without_their_reasons() {
+        let report = checks(false).run(&[]).await;
+        assert!(!report.healthy());
+        assert_eq!(
+            report.results[1].outcome,
+            Outcome::Failed("pod-worker is broken".to_owned())
+        );
+        assert_eq!(
+            report.render("readyz", false),
+            "[+]apiserver-connectivity ok\n[-]pod-worker failed: reason withheld\nreadyz check failed\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn excluded_checks_are_not_run() {
+        let report = checks(false)
+            .run(&[POD_WORKER.to_owned(), "etcd".to_owned()])
+            .await;
+        assert!(report.healthy());
+        assert_eq!(
+            report.render("healthz", true),
+            "[+]apiserver-connectivity ok\n[+]pod-worker excluded: ok\nwarn: some health checks cannot be excluded: no matches for \"etcd\"\nhealthz check passed\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeats_must_be_recent() {
+        let (heartbeats, check) = HeartbeatCheck::new(POD_WORKER, Duration::from_secs(60));
+        assert!(check.check().await.is_ok());
+        heartbeats
+            .send(Instant::now() - Duration::from_secs(61))
+            .unwrap();
+        assert!(check.check().await.is_err());
+        heartbeats.send(Instant::now()).unwrap();
+        assert!(check.check().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn disks_must_be_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DiskCheck::new(dir.path()).check().await.is_ok());
+        assert!(!dir.path().join(DISK_PROBE_FILE).exists());
+        assert!(DiskCheck::new(dir.path().join("missing"))
+            .check()
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,541 @@
+//! Huge pages, which the node advertises as the `hugepages-2Mi` and
+//! `hugepages-1Gi` resources, like other kubelets.
+//!
+//! The huge pages the system has are read from `/proc/meminfo` when the node
+//! is created. Pods request them in bytes, like `hugepages-2Mi: 8Mi` for four
+//! 2 MiB pages, which must be a whole number of pages. Before a pod's
+//! containers start, the [`HugePagesManager`] reserves the pages the pod
+//! requests from the ones no other pod has, and gives them back once the pod
+//! is deleted. Providers back the memory their runtime allocates for the
+//! pod's containers with the reserved pages, with [`PodHugePages::enter`] on
+//! the thread allocating it and [`map_huge_pages`] for each allocation.
+//!
+//! Memory is only backed with huge pages on Linux.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, warn};
+
+use crate::container::Container;
+use crate::pod::Pod;
+use crate::resources::parse_quantity;
+
+/// Where the kernel describes the memory of the system
+const MEMINFO: &str = "/proc/meminfo";
+
+thread_local! {
+    static CURRENT_PAGES: RefCell<Option<Arc<PodHugePages>>> = const { RefCell::new(None) };
+}
+
+/// A size of huge page that pods can request
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum HugePageSize {
+    /// 2 MiB pages
+    Size2Mi,
+    /// 1 GiB pages
+    Size1Gi,
+}
+
+impl HugePageSize {
+    /// Every size of huge page, from the smallest
+    pub const ALL: [HugePageSize; 2] = [HugePageSize::Size2Mi, HugePageSize::Size1Gi];
+
+    /// The size of a page in bytes
+    pub fn bytes(&self) -> u64 {
+        match self {
+            HugePageSize::Size2Mi => 2 * 1024 * 1024,
+            HugePageSize::Size1Gi => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// The name of the resource pods request pages of this size with
+    pub fn resource_name(&self) -> &'static str {
+        match self {
+            HugePageSize::Size2Mi => "hugepages-2Mi",
+            HugePageSize::Size1Gi => "hugepages-1Gi",
+        }
+    }
+
+    fn from_kib(kib: u64) -> Option<Self> {
+        HugePageSize::ALL
+            .iter()
+            .copied()
+            .find(|size| size.bytes() == kib * 1024)
+    }
+
+    /// The flags that map memory with pages of this size
+    #[cfg(target_os = "linux")]
+    fn mmap_flags(&self) -> libc::c_int {
+        // From linux/mman.h. The log2 of the page size is given in the bits
+        // from MAP_HUGE_SHIFT
+        const MAP_HUGE_SHIFT: libc::c_int = 26;
+        let log2 = self.bytes().trailing_zeros() as libc::c_int;
+        libc::MAP_HUGETLB | (log2 << MAP_HUGE_SHIFT)
+    }
+}
+
+/// The number of huge pages of each size the system has. Only the default
+/// size of huge page is described by `/proc/meminfo`, so the node has none of
+/// the other sizes. Systems without huge pages have none of any size.
+pub fn system_capacity() -> BTreeMap<HugePageSize, u64> {
+    match std::fs::read_to_string(MEMINFO) {
+        Ok(meminfo) => parse_meminfo(&meminfo),
+        Err(e) => {
+            debug!(
+                "Unable to read {}, so there are no huge pages: {}",
+                MEMINFO, e
+            );
+            BTreeMap::new()
+        }
+    }
+}
+
+fn parse_meminfo(meminfo: &str) -> BTreeMap<HugePageSize, u64> {
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            let value = line.strip_prefix(name)?.strip_prefix(':')?;
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+    };
+    let mut capacity = BTreeMap::new();
+    if let (Some(total), Some(size)) = (
+        field("HugePages_Total"),
+        field("Hugepagesize").and_then(HugePageSize::from_kib),
+    ) {
+        capacity.insert(size, total);
+    }
+    capacity
+}
+
+/// The number of huge pages of each size the pod needs. As init containers
+/// run before the other containers, the pod needs as many pages as the most
+/// any of them, or all the other containers, request.
+pub fn pod_requests(pod: &Pod) -> anyhow::Result<BTreeMap<HugePageSize, u64>> {
+    let mut requests: BTreeMap<HugePageSize, u64> = BTreeMap::new();
+    for container in pod.containers() {
+        for (size, pages) in container_requests(&container)? {
+            *requests.entry(size).or_default() += pages;
+        }
+    }
+    for container in pod.init_containers() {
+        for (size, pages) in container_requests(&container)? {
+            let total = requests.entry(size).or_default();
+            *total = (*total).max(pages);
+        }
+    }
+    requests.retain(|_, pages| *pages > 0);
+    Ok(requests)
+}
+
+/// The number of huge pages of each size the container has a limit of, or
+/// requests if it has no limit, as huge pages can't be overcommitted
+fn container_requests(container: &Container) -> anyhow::Result<Vec<(HugePageSize, u64)>> {
+    let resources = match container.resources() {
+        Some(resources) => resources,
+        None => return Ok(vec![]),
+    };
+    let mut requests = vec![];
+    for size in &HugePageSize::ALL {
+        let quantity = resources
+            .limits
+            .as_ref()
+            .and_then(|l| l.get(size.resource_name()))
+            .or_else(|| {
+                resources
+                    .requests
+                    .as_ref()
+                    .and_then(|r| r.get(size.resource_name()))
+            });
+        if let Some(quantity) = quantity {
+            let bytes = parse_quantity(&quantity.0)?;
+            if bytes % size.bytes() != 0 {
+                return Err(anyhow::anyhow!(
+                    "container {} requests {} of {}, which is not a whole number of pages",
+                    container.name(),
+                    quantity.0,
+                    size.resource_name()
+                ));
+            }
+            requests.push((*size, bytes / size.bytes()));
+        }
+    }
+    Ok(requests)
+}
+
+/// Tracks the huge pages reserved for pods
+pub struct HugePagesManager {
+    capacity: BTreeMap<HugePageSize, u64>,
+    /// The pages reserved for each pod, by UID
+    reserved: Mutex<HashMap<String, Arc<PodHugePages>>>,
+}
+
+impl HugePagesManager {
+    /// Creates a manager of the huge pages the system has
+    pub fn new() -> Arc<Self> {
+        Self::with_capacity(system_capacity())
+    }
+
+    fn with_capacity(capacity: BTreeMap<HugePageSize, u64>) -> Arc<Self> {
+        Arc::new(HugePagesManager {
+            capacity,
+            reserved: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The number of huge pages of each size the system has
+    pub fn capacity(&self) -> &BTreeMap<HugePageSize, u64> {
+        &self.capacity
+    }
+
+    /// The number of huge pages of the size that aren't reserved for any pod
+    pub fn available(&self, size: HugePageSize) -> u64 {
+        let reserved: u64 = self.lock().values().map(|pod| pod.pages(size)).sum();
+        self.capacity
+            .get(&size)
+            .copied()
+            .unwrap_or(0)
+            .saturating_sub(reserved)
+    }
+
+    /// Reserves the huge pages the pod requests, returning them, or nothing if
+    /// it requests none. A pod that already has pages keeps the ones it has,
+    /// so that its containers can restart with them. Fails if there aren't
+    /// enough pages left.
+    pub fn reserve(&self, pod: &Pod) -> anyhow::Result<Option<Arc<PodHugePages>>> {
+        let requests = pod_requests(pod)?;
+        if requests.is_empty() {
+            return Ok(None);
+        }
+        if let Some(reserved) = self.lock().get(pod.pod_uid()) {
+            return Ok(Some(reserved.clone()));
+        }
+        for (size, pages) in &requests {
+            let available = self.available(*size);
+            if available < *pages {
+                return Err(anyhow::anyhow!(
+                    "not enough huge pages available: requested {} of {}, but only {} are left",
+                    pages,
+                    size.resource_name(),
+                    available
+                ));
+            }
+        }
+        debug!("Reserving huge pages {:?} for pod {}", requests, pod.name());
+        let reserved = Arc::new(PodHugePages {
+            unused: requests
+                .iter()
+                .map(|(size, pages)| (*size, AtomicU64::new(*pages)))
+                .collect(),
+            pages: requests,
+        });
+        self.lock()
+            .insert(pod.pod_uid().to_owned(), reserved.clone());
+        Ok(Some(reserved))
+    }
+
+    /// Returns the huge pages reserved for the pod with the given UID to the
+    /// pool, such as once it is deleted
+    pub fn release(&self, pod_uid: &str) {
+        if self.lock().remove(pod_uid).is_some() {
+            debug!("Released the huge pages of pod {}", pod_uid);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<PodHugePages>>> {
+        self.reserved
+            .lock()
+            .expect("huge pages manager lock should not be poisoned")
+    }
+}
+
+/// The huge pages reserved for a pod, which the memory of its containers is
+/// backed with
+#[derive(Debug)]
+pub struct PodHugePages {
+    pages: BTreeMap<HugePageSize, u64>,
+    /// The pages not backing any memory yet
+    unused: BTreeMap<HugePageSize, AtomicU64>,
+}
+
+impl PodHugePages {
+    /// The number of pages of the size reserved for the pod
+    pub fn pages(&self, size: HugePageSize) -> u64 {
+        self.pages.get(&size).copied().unwrap_or(0)
+    }
+
+    /// The number of pages of the size that aren't backing any memory
+    pub fn unused(&self, size: HugePageSize) -> u64 {
+        self.unused
+            .get(&size)
+            .map(|unused| unused.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+
+    /// Backs the memory passed to [`map_huge_pages`] on the current thread
+    /// with these pages until the returned guard is dropped
+    pub fn enter(self: &Arc<Self>) -> HugePagesGuard {
+        CURRENT_PAGES.with(|current| current.replace(Some(self.clone())));
+        HugePagesGuard {}
+    }
+
+    /// Takes up to `pages` unused pages of the size, returning how many were
+    /// taken
+    fn take(&self, size: HugePageSize, pages: u64) -> u64 {
+        let unused = match self.unused.get(&size) {
+            Some(unused) => unused,
+            None => return 0,
+        };
+        match unused.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+            Some(left - left.min(pages))
+        }) {
+            Ok(left) => left.min(pages),
+            Err(_) => 0,
+        }
+    }
+
+    fn give_back(&self, size: HugePageSize, pages: u64) {
+        if let Some(unused) = self.unused.get(&size) {
+            unused.fetch_add(pages, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Stops backing memory with huge pages on the current thread when dropped
+pub struct HugePagesGuard {}
+
+impl Drop for HugePagesGuard {
+    fn drop(&mut self) {
+        CURRENT_PAGES.with(|current| current.replace(None));
+    }
+}
+
+/// Huge pages backing part of a memory mapping. The pages are given back to
+/// the pod when this is dropped, which must be when the mapping is unmapped.
+#[derive(Debug)]
+pub struct HugePageMapping {
+    pod: Arc<PodHugePages>,
+    size: HugePageSize,
+    pages: u64,
+}
+
+impl HugePageMapping {
+    /// The number of huge pages backing the mapping
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+}
+
+impl Drop for HugePageMapping {
+    fn drop(&mut self) {
+        self.pod.give_back(self.size, self.pages);
+    }
+}
+
+/// Backs as much of the accessible memory from `ptr` to `ptr + len` as whole
+/// huge pages cover with the unused pages of the pod entered on the current
+/// thread, if any, largest pages first. Whatever was in the memory is
+/// replaced with zeroes, so this must only be called for memory that hasn't
+/// been written yet. Failing to back the memory isn't fatal, as it can still
+/// be used with ordinary pages.
+pub fn map_huge_pages(ptr: *mut u8, len: usize) -> Vec<HugePageMapping> {
+    let pod = match CURRENT_PAGES.with(|current| current.borrow().clone()) {
+        Some(pod) => pod,
+        None => return vec![],
+    };
+    let mut mappings = vec![];
+    let (mut start, end) = (ptr as usize, ptr as usize + len);
+    for size in HugePageSize::ALL.iter().rev() {
+        let page = size.bytes() as usize;
+        let aligned_start = start.next_multiple_of(page);
+        let wanted = (end.saturating_sub(aligned_start) / page) as u64;
+        let pages = pod.take(*size, wanted);
+        if pages == 0 {
+            continue;
+        }
+        let mapping_len = pages as usize * page;
+        if let Err(e) = map_fixed(aligned_start as *mut u8, mapping_len, *size) {
+            warn!(
+                "Unable to back memory with {} huge pages of {}: {}",
+                pages,
+                size.resource_name(),
+                e
+            );
+            pod.give_back(*size, pages);
+            continue;
+        }
+        mappings.push(HugePageMapping {
+            pod: pod.clone(),
+            size: *size,
+            pages,
+        });
+        // Smaller pages are only used after the larger ones
+        start = aligned_start + mapping_len;
+    }
+    mappings
+}
+
+/// Replaces the memory at `ptr` with a readable and writable mapping of huge
+/// pages. The kernel reserves the pages before it replaces the existing
+/// mapping, so if there aren't enough, the existing mapping is left as it is.
+#[cfg(target_os = "linux")]
+fn map_fixed(ptr: *mut u8, len: usize, size: HugePageSize) -> std::io::Result<()> {
+    let mapped = unsafe {
+        libc::mmap(
+            ptr as *mut libc::c_void,
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | size.mmap_flags(),
+            -1,
+            0,
+        )
+    };
+    if mapped == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_fixed(_ptr: *mut u8, _len: usize, _size: HugePageSize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "huge pages are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec, ResourceRequirements};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    fn container(limits: &[(&str, &str)]) -> KubeContainer {
+        KubeContainer {
+            name: "container".to_owned(),
+            resources: Some(ResourceRequirements {
+                limits: Some(
+                    limits
+                        .iter()
+                        .map(|(r, q)| (r.to_string(), Quantity(q.to_string())))
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pod(uid: &str, init_containers: Vec<KubeContainer>, containers: Vec<KubeContainer>) -> Pod {
+        test_util::pod(
+            uid,
+            PodSpec {
+                init_containers: Some(init_containers),
+                containers,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn capacity_is_read_from_meminfo() {
+        let meminfo = "MemTotal:       16318412 kB\nHugePages_Total:       4\nHugePages_Free:        4\nHugepagesize:       2048 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            vec![(HugePageSize::Size2Mi, 4)].into_iter().collect()
+        );
+        assert!(parse_meminfo("MemTotal:       16318412 kB\n").is_empty());
+    }
+
+    #[test]
+    fn pods_request_whole_pages() {
+        let requests = pod_requests(&pod(
+            "pod",
+            vec![container(&[("hugepages-2Mi", "16Mi")])],
+            vec![
+                container(&[("hugepages-2Mi", "4Mi"), ("hugepages-1Gi", "1Gi")]),
+                container(&[("hugepages-2Mi", "6Mi"), ("memory", "1Gi")]),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            requests,
+            vec![(HugePageSize::Size2Mi, 8), (HugePageSize::Size1Gi, 1)]
+                .into_iter()
+                .collect()
+        );
+        assert!(pod_requests(&pod(
+            "pod",
+            vec![],
+            vec![container(&[("hugepages-2Mi", "3Mi")])]
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn pages_are_reserved_until_released() {
+        let manager =
+            HugePagesManager::with_capacity(vec![(HugePageSize::Size2Mi, 4)].into_iter().collect());
+        let first = pod(
+            "first",
+            vec![],
+            vec![container(&[("hugepages-2Mi", "6Mi")])],
+        );
+        let second = pod(
+            "second",
+            vec![],
+            vec![container(&[("hugepages-2Mi", "4Mi")])],
+        );
+
+        let reserved = manager.reserve(&first).unwrap().unwrap();
+        assert_eq!(reserved.pages(HugePageSize::Size2Mi), 3);
+        assert_eq!(manager.available(HugePageSize::Size2Mi), 1);
+        // Pods keep the pages they already have
+        assert!(Arc::ptr_eq(
+            &reserved,
+            &manager.reserve(&first).unwrap().unwrap()
+        ));
+        assert!(manager.reserve(&second).is_err());
+        assert!(manager
+            .reserve(&pod("none", vec![], vec![container(&[])]))
+            .unwrap()
+            .is_none());
+
+        manager.release("first");
+        assert_eq!(manager.available(HugePageSize::Size2Mi), 4);
+        assert!(manager.reserve(&second).is_ok());
+    }
+
+    #[test]
+    fn unused_pages_are_taken_and_given_back() {
+        let manager =
+            HugePagesManager::with_capacity(vec![(HugePageSize::Size2Mi, 4)].into_iter().collect());
+        let pod = pod("pod", vec![], vec![container(&[("hugepages-2Mi", "6Mi")])]);
+        let reserved = manager.reserve(&pod).unwrap().unwrap();
+        assert_eq!(reserved.take(HugePageSize::Size2Mi, 2), 2);
+        assert_eq!(reserved.take(HugePageSize::Size2Mi, 2), 1);
+        assert_eq!(reserved.take(HugePageSize::Size1Gi, 1), 0);
+        let mapping = HugePageMapping {
+            pod: reserved.clone(),
+            size: HugePageSize::Size2Mi,
+            pages: 3,
+        };
+        assert_eq!(reserved.unused(HugePageSize::Size2Mi), 0);
+        drop(mapping);
+        assert_eq!(reserved.unused(HugePageSize::Size2Mi), 3);
+    }
+
+    #[test]
+    fn memory_is_not_mapped_without_reserved_pages() {
+        let mut memory = vec![0u8; 4096];
+        assert!(map_huge_pages(memory.as_mut_ptr(), memory.len()).is_empty());
+    }
+}
@@ -11,7 +11,7 @@ pub(crate) fn exists() -> bool {
 }
 
 /// Returns kubeconfig path from specified environment variable.
-fn path() -> Option<PathBuf> {
+pub(crate) fn path() -> Option<PathBuf> {
     env::var_os(KUBECONFIG)
         .map(PathBuf::from)
         .or_else(default_path)
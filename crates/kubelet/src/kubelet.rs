@@ -1,22 +1,40 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
-use crate::config::Config;
+use crate::auth::{TokenReviewAuthenticator, WebhookAuthorizer};
+use crate::bootstrapping::{rotate_client_certificate, rotate_serving_certificate};
+use crate::config::{AuthorizationMode, Config};
+use crate::device_plugin_manager::DevicePluginManager;
+use crate::health::{self, DiskCheck, HealthChecks, HeartbeatCheck, ProviderCheck};
 use crate::node;
 use crate::operator::PodOperator;
 use crate::plugin_watcher::PluginRegistry;
+use crate::pod::PodStore;
 use crate::provider::Provider;
-use crate::webserver::start as start_webserver;
+use crate::state::record::TransitionRecorder;
+use crate::stats::StatsCollector;
+use crate::volume::remove_orphaned_volumes_periodically;
+use crate::webserver::{start as start_webserver, tls_config};
 
 use futures::future::{FutureExt, TryFutureExt};
 use kube::api::ListParams;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::signal::ctrl_c;
+use tokio::sync::{watch, RwLock};
 use tokio::task;
 use tracing::{error, info, warn};
 
 use krator::OperatorRuntime;
 
+/// The directory in the data directory that the states of pods are saved
+/// to, to resume them in when the kubelet restarts
+const POD_STATES_DIR: &str = "pod-states";
+
+/// The file in the data directory that the cluster's CA certificates are
+/// written to when clients of the server are authenticated with them
+const CLUSTER_CA_FILE: &str = "config/cluster-ca.crt";
+
 /// A Kubelet server backed by a given `Provider`.
 ///
 /// A Kubelet is a special kind of server that handles Kubernetes requests
@@ -37,12 +55,24 @@ pub struct Kubelet<P> {
 
 impl<P: Provider> Kubelet<P> {
     /// Create a new Kubelet with a provider, a kubernetes configuration,
-    /// and a kubelet configuration
+    /// and a kubelet configuration. Unless the configuration gives a client
+    /// CA, clients of the server may authenticate with certificates signed by
+    /// the CA in the kubernetes configuration, as the API server does.
     pub async fn new(
         provider: P,
         kube_config: kube::Config,
-        config: Config,
+        mut config: Config,
     ) -> anyhow::Result<Self> {
+        // Subsystems without access to the config check the feature gates
+        // installed here
+        config.feature_gates.install();
+        if config.server_config.client_ca_file.is_none() {
+            if let Some(certificates) = &kube_config.root_cert {
+                let path = config.data_dir.join(CLUSTER_CA_FILE);
+                write_cluster_ca(&path, certificates).await?;
+                config.server_config.client_ca_file = Some(path);
+            }
+        }
         Ok(Self {
             provider: Arc::new(provider),
             kube_config,
@@ -58,6 +88,16 @@ impl<P: Provider> Kubelet<P> {
     /// events, which it will handle.
     pub async fn start(&self) -> anyhow::Result<()> {
         let client = kube::Client::new(self.kube_config.clone());
+        // Components that talk to the API server for as long as the kubelet
+        // runs use the latest client sent here, which is replaced when the
+        // client certificate is rotated
+        let (client_updates, clients) = watch::channel(client.clone());
+        if self.config.rotate_certificates {
+            task::spawn(rotate_client_certificate(
+                (*self.config).clone(),
+                client_updates,
+            ));
+        }
 
         // Create the node. If it already exists, this will exit
         node::create(&client, &self.config, self.provider.clone()).await;
@@ -66,32 +106,136 @@ impl<P: Provider> Kubelet<P> {
         let signal = Arc::new(AtomicBool::new(false));
         let signal_task = start_signal_task(Arc::clone(&signal)).fuse().boxed();
 
-        let plugin_registrar = start_plugin_registry(self.provider.plugin_registry())
+        // Hold back shutdowns of the host until the node has been drained
+        let inhibitor = if self.config.shutdown_inhibitor {
+            Some(Arc::new(
+                node::ShutdownInhibitor::new(&client, &self.config).await?,
+            ))
+        } else {
+            None
+        };
+        let host_shutdown = start_host_shutdown_watcher(inhibitor.clone())
             .fuse()
             .boxed();
 
-        // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config)
+        let plugin_registrar = start_plugin_registry(self.provider.plugin_registry())
             .fuse()
             .boxed();
 
+        let device_plugin_manager =
+            start_device_plugin_manager(self.provider.device_plugin_manager())
+                .fuse()
+                .boxed();
+
+        // The health of the components is checked for /healthz and /readyz.
+        // The lease is renewed every quarter of its duration, so it has
+        // failed to be renewed a few times once it is as old as that.
+        let mut health_checks = HealthChecks::new();
+        let (lease_heartbeats, apiserver_connectivity) = HeartbeatCheck::new(
+            health::APISERVER_CONNECTIVITY,
+            self.config.node_lease_duration,
+        );
+        health_checks.register(apiserver_connectivity);
+        let (pod_worker_heartbeats, pod_worker) =
+            HeartbeatCheck::new(health::POD_WORKER, health::POD_WORKER_MAX_AGE);
+        health_checks.register(pod_worker);
+        health_checks.register(ProviderCheck::new(self.provider.clone()));
+        health_checks.register(DiskCheck::new(&self.config.data_dir));
+
+        // The pods being run are listed at /pods as their states change
+        let pods = PodStore::new();
+
+        // Start the webserver
+        let tls_config = Arc::new(RwLock::new(tls_config(&self.config.server_config)?));
+        let token_review = if self.config.server_config.authentication_token_webhook {
+            Some(TokenReviewAuthenticator::new(clients.clone()))
+        } else {
+            None
+        };
+        let authorizer = match self.config.server_config.authorization_mode {
+            AuthorizationMode::AlwaysAllow => None,
+            AuthorizationMode::Webhook => Some(WebhookAuthorizer::new(
+                clients.clone(),
+                self.config.node_name.clone(),
+            )),
+        };
+        let webserver = start_webserver(
+            self.provider.clone(),
+            &self.config.server_config,
+            tls_config.clone(),
+            health_checks,
+            pods.clone(),
+            token_review,
+            authorizer,
+            Arc::new(StatsCollector::new(&self.config)),
+        )
+        .fuse()
+        .boxed();
+        // The server keeps answering while the node shuts down, reporting
+        // it as not healthy, so it stops with the kubelet rather than with
+        // the other services. If it fails, the node is shut down.
+        let webserver_failed = tokio::sync::Notify::new();
+        let webserver = async {
+            let res = webserver.await;
+            error!("Webserver task completed with result {:?}", &res);
+            webserver_failed.notify_one();
+            futures::future::pending::<()>().await
+        };
+
+        // Renew the serving certificate in the background when it is close
+        // to expiring
+        let renewal_threshold = self
+            .config
+            .server_config
+            .renewal_threshold_days
+            .map(|days| chrono::Duration::days(days as i64));
+        if self.config.server_config.rotate_certificates || renewal_threshold.is_some() {
+            task::spawn(rotate_serving_certificate(
+                (*self.config).clone(),
+                clients.clone(),
+                tls_config,
+                renewal_threshold,
+            ));
+        }
+
+        // Remove the volumes that pods deleted while the kubelet wasn't
+        // running left behind, and any left behind later on
+        if let Some(volume_path) = self.provider.volume_path() {
+            task::spawn(remove_orphaned_volumes_periodically(
+                clients.clone(),
+                self.config.node_name.clone(),
+                volume_path,
+            ));
+        }
+
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
-            .fuse()
-            .boxed();
+        let node_updater = start_node_updater(
+            clients.clone(),
+            self.config.clone(),
+            self.provider.clone(),
+            Arc::new(lease_heartbeats),
+        )
+        .fuse()
+        .boxed();
 
-        // If any of these tasks fail, we can initiate graceful shutdown.
+        // If any of these tasks fail, we can initiate graceful shutdown. The
+        // node updater stops once shutdown starts, so that the lease is no
+        // longer renewed and the node isn't reported as ready again.
         let services = Box::pin(async {
             tokio::select! {
                 res = signal_task => if let Err(e) = res {
                     error!("Signal task completed with error {:?}", &e);
                 },
-                res = webserver => error!("Webserver task completed with result {:?}", &res),
+                _ = host_shutdown => (),
+                _ = webserver_failed.notified() => (),
                 res = node_updater => if let Err(e) = res {
                     error!("Node updater task completed with error {:?}", &e);
                 },
                 res = plugin_registrar => if let Err(e) = res {
                     error!("Plugin registrar task completed with error {:?}", &e);
+                },
+                res = device_plugin_manager => if let Err(e) = res {
+                    error!("Device plugin manager task completed with error {:?}", &e);
                 }
             };
             // Use relaxed ordering because we just need other tasks to eventually catch the signal.
@@ -102,19 +246,27 @@ impl<P: Provider> Kubelet<P> {
         // Periodically checks for shutdown signal and cleans up resources gracefully if caught.
         let signal_handler = start_signal_handler(
             Arc::clone(&signal),
-            client.clone(),
-            self.config.node_name.clone(),
+            clients.clone(),
+            self.config.clone(),
+            inhibitor,
         )
         .fuse()
         .boxed();
 
-        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone());
+        let operator = PodOperator::new(Arc::clone(&self.provider), clients.clone(), pods);
+        let operator = match &self.config.record_transitions {
+            Some(path) => operator.with_recorder(Arc::new(TransitionRecorder::create(path)?)),
+            None => operator,
+        };
         let node_selector = format!("spec.nodeName={}", &self.config.node_name);
         let params = ListParams {
             field_selector: Some(node_selector),
             ..Default::default()
         };
-        let mut operator_runtime = OperatorRuntime::new(&self.kube_config, operator, Some(params));
+        let mut operator_runtime = OperatorRuntime::new(&self.kube_config, operator, Some(params))
+            .with_snapshots(self.config.data_dir.join(POD_STATES_DIR))
+            .with_client_updates(clients)
+            .with_heartbeats(pod_worker_heartbeats);
         let operator_task = operator_runtime.start().fuse().boxed();
 
         // These must all be running for graceful shutdown. An error here exits ungracefully.
@@ -128,6 +280,7 @@ impl<P: Provider> Kubelet<P> {
                     warn!("Pod operator has completed");
                     Ok(())
                 }
+                _ = webserver => Ok(()),
             }
         });
 
@@ -151,14 +304,57 @@ impl<P> Clone for Kubelet<P> {
     }
 }
 
-/// Awaits SIGINT and sets graceful shutdown flag if detected.
+/// Awaits SIGINT, or SIGTERM on Unix or Ctrl+Break on Windows, and sets
+/// graceful shutdown flag if detected.
 async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
-    ctrl_c().await?;
-    warn!("Caught keyboard interrupt.");
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal as unix_signal, SignalKind};
+        let mut terminate = unix_signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = ctrl_c() => {
+                res?;
+                warn!("Caught keyboard interrupt.");
+            }
+            _ = terminate.recv() => warn!("Caught SIGTERM."),
+        }
+    }
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::ctrl_break;
+        let mut ctrl_break = ctrl_break()?;
+        tokio::select! {
+            res = ctrl_c() => {
+                res?;
+                warn!("Caught keyboard interrupt.");
+            }
+            _ = ctrl_break.recv() => warn!("Caught Ctrl+Break."),
+        }
+    }
+    #[cfg(not(any(target_family = "unix", windows)))]
+    {
+        ctrl_c().await?;
+        warn!("Caught keyboard interrupt.");
+    }
     signal.store(true, Ordering::Relaxed);
     Ok(())
 }
 
+/// Awaits the host starting to shut down, if shutdowns are inhibited, so
+/// that the node is drained as on SIGTERM. If the host's shutdowns can no
+/// longer be watched, they are no longer held back.
+async fn start_host_shutdown_watcher(inhibitor: Option<Arc<node::ShutdownInhibitor>>) {
+    let inhibitor = match inhibitor {
+        Some(inhibitor) => inhibitor,
+        None => return futures::future::pending().await,
+    };
+    if let Err(e) = inhibitor.wait_for_shutdown().await {
+        error!("Unable to watch for shutdowns of the host: {:?}", e);
+        inhibitor.stop();
+        futures::future::pending().await
+    }
+}
+
 async fn start_plugin_registry(registrar: Option<Arc<PluginRegistry>>) -> anyhow::Result<()> {
     match registrar {
         Some(r) => r.run().await,
@@ -176,26 +372,129 @@ async fn start_plugin_registry(registrar: Option<Arc<PluginRegistry>>) -> anyhow
     }
 }
 
-/// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
-    let sleep_interval = std::time::Duration::from_secs(10);
+async fn start_device_plugin_manager(
+    manager: Option<Arc<DevicePluginManager>>,
+) -> anyhow::Result<()> {
+    match manager {
+        Some(m) => m.run().await,
+        // Do nothing; just poll forever, as for the plugin registry
+        None => futures::future::pending().await,
+    }
+}
+
+/// Periodically renew node lease and status, each at its own interval, and
+/// register the node again if it is deleted. Exits if signal is caught. The
+/// updates are restarted with the new client when the client is replaced.
+/// Each renewal of the lease sends a heartbeat on `lease_heartbeats`.
+async fn start_node_updater<P: Provider>(
+    mut clients: watch::Receiver<kube::Client>,
+    config: Box<Config>,
+    provider: Arc<P>,
+    lease_heartbeats: Arc<watch::Sender<Instant>>,
+) -> anyhow::Result<()> {
     loop {
-        node::update(&client, &node_name).await;
-        tokio::time::sleep(sleep_interval).await;
+        let client = clients.borrow().clone();
+        let updater = update_node(
+            client,
+            config.clone(),
+            provider.clone(),
+            lease_heartbeats.clone(),
+        );
+        tokio::select! {
+            res = updater => return res,
+            replaced = clients.changed() => if replaced.is_err() {
+                // The client is never replaced again
+                let client = clients.borrow().clone();
+                return update_node(client, config, provider, lease_heartbeats).await;
+            },
+        }
+        info!("Kubernetes client was replaced. Restarting node updates...");
+    }
+}
+
+async fn update_node<P: Provider>(
+    client: kube::Client,
+    config: Box<Config>,
+    provider: Arc<P>,
+    lease_heartbeats: Arc<watch::Sender<Instant>>,
+) -> anyhow::Result<()> {
+    let node_missing = Arc::new(tokio::sync::Notify::new());
+    let images = node::ImageLister::new(&config, provider.store());
+    let status_provider = provider.clone();
+    let reregistration = async {
+        if config.reregister_node {
+            node::reregister_when_missing(
+                client.clone(),
+                config.clone(),
+                provider,
+                node_missing.clone(),
+            )
+            .await
+        }
+    };
+    tokio::join!(
+        node::renew_lease_periodically(
+            client.clone(),
+            config.node_name.clone(),
+            config.node_lease_duration,
+            node_missing.clone(),
+            lease_heartbeats,
+        ),
+        node::update_status_periodically(
+            client.clone(),
+            config.node_name.clone(),
+            config.node_status_update_frequency,
+            status_provider,
+            node::ResourceDetector::new(&config),
+            node::ConditionManager::new(&config),
+            images,
+            node_missing.clone(),
+        ),
+        reregistration,
+    );
+    Ok(())
+}
+
+/// Writes the cluster's DER encoded CA certificates to `path` as PEM
+async fn write_cluster_ca(
+    path: &std::path::Path,
+    certificates: &[kube::config::Der],
+) -> anyhow::Result<()> {
+    let pem: String = certificates
+        .iter()
+        .map(|certificate| {
+            pem::encode(&pem::Pem {
+                tag: "CERTIFICATE".to_owned(),
+                contents: certificate.0.clone(),
+            })
+        })
+        .collect();
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
     }
+    tokio::fs::write(path, pem)
+        .await
+        .map_err(|e| anyhow::anyhow!("unable to write cluster CA to {}: {}", path.display(), e))
 }
 
-/// Checks for shutdown signal and cleans up resources gracefully.
+/// Checks for shutdown signal and shuts the node down gracefully. Shutdowns
+/// of the host are let go on once the node has been drained.
 async fn start_signal_handler(
     signal: Arc<AtomicBool>,
-    client: kube::Client,
-    node_name: String,
+    clients: watch::Receiver<kube::Client>,
+    config: Box<Config>,
+    inhibitor: Option<Arc<node::ShutdownInhibitor>>,
 ) -> anyhow::Result<()> {
     let duration = std::time::Duration::from_millis(100);
     loop {
         if signal.load(Ordering::Relaxed) {
             info!("Signal caught.");
-            node::drain(&client, &node_name).await?;
+            let client = clients.borrow().clone();
+            let res = node::shutdown(&client, &config).await;
+            if let Some(inhibitor) = inhibitor {
+                inhibitor.release().await;
+            }
+            res?;
             break Ok(());
         }
         tokio::time::sleep(duration).await;
@@ -395,4 +694,21 @@ mod test {
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
     }
+
+    #[tokio::test]
+    async fn the_cluster_ca_is_written_as_pem() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CLUSTER_CA_FILE);
+        let certificates = vec![
+            kube::config::Der(vec![1, 2, 3]),
+            kube::config::Der(vec![4, 5]),
+        ];
+        write_cluster_ca(&path, &certificates).await.unwrap();
+
+        let written = pem::parse_many(std::fs::read(&path).unwrap());
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0].tag, "CERTIFICATE");
+        assert_eq!(written[0].contents, vec![1, 2, 3]);
+        assert_eq!(written[1].contents, vec![4, 5]);
+    }
 }
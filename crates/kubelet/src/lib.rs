@@ -75,6 +75,7 @@
 #![deny(missing_docs)]
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
+mod auth;
 mod bootstrapping;
 mod config_interpreter;
 mod kubelet;
@@ -89,24 +90,44 @@ pub(crate) mod plugin_registration_api {
         tonic::include_proto!("pluginregistration");
     }
 }
+pub(crate) mod device_plugin_api {
+    pub(crate) mod v1beta1 {
+        pub const API_VERSION: &str = "v1beta1";
+
+        tonic::include_proto!("v1beta1");
+    }
+}
 pub(crate) mod fs_watch;
 pub(crate) mod grpc_sock;
 #[cfg(target_family = "windows")]
 #[allow(dead_code, clippy::all)]
 pub(crate) mod mio_uds_windows;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 pub mod backoff;
 pub mod config;
 pub mod container;
+pub mod cpu_manager;
+pub mod device_plugin_manager;
+pub mod ephemeral_storage;
+pub mod extended_resources;
+pub mod feature_gate;
 pub mod handle;
+pub mod health;
+pub mod hugepages;
 pub mod log;
+pub mod memory_manager;
 pub mod node;
 pub mod plugin_watcher;
 pub mod pod;
 pub mod provider;
+pub mod resources;
 pub mod secret;
 pub mod state;
+pub mod stats;
 pub mod store;
+pub mod topology_manager;
 pub mod volume;
 
 pub use self::kubelet::Kubelet;
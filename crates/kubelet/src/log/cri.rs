@@ -0,0 +1,402 @@
+//! Container output in the [CRI log format](https://github.com/kubernetes/community/blob/master/contributors/design-proposals/node/kubelet-cri-logging.md).
+//!
+//! Each entry is a line of the form `<timestamp> <stream> <tag> <content>`,
+//! for example `2021-01-01T00:00:00.000000000Z stderr F oh no`. The tag is `F`
+//! for the end of a line of output and `P` for part of a line that continues
+//! in the next entry from the same stream.
+use std::io::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+
+/// The longest content written in a single entry. Longer lines are split into
+/// partial entries.
+const MAX_ENTRY_BYTES: usize = 16 * 1024;
+
+/// A stream of container output
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum Stream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stdout" => Some(Stream::Stdout),
+            "stderr" => Some(Stream::Stderr),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the output of one stream of a container as CRI log entries.
+///
+/// Complete lines are written as they arrive. The rest of a line is kept
+/// until the line ends, and is written as a partial entry if the writer is
+/// flushed or dropped first.
+pub struct Writer<W: Write> {
+    inner: W,
+    stream: Stream,
+    line: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Create a `Writer` tagging entries written to `inner` with `stream`.
+    pub fn new(inner: W, stream: Stream) -> Self {
+        Writer {
+            inner,
+            stream,
+            line: Vec::new(),
+        }
+    }
+
+    fn write_entry(&mut self, content: &[u8], partial: bool) -> std::io::Result<()> {
+        let mut entry = format!(
+            "{} {} {} ",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.stream.as_str(),
+            if partial { "P" } else { "F" }
+        )
+        .into_bytes();
+        entry.extend_from_slice(content);
+        entry.push(b'\n');
+        // Entries are written whole so that they don't interleave with
+        // entries from the other stream
+        self.inner.write_all(&entry)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.write_entry(&line[..end], false)?;
+        }
+        while self.line.len() >= MAX_ENTRY_BYTES {
+            let part: Vec<u8> = self.line.drain(..MAX_ENTRY_BYTES).collect();
+            self.write_entry(&part, true)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let mut written = 0;
+        for buf in bufs {
+            written += self.write(buf)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            let part = std::mem::take(&mut self.line);
+            self.write_entry(&part, true)?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// A line of container output
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    /// When the line was written, in RFC 3339 format, or `None` for logs
+    /// written before output was timestamped
+    pub timestamp: Option<String>,
+    /// The stream the line was written to, or `None` for logs written before
+    /// output was split by stream
+    pub stream: Option<Stream>,
+    /// The line, without a trailing newline
+    pub content: String,
+}
+
+impl Line {
+    /// Formats the line for sending to a client, prefixed with its timestamp
+    /// if `timestamps` is true and it has one.
+    pub fn format(&self, timestamps: bool) -> String {
+        match &self.timestamp {
+            Some(timestamp) if timestamps => format!("{} {}\n", timestamp, self.content),
+            _ => format!("{}\n", self.content),
+        }
+    }
+}
+
+/// A parsed log entry
+struct Entry<'a> {
+    timestamp: &'a str,
+    time: DateTime<Utc>,
+    stream: Stream,
+    partial: bool,
+    content: &'a str,
+}
+
+impl<'a> Entry<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut fields = line.splitn(4, ' ');
+        let timestamp = fields.next()?;
+        let time = DateTime::parse_from_rfc3339(timestamp)
+            .ok()?
+            .with_timezone(&Utc);
+        let stream = Stream::parse(fields.next()?)?;
+        let partial = match fields.next()? {
+            "P" => true,
+            "F" => false,
+            _ => return None,
+        };
+        Some(Entry {
+            timestamp,
+            time,
+            stream,
+            partial,
+            content: fields.next().unwrap_or_default(),
+        })
+    }
+}
+
+/// Returns the stream of the log entry `entry` and whether it is partial, or
+/// `None` if it isn't a CRI log entry
+pub(super) fn entry_tag(entry: &[u8]) -> Option<(Stream, bool)> {
+    let entry = Entry::parse(std::str::from_utf8(entry).ok()?)?;
+    Some((entry.stream, entry.partial))
+}
+
+/// Reads lines of container output from CRI log entries, joining partial
+/// entries back into whole lines. Lines that aren't CRI log entries are
+/// read as they are, so that logs written before output was timestamped can
+/// still be read.
+pub struct Reader<R: AsyncRead + Unpin> {
+    lines: tokio::io::Lines<tokio::io::BufReader<R>>,
+    stream: Option<Stream>,
+    since: Option<DateTime<Utc>>,
+    flush_partial: bool,
+    /// The partial lines of each stream, with the timestamp of their first
+    /// entry
+    partial: Vec<(Stream, Timestamp, String)>,
+}
+
+/// The timestamp of a line, as written and parsed
+type Timestamp = (String, DateTime<Utc>);
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// Create a `Reader` returning the lines written to `stream`, or to
+    /// either stream if `None`. If `flush_partial` is true, lines that are
+    /// still partial at the end of the log are returned once it has been
+    /// read; otherwise they are kept until they are completed, for logs that
+    /// are still being written to.
+    pub fn new(handle: R, stream: Option<Stream>, flush_partial: bool) -> Self {
+        Reader {
+            lines: tokio::io::BufReader::new(handle).lines(),
+            stream,
+            since: None,
+            flush_partial,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Only return lines written at or after `since`. Lines in logs written
+    /// before output was timestamped are skipped.
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    fn is_wanted(&self, time: &DateTime<Utc>) -> bool {
+        self.since.map(|since| *time >= since).unwrap_or(true)
+    }
+
+    /// Reads the next line, or `None` if the end of the log has been reached.
+    /// For logs that are still being written to, later calls may return more
+    /// lines.
+    pub async fn next_line(&mut self) -> std::io::Result<Option<Line>> {
+        while let Some(line) = self.lines.next_line().await? {
+            let entry = match Entry::parse(&line) {
+                Some(entry) => entry,
+                None if self.stream.is_none() && self.since.is_none() => {
+                    return Ok(Some(Line {
+                        timestamp: None,
+                        stream: None,
+                        content: line,
+                    }))
+                }
+                None => continue,
+            };
+            if self.stream.map(|s| s != entry.stream).unwrap_or(false) {
+                continue;
+            }
+            let existing = self.partial.iter().position(|(s, _, _)| *s == entry.stream);
+            let (timestamp, mut content) = match existing {
+                Some(index) => {
+                    let (_, timestamp, content) = self.partial.remove(index);
+                    (timestamp, content)
+                }
+                None => ((entry.timestamp.to_owned(), entry.time), String::new()),
+            };
+            content.push_str(entry.content);
+            if entry.partial {
+                self.partial.push((entry.stream, timestamp, content));
+                continue;
+            }
+            if !self.is_wanted(&timestamp.1) {
+                continue;
+            }
+            return Ok(Some(Line {
+                timestamp: Some(timestamp.0),
+                stream: Some(entry.stream),
+                content,
+            }));
+        }
+        while self.flush_partial && !self.partial.is_empty() {
+            let (stream, timestamp, content) = self.partial.remove(0);
+            if !self.is_wanted(&timestamp.1) {
+                continue;
+            }
+            return Ok(Some(Line {
+                timestamp: Some(timestamp.0),
+                stream: Some(stream),
+                content,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(stream: Stream, output: &[&[u8]]) -> Vec<u8> {
+        let mut log = Vec::new();
+        {
+            let mut writer = Writer::new(&mut log, stream);
+            for output in output {
+                writer.write_all(output).unwrap();
+            }
+        }
+        log
+    }
+
+    async fn read(log: &[u8], stream: Option<Stream>) -> Vec<Line> {
+        let mut reader = Reader::new(log, stream, true);
+        let mut lines = vec![];
+        while let Some(line) = reader.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn output_is_written_as_tagged_entries() {
+        let log = write(Stream::Stderr, &[b"one\ntw", b"o\nthr"]);
+        let log = String::from_utf8(log).unwrap();
+        let entries: Vec<_> = log.lines().map(|l| Entry::parse(l).unwrap()).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|e| e.stream == Stream::Stderr));
+        let contents: Vec<_> = entries.iter().map(|e| (e.content, e.partial)).collect();
+        assert_eq!(
+            contents,
+            vec![("one", false), ("two", false), ("thr", true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_entries_are_joined() {
+        let mut log = Vec::new();
+        {
+            let mut stdout = Writer::new(&mut log, Stream::Stdout);
+            stdout.write_all(b"hello").unwrap();
+            stdout.flush().unwrap();
+            stdout.write_all(b" world\n").unwrap();
+        }
+        let mut long_line = vec![b'x'; MAX_ENTRY_BYTES * 2 + 1];
+        long_line.push(b'\n');
+        log.extend(write(Stream::Stderr, &[&long_line]));
+
+        let lines = read(&log, None).await;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].content, "hello world");
+        assert_eq!(lines[0].stream, Some(Stream::Stdout));
+        assert_eq!(lines[1].content.len(), MAX_ENTRY_BYTES * 2 + 1);
+        assert_eq!(lines[1].stream, Some(Stream::Stderr));
+    }
+
+    #[tokio::test]
+    async fn lines_are_filtered_by_stream() {
+        let log = b"2021-01-01T00:00:00.000000001Z stdout F out\n\
+                    2021-01-01T00:00:00.000000002Z stderr P e\n\
+                    2021-01-01T00:00:00.000000003Z stdout F put\n\
+                    2021-01-01T00:00:00.000000004Z stderr F rr\n";
+        let lines = read(log, Some(Stream::Stderr)).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].content, "err");
+        assert_eq!(
+            lines[0].format(true),
+            "2021-01-01T00:00:00.000000002Z err\n"
+        );
+        assert_eq!(lines[0].format(false), "err\n");
+        assert_eq!(read(log, Some(Stream::Stdout)).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn lines_are_filtered_by_time() {
+        let log = b"untimestamped\n\
+                    2021-01-01T00:00:01Z stdout F early\n\
+                    2021-01-01T00:00:02Z stdout P on \n\
+                    2021-01-01T00:00:03Z stdout F time\n\
+                    2021-01-01T00:00:04Z stderr F late\n";
+        let since = DateTime::parse_from_rfc3339("2021-01-01T00:00:02Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut reader = Reader::new(&log[..], None, true).since(since);
+        let mut lines = vec![];
+        while let Some(line) = reader.next_line().await.unwrap() {
+            lines.push(line.content);
+        }
+        assert_eq!(lines, vec!["on time", "late"]);
+    }
+
+    #[tokio::test]
+    async fn untimestamped_logs_are_read() {
+        let log = b"plain output\n2021-01-01T00:00:00Z stdout F new output\n";
+        let lines = read(log, None).await;
+        assert_eq!(
+            lines,
+            vec![
+                Line {
+                    timestamp: None,
+                    stream: None,
+                    content: "plain output".to_owned(),
+                },
+                Line {
+                    timestamp: Some("2021-01-01T00:00:00Z".to_owned()),
+                    stream: Some(Stream::Stdout),
+                    content: "new output".to_owned(),
+                }
+            ]
+        );
+        assert_eq!(lines[0].format(true), "plain output\n");
+    }
+
+    #[tokio::test]
+    async fn unfinished_lines_are_kept_for_logs_being_written() {
+        let log = b"2021-01-01T00:00:00Z stdout P still writing\n";
+        let mut reader = Reader::new(&log[..], None, false);
+        assert!(reader.next_line().await.unwrap().is_none());
+    }
+}
@@ -1,14 +1,27 @@
 //! `log` contains convenient wrappers around fetching logs from the Kubernetes API.
+//!
+//! Logs are stored in the CRI log format, which [`Writer`] writes and the log
+//! endpoint reads, so that clients can request timestamps or a single stream.
+//! Requests are described by [`LogOptions`], from the query parameters that
+//! `kubectl logs` sends.
 use anyhow::bail;
-use serde::Deserialize;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
+use serde::{Deserialize, Deserializer};
+use tokio::io::AsyncRead;
 use tracing::{debug, error};
 
+mod cri;
+mod rotation;
+
+pub use cri::{Line, Reader, Stream, Writer};
+pub use rotation::{RotatedFiles, RotatingFile};
+
 /// Possible errors sending log data.
 #[derive(Debug)]
 pub enum SendError {
     /// Client has disconnected.
     ChannelClosed,
+    /// The number of bytes the client asked for has been sent.
+    LimitReached,
     /// An unexpected error occured.
     Abnormal(anyhow::Error),
 }
@@ -23,6 +36,7 @@ impl std::fmt::Display for SendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SendError::ChannelClosed => write!(f, "ChannelClosed"),
+            SendError::LimitReached => write!(f, "LimitReached"),
             SendError::Abnormal(e) => write!(f, "{}", e),
         }
     }
@@ -31,38 +45,87 @@ impl std::fmt::Display for SendError {
 impl std::error::Error for SendError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            SendError::ChannelClosed => None,
+            SendError::ChannelClosed | SendError::LimitReached => None,
             SendError::Abnormal(e) => Some(e.root_cause()),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 /// Client options for fetching logs.
-pub struct Options {
+pub struct LogOptions {
     /// the number of lines to stream back to the client.
-    #[serde(rename = "tailLines")]
-    pub tail: Option<usize>,
+    #[serde(default, rename = "tailLines")]
+    pub tail_lines: Option<usize>,
     /// determines whether the stream should stay open after tailing until the channel has closed.
     #[serde(default)]
     pub follow: bool,
+    /// determines whether each line should be prefixed with the time it was written.
+    #[serde(default)]
+    pub timestamps: bool,
+    /// only return lines written in this many seconds before the request.
+    #[serde(default, rename = "sinceSeconds")]
+    pub since_seconds: Option<u64>,
+    /// only return lines written at or after this time.
+    #[serde(default, rename = "sinceTime")]
+    pub since_time: Option<chrono::DateTime<chrono::Utc>>,
+    /// the most bytes to send, after which the log is cut off.
+    #[serde(default, rename = "limitBytes")]
+    pub limit_bytes: Option<usize>,
+    /// determines whether to return the logs of the previous instance of the container.
+    #[serde(default)]
+    pub previous: bool,
+    /// the stream to return lines from, or `None` for both.
+    #[serde(default, deserialize_with = "deserialize_stream")]
+    pub stream: Option<Stream>,
+}
+
+/// Deserializes the stream to return lines from, as `Stdout`, `Stderr` or
+/// `All`.
+fn deserialize_stream<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Stream>, D::Error> {
+    #[derive(Deserialize)]
+    enum Streams {
+        All,
+        Stdout,
+        Stderr,
+    }
+    Ok(match Streams::deserialize(deserializer)? {
+        Streams::All => None,
+        Streams::Stdout => Some(Stream::Stdout),
+        Streams::Stderr => Some(Stream::Stderr),
+    })
 }
 
 /// Sender for streaming logs to client.
 pub struct Sender {
     sender: hyper::body::Sender,
-    opts: Options,
+    opts: LogOptions,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    remaining_bytes: Option<usize>,
 }
 
 impl Sender {
     /// Create new `Sender` from `hyper::body::Sender`.
-    pub fn new(sender: hyper::body::Sender, opts: Options) -> Self {
-        Sender { sender, opts }
+    pub fn new(sender: hyper::body::Sender, opts: LogOptions) -> Self {
+        // Relative times are from when the request was made, not when lines
+        // are read
+        let since = opts.since_time.or_else(|| {
+            opts.since_seconds
+                .map(|seconds| chrono::Utc::now() - chrono::Duration::seconds(seconds as i64))
+        });
+        Sender {
+            sender,
+            since,
+            remaining_bytes: opts.limit_bytes,
+            opts,
+        }
     }
 
     /// The tail flag indicated by the request if present.
     pub fn tail(&self) -> Option<usize> {
-        self.opts.tail
+        self.opts.tail_lines
     }
 
     /// The follow flag indicated by the request, or `false` if absent.
@@ -70,8 +133,42 @@ impl Sender {
         self.opts.follow
     }
 
-    /// Async send some data to a client.
+    /// The timestamps flag indicated by the request, or `false` if absent.
+    pub fn timestamps(&self) -> bool {
+        self.opts.timestamps
+    }
+
+    /// The time lines must have been written at or after, if requested
+    /// either as a time or as a number of seconds before the request.
+    pub fn since_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.since
+    }
+
+    /// The limit bytes indicated by the request if present.
+    pub fn limit_bytes(&self) -> Option<usize> {
+        self.opts.limit_bytes
+    }
+
+    /// The previous flag indicated by the request, or `false` if absent.
+    pub fn previous(&self) -> bool {
+        self.opts.previous
+    }
+
+    /// The stream requested, or `None` if lines from both streams should be
+    /// sent.
+    pub fn stream(&self) -> Option<Stream> {
+        self.opts.stream
+    }
+
+    /// Async send some data to a client. If the request limited the bytes
+    /// to send, data past the limit is cut off, and
+    /// [`SendError::LimitReached`] is returned once it has been reached.
     pub async fn send(&mut self, data: String) -> Result<(), SendError> {
+        let mut data = data.into_bytes();
+        if let Some(remaining) = self.remaining_bytes {
+            data.truncate(remaining);
+            self.remaining_bytes = Some(remaining - data.len());
+        }
         let b: hyper::body::Bytes = data.into();
         self.sender.send_data(b).await.map_err(|e| {
             if e.is_closed() {
@@ -81,13 +178,17 @@ impl Sender {
                 error!("channel error: {}", e);
                 SendError::Abnormal(anyhow::Error::new(e))
             }
-        })
+        })?;
+        match self.remaining_bytes {
+            Some(0) => Err(SendError::LimitReached),
+            _ => Ok(()),
+        }
     }
 }
 
 /// Stream last `n` lines.
 async fn tail<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    lines: &mut Reader<R>,
     sender: &mut Sender,
     n: usize,
 ) -> Result<(), SendError> {
@@ -102,25 +203,28 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
+        if n == 0 {
+            continue;
+        }
         if line_buf.len() == n {
             line_buf.pop_front();
         }
         line_buf.push_back(line);
     }
 
-    for mut line in line_buf {
-        line.push('\n');
-        sender.send(line).await?;
+    let timestamps = sender.timestamps();
+    for line in line_buf {
+        sender.send(line.format(timestamps)).await?;
     }
     Ok(())
 }
 
 /// Stream log to end.
 async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    lines: &mut Reader<R>,
     sender: &mut Sender,
 ) -> Result<(), SendError> {
-    while let Some(mut line) = match lines.next_line().await {
+    while let Some(line) = match lines.next_line().await {
         Ok(line) => line,
         Err(e) => {
             let err = format!("Error reading from log: {:?}", e);
@@ -129,8 +233,7 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
-        line.push('\n');
-        sender.send(line).await?;
+        sender.send(line.format(sender.timestamps())).await?;
     }
     Ok(())
 }
@@ -140,19 +243,23 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     handle: R,
     mut sender: Sender,
 ) -> anyhow::Result<()> {
-    let buf = tokio::io::BufReader::new(handle);
-    let mut lines = buf.lines();
+    // When following, partial lines may still be completed, so they are only
+    // sent once they are
+    let mut lines = Reader::new(handle, sender.stream(), !sender.follow());
+    if let Some(since) = sender.since_time() {
+        lines = lines.since(since);
+    }
 
     if let Some(n) = sender.tail() {
         match tail(&mut lines, &mut sender, n).await {
             Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
+            Err(SendError::ChannelClosed) | Err(SendError::LimitReached) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
         }
     } else {
         match stream_to_end(&mut lines, &mut sender).await {
             Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
+            Err(SendError::ChannelClosed) | Err(SendError::LimitReached) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
         }
     }
@@ -161,7 +268,7 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
         loop {
             match stream_to_end(&mut lines, &mut sender).await {
                 Ok(_) => (),
-                Err(SendError::ChannelClosed) => return Ok(()),
+                Err(SendError::ChannelClosed) | Err(SendError::LimitReached) => return Ok(()),
                 Err(SendError::Abnormal(e)) => bail!(e),
             }
 
@@ -179,4 +286,153 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// Create new log reader for reading the last `lines` lines written to
+    /// `stream`, or to either stream if `None`. Readers may start anywhere
+    /// before those lines, so by default this reads the whole log.
+    fn new_tail_handle(&self, _lines: usize, _stream: Option<Stream>) -> R {
+        self.new_handle()
+    }
+
+    /// Create a factory for readers of the log of the container's previous
+    /// instance, or `None` if there wasn't one or its log wasn't kept, as it
+    /// isn't by default.
+    fn previous(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{SecondsFormat, Utc};
+    use hyper::body::HttpBody;
+    use std::io::Write;
+
+    /// A log of output written in the last ten minutes, with a line split
+    /// across two entries
+    fn fixture() -> String {
+        let entries = [
+            (10, "stdout F one"),
+            (8, "stderr F two"),
+            (6, "stdout P thr"),
+            (6, "stdout F ee"),
+            (4, "stdout F four"),
+            (2, "stderr F five"),
+        ];
+        entries
+            .iter()
+            .map(|(minutes, entry)| format!("{} {}\n", minutes_ago(*minutes), entry))
+            .collect()
+    }
+
+    fn minutes_ago(minutes: i64) -> String {
+        (Utc::now() - chrono::Duration::minutes(minutes))
+            .to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    async fn options(query: &str) -> LogOptions {
+        warp::test::request()
+            .path(&format!("/containerLogs?{}", query))
+            .filter(&warp::query::<LogOptions>())
+            .await
+            .unwrap()
+    }
+
+    /// Returns what is sent for a request for `log` with the query parameters
+    /// in `query`
+    async fn logs(log: &str, query: &str) -> String {
+        let (sender, body) = hyper::Body::channel();
+        let opts = options(query).await;
+        let (streamed, body) = tokio::join!(
+            stream(log.as_bytes(), Sender::new(sender, opts)),
+            hyper::body::to_bytes(body)
+        );
+        streamed.unwrap();
+        let body = body.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn whole_logs_are_sent_by_default() {
+        assert_eq!(logs(&fixture(), "").await, "one\ntwo\nthree\nfour\nfive\n");
+    }
+
+    #[tokio::test]
+    async fn tail_lines_are_sent() {
+        let log = fixture();
+        assert_eq!(logs(&log, "tailLines=2").await, "four\nfive\n");
+        assert_eq!(logs(&log, "tailLines=0").await, "");
+        assert_eq!(
+            logs(&log, "tailLines=100").await,
+            "one\ntwo\nthree\nfour\nfive\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_since_seconds_are_sent() {
+        assert_eq!(logs(&fixture(), "sinceSeconds=300").await, "four\nfive\n");
+    }
+
+    #[tokio::test]
+    async fn lines_since_time_are_sent() {
+        let log = fixture();
+        let since = minutes_ago(7).replace(':', "%3A");
+        assert_eq!(
+            logs(&log, &format!("sinceTime={}", since)).await,
+            "three\nfour\nfive\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_are_cut_off_at_limit_bytes() {
+        let log = fixture();
+        assert_eq!(logs(&log, "limitBytes=6").await, "one\ntw");
+        assert_eq!(logs(&log, "limitBytes=6&tailLines=2").await, "four\nf");
+    }
+
+    #[tokio::test]
+    async fn timestamps_are_sent_if_requested() {
+        let log = fixture();
+        let sent = logs(&log, "timestamps=true&tailLines=1").await;
+        let timestamp = log.lines().last().unwrap().split(' ').next().unwrap();
+        assert_eq!(sent, format!("{} five\n", timestamp));
+    }
+
+    #[tokio::test]
+    async fn lines_of_one_stream_are_sent_if_requested() {
+        let log = fixture();
+        assert_eq!(logs(&log, "stream=Stderr").await, "two\nfive\n");
+        assert_eq!(
+            logs(&log, "stream=Stdout&tailLines=2").await,
+            "three\nfour\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn previous_is_read() {
+        assert!(options("previous=true").await.previous);
+        assert!(!options("").await.previous);
+    }
+
+    #[tokio::test]
+    async fn followed_tails_continue_streaming() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = RotatingFile::create(path.clone(), 1024 * 1024, 2).unwrap();
+        file.write_all(fixture().as_bytes()).unwrap();
+
+        let (sender, mut body) = hyper::Body::channel();
+        let opts = options("tailLines=1&follow=true").await;
+        let handle = RotatedFiles::open_tail(&path, 1, None).unwrap();
+        tokio::spawn(stream(handle, Sender::new(sender, opts)));
+        assert_eq!(body.data().await.unwrap().unwrap(), "five\n");
+
+        file.write_all(format!("{} stdout F six\n", minutes_ago(0)).as_bytes())
+            .unwrap();
+        assert_eq!(body.data().await.unwrap().unwrap(), "six\n");
+    }
 }
@@ -0,0 +1,546 @@
+//! Container log files that are rotated once they reach a maximum size.
+//!
+//! Logs are written to a single file, which is renamed to `<file>.1` once it
+//! is full, with older files moving up to `<file>.2` and so on and the oldest
+//! being deleted. [`RotatedFiles`] reads the files back in the order they were
+//! written, and follows the log across rotations when it is still being
+//! written to.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::warn;
+
+use super::cri::{entry_tag, Stream};
+
+/// How much of a log file is read at a time when looking for where its last
+/// lines start
+const TAIL_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// A log file that is rotated when it would grow past a maximum size. Clones
+/// write to the same file, so that output from each stream of a container can
+/// be written to it.
+#[derive(Clone)]
+pub struct RotatingFile {
+    active: Arc<Mutex<ActiveFile>>,
+}
+
+struct ActiveFile {
+    path: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    /// Create an empty log file at `path`, which is rotated when it would
+    /// grow past `max_size` bytes, keeping at most `max_files` files
+    /// including the one being written. Any files already rotated from
+    /// `path` are removed.
+    pub fn create(path: PathBuf, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        remove_rotated(&path, 1)?;
+        let file = std::fs::File::create(&path)?;
+        Ok(RotatingFile {
+            active: Arc::new(Mutex::new(ActiveFile {
+                path,
+                file,
+                size: 0,
+                max_size,
+                max_files,
+            })),
+        })
+    }
+
+    /// Removes the log file at `path`, and all the files rotated from it
+    pub fn remove(path: &Path) -> std::io::Result<()> {
+        match std::fs::remove_file(path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        remove_rotated(path, 1)
+    }
+
+    /// Moves the log file at `from`, and the files rotated from it, to `to`,
+    /// replacing any log already there along with the files rotated from it
+    pub fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+        Self::remove(to)?;
+        let mut index = 1;
+        while rename_if_exists(&rotated_path(from, index), &rotated_path(to, index))? {
+            index += 1;
+        }
+        rename_if_exists(from, to)?;
+        Ok(())
+    }
+
+    fn active(&self) -> std::sync::MutexGuard<'_, ActiveFile> {
+        self.active
+            .lock()
+            .expect("log file lock should not be poisoned")
+    }
+}
+
+impl ActiveFile {
+    /// Renames the log files up one place, deleting the oldest, and starts a
+    /// new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        remove_rotated(&self.path, self.max_files - 1)?;
+        for index in (1..self.max_files - 1).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                std::fs::rename(from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = std::fs::File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes all of `buf` to the same file, rotating first if it would not
+    /// fit, so that log entries are never split across files
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let mut active = self.active();
+        if active.size > 0 && active.size + buf.len() as u64 > active.max_size {
+            active.rotate()?;
+        }
+        active.file.write_all(buf)?;
+        active.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.active().file.flush()
+    }
+}
+
+/// Removes the files rotated from `path` from `<path>.<first>` onwards
+fn remove_rotated(path: &Path, first: usize) -> std::io::Result<()> {
+    let mut index = first;
+    loop {
+        match std::fs::remove_file(rotated_path(path, index)) {
+            Ok(()) => index += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Renames `from` to `to`, returning whether there was a file at `from`
+fn rename_if_exists(from: &Path, to: &Path) -> std::io::Result<bool> {
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Identifies a file, so that the reader can tell when the log has been
+/// rotated
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Files can't be identified on other platforms, so readers following a log
+/// stop at the file that was being written when they started
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+type RotationCheck = Pin<Box<dyn Future<Output = std::io::Result<Vec<OpenFile>>> + Send>>;
+
+struct OpenFile {
+    file: tokio::fs::File,
+    id: Option<(u64, u64)>,
+}
+
+impl OpenFile {
+    fn new(file: std::fs::File) -> std::io::Result<Self> {
+        let id = file_id(&file.metadata()?);
+        Ok(OpenFile {
+            file: tokio::fs::File::from_std(file),
+            id,
+        })
+    }
+}
+
+fn open_if_exists(path: &Path) -> std::io::Result<Option<std::fs::File>> {
+    match std::fs::File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a log and the files rotated from it, oldest first.
+///
+/// At the end of the file being written, the reader checks whether it has
+/// been rotated since it was opened, and if so carries on with the files
+/// written since. So a reader reading to the end again and again, such as to
+/// follow the log, sees all of the log even if it is rotated in between,
+/// unless it falls so far behind that files are deleted before it reads them.
+pub struct RotatedFiles {
+    path: PathBuf,
+    current: OpenFile,
+    /// The files written after the current one, oldest first
+    next: VecDeque<OpenFile>,
+    checking_rotation: Option<RotationCheck>,
+}
+
+impl RotatedFiles {
+    /// Opens the log at `path` and the files rotated from it
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::from_files(path, open_all(path)?)
+    }
+
+    /// Opens the log at `path` and the files rotated from it, starting far
+    /// enough from the end to read the last `lines` lines of `stream`, or of
+    /// either stream if `None`. Only the end of the log is read to find where
+    /// to start, however long it is.
+    pub fn open_tail(path: &Path, lines: usize, stream: Option<Stream>) -> std::io::Result<Self> {
+        let mut files = open_all(path)?;
+        let mut remaining = lines;
+        // Where to start reading, as the index of a file and an offset in it,
+        // and the streams whose last entry before there hasn't been read
+        let mut start = None;
+        let mut unchecked = vec![];
+        'files: for index in (0..files.len()).rev() {
+            let mut entries = ReverseEntries::new(&mut files[index])?;
+            while let Some((end, entry)) = entries.next()? {
+                let tag = entry_tag(&entry);
+                if start.is_some() {
+                    if let Some((entry_stream, partial)) = tag {
+                        if let Some(i) = unchecked.iter().position(|s| *s == entry_stream) {
+                            unchecked.remove(i);
+                            // Starting here would split a line across the
+                            // start, so start further back
+                            if partial {
+                                start = None;
+                            }
+                        }
+                    }
+                    if start.is_some() && unchecked.is_empty() {
+                        break 'files;
+                    }
+                }
+                if start.is_none() {
+                    if remaining > 0 {
+                        let ends_line = match tag {
+                            Some((entry_stream, partial)) => {
+                                !partial && stream.map(|s| s == entry_stream).unwrap_or(true)
+                            }
+                            None => stream.is_none(),
+                        };
+                        if ends_line {
+                            remaining -= 1;
+                        }
+                    } else if !tag.map(|(_, partial)| partial).unwrap_or(false) {
+                        start = Some((index, end));
+                        unchecked = [Stream::Stdout, Stream::Stderr]
+                            .iter()
+                            .copied()
+                            .filter(|s| Some(*s) != tag.map(|(s, _)| s))
+                            .collect();
+                    }
+                }
+            }
+        }
+        let (first, offset) = start.unwrap_or((0, 0));
+        files.drain(..first);
+        for (index, file) in files.iter_mut().enumerate() {
+            file.seek(SeekFrom::Start(if index == 0 { offset } else { 0 }))?;
+        }
+        Self::from_files(path, files)
+    }
+
+    fn from_files(path: &Path, files: VecDeque<std::fs::File>) -> std::io::Result<Self> {
+        let mut files = files
+            .into_iter()
+            .map(OpenFile::new)
+            .collect::<std::io::Result<VecDeque<_>>>()?;
+        let current = files.pop_front().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("log file {:?} not found", path),
+            )
+        })?;
+        Ok(RotatedFiles {
+            path: path.to_owned(),
+            current,
+            next: files,
+            checking_rotation: None,
+        })
+    }
+}
+
+/// Opens the files of a log, oldest first
+fn open_all(path: &Path) -> std::io::Result<VecDeque<std::fs::File>> {
+    let mut files = VecDeque::new();
+    let mut index = 1;
+    while let Some(file) = open_if_exists(&rotated_path(path, index))? {
+        files.push_front(file);
+        index += 1;
+    }
+    if let Some(file) = open_if_exists(path)? {
+        files.push_back(file);
+    }
+    Ok(files)
+}
+
+/// Reads the entries of a log file backwards from the end
+struct ReverseEntries<'a> {
+    file: &'a mut std::fs::File,
+    /// The offset of the start of `unread`
+    start: u64,
+    /// The part of the file before the entries already read
+    unread: Vec<u8>,
+}
+
+impl<'a> ReverseEntries<'a> {
+    fn new(file: &'a mut std::fs::File) -> std::io::Result<Self> {
+        let start = file.metadata()?.len();
+        Ok(ReverseEntries {
+            file,
+            start,
+            unread: vec![],
+        })
+    }
+
+    /// Returns the previous entry, with the offset of the entry after it
+    fn next(&mut self) -> std::io::Result<Option<(u64, Vec<u8>)>> {
+        loop {
+            // Entries end with a newline, except the last one if it is still
+            // being written
+            let end = match self.unread.last() {
+                Some(b'\n') => self.unread.len() - 1,
+                _ => self.unread.len(),
+            };
+            let after = self.start + self.unread.len() as u64;
+            match self.unread[..end].iter().rposition(|b| *b == b'\n') {
+                Some(newline) => {
+                    let entry = self.unread[newline + 1..end].to_vec();
+                    self.unread.truncate(newline + 1);
+                    return Ok(Some((after, entry)));
+                }
+                None if self.start == 0 => {
+                    if end == 0 {
+                        return Ok(None);
+                    }
+                    let entry = self.unread[..end].to_vec();
+                    self.unread.clear();
+                    return Ok(Some((after, entry)));
+                }
+                None => {
+                    let chunk = self.start.min(TAIL_CHUNK_BYTES);
+                    self.start -= chunk;
+                    let mut read = vec![0; chunk as usize];
+                    self.file.seek(SeekFrom::Start(self.start))?;
+                    self.file.read_exact(&mut read)?;
+                    read.extend_from_slice(&self.unread);
+                    self.unread = read;
+                }
+            }
+        }
+    }
+}
+
+/// Opens the files of a log written after the file identified by `current`,
+/// oldest first. If the file has been deleted, all of the log is newer.
+fn newer_files(path: &Path, current: Option<(u64, u64)>) -> std::io::Result<Vec<OpenFile>> {
+    if current.is_none() {
+        return Ok(vec![]);
+    }
+    let mut files = open_all(path)?
+        .into_iter()
+        .map(OpenFile::new)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if let Some(position) = files.iter().position(|f| f.id == current) {
+        files.drain(..=position);
+    }
+    Ok(files)
+}
+
+impl AsyncRead for RotatedFiles {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let filled = buf.filled().len();
+            futures::ready!(Pin::new(&mut this.current.file).poll_read(cx, buf))?;
+            if buf.filled().len() > filled {
+                return Poll::Ready(Ok(()));
+            }
+
+            // At the end of the current file
+            if let Some(next) = this.next.pop_front() {
+                this.current = next;
+                continue;
+            }
+            let path = this.path.clone();
+            let current = this.current.id;
+            let checking_rotation = this.checking_rotation.get_or_insert_with(|| {
+                Box::pin(async move {
+                    tokio::task::spawn_blocking(move || newer_files(&path, current)).await?
+                })
+            });
+            let result = futures::ready!(checking_rotation.as_mut().poll(cx));
+            this.checking_rotation = None;
+            match result {
+                // Anything written to the current file before it was rotated
+                // is read before moving on to the newer files
+                Ok(newer) if !newer.is_empty() => this.next.extend(newer),
+                Ok(_) => return Poll::Ready(Ok(())),
+                Err(e) => {
+                    warn!(
+                        "Unable to check whether {:?} was rotated: {:?}",
+                        this.path, e
+                    );
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn read_to_end(files: &mut RotatedFiles) -> String {
+        let mut contents = String::new();
+        files.read_to_string(&mut contents).await.unwrap();
+        contents
+    }
+
+    #[test]
+    fn files_are_rotated_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = RotatingFile::create(path.clone(), 10, 3).unwrap();
+        for line in &["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "six\n");
+        assert_eq!(read(rotated_path(&path, 1)), "four\nfive\n");
+        assert_eq!(read(rotated_path(&path, 2)), "three\n");
+        assert!(!rotated_path(&path, 3).exists());
+
+        RotatingFile::remove(&path).unwrap();
+        assert!(!path.exists());
+        assert!(!rotated_path(&path, 1).exists());
+        assert!(!rotated_path(&path, 2).exists());
+    }
+
+    #[test]
+    fn renamed_logs_keep_their_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let previous = dir.path().join("log.previous");
+        let mut file = RotatingFile::create(previous.clone(), 10, 3).unwrap();
+        for line in &["one\n", "two\n", "three\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let mut file = RotatingFile::create(path.clone(), 10, 3).unwrap();
+        for line in &["four\n", "five\n", "six\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        RotatingFile::rename(&path, &previous).unwrap();
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(previous.clone()), "six\n");
+        assert_eq!(read(rotated_path(&previous, 1)), "four\nfive\n");
+        assert!(!rotated_path(&previous, 2).exists());
+        assert!(!path.exists());
+        assert!(!rotated_path(&path, 1).exists());
+    }
+
+    #[tokio::test]
+    async fn rotated_files_are_read_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = RotatingFile::create(path.clone(), 8, 5).unwrap();
+        for line in &["one\n", "two\n", "three\n", "four\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let mut files = RotatedFiles::open(&path).unwrap();
+        assert_eq!(read_to_end(&mut files).await, "one\ntwo\nthree\nfour\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn readers_follow_logs_across_rotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = RotatingFile::create(path.clone(), 8, 2).unwrap();
+        file.write_all(b"one\n").unwrap();
+        let mut files = RotatedFiles::open(&path).unwrap();
+        assert_eq!(read_to_end(&mut files).await, "one\n");
+
+        file.write_all(b"two\n").unwrap();
+        file.write_all(b"three\n").unwrap();
+        file.write_all(b"four\n").unwrap();
+        assert_eq!(read_to_end(&mut files).await, "two\nthree\nfour\n");
+    }
+
+    #[tokio::test]
+    async fn tails_start_at_the_last_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut file = RotatingFile::create(path.clone(), 80, 10).unwrap();
+        let entries = [
+            "2021-01-01T00:00:01Z stdout F one\n",
+            "2021-01-01T00:00:02Z stderr F two\n",
+            "2021-01-01T00:00:03Z stdout P thr\n",
+            "2021-01-01T00:00:04Z stderr F four\n",
+            "2021-01-01T00:00:05Z stdout F ee\n",
+            "2021-01-01T00:00:06Z stderr F five\n",
+        ];
+        for entry in &entries {
+            file.write_all(entry.as_bytes()).unwrap();
+        }
+        assert!(rotated_path(&path, 2).exists());
+
+        let tail = |lines, stream| {
+            let path = path.clone();
+            async move { read_to_end(&mut RotatedFiles::open_tail(&path, lines, stream).unwrap()).await }
+        };
+        assert_eq!(tail(1, None).await, entries[5]);
+        // The third line from the end is split, so reading starts at its
+        // first entry
+        assert_eq!(tail(2, None).await, entries[2..].concat());
+        assert_eq!(tail(3, None).await, entries[2..].concat());
+        assert_eq!(tail(4, None).await, entries[1..].concat());
+        assert_eq!(tail(1, Some(Stream::Stdout)).await, entries[2..].concat());
+        assert_eq!(tail(2, Some(Stream::Stdout)).await, entries[..].concat());
+        assert_eq!(tail(2, Some(Stream::Stderr)).await, entries[2..].concat());
+        assert_eq!(tail(0, None).await, "");
+        assert_eq!(tail(100, None).await, entries[..].concat());
+    }
+}
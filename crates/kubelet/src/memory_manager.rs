@@ -0,0 +1,421 @@
+//! The Kubelet memory manager, which allocates the memory of containers from
+//! the NUMA nodes of the CPUs they run on, like the [memory
+//! manager](https://kubernetes.io/docs/tasks/administer-cluster/memory-manager/)
+//! of other kubelets.
+//!
+//! With the `None` policy, which is the default, memory is allocated from
+//! whichever node the kernel chooses. With the `Static` policy, the memory of
+//! each pod that the [CPU manager](crate::cpu_manager) has assigned exclusive
+//! CPUs is bound to the NUMA nodes those CPUs belong to, so that the pod
+//! doesn't pay for accessing memory on another node. Providers apply the
+//! binding to the memory their runtime allocates for a container, with
+//! [`NumaBinding::enter`] on the thread allocating it and [`bind_memory`] for
+//! each allocation.
+//!
+//! The NUMA topology is read from sysfs when the manager is created. On nodes
+//! with a single NUMA node, or where the topology can't be read, there is
+//! nothing to bind to, and memory is allocated as with the `None` policy.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use tracing::{debug, warn};
+
+use crate::cpu_manager::is_guaranteed_pod;
+use crate::pod::Pod;
+use crate::topology_manager::{generate_hints, HintProvider, TopologyHint};
+
+/// Where the kernel describes the NUMA nodes of the system
+const NUMA_NODE_DIR: &str = "/sys/devices/system/node";
+
+thread_local! {
+    static CURRENT_BINDING: RefCell<Option<NumaBinding>> = const { RefCell::new(None) };
+}
+
+/// How the memory manager places the memory of containers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MemoryManagerPolicy {
+    /// Memory is allocated from any NUMA node
+    #[default]
+    None,
+    /// The memory of pods with exclusive CPUs is allocated from the NUMA
+    /// nodes of those CPUs
+    Static,
+}
+
+impl std::str::FromStr for MemoryManagerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "None" => Ok(MemoryManagerPolicy::None),
+            "Static" => Ok(MemoryManagerPolicy::Static),
+            _ => Err(anyhow::anyhow!(
+                "unknown memory manager policy {:?}: expected None or Static",
+                s
+            )),
+        }
+    }
+}
+
+/// A NUMA node of the system
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaNode {
+    /// The number the kernel identifies the node by
+    pub id: usize,
+    /// The CPUs that belong to the node
+    pub cpus: Vec<usize>,
+}
+
+/// Decides which NUMA nodes the memory of pods is allocated from
+pub struct MemoryManager {
+    policy: MemoryManagerPolicy,
+    nodes: Vec<NumaNode>,
+}
+
+impl MemoryManager {
+    /// Creates a memory manager with the given policy, reading the NUMA
+    /// topology of the system if the policy needs it
+    pub fn new(policy: MemoryManagerPolicy) -> Arc<Self> {
+        let nodes = match policy {
+            MemoryManagerPolicy::None => vec![],
+            MemoryManagerPolicy::Static => match numa_topology() {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    warn!(
+                        "Unable to read the NUMA topology, so memory will not be bound to NUMA nodes: {}",
+                        e
+                    );
+                    vec![]
+                }
+            },
+        };
+        if policy == MemoryManagerPolicy::Static && nodes.len() < 2 {
+            debug!("Not a NUMA system, so memory will not be bound to NUMA nodes");
+        }
+        Self::with_nodes(policy, nodes)
+    }
+
+    fn with_nodes(policy: MemoryManagerPolicy, nodes: Vec<NumaNode>) -> Arc<Self> {
+        Arc::new(MemoryManager { policy, nodes })
+    }
+
+    /// The policy memory is placed with
+    pub fn policy(&self) -> MemoryManagerPolicy {
+        self.policy
+    }
+
+    /// The NUMA nodes of the system, which is empty if the policy doesn't
+    /// need them or they couldn't be read
+    pub fn numa_nodes(&self) -> &[NumaNode] {
+        &self.nodes
+    }
+
+    /// The NUMA nodes that the memory of a pod running on the given exclusive
+    /// CPUs should be allocated from. This is nothing if the pod has no
+    /// exclusive CPUs, or the policy is `None`, or there is only one node.
+    pub fn binding(&self, cpus: &[usize]) -> Option<NumaBinding> {
+        if self.policy == MemoryManagerPolicy::None || self.nodes.len() < 2 || cpus.is_empty() {
+            return None;
+        }
+        let nodes: Vec<usize> = self
+            .nodes
+            .iter()
+            .filter(|node| node.cpus.iter().any(|cpu| cpus.contains(cpu)))
+            .map(|node| node.id)
+            .collect();
+        if nodes.is_empty() {
+            None
+        } else {
+            Some(NumaBinding { nodes })
+        }
+    }
+}
+
+/// With the `Static` policy, the memory of Guaranteed pods could come from any
+/// of the nodes, but is best kept on one, which the [topology
+/// manager](crate::topology_manager) aligns with the pod's other resources.
+impl HintProvider for MemoryManager {
+    fn topology_hints(
+        &self,
+        pod: &Pod,
+        topology: &[NumaNode],
+    ) -> anyhow::Result<HashMap<String, Vec<TopologyHint>>> {
+        let mut hints = HashMap::new();
+        if self.policy == MemoryManagerPolicy::Static && is_guaranteed_pod(pod)? {
+            hints.insert(
+                "memory".to_owned(),
+                generate_hints(topology, |_| true, |_| true),
+            );
+        }
+        Ok(hints)
+    }
+}
+
+/// The NUMA nodes that the memory of a pod is allocated from
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaBinding {
+    nodes: Vec<usize>,
+}
+
+impl NumaBinding {
+    /// The IDs of the nodes
+    pub fn nodes(&self) -> &[usize] {
+        &self.nodes
+    }
+
+    /// Applies this binding to the memory passed to [`bind_memory`] on the
+    /// current thread until the returned guard is dropped
+    pub fn enter(&self) -> NumaBindingGuard {
+        CURRENT_BINDING.with(|current| current.replace(Some(self.clone())));
+        NumaBindingGuard {}
+    }
+}
+
+/// Stops applying a NUMA binding to the current thread when dropped
+pub struct NumaBindingGuard {}
+
+impl Drop for NumaBindingGuard {
+    fn drop(&mut self) {
+        CURRENT_BINDING.with(|current| current.replace(None));
+    }
+}
+
+/// Binds the pages of a memory mapping to the NUMA nodes of the binding
+/// entered on the current thread, if any. The range must be page aligned, as
+/// mappings are. Pages already allocated stay where they are, so this should
+/// be called before the memory is first written. Failing to bind the memory
+/// isn't fatal, as it can still be used.
+pub fn bind_memory(ptr: *mut u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    CURRENT_BINDING.with(|current| {
+        if let Some(binding) = current.borrow().as_ref() {
+            if let Err(e) = mbind(ptr, len, &binding.nodes) {
+                warn!(
+                    "Unable to bind memory to NUMA nodes {:?}: {}",
+                    binding.nodes, e
+                );
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn mbind(ptr: *mut u8, len: usize, nodes: &[usize]) -> std::io::Result<()> {
+    // From linux/mempolicy.h
+    const MPOL_BIND: libc::c_int = 2;
+    const BITS: usize = std::mem::size_of::<libc::c_ulong>() * 8;
+    let max_node = nodes.iter().copied().max().unwrap_or(0);
+    let mut mask: Vec<libc::c_ulong> = vec![0; max_node / BITS + 1];
+    for node in nodes {
+        mask[node / BITS] |= 1 << (node % BITS);
+    }
+    // The kernel reads one bit less than it is told there are
+    let max_nodes = mask.len() * BITS + 1;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            MPOL_BIND,
+            mask.as_ptr(),
+            max_nodes,
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mbind(_ptr: *mut u8, _len: usize, _nodes: &[usize]) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Reads the NUMA nodes of the system
+pub(crate) fn numa_topology() -> std::io::Result<Vec<NumaNode>> {
+    read_topology(Path::new(NUMA_NODE_DIR))
+}
+
+/// Reads the NUMA nodes described in `dir`, which has a `nodeN` directory
+/// with a `cpulist` file for each node
+fn read_topology(dir: &Path) -> std::io::Result<Vec<NumaNode>> {
+    let mut nodes = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let id = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let cpus = std::fs::read_to_string(entry.path().join("cpulist"))?;
+        let cpus = parse_cpu_list(&cpus).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid CPU list for NUMA node {}: {:?}", id, cpus),
+            )
+        })?;
+        nodes.push(NumaNode { id, cpus });
+    }
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Parses a list of CPUs in the kernel's format, such as `0-3,8,10-11`
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let list = list.trim();
+    if list.is_empty() {
+        return Some(vec![]);
+    }
+    let mut cpus = vec![];
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+
+    fn two_nodes() -> Vec<NumaNode> {
+        vec![
+            NumaNode {
+                id: 0,
+                cpus: vec![0, 1, 2, 3],
+            },
+            NumaNode {
+                id: 1,
+                cpus: vec![4, 5, 6, 7],
+            },
+        ]
+    }
+
+    #[test]
+    fn hints_prefer_a_single_node() {
+        let manager = MemoryManager::with_nodes(MemoryManagerPolicy::Static, two_nodes());
+        let spec = serde_json::json!({
+            "containers": [{
+                "name": "container",
+                "resources": { "limits": { "cpu": "1", "memory": "1Gi" } }
+            }]
+        });
+        let pod = test_util::pod("pod", serde_json::from_value(spec).unwrap());
+        let hints = manager.topology_hints(&pod, &two_nodes()).unwrap();
+        let preferred: Vec<bool> = hints["memory"].iter().map(|h| h.preferred).collect();
+        assert_eq!(preferred, vec![true, true, false]);
+
+        let manager = MemoryManager::with_nodes(MemoryManagerPolicy::None, two_nodes());
+        assert!(manager
+            .topology_hints(&pod, &two_nodes())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        crate::test_util::assert_parses(
+            &[
+                ("None", MemoryManagerPolicy::None),
+                ("Static", MemoryManagerPolicy::Static),
+            ],
+            &["static"],
+        );
+    }
+
+    #[test]
+    fn cpu_lists_are_parsed() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+    }
+
+    #[test]
+    fn topology_is_read() {
+        let dir = tempfile::tempdir().unwrap();
+        for (node, cpus) in &[("node0", "0-1\n"), ("node1", "2-3\n")] {
+            std::fs::create_dir(dir.path().join(node)).unwrap();
+            std::fs::write(dir.path().join(node).join("cpulist"), cpus).unwrap();
+        }
+        std::fs::create_dir(dir.path().join("power")).unwrap();
+        std::fs::write(dir.path().join("possible"), "0-1\n").unwrap();
+
+        assert_eq!(
+            read_topology(dir.path()).unwrap(),
+            vec![
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1]
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![2, 3]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_is_bound_to_the_nodes_of_the_cpus() {
+        let manager = MemoryManager::with_nodes(MemoryManagerPolicy::Static, two_nodes());
+        assert_eq!(manager.binding(&[4, 5]).unwrap().nodes(), &[1]);
+        assert_eq!(manager.binding(&[3, 4]).unwrap().nodes(), &[0, 1]);
+        // Pods without exclusive CPUs run anywhere
+        assert_eq!(manager.binding(&[]), None);
+        assert_eq!(manager.binding(&[9]), None);
+    }
+
+    #[test]
+    fn memory_is_not_bound_without_numa() {
+        let manager = MemoryManager::with_nodes(MemoryManagerPolicy::None, two_nodes());
+        assert_eq!(manager.binding(&[4, 5]), None);
+
+        let manager = MemoryManager::with_nodes(
+            MemoryManagerPolicy::Static,
+            vec![NumaNode {
+                id: 0,
+                cpus: vec![0, 1, 2, 3],
+            }],
+        );
+        assert_eq!(manager.binding(&[1, 2]), None);
+    }
+
+    #[test]
+    fn bindings_apply_to_the_current_thread() {
+        let binding = NumaBinding { nodes: vec![0] };
+        let current = || CURRENT_BINDING.with(|current| current.borrow().clone());
+        {
+            let _guard = binding.enter();
+            assert_eq!(current(), Some(binding));
+            std::thread::spawn(move || assert_eq!(current(), None))
+                .join()
+                .unwrap();
+        }
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,327 @@
+//! Capacity and allocatable resources of the node.
+//!
+//! The node's capacity of CPU, memory and ephemeral storage is detected from
+//! the host: the CPUs the kubelet can run on, the memory in `/proc/meminfo`,
+//! and the size of the filesystem the kubelet's data directory is on. Its
+//! capacity of pods is `max_pods`. What is allocatable to pods is the
+//! capacity less what `system_reserved` and `kube_reserved` set aside for
+//! the host's own processes and for the kubelet. The resources are detected
+//! each time the node's status is checked, and reported as soon as they
+//! change, so that memory that is hot-plugged or a disk that is resized is
+//! reflected without restarting the kubelet. Resources that can't be
+//! detected are reported with the values krustlet reported before it
+//! detected them.
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use tracing::debug;
+
+use crate::config::Config;
+use crate::resources::{
+    format_binary_quantity, format_milli_quantity, parse_milli_quantity, parse_quantity,
+};
+
+const CPU: &str = "cpu";
+const MEMORY: &str = "memory";
+const EPHEMERAL_STORAGE: &str = "ephemeral-storage";
+const PODS: &str = "pods";
+
+/// The resources that `system_reserved` and `kube_reserved` can reserve
+pub(crate) const RESERVABLE_RESOURCES: &[&str] = &[CPU, MEMORY, EPHEMERAL_STORAGE];
+
+/// The CPUs reported when they can't be detected
+const FALLBACK_CPU_MILLIS: u64 = 4000;
+/// The memory reported when it can't be detected
+const FALLBACK_MEMORY_BYTES: u64 = 4_032_800 * 1024;
+/// The ephemeral storage reported when it can't be detected
+const FALLBACK_EPHEMERAL_STORAGE_BYTES: u64 = 61_255_492 * 1024;
+
+#[cfg(target_os = "linux")]
+const MEMINFO: &str = "/proc/meminfo";
+
+/// The size and free space of a filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FilesystemStats {
+    pub(crate) total_bytes: u64,
+    /// The bytes available to unprivileged processes
+    pub(crate) available_bytes: u64,
+    pub(crate) total_inodes: u64,
+    /// The inodes available to unprivileged processes
+    pub(crate) available_inodes: u64,
+}
+
+/// The node's capacity of the resources that are detected from the host
+#[derive(Clone, Debug, PartialEq)]
+struct HostResources {
+    cpu_millis: u64,
+    memory_bytes: u64,
+    ephemeral_storage_bytes: u64,
+}
+
+/// The capacity and allocatable resources the node reports, as quantities
+/// by resource name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct NodeResources {
+    pub(crate) capacity: BTreeMap<String, String>,
+    pub(crate) allocatable: BTreeMap<String, String>,
+}
+
+impl NodeResources {
+    /// Adds the extended resources the provider advertises, which are all
+    /// allocatable
+    pub(crate) fn add_extended(&mut self, extended: &HashMap<String, u64>) {
+        for (resource, count) in extended {
+            self.capacity.insert(resource.clone(), count.to_string());
+            self.allocatable.insert(resource.clone(), count.to_string());
+        }
+    }
+}
+
+/// Detects the node's resources as configured
+#[derive(Clone, Debug)]
+pub(crate) struct ResourceDetector {
+    data_dir: PathBuf,
+    max_pods: u16,
+    /// What `system_reserved` and `kube_reserved` reserve together
+    reserved: HashMap<String, u64>,
+}
+
+impl ResourceDetector {
+    pub(crate) fn new(config: &Config) -> Self {
+        ResourceDetector {
+            data_dir: config.data_dir.clone(),
+            max_pods: config.max_pods,
+            reserved: total_reserved(&[&config.system_reserved, &config.kube_reserved]),
+        }
+    }
+
+    /// The node's resources as they are now
+    pub(crate) fn detect(&self) -> NodeResources {
+        let host = HostResources {
+            cpu_millis: detect_or(CPU, detect_cpu_millis(), FALLBACK_CPU_MILLIS),
+            memory_bytes: detect_or(MEMORY, detect_memory_bytes(), FALLBACK_MEMORY_BYTES),
+            ephemeral_storage_bytes: detect_or(
+                EPHEMERAL_STORAGE,
+                filesystem_stats(&self.data_dir).map(|stats| stats.total_bytes),
+                FALLBACK_EPHEMERAL_STORAGE_BYTES,
+            ),
+        };
+        node_resources(&host, &self.reserved, self.max_pods)
+    }
+}
+
+fn detect_or(resource: &str, detected: anyhow::Result<u64>, fallback: u64) -> u64 {
+    detected.unwrap_or_else(|e| {
+        // This is checked often, so it would flood the log as a warning on
+        // hosts where it can never be detected
+        debug!(
+            "Unable to detect the node's capacity of {}, reporting a default: {:?}",
+            resource, e
+        );
+        fallback
+    })
+}
+
+/// The amount of each resource reserved, in the unit the resource is
+/// reported in. Quantities are validated when the configuration is built,
+/// so invalid ones are ignored here.
+fn total_reserved(reservations: &[&HashMap<String, String>]) -> HashMap<String, u64> {
+    let mut total = HashMap::new();
+    for reservation in reservations {
+        for (resource, quantity) in reservation.iter() {
+            if let Ok(amount) = parse_reserved(resource, quantity) {
+                *total.entry(resource.clone()).or_insert(0) += amount;
+            }
+        }
+    }
+    total
+}
+
+/// Parses a quantity of a reservable resource, in millicores for CPU and
+/// bytes otherwise
+pub(crate) fn parse_reserved(resource: &str, quantity: &str) -> anyhow::Result<u64> {
+    if !RESERVABLE_RESOURCES.contains(&resource) {
+        return Err(anyhow::anyhow!(
+            "{} can't be reserved, only {}",
+            resource,
+            RESERVABLE_RESOURCES.join(", ")
+        ));
+    }
+    if resource == CPU {
+        parse_milli_quantity(quantity)
+    } else {
+        parse_quantity(quantity)
+    }
+}
+
+fn node_resources(
+    host: &HostResources,
+    reserved: &HashMap<String, u64>,
+    max_pods: u16,
+) -> NodeResources {
+    let allocatable = |resource: &str, capacity: u64| {
+        capacity.saturating_sub(reserved.get(resource).copied().unwrap_or(0))
+    };
+    let mut resources = NodeResources::default();
+    let mut add = |resource: &str, capacity: String, allocatable: String| {
+        resources.capacity.insert(resource.to_owned(), capacity);
+        resources
+            .allocatable
+            .insert(resource.to_owned(), allocatable);
+    };
+    add(
+        CPU,
+        format_milli_quantity(host.cpu_millis),
+        format_milli_quantity(allocatable(CPU, host.cpu_millis)),
+    );
+    add(
+        MEMORY,
+        format_binary_quantity(host.memory_bytes),
+        format_binary_quantity(allocatable(MEMORY, host.memory_bytes)),
+    );
+    add(
+        EPHEMERAL_STORAGE,
+        format_binary_quantity(host.ephemeral_storage_bytes),
+        format_binary_quantity(allocatable(EPHEMERAL_STORAGE, host.ephemeral_storage_bytes)),
+    );
+    add(PODS, max_pods.to_string(), max_pods.to_string());
+    resources
+}
+
+fn detect_cpu_millis() -> anyhow::Result<u64> {
+    Ok(std::thread::available_parallelism()?.get() as u64 * 1000)
+}
+
+fn detect_memory_bytes() -> anyhow::Result<u64> {
+    parse_meminfo(&read_meminfo()?, "MemTotal")
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn read_meminfo() -> anyhow::Result<String> {
+    Ok(std::fs::read_to_string(MEMINFO)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read_meminfo() -> anyhow::Result<String> {
+    Err(anyhow::anyhow!("memory is only detected on Linux"))
+}
+
+/// A field of `/proc/meminfo`, such as `MemTotal`, in bytes
+pub(crate) fn parse_meminfo(meminfo: &str, field: &str) -> anyhow::Result<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kilobytes| kilobytes.trim().parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+        .ok_or_else(|| anyhow::anyhow!("no {} in meminfo", field))
+}
+
+/// The size and free space of the filesystem the directory is on
+#[cfg(unix)]
+pub(crate) fn filesystem_stats(dir: &std::path::Path) -> anyhow::Result<FilesystemStats> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow::anyhow!(
+            "unable to get the filesystem size of {:?}: {}",
+            dir,
+            std::io::Error::last_os_error()
+        ));
+    }
+    // The sizes of the fields differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(FilesystemStats {
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64,
+        available_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_inodes: stat.f_files as u64,
+        available_inodes: stat.f_favail as u64,
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn filesystem_stats(_dir: &std::path::Path) -> anyhow::Result<FilesystemStats> {
+    Err(anyhow::anyhow!(
+        "filesystem sizes are only detected on Unix"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reservation(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(resource, quantity)| (resource.to_string(), quantity.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reservations_are_subtracted_from_allocatable() {
+        let host = HostResources {
+            cpu_millis: 4000,
+            memory_bytes: 4 * 1024 * 1024 * 1024,
+            ephemeral_storage_bytes: 10 * 1024 * 1024 * 1024,
+        };
+        let system_reserved = reservation(&[("cpu", "100m"), ("memory", "128Mi")]);
+        let kube_reserved = reservation(&[("memory", "48Mi"), ("ephemeral-storage", "1Gi")]);
+        let reserved = total_reserved(&[&system_reserved, &kube_reserved]);
+        let resources = node_resources(&host, &reserved, 110);
+
+        assert_eq!(resources.capacity["cpu"], "4");
+        assert_eq!(resources.allocatable["cpu"], "3900m");
+        assert_eq!(resources.capacity["memory"], "4Gi");
+        assert_eq!(resources.allocatable["memory"], "3920Mi");
+        assert_eq!(resources.capacity["ephemeral-storage"], "10Gi");
+        assert_eq!(resources.allocatable["ephemeral-storage"], "9Gi");
+        assert_eq!(resources.capacity["pods"], "110");
+        assert_eq!(resources.allocatable["pods"], "110");
+    }
+
+    #[test]
+    fn reservations_larger_than_the_capacity_leave_nothing() {
+        let host = HostResources {
+            cpu_millis: 1000,
+            memory_bytes: 512 * 1024 * 1024,
+            ephemeral_storage_bytes: 1024,
+        };
+        let reserved = total_reserved(&[&reservation(&[("cpu", "2"), ("memory", "1Gi")])]);
+        let resources = node_resources(&host, &reserved, 10);
+        assert_eq!(resources.allocatable["cpu"], "0");
+        assert_eq!(resources.allocatable["memory"], "0");
+        assert_eq!(resources.allocatable["ephemeral-storage"], "1Ki");
+    }
+
+    #[test]
+    fn only_cpu_memory_and_ephemeral_storage_can_be_reserved() {
+        assert_eq!(parse_reserved("cpu", "1.5").unwrap(), 1500);
+        assert_eq!(parse_reserved("memory", "1Ki").unwrap(), 1024);
+        assert!(parse_reserved("pods", "10").is_err());
+        assert!(parse_reserved("memory", "lots").is_err());
+    }
+
+    #[test]
+    fn total_memory_is_read_from_meminfo() {
+        let meminfo = "MemTotal:        4014080 kB\nMemFree:          102400 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo, "MemTotal").unwrap(),
+            4_014_080 * 1024
+        );
+        assert_eq!(parse_meminfo(meminfo, "MemFree").unwrap(), 102_400 * 1024);
+        assert!(parse_meminfo(meminfo, "MemAvailable").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filesystem_sizes_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = filesystem_stats(dir.path()).unwrap();
+        assert!(stats.total_bytes > 0);
+        assert!(stats.available_bytes <= stats.total_bytes);
+        assert!(filesystem_stats(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! PodDisruptionBudgets of pods evicted voluntarily.
+//!
+//! Before a pod is evicted to drain the node, the budgets in its namespace
+//! that select it are checked, and the pod isn't evicted while any of them
+//! allows no more disruptions. Blocked evictions are retried with
+//! exponential backoff, and a `Warning` event on the pod tells operators why
+//! the drain is stalled.
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::policy::v1beta1::PodDisruptionBudget;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::{Api, ListParams};
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+use crate::pod::event::{record_event, EventType};
+use crate::pod::Pod;
+
+/// The reason of the event recorded when a budget blocks a pod's eviction
+const EVICTION_BLOCKED: &str = "EvictionBlocked";
+
+/// A PodDisruptionBudget allows no more disruptions of the pod
+#[derive(Debug, Error)]
+#[error(
+    "Cannot evict pod {} as it would violate the pod's disruption budget {}",
+    pod,
+    budget
+)]
+pub struct DisruptionBudgetViolation {
+    /// The pod that can't be evicted
+    pub pod: String,
+    /// The name of the budget that allows no more disruptions
+    pub budget: String,
+}
+
+/// Checks that none of the PodDisruptionBudgets that select the pod forbid
+/// evicting it. Fails with a [`DisruptionBudgetViolation`] if one does.
+pub async fn check_disruption_budgets(client: &kube::Client, pod: &Pod) -> anyhow::Result<()> {
+    let api: Api<PodDisruptionBudget> = Api::namespaced(client.clone(), pod.namespace());
+    let budgets = api.list(&ListParams::default()).await?;
+    match budgets
+        .items
+        .iter()
+        .find(|budget| blocks_eviction(budget, pod.labels()))
+    {
+        Some(budget) => Err(DisruptionBudgetViolation {
+            pod: pod.name().to_owned(),
+            budget: budget.metadata.name.clone().unwrap_or_default(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Waits until the PodDisruptionBudgets of the pod allow it to be evicted,
+/// backing off exponentially while they don't. Gives up once `deadline`
+/// passes, if there is one.
+pub(crate) async fn wait_for_disruption_budgets(
+    client: &kube::Client,
+    pod: &Pod,
+    deadline: Option<Instant>,
+) -> anyhow::Result<()> {
+    let mut backoff = ExponentialBackoffStrategy::default();
+    let mut warned = false;
+    loop {
+        let violation = match check_disruption_budgets(client, pod).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e.downcast::<DisruptionBudgetViolation>()?,
+        };
+        let mut delay = backoff.next_duration();
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(violation.into());
+            }
+            delay = delay.min(remaining);
+        }
+        info!("{}, retrying in {:?}", violation, delay);
+        // The event is recorded once, as it says the same thing every time
+        if !warned {
+            let message = format!("{}. The node's drain is waiting for it.", violation);
+            if let Err(e) =
+                record_event(client, pod, EventType::Warning, EVICTION_BLOCKED, &message).await
+            {
+                warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+            }
+            warned = true;
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether the budget selects a pod with the given labels and allows no
+/// more disruptions. Budgets the disruption controller hasn't processed yet
+/// allow none.
+fn blocks_eviction(budget: &PodDisruptionBudget, labels: &BTreeMap<String, String>) -> bool {
+    let selects = budget
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.selector.as_ref())
+        .map(|selector| selects(selector, labels))
+        .unwrap_or(false);
+    let disruptions_allowed = budget
+        .status
+        .as_ref()
+        .map(|status| status.disruptions_allowed)
+        .unwrap_or(0);
+    selects && disruptions_allowed <= 0
+}
+
+/// Whether the selector selects a pod with the given labels. As in
+/// `policy/v1beta1`, an empty selector selects no pods.
+fn selects(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector.match_labels.as_ref();
+    let match_expressions = selector.match_expressions.as_ref();
+    if match_labels.is_none_or(BTreeMap::is_empty) && match_expressions.is_none_or(Vec::is_empty) {
+        return false;
+    }
+    let labels_match = match_labels
+        .into_iter()
+        .flatten()
+        .all(|(key, value)| labels.get(key) == Some(value));
+    let expressions_match = match_expressions.into_iter().flatten().all(|expression| {
+        let values = expression.values.as_deref().unwrap_or_default();
+        let value = labels.get(&expression.key);
+        match expression.operator.as_str() {
+            "In" => value.is_some_and(|value| values.contains(value)),
+            "NotIn" => value.is_none_or(|value| !values.contains(value)),
+            "Exists" => value.is_some(),
+            "DoesNotExist" => value.is_none(),
+            _ => false,
+        }
+    });
+    labels_match && expressions_match
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::policy::v1beta1::{PodDisruptionBudgetSpec, PodDisruptionBudgetStatus};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn budget(selector: LabelSelector, disruptions_allowed: Option<i32>) -> PodDisruptionBudget {
+        PodDisruptionBudget {
+            spec: Some(PodDisruptionBudgetSpec {
+                selector: Some(selector),
+                ..Default::default()
+            }),
+            status: disruptions_allowed.map(|disruptions_allowed| PodDisruptionBudgetStatus {
+                disruptions_allowed,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn app_selector(app: &str) -> LabelSelector {
+        LabelSelector {
+            match_labels: Some(labels(&[("app", app)])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_budgets_without_disruptions_left_block_eviction() {
+        let web = labels(&[("app", "web"), ("tier", "frontend")]);
+        assert!(blocks_eviction(&budget(app_selector("web"), Some(0)), &web));
+        assert!(!blocks_eviction(
+            &budget(app_selector("web"), Some(1)),
+            &web
+        ));
+        assert!(!blocks_eviction(&budget(app_selector("db"), Some(0)), &web));
+        // Budgets that haven't been processed allow no disruptions yet
+        assert!(blocks_eviction(&budget(app_selector("web"), None), &web));
+        // Empty selectors select no pods
+        assert!(!blocks_eviction(
+            &budget(LabelSelector::default(), Some(0)),
+            &web
+        ));
+    }
+
+    #[test]
+    fn selectors_match_expressions() {
+        let requirement = |key: &str, operator: &str, values: &[&str]| LabelSelectorRequirement {
+            key: key.to_owned(),
+            operator: operator.to_owned(),
+            values: Some(values.iter().map(|value| value.to_string()).collect()),
+        };
+        let selector = LabelSelector {
+            match_expressions: Some(vec![
+                requirement("tier", "In", &["frontend", "backend"]),
+                requirement("env", "NotIn", &["dev"]),
+                requirement("app", "Exists", &[]),
+                requirement("canary", "DoesNotExist", &[]),
+            ]),
+            ..Default::default()
+        };
+        assert!(selects(
+            &selector,
+            &labels(&[("app", "web"), ("tier", "frontend")])
+        ));
+        assert!(!selects(
+            &selector,
+            &labels(&[("app", "web"), ("tier", "frontend"), ("env", "dev")])
+        ));
+        assert!(!selects(
+            &selector,
+            &labels(&[("app", "web"), ("tier", "cache")])
+        ));
+        assert!(!selects(
+            &selector,
+            &labels(&[("app", "web"), ("tier", "frontend"), ("canary", "true")])
+        ));
+    }
+}
@@ -0,0 +1,601 @@
+//! Heartbeats of the node.
+//!
+//! As with other kubelets, the node tells the cluster that it is alive in two
+//! ways at their own pace. Its Lease in `kube-node-lease` is small, and is
+//! renewed every quarter of its duration. Its status is much larger, and is
+//! only patched every `node_status_update_frequency`, or as soon as something
+//! it reports changes. Renewals that fail are retried with backoff, and once
+//! [`LEASE_FAILURE_THRESHOLD`] of them in a row have failed the lease is
+//! created again if it was deleted.
+//!
+//! The node's capacity and allocatable resources are part of its status, and
+//! are detected again each time it is checked for changes, with the extended
+//! resources the provider advertises, as are its
+//! pressure conditions and the images in the module store. While the node is
+//! low on a resource, one of its pods is evicted each time.
+//!
+//! Renewals and status updates that fail as nothing is found may mean that
+//! the node was deleted, as its lease is deleted with it. Unless
+//! `reregister_node` is turned off, the node is then registered again as it
+//! was when the kubelet started, and the statuses of its running pods are
+//! patched, as their readiness was taken away with the node.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::ContainerImage;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, ListParams, PatchParams};
+use kube::error::ErrorResponse;
+use kube::Error;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use super::capacity::{NodeResources, ResourceDetector};
+use super::images::ImageLister;
+use super::pressure::ConditionManager;
+use super::{create, create_lease, evict_for_pressure, uid, update_lease};
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+use crate::config::Config;
+use crate::pod::{make_running_status, patch_status, Phase, Pod};
+use crate::provider::Provider;
+
+/// How long to wait before retrying the first renewal of the lease that fails
+const LEASE_RETRY_BASE: Duration = Duration::from_millis(200);
+/// The most to wait before retrying a renewal of the lease that failed
+const LEASE_RETRY_CAP: Duration = Duration::from_secs(7);
+/// How many renewals of the lease in a row can fail before it is reported
+/// as an error, and created again if it was deleted
+const LEASE_FAILURE_THRESHOLD: u32 = 5;
+/// How often the node's status is checked for changes to report
+const STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Renews the node's lease every quarter of `lease_duration`, notifying
+/// `node_missing` when the lease isn't found, and sending a heartbeat on
+/// `heartbeats` each time it is renewed
+pub(crate) async fn renew_lease_periodically(
+    client: kube::Client,
+    node_name: String,
+    lease_duration: Duration,
+    node_missing: Arc<Notify>,
+    heartbeats: Arc<watch::Sender<std::time::Instant>>,
+) {
+    let mut renewer = LeaseRenewer::new(client, node_name, lease_duration, node_missing)
+        .with_heartbeats(heartbeats);
+    loop {
+        let delay = renewer.renew().await;
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Patches the node's status every `frequency`, and as soon as something it
+/// reports changes, notifying `node_missing` when the node isn't found
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn update_status_periodically<P: Provider>(
+    client: kube::Client,
+    node_name: String,
+    frequency: Duration,
+    provider: Arc<P>,
+    resources: ResourceDetector,
+    mut conditions: ConditionManager,
+    images: ImageLister,
+    node_missing: Arc<Notify>,
+) {
+    let mut reporter = StatusReporter::new(frequency);
+    loop {
+        conditions.observe(&conditions.sample(), Utc::now());
+        let extended = provider.node_resources();
+        crate::extended_resources::set_capacity(&extended);
+        let status =
+            TrackedStatus::current(&resources, &extended, &conditions, images.list().await);
+        if reporter.is_due(&status, Instant::now()) {
+            match update_status(&node_name, &client, &status).await {
+                Ok(()) => reporter.reported(status, Instant::now()),
+                Err(e) => {
+                    warn!("Unable to update status of node '{}': {}", node_name, e);
+                    if is_not_found(&e) {
+                        node_missing.notify_one();
+                    }
+                }
+            }
+        }
+        let starved = conditions.starved_resources();
+        if !starved.is_empty() {
+            if let Err(e) = evict_for_pressure(&client, &node_name, &starved).await {
+                error!(
+                    "Unable to evict a pod from node '{}', which is low on {}: {:?}",
+                    node_name,
+                    starved.join(", "),
+                    e
+                );
+            }
+        }
+        tokio::time::sleep(STATUS_CHECK_INTERVAL.min(frequency)).await;
+    }
+}
+
+/// Registers the node again each time `node_missing` is notified and the
+/// node no longer exists
+pub(crate) async fn reregister_when_missing<P: Provider>(
+    client: kube::Client,
+    config: Box<Config>,
+    provider: Arc<P>,
+    node_missing: Arc<Notify>,
+) {
+    loop {
+        node_missing.notified().await;
+        if let Err(e) = reregister(&client, &config, provider.clone()).await {
+            error!(
+                "Unable to register node '{}' again: {:?}",
+                config.node_name, e
+            );
+        }
+    }
+}
+
+/// Registers the node again if it no longer exists, and patches the statuses
+/// of its running pods
+async fn reregister<P: Provider>(
+    client: &kube::Client,
+    config: &Config,
+    provider: Arc<P>,
+) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    match node_client.get(&config.node_name).await {
+        Ok(_) => return Ok(()),
+        Err(e) if is_not_found(&e) => (),
+        Err(e) => return Err(e.into()),
+    }
+    warn!(
+        "Node '{}' was deleted while the kubelet was running, registering it again",
+        config.node_name
+    );
+    create(client, config, provider).await;
+    resync_pod_statuses(client, &config.node_name).await
+}
+
+/// Patches the statuses of the node's running pods as they are, restoring
+/// the conditions taken away while the node didn't exist
+async fn resync_pod_statuses(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    for pod in api.list(&params).await?.items.into_iter().map(Pod::from) {
+        let running = pod
+            .as_kube_pod()
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref())
+            == Some(&Phase::Running.to_string());
+        if running {
+            debug!("Resyncing status of pod {}", pod.name());
+            let pod_api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+            patch_status(&pod_api, pod.name(), make_running_status(&pod)).await;
+        }
+    }
+    Ok(())
+}
+
+fn is_not_found(error: &Error) -> bool {
+    matches!(error, Error::Api(ErrorResponse { code: 404, .. }))
+}
+
+/// Renews the node's lease, backing off while renewals fail
+struct LeaseRenewer {
+    client: kube::Client,
+    node_name: String,
+    lease_duration: Duration,
+    backoff: ExponentialBackoffStrategy,
+    /// How many renewals in a row have failed
+    failures: u32,
+    /// Notified when the lease isn't found, as the node may have been
+    /// deleted with it
+    node_missing: Arc<Notify>,
+    /// Sent the time of each renewal, for the health check of the kubelet's
+    /// connection to the API server
+    heartbeats: Option<Arc<watch::Sender<std::time::Instant>>>,
+}
+
+impl LeaseRenewer {
+    fn new(
+        client: kube::Client,
+        node_name: String,
+        lease_duration: Duration,
+        node_missing: Arc<Notify>,
+    ) -> Self {
+        LeaseRenewer {
+            client,
+            node_name,
+            lease_duration,
+            backoff: ExponentialBackoffStrategy::new(LEASE_RETRY_BASE, LEASE_RETRY_CAP),
+            failures: 0,
+            node_missing,
+            heartbeats: None,
+        }
+    }
+
+    fn with_heartbeats(mut self, heartbeats: Arc<watch::Sender<std::time::Instant>>) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    /// How often the lease is renewed while renewals succeed
+    fn interval(&self) -> Duration {
+        self.lease_duration / 4
+    }
+
+    /// Renews the lease, returning how long to wait before renewing it again
+    async fn renew(&mut self) -> Duration {
+        let error = match update_lease(&self.node_name, self.lease_duration, &self.client).await {
+            Ok(_) => {
+                if self.failures > 0 {
+                    info!(
+                        "Renewed lease for node '{}' after {} failed renewals",
+                        self.node_name, self.failures
+                    );
+                }
+                self.failures = 0;
+                self.backoff.reset();
+                if let Some(heartbeats) = &self.heartbeats {
+                    // No one is listening once every receiver was dropped
+                    let _ = heartbeats.send(std::time::Instant::now());
+                }
+                return self.interval();
+            }
+            Err(e) => e,
+        };
+        self.failures += 1;
+        if is_not_found(&error) {
+            self.node_missing.notify_one();
+        }
+        if self.failures < LEASE_FAILURE_THRESHOLD {
+            warn!(
+                "Failed to renew lease for node '{}': {}. Retrying...",
+                self.node_name, error
+            );
+        } else {
+            error!(
+                "Failed to renew lease for node '{}' {} times in a row: {}. The node will be marked as not ready once its lease expires.",
+                self.node_name, self.failures, error
+            );
+            if is_not_found(&error) {
+                self.recreate().await;
+            }
+        }
+        self.backoff.next_duration().min(self.interval())
+    }
+
+    /// Creates the lease again after it was deleted
+    async fn recreate(&self) {
+        warn!(
+            "Lease for node '{}' no longer exists, creating it again",
+            self.node_name
+        );
+        let created = match uid(&self.client, &self.node_name).await {
+            Ok(node_uid) => create_lease(
+                &node_uid,
+                &self.node_name,
+                self.lease_duration,
+                &self.client,
+            )
+            .await
+            .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = created {
+            error!(
+                "Unable to create lease for node '{}' again: {}",
+                self.node_name, e
+            );
+        }
+    }
+}
+
+/// The parts of the node's status that are reported as soon as they change
+#[derive(Clone, Debug, PartialEq)]
+struct TrackedStatus {
+    /// The PersistentVolumes the node's pods use
+    volumes_in_use: Vec<String>,
+    /// The node's capacity and allocatable resources
+    resources: NodeResources,
+    /// The node's pressure conditions, without heartbeat times
+    conditions: Vec<NodeCondition>,
+    /// The images in the module store, the largest first
+    images: Vec<ContainerImage>,
+}
+
+impl TrackedStatus {
+    fn current(
+        resources: &ResourceDetector,
+        extended: &HashMap<String, u64>,
+        conditions: &ConditionManager,
+        images: Vec<ContainerImage>,
+    ) -> Self {
+        let mut detected = resources.detect();
+        detected.add_extended(extended);
+        TrackedStatus {
+            volumes_in_use: crate::volume::volumes_in_use(),
+            resources: detected,
+            conditions: conditions.conditions(),
+            images,
+        }
+    }
+}
+
+/// Decides when the node's status is reported
+struct StatusReporter {
+    frequency: Duration,
+    /// The status last reported, and when
+    last: Option<(TrackedStatus, Instant)>,
+}
+
+impl StatusReporter {
+    fn new(frequency: Duration) -> Self {
+        StatusReporter {
+            frequency,
+            last: None,
+        }
+    }
+
+    /// Whether `status` should be reported at `now`, as it has changed or
+    /// the last report is `frequency` old
+    fn is_due(&self, status: &TrackedStatus, now: Instant) -> bool {
+        match &self.last {
+            Some((last, at)) => last != status || now.duration_since(*at) >= self.frequency,
+            None => true,
+        }
+    }
+
+    fn reported(&mut self, status: TrackedStatus, now: Instant) {
+        self.last = Some((status, now));
+    }
+}
+
+async fn update_status(
+    node_name: &str,
+    client: &kube::Client,
+    status: &TrackedStatus,
+) -> Result<(), Error> {
+    debug!("Updating status of node '{}'", node_name);
+    let now = Utc::now();
+    // TODO: Update the lastTransitionTime properly
+    let mut conditions = vec![serde_json::json!({
+        "lastHeartbeatTime": now.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+        "message": "kubelet is posting ready status",
+        "reason": "KubeletReady",
+        "status": "True",
+        "type": "Ready"
+    })];
+    conditions.extend(status.conditions.iter().map(|condition| {
+        serde_json::json!(NodeCondition {
+            last_heartbeat_time: Some(Time(now)),
+            ..condition.clone()
+        })
+    }));
+    let status_patch = serde_json::json!({
+        "status": {
+            "conditions": conditions,
+            "volumesInUse": status.volumes_in_use,
+            "capacity": status.resources.capacity,
+            "allocatable": status.resources.allocatable,
+            "images": status.images,
+        }
+    });
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let _node = node_client
+        .patch_status(
+            node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(status_patch),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+    use warp::http::{Method, StatusCode};
+    use warp::Filter;
+
+    /// What the mock API server has been asked to do
+    #[derive(Default)]
+    struct Requests {
+        renewals: AtomicU32,
+        creations: AtomicU32,
+    }
+
+    /// Serves the lease and node of `node`. Every other renewal of the lease
+    /// is dropped with an error if `drop_renewals`, and renewals fail with
+    /// `404 Not Found` until the lease is created if it is `deleted`.
+    async fn start_api(drop_renewals: bool, deleted: bool) -> (kube::Client, Arc<Requests>) {
+        let requests = Arc::new(Requests::default());
+        let deleted = Arc::new(AtomicBool::new(deleted));
+        let lease = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": { "name": "node", "namespace": "kube-node-lease" },
+        });
+        let node = json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": { "name": "node", "uid": "node-uid" },
+        });
+        let failure = |code: StatusCode| {
+            let status = json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Failure",
+                "message": "mock failure",
+                "reason": code.canonical_reason(),
+                "code": code.as_u16(),
+            });
+            warp::reply::with_status(warp::reply::json(&status), code)
+        };
+        let served = requests.clone();
+        let routes = warp::method().and(warp::path::full()).map(
+            move |method: Method, path: warp::path::FullPath| match (method, path.as_str()) {
+                (
+                    Method::PATCH,
+                    "/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases/node",
+                ) => {
+                    let n = served.renewals.fetch_add(1, Ordering::SeqCst);
+                    if deleted.load(Ordering::SeqCst) {
+                        failure(StatusCode::NOT_FOUND)
+                    } else if drop_renewals && n % 2 == 0 {
+                        failure(StatusCode::INTERNAL_SERVER_ERROR)
+                    } else {
+                        warp::reply::with_status(warp::reply::json(&lease), StatusCode::OK)
+                    }
+                }
+                (
+                    Method::POST,
+                    "/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases",
+                ) => {
+                    served.creations.fetch_add(1, Ordering::SeqCst);
+                    deleted.store(false, Ordering::SeqCst);
+                    warp::reply::with_status(warp::reply::json(&lease), StatusCode::CREATED)
+                }
+                (Method::GET, "/api/v1/nodes/node") => {
+                    warp::reply::with_status(warp::reply::json(&node), StatusCode::OK)
+                }
+                _ => failure(StatusCode::NOT_FOUND),
+            },
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            warp::serve(routes)
+                .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        let client = kube::Client::new(kube::Config::new(url.parse().unwrap()));
+        (client, requests)
+    }
+
+    async fn is_notified(notify: &Notify) -> bool {
+        tokio::time::timeout(Duration::from_millis(10), notify.notified())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn dropped_renewals_are_retried_with_backoff() {
+        let (client, requests) = start_api(true, false).await;
+        let lease_duration = Duration::from_secs(40);
+        let node_missing = Arc::new(Notify::new());
+        let started = std::time::Instant::now();
+        let (heartbeats, renewals) = watch::channel(started);
+        let mut renewer = LeaseRenewer::new(
+            client,
+            "node".to_owned(),
+            lease_duration,
+            node_missing.clone(),
+        )
+        .with_heartbeats(Arc::new(heartbeats));
+
+        for _ in 0..3 {
+            let last_renewal = *renewals.borrow();
+            assert_eq!(renewer.renew().await, LEASE_RETRY_BASE);
+            assert_eq!(renewer.failures, 1);
+            assert_eq!(*renewals.borrow(), last_renewal);
+            // A success renews at the usual interval, and resets the backoff
+            assert_eq!(renewer.renew().await, Duration::from_secs(10));
+            assert_eq!(renewer.failures, 0);
+            assert!(*renewals.borrow() > last_renewal);
+        }
+        assert_eq!(requests.renewals.load(Ordering::SeqCst), 6);
+        assert_eq!(requests.creations.load(Ordering::SeqCst), 0);
+        // Errors other than the lease not being found don't mean that the
+        // node is missing
+        assert!(!is_notified(&node_missing).await);
+    }
+
+    #[tokio::test]
+    async fn deleted_leases_are_created_again_after_the_threshold() {
+        let (client, requests) = start_api(false, true).await;
+        let lease_duration = Duration::from_secs(40);
+        let node_missing = Arc::new(Notify::new());
+        let mut renewer = LeaseRenewer::new(
+            client,
+            "node".to_owned(),
+            lease_duration,
+            node_missing.clone(),
+        );
+
+        let mut delays = vec![];
+        for _ in 1..LEASE_FAILURE_THRESHOLD {
+            delays.push(renewer.renew().await);
+            assert_eq!(requests.creations.load(Ordering::SeqCst), 0);
+            // The node may have been deleted with its lease, which is
+            // checked for straight away
+            assert!(is_notified(&node_missing).await);
+        }
+        // The backoff doubles from its base
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(200),
+                Duration::from_millis(400),
+                Duration::from_millis(800),
+                Duration::from_millis(1600),
+            ]
+        );
+
+        renewer.renew().await;
+        assert_eq!(requests.creations.load(Ordering::SeqCst), 1);
+        assert_eq!(renewer.renew().await, Duration::from_secs(10));
+        assert_eq!(renewer.failures, 0);
+    }
+
+    #[test]
+    fn status_is_reported_when_it_changes_or_is_old() {
+        let mut reporter = StatusReporter::new(Duration::from_secs(300));
+        let start = Instant::now();
+        let status = TrackedStatus {
+            volumes_in_use: vec![],
+            resources: NodeResources::default(),
+            conditions: vec![],
+            images: vec![],
+        };
+        assert!(reporter.is_due(&status, start));
+        reporter.reported(status.clone(), start);
+
+        assert!(!reporter.is_due(&status, start + Duration::from_secs(10)));
+        let changed = TrackedStatus {
+            volumes_in_use: vec!["kubernetes.io/csi/driver^volume".to_owned()],
+            resources: NodeResources::default(),
+            conditions: vec![],
+            images: vec![],
+        };
+        assert!(reporter.is_due(&changed, start + Duration::from_secs(10)));
+        let mut resized = status.clone();
+        resized
+            .resources
+            .capacity
+            .insert("memory".to_owned(), "8Gi".to_owned());
+        assert!(reporter.is_due(&resized, start + Duration::from_secs(10)));
+        let mut pressured = status.clone();
+        pressured.conditions.push(NodeCondition {
+            type_: "MemoryPressure".to_owned(),
+            status: "True".to_owned(),
+            ..Default::default()
+        });
+        assert!(reporter.is_due(&pressured, start + Duration::from_secs(10)));
+        let mut pulled = status.clone();
+        pulled.images.push(ContainerImage {
+            names: vec!["webassembly.azurecr.io/hello-wasm:v1".to_owned()],
+            size_bytes: Some(1024),
+        });
+        assert!(reporter.is_due(&pulled, start + Duration::from_secs(10)));
+        reporter.reported(pulled.clone(), start + Duration::from_secs(10));
+        assert!(!reporter.is_due(&pulled, start + Duration::from_secs(20)));
+        assert!(reporter.is_due(&status, start + Duration::from_secs(300)));
+    }
+}
@@ -0,0 +1,106 @@
+//! Images of the node.
+//!
+//! Like other kubelets, the node reports the images it holds in its status,
+//! the largest first and at most `node_status_max_images` of them, so that
+//! the scheduler can prefer nodes that already have the images of a pod.
+//! The images are those in the provider's module store, which are listed
+//! again each time the node's status is checked for changes. An image that
+//! is removed from the store is therefore no longer reported the next time
+//! the status is checked.
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::ContainerImage;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::store::{Store, StoredImage};
+
+/// Lists the images to report in the node's status
+#[derive(Clone)]
+pub(crate) struct ImageLister {
+    /// The provider's module store, if it has one
+    store: Option<Arc<dyn Store + Send + Sync>>,
+    max_images: Option<usize>,
+}
+
+impl ImageLister {
+    pub(crate) fn new(config: &Config, store: Option<Arc<dyn Store + Send + Sync>>) -> Self {
+        ImageLister {
+            store,
+            max_images: config.node_status_max_images,
+        }
+    }
+
+    /// The images to report, or none if the store can't be listed
+    pub(crate) async fn list(&self) -> Vec<ContainerImage> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return vec![],
+        };
+        match store.images().await {
+            Ok(images) => status_images(images, self.max_images),
+            Err(e) => {
+                warn!("Unable to list the images in the module store: {:?}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Sorts `images` by size, largest first, keeping at most `max_images`.
+/// Images of the same size are sorted by name, so that the same images are
+/// always reported in the same order.
+fn status_images(mut images: Vec<StoredImage>, max_images: Option<usize>) -> Vec<ContainerImage> {
+    images.sort_by(|a, b| {
+        b.size_bytes
+            .cmp(&a.size_bytes)
+            .then_with(|| a.names.cmp(&b.names))
+    });
+    if let Some(max_images) = max_images {
+        images.truncate(max_images);
+    }
+    images
+        .into_iter()
+        .map(|image| ContainerImage {
+            names: image.names,
+            size_bytes: Some(image.size_bytes as i64),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(name: &str, size_bytes: u64) -> StoredImage {
+        StoredImage {
+            names: vec![name.to_owned()],
+            size_bytes,
+        }
+    }
+
+    fn names(images: &[ContainerImage]) -> Vec<&str> {
+        images.iter().map(|image| image.names[0].as_str()).collect()
+    }
+
+    #[test]
+    fn images_are_reported_largest_first_up_to_the_maximum() {
+        let images = vec![
+            image("small:1", 10),
+            image("large:1", 1000),
+            image("medium:2", 100),
+            image("medium:1", 100),
+        ];
+        let reported = status_images(images.clone(), None);
+        assert_eq!(
+            names(&reported),
+            vec!["large:1", "medium:1", "medium:2", "small:1"]
+        );
+        assert_eq!(reported[0].size_bytes, Some(1000));
+
+        let reported = status_images(images.clone(), Some(2));
+        assert_eq!(names(&reported), vec!["large:1", "medium:1"]);
+
+        assert!(status_images(images, Some(0)).is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+//! Delaying shutdowns of the host until the node's pods are drained.
+//!
+//! On hosts that reboot on their own, such as edge devices installing
+//! updates, the node would otherwise go down with its pods still running.
+//! With `shutdown_inhibitor` set, the kubelet takes a delay inhibitor lock
+//! from systemd-logind when it starts. When the host is about to shut down or
+//! reboot, logind announces it with the `PrepareForShutdown` signal and waits
+//! for the lock to be released. The node is then shut down as on SIGTERM, and
+//! the lock is released once its pods have been drained, letting the host's
+//! shutdown go on. logind waits for at most its `InhibitDelayMaxSec`
+//! setting, so this should be at least the shutdown grace period.
+//!
+//! Talking to logind needs the `systemd` feature. Without it, the inhibitor
+//! can't be created.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::pod::event::{record_node_event, EventType};
+#[cfg(feature = "systemd")]
+use logind::{InhibitorLock, Logind};
+// Without the `systemd` feature, logind can't be reached
+#[cfg(not(feature = "systemd"))]
+use unsupported::{InhibitorLock, Logind};
+
+/// The reason of the event recorded when the host starts to shut down
+const SHUTDOWN_DETECTED: &str = "ShutdownDetected";
+/// The reason of the event recorded when the node's pods have been drained
+/// and the host's shutdown goes on
+const DRAIN_COMPLETE: &str = "DrainComplete";
+
+/// Holds back shutdowns of the host until the node's pods are drained
+pub struct ShutdownInhibitor {
+    client: kube::Client,
+    node_name: String,
+    logind: Logind,
+    /// Closing the lock lets the host's shutdown go on
+    lock: Mutex<Option<InhibitorLock>>,
+}
+
+impl ShutdownInhibitor {
+    /// Takes a delay inhibitor lock on shutdowns of the host
+    pub async fn new(client: &kube::Client, config: &Config) -> anyhow::Result<Self> {
+        let logind = Logind::connect()?;
+        let max_delay = logind.max_delay()?;
+        if max_delay < config.shutdown_grace_period {
+            warn!(
+                "systemd-logind delays shutdowns for at most {:?}, which is shorter than the shutdown grace period of {:?}. Raise InhibitDelayMaxSec in logind.conf to give pods all of their grace period",
+                max_delay, config.shutdown_grace_period
+            );
+        }
+        let lock = logind.inhibit()?;
+        info!(
+            "Delaying shutdowns of the host by up to {:?} to drain the node",
+            max_delay
+        );
+        Ok(ShutdownInhibitor {
+            client: client.clone(),
+            node_name: config.node_name.clone(),
+            logind,
+            lock: Mutex::new(Some(lock)),
+        })
+    }
+
+    /// Waits until the host starts to shut down, recording an event on the
+    /// node when it does
+    pub async fn wait_for_shutdown(&self) -> anyhow::Result<()> {
+        // Receiving from the bus blocks, so it is done on a thread of its
+        // own, which unlike the blocking tasks of the runtime doesn't hold
+        // up the kubelet exiting
+        let (tx, rx) = oneshot::channel();
+        let logind = self.logind.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(logind.wait_for_shutdown());
+        });
+        rx.await??;
+
+        warn!("Host is shutting down, draining the node");
+        if let Err(e) = record_node_event(
+            &self.client,
+            &self.node_name,
+            EventType::Warning,
+            SHUTDOWN_DETECTED,
+            "Host is shutting down, draining the node before the shutdown goes on",
+        )
+        .await
+        {
+            warn!(
+                "Unable to record event for node {}: {:?}",
+                self.node_name, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Releases the lock once the node's pods have been drained, letting the
+    /// host's shutdown go on
+    pub async fn release(&self) {
+        // The lock is held until the end of this block, so that the event is
+        // recorded before the host's shutdown goes on
+        {
+            let _lock = match self.take_lock() {
+                Some(lock) => lock,
+                None => return,
+            };
+            if let Err(e) = record_node_event(
+                &self.client,
+                &self.node_name,
+                EventType::Normal,
+                DRAIN_COMPLETE,
+                "Node's pods have been drained, letting the host's shutdown go on",
+            )
+            .await
+            {
+                warn!(
+                    "Unable to record event for node {}: {:?}",
+                    self.node_name, e
+                );
+            }
+        }
+        info!("Released the shutdown inhibitor lock");
+    }
+
+    /// Releases the lock without the node being drained, such as when
+    /// shutdowns of the host can no longer be watched for, so that they
+    /// aren't held back for nothing
+    pub fn stop(&self) {
+        if self.take_lock().is_some() {
+            warn!("Released the shutdown inhibitor lock, shutdowns of the host no longer drain the node");
+        }
+    }
+
+    fn take_lock(&self) -> Option<InhibitorLock> {
+        self.lock
+            .lock()
+            .expect("shutdown inhibitor lock should not be poisoned")
+            .take()
+    }
+}
+
+#[cfg(feature = "systemd")]
+mod logind {
+    use super::*;
+
+    const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+    const LOGIND_PATH: &str = "/org/freedesktop/login1";
+    const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+    /// The signal logind sends with `true` when the host starts to shut down,
+    /// and with `false` if the shutdown is cancelled
+    const PREPARE_FOR_SHUTDOWN: &str = "PrepareForShutdown";
+
+    /// Shutdowns are delayed for as long as this file is open
+    pub(super) type InhibitorLock = zvariant::OwnedFd;
+
+    /// A connection to systemd-logind on the system bus
+    #[derive(Clone)]
+    pub(super) struct Logind {
+        connection: zbus::Connection,
+    }
+
+    impl Logind {
+        pub(super) fn connect() -> anyhow::Result<Self> {
+            let connection = zbus::Connection::new_system().map_err(|e| {
+                anyhow::anyhow!(
+                    "unable to connect to the system bus for systemd-logind: {}",
+                    e
+                )
+            })?;
+            // Listen for the signal before the lock is taken, so that it isn't
+            // missed
+            zbus::fdo::DBusProxy::new(&connection)?.add_match(&format!(
+                "type='signal',interface='{}',member='{}'",
+                LOGIND_MANAGER, PREPARE_FOR_SHUTDOWN
+            ))?;
+            Ok(Logind { connection })
+        }
+
+        fn manager(&self) -> zbus::Result<zbus::Proxy<'_>> {
+            zbus::Proxy::new(
+                &self.connection,
+                LOGIND_DESTINATION,
+                LOGIND_PATH,
+                LOGIND_MANAGER,
+            )
+        }
+
+        /// The longest logind waits for delay locks to be released
+        pub(super) fn max_delay(&self) -> anyhow::Result<Duration> {
+            let usec: u64 = self.manager()?.get_property("InhibitDelayMaxUSec")?;
+            Ok(Duration::from_micros(usec))
+        }
+
+        pub(super) fn inhibit(&self) -> anyhow::Result<InhibitorLock> {
+            let lock = self.manager()?.call(
+                "Inhibit",
+                &("shutdown", "krustlet", "Draining the node's pods", "delay"),
+            )?;
+            Ok(lock)
+        }
+
+        /// Blocks until the host starts to shut down
+        pub(super) fn wait_for_shutdown(&self) -> anyhow::Result<()> {
+            loop {
+                let message = self.connection.receive_message()?;
+                let header = message.header()?;
+                if header.message_type()? == zbus::MessageType::Signal
+                    && header.interface()? == Some(LOGIND_MANAGER)
+                    && header.member()? == Some(PREPARE_FOR_SHUTDOWN)
+                    && message.body::<bool>()?
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+mod unsupported {
+    use super::*;
+
+    pub(super) struct InhibitorLock;
+
+    #[derive(Clone)]
+    pub(super) struct Logind;
+
+    impl Logind {
+        pub(super) fn connect() -> anyhow::Result<Self> {
+            Err(anyhow::anyhow!(
+                "the shutdown inhibitor needs krustlet to be built with the systemd feature"
+            ))
+        }
+
+        pub(super) fn max_delay(&self) -> anyhow::Result<Duration> {
+            unreachable!("logind can't be connected to without the systemd feature")
+        }
+
+        pub(super) fn inhibit(&self) -> anyhow::Result<InhibitorLock> {
+            unreachable!("logind can't be connected to without the systemd feature")
+        }
+
+        pub(super) fn wait_for_shutdown(&self) -> anyhow::Result<()> {
+            unreachable!("logind can't be connected to without the systemd feature")
+        }
+    }
+}
@@ -2,7 +2,8 @@
 //! nodes operating within the cluster.
 use crate::config::Config;
 use crate::container::Status as ContainerStatus;
-use crate::pod::{Phase, Pod};
+use crate::pod::event::{record_event, EventType};
+use crate::pod::{admission, Phase, Pod};
 use crate::provider::Provider;
 use chrono::prelude::*;
 use futures::{StreamExt, TryStreamExt};
@@ -11,13 +12,37 @@ use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
-use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
+use kube::api::{Api, DeleteParams, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::Error;
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, warn};
 
+mod capacity;
+mod disruption;
+mod heartbeat;
+mod images;
+mod inhibitor;
+mod pressure;
+mod shutdown;
+mod system_info;
+
+pub(crate) use capacity::{
+    filesystem_stats, parse_meminfo, parse_reserved, read_meminfo, ResourceDetector,
+};
+pub use disruption::{check_disruption_budgets, DisruptionBudgetViolation};
+pub(crate) use heartbeat::{
+    renew_lease_periodically, reregister_when_missing, update_status_periodically,
+};
+pub(crate) use images::ImageLister;
+pub use inhibitor::ShutdownInhibitor;
+pub(crate) use pressure::{ConditionManager, EvictionThreshold};
+pub use shutdown::{
+    is_shutting_down, shutdown, ShutdownGracePeriods, NODE_SHUTDOWN_REASON,
+    SYSTEM_CRITICAL_PRIORITY,
+};
+
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 macro_rules! retry {
@@ -65,13 +90,20 @@ macro_rules! retry {
 /// A node comes with a lease, and we maintain the lease to tell Kubernetes that the
 /// node remains alive and functional. Note that this will not work in
 /// versions of Kubernetes prior to 1.14.
+///
+/// If the node already exists, it is given the labels it would be created
+/// with, so that labels changed since it was created take effect. Its taints
+/// are left as they are, as other controllers manage some of them.
 pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provider: Arc<P>) {
     let node_client: Api<KubeNode> = Api::all(client.clone());
+    let node = node_definition(config, provider).await;
 
     match retry!(node_client.get(&config.node_name).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 404, .. }))
     {
-        Ok(_) => {
-            debug!("Node already exists, skipping node creation");
+        Ok(existing) => {
+            debug!("Node already exists, updating its labels");
+            update_labels(&node_client, &node).await;
+            set_missing_provider_id(&node_client, &existing, &node).await;
             return;
         }
         Err(Error::Api(ErrorResponse { code: 404, .. })) => (),
@@ -84,6 +116,35 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
         }
     };
 
+    match retry!(node_client.create(&PostParams::default(), &node).await, times: 4) {
+        Ok(node) => {
+            let node_uid = node.metadata.uid.unwrap();
+            if let Err(e) = create_lease(
+                &node_uid,
+                &config.node_name,
+                config.node_lease_duration,
+                client,
+            )
+            .await
+            {
+                error!("Failed to create lease: {}", e);
+                return;
+            }
+        }
+        Err(e) => {
+            error!(
+                "Exhausted retries creating node after failed create: {}. Not retrying.",
+                e
+            );
+            return;
+        }
+    };
+
+    info!("Successfully created node '{}'", &config.node_name);
+}
+
+/// The node as it is registered, with the provider's additions
+async fn node_definition<P: Provider>(config: &Config, provider: Arc<P>) -> KubeNode {
     let mut builder = Node::builder();
 
     builder.set_name(&config.node_name);
@@ -95,21 +156,31 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     );
 
     node_labels_definition(P::ARCH, &config, &mut builder);
+    for taint in &config.register_with_taints {
+        builder.add_taint(
+            &taint.effect,
+            &taint.key,
+            taint.value.as_deref().unwrap_or_default(),
+        );
+    }
+
+    let extended = provider.node_resources();
+    crate::extended_resources::set_capacity(&extended);
+    let mut resources = ResourceDetector::new(config).detect();
+    resources.add_extended(&extended);
+    for (resource, quantity) in &resources.capacity {
+        builder.add_capacity(resource, quantity);
+    }
+    for (resource, quantity) in &resources.allocatable {
+        builder.add_allocatable(resource, quantity);
+    }
 
-    // TODO Do we want to detect this?
-    builder.add_capacity("cpu", "4");
-    builder.add_capacity("ephemeral-storage", "61255492Ki");
-    builder.add_capacity("hugepages-1Gi", "0");
-    builder.add_capacity("hugepages-2Mi", "0");
-    builder.add_capacity("memory", "4032800Ki");
-    builder.add_capacity("pods", &config.max_pods.to_string());
-
-    builder.add_allocatable("cpu", "4");
-    builder.add_allocatable("ephemeral-storage", "61255492Ki");
-    builder.add_allocatable("hugepages-1Gi", "0");
-    builder.add_allocatable("hugepages-2Mi", "0");
-    builder.add_allocatable("memory", "4032800Ki");
-    builder.add_allocatable("pods", &config.max_pods.to_string());
+    let huge_pages = crate::hugepages::system_capacity();
+    for size in &crate::hugepages::HugePageSize::ALL {
+        let bytes = huge_pages.get(size).copied().unwrap_or(0) * size.bytes();
+        builder.add_capacity(size.resource_name(), &bytes.to_string());
+        builder.add_allocatable(size.resource_name(), &bytes.to_string());
+    }
 
     let ts = Utc::now();
     builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
@@ -121,35 +192,85 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
         "kubelet has sufficient disk space available",
     );
 
-    builder.add_address("InternalIP", &format!("{}", config.node_ip));
-    builder.add_address("Hostname", &config.hostname);
+    node_addresses_definition(config, &mut builder);
 
     builder.set_port(config.server_config.port as i32);
 
+    let system_info = system_info::SystemInfo::detect();
+    builder.set_architecture(&system_info.architecture);
+    builder.set_operating_system(&system_info.operating_system);
+    builder.set_kernel_version(&system_info.kernel_version);
+    builder.set_os_image(&system_info.os_image);
+    builder.set_container_runtime_version(&provider.container_runtime_version());
+    if let Some(provider_id) = &config.provider_id {
+        builder.set_provider_id(provider_id);
+    }
+
     match provider.node(&mut builder).await {
         Ok(()) => (),
         Err(e) => warn!("Provider node annotation error: {:?}", e),
     }
 
-    let node = builder.build().into_inner();
-    match retry!(node_client.create(&PostParams::default(), &node).await, times: 4) {
-        Ok(node) => {
-            let node_uid = node.metadata.uid.unwrap();
-            if let Err(e) = create_lease(&node_uid, &config.node_name, &client).await {
-                error!("Failed to create lease: {}", e);
-                return;
-            }
+    builder.build().into_inner()
+}
+
+/// Updates the labels of an existing node to those of its definition.
+/// Labels that aren't in the definition are left alone.
+async fn update_labels(node_client: &Api<KubeNode>, node: &KubeNode) {
+    let name = node.metadata.name.as_deref().unwrap_or_default();
+    let patch = serde_json::json!({
+        "metadata": {
+            "labels": node.metadata.labels,
         }
-        Err(e) => {
-            error!(
-                "Exhausted retries creating node after failed create: {}. Not retrying.",
-                e
+    });
+    match retry!(node_client.patch(name, &PatchParams::default(), &kube::api::Patch::Strategic(patch.clone())).await, times: 4)
+    {
+        Ok(_) => info!("Updated labels of node '{}'", name),
+        Err(e) => error!("Unable to update labels of node '{}': {}", name, e),
+    }
+}
+
+/// Sets the provider ID of an existing node if it has none. A provider ID
+/// can't be changed once set, and one set by a cloud controller manager is
+/// left alone.
+async fn set_missing_provider_id(
+    node_client: &Api<KubeNode>,
+    existing: &KubeNode,
+    node: &KubeNode,
+) {
+    let provider_id = match node
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.provider_id.as_ref())
+    {
+        Some(provider_id) => provider_id,
+        None => return,
+    };
+    let existing_id = existing
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.provider_id.as_deref())
+        .filter(|id| !id.is_empty());
+    if let Some(existing_id) = existing_id {
+        if existing_id != provider_id {
+            warn!(
+                "Node already has provider ID {}, leaving it instead of setting {}",
+                existing_id, provider_id
             );
-            return;
         }
-    };
-
-    info!("Successfully created node '{}'", &config.node_name);
+        return;
+    }
+    let name = node.metadata.name.as_deref().unwrap_or_default();
+    let patch = serde_json::json!({
+        "spec": {
+            "providerID": provider_id,
+        }
+    });
+    match retry!(node_client.patch(name, &PatchParams::default(), &kube::api::Patch::Strategic(patch.clone())).await, times: 4)
+    {
+        Ok(_) => info!("Set provider ID of node '{}' to {}", name, provider_id),
+        Err(e) => error!("Unable to set provider ID of node '{}': {}", name, e),
+    }
 }
 
 /// Fetch the uid of a node by name.
@@ -173,14 +294,45 @@ pub async fn uid(client: &kube::Client, node_name: &str) -> anyhow::Result<Strin
     }
 }
 
-/// Cordons node and evicts all pods.
+/// Cordons node and evicts all pods. Pods are only evicted once their
+/// PodDisruptionBudgets allow it.
 pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
-    evict_pods(client, node_name).await?;
+    cordon(client, node_name).await?;
+    evict_pods(client, node_name, &ShutdownGracePeriods::default(), true).await?;
+    Ok(())
+}
+
+/// Marks the node as unschedulable, so that no new pods are scheduled to it.
+pub async fn cordon(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let patch = serde_json::json!({
+        "spec": {
+            "unschedulable": true
+        }
+    });
+    node_client
+        .patch(
+            node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(patch),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to cordon node: {}", e))?;
+    info!("Node '{}' cordoned.", node_name);
     Ok(())
 }
 
-/// Fetches list of pods on this node and deletes them.
-pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+/// Fetches list of pods on this node and deletes them, lowest priority
+/// first. Each pod is given the smaller of its termination grace period and
+/// what remains of the shutdown grace period for its kind of pod. If
+/// `respect_disruption_budgets` is set, the eviction is voluntary, and each
+/// pod waits for its PodDisruptionBudgets to allow its eviction.
+pub async fn evict_pods(
+    client: &kube::Client,
+    node_name: &str,
+    grace_periods: &ShutdownGracePeriods,
+    respect_disruption_budgets: bool,
+) -> anyhow::Result<()> {
     let pod_client: Api<KubePod> = Api::all(client.clone());
     let node_selector = format!("spec.nodeName={}", node_name);
     let params = ListParams {
@@ -196,6 +348,8 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
 
     info!("Evicting {} pods.", pods.len());
 
+    let mut regular_pods = vec![];
+    let mut critical_pods = vec![];
     for pod in pods {
         let pod = Pod::from(pod);
         if pod.is_daemonset() {
@@ -215,7 +369,10 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
                             ContainerStatus::Terminated {
                                 timestamp: Utc::now(),
                                 message: "Evicted on node shutdown".to_string(),
-                                failed: false
+                                failed: false,
+                                reason: None,
+                                started_at: None,
+                                exit_code: None,
                             }.to_kubernetes(container.name())
                         }).collect::<Vec<KubeContainerStatus>>()
                     }
@@ -231,15 +388,99 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
             info!("Marked static pod as terminated.");
             continue;
         } else {
-            match evict_pod(&client, pod.name(), pod.namespace(), &mut stream).await {
-                Ok(_) => (),
-                Err(e) => {
-                    // Absorb the error and attempt to delete other pods with best effort.
-                    error!("Error evicting pod: {:?}", e)
-                }
+            let priority = admission::priority(client, &pod).await;
+            if priority >= shutdown::SYSTEM_CRITICAL_PRIORITY {
+                critical_pods.push((priority, pod));
+            } else {
+                regular_pods.push((priority, pod));
             }
         }
     }
+
+    // System-critical pods are evicted last, so that the node keeps working
+    // while the other pods stop
+    evict_in_order(
+        client,
+        regular_pods,
+        grace_periods.regular_pods(),
+        respect_disruption_budgets,
+        &mut stream,
+    )
+    .await;
+    evict_in_order(
+        client,
+        critical_pods,
+        grace_periods.critical_pods(),
+        respect_disruption_budgets,
+        &mut stream,
+    )
+    .await;
+    Ok(())
+}
+
+/// Evicts the lowest priority pod on the node, as the node is low on the
+/// resources. Static, DaemonSet and system-critical pods aren't evicted. As
+/// with other kubelets, one pod is evicted at a time: while an evicted pod
+/// is still stopping, no more are evicted.
+pub(crate) async fn evict_for_pressure(
+    client: &kube::Client,
+    node_name: &str,
+    resources: &[&str],
+) -> anyhow::Result<()> {
+    let pod_client: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let mut candidates = vec![];
+    for pod in pod_client
+        .list(&params)
+        .await?
+        .items
+        .into_iter()
+        .map(Pod::from)
+    {
+        let finished = matches!(
+            pod.as_kube_pod()
+                .status
+                .as_ref()
+                .and_then(|status| status.phase.as_deref()),
+            Some("Succeeded") | Some("Failed")
+        );
+        if finished || pod.is_static() || pod.is_daemonset() {
+            continue;
+        }
+        if pod.deletion_timestamp().is_some() {
+            debug!(
+                "Pod '{}' is stopping, evicting no more pods until it has",
+                pod.name()
+            );
+            return Ok(());
+        }
+        let priority = admission::priority(client, &pod).await;
+        if priority < shutdown::SYSTEM_CRITICAL_PRIORITY {
+            candidates.push((priority, pod));
+        }
+    }
+    let pod = match shutdown::eviction_order(candidates)
+        .into_iter()
+        .next()
+        .and_then(|(_, group)| group.into_iter().next())
+    {
+        Some(pod) => pod,
+        None => {
+            warn!(
+                "Node '{}' is low on {} but has no pods that can be evicted",
+                node_name,
+                resources.join(", ")
+            );
+            return Ok(());
+        }
+    };
+    let message = format!("The node was low on resource: {}.", resources.join(", "));
+    if let Err(e) = record_event(client, &pod, EventType::Warning, "Evicted", &message).await {
+        warn!("Unable to record eviction of pod '{}': {:?}", pod.name(), e);
+    }
+    // The node can't wait for the pod's grace period while it is low on
+    // resources
+    evict_pod(client, &pod, std::time::Duration::from_secs(0)).await?;
     Ok(())
 }
 
@@ -250,68 +491,145 @@ type PodStream = std::pin::Pin<
     >,
 >;
 
+/// Evicts the pods lowest priority first, waiting for the pods of each
+/// priority to be deleted before evicting the next. If `grace_period` is
+/// set, all the pods are given that long to stop. If
+/// `respect_disruption_budgets` is set, pods whose PodDisruptionBudgets
+/// forbid their eviction aren't evicted until they allow it.
+async fn evict_in_order(
+    client: &kube::Client,
+    pods: Vec<(i32, Pod)>,
+    grace_period: Option<std::time::Duration>,
+    respect_disruption_budgets: bool,
+    stream: &mut PodStream,
+) {
+    let deadline = grace_period.map(|period| tokio::time::Instant::now() + period);
+    let remaining = || deadline.map(|d| d.saturating_duration_since(tokio::time::Instant::now()));
+    for (priority, group) in shutdown::eviction_order(pods) {
+        info!("Evicting {} pods with priority {}.", group.len(), priority);
+        // Pods waiting for their disruption budgets don't hold up the
+        // others
+        let evictions = group.iter().map(|pod| async move {
+            if respect_disruption_budgets {
+                disruption::wait_for_disruption_budgets(client, pod, deadline).await?;
+            }
+            let grace = match remaining() {
+                Some(remaining) => pod.termination_grace_period().min(remaining),
+                None => pod.termination_grace_period(),
+            };
+            evict_pod(client, pod, grace).await
+        });
+        let results = futures::future::join_all(evictions).await;
+        let mut pending = vec![];
+        for (pod, result) in group.iter().zip(results) {
+            match result {
+                Ok(true) => pending.push((pod.namespace().to_owned(), pod.name().to_owned())),
+                Ok(false) => info!("Pod '{}' evicted.", pod.name()),
+                // Absorb the error and attempt to delete other pods with best effort.
+                Err(e) => error!("Error evicting pod: {:?}", e),
+            }
+        }
+        let remaining = remaining();
+        let waiting = wait_for_deletion(&mut pending, stream);
+        let result = match remaining {
+            Some(remaining) => tokio::time::timeout(remaining, waiting).await.ok(),
+            None => Some(waiting.await),
+        };
+        match result {
+            Some(Ok(())) => (),
+            Some(Err(e)) => error!("Error waiting for pods to be evicted: {:?}", e),
+            None => warn!(
+                "{} pods with priority {} did not stop within the shutdown grace period.",
+                pending.len(),
+                priority
+            ),
+        }
+    }
+}
+
+/// Deletes the pod, giving it `grace_period` to stop. Returns whether the
+/// deletion is pending until the pod has stopped.
 async fn evict_pod(
     client: &kube::Client,
-    name: &str,
-    namespace: &str,
+    pod: &Pod,
+    grace_period: std::time::Duration,
+) -> anyhow::Result<bool> {
+    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    info!(
+        "Evicting namespace '{}' pod '{}' with a grace period of {:?}",
+        pod.namespace(),
+        pod.name(),
+        grace_period
+    );
+    let params = DeleteParams {
+        // A grace period of zero would delete the pod without it being
+        // stopped
+        grace_period_seconds: Some(grace_period.as_secs().max(1) as u32),
+        ..Default::default()
+    };
+    let response = ns_client.delete(pod.name(), &params).await?;
+    Ok(response.is_left())
+}
+
+/// Waits for the pending pods, by namespace and name, to be deleted
+async fn wait_for_deletion(
+    pending: &mut Vec<(String, String)>,
     stream: &mut PodStream,
 ) -> anyhow::Result<()> {
-    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), namespace);
-    info!("Evicting namespace '{}' pod '{}'", namespace, name);
-    let params = Default::default();
-    let response = ns_client.delete(name, &params).await?;
-
-    if response.is_left() {
-        // TODO Timeout?
-        info!("Waiting for pod '{}' eviction.", name);
-        while let Some(event) = stream.try_next().await? {
-            if let kube::api::WatchEvent::Deleted(s) = event {
+    while !pending.is_empty() {
+        info!("Waiting for {} pods to be evicted.", pending.len());
+        match stream.try_next().await? {
+            Some(kube::api::WatchEvent::Deleted(s)) => {
                 let pod = Pod::from(s);
-                if name == pod.name() && namespace == pod.namespace() {
-                    info!("Pod '{}' evicted.", name);
-                    break;
+                if let Some(index) = pending.iter().position(|(namespace, name)| {
+                    name == pod.name() && namespace == pod.namespace()
+                }) {
+                    info!("Pod '{}' evicted.", pod.name());
+                    pending.remove(index);
                 }
             }
+            Some(_) => (),
+            None => break,
         }
-    } else {
-        info!("Pod '{}' evicted.", name);
     }
     Ok(())
 }
 
-/// Update the timestamps on the Node object.
-///
-/// This is how we report liveness to the upstream.
-/// If we are unable to update the node after several retries we panic, as we could be in an
-/// inconsistent state
-pub async fn update(client: &kube::Client, node_name: &str) {
-    debug!("Updating node '{}'", node_name);
-    if let Ok(uid) = uid(client, node_name).await {
-        debug!("Node to update '{}' fetched.", node_name);
-        retry!(update_lease(&uid, node_name, client).await, times: 4)
-            .expect("Could not update lease");
-        retry!(update_status(node_name, client).await, times: 4)
-            .expect("Could not update node status");
+/// Deletes the node from the API server, as it is shutting down and should
+/// not be kept around as `NotReady`. Its lease is deleted with it.
+pub async fn delete(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    match node_client
+        .delete(node_name, &DeleteParams::default())
+        .await
+    {
+        Ok(_) => (),
+        // The node may have been deleted already
+        Err(Error::Api(ErrorResponse { code: 404, .. })) => (),
+        Err(e) => return Err(anyhow::anyhow!("Unable to delete node: {}", e)),
     }
+    info!("Node '{}' deregistered.", node_name);
+    Ok(())
 }
 
-async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result<()> {
-    // TODO: Update the lastTransitionTime properly
+/// Reports the node as not ready, as it is shutting down.
+pub async fn set_not_ready(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
     let status_patch = serde_json::json!({
         "status": {
             "conditions": [
                 {
                     "lastHeartbeatTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-                    "message": "kubelet is posting ready status",
-                    "reason": "KubeletReady",
-                    "status": "True",
+                    "lastTransitionTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                    "message": "node is shutting down",
+                    "reason": "KubeletNotReady",
+                    "status": "False",
                     "type": "Ready"
                 }
             ],
         }
     });
     let node_client: Api<KubeNode> = Api::all(client.clone());
-    let _node = node_client
+    node_client
         .patch_status(
             node_name,
             &PatchParams::default(),
@@ -319,6 +637,7 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
         )
         .await
         .map_err(|e| anyhow::anyhow!("Unable to patch node status: {}", e))?;
+    info!("Node '{}' reported as not ready.", node_name);
     Ok(())
 }
 
@@ -330,11 +649,16 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
 ///
 /// As far as I can tell, leases ALWAYS go in the 'kube-node-lease'
 /// namespace, no exceptions.
-async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) -> Result<(), Error> {
+async fn create_lease(
+    node_uid: &str,
+    node_name: &str,
+    lease_duration: std::time::Duration,
+    client: &kube::Client,
+) -> Result<(), Error> {
     debug!("Creating lease for node '{}'", node_name);
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let lease = lease_definition(node_uid, node_name, lease_duration);
     let lease = serde_json::from_value(lease)
         .expect("failed to deserialize lease from lease definition JSON");
 
@@ -364,19 +688,24 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
 }
 
 /// Update the Kubernetes node lease, essentially requesting that we keep
-/// the lease for another period.
-///
-/// TODO: Our patch is overzealous right now. We just need to update the
-/// timestamp.
+/// the lease for another period. Only the renewal time and duration are
+/// patched.
 async fn update_lease(
-    node_uid: &str,
     node_name: &str,
+    lease_duration: std::time::Duration,
     client: &kube::Client,
 ) -> Result<Lease, Error> {
     debug!("Updating lease for node '{}'...", node_name);
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    let lease = serde_json::json!({
+        "spec": {
+            "holderIdentity": node_name,
+            "renewTime": now,
+            "leaseDurationSeconds": lease_duration.as_secs(),
+        }
+    });
 
     let resp = leases
         .patch(
@@ -387,7 +716,7 @@ async fn update_lease(
         .await;
     match &resp {
         Ok(_) => debug!("Lease updated for '{}'", node_name),
-        Err(e) => error!("Failed to update lease for '{}': {}", node_name, e),
+        Err(e) => debug!("Failed to update lease for '{}': {}", node_name, e),
     }
     resp
 }
@@ -397,7 +726,11 @@ async fn update_lease(
 /// The lease tells Kubernetes that we want to claim the node for a while
 /// longer. And then tells Kubernetes how long it should wait before
 /// expecting a new lease.
-fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
+fn lease_definition(
+    node_uid: &str,
+    node_name: &str,
+    lease_duration: std::time::Duration,
+) -> serde_json::Value {
     serde_json::json!(
         {
             "apiVersion": "coordination.k8s.io/v1",
@@ -413,7 +746,7 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
                     }
                 ]
             },
-            "spec": lease_spec_definition(node_name)
+            "spec": lease_spec_definition(node_name, lease_duration)
         }
     )
 }
@@ -421,7 +754,10 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
 /// Defines a new coordiation lease for Kubernetes
 ///
 /// We set the lease times, the lease duration, and the node name.
-fn lease_spec_definition(node_name: &str) -> serde_json::Value {
+fn lease_spec_definition(
+    node_name: &str,
+    lease_duration: std::time::Duration,
+) -> serde_json::Value {
     // Workaround for https://github.com/deislabs/krustlet/issues/5
     // In the future, use LeaseSpec rather than a JSON value
     let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
@@ -431,10 +767,18 @@ fn lease_spec_definition(node_name: &str) -> serde_json::Value {
             "holderIdentity": node_name,
             "acquireTime": now,
             "renewTime": now,
-            "leaseDurationSeconds": 300
+            "leaseDurationSeconds": lease_duration.as_secs()
         }
     )
 }
+/// Defines the node's addresses, with an `InternalIP` for each of its IP
+/// addresses, the primary one first, followed by its `Hostname`
+fn node_addresses_definition(config: &Config, builder: &mut Builder) {
+    for node_ip in &config.node_ips {
+        builder.add_address("InternalIP", &node_ip.to_string());
+    }
+    builder.add_address("Hostname", &config.hostname);
+}
 
 /// Defines the labels that will be applied to this node
 ///
@@ -524,11 +868,14 @@ pub struct Builder {
     labels: BTreeMap<String, String>,
     pod_cidr: String,
     taints: Vec<k8s_openapi::api::core::v1::Taint>,
+    provider_id: Option<String>,
     architecture: String,
     kube_proxy_version: String,
     kubelet_version: String,
     container_runtime_version: String,
     operating_system: String,
+    kernel_version: String,
+    os_image: String,
     capacity: BTreeMap<String, k8s_openapi::apimachinery::pkg::api::resource::Quantity>,
     allocatable: BTreeMap<String, k8s_openapi::apimachinery::pkg::api::resource::Quantity>,
     port: i32,
@@ -562,8 +909,11 @@ impl Builder {
         self.pod_cidr = cidr.to_string();
     }
 
-    /// Add a taint to the node.
+    /// Add a taint to the node, in place of any taint with the same key and
+    /// effect.
     pub fn add_taint(&mut self, effect: &str, key: &str, value: &str) {
+        self.taints
+            .retain(|taint| taint.key != key || taint.effect != effect);
         self.taints.push(k8s_openapi::api::core::v1::Taint {
             effect: effect.to_string(),
             key: key.to_string(),
@@ -572,6 +922,12 @@ impl Builder {
         });
     }
 
+    /// Set the ID the node's cloud provider knows it by, such as
+    /// `aws:///us-east-1a/i-0123456789abcdef0`.
+    pub fn set_provider_id(&mut self, provider_id: &str) {
+        self.provider_id = Some(provider_id.to_string());
+    }
+
     /// Set the architecture of the node.
     pub fn set_architecture(&mut self, arch: &str) {
         self.architecture = arch.to_string();
@@ -597,6 +953,16 @@ impl Builder {
         self.operating_system = os.to_string();
     }
 
+    /// Set the kernel version of the node.
+    pub fn set_kernel_version(&mut self, version: &str) {
+        self.kernel_version = version.to_string();
+    }
+
+    /// Set the OS image of the node, such as the name of its distribution.
+    pub fn set_os_image(&mut self, image: &str) {
+        self.os_image = image.to_string();
+    }
+
     /// Add a capacity of the node.
     pub fn add_capacity(&mut self, key: &str, value: &str) {
         self.capacity.insert(
@@ -658,6 +1024,7 @@ impl Builder {
 
         let spec = k8s_openapi::api::core::v1::NodeSpec {
             pod_cidr: Some(self.pod_cidr),
+            provider_id: self.provider_id,
             taints: Some(self.taints),
             ..Default::default()
         };
@@ -668,6 +1035,8 @@ impl Builder {
             kubelet_version: self.kubelet_version,
             container_runtime_version: self.container_runtime_version,
             operating_system: self.operating_system,
+            kernel_version: self.kernel_version,
+            os_image: self.os_image,
             ..Default::default()
         };
 
@@ -702,11 +1071,14 @@ impl Default for Builder {
             labels: BTreeMap::new(),
             pod_cidr: "10.244.0.0/24".to_string(),
             taints: vec![],
-            architecture: "".to_string(),
-            kube_proxy_version: "v1.17.0".to_string(),
-            kubelet_version: KUBELET_VERSION.to_string(),
+            provider_id: None,
+            architecture: system_info::architecture().to_string(),
+            kube_proxy_version: format!("v{}", KUBELET_VERSION),
+            kubelet_version: format!("v{}", KUBELET_VERSION),
             container_runtime_version: "mvp".to_string(),
-            operating_system: "linux".to_string(),
+            operating_system: system_info::operating_system().to_string(),
+            kernel_version: "".to_string(),
+            os_image: "".to_string(),
             capacity: BTreeMap::new(),
             allocatable: BTreeMap::new(),
             port: 10250,
@@ -730,6 +1102,31 @@ mod test {
     use std::net::{IpAddr, Ipv4Addr};
     use std::path::PathBuf;
 
+    #[test]
+    fn taints_take_the_place_of_those_with_the_same_key_and_effect() {
+        let mut builder = Node::builder();
+        builder.add_taint("NoSchedule", "kubernetes.io/arch", "from-config");
+        builder.add_taint("NoExecute", "kubernetes.io/arch", "wasm32-wasi");
+        builder.add_taint("NoSchedule", "kubernetes.io/arch", "wasm32-wasi");
+        let taints: Vec<(String, Option<String>)> = builder
+            .build()
+            .into_inner()
+            .spec
+            .unwrap()
+            .taints
+            .unwrap()
+            .into_iter()
+            .map(|taint| (taint.effect, taint.value))
+            .collect();
+        assert_eq!(
+            taints,
+            vec![
+                ("NoExecute".to_owned(), Some("wasm32-wasi".to_owned())),
+                ("NoSchedule".to_owned(), Some("wasm32-wasi".to_owned())),
+            ]
+        );
+    }
+
     #[test]
     fn test_node_labels_definition() {
         let mut node_labels = HashMap::new();
@@ -749,34 +1146,113 @@ mod test {
         node_labels.insert("beta.kubernetes.io/os".to_owned(), "managed".to_owned());
 
         let config = Config {
+            node_labels,
+            ..test_config()
+        };
+
+        let mut builder = Node::builder();
+        node_labels_definition("linux", &config, &mut builder);
+
+        let result = builder.labels;
+
+        assert!(result.contains_key("foo"));
+        assert!(result.contains_key("kubelet.kubernetes.io/allowed-prefix"));
+        assert!(!result.contains_key("not-allowed.kubernetes.io"));
+        assert!(result.contains_key("kubernetes.io/instance-type"));
+        assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
+        assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
+    }
+
+    #[test]
+    fn node_addresses_have_the_primary_ip_first() {
+        let primary: IpAddr = "fd00::2".parse().unwrap();
+        let secondary: IpAddr = "10.0.0.2".parse().unwrap();
+        let config = Config {
+            node_ip: primary,
+            node_ips: vec![primary, secondary],
+            ..test_config()
+        };
+
+        let mut builder = Node::builder();
+        node_addresses_definition(&config, &mut builder);
+
+        let addresses: Vec<(&str, &str)> = builder
+            .addresses
+            .iter()
+            .map(|address| (address.type_.as_str(), address.address.as_str()))
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![
+                ("InternalIP", "fd00::2"),
+                ("InternalIP", "10.0.0.2"),
+                ("Hostname", "foo"),
+            ]
+        );
+    }
+
+    fn test_config() -> Config {
+        Config {
+            register_with_taints: vec![],
             node_ip: IpAddr::from(Ipv4Addr::LOCALHOST),
+            node_ips: vec![IpAddr::from(Ipv4Addr::LOCALHOST)],
             hostname: String::from("foo"),
             node_name: String::from("bar"),
+            provider_id: None,
             server_config: ServerConfig {
                 addr: IpAddr::from(Ipv4Addr::LOCALHOST),
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                client_ca_file: None,
+                rotate_certificates: false,
+                renewal_threshold_days: None,
+                oidc: None,
+                authentication_token_webhook: false,
+                anonymous_auth: false,
+                authorization_mode: crate::config::AuthorizationMode::AlwaysAllow,
+                read_only_port: 0,
+                max_log_follows: 256,
+                max_exec_sessions: 128,
+                max_port_forwards: 128,
+                streaming_connection_idle_timeout: std::time::Duration::from_secs(4 * 60 * 60),
             },
             bootstrap_file: "doesnt/matter".into(),
+            rotate_certificates: false,
             allow_local_modules: false,
+            secrets_in_memory: false,
+            reregister_node: true,
+            deregister_on_shutdown: false,
             insecure_registries: None,
+            registry_proxy: None,
+            image_verification_key_file: None,
+            pull_progress_interval: std::time::Duration::from_secs(5),
+            readiness_gate_poll_interval: std::time::Duration::from_secs(5),
+            docker_config_file: None,
+            node_status_update_frequency: std::time::Duration::from_secs(300),
+            node_lease_duration: std::time::Duration::from_secs(40),
+            eviction_hard: HashMap::new(),
+            system_reserved: HashMap::new(),
+            kube_reserved: HashMap::new(),
+            feature_gates: crate::feature_gate::FeatureGates::default(),
+            default_container_memory_limit: None,
+            cpu_limit_tick_interval: std::time::Duration::from_millis(10),
+            cpu_manager_policy: crate::cpu_manager::CpuManagerPolicy::None,
+            memory_manager_policy: crate::memory_manager::MemoryManagerPolicy::None,
+            topology_manager_policy: crate::topology_manager::TopologyManagerPolicy::None,
+            ephemeral_storage_check_interval: std::time::Duration::from_secs(10),
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            shutdown_grace_period_critical_pods: std::time::Duration::from_secs(0),
+            shutdown_inhibitor: false,
+            container_log_max_size: 10 * 1024 * 1024,
+            container_log_max_files: 5,
+            node_status_max_images: Some(50),
+            record_transitions: None,
             data_dir: PathBuf::new(),
             plugins_dir: PathBuf::new(),
-            node_labels,
+            device_plugins_dir: PathBuf::new(),
+            node_labels: HashMap::new(),
             max_pods: 110,
-        };
-
-        let mut builder = Node::builder();
-        node_labels_definition("linux", &config, &mut builder);
-
-        let result = builder.labels;
-
-        assert!(result.contains_key("foo"));
-        assert!(result.contains_key("kubelet.kubernetes.io/allowed-prefix"));
-        assert!(!result.contains_key("not-allowed.kubernetes.io"));
-        assert!(result.contains_key("kubernetes.io/instance-type"));
-        assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
-        assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
+        }
     }
 }
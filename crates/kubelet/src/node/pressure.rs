@@ -0,0 +1,585 @@
+//! Pressure conditions of the node.
+//!
+//! Each time the node's status is checked, the memory available on the host,
+//! the free space and inodes of the filesystem the kubelet's data directory
+//! is on, and the process IDs left are sampled and compared with the
+//! `eviction_hard` thresholds. The node reports `MemoryPressure`,
+//! `DiskPressure` or `PIDPressure` while a threshold of a signal behind the
+//! condition is crossed, and for [`PRESSURE_TRANSITION_PERIOD_SECONDS`]
+//! after, so that a node hovering around a threshold doesn't flap. Modules
+//! are stored in the data directory, so the `imagefs` signals observe the
+//! same filesystem as the `nodefs` ones.
+//!
+//! While a threshold is crossed, the node is low on a resource, and its pods
+//! are evicted one at a time until it no longer is.
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use tracing::{debug, info};
+
+use super::capacity::{filesystem_stats, parse_meminfo, read_meminfo};
+use crate::config::Config;
+use crate::resources::parse_quantity;
+
+/// How long a condition stays true after the thresholds behind it are no
+/// longer crossed, as with the default `evictionPressureTransitionPeriod`
+/// of other kubelets
+pub(crate) const PRESSURE_TRANSITION_PERIOD_SECONDS: i64 = 300;
+
+#[cfg(target_os = "linux")]
+const PID_MAX: &str = "/proc/sys/kernel/pid_max";
+#[cfg(target_os = "linux")]
+const LOADAVG: &str = "/proc/loadavg";
+
+/// What an eviction threshold can be set for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Signal {
+    MemoryAvailable,
+    NodefsAvailable,
+    NodefsInodesFree,
+    ImagefsAvailable,
+    ImagefsInodesFree,
+    PidAvailable,
+}
+
+const SIGNALS: &[Signal] = &[
+    Signal::MemoryAvailable,
+    Signal::NodefsAvailable,
+    Signal::NodefsInodesFree,
+    Signal::ImagefsAvailable,
+    Signal::ImagefsInodesFree,
+    Signal::PidAvailable,
+];
+
+impl Signal {
+    fn name(self) -> &'static str {
+        match self {
+            Signal::MemoryAvailable => "memory.available",
+            Signal::NodefsAvailable => "nodefs.available",
+            Signal::NodefsInodesFree => "nodefs.inodesFree",
+            Signal::ImagefsAvailable => "imagefs.available",
+            Signal::ImagefsInodesFree => "imagefs.inodesFree",
+            Signal::PidAvailable => "pid.available",
+        }
+    }
+
+    /// The condition the node reports while the signal's threshold is
+    /// crossed
+    fn pressure(self) -> Pressure {
+        match self {
+            Signal::MemoryAvailable => Pressure::Memory,
+            Signal::PidAvailable => Pressure::Pid,
+            _ => Pressure::Disk,
+        }
+    }
+
+    /// The resource the node is low on while the signal's threshold is
+    /// crossed
+    fn resource(self) -> &'static str {
+        match self {
+            Signal::MemoryAvailable => "memory",
+            Signal::NodefsAvailable | Signal::ImagefsAvailable => "ephemeral-storage",
+            Signal::NodefsInodesFree | Signal::ImagefsInodesFree => "inodes",
+            Signal::PidAvailable => "pids",
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SIGNALS
+            .iter()
+            .copied()
+            .find(|signal| signal.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown signal {}", s))
+    }
+}
+
+/// The pressure conditions the node reports, by what the node is low on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Pressure {
+    Memory,
+    Disk,
+    Pid,
+}
+
+const PRESSURES: &[Pressure] = &[Pressure::Memory, Pressure::Disk, Pressure::Pid];
+
+impl Pressure {
+    fn type_name(self) -> &'static str {
+        match self {
+            Pressure::Memory => "MemoryPressure",
+            Pressure::Disk => "DiskPressure",
+            Pressure::Pid => "PIDPressure",
+        }
+    }
+
+    /// The reason and message of the condition, as other kubelets report them
+    fn reason_and_message(self, under_pressure: bool) -> (&'static str, &'static str) {
+        match (self, under_pressure) {
+            (Pressure::Memory, false) => (
+                "KubeletHasSufficientMemory",
+                "kubelet has sufficient memory available",
+            ),
+            (Pressure::Memory, true) => (
+                "KubeletHasInsufficientMemory",
+                "kubelet has insufficient memory available",
+            ),
+            (Pressure::Disk, false) => ("KubeletHasNoDiskPressure", "kubelet has no disk pressure"),
+            (Pressure::Disk, true) => ("KubeletHasDiskPressure", "kubelet has disk pressure"),
+            (Pressure::Pid, false) => (
+                "KubeletHasSufficientPID",
+                "kubelet has sufficient PID available",
+            ),
+            (Pressure::Pid, true) => (
+                "KubeletHasInsufficientPID",
+                "kubelet has insufficient PID available",
+            ),
+        }
+    }
+}
+
+/// The least of a signal that may be left before the node is under pressure
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Minimum {
+    /// An amount, such as bytes for `memory.available`
+    Quantity(u64),
+    /// A percentage of the capacity
+    Percentage(f64),
+}
+
+/// A hard eviction threshold, such as `memory.available<100Mi`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct EvictionThreshold {
+    signal: Signal,
+    minimum: Minimum,
+}
+
+impl EvictionThreshold {
+    /// Parses the threshold for a signal, such as `memory.available`, from a
+    /// quantity, such as `100Mi`, or a percentage of the capacity, such as
+    /// `10%`
+    pub(crate) fn parse(signal: &str, quantity: &str) -> anyhow::Result<Self> {
+        let signal: Signal = signal.parse()?;
+        let quantity = quantity.trim();
+        if quantity.is_empty() {
+            return Err(anyhow::anyhow!("no quantity for {}", signal.name()));
+        }
+        let minimum =
+            match quantity.strip_suffix('%') {
+                Some(percentage) => match percentage.trim().parse::<f64>() {
+                    Ok(percentage) if (0.0..=100.0).contains(&percentage) => {
+                        Minimum::Percentage(percentage)
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "invalid percentage {} for {}",
+                            quantity,
+                            signal.name()
+                        ))
+                    }
+                },
+                None => Minimum::Quantity(parse_quantity(quantity).map_err(|e| {
+                    anyhow::anyhow!("invalid quantity for {}: {}", signal.name(), e)
+                })?),
+            };
+        Ok(EvictionThreshold { signal, minimum })
+    }
+
+    fn is_crossed(&self, observation: &Observation) -> bool {
+        let minimum = match self.minimum {
+            Minimum::Quantity(quantity) => quantity,
+            Minimum::Percentage(percentage) => {
+                (observation.capacity as f64 * percentage / 100.0) as u64
+            }
+        };
+        observation.available < minimum
+    }
+}
+
+/// How much of a signal is left, out of how much there is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Observation {
+    pub(crate) available: u64,
+    pub(crate) capacity: u64,
+}
+
+/// A condition as it was last observed
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ConditionState {
+    under_pressure: bool,
+    /// When the condition last changed
+    last_transition: DateTime<Utc>,
+}
+
+/// Tracks the node's pressure conditions from samples of the signals behind
+/// them
+#[derive(Clone, Debug)]
+pub(crate) struct ConditionManager {
+    data_dir: PathBuf,
+    thresholds: Vec<EvictionThreshold>,
+    states: BTreeMap<Pressure, ConditionState>,
+    /// When the thresholds behind each condition were last crossed
+    last_crossed: HashMap<Pressure, DateTime<Utc>>,
+    /// The signals whose thresholds were crossed when last observed
+    crossed: Vec<Signal>,
+}
+
+impl ConditionManager {
+    pub(crate) fn new(config: &Config) -> Self {
+        // Thresholds are validated when the configuration is built, so
+        // invalid ones are ignored here
+        let thresholds = config
+            .eviction_hard
+            .iter()
+            .filter_map(|(signal, quantity)| EvictionThreshold::parse(signal, quantity).ok())
+            .collect();
+        Self::with_thresholds(config.data_dir.clone(), thresholds)
+    }
+
+    fn with_thresholds(data_dir: PathBuf, thresholds: Vec<EvictionThreshold>) -> Self {
+        ConditionManager {
+            data_dir,
+            thresholds,
+            states: BTreeMap::new(),
+            last_crossed: HashMap::new(),
+            crossed: vec![],
+        }
+    }
+
+    /// Samples the signals that thresholds are set for. Signals that can't be
+    /// sampled are left out, so that they never put the node under pressure.
+    pub(crate) fn sample(&self) -> HashMap<Signal, Observation> {
+        let mut observations = HashMap::new();
+        for threshold in &self.thresholds {
+            let signal = threshold.signal;
+            if observations.contains_key(&signal) {
+                continue;
+            }
+            match sample_signal(signal, &self.data_dir) {
+                Ok(observation) => {
+                    observations.insert(signal, observation);
+                }
+                // This is sampled often, so it would flood the log as a
+                // warning on hosts where it can never be sampled
+                Err(e) => debug!("Unable to sample {}: {:?}", signal.name(), e),
+            }
+        }
+        observations
+    }
+
+    /// Updates the conditions from what was observed at `now`
+    pub(crate) fn observe(
+        &mut self,
+        observations: &HashMap<Signal, Observation>,
+        now: DateTime<Utc>,
+    ) {
+        self.crossed = SIGNALS
+            .iter()
+            .copied()
+            .filter(|signal| {
+                let observation = match observations.get(signal) {
+                    Some(observation) => observation,
+                    None => return false,
+                };
+                self.thresholds
+                    .iter()
+                    .filter(|threshold| threshold.signal == *signal)
+                    .any(|threshold| threshold.is_crossed(observation))
+            })
+            .collect();
+        for signal in &self.crossed {
+            self.last_crossed.insert(signal.pressure(), now);
+        }
+        let transition_period = chrono::Duration::seconds(PRESSURE_TRANSITION_PERIOD_SECONDS);
+        for pressure in PRESSURES {
+            let under_pressure = self
+                .last_crossed
+                .get(pressure)
+                .is_some_and(|crossed| now - *crossed < transition_period);
+            let state = self.states.entry(*pressure).or_insert(ConditionState {
+                under_pressure,
+                last_transition: now,
+            });
+            if state.under_pressure != under_pressure {
+                info!(
+                    "Node condition {} is now {}",
+                    pressure.type_name(),
+                    under_pressure
+                );
+                *state = ConditionState {
+                    under_pressure,
+                    last_transition: now,
+                };
+            }
+        }
+    }
+
+    /// The resources the node is low on, as the thresholds of their signals
+    /// were crossed when last observed. Pods are evicted while there are any.
+    pub(crate) fn starved_resources(&self) -> Vec<&'static str> {
+        let mut resources = vec![];
+        for signal in &self.crossed {
+            if !resources.contains(&signal.resource()) {
+                resources.push(signal.resource());
+            }
+        }
+        resources
+    }
+
+    /// The pressure conditions as last observed, without heartbeat times,
+    /// which are set when they are reported
+    pub(crate) fn conditions(&self) -> Vec<NodeCondition> {
+        self.states
+            .iter()
+            .map(|(pressure, state)| {
+                let (reason, message) = pressure.reason_and_message(state.under_pressure);
+                NodeCondition {
+                    type_: pressure.type_name().to_owned(),
+                    status: if state.under_pressure {
+                        "True"
+                    } else {
+                        "False"
+                    }
+                    .to_owned(),
+                    reason: Some(reason.to_owned()),
+                    message: Some(message.to_owned()),
+                    last_transition_time: Some(Time(state.last_transition)),
+                    last_heartbeat_time: None,
+                }
+            })
+            .collect()
+    }
+}
+
+fn sample_signal(signal: Signal, data_dir: &std::path::Path) -> anyhow::Result<Observation> {
+    match signal {
+        Signal::MemoryAvailable => {
+            let meminfo = read_meminfo()?;
+            Ok(Observation {
+                available: parse_meminfo(&meminfo, "MemAvailable")?,
+                capacity: parse_meminfo(&meminfo, "MemTotal")?,
+            })
+        }
+        Signal::NodefsAvailable | Signal::ImagefsAvailable => {
+            let stats = filesystem_stats(data_dir)?;
+            Ok(Observation {
+                available: stats.available_bytes,
+                capacity: stats.total_bytes,
+            })
+        }
+        Signal::NodefsInodesFree | Signal::ImagefsInodesFree => {
+            let stats = filesystem_stats(data_dir)?;
+            Ok(Observation {
+                available: stats.available_inodes,
+                capacity: stats.total_inodes,
+            })
+        }
+        Signal::PidAvailable => sample_pids(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sample_pids() -> anyhow::Result<Observation> {
+    let pid_max: u64 = std::fs::read_to_string(PID_MAX)?.trim().parse()?;
+    let processes = parse_process_count(&std::fs::read_to_string(LOADAVG)?)?;
+    Ok(Observation {
+        available: pid_max.saturating_sub(processes),
+        capacity: pid_max,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sample_pids() -> anyhow::Result<Observation> {
+    Err(anyhow::anyhow!("process IDs are only sampled on Linux"))
+}
+
+/// The number of processes and threads on the host, from the fourth field of
+/// `/proc/loadavg`, such as `2/345`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_process_count(loadavg: &str) -> anyhow::Result<u64> {
+    loadavg
+        .split_whitespace()
+        .nth(3)
+        .and_then(|field| field.split('/').nth(1))
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("no process count in loadavg"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn manager(thresholds: &[(&str, &str)]) -> ConditionManager {
+        let thresholds = thresholds
+            .iter()
+            .map(|(signal, quantity)| EvictionThreshold::parse(signal, quantity).unwrap())
+            .collect();
+        ConditionManager::with_thresholds(PathBuf::from("/"), thresholds)
+    }
+
+    fn observations(pairs: &[(Signal, u64, u64)]) -> HashMap<Signal, Observation> {
+        pairs
+            .iter()
+            .map(|(signal, available, capacity)| {
+                (
+                    *signal,
+                    Observation {
+                        available: *available,
+                        capacity: *capacity,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn status(manager: &ConditionManager, condition: &str) -> (String, String, Time) {
+        let condition = manager
+            .conditions()
+            .into_iter()
+            .find(|c| c.type_ == condition)
+            .unwrap();
+        (
+            condition.status,
+            condition.reason.unwrap(),
+            condition.last_transition_time.unwrap(),
+        )
+    }
+
+    const MI: u64 = 1024 * 1024;
+
+    #[test]
+    fn thresholds_are_quantities_or_percentages() {
+        let threshold = EvictionThreshold::parse("memory.available", "100Mi").unwrap();
+        let observation = |available| Observation {
+            available,
+            capacity: 1024 * MI,
+        };
+        assert!(threshold.is_crossed(&observation(99 * MI)));
+        assert!(!threshold.is_crossed(&observation(100 * MI)));
+
+        let threshold = EvictionThreshold::parse("nodefs.available", "10%").unwrap();
+        assert!(threshold.is_crossed(&Observation {
+            available: 9,
+            capacity: 100
+        }));
+        assert!(!threshold.is_crossed(&Observation {
+            available: 10,
+            capacity: 100
+        }));
+
+        assert!(EvictionThreshold::parse("cpu.available", "1").is_err());
+        assert!(EvictionThreshold::parse("memory.available", "").is_err());
+        assert!(EvictionThreshold::parse("memory.available", "lots").is_err());
+        assert!(EvictionThreshold::parse("nodefs.available", "110%").is_err());
+    }
+
+    #[test]
+    fn conditions_follow_the_thresholds_of_their_signals() {
+        let mut manager = manager(&[
+            ("memory.available", "100Mi"),
+            ("nodefs.available", "10%"),
+            ("nodefs.inodesFree", "5%"),
+            ("pid.available", "1000"),
+        ]);
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let healthy = observations(&[
+            (Signal::MemoryAvailable, 512 * MI, 1024 * MI),
+            (Signal::NodefsAvailable, 50, 100),
+            (Signal::NodefsInodesFree, 50, 100),
+            (Signal::PidAvailable, 30_000, 32_768),
+        ]);
+        manager.observe(&healthy, start);
+        for condition in &["MemoryPressure", "DiskPressure", "PIDPressure"] {
+            assert_eq!(status(&manager, condition).0, "False");
+        }
+        assert_eq!(
+            status(&manager, "MemoryPressure").1,
+            "KubeletHasSufficientMemory"
+        );
+        assert!(manager.starved_resources().is_empty());
+
+        let later = start + chrono::Duration::seconds(10);
+        let low_on_disk_and_pids = observations(&[
+            (Signal::MemoryAvailable, 512 * MI, 1024 * MI),
+            (Signal::NodefsAvailable, 50, 100),
+            (Signal::NodefsInodesFree, 4, 100),
+            (Signal::PidAvailable, 999, 32_768),
+        ]);
+        manager.observe(&low_on_disk_and_pids, later);
+        assert_eq!(
+            status(&manager, "DiskPressure"),
+            (
+                "True".to_owned(),
+                "KubeletHasDiskPressure".to_owned(),
+                Time(later)
+            )
+        );
+        assert_eq!(
+            status(&manager, "PIDPressure").1,
+            "KubeletHasInsufficientPID"
+        );
+        assert_eq!(
+            status(&manager, "MemoryPressure"),
+            (
+                "False".to_owned(),
+                "KubeletHasSufficientMemory".to_owned(),
+                Time(start)
+            )
+        );
+        assert_eq!(manager.starved_resources(), vec!["inodes", "pids"]);
+    }
+
+    #[test]
+    fn conditions_stay_true_for_the_transition_period() {
+        let mut manager = manager(&[("memory.available", "100Mi")]);
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let low = observations(&[(Signal::MemoryAvailable, 50 * MI, 1024 * MI)]);
+        let recovered = observations(&[(Signal::MemoryAvailable, 200 * MI, 1024 * MI)]);
+        manager.observe(&low, start);
+        assert_eq!(status(&manager, "MemoryPressure").0, "True");
+        assert_eq!(manager.starved_resources(), vec!["memory"]);
+
+        // Pods are no longer evicted once the threshold isn't crossed, but
+        // the condition stays true in case it is again
+        let during = start + chrono::Duration::seconds(PRESSURE_TRANSITION_PERIOD_SECONDS - 1);
+        manager.observe(&recovered, during);
+        assert_eq!(status(&manager, "MemoryPressure").0, "True");
+        assert!(manager.starved_resources().is_empty());
+
+        let after = start + chrono::Duration::seconds(PRESSURE_TRANSITION_PERIOD_SECONDS);
+        manager.observe(&recovered, after);
+        assert_eq!(
+            status(&manager, "MemoryPressure"),
+            (
+                "False".to_owned(),
+                "KubeletHasSufficientMemory".to_owned(),
+                Time(after)
+            )
+        );
+    }
+
+    #[test]
+    fn signals_that_are_not_sampled_never_cause_pressure() {
+        let mut manager = manager(&[("memory.available", "100Mi")]);
+        manager.observe(&HashMap::new(), Utc::now());
+        assert_eq!(status(&manager, "MemoryPressure").0, "False");
+        assert!(manager.starved_resources().is_empty());
+    }
+
+    #[test]
+    fn process_counts_are_read_from_loadavg() {
+        assert_eq!(
+            parse_process_count("0.52 0.58 0.59 3/467 12345\n").unwrap(),
+            467
+        );
+        assert!(parse_process_count("0.52 0.58 0.59").is_err());
+    }
+}
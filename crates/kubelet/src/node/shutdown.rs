@@ -0,0 +1,163 @@
+//! Graceful shutdown of the node.
+//!
+//! When the kubelet is asked to stop, the node stops accepting new pods and
+//! is cordoned, so that no more pods are scheduled to it. Its lease is no
+//! longer renewed, and the kubelet server reports it as not healthy while it
+//! keeps serving. Its pods are then evicted in order of their priority, the
+//! lowest first, with pods of the same priority evicted together, and report
+//! that they were stopped with the `NodeShutdown` reason. System-critical
+//! pods are evicted last, in the part of the shutdown grace period kept for
+//! them. Finally the node is reported as `NotReady`, or deleted if
+//! `deregister_on_shutdown` is set.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::pod::Pod;
+
+/// The priority of the `system-cluster-critical` priority class. Pods with
+/// at least this priority are system-critical.
+pub const SYSTEM_CRITICAL_PRIORITY: i32 = 2_000_000_000;
+
+/// The reason reported by pods stopped as the node shuts down
+pub const NODE_SHUTDOWN_REASON: &str = "NodeShutdown";
+
+/// Whether the node is shutting down, after which no new pods are admitted
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the node is shutting down, in which case pods that reach it
+/// should be rejected rather than run
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// How long the node's pods are given to stop when the node shuts down
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ShutdownGracePeriods {
+    /// How long all of the pods are given. If zero, pods are given their own
+    /// termination grace periods.
+    pub total: Duration,
+    /// The part of `total` kept for system-critical pods
+    pub critical_pods: Duration,
+}
+
+impl ShutdownGracePeriods {
+    /// The grace periods set in the kubelet's configuration
+    pub fn from_config(config: &Config) -> Self {
+        ShutdownGracePeriods {
+            total: config.shutdown_grace_period,
+            critical_pods: config.shutdown_grace_period_critical_pods,
+        }
+    }
+
+    /// How long the pods that aren't system-critical are given to stop,
+    /// if they are limited
+    pub(crate) fn regular_pods(&self) -> Option<Duration> {
+        self.limited()
+            .map(|total| total.checked_sub(self.critical_pods).unwrap_or_default())
+    }
+
+    /// How long the system-critical pods are given to stop, if they are
+    /// limited
+    pub(crate) fn critical_pods(&self) -> Option<Duration> {
+        self.limited().map(|_| self.critical_pods)
+    }
+
+    fn limited(&self) -> Option<Duration> {
+        Some(self.total).filter(|total| !total.is_zero())
+    }
+}
+
+/// Shuts the node down gracefully: stops accepting pods, cordons the node,
+/// evicts its pods in order of their priority and reports the node as not
+/// ready, or deletes it if the configuration says to.
+pub async fn shutdown(client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    info!("Node is shutting down, no longer accepting pods");
+    if let Err(e) = super::cordon(client, &config.node_name).await {
+        warn!("Unable to cordon node '{}': {:?}", config.node_name, e);
+    }
+    super::evict_pods(
+        client,
+        &config.node_name,
+        &ShutdownGracePeriods::from_config(config),
+        // The node goes down whatever the pods' disruption budgets say
+        false,
+    )
+    .await?;
+    // The pods' volumes are gone once their pods are, but in-memory secrets
+    // must not outlive the kubelet in any case
+    crate::volume::unmount_in_memory_secrets();
+    if config.deregister_on_shutdown {
+        super::delete(client, &config.node_name).await?;
+    } else {
+        super::set_not_ready(client, &config.node_name).await?;
+    }
+    info!("Node shut down");
+    Ok(())
+}
+
+/// Groups the pods by their priority, in the order they are evicted: the
+/// lowest priority first
+pub(crate) fn eviction_order(mut pods: Vec<(i32, Pod)>) -> Vec<(i32, Vec<Pod>)> {
+    // The sort is stable, so that pods of the same priority keep their order
+    pods.sort_by_key(|(priority, _)| *priority);
+    let mut groups: Vec<(i32, Vec<Pod>)> = vec![];
+    for (priority, pod) in pods {
+        match groups.last_mut() {
+            Some((last, group)) if *last == priority => group.push(pod),
+            _ => groups.push((priority, vec![pod])),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util::pod;
+    use k8s_openapi::api::core::v1::PodSpec;
+
+    #[test]
+    fn pods_are_evicted_lowest_priority_first() {
+        let pods = vec![
+            (100, pod("web", PodSpec::default())),
+            (0, pod("batch", PodSpec::default())),
+            (100, pod("api", PodSpec::default())),
+            (-5, pod("preemptible", PodSpec::default())),
+        ];
+        let order: Vec<(i32, Vec<String>)> = eviction_order(pods)
+            .into_iter()
+            .map(|(priority, group)| {
+                (
+                    priority,
+                    group.iter().map(|pod| pod.name().to_owned()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (-5, vec!["preemptible".to_owned()]),
+                (0, vec!["batch".to_owned()]),
+                (100, vec!["web".to_owned(), "api".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn critical_pods_are_kept_part_of_the_grace_period() {
+        let periods = ShutdownGracePeriods {
+            total: Duration::from_secs(60),
+            critical_pods: Duration::from_secs(20),
+        };
+        assert_eq!(periods.regular_pods(), Some(Duration::from_secs(40)));
+        assert_eq!(periods.critical_pods(), Some(Duration::from_secs(20)));
+
+        let unlimited = ShutdownGracePeriods::default();
+        assert_eq!(unlimited.regular_pods(), None);
+        assert_eq!(unlimited.critical_pods(), None);
+    }
+}
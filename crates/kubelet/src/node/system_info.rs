@@ -0,0 +1,219 @@
+//! The system information of the node's host, reported in the node's status.
+//!
+//! The architecture and operating system are those krustlet was built for,
+//! named as Go names them, like other kubelets report them. The kernel
+//! version and OS image are read from the host when the node is registered:
+//! on Linux from `uname` and `/etc/os-release`, and on Windows from `ver`.
+//! Anything that can't be read is reported as `unknown`.
+use tracing::debug;
+
+/// Reported for what can't be read from the host
+const UNKNOWN: &str = "unknown";
+
+/// The information about the host reported in the node's `nodeInfo`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SystemInfo {
+    pub(crate) architecture: String,
+    pub(crate) operating_system: String,
+    pub(crate) kernel_version: String,
+    pub(crate) os_image: String,
+}
+
+impl SystemInfo {
+    /// Reads the system information of the host
+    pub(crate) fn detect() -> Self {
+        let (kernel_version, os_image) = host_versions();
+        SystemInfo {
+            architecture: architecture().to_owned(),
+            operating_system: operating_system().to_owned(),
+            kernel_version: kernel_version.unwrap_or_else(|| UNKNOWN.to_owned()),
+            os_image: os_image.unwrap_or_else(|| UNKNOWN.to_owned()),
+        }
+    }
+}
+
+/// The architecture krustlet was built for, as Go names it
+pub(crate) fn architecture() -> &'static str {
+    go_architecture(std::env::consts::ARCH)
+}
+
+/// The operating system krustlet was built for, as Go names it
+pub(crate) fn operating_system() -> &'static str {
+    go_operating_system(std::env::consts::OS)
+}
+
+fn go_architecture(arch: &'static str) -> &'static str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "powerpc64" => "ppc64",
+        other => other,
+    }
+}
+
+fn go_operating_system(os: &'static str) -> &'static str {
+    match os {
+        "macos" => "darwin",
+        other => other,
+    }
+}
+
+/// The kernel version and OS image of the host
+#[cfg(target_os = "linux")]
+fn host_versions() -> (Option<String>, Option<String>) {
+    let os_image = match std::fs::read_to_string("/etc/os-release") {
+        Ok(contents) => os_image_from_os_release(&contents),
+        Err(e) => {
+            debug!("Unable to read /etc/os-release: {}", e);
+            None
+        }
+    };
+    (uname_release(), os_image)
+}
+
+/// The kernel version and OS image of the host
+#[cfg(all(unix, not(target_os = "linux")))]
+fn host_versions() -> (Option<String>, Option<String>) {
+    (uname_release(), uname_sysname())
+}
+
+/// The kernel version and OS image of the host
+#[cfg(windows)]
+fn host_versions() -> (Option<String>, Option<String>) {
+    let output = match std::process::Command::new("cmd")
+        .args(&["/C", "ver"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(e) => {
+            debug!("Unable to run ver: {}", e);
+            return (None, None);
+        }
+    };
+    (
+        kernel_version_from_ver(&output),
+        Some("Windows".to_owned()).filter(|_| output.contains("Windows")),
+    )
+}
+
+/// The kernel version and OS image of the host
+#[cfg(not(any(unix, windows)))]
+fn host_versions() -> (Option<String>, Option<String>) {
+    (None, None)
+}
+
+#[cfg(unix)]
+fn uname() -> Option<libc::utsname> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        debug!("Unable to call uname: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    Some(uts)
+}
+
+#[cfg(unix)]
+fn uname_field(field: &[libc::c_char]) -> Option<String> {
+    let bytes: Vec<u8> = field
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+    Some(String::from_utf8_lossy(&bytes).into_owned()).filter(|s| !s.is_empty())
+}
+
+/// The release of the running kernel, such as `5.10.0-8-amd64`
+#[cfg(unix)]
+fn uname_release() -> Option<String> {
+    uname().and_then(|uts| uname_field(&uts.release))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn uname_sysname() -> Option<String> {
+    uname().and_then(|uts| uname_field(&uts.sysname))
+}
+
+/// The name of the distribution in the contents of `/etc/os-release`, such
+/// as `Debian GNU/Linux 11 (bullseye)`: its `PRETTY_NAME`, or its `NAME` if
+/// it has none
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn os_image_from_os_release(contents: &str) -> Option<String> {
+    let field = |name: &str| {
+        contents.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(name)?.strip_prefix('=')?;
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            Some(value.to_owned()).filter(|v| !v.is_empty())
+        })
+    };
+    field("PRETTY_NAME").or_else(|| field("NAME"))
+}
+
+/// The version in the output of `ver`, such as `10.0.17763.1577` in
+/// `Microsoft Windows [Version 10.0.17763.1577]`
+#[cfg_attr(not(windows), allow(dead_code))]
+fn kernel_version_from_ver(output: &str) -> Option<String> {
+    let start = output.find("Version ")? + "Version ".len();
+    let version: String = output[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    Some(version).filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_targets_are_named_as_go_names_them() {
+        assert_eq!(go_architecture("x86_64"), "amd64");
+        assert_eq!(go_architecture("aarch64"), "arm64");
+        assert_eq!(go_architecture("arm"), "arm");
+        assert_eq!(go_architecture("s390x"), "s390x");
+        assert_eq!(go_operating_system("linux"), "linux");
+        assert_eq!(go_operating_system("windows"), "windows");
+        assert_eq!(go_operating_system("macos"), "darwin");
+    }
+
+    #[test]
+    fn os_images_are_read_from_os_release() {
+        let os_release = r#"PRETTY_NAME="Debian GNU/Linux 11 (bullseye)"
+NAME="Debian GNU/Linux"
+VERSION_ID="11"
+"#;
+        assert_eq!(
+            os_image_from_os_release(os_release),
+            Some("Debian GNU/Linux 11 (bullseye)".to_owned())
+        );
+        assert_eq!(
+            os_image_from_os_release("NAME=Alpine\nID=alpine\n"),
+            Some("Alpine".to_owned())
+        );
+        assert_eq!(os_image_from_os_release("ID=custom\n"), None);
+    }
+
+    #[test]
+    fn windows_versions_are_read_from_ver() {
+        assert_eq!(
+            kernel_version_from_ver("\r\nMicrosoft Windows [Version 10.0.17763.1577]\r\n"),
+            Some("10.0.17763.1577".to_owned())
+        );
+        assert_eq!(kernel_version_from_ver("unexpected"), None);
+    }
+
+    #[test]
+    fn the_host_is_detected() {
+        let info = SystemInfo::detect();
+        assert!(!info.architecture.is_empty());
+        assert!(!info.operating_system.is_empty());
+        if cfg!(target_os = "linux") {
+            assert_eq!(info.operating_system, "linux");
+            assert_ne!(info.kernel_version, UNKNOWN);
+        }
+        if cfg!(windows) {
+            assert_eq!(info.operating_system, "windows");
+        }
+    }
+}
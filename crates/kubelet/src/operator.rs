@@ -1,23 +1,42 @@
 use crate::pod::initialize_pod_container_statuses;
-use crate::pod::Pod;
+use crate::pod::{Pod, PodStore};
 use crate::provider::Provider;
+use crate::state::record::TransitionRecorder;
 use crate::volume::Ref;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::state::SharedState;
 use krator::ObjectState;
-use krator::{Manifest, Operator};
+use krator::{Manifest, Operator, State};
 use kube::Api;
 use std::sync::Arc;
-use tracing::error;
+use tokio::sync::watch;
+use tracing::{error, warn};
 
 pub(crate) struct PodOperator<P: Provider> {
     provider: Arc<P>,
-    client: kube::Client,
+    /// The latest client, which is replaced when the client certificate is
+    /// rotated
+    clients: watch::Receiver<kube::Client>,
+    /// The pods being run, and the states they're in
+    pods: PodStore,
+    /// Records the states pods enter, if they are recorded
+    recorder: Option<Arc<TransitionRecorder>>,
 }
 
 impl<P: Provider> PodOperator<P> {
-    pub fn new(provider: Arc<P>, client: kube::Client) -> Self {
-        PodOperator { provider, client }
+    pub fn new(provider: Arc<P>, clients: watch::Receiver<kube::Client>, pods: PodStore) -> Self {
+        PodOperator {
+            provider,
+            clients,
+            pods,
+            recorder: None,
+        }
+    }
+
+    /// Record the states pods enter with `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<TransitionRecorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
     }
 }
 
@@ -37,21 +56,36 @@ impl<P: Provider> Operator for PodOperator<P> {
         self.provider.provider_state()
     }
 
+    fn resume_state(&self, name: &str) -> Option<Box<dyn State<P::PodState>>> {
+        self.provider.resume_state(name)
+    }
+
+    fn state_entered(&self, manifest: &Manifest<Pod>, state: &'static str) {
+        self.pods.entered(manifest, state);
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(&manifest.latest(), state) {
+                warn!("Unable to record pod entering state {}: {:?}", state, e);
+            }
+        }
+    }
+
     async fn registration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
         let initial_manifest = manifest.latest();
         let namespace = initial_manifest.namespace();
         let name = initial_manifest.name().to_string();
-        let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
+        let api: Api<KubePod> = Api::namespaced(self.clients.borrow().clone(), namespace);
 
         initialize_pod_container_statuses(name, manifest, &api).await
     }
 
     async fn deregistration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+        self.pods.remove(&manifest.latest());
+        crate::extended_resources::release(&manifest.latest());
         if let Some(volume_path) = self.provider.volume_path() {
             let pod = manifest.latest();
             let plugin_registry = self.provider.plugin_registry();
-            match Ref::unmount_volumes_from_pod(&volume_path, &pod, &self.client, plugin_registry)
-                .await
+            let client = self.clients.borrow().clone();
+            match Ref::unmount_volumes_from_pod(&volume_path, &pod, &client, plugin_registry).await
             {
                 Ok(_) => {}
                 Err(e) => {
@@ -1,4 +1,8 @@
 //! The Kubelet plugin manager. Used to lookup which plugins are registered with this node.
+//!
+//! Plugins register by creating a socket in the plugin directory, which the [`PluginRegistry`]
+//! watches. Each new socket is asked for its `PluginInfo` and, once validated, the plugin is
+//! handed to the [`PluginHandler`] for its type, such as the device manager for device plugins.
 use crate::fs_watch::FileSystemWatcher;
 use crate::grpc_sock;
 use crate::plugin_registration_api::v1::{
@@ -7,6 +11,7 @@ use crate::plugin_registration_api::v1::{
 };
 
 use anyhow::Context;
+use async_trait::async_trait;
 use notify::Event;
 use tokio::fs::{create_dir_all, read_dir};
 use tokio::sync::{RwLock, RwLockWriteGuard};
@@ -18,6 +23,7 @@ use tracing::{debug, error, trace, warn};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[cfg(target_family = "unix")]
 const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/plugins_registry/";
@@ -29,9 +35,11 @@ const ALLOWED_PLUGIN_TYPES: &[PluginType] = &[PluginType::CSIPlugin];
 
 /// An enum for capturing possible plugin types. This is purely for clarity and capturing this
 /// information is a compiled type as the information we get from gRPC is a string
-#[derive(Debug, PartialEq)]
-enum PluginType {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PluginType {
+    /// A Container Storage Interface plugin
     CSIPlugin,
+    /// A device plugin, advertising devices such as GPUs to the node
     DevicePlugin,
 }
 
@@ -50,19 +58,41 @@ impl TryFrom<&str> for PluginType {
     }
 }
 
+/// Handles plugins of one type once they have registered, like the `PluginHandler`s of the
+/// Kubernetes plugin manager. A plugin is only told that it registered successfully if its
+/// handler accepts it.
+#[async_trait]
+pub trait PluginHandler: Send + Sync {
+    /// Called when a plugin with the given name registers. The endpoint is the socket the
+    /// plugin serves its API on. Returning an error rejects the registration, and the error is
+    /// reported to the plugin.
+    async fn register(&self, name: &str, endpoint: &Path) -> anyhow::Result<()>;
+
+    /// Called when the socket of a registered plugin is removed
+    async fn deregister(&self, name: &str);
+}
+
 /// Internal storage structure for a plugin
 #[derive(Debug)]
 struct PluginEntry {
+    plugin_type: PluginType,
     plugin_path: PathBuf,
     endpoint: Option<PathBuf>,
 }
 
+impl PluginEntry {
+    fn endpoint(&self) -> &Path {
+        self.endpoint.as_ref().unwrap_or(&self.plugin_path)
+    }
+}
+
 /// An internal storage plugin registry that implements most the same functionality as the [plugin
 /// manager](https://github.com/kubernetes/kubernetes/tree/fd74333a971e2048b5fb2b692a9e043483d63fba/pkg/kubelet/pluginmanager)
 /// in kubelet
 pub struct PluginRegistry {
     plugins: RwLock<HashMap<String, PluginEntry>>,
     plugin_dir: PathBuf,
+    handlers: HashMap<PluginType, Arc<dyn PluginHandler>>,
 }
 
 impl Default for PluginRegistry {
@@ -70,6 +100,7 @@ impl Default for PluginRegistry {
         PluginRegistry {
             plugin_dir: PathBuf::from(DEFAULT_PLUGIN_PATH),
             plugins: RwLock::new(HashMap::new()),
+            handlers: HashMap::new(),
         }
     }
 }
@@ -83,14 +114,23 @@ impl PluginRegistry {
         }
     }
 
+    /// Hands plugins of the given type to `handler` when they register. Plugins of a type
+    /// other than `CSIPlugin` are only accepted once they have a handler.
+    pub fn with_handler(
+        mut self,
+        plugin_type: PluginType,
+        handler: Arc<dyn PluginHandler>,
+    ) -> Self {
+        self.handlers.insert(plugin_type, handler);
+        self
+    }
+
     /// Gets the endpoint for the given plugin name, returning `None` if it doesn't exist
     // TODO: Remove clippy exception when CSI is completed.
     #[allow(dead_code)]
     pub async fn get_endpoint(&self, plugin_name: &str) -> Option<PathBuf> {
         let plugins = self.plugins.read().await;
-        plugins
-            .get(plugin_name)
-            .map(|v| v.endpoint.as_ref().unwrap_or(&v.plugin_path).to_owned())
+        plugins.get(plugin_name).map(|v| v.endpoint().to_owned())
     }
 
     /// Starts the plugin registrar and runs all automatic plugin discovery and registration loops.
@@ -167,10 +207,21 @@ impl PluginRegistry {
                 plugin_info
             );
 
-            // Step 3: Register plugin to local storage
+            // Step 3: Hand the plugin to the handler for its type, if there is one
+            if let Err(e) = self.handle_register(&plugin_info, &discovered_path).await {
+                inform_plugin(&discovered_path, Some(e.to_string())).await?;
+                return Err(e).with_context(|| {
+                    format!(
+                        "Handler failed to register plugin discovered at {}",
+                        discovered_path.display()
+                    )
+                });
+            }
+
+            // Step 4: Register plugin to local storage
             self.register(&plugin_info, &discovered_path).await;
 
-            // Step 4: Inform plugin
+            // Step 5: Inform plugin
             inform_plugin(&discovered_path, None).await?;
             debug!("Plugin registration complete for {:?}", plugin_info)
         }
@@ -178,18 +229,49 @@ impl PluginRegistry {
     }
 
     async fn handle_delete(&self, event: Event) {
-        let mut plugins = self.plugins.write().await;
-        for deleted_plugin in plugin_paths(event.paths) {
-            remove_plugin(&mut plugins, deleted_plugin);
+        let mut removed = Vec::new();
+        {
+            let mut plugins = self.plugins.write().await;
+            for deleted_plugin in plugin_paths(event.paths) {
+                removed.extend(remove_plugin(&mut plugins, deleted_plugin));
+            }
+        }
+        // Handlers are called without holding the lock, as they may take a while
+        for (name, entry) in removed {
+            if let Some(handler) = self.handlers.get(&entry.plugin_type) {
+                debug!("Deregistering plugin {} from its handler", name);
+                handler.deregister(&name).await;
+            }
         }
     }
 
+    /// Calls the register function of the handler for the plugin's type, if any
+    async fn handle_register(
+        &self,
+        info: &PluginInfo,
+        discovered_path: &PathBuf,
+    ) -> anyhow::Result<()> {
+        let plugin_type = PluginType::try_from(info.r#type.as_str())?;
+        if let Some(handler) = self.handlers.get(&plugin_type) {
+            let endpoint = match info.endpoint.is_empty() {
+                true => discovered_path.to_owned(),
+                false => PathBuf::from(&info.endpoint),
+            };
+            handler.register(&info.name, &endpoint).await?;
+        }
+        Ok(())
+    }
+
     /// Registers the plugin in our HashMap
     async fn register(&self, info: &PluginInfo, discovered_path: &PathBuf) {
+        // The type was checked during validation
+        let plugin_type =
+            PluginType::try_from(info.r#type.as_str()).unwrap_or(PluginType::CSIPlugin);
         let mut lock = self.plugins.write().await;
         lock.insert(
             info.name.clone(),
             PluginEntry {
+                plugin_type,
                 plugin_path: discovered_path.to_owned(),
                 endpoint: match info.endpoint.is_empty() {
                     true => None,
@@ -202,8 +284,7 @@ impl PluginRegistry {
     /// Validates the given plugin info gathered from a discovered plugin, returning an error with
     /// additional information if it is not valid. This will validate 3 specific things (should
     /// answer YES to all of these):
-    /// 1. Is it a CSIPlugin? If it isn't we will deny it for now. This will be removed as we
-    ///    iterate and if it is needed
+    /// 1. Is it a CSIPlugin, or a type with a handler? If it isn't we will deny it
     /// 2. Does the list of supported versions contain the version we expect?
     /// 3. Is the plugin name available? 3a. If the name is already registered, is the endpoint the
     ///    exact same? If it is, we allow it to reregister
@@ -230,12 +311,15 @@ impl PluginRegistry {
 
     // Individual validation steps
 
-    /// Check for valid type and if it is a CSIPlugin
+    /// Check for valid type and if it is a CSIPlugin or has a handler
     fn validate_plugin_type(&self, plugin_type: &str) -> anyhow::Result<()> {
         let plugin_type = PluginType::try_from(plugin_type)?;
-        if !is_allowed_plugin_type(plugin_type) {
-            warn!("DevicePlugins are not currently supported");
-            return Err(anyhow::anyhow!("DevicePlugins are not currently supported"));
+        if !is_allowed_plugin_type(plugin_type) && !self.handlers.contains_key(&plugin_type) {
+            warn!("{:?}s are not currently supported", plugin_type);
+            return Err(anyhow::anyhow!(
+                "{:?}s are not currently supported",
+                plugin_type
+            ));
         }
         Ok(())
     }
@@ -284,11 +368,11 @@ impl PluginRegistry {
 }
 
 /// A helper function to clarify code intent when removing a plugin. This puts all the iterating and
-/// stuff into a well-named place
+/// stuff into a well-named place. Returns the name and entry of the removed plugin
 fn remove_plugin(
     plugins: &mut RwLockWriteGuard<HashMap<String, PluginEntry>>,
     deleted_plugin: PathBuf,
-) {
+) -> Option<(String, PluginEntry)> {
     let key = match plugins
         .iter()
         .find(|(_, v)| *v.plugin_path == deleted_plugin)
@@ -296,9 +380,9 @@ fn remove_plugin(
         // Take ownership of the key to avoid an immutable borrow
         Some((key, _)) => key.to_owned(),
         // If for some reason it is already gone, no need to error
-        None => return,
+        None => return None,
     };
-    plugins.remove(&key);
+    plugins.remove_entry(&key)
 }
 
 // An allow list check for currently supported plugin types
@@ -461,6 +545,63 @@ mod test {
         }
     }
 
+    #[derive(Debug)]
+    // A device plugin, which is only accepted when there is a handler for device plugins
+    struct TestDevicePlugin {
+        name: String,
+        registration_response: Mutex<Sender<RegistrationStatus>>,
+    }
+
+    #[tonic::async_trait]
+    impl Registration for TestDevicePlugin {
+        async fn get_info(
+            &self,
+            _req: Request<InfoRequest>,
+        ) -> Result<Response<PluginInfo>, Status> {
+            Ok(Response::new(PluginInfo {
+                r#type: "DevicePlugin".to_string(),
+                name: self.name.clone(),
+                endpoint: FAKE_ENDPOINT.to_string(),
+                supported_versions: vec![API_VERSION.to_string()],
+            }))
+        }
+
+        async fn notify_registration_status(
+            &self,
+            req: Request<RegistrationStatus>,
+        ) -> Result<Response<RegistrationStatusResponse>, Status> {
+            self.registration_response
+                .lock()
+                .await
+                .send(req.into_inner())
+                .await
+                .expect("should be able to send registration status on channel");
+
+            Ok(Response::new(RegistrationStatusResponse {}))
+        }
+    }
+
+    // A handler that records the plugins registered with it
+    #[derive(Default)]
+    struct TestHandler {
+        registered: Mutex<HashMap<String, PathBuf>>,
+    }
+
+    #[async_trait]
+    impl PluginHandler for TestHandler {
+        async fn register(&self, name: &str, endpoint: &Path) -> anyhow::Result<()> {
+            self.registered
+                .lock()
+                .await
+                .insert(name.to_owned(), endpoint.to_owned());
+            Ok(())
+        }
+
+        async fn deregister(&self, name: &str) {
+            self.registered.lock().await.remove(name);
+        }
+    }
+
     /// Setup the test, returning the temporary directory (as we need it around to keep it from
     /// dropping) and a PluginRegistry configured with the path. The PluginRegistry is wrapped in
     /// an Arc to facilitate easy moving to a task using tokio::spawn
@@ -552,6 +693,51 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_registration_with_handler() {
+        let tempdir = tempfile::tempdir().expect("should be able to create tempdir");
+        let handler = Arc::new(TestHandler::default());
+        let registrar = Arc::new(
+            PluginRegistry::new(&tempdir).with_handler(PluginType::DevicePlugin, handler.clone()),
+        );
+
+        let (tx, rx) = mpsc::channel(1);
+
+        let plugin = TestDevicePlugin {
+            name: "example.com/gpu".to_string(),
+            registration_response: Mutex::new(tx),
+        };
+
+        start_registrar(registrar.clone()).await;
+
+        let sock_path = tempdir.path().join("gpu.sock");
+        setup_server(plugin, &sock_path).await;
+
+        let registration_status = get_registration_response(rx).await;
+
+        assert!(
+            registration_status.plugin_registered,
+            "Plugin did not receive successful registration request"
+        );
+        assert_eq!(
+            handler.registered.lock().await.get("example.com/gpu"),
+            Some(&PathBuf::from(FAKE_ENDPOINT)),
+            "Plugin should have been handed to its handler"
+        );
+
+        tokio::fs::remove_file(sock_path)
+            .await
+            .expect("Unable to remove socket");
+
+        // Delay to give it time to remove (needs to be a little longer for MacOS' sake)
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        assert!(
+            handler.registered.lock().await.is_empty(),
+            "Plugin should have been deregistered from its handler"
+        );
+    }
+
     #[tokio::test]
     async fn test_unsuccessful_registration() {
         let (tempdir, registrar) = setup();
@@ -712,6 +898,16 @@ mod test {
             "DevicePlugin type should error"
         );
 
+        let registrar =
+            registrar.with_handler(PluginType::DevicePlugin, Arc::new(TestHandler::default()));
+        assert!(
+            registrar
+                .validate(&info, &PathBuf::from("/fake"))
+                .await
+                .is_ok(),
+            "DevicePlugin type should be allowed with a handler"
+        );
+
         info.r#type = "NonExistent".to_string();
         assert!(
             registrar
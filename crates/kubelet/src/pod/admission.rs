@@ -0,0 +1,245 @@
+//! Orders the admission of pods by their priority.
+//!
+//! Pods that reach the node at the same time are admitted one at a time, and
+//! while a pod is being admitted the others wait in an [`AdmissionQueue`].
+//! The waiting pod with the highest priority is admitted next, and pods with
+//! the same priority are admitted in the order they arrived. A pod's priority
+//! is the `value` of its `PriorityClass`.
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use k8s_openapi::api::scheduling::v1::PriorityClass;
+use kube::Api;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use super::Pod;
+
+/// A pod waiting in the admission queue
+#[derive(Debug)]
+pub struct PodAdmissionRequest {
+    /// When the pod joined the queue, for pods with the same priority to be
+    /// admitted in the order they arrived
+    sequence: u64,
+    pod: String,
+    admit: oneshot::Sender<AdmissionGuard>,
+}
+
+// Requests are only compared by when they arrived, earlier requests being
+// greater so that they are popped from the queue first
+
+impl PartialEq for PodAdmissionRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.sequence == other.sequence
+    }
+}
+
+impl Eq for PodAdmissionRequest {}
+
+impl PartialOrd for PodAdmissionRequest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PodAdmissionRequest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.sequence.cmp(&self.sequence)
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    waiting: BinaryHeap<(i32, PodAdmissionRequest)>,
+    admitting: bool,
+    next_sequence: u64,
+}
+
+/// The pods waiting to be admitted, by priority
+#[derive(Debug, Default)]
+pub struct AdmissionQueue {
+    state: Mutex<QueueState>,
+}
+
+impl AdmissionQueue {
+    /// Creates an empty queue
+    pub fn new() -> Arc<Self> {
+        Arc::new(AdmissionQueue::default())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state
+            .lock()
+            .expect("admission queue lock should not be poisoned")
+    }
+
+    /// Waits for the pod's turn to be admitted. The next pod isn't admitted
+    /// until the returned guard is dropped.
+    pub async fn admit(self: &Arc<Self>, pod: &Pod, priority: i32) -> AdmissionGuard {
+        let pod = format!("{}/{}", pod.namespace(), pod.name());
+        let admitted = {
+            let mut state = self.lock();
+            if !state.admitting {
+                state.admitting = true;
+                None
+            } else {
+                let (admit, admitted) = oneshot::channel();
+                let sequence = state.next_sequence;
+                state.next_sequence += 1;
+                debug!(
+                    "Pod {} with priority {} is waiting to be admitted behind {} other pods",
+                    pod,
+                    priority,
+                    state.waiting.len()
+                );
+                state.waiting.push((
+                    priority,
+                    PodAdmissionRequest {
+                        sequence,
+                        pod,
+                        admit,
+                    },
+                ));
+                Some(admitted)
+            }
+        };
+        match admitted {
+            None => AdmissionGuard {
+                queue: Arc::clone(self),
+            },
+            Some(admitted) => admitted
+                .await
+                .expect("admission queue should admit every waiting pod"),
+        }
+    }
+
+    /// Admits the waiting pod with the highest priority, if any
+    fn admit_next(self: &Arc<Self>) {
+        let next = {
+            let mut state = self.lock();
+            let next = state.waiting.pop();
+            if next.is_none() {
+                state.admitting = false;
+            }
+            next
+        };
+        if let Some((priority, request)) = next {
+            debug!("Admitting pod {} with priority {}", request.pod, priority);
+            // If the pod stopped waiting, the guard is dropped, which admits
+            // the pod after it
+            request
+                .admit
+                .send(AdmissionGuard {
+                    queue: Arc::clone(self),
+                })
+                .ok();
+        }
+    }
+}
+
+/// A pod's turn to be admitted, which ends when this is dropped
+#[derive(Debug)]
+pub struct AdmissionGuard {
+    queue: Arc<AdmissionQueue>,
+}
+
+impl Drop for AdmissionGuard {
+    fn drop(&mut self) {
+        self.queue.admit_next();
+    }
+}
+
+/// Returns the priority of the pod, which is the `value` of its
+/// `PriorityClass`. If the class can't be read, the priority the API server
+/// resolved for the pod is used, and pods without a class have that or no
+/// priority.
+pub async fn priority(client: &kube::Client, pod: &Pod) -> i32 {
+    let fallback = pod.priority().unwrap_or(0);
+    let name = match pod.priority_class_name() {
+        Some(name) if !name.is_empty() => name,
+        _ => return fallback,
+    };
+    let api: Api<PriorityClass> = Api::all(client.clone());
+    match api.get(name).await {
+        Ok(class) => class.value,
+        Err(e) => {
+            warn!(
+                "Unable to read priority class {} of pod {}, using priority {}: {:?}",
+                name,
+                pod.name(),
+                fallback,
+                e
+            );
+            fallback
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util::pod;
+    use k8s_openapi::api::core::v1::PodSpec;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn pods_are_admitted_by_priority_then_arrival() {
+        let queue = AdmissionQueue::new();
+        let first = queue.admit(&pod("first", PodSpec::default()), 0).await;
+
+        let (admitted, mut order) = tokio::sync::mpsc::unbounded_channel();
+        let pods = vec![
+            ("low", -10),
+            ("high", 1000),
+            ("default", 0),
+            ("also-high", 1000),
+        ];
+        for (waiting, (name, priority)) in pods.into_iter().enumerate() {
+            let task_queue = Arc::clone(&queue);
+            let admitted = admitted.clone();
+            tokio::spawn(async move {
+                let _guard = task_queue
+                    .admit(&pod(name, PodSpec::default()), priority)
+                    .await;
+                admitted.send(name).unwrap();
+            });
+            // Let the pod join the queue before the next one arrives
+            while queue.lock().waiting.len() == waiting {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        drop(first);
+        let mut names = vec![];
+        for _ in 0..4 {
+            names.push(order.recv().await.unwrap());
+        }
+        assert_eq!(names, vec!["high", "also-high", "default", "low"]);
+    }
+
+    #[tokio::test]
+    async fn pods_that_stop_waiting_do_not_block_the_queue() {
+        let queue = AdmissionQueue::new();
+        let first = queue.admit(&pod("first", PodSpec::default()), 0).await;
+        let gone = {
+            let queue = Arc::clone(&queue);
+            tokio::spawn(async move {
+                let _guard = queue.admit(&pod("gone", PodSpec::default()), 100).await;
+            })
+        };
+        while queue.lock().waiting.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        gone.abort();
+        let _ = gone.await;
+
+        drop(first);
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            queue.admit(&pod("next", PodSpec::default()), 0),
+        )
+        .await
+        .expect("the next pod should be admitted");
+    }
+}
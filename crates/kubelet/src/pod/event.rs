@@ -0,0 +1,114 @@
+//! Recording of Kubernetes events about pods and the node.
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PostParams};
+
+use crate::pod::Pod;
+
+/// The component name events are reported by
+const EVENT_COMPONENT: &str = "krustlet";
+/// The namespace events about the node are recorded in, as other kubelets do
+const NODE_EVENT_NAMESPACE: &str = "default";
+
+/// The type of an event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    /// Something happened as expected
+    Normal,
+    /// Something went wrong and may need attention
+    Warning,
+}
+
+impl std::fmt::Display for EventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventType::Normal => "Normal".fmt(f),
+            EventType::Warning => "Warning".fmt(f),
+        }
+    }
+}
+
+/// Records an event about a pod, such as those shown by `kubectl describe pod`.
+pub async fn record_event(
+    client: &kube::Client,
+    pod: &Pod,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let kube_pod = pod.as_kube_pod();
+    let now = Time(Utc::now());
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", pod.name())),
+            namespace: Some(pod.namespace().to_owned()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            name: Some(pod.name().to_owned()),
+            namespace: Some(pod.namespace().to_owned()),
+            uid: kube_pod.metadata.uid.clone(),
+            resource_version: kube_pod.metadata.resource_version.clone(),
+            ..Default::default()
+        },
+        type_: Some(event_type.to_string()),
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        count: Some(1),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        source: Some(EventSource {
+            component: Some(EVENT_COMPONENT.to_owned()),
+            host: kube_pod.spec.as_ref().and_then(|s| s.node_name.clone()),
+        }),
+        reporting_component: Some(EVENT_COMPONENT.to_owned()),
+        ..Default::default()
+    };
+    let events: Api<Event> = Api::namespaced(client.clone(), pod.namespace());
+    events.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
+
+/// Records an event about the node, such as those shown by
+/// `kubectl describe node`.
+pub async fn record_node_event(
+    client: &kube::Client,
+    node_name: &str,
+    event_type: EventType,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let now = Time(Utc::now());
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", node_name)),
+            namespace: Some(NODE_EVENT_NAMESPACE.to_owned()),
+            ..Default::default()
+        },
+        // Like other kubelets, the node is referred to by its name
+        involved_object: ObjectReference {
+            kind: Some("Node".to_owned()),
+            name: Some(node_name.to_owned()),
+            uid: Some(node_name.to_owned()),
+            ..Default::default()
+        },
+        type_: Some(event_type.to_string()),
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        count: Some(1),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        source: Some(EventSource {
+            component: Some(EVENT_COMPONENT.to_owned()),
+            host: Some(node_name.to_owned()),
+        }),
+        reporting_component: Some(EVENT_COMPONENT.to_owned()),
+        ..Default::default()
+    };
+    let events: Api<Event> = Api::namespaced(client.clone(), NODE_EVENT_NAMESPACE);
+    events.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::io::AsyncRead;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
 
@@ -51,6 +51,11 @@ impl<H: StopHandler, F> Handle<H, F> {
         }
     }
 
+    /// The pod the handle manages, as it was when the handle was created.
+    pub fn pod(&self) -> &Pod {
+        &self.pod
+    }
+
     /// Insert container `Handle` by `ContainerKey`.
     pub async fn insert_container_handle(&self, key: ContainerKey, value: ContainerHandle<H, F>) {
         let mut map = self.container_handles.write().await;
@@ -61,7 +66,7 @@ impl<H: StopHandler, F> Handle<H, F> {
     /// Optionally tails the output and/or continues to watch the file and stream changes.
     pub async fn output<R>(&self, container_name: &str, sender: Sender) -> anyhow::Result<()>
     where
-        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
         F: HandleFactory<R>,
     {
         let mut handles = self.container_handles.write().await;
@@ -71,6 +76,13 @@ impl<H: StopHandler, F> Handle<H, F> {
                 pod_name: self.pod.name().to_owned(),
                 container_name: container_name.to_owned(),
             })?;
+        if sender.previous() && !handle.has_previous::<R>() {
+            return Err(ProviderError::PreviousContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            }
+            .into());
+        }
         handle.output(sender).await
     }
 
@@ -93,6 +105,34 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 
+    /// Signal one of the pod's containers to stop, without waiting for it to
+    /// complete. Does nothing if the pod has no such container.
+    pub async fn stop_container(&self, key: &ContainerKey) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        match handles.get_mut(key) {
+            Some(handle) => {
+                info!("Stopping container: {}", key);
+                handle.stop().await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Wait up to `timeout` for all containers in the pod to complete,
+    /// whether or not they succeed. Returns whether they all completed in
+    /// time.
+    pub async fn wait_within(&self, timeout: std::time::Duration) -> bool {
+        let mut handles = self.container_handles.write().await;
+        let all_completed = async {
+            for (key, handle) in handles.iter_mut() {
+                if let Err(e) = handle.wait().await {
+                    debug!("Container {} completed with error: {:?}", key, e);
+                }
+            }
+        };
+        tokio::time::timeout(timeout, all_completed).await.is_ok()
+    }
+
     /// Wait for all containers in the pod to complete
     pub async fn wait(&mut self) -> anyhow::Result<()> {
         let mut handles = self.container_handles.write().await;
@@ -1,25 +1,39 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+pub mod admission;
+pub mod event;
 mod handle;
+pub mod resize;
+pub mod security;
 pub mod state;
 mod status;
+mod store;
+#[cfg(test)]
+pub(crate) mod test_util;
 // Ignore deprecated here as this is just a reexport
 #[allow(deprecated)]
 pub use handle::{key_from_pod, pod_key, Handle};
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
+    make_registered_status, make_running_status, make_status, make_status_with_containers,
+    patch_status, unready_readiness_gate, Phase, Status,
 };
+pub(crate) use store::PodStore;
+pub use store::STATE_ANNOTATION;
 
 use crate::container::{Container, ContainerKey};
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    Container as KubeContainer, Pod as KubePod, Volume as KubeVolume,
+    Container as KubeContainer, Pod as KubePod, PodCondition as KubePodCondition,
+    Volume as KubeVolume,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::Meta;
 use serde::Deserialize;
 use serde::Serialize;
 
+/// The termination grace period of pods that don't set one
+const DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS: i64 = 30;
+
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
@@ -51,6 +65,14 @@ impl Pod {
             .unwrap_or("default")
     }
 
+    /// Get the pod's UID
+    ///
+    /// Returns an empty string if the pod has not been given one, as only
+    /// pods that haven't been created yet lack one
+    pub fn pod_uid(&self) -> &str {
+        self.kube_pod.metadata.uid.as_deref().unwrap_or("")
+    }
+
     /// Get the pod's node_selector map
     pub fn node_selector(&self) -> Option<&std::collections::BTreeMap<String, String>> {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
@@ -62,12 +84,77 @@ impl Pod {
         spec.service_account_name.as_deref()
     }
 
+    /// Get the name of the pod's priority class
+    pub fn priority_class_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.priority_class_name.as_deref()
+    }
+
+    /// Get the pod's priority, as resolved by the API server from its
+    /// priority class
+    pub fn priority(&self) -> Option<i32> {
+        self.kube_pod.spec.as_ref()?.priority
+    }
+
+    /// Get how long the pod is given to stop once it is deleted, which is
+    /// 30 seconds if the pod doesn't say
+    pub fn termination_grace_period(&self) -> std::time::Duration {
+        let seconds = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.termination_grace_period_seconds)
+            .unwrap_or(DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS);
+        std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+
+    /// Get how long the pod was given to stop when it was deleted, which
+    /// can be shorter than its termination grace period if the deletion
+    /// asked for that
+    pub fn deletion_grace_period(&self) -> std::time::Duration {
+        match self.kube_pod.metadata.deletion_grace_period_seconds {
+            Some(seconds) => std::time::Duration::from_secs(seconds.max(0) as u64),
+            None => self.termination_grace_period(),
+        }
+    }
+
+    /// Get the condition types named by the pod's readiness gates
+    pub fn readiness_gates(&self) -> Vec<&str> {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.readiness_gates.as_ref())
+            .map(|gates| {
+                gates
+                    .iter()
+                    .map(|gate| gate.condition_type.as_str())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get the condition of the given type from the pod's status
+    pub fn condition(&self, condition_type: &str) -> Option<&KubePodCondition> {
+        let status = self.kube_pod.status.as_ref()?;
+        status
+            .conditions
+            .as_ref()?
+            .iter()
+            .find(|condition| condition.type_ == condition_type)
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> Option<&Vec<KubeVolume>> {
         let spec = self.kube_pod.spec.as_ref()?;
         spec.volumes.as_ref()
     }
 
+    /// Get the name of the node the pod is scheduled to
+    pub fn node_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.node_name.as_deref()
+    }
+
     /// Get the pod's host ip
     pub fn host_ip(&self) -> Option<&str> {
         let status = self.kube_pod.status.as_ref()?;
@@ -127,6 +214,32 @@ impl Pod {
         false
     }
 
+    /// Indicate if this pod is part of a StatefulSet
+    pub fn is_statefulset(&self) -> bool {
+        if let Some(owners) = &self.kube_pod.meta().owner_references {
+            for owner in owners {
+                if owner.kind == "StatefulSet" {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Get the pod's ordinal in its StatefulSet, which is the number at the
+    /// end of its name, such as 1 for `web-1`. Pods that aren't part of a
+    /// StatefulSet have no ordinal.
+    pub fn stateful_set_ordinal(&self) -> Option<u32> {
+        if !self.is_statefulset() {
+            return None;
+        }
+        let (_, ordinal) = self.name().rsplit_once('-')?;
+        if ordinal.is_empty() || !ordinal.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        ordinal.parse().ok()
+    }
+
     ///  Get a specific annotation from the pod
     pub fn get_annotation(&self, key: &str) -> Option<&str> {
         Some(self.annotations().get(key)?.as_str())
@@ -321,3 +434,32 @@ lazy_static::lazy_static! {
     static ref EMPTY_MAP: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new();
     static ref EMPTY_VEC: Vec<KubeContainer> = Vec::new();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+    use k8s_openapi::Metadata;
+
+    fn pod(name: &str, owner_kind: &str) -> Pod {
+        let mut pod = test_util::pod(name, Default::default());
+        pod.metadata_mut().owner_references = Some(vec![OwnerReference {
+            kind: owner_kind.to_owned(),
+            name: "web".to_owned(),
+            ..Default::default()
+        }]);
+        pod
+    }
+
+    #[test]
+    fn stateful_set_ordinals_come_from_pod_names() {
+        assert_eq!(pod("web-0", "StatefulSet").stateful_set_ordinal(), Some(0));
+        assert_eq!(
+            pod("my-web-12", "StatefulSet").stateful_set_ordinal(),
+            Some(12)
+        );
+        assert_eq!(pod("web-x", "StatefulSet").stateful_set_ordinal(), None);
+        assert_eq!(pod("web-+1", "StatefulSet").stateful_set_ordinal(), None);
+        assert_eq!(pod("web-1", "ReplicaSet").stateful_set_ordinal(), None);
+    }
+}
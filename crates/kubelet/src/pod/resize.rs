@@ -0,0 +1,165 @@
+//! Resizing of the resources of running pods.
+//!
+//! With the `InPlacePodVerticalScaling` feature gate enabled, changes to the
+//! resources of a running pod's containers are applied while they run, as
+//! other kubelets do since Kubernetes 1.27, rather than only once they
+//! restart. Providers apply the new resources themselves, and report how the
+//! resize went in the pod's `status.resize`: a resize that can't be applied
+//! yet is `Deferred`, and retried until it can be, and one that can't be
+//! applied without restarting the containers is `Infeasible`. Once a resize
+//! is applied, `status.resize` is cleared.
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, PatchParams};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::pod::Pod;
+
+/// The feature gate that enables resizing running pods
+pub const IN_PLACE_POD_VERTICAL_SCALING: &str = "InPlacePodVerticalScaling";
+
+/// How the resize of a pod is going, as reported in its `status.resize`
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+pub enum ResizeStatus {
+    /// The resize can be applied, but not yet, such as while the node
+    /// doesn't have the resources free
+    Deferred,
+    /// The resize can't be applied without restarting the containers
+    Infeasible,
+}
+
+/// Why a resize couldn't be applied
+#[derive(Debug, Error)]
+pub enum ResizeError {
+    /// The resize can be applied later
+    #[error("resize deferred: {0}")]
+    Deferred(String),
+    /// The resize can't be applied while the containers run
+    #[error("resize infeasible: {0}")]
+    Infeasible(String),
+}
+
+impl ResizeError {
+    /// The status to report the resize with
+    pub fn status(&self) -> ResizeStatus {
+        match self {
+            ResizeError::Deferred(_) => ResizeStatus::Deferred,
+            ResizeError::Infeasible(_) => ResizeStatus::Infeasible,
+        }
+    }
+}
+
+/// The resources of a container that can change while it runs
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerResources {
+    /// The memory limit in bytes, if it has one
+    pub memory_limit: Option<u64>,
+    /// The CPU limit in millicores, if it has one
+    pub cpu_limit_millis: Option<u64>,
+}
+
+/// The resources of each of the pod's containers, by name. Init containers
+/// have finished by the time the pod runs, so they are never resized.
+pub fn container_resources(pod: &Pod) -> anyhow::Result<BTreeMap<String, ContainerResources>> {
+    pod.containers()
+        .iter()
+        .map(|container| {
+            let resources = ContainerResources {
+                memory_limit: container.memory_limit()?,
+                cpu_limit_millis: container.cpu_limit_millis()?,
+            };
+            Ok((container.name().to_owned(), resources))
+        })
+        .collect()
+}
+
+/// Patches the pod's `status.resize` with how its resize is going, or clears
+/// it if `None`.
+pub async fn patch_resize_status(client: &kube::Client, pod: &Pod, status: Option<ResizeStatus>) {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let name = pod.name();
+    let patch = resize_status_patch(status);
+    debug!(
+        "Applying resize status patch to Pod {}: '{:?}'",
+        name, patch
+    );
+    if let Err(e) = api
+        .patch_status(
+            name,
+            &PatchParams::default(),
+            &kube::api::Patch::Merge(patch),
+        )
+        .await
+    {
+        warn!("Pod {} error patching resize status: {:?}", name, e);
+    }
+}
+
+fn resize_status_patch(status: Option<ResizeStatus>) -> serde_json::Value {
+    serde_json::json!({
+        "status": {
+            "resize": status,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use k8s_openapi::api::core::v1::{Container, PodSpec, ResourceRequirements};
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    #[test]
+    fn container_resources_are_read_from_limits() {
+        let limited = Container {
+            name: "limited".to_owned(),
+            resources: Some(ResourceRequirements {
+                limits: Some(
+                    vec![
+                        ("memory".to_owned(), Quantity("64Mi".to_owned())),
+                        ("cpu".to_owned(), Quantity("250m".to_owned())),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+                requests: None,
+            }),
+            ..Default::default()
+        };
+        let unlimited = Container {
+            name: "unlimited".to_owned(),
+            ..Default::default()
+        };
+        let pod = test_util::pod(
+            "pod",
+            PodSpec {
+                containers: vec![limited, unlimited],
+                ..Default::default()
+            },
+        );
+        let resources = container_resources(&pod).unwrap();
+        assert_eq!(
+            resources["limited"],
+            ContainerResources {
+                memory_limit: Some(64 * 1024 * 1024),
+                cpu_limit_millis: Some(250),
+            }
+        );
+        assert_eq!(resources["unlimited"], ContainerResources::default());
+    }
+
+    #[test]
+    fn resize_status_is_cleared_with_null() {
+        assert_eq!(
+            resize_status_patch(Some(ResizeStatus::Deferred)),
+            serde_json::json!({"status": {"resize": "Deferred"}})
+        );
+        assert_eq!(
+            resize_status_patch(None),
+            serde_json::json!({"status": {"resize": null}})
+        );
+    }
+}
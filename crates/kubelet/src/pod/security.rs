@@ -0,0 +1,523 @@
+//! Enforcement of the Kubernetes Pod Security Standards.
+//!
+//! The level to enforce is read from the `pod-security.kubernetes.io/enforce`
+//! label of the pod's namespace. See
+//! <https://kubernetes.io/docs/concepts/security/pod-security-standards/> for
+//! the definition of each level.
+use k8s_openapi::api::core::v1::{Namespace, PodSpec, Volume as KubeVolume};
+use kube::api::Api;
+use thiserror::Error;
+
+use crate::container::Container;
+use crate::pod::Pod;
+
+/// The namespace label that selects the Pod Security Standards level to enforce
+pub const ENFORCE_LABEL: &str = "pod-security.kubernetes.io/enforce";
+
+/// The annotation holding the seccomp profile for all containers in a pod.
+/// Versions of Kubernetes before seccomp profiles were added to the security
+/// context use annotations to select them
+const POD_SECCOMP_ANNOTATION: &str = "seccomp.security.alpha.kubernetes.io/pod";
+/// The prefix of the annotation holding the seccomp profile for a single
+/// container. The container name follows the prefix
+const CONTAINER_SECCOMP_ANNOTATION_PREFIX: &str = "container.seccomp.security.alpha.kubernetes.io/";
+const SECCOMP_UNCONFINED: &str = "unconfined";
+const SECCOMP_DEFAULT_PROFILES: &[&str] = &["runtime/default", "docker/default"];
+
+/// The capabilities that the baseline level allows containers to add
+const BASELINE_CAPABILITIES: &[&str] = &[
+    "AUDIT_WRITE",
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "MKNOD",
+    "NET_BIND_SERVICE",
+    "SETFCAP",
+    "SETGID",
+    "SETPCAP",
+    "SETUID",
+    "SYS_CHROOT",
+];
+/// The capabilities that the restricted level allows containers to add
+const RESTRICTED_CAPABILITIES: &[&str] = &["NET_BIND_SERVICE"];
+/// The volume types that the restricted level allows
+const RESTRICTED_VOLUME_TYPES: &[&str] = &[
+    "configMap",
+    "csi",
+    "downwardAPI",
+    "emptyDir",
+    "ephemeral",
+    "persistentVolumeClaim",
+    "projected",
+    "secret",
+];
+
+/// A Pod Security Standards level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Unrestricted. No pods are rejected
+    Privileged,
+    /// Prevents known privilege escalations
+    Baseline,
+    /// Follows current pod hardening best practices
+    Restricted,
+}
+
+impl std::str::FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "privileged" => Ok(Level::Privileged),
+            "baseline" => Ok(Level::Baseline),
+            "restricted" => Ok(Level::Restricted),
+            other => Err(anyhow::anyhow!(
+                "unknown pod security level {}, expected one of privileged, baseline or restricted",
+                other
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Privileged => "privileged".fmt(f),
+            Level::Baseline => "baseline".fmt(f),
+            Level::Restricted => "restricted".fmt(f),
+        }
+    }
+}
+
+impl Level {
+    /// Returns the level set by the labels of a namespace. Namespaces without
+    /// the enforce label are privileged.
+    pub fn from_labels(
+        labels: Option<&std::collections::BTreeMap<String, String>>,
+    ) -> anyhow::Result<Self> {
+        match labels.and_then(|l| l.get(ENFORCE_LABEL)) {
+            Some(level) => level.parse(),
+            None => Ok(Level::Privileged),
+        }
+    }
+
+    /// Returns a description of each way in which the pod violates this
+    /// level. An empty list means the pod is allowed to run.
+    pub fn violations(&self, pod: &Pod) -> Vec<String> {
+        let mut violations = vec![];
+        if *self == Level::Privileged {
+            return violations;
+        }
+        let spec = match pod.as_kube_pod().spec.as_ref() {
+            Some(spec) => spec,
+            None => return violations,
+        };
+        baseline_violations(pod, spec, &mut violations);
+        if *self == Level::Restricted {
+            restricted_violations(pod, spec, &mut violations);
+        }
+        violations
+    }
+}
+
+/// Why the level to enforce for pods in a namespace is unknown
+#[derive(Debug, Error)]
+pub enum LevelError {
+    /// The namespace couldn't be read, such as when the node isn't allowed to
+    #[error("unable to read namespace {namespace}: {source}")]
+    Unreadable {
+        /// The namespace
+        namespace: String,
+        /// Why it couldn't be read
+        source: kube::Error,
+    },
+    /// The enforce label of the namespace isn't a level
+    #[error(
+        "namespace {namespace} has an invalid {} label: {message}",
+        ENFORCE_LABEL
+    )]
+    InvalidLabel {
+        /// The namespace
+        namespace: String,
+        /// Why the label is invalid
+        message: String,
+    },
+}
+
+/// Fetches the level to enforce for pods in the given namespace.
+pub async fn namespace_level(client: &kube::Client, namespace: &str) -> Result<Level, LevelError> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    let labels = namespaces
+        .get(namespace)
+        .await
+        .map_err(|source| LevelError::Unreadable {
+            namespace: namespace.to_owned(),
+            source,
+        })?
+        .metadata
+        .labels;
+    Level::from_labels(labels.as_ref()).map_err(|e| LevelError::InvalidLabel {
+        namespace: namespace.to_owned(),
+        message: e.to_string(),
+    })
+}
+
+fn baseline_violations(pod: &Pod, spec: &PodSpec, violations: &mut Vec<String>) {
+    for (field, enabled) in &[
+        ("hostNetwork", spec.host_network),
+        ("hostPID", spec.host_pid),
+        ("hostIPC", spec.host_ipc),
+    ] {
+        if enabled.unwrap_or(false) {
+            violations.push(format!("host namespaces ({}=true)", field));
+        }
+    }
+
+    for volume in spec.volumes.iter().flatten() {
+        if volume.host_path.is_some() {
+            violations.push(format!("hostPath volumes (volume {:?})", volume.name));
+        }
+    }
+
+    for container in pod.all_containers() {
+        let security_context = container.security_context();
+        if security_context.and_then(|s| s.privileged).unwrap_or(false) {
+            violations.push(format!("privileged (container {:?})", container.name()));
+        }
+        let disallowed: Vec<&String> = added_capabilities(&container)
+            .filter(|c| !BASELINE_CAPABILITIES.contains(&c.as_str()))
+            .collect();
+        if !disallowed.is_empty() {
+            violations.push(format!(
+                "non-default capabilities (container {:?} adds {:?})",
+                container.name(),
+                disallowed
+            ));
+        }
+        let host_ports: Vec<i32> = container
+            .ports()
+            .iter()
+            .flatten()
+            .filter_map(|p| p.host_port)
+            .filter(|p| *p != 0)
+            .collect();
+        if !host_ports.is_empty() {
+            violations.push(format!(
+                "hostPort (container {:?} uses {:?})",
+                container.name(),
+                host_ports
+            ));
+        }
+        if seccomp_profile(pod, &container) == Some(SECCOMP_UNCONFINED) {
+            violations.push(format!(
+                "seccomp profile (container {:?} is unconfined)",
+                container.name()
+            ));
+        }
+    }
+}
+
+fn restricted_violations(pod: &Pod, spec: &PodSpec, violations: &mut Vec<String>) {
+    for volume in spec.volumes.iter().flatten() {
+        let volume_type = volume_type(volume);
+        // hostPath volumes are already reported as a baseline violation
+        if volume.host_path.is_none() && !RESTRICTED_VOLUME_TYPES.contains(&volume_type.as_str()) {
+            violations.push(format!(
+                "restricted volume types (volume {:?} uses {})",
+                volume.name, volume_type
+            ));
+        }
+    }
+
+    let pod_security_context = spec.security_context.as_ref();
+    for container in pod.all_containers() {
+        let security_context = container.security_context();
+        if security_context
+            .and_then(|s| s.allow_privilege_escalation)
+            .unwrap_or(true)
+        {
+            violations.push(format!(
+                "allowPrivilegeEscalation != false (container {:?})",
+                container.name()
+            ));
+        }
+
+        let run_as_non_root = security_context
+            .and_then(|s| s.run_as_non_root)
+            .or_else(|| pod_security_context.and_then(|s| s.run_as_non_root));
+        if run_as_non_root != Some(true) {
+            violations.push(format!(
+                "runAsNonRoot != true (container {:?})",
+                container.name()
+            ));
+        }
+        let run_as_user = security_context
+            .and_then(|s| s.run_as_user)
+            .or_else(|| pod_security_context.and_then(|s| s.run_as_user));
+        if run_as_user == Some(0) {
+            violations.push(format!("runAsUser=0 (container {:?})", container.name()));
+        }
+
+        // Capabilities outside the baseline set are already reported as a
+        // baseline violation
+        let disallowed: Vec<&String> = added_capabilities(&container)
+            .filter(|c| BASELINE_CAPABILITIES.contains(&c.as_str()))
+            .filter(|c| !RESTRICTED_CAPABILITIES.contains(&c.as_str()))
+            .collect();
+        if !disallowed.is_empty() {
+            violations.push(format!(
+                "unrestricted capabilities (container {:?} adds {:?})",
+                container.name(),
+                disallowed
+            ));
+        }
+
+        match seccomp_profile(pod, &container) {
+            // Unconfined profiles are already reported as a baseline violation
+            Some(SECCOMP_UNCONFINED) => (),
+            Some(profile) if SECCOMP_DEFAULT_PROFILES.contains(&profile) => (),
+            Some(profile) => violations.push(format!(
+                "seccomp profile (container {:?} uses {:?}, must be runtime/default)",
+                container.name(),
+                profile
+            )),
+            None => violations.push(format!(
+                "seccomp profile (container {:?} must set runtime/default)",
+                container.name()
+            )),
+        }
+    }
+}
+
+fn added_capabilities(container: &Container) -> impl Iterator<Item = &String> {
+    container
+        .security_context()
+        .and_then(|s| s.capabilities.as_ref())
+        .and_then(|c| c.add.as_ref())
+        .into_iter()
+        .flatten()
+}
+
+/// Returns the seccomp profile for a container, such as `runtime/default` or
+/// `localhost/<path>`, falling back to the profile for the whole pod
+pub fn seccomp_profile<'a>(pod: &'a Pod, container: &Container) -> Option<&'a str> {
+    pod.get_annotation(&format!(
+        "{}{}",
+        CONTAINER_SECCOMP_ANNOTATION_PREFIX,
+        container.name()
+    ))
+    .or_else(|| pod.get_annotation(POD_SECCOMP_ANNOTATION))
+}
+
+/// Returns the name of the volume source field that is set on a volume, such
+/// as `configMap` or `hostPath`
+fn volume_type(volume: &KubeVolume) -> String {
+    let value = serde_json::to_value(volume).unwrap_or_default();
+    value
+        .as_object()
+        .and_then(|fields| fields.keys().find(|k| k.as_str() != "name").cloned())
+        // A volume with no source is an empty dir
+        .unwrap_or_else(|| "emptyDir".to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn pod(pod: serde_json::Value) -> Pod {
+        let pod: k8s_openapi::api::core::v1::Pod =
+            serde_json::from_value(pod).expect("should be a valid pod");
+        Pod::from(pod)
+    }
+
+    fn restricted_pod() -> serde_json::Value {
+        json!({
+            "metadata": {
+                "name": "hello",
+                "annotations": {
+                    "seccomp.security.alpha.kubernetes.io/pod": "runtime/default"
+                }
+            },
+            "spec": {
+                "securityContext": {
+                    "runAsNonRoot": true,
+                    "runAsUser": 1000
+                },
+                "containers": [{
+                    "name": "hello",
+                    "image": "webassembly.azurecr.io/hello:v1",
+                    "securityContext": {
+                        "allowPrivilegeEscalation": false
+                    }
+                }],
+                "volumes": [{
+                    "name": "config",
+                    "configMap": { "name": "config" }
+                }]
+            }
+        })
+    }
+
+    #[test]
+    fn levels_are_read_from_namespace_labels() {
+        let mut labels = std::collections::BTreeMap::new();
+        assert_eq!(Level::Privileged, Level::from_labels(None).unwrap());
+        assert_eq!(
+            Level::Privileged,
+            Level::from_labels(Some(&labels)).unwrap()
+        );
+        labels.insert(ENFORCE_LABEL.to_owned(), "restricted".to_owned());
+        assert_eq!(
+            Level::Restricted,
+            Level::from_labels(Some(&labels)).unwrap()
+        );
+        labels.insert(ENFORCE_LABEL.to_owned(), "strict".to_owned());
+        assert!(Level::from_labels(Some(&labels)).is_err());
+    }
+
+    #[test]
+    fn privileged_allows_anything() {
+        let mut spec = restricted_pod();
+        spec["spec"]["hostNetwork"] = json!(true);
+        spec["spec"]["volumes"] = json!([{ "name": "root", "hostPath": { "path": "/" } }]);
+        assert!(Level::Privileged.violations(&pod(spec)).is_empty());
+    }
+
+    #[test]
+    fn restricted_allows_hardened_pods() {
+        let pod = pod(restricted_pod());
+        assert!(Level::Restricted.violations(&pod).is_empty());
+        assert!(Level::Baseline.violations(&pod).is_empty());
+    }
+
+    #[test]
+    fn host_path_volumes_are_rejected() {
+        let mut spec = restricted_pod();
+        spec["spec"]["volumes"] = json!([{ "name": "root", "hostPath": { "path": "/" } }]);
+        let pod = pod(spec);
+        assert_eq!(
+            vec!["hostPath volumes (volume \"root\")".to_owned()],
+            Level::Restricted.violations(&pod)
+        );
+        assert_eq!(1, Level::Baseline.violations(&pod).len());
+    }
+
+    #[test]
+    fn root_containers_are_rejected_by_restricted() {
+        let mut spec = restricted_pod();
+        spec["spec"]["securityContext"] = json!({});
+        spec["spec"]["containers"][0]["securityContext"]["runAsUser"] = json!(0);
+        let pod = pod(spec);
+        let violations = Level::Restricted.violations(&pod);
+        assert_eq!(
+            vec![
+                "runAsNonRoot != true (container \"hello\")".to_owned(),
+                "runAsUser=0 (container \"hello\")".to_owned(),
+            ],
+            violations
+        );
+        assert!(Level::Baseline.violations(&pod).is_empty());
+    }
+
+    #[test]
+    fn non_default_seccomp_profiles_are_rejected_by_restricted() {
+        let mut spec = restricted_pod();
+        spec["metadata"]["annotations"]["container.seccomp.security.alpha.kubernetes.io/hello"] =
+            json!("localhost/custom.json");
+        let pod = pod(spec);
+        assert_eq!(1, Level::Restricted.violations(&pod).len());
+        assert!(Level::Baseline.violations(&pod).is_empty());
+    }
+
+    #[test]
+    fn unconfined_seccomp_is_rejected_by_baseline() {
+        let mut spec = restricted_pod();
+        spec["metadata"]["annotations"]["seccomp.security.alpha.kubernetes.io/pod"] =
+            json!("unconfined");
+        let pod = pod(spec);
+        assert_eq!(
+            vec!["seccomp profile (container \"hello\" is unconfined)".to_owned()],
+            Level::Baseline.violations(&pod)
+        );
+        assert_eq!(1, Level::Restricted.violations(&pod).len());
+    }
+
+    #[test]
+    fn restricted_volume_types_are_enforced() {
+        let mut spec = restricted_pod();
+        spec["spec"]["volumes"] =
+            json!([{ "name": "data", "nfs": { "server": "nfs", "path": "/" } }]);
+        let pod = pod(spec);
+        assert_eq!(
+            vec!["restricted volume types (volume \"data\" uses nfs)".to_owned()],
+            Level::Restricted.violations(&pod)
+        );
+        assert!(Level::Baseline.violations(&pod).is_empty());
+    }
+
+    /// Starts an API server serving the namespaces `restricted`, whose pods
+    /// are restricted, and `strict`, whose enforce label isn't a level, and
+    /// forbidding reads of any others
+    async fn start_api() -> kube::Client {
+        use warp::Filter;
+        let routes = warp::path!("api" / "v1" / "namespaces" / String).map(|name: String| {
+            let level = match name.as_str() {
+                "restricted" => "restricted",
+                "strict" => "strict",
+                _ => {
+                    let status = json!({
+                        "kind": "Status",
+                        "apiVersion": "v1",
+                        "status": "Failure",
+                        "reason": "Forbidden",
+                        "message": format!("namespaces {:?} is forbidden", name),
+                        "code": 403,
+                    });
+                    return warp::reply::with_status(
+                        warp::reply::json(&status),
+                        http::StatusCode::FORBIDDEN,
+                    );
+                }
+            };
+            let namespace = json!({
+                "apiVersion": "v1",
+                "kind": "Namespace",
+                "metadata": { "name": name, "labels": { ENFORCE_LABEL: level } },
+            });
+            warp::reply::with_status(warp::reply::json(&namespace), http::StatusCode::OK)
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            warp::serve(routes)
+                .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        kube::Client::new(kube::Config::new(url.parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn levels_that_cannot_be_found_are_told_apart() {
+        let client = start_api().await;
+        assert_eq!(
+            Level::Restricted,
+            namespace_level(&client, "restricted").await.unwrap()
+        );
+        match namespace_level(&client, "strict").await {
+            Err(e @ LevelError::InvalidLabel { .. }) => assert!(
+                e.to_string().starts_with(
+                    "namespace strict has an invalid pod-security.kubernetes.io/enforce label"
+                ),
+                "{}",
+                e
+            ),
+            other => panic!("expected an invalid label, got {:?}", other),
+        }
+        assert!(matches!(
+            namespace_level(&client, "kube-system").await,
+            Err(LevelError::Unreadable { .. })
+        ));
+    }
+}
@@ -5,8 +5,8 @@ use krator::{Manifest, ObjectState, SharedState, State, Transition};
 /// Prelude for Pod state machines.
 pub mod prelude {
     pub use crate::pod::{
-        make_status, make_status_with_containers, status::StatusBuilder, Phase, Pod,
-        Status as PodStatus,
+        make_running_status, make_status, make_status_with_containers, status::StatusBuilder,
+        Phase, Pod, Status as PodStatus,
     };
     pub use krator::{Manifest, ObjectState, SharedState, State, Transition, TransitionTo};
 }
@@ -4,7 +4,9 @@ use super::Pod;
 use crate::container::make_initial_container_status;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
 use k8s_openapi::api::core::v1::PodStatus as KubePodStatus;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use krator::{Manifest, ObjectStatus};
 use kube::api::PatchParams;
 use kube::Api;
@@ -126,6 +128,77 @@ pub fn make_status(phase: Phase, reason: &str) -> Status {
         .build()
 }
 
+/// The reason the pod isn't ready when the conditions of its readiness gates
+/// aren't all `True`
+const READINESS_GATES_NOT_READY: &str = "ReadinessGatesNotReady";
+
+/// Create the status patch of a running Pod, whose containers are ready. The
+/// Pod is only ready if the conditions of all its readiness gates are `True`,
+/// and the patch includes those conditions.
+pub fn make_running_status(pod: &Pod) -> Status {
+    let unready_gate = unready_readiness_gate(pod);
+    let mut conditions: Vec<KubePodCondition> = pod
+        .readiness_gates()
+        .into_iter()
+        .filter_map(|gate| pod.condition(gate).cloned())
+        .collect();
+    conditions.push(make_condition(pod, "ContainersReady", None));
+    conditions.push(make_condition(
+        pod,
+        "Ready",
+        unready_gate
+            .as_deref()
+            .map(|message| (READINESS_GATES_NOT_READY, message)),
+    ));
+    StatusBuilder::new()
+        .phase(Phase::Running)
+        .reason("Running")
+        .message("Running")
+        .conditions(conditions)
+        .build()
+}
+
+/// Returns why the Pod isn't ready because of its readiness gates, if the
+/// condition of any of them isn't `True`
+pub fn unready_readiness_gate(pod: &Pod) -> Option<String> {
+    pod.readiness_gates()
+        .into_iter()
+        .find_map(|gate| match pod.condition(gate) {
+            None => Some(format!(
+                "corresponding condition of pod readiness gate \"{}\" does not exist.",
+                gate
+            )),
+            Some(condition) if condition.status != "True" => Some(format!(
+                "the status of pod readiness gate \"{}\" is not \"True\", but {}",
+                gate, condition.status
+            )),
+            Some(_) => None,
+        })
+}
+
+/// Create a condition of the Pod that is `True`, or `False` for the given
+/// reason and message. The condition keeps its last transition time if its
+/// status hasn't changed.
+fn make_condition(
+    pod: &Pod,
+    condition_type: &str,
+    unmet: Option<(&str, &str)>,
+) -> KubePodCondition {
+    let status = if unmet.is_some() { "False" } else { "True" };
+    let last_transition_time = match pod.condition(condition_type) {
+        Some(condition) if condition.status == status => condition.last_transition_time.clone(),
+        _ => Some(Time(chrono::Utc::now())),
+    };
+    KubePodCondition {
+        type_: condition_type.to_owned(),
+        status: status.to_owned(),
+        reason: unmet.map(|(reason, _)| reason.to_owned()),
+        message: unmet.map(|(_, message)| message.to_owned()),
+        last_transition_time,
+        last_probe_time: None,
+    }
+}
+
 /// Create basic Pod status patch.
 pub fn make_status_with_containers(
     phase: Phase,
@@ -191,6 +264,12 @@ impl StatusBuilder {
         self
     }
 
+    /// Set Pod conditions.
+    pub fn conditions(mut self, conditions: Vec<KubePodCondition>) -> StatusBuilder {
+        self.0.conditions = Some(conditions);
+        self
+    }
+
     /// Finalize Pod Status from builder.
     pub fn build(self) -> Status {
         Status(self.0)
@@ -249,6 +328,10 @@ impl ObjectStatus for Status {
             status.insert("initContainerStatuses".to_string(), serde_json::json!(s));
         };
 
+        if let Some(s) = self.0.conditions.clone() {
+            status.insert("conditions".to_string(), serde_json::json!(s));
+        };
+
         serde_json::json!(
             {
                 "metadata": {
@@ -267,3 +350,110 @@ impl ObjectStatus for Status {
             .build()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use k8s_openapi::api::core::v1::{PodReadinessGate, PodSpec};
+
+    fn pod(gates: &[&str], conditions: &[(&str, &str)]) -> Pod {
+        let spec = PodSpec {
+            readiness_gates: Some(
+                gates
+                    .iter()
+                    .map(|gate| PodReadinessGate {
+                        condition_type: gate.to_string(),
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let mut pod = test_util::pod("pod", spec).into_kube_pod();
+        pod.status = Some(KubePodStatus {
+            conditions: Some(
+                conditions
+                    .iter()
+                    .map(|(type_, status)| KubePodCondition {
+                        type_: type_.to_string(),
+                        status: status.to_string(),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+        Pod::from(pod)
+    }
+
+    fn ready_condition(status: &Status) -> &KubePodCondition {
+        status
+            .0
+            .conditions
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|condition| condition.type_ == "Ready")
+            .unwrap()
+    }
+
+    #[test]
+    fn pods_without_readiness_gates_are_ready() {
+        let status = make_running_status(&pod(&[], &[]));
+        assert_eq!(ready_condition(&status).status, "True");
+    }
+
+    #[test]
+    fn pods_are_ready_once_all_readiness_gates_are_true() {
+        let gates = ["example.com/feature-1", "example.com/feature-2"];
+
+        let missing = pod(&gates, &[("example.com/feature-1", "True")]);
+        assert!(unready_readiness_gate(&missing)
+            .unwrap()
+            .contains("example.com/feature-2"));
+        let status = make_running_status(&missing);
+        assert_eq!(ready_condition(&status).status, "False");
+        assert_eq!(
+            ready_condition(&status).reason.as_deref(),
+            Some("ReadinessGatesNotReady")
+        );
+
+        let not_true = pod(
+            &gates,
+            &[
+                ("example.com/feature-1", "True"),
+                ("example.com/feature-2", "False"),
+            ],
+        );
+        assert!(unready_readiness_gate(&not_true).is_some());
+
+        let ready = pod(
+            &gates,
+            &[
+                ("example.com/feature-1", "True"),
+                ("example.com/feature-2", "True"),
+            ],
+        );
+        assert_eq!(unready_readiness_gate(&ready), None);
+        let status = make_running_status(&ready);
+        assert_eq!(ready_condition(&status).status, "True");
+        // The readiness gates' conditions are reported with the pod's own
+        let types: Vec<&str> = status
+            .0
+            .conditions
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|condition| condition.type_.as_str())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                "example.com/feature-1",
+                "example.com/feature-2",
+                "ContainersReady",
+                "Ready"
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! The pods the kubelet is running, as it sees them.
+//!
+//! Pods are added when their state machine first enters a state, and removed
+//! once they have been deregistered. Each listed pod is the latest manifest
+//! the kubelet has, annotated with the state its state machine is in, which
+//! is served at `/pods` for debugging.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use krator::Manifest;
+
+use crate::pod::{Pod, PodKey};
+
+/// The annotation listed pods have with the state their state machine is in
+pub const STATE_ANNOTATION: &str = "krustlet.dev/state";
+
+/// A pod the kubelet is running, and the state its state machine is in
+struct StoredPod {
+    manifest: Manifest<Pod>,
+    state: &'static str,
+}
+
+/// The pods the kubelet is running, which clones share
+#[derive(Clone, Default)]
+pub(crate) struct PodStore {
+    pods: Arc<RwLock<BTreeMap<PodKey, StoredPod>>>,
+}
+
+impl PodStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the state machine of the pod in `manifest` has entered
+    /// `state`, adding the pod if it wasn't listed
+    pub(crate) fn entered(&self, manifest: &Manifest<Pod>, state: &'static str) {
+        let key = PodKey::from(&manifest.latest());
+        let mut pods = self.pods.write().expect("pod store lock poisoned");
+        pods.insert(
+            key,
+            StoredPod {
+                manifest: manifest.clone(),
+                state,
+            },
+        );
+    }
+
+    /// Stops listing `pod`
+    pub(crate) fn remove(&self, pod: &Pod) {
+        let mut pods = self.pods.write().expect("pod store lock poisoned");
+        pods.remove(&PodKey::from(pod));
+    }
+
+    /// The latest manifest of each listed pod, ordered by name then namespace,
+    /// annotated with the state it is in
+    pub(crate) fn list(&self) -> Vec<Pod> {
+        let pods = self.pods.read().expect("pod store lock poisoned");
+        pods.values()
+            .map(|stored| {
+                let mut pod = stored.manifest.latest();
+                pod.kube_pod
+                    .metadata
+                    .annotations
+                    .get_or_insert_with(Default::default)
+                    .insert(STATE_ANNOTATION.to_owned(), stored.state.to_owned());
+                pod
+            })
+            .collect()
+    }
+
+    /// The listed pods as a Kubernetes `PodList`
+    pub(crate) fn pod_list(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": "PodList",
+            "apiVersion": "v1",
+            "metadata": {},
+            "items": self.list(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn manifest(namespace: &str, name: &str) -> Manifest<Pod> {
+        let pod: KubePod = serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": name, "namespace": namespace },
+            "spec": { "containers": [{ "name": "main", "image": "hello-wasm:v1" }] },
+        }))
+        .unwrap();
+        Manifest::new(Pod::from(pod)).1
+    }
+
+    fn names(store: &PodStore) -> Vec<(String, String)> {
+        store
+            .list()
+            .iter()
+            .map(|pod| {
+                (
+                    pod.name().to_owned(),
+                    pod.annotations()[STATE_ANNOTATION].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pods_are_listed_until_removed() {
+        let store = PodStore::new();
+        let web = manifest("default", "web");
+        let db = manifest("data", "db");
+
+        store.entered(&web, "Registered");
+        store.entered(&db, "Registered");
+        store.entered(&web, "Running");
+        assert_eq!(
+            names(&store),
+            vec![
+                ("db".to_owned(), "Registered".to_owned()),
+                ("web".to_owned(), "Running".to_owned()),
+            ]
+        );
+
+        store.remove(&web.latest());
+        assert_eq!(
+            names(&store),
+            vec![("db".to_owned(), "Registered".to_owned())]
+        );
+        store.remove(&db.latest());
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn pods_are_served_as_a_pod_list() {
+        let store = PodStore::new();
+        store.entered(&manifest("default", "web"), "ImagePull");
+
+        let list = store.pod_list();
+        assert_eq!(list["kind"], "PodList");
+        assert_eq!(list["apiVersion"], "v1");
+        let items = list["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["kind"], "Pod");
+        assert_eq!(items[0]["metadata"]["name"], "web");
+        assert_eq!(
+            items[0]["metadata"]["annotations"][STATE_ANNOTATION],
+            "ImagePull"
+        );
+    }
+}
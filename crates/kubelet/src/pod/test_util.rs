@@ -0,0 +1,20 @@
+//! Pods shared by the tests of the kubelet's modules
+
+use super::Pod;
+use k8s_openapi::api::core::v1::{Pod as KubePod, PodSpec};
+use kube::api::ObjectMeta;
+
+/// Returns a pod named `name` in the default namespace, with its name as its
+/// UID too, and the spec `spec`
+pub(crate) fn pod(name: &str, spec: PodSpec) -> Pod {
+    Pod::from(KubePod {
+        metadata: ObjectMeta {
+            name: Some(name.to_owned()),
+            namespace: Some("default".to_owned()),
+            uid: Some(name.to_owned()),
+            ..Default::default()
+        },
+        spec: Some(spec),
+        ..Default::default()
+    })
+}
@@ -1,19 +1,24 @@
 //! Traits and types needed to create backend providers for a Kubelet
 use std::collections::HashMap;
+use std::net::SocketAddr;
 
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
+use k8s_openapi::api::core::v1::{ConfigMap, EnvFromSource, EnvVarSource, Secret};
 use kube::api::Api;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info, warn};
 
 use crate::container::Container;
+use crate::device_plugin_manager::DevicePluginManager;
 use crate::log::Sender;
 use crate::node::Builder;
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Pod;
 use crate::pod::Status as PodStatus;
+use crate::pod::{Pod, PodKey};
+use crate::stats::PodStats;
+use crate::store::Store;
 use krator::{ObjectState, State};
 
 /// A back-end for a Kubelet.
@@ -119,12 +124,32 @@ pub trait Provider: Sized + Send + Sync + 'static {
         sender: Sender,
     ) -> anyhow::Result<()>;
 
-    /// Execute a given command on a workload and then return the result.
+    /// Gets the provider's implementation of `kubectl exec`, if it has one.
     ///
-    /// The default implementation of this returns a message that this feature is
-    /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
-        Err(NotImplementedError.into())
+    /// The default implementation of this returns `None`, and exec requests
+    /// are answered with a failure saying that exec is not supported.
+    /// Override this only when there is an implementation.
+    fn exec_provider(&self) -> Option<&dyn ExecProvider> {
+        None
+    }
+
+    /// Gets the provider's implementation of `kubectl attach`, if it has one.
+    ///
+    /// The default implementation of this returns `None`, and attach requests
+    /// are answered with a failure saying that attach is not supported.
+    /// Override this only when there is an implementation.
+    fn attach_provider(&self) -> Option<&dyn AttachProvider> {
+        None
+    }
+
+    /// Gets the provider's implementation of `kubectl port-forward`, if it
+    /// has one.
+    ///
+    /// The default implementation of this returns `None`, and forwarded
+    /// ports are answered with a failure saying that port forwarding is not
+    /// supported. Override this only when there is an implementation.
+    fn port_forward_provider(&self) -> Option<&dyn PortForwardProvider> {
+        None
     }
 
     /// Gets the path at which to construct temporary directories for volumes.
@@ -137,6 +162,72 @@ pub trait Provider: Sized + Send + Sync + 'static {
         None
     }
 
+    /// Fetch the device plugin manager, which allocates the devices of
+    /// extended resources to containers. When this is `None`, device plugins
+    /// can't register with the node.
+    fn device_plugin_manager(&self) -> Option<Arc<DevicePluginManager>> {
+        None
+    }
+
+    /// Gets the version of the provider's runtime, reported as the node's
+    /// container runtime version in the form `<runtime>://<version>`.
+    ///
+    /// The default implementation reports `mvp`.
+    fn container_runtime_version(&self) -> String {
+        "mvp".to_owned()
+    }
+
+    /// Gets the extended resources the node advertises, such as
+    /// `example.com/wasm-gpu`, and how many of each it has. They are added to
+    /// the node's capacity and allocatable resources when it registers and
+    /// each time its status is updated, and pods are only admitted while
+    /// what they request of them is free.
+    ///
+    /// The default implementation advertises none.
+    fn node_resources(&self) -> HashMap<String, u64> {
+        HashMap::new()
+    }
+
+    /// Gets the resource usage of the provider's running pods and their
+    /// containers, which is served in the node's summary at `/stats/summary`
+    /// for metrics-server and `kubectl top`. Values the provider doesn't know
+    /// should be left out rather than reported as zero. If the CPU or memory
+    /// of a pod as a whole is left out, the kubelet adds up what its
+    /// containers use, and CPU usage rates are worked out from the cumulative
+    /// CPU time of consecutive summaries if they are left out.
+    ///
+    /// The default implementation reports no pods.
+    async fn pod_stats(&self) -> anyhow::Result<Vec<PodStats>> {
+        Ok(vec![])
+    }
+
+    /// Checks that the provider can run pods, for the kubelet's `/healthz`
+    /// and `/readyz`, returning an error saying why if it can't. This is
+    /// called for each request to them, so it should be quick.
+    ///
+    /// The default implementation is always healthy.
+    async fn healthy(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Gets the state named `name` in a pod's snapshot, to resume the pod's
+    /// state machine in when the kubelet restarts. The kubelet saves the
+    /// last state each pod entered for which [`State::is_resumable`] is
+    /// true, by its [`State::name`], along with what
+    /// [`ObjectState::snapshot`] returns for its pod state.
+    ///
+    /// The default implementation returns `None`, and pods are started over
+    /// from the initial state.
+    fn resume_state(&self, _name: &str) -> Option<Box<dyn State<Self::PodState>>> {
+        None
+    }
+
+    /// Fetch the module store, whose images are reported in the node's
+    /// status. When this is `None`, the node reports no images.
+    fn store(&self) -> Option<Arc<dyn Store + Send + Sync>> {
+        None
+    }
+
     /// Resolve the environment variables for a container.
     ///
     /// This generally should not be overwritten unless you need to handle
@@ -175,6 +266,86 @@ pub trait Provider: Sized + Send + Sync + 'static {
     }
 }
 
+/// Input streamed to a command run with [`ExecProvider::exec`], or to a
+/// container attached to with [`AttachProvider::attach`]
+pub type ExecInput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Output streamed from a command run with [`ExecProvider::exec`], or from a
+/// container attached to with [`AttachProvider::attach`]
+pub type ExecOutput = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The exit code of a command run with [`ExecProvider::exec`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitCode(pub i32);
+
+impl ExitCode {
+    /// Whether the command succeeded, which is when it exited with 0
+    pub fn success(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// A provider that can run commands in the containers of its pods, for
+/// `kubectl exec`.
+///
+/// Providers opt into this by returning themselves from
+/// [`Provider::exec_provider`].
+#[async_trait]
+pub trait ExecProvider: Send + Sync {
+    /// Runs `command` in the container of the given pod, streaming its
+    /// standard streams to and from the caller until it exits. Streams the
+    /// caller didn't ask for are `None`. If `tty` is set, the caller asked
+    /// for a terminal, which providers may ignore.
+    #[allow(clippy::too_many_arguments)]
+    async fn exec(
+        &self,
+        pod: PodKey,
+        container: String,
+        command: Vec<String>,
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+        tty: bool,
+    ) -> anyhow::Result<ExitCode>;
+}
+
+/// A provider that can connect callers to the standard streams of the running
+/// containers of its pods, for `kubectl attach`.
+///
+/// Providers opt into this by returning themselves from
+/// [`Provider::attach_provider`].
+#[async_trait]
+pub trait AttachProvider: Send + Sync {
+    /// Streams the output of the running container of the given pod to the
+    /// caller, and the caller's input to the container if its spec has
+    /// `stdin` set, until the container exits. Streams the caller didn't ask
+    /// for are `None`. The caller detaches by dropping the returned future,
+    /// which must not stop the container. If `tty` is set, the caller asked
+    /// for a terminal, which providers may ignore.
+    async fn attach(
+        &self,
+        pod: PodKey,
+        container: String,
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+        tty: bool,
+    ) -> anyhow::Result<()>;
+}
+
+/// A provider that can forward connections to the ports of its pods, for
+/// `kubectl port-forward`.
+///
+/// Providers opt into this by returning themselves from
+/// [`Provider::port_forward_provider`].
+#[async_trait]
+pub trait PortForwardProvider: Send + Sync {
+    /// Returns the address on the node that connections to the given port of
+    /// the pod can be made to. The kubelet connects to it for each forwarded
+    /// connection, and copies bytes between it and the caller.
+    async fn port_forward(&self, pod: PodKey, port: u16) -> anyhow::Result<SocketAddr>;
+}
+
 /// Resolve the environment variables for a container.
 ///
 /// This generally should not be overwritten unless you need to handle
@@ -182,12 +353,21 @@ pub trait Provider: Sized + Send + Sync + 'static {
 /// custom Downward API fields.
 ///
 /// It is safe to call from within your own providers.
+///
+/// The variables of the container's `envFrom` sources come first, in order,
+/// and its `env` variables take precedence over them.
 pub async fn env_vars(
     container: &Container,
     pod: &Pod,
     client: &kube::Client,
 ) -> HashMap<String, String> {
     let mut env = HashMap::new();
+    for source in container.env_from().iter().flatten() {
+        match env_from_source(source, client, pod.namespace()).await {
+            Ok(vars) => env.extend(vars),
+            Err(e) => error!("Unable to get envFrom variables: {:?}", e),
+        }
+    }
     let vars = match container.env().as_ref() {
         Some(e) => e,
         None => return env,
@@ -207,6 +387,77 @@ pub async fn env_vars(
     env
 }
 
+/// Gets the variables of all the keys of an `envFrom` ConfigMap or Secret,
+/// with the source's prefix. A source that doesn't exist gives no variables
+/// if it is optional, and is an error if not. Secret values that aren't UTF-8
+/// can't be variables, so their keys are skipped.
+async fn env_from_source(
+    source: &EnvFromSource,
+    client: &kube::Client,
+    ns: &str,
+) -> anyhow::Result<HashMap<String, String>> {
+    let prefix = source.prefix.as_deref().unwrap_or_default();
+    let data: Vec<(String, String)> = if let Some(cfref) = source.config_map_ref.as_ref() {
+        let name = cfref.name.as_deref().unwrap_or_default();
+        let config_map = Api::<ConfigMap>::namespaced(client.clone(), ns)
+            .get(name)
+            .await;
+        match optional_source(config_map, "config map", name, cfref.optional)? {
+            Some(cfgmap) => cfgmap.data.unwrap_or_default().into_iter().collect(),
+            None => vec![],
+        }
+    } else if let Some(secref) = source.secret_ref.as_ref() {
+        let name = secref.name.as_deref().unwrap_or_default();
+        let secret = Api::<Secret>::namespaced(client.clone(), ns)
+            .get(name)
+            .await;
+        match optional_source(secret, "secret", name, secref.optional)? {
+            Some(secret) => secret
+                .data
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(k, v)| match String::from_utf8(v.0) {
+                    Ok(v) => Some((k, v)),
+                    Err(_) => {
+                        warn!(
+                            "Skipping key {} of secret {}, as its value is not valid UTF-8",
+                            k, name
+                        );
+                        None
+                    }
+                })
+                .collect(),
+            None => vec![],
+        }
+    } else {
+        vec![]
+    };
+    Ok(data
+        .into_iter()
+        .map(|(k, v)| (format!("{}{}", prefix, k), v))
+        .collect())
+}
+
+/// The `envFrom` source that was fetched, or `None` if it doesn't exist and
+/// is optional
+fn optional_source<T>(
+    fetched: kube::Result<T>,
+    kind: &str,
+    name: &str,
+    optional: Option<bool>,
+) -> anyhow::Result<Option<T>> {
+    match fetched {
+        Ok(source) => Ok(Some(source)),
+        Err(kube::Error::Api(kube::error::ErrorResponse { code: 404, .. }))
+            if optional.unwrap_or(false) =>
+        {
+            debug!("Optional {} {} does not exist", kind, name);
+            Ok(None)
+        }
+        Err(e) => Err(anyhow::anyhow!("unable to fetch {} {}: {}", kind, name, e)),
+    }
+}
+
 /// Called when an env var does not have a value associated with.
 ///
 /// This follows the env_var_source to get the value
@@ -326,9 +577,150 @@ pub enum ProviderError {
         /// The container's name
         container_name: String,
     },
+    /// The container has no previous instance whose log was kept
+    #[error(
+        "previous terminated container {} in pod {} not found",
+        container_name,
+        pod_name
+    )]
+    PreviousContainerNotFound {
+        /// The container's pod's name
+        pod_name: String,
+        /// The container's name
+        container_name: String,
+    },
 }
 
 /// A specific operation is not implemented
 #[derive(Error, Debug)]
 #[error("Operation not supported")]
 pub struct NotImplementedError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{ConfigMapEnvSource, SecretEnvSource};
+    use serde_json::json;
+    use warp::Filter;
+
+    /// Starts an API server serving the config map `config` and the secret
+    /// `credentials` in the namespace `apps`, one of whose values isn't UTF-8
+    async fn start_api() -> kube::Client {
+        let routes = warp::path!("api" / "v1" / "namespaces" / "apps" / String / String).map(
+            |kind: String, name: String| {
+                let object = match (kind.as_str(), name.as_str()) {
+                    ("configmaps", "config") => json!({
+                        "apiVersion": "v1",
+                        "kind": "ConfigMap",
+                        "metadata": { "name": "config" },
+                        "data": { "LEVEL": "debug" },
+                    }),
+                    ("secrets", "credentials") => json!({
+                        "apiVersion": "v1",
+                        "kind": "Secret",
+                        "metadata": { "name": "credentials" },
+                        // "admin", and bytes that aren't UTF-8
+                        "data": { "USER": "YWRtaW4=", "KEY": "//4=" },
+                    }),
+                    _ => {
+                        let status = json!({
+                            "kind": "Status",
+                            "apiVersion": "v1",
+                            "status": "Failure",
+                            "reason": "NotFound",
+                            "message": format!("{} {:?} not found", kind, name),
+                            "code": 404,
+                        });
+                        return warp::reply::with_status(
+                            warp::reply::json(&status),
+                            http::StatusCode::NOT_FOUND,
+                        );
+                    }
+                };
+                warp::reply::with_status(warp::reply::json(&object), http::StatusCode::OK)
+            },
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            warp::serve(routes)
+                .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        kube::Client::new(kube::Config::new(url.parse().unwrap()))
+    }
+
+    fn config_map(name: &str, prefix: Option<&str>, optional: Option<bool>) -> EnvFromSource {
+        EnvFromSource {
+            config_map_ref: Some(ConfigMapEnvSource {
+                name: Some(name.to_owned()),
+                optional,
+            }),
+            prefix: prefix.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    fn secret(name: &str, prefix: Option<&str>, optional: Option<bool>) -> EnvFromSource {
+        EnvFromSource {
+            secret_ref: Some(SecretEnvSource {
+                name: Some(name.to_owned()),
+                optional,
+            }),
+            prefix: prefix.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn env_from_sources_are_prefixed() {
+        let client = start_api().await;
+        let vars = env_from_source(&config_map("config", Some("APP_"), None), &client, "apps")
+            .await
+            .unwrap();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars["APP_LEVEL"], "debug");
+
+        // Values that aren't UTF-8 are skipped rather than left empty
+        let vars = env_from_source(&secret("credentials", Some("DB_"), None), &client, "apps")
+            .await
+            .unwrap();
+        assert_eq!(vars.len(), 1);
+        assert_eq!(vars["DB_USER"], "admin");
+
+        let vars = env_from_source(&secret("credentials", None, None), &client, "apps")
+            .await
+            .unwrap();
+        assert_eq!(vars["USER"], "admin");
+    }
+
+    #[tokio::test]
+    async fn only_required_sources_must_exist() {
+        let client = start_api().await;
+        for source in &[
+            config_map("missing", None, Some(true)),
+            secret("missing", None, Some(true)),
+        ] {
+            let vars = env_from_source(source, &client, "apps").await.unwrap();
+            assert!(vars.is_empty());
+        }
+
+        let err = env_from_source(&config_map("missing", None, None), &client, "apps")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("unable to fetch config map missing"),
+            "{}",
+            err
+        );
+        let err = env_from_source(&secret("missing", None, Some(false)), &client, "apps")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("unable to fetch secret missing"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Parsing and formatting of Kubernetes resource quantities, such as the
+//! `128Mi` in a container's `resources.limits.memory`
+
+/// Binary suffixes and the power of 1024 they multiply by
+const BINARY_SUFFIXES: &[(&str, u32)] = &[
+    ("Ki", 1),
+    ("Mi", 2),
+    ("Gi", 3),
+    ("Ti", 4),
+    ("Pi", 5),
+    ("Ei", 6),
+];
+/// Decimal suffixes and the power of 10 they multiply by
+const DECIMAL_SUFFIXES: &[(&str, i32)] = &[
+    ("n", -9),
+    ("u", -6),
+    ("m", -3),
+    ("", 0),
+    ("k", 3),
+    ("M", 6),
+    ("G", 9),
+    ("T", 12),
+    ("P", 15),
+    ("E", 18),
+];
+
+/// Parses a quantity such as `128Mi`, `1.5G`, `500m` or `1e3`, returning its
+/// value rounded up to a whole number, which for memory is a number of bytes.
+pub fn parse_quantity(quantity: &str) -> anyhow::Result<u64> {
+    parse_scaled_quantity(quantity, 0)
+}
+
+/// Parses a quantity such as `500m` or `2`, returning its value in
+/// thousandths rounded up to a whole number, which for CPU is a number of
+/// millicores.
+pub fn parse_milli_quantity(quantity: &str) -> anyhow::Result<u64> {
+    parse_scaled_quantity(quantity, 3)
+}
+
+/// Formats a whole number, such as a number of bytes, in the canonical form
+/// Kubernetes gives binary quantities: with the largest binary suffix it is
+/// a whole multiple of, such as `3920Mi`, or without a suffix if it isn't a
+/// multiple of 1024.
+pub fn format_binary_quantity(value: u64) -> String {
+    if value == 0 {
+        return "0".to_owned();
+    }
+    BINARY_SUFFIXES
+        .iter()
+        .rev()
+        .find_map(|(suffix, exponent)| {
+            let multiplier = 1024u64.pow(*exponent);
+            if value.is_multiple_of(multiplier) {
+                Some(format!("{}{}", value / multiplier, suffix))
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| value.to_string())
+}
+
+/// Formats a number of thousandths, such as millicores of CPU, in the
+/// canonical form Kubernetes gives decimal quantities: as a whole number if
+/// it is one, such as `4`, and in thousandths otherwise, such as `3500m`.
+pub fn format_milli_quantity(millis: u64) -> String {
+    if millis.is_multiple_of(1000) {
+        (millis / 1000).to_string()
+    } else {
+        format!("{}m", millis)
+    }
+}
+
+/// Parses a quantity, returning its value multiplied by 10 to the power of
+/// `scale` and rounded up to a whole number
+fn parse_scaled_quantity(quantity: &str, scale: i32) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid quantity {:?}", quantity);
+    let number_len = quantity
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(quantity.len());
+    let (number, suffix) = quantity.split_at(number_len);
+    let mut parts = number.splitn(2, '.');
+    let whole = parts.next().unwrap_or_default();
+    let fraction = parts.next().unwrap_or_default();
+    if (whole.is_empty() && fraction.is_empty()) || fraction.contains('.') {
+        return Err(invalid());
+    }
+
+    let (binary_exponent, mut decimal_exponent) =
+        match BINARY_SUFFIXES.iter().find(|(s, _)| *s == suffix) {
+            Some((_, exponent)) => (*exponent, 0),
+            None => match DECIMAL_SUFFIXES.iter().find(|(s, _)| *s == suffix) {
+                Some((_, exponent)) => (0, *exponent),
+                None if suffix.starts_with(['e', 'E']) => {
+                    (0, suffix[1..].parse::<i32>().map_err(|_| invalid())?)
+                }
+                None => return Err(invalid()),
+            },
+        };
+
+    // Work with the digits as an integer, moving the decimal point into the
+    // exponent
+    let digits: u128 = format!("{}{}", whole, fraction)
+        .parse()
+        .map_err(|_| invalid())?;
+    decimal_exponent += scale - fraction.len() as i32;
+    let mut value = 1024u128
+        .checked_pow(binary_exponent)
+        .and_then(|multiplier| digits.checked_mul(multiplier))
+        .ok_or_else(invalid)?;
+    if decimal_exponent >= 0 {
+        value = 10u128
+            .checked_pow(decimal_exponent as u32)
+            .and_then(|multiplier| value.checked_mul(multiplier))
+            .ok_or_else(invalid)?;
+    } else {
+        let divisor = 10u128
+            .checked_pow(decimal_exponent.unsigned_abs())
+            .ok_or_else(invalid)?;
+        value = value.div_ceil(divisor);
+    }
+    std::convert::TryFrom::try_from(value).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plain_numbers_are_parsed() {
+        assert_eq!(parse_quantity("0").unwrap(), 0);
+        assert_eq!(parse_quantity("1024").unwrap(), 1024);
+    }
+
+    #[test]
+    fn binary_suffixes_are_parsed() {
+        assert_eq!(parse_quantity("1Ki").unwrap(), 1024);
+        assert_eq!(parse_quantity("128Mi").unwrap(), 128 * 1024 * 1024);
+        assert_eq!(parse_quantity("2Gi").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_quantity("1.5Ki").unwrap(), 1536);
+    }
+
+    #[test]
+    fn decimal_suffixes_are_parsed() {
+        assert_eq!(parse_quantity("1k").unwrap(), 1000);
+        assert_eq!(parse_quantity("128M").unwrap(), 128_000_000);
+        assert_eq!(parse_quantity("1.5G").unwrap(), 1_500_000_000);
+        assert_eq!(parse_quantity("1e3").unwrap(), 1000);
+        assert_eq!(parse_quantity("1E6").unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn fractional_values_are_rounded_up() {
+        assert_eq!(parse_quantity("500m").unwrap(), 1);
+        assert_eq!(parse_quantity("1500m").unwrap(), 2);
+        assert_eq!(parse_quantity("0.1").unwrap(), 1);
+    }
+
+    #[test]
+    fn milli_quantities_are_parsed() {
+        assert_eq!(parse_milli_quantity("500m").unwrap(), 500);
+        assert_eq!(parse_milli_quantity("2").unwrap(), 2000);
+        assert_eq!(parse_milli_quantity("0.25").unwrap(), 250);
+        assert_eq!(parse_milli_quantity("100u").unwrap(), 1);
+    }
+
+    #[test]
+    fn binary_quantities_are_formatted_canonically() {
+        assert_eq!(format_binary_quantity(0), "0");
+        assert_eq!(format_binary_quantity(1000), "1000");
+        assert_eq!(format_binary_quantity(1536), "1536");
+        assert_eq!(format_binary_quantity(2048), "2Ki");
+        assert_eq!(format_binary_quantity(3920 * 1024 * 1024), "3920Mi");
+        assert_eq!(format_binary_quantity(4 * 1024 * 1024 * 1024), "4Gi");
+        assert_eq!(format_binary_quantity(61_255_492 * 1024), "61255492Ki");
+        for value in &[1536, 3920 * 1024 * 1024, 61_255_492 * 1024] {
+            assert_eq!(
+                parse_quantity(&format_binary_quantity(*value)).unwrap(),
+                *value
+            );
+        }
+    }
+
+    #[test]
+    fn milli_quantities_are_formatted_canonically() {
+        assert_eq!(format_milli_quantity(0), "0");
+        assert_eq!(format_milli_quantity(4000), "4");
+        assert_eq!(format_milli_quantity(3500), "3500m");
+        assert_eq!(format_milli_quantity(100), "100m");
+        assert_eq!(
+            parse_milli_quantity(&format_milli_quantity(3500)).unwrap(),
+            3500
+        );
+    }
+
+    #[test]
+    fn invalid_quantities_are_rejected() {
+        assert!(parse_quantity("").is_err());
+        assert!(parse_quantity("Mi").is_err());
+        assert!(parse_quantity("12Qi").is_err());
+        assert!(parse_quantity("1.2.3").is_err());
+        assert!(parse_quantity("-1").is_err());
+        assert!(parse_quantity("100Ei").is_err());
+    }
+}
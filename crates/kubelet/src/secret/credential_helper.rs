@@ -0,0 +1,252 @@
+//! Resolves registry authentication using Docker credential helpers
+//!
+//! Credential helpers are programs named `docker-credential-<name>` that
+//! return credentials for a registry, often minting short-lived tokens, such
+//! as `docker-credential-ecr-login`. The helper to use for each registry is
+//! read from the `credHelpers` and `credsStore` fields of a Docker config
+//! file. See <https://github.com/docker/docker-credential-helpers> for the
+//! protocol.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use oci_distribution::secrets::RegistryAuth;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// The prefix of the name of every credential helper program
+const HELPER_PROGRAM_PREFIX: &str = "docker-credential-";
+/// The username helpers return when the secret is an identity token
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+/// How long credentials returned by a helper are reused for
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long to wait for a helper before falling back to other credentials
+const HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The credential helpers configured in a Docker config file
+pub struct CredentialHelpers {
+    /// The helper for each registry, by registry host name
+    cred_helpers: HashMap<String, String>,
+    /// The helper for registries without their own entry in `cred_helpers`
+    creds_store: Option<String>,
+    /// The directory containing the helper programs. If not set, helpers are
+    /// found using the `PATH`
+    helper_dir: Option<PathBuf>,
+    cache_ttl: Duration,
+    cache: RwLock<HashMap<String, CachedCredentials>>,
+}
+
+impl CredentialHelpers {
+    /// Loads the credential helpers configured in the given Docker config file
+    pub fn from_docker_config<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let config = std::fs::read(path.as_ref()).map_err(|e| {
+            anyhow::anyhow!(
+                "unable to read Docker config {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Self::parse(&config, None)
+    }
+
+    fn parse(config: &[u8], helper_dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let config: DockerConfig = serde_json::from_slice(config)
+            .map_err(|e| anyhow::anyhow!("unable to parse Docker config: {}", e))?;
+        Ok(CredentialHelpers {
+            cred_helpers: config.cred_helpers,
+            creds_store: config.creds_store.filter(|s| !s.is_empty()),
+            helper_dir,
+            cache_ttl: CACHE_TTL,
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Gets the authentication for the given registry from its credential
+    /// helper. Returns `None` if the registry has no helper, or the helper
+    /// fails, so that other sources of credentials can be tried.
+    pub async fn get(&self, registry: &str) -> Option<RegistryAuth> {
+        let helper = self
+            .cred_helpers
+            .get(registry)
+            .or(self.creds_store.as_ref())?;
+
+        if let Some(cached) = self.cache.read().await.get(registry) {
+            if cached.fetched.elapsed() < self.cache_ttl {
+                debug!("Using cached credentials for registry {}", registry);
+                return Some(cached.credentials.to_registry_auth());
+            }
+        }
+
+        match self.run_helper(helper, registry).await {
+            Ok(credentials) => {
+                let auth = credentials.to_registry_auth();
+                self.cache.write().await.insert(
+                    registry.to_owned(),
+                    CachedCredentials {
+                        credentials,
+                        fetched: Instant::now(),
+                    },
+                );
+                Some(auth)
+            }
+            Err(e) => {
+                warn!(
+                    "Credential helper {} failed for registry {}: {:?}",
+                    helper, registry, e
+                );
+                None
+            }
+        }
+    }
+
+    async fn run_helper(&self, helper: &str, registry: &str) -> anyhow::Result<HelperCredentials> {
+        let program_name = format!("{}{}", HELPER_PROGRAM_PREFIX, helper);
+        let program = match &self.helper_dir {
+            Some(dir) => dir.join(program_name),
+            None => PathBuf::from(program_name),
+        };
+        debug!(
+            "Running credential helper {} for registry {}",
+            program.display(),
+            registry
+        );
+        let mut child = tokio::process::Command::new(&program)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to run {}: {}", program.display(), e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(registry.as_bytes()).await?;
+        }
+        let output = tokio::time::timeout(HELPER_TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out after {:?}", HELPER_TIMEOUT))??;
+        if !output.status.success() {
+            // Helpers report errors such as missing credentials on stdout
+            let message = if output.stdout.is_empty() {
+                output.stderr
+            } else {
+                output.stdout
+            };
+            return Err(anyhow::anyhow!(
+                "{} ({})",
+                String::from_utf8_lossy(&message).trim(),
+                output.status
+            ));
+        }
+        serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow::anyhow!("unable to parse helper output: {}", e))
+    }
+}
+
+/// Creates the credential helpers described by the kubelet configuration.
+/// Returns `None` if no Docker config file is configured.
+pub fn configured_credential_helpers(
+    config: &Config,
+) -> anyhow::Result<Option<Arc<CredentialHelpers>>> {
+    match &config.docker_config_file {
+        Some(path) => Ok(Some(Arc::new(CredentialHelpers::from_docker_config(path)?))),
+        None => Ok(None),
+    }
+}
+
+/// The parts of a Docker config file that configure credential helpers
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    #[serde(default)]
+    creds_store: Option<String>,
+}
+
+/// The credentials a helper prints in response to `get`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+impl HelperCredentials {
+    fn to_registry_auth(&self) -> RegistryAuth {
+        if self.username == IDENTITY_TOKEN_USERNAME {
+            RegistryAuth::IdentityToken(self.secret.clone())
+        } else {
+            RegistryAuth::Basic(self.username.clone(), self.secret.clone())
+        }
+    }
+}
+
+struct CachedCredentials {
+    credentials: HelperCredentials,
+    fetched: Instant,
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn fixture_helpers(config: &str) -> CredentialHelpers {
+        let helper_dir =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/credential-helpers");
+        CredentialHelpers::parse(config.as_bytes(), Some(helper_dir))
+            .expect("config should be valid")
+    }
+
+    #[tokio::test]
+    async fn helpers_are_chosen_per_registry() {
+        let helpers = fixture_helpers(
+            r#"{
+                "credHelpers": { "fake.registry.io": "fake" },
+                "credsStore": "missing"
+            }"#,
+        );
+        match helpers.get("fake.registry.io").await {
+            Some(RegistryAuth::Basic(username, password)) => {
+                assert_eq!("fakeuser", username);
+                assert_eq!("fakepass", password);
+            }
+            _ => panic!("expected basic auth from the fake helper"),
+        }
+        // Other registries use the credsStore helper, which doesn't exist
+        assert!(helpers.get("other.registry.io").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn identity_tokens_are_recognised() {
+        let helpers = fixture_helpers(r#"{ "credsStore": "fake" }"#);
+        match helpers.get("token.registry.io").await {
+            Some(RegistryAuth::IdentityToken(token)) => assert_eq!("faketoken", token),
+            _ => panic!("expected an identity token from the fake helper"),
+        }
+    }
+
+    #[tokio::test]
+    async fn helper_failures_return_none() {
+        let helpers = fixture_helpers(r#"{ "credsStore": "fake" }"#);
+        assert!(helpers.get("unknown.registry.io").await.is_none());
+        let helpers = fixture_helpers(r#"{}"#);
+        assert!(helpers.get("fake.registry.io").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn credentials_are_cached_until_the_ttl_expires() {
+        let mut helpers = fixture_helpers(r#"{ "credsStore": "fake" }"#);
+        assert!(helpers.get("fake.registry.io").await.is_some());
+        // With the helper gone, credentials come from the cache
+        helpers.helper_dir = Some(PathBuf::from("/nonexistent"));
+        assert!(helpers.get("fake.registry.io").await.is_some());
+        helpers.cache_ttl = Duration::from_secs(0);
+        assert!(helpers.get("fake.registry.io").await.is_none());
+    }
+}
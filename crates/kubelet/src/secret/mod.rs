@@ -1,14 +1,21 @@
 //! Resolves image pull secrets
 
+pub mod credential_helper;
+
+use std::sync::Arc;
+
 use k8s_openapi::api::core::v1::Secret;
 use kube::api::Api;
 use oci_distribution::secrets::RegistryAuth;
 
+use credential_helper::CredentialHelpers;
+
 /// Resolves registry authentication from image pull secrets
 pub struct RegistryAuthResolver {
     kube_client: kube::Client,
     pod_namespace: String,
     image_pull_secret_names: Vec<String>,
+    credential_helpers: Option<Arc<CredentialHelpers>>,
 }
 
 impl RegistryAuthResolver {
@@ -20,9 +27,18 @@ impl RegistryAuthResolver {
             kube_client: client,
             pod_namespace: pod.namespace().to_owned(),
             image_pull_secret_names: pod.image_pull_secrets(),
+            credential_helpers: None,
         }
     }
 
+    /// Also resolves authentication using Docker credential helpers, for
+    /// registries that none of the pod's image pull secrets have credentials
+    /// for
+    pub fn with_credential_helpers(mut self, helpers: Arc<CredentialHelpers>) -> Self {
+        self.credential_helpers = Some(helpers);
+        self
+    }
+
     /// Get the registry authentication method appropriate to the given image reference
     pub async fn resolve_registry_auth(
         &self,
@@ -49,6 +65,12 @@ impl RegistryAuthResolver {
             }
         }
 
+        if let Some(helpers) = &self.credential_helpers {
+            if let Some(auth) = helpers.get(reference.registry()).await {
+                return Ok(auth);
+            }
+        }
+
         Ok(RegistryAuth::Anonymous)
     }
 }
@@ -100,6 +122,13 @@ fn parse_auth_from_json_value(
 }
 
 fn parse_auth_from_json_creds(json_creds: &serde_json::Value) -> Option<RegistryAuth> {
+    // An identity token takes precedence over username and password, as
+    // registries that hand these out expect them to be used
+    if let Some(serde_json::Value::String(t)) = json_creds.get("identitytoken") {
+        if !t.is_empty() {
+            return Some(RegistryAuth::IdentityToken(t.to_owned()));
+        }
+    }
     let username = json_creds.get("username");
     let password = json_creds.get("password");
     // TODO: my test creds also included an entry "auth" - should we return this? (e.g. bearer auth?)
@@ -47,6 +47,8 @@
 //!
 
 pub mod common;
+pub mod record;
+pub mod testing;
 
 #[cfg(feature = "derive")]
 #[doc(hidden)]
@@ -3,6 +3,7 @@
 use super::registered::Registered;
 use super::{BackoffSequence, GenericPodState, GenericProvider};
 use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
 
 /// The pod is backing off after repeated failures and retries.
 pub struct CrashLoopBackoff<P: GenericProvider> {
@@ -42,3 +43,12 @@ impl<P: GenericProvider> State<P::PodState> for CrashLoopBackoff<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<Registered<P>> for CrashLoopBackoff<P> {}
+
+impl<P: GenericProvider> Transitions for CrashLoopBackoff<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![StateNode::of::<Registered<P>>()]
+    }
+}
@@ -0,0 +1,60 @@
+//! The Pod was evicted for using more ephemeral storage than its limit.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
+
+/// The reason given for pods the kubelet has evicted
+const EVICTED: &str = "Evicted";
+
+/// The Pod was evicted for using more ephemeral storage than its limit. Its
+/// containers have been stopped and its scratch files deleted.
+pub struct EphemeralStorageExceeded<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for EphemeralStorageExceeded<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("EphemeralStorageExceeded: {}", self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> EphemeralStorageExceeded<P> {
+    /// Creates an instance of the EphemeralStorageExceeded state.
+    pub fn new(message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            message,
+        }
+    }
+}
+
+/// EphemeralStorageExceeded ends the state machine
+impl<P: GenericProvider> Transitions for EphemeralStorageExceeded<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for EphemeralStorageExceeded<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // Evicted pods aren't restarted on the same node
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(EVICTED)
+            .message(&self.message)
+            .build())
+    }
+}
@@ -4,6 +4,7 @@ use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
 use super::{GenericPodState, GenericProvider, ThresholdTrigger};
 use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
 
 /// The Pod failed to run.
 pub struct Error<P: GenericProvider> {
@@ -56,3 +57,15 @@ impl<P: GenericProvider> State<P::PodState> for Error<P> {
 
 impl<P: GenericProvider> TransitionTo<CrashLoopBackoff<P>> for Error<P> {}
 impl<P: GenericProvider> TransitionTo<Registered<P>> for Error<P> {}
+
+impl<P: GenericProvider> Transitions for Error<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![
+            StateNode::of::<CrashLoopBackoff<P>>(),
+            StateNode::of::<Registered<P>>(),
+        ]
+    }
+}
@@ -1,11 +1,30 @@
 //! Kubelet is pulling container images.
 
 use super::image_pull_backoff::ImagePullBackoff;
+use super::image_verification_failed::ImageVerificationFailed;
 use super::volume_mount::VolumeMount;
 use super::{BackoffSequence, GenericPodState, GenericProvider, GenericProviderState};
+use crate::container::{patch_container_status, Container, ContainerKey, Status};
+use crate::pod::event::{record_event, EventType};
 use crate::pod::state::prelude::*;
+use crate::secret::RegistryAuthResolver;
+use crate::store::verification::verify_pod_images;
+use crate::store::{PullOutcome, Store};
+use krator::diagram::{StateNode, Transitions};
 
-use tracing::error;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use oci_distribution::client::PullProgress;
+use tokio::sync::watch;
+use tracing::{debug, error, warn};
+
+/// The reason given when a pulled module fails verification
+const IMAGE_VERIFICATION_FAILED: &str = "ImageVerificationFailed";
+/// The reason given when a module has been fetched
+const PULLED: &str = "Pulled";
 
 /// Kubelet is pulling container images.
 pub struct ImagePull<P: GenericProvider> {
@@ -36,19 +55,55 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
-        let (client, store) = {
+        let (client, store, verifier, credential_helpers, progress_interval) = {
             // Minimise the amount of time we hold any locks
             let state_reader = provider_state.read().await;
-            (state_reader.client(), state_reader.store())
+            (
+                state_reader.client(),
+                state_reader.store(),
+                state_reader.content_verifier(),
+                state_reader.credential_helpers(),
+                state_reader.pull_progress_interval(),
+            )
+        };
+        let mut auth_resolver = RegistryAuthResolver::new(client.clone(), &pod);
+        if let Some(helpers) = credential_helpers {
+            auth_resolver = auth_resolver.with_credential_helpers(helpers);
+        }
+        let fetcher = ModuleFetcher {
+            client: &client,
+            store: store.as_ref(),
+            auth: &auth_resolver,
+            pod: &pod,
+            progress_interval,
         };
-        let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
-        let modules = match store.fetch_pod_modules(&pod, &auth_resolver).await {
+        let modules = match fetcher.fetch_all().await {
             Ok(m) => m,
             Err(e) => {
                 error!("{:?}", e);
                 return Transition::next(self, ImagePullBackoff::<P>::default());
             }
         };
+        if let Some(verifier) = verifier {
+            if let Err(failure) =
+                verify_pod_images(&pod, store.as_ref(), verifier.as_ref(), &auth_resolver).await
+            {
+                let message = failure.to_string();
+                error!("{}", message);
+                if let Err(e) = record_event(
+                    &client,
+                    &pod,
+                    EventType::Warning,
+                    IMAGE_VERIFICATION_FAILED,
+                    &message,
+                )
+                .await
+                {
+                    warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+                }
+                return Transition::next(self, ImageVerificationFailed::<P>::new(message));
+            }
+        }
         pod_state.set_modules(modules).await;
         pod_state.reset_backoff(BackoffSequence::ImagePull).await;
         Transition::next(self, VolumeMount::<P>::default())
@@ -59,5 +114,198 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
     }
 }
 
+/// Fetches the modules for the containers of a pod, reporting the progress
+/// of any pulls in the container statuses and as pod events.
+struct ModuleFetcher<'a> {
+    client: &'a kube::Client,
+    store: &'a (dyn Store + Send + Sync),
+    auth: &'a RegistryAuthResolver,
+    pod: &'a Pod,
+    progress_interval: Duration,
+}
+
+impl<'a> ModuleFetcher<'a> {
+    /// Fetches the modules of all the pod's containers in parallel, keyed by
+    /// container name.
+    async fn fetch_all(&self) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        debug!(
+            "Fetching all the container modules for pod '{}'",
+            self.pod.name()
+        );
+        let init_containers = self
+            .pod
+            .init_containers()
+            .into_iter()
+            .map(|c| (ContainerKey::Init(c.name().to_owned()), c));
+        let app_containers = self
+            .pod
+            .containers()
+            .into_iter()
+            .map(|c| (ContainerKey::App(c.name().to_owned()), c));
+        let fetches = init_containers
+            .chain(app_containers)
+            .map(|(key, container)| async move {
+                let module = self.fetch(&key, &container).await?;
+                Ok((key.name(), module))
+            });
+        futures::future::join_all(fetches)
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    async fn fetch(&self, key: &ContainerKey, container: &Container) -> anyhow::Result<Vec<u8>> {
+        let image_ref = container
+            .image()?
+            .ok_or_else(|| anyhow::anyhow!("container {} must have an image", container.name()))?;
+        let pull_policy = container.effective_pull_policy()?;
+        let registry_auth = self.auth.resolve_registry_auth(&image_ref).await?;
+
+        let (sender, receiver) = watch::channel(None);
+        let report_progress = move |progress: PullProgress| {
+            // The receiver is only dropped once the fetch is complete
+            let _ = sender.send(Some(progress));
+        };
+        let started = Instant::now();
+        let fetch =
+            self.store
+                .get_with_progress(&image_ref, pull_policy, &registry_auth, &report_progress);
+        tokio::pin!(fetch);
+        let (module, outcome) = tokio::select! {
+            result = &mut fetch => result?,
+            _ = self.report_progress(key, receiver) => fetch.await?,
+        };
+
+        let message = match outcome {
+            PullOutcome::Pulled(bytes) => format!(
+                "Successfully pulled image \"{}\" ({}) in {:.2?}",
+                image_ref,
+                format_bytes(bytes),
+                started.elapsed()
+            ),
+            PullOutcome::AlreadyPresent => {
+                format!(
+                    "Container image \"{}\" already present on machine",
+                    image_ref
+                )
+            }
+            PullOutcome::Unknown => format!(
+                "Successfully fetched image \"{}\" in {:.2?}",
+                image_ref,
+                started.elapsed()
+            ),
+        };
+        if let Err(e) =
+            record_event(self.client, self.pod, EventType::Normal, PULLED, &message).await
+        {
+            warn!(
+                "Unable to record event for pod {}: {:?}",
+                self.pod.name(),
+                e
+            );
+        }
+        Ok(module)
+    }
+
+    /// Patches the waiting message of the container with the latest progress
+    /// of its pull, at most once per progress interval. Only returns if the
+    /// progress sender is dropped.
+    async fn report_progress(
+        &self,
+        key: &ContainerKey,
+        mut receiver: watch::Receiver<Option<PullProgress>>,
+    ) {
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), self.pod.namespace());
+        while receiver.changed().await.is_ok() {
+            let progress = *receiver.borrow();
+            if let Some(progress) = progress {
+                let status = Status::waiting(&progress_message(&progress));
+                if let Err(e) = patch_container_status(&api, self.pod, key, &status).await {
+                    warn!(
+                        "Unable to report pull progress for container {} of pod {}: {:?}",
+                        key.name(),
+                        self.pod.name(),
+                        e
+                    );
+                }
+            }
+            tokio::time::sleep(self.progress_interval).await;
+        }
+    }
+}
+
+/// Describes the progress of a pull, e.g. "Pulling image: 45% (90MiB/200MiB)"
+fn progress_message(progress: &PullProgress) -> String {
+    let percent = (progress.downloaded_bytes.min(progress.total_bytes) * 100)
+        .checked_div(progress.total_bytes)
+        .unwrap_or(0);
+    format!(
+        "Pulling image: {}% ({}/{})",
+        percent,
+        format_bytes(progress.downloaded_bytes),
+        format_bytes(progress.total_bytes)
+    )
+}
+
+/// Formats a number of bytes using the largest binary unit that fits
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    let mut unit = "B";
+    let mut value = bytes;
+    for next_unit in UNITS {
+        if value < 1024 {
+            break;
+        }
+        value /= 1024;
+        unit = next_unit;
+    }
+    format!("{}{}", value, unit)
+}
+
 impl<P: GenericProvider> TransitionTo<ImagePullBackoff<P>> for ImagePull<P> {}
+impl<P: GenericProvider> TransitionTo<ImageVerificationFailed<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for ImagePull<P> {}
+
+impl<P: GenericProvider> Transitions for ImagePull<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![
+            StateNode::of::<ImagePullBackoff<P>>(),
+            StateNode::of::<ImageVerificationFailed<P>>(),
+            StateNode::of::<VolumeMount<P>>(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_are_formatted_in_binary_units() {
+        assert_eq!("0B", format_bytes(0));
+        assert_eq!("1023B", format_bytes(1023));
+        assert_eq!("1KiB", format_bytes(1024));
+        assert_eq!("90MiB", format_bytes(90 * 1024 * 1024 + 1000));
+        assert_eq!("3GiB", format_bytes(3 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn progress_message_shows_percentage_and_sizes() {
+        let progress = PullProgress {
+            downloaded_bytes: 90 * 1024 * 1024,
+            total_bytes: 200 * 1024 * 1024,
+        };
+        assert_eq!(
+            "Pulling image: 45% (90MiB/200MiB)",
+            progress_message(&progress)
+        );
+        let empty = PullProgress {
+            downloaded_bytes: 0,
+            total_bytes: 0,
+        };
+        assert_eq!("Pulling image: 0% (0B/0B)", progress_message(&empty));
+    }
+}
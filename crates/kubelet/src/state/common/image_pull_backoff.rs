@@ -3,6 +3,7 @@
 use super::image_pull::ImagePull;
 use super::{BackoffSequence, GenericPodState, GenericProvider};
 use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
 
 /// Kubelet encountered an error when pulling container image.
 pub struct ImagePullBackoff<P: GenericProvider> {
@@ -41,3 +42,12 @@ impl<P: GenericProvider> State<P::PodState> for ImagePullBackoff<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<ImagePull<P>> for ImagePullBackoff<P> {}
+
+impl<P: GenericProvider> Transitions for ImagePullBackoff<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![StateNode::of::<ImagePull<P>>()]
+    }
+}
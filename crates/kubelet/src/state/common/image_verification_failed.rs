@@ -0,0 +1,56 @@
+//! A module used by the Pod failed verification.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
+
+/// A module used by the Pod failed verification.
+pub struct ImageVerificationFailed<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for ImageVerificationFailed<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("ImageVerificationFailed: {}", self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> ImageVerificationFailed<P> {
+    /// Creates an instance of the ImageVerificationFailed state.
+    pub fn new(message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            message,
+        }
+    }
+}
+
+/// ImageVerificationFailed ends the state machine
+impl<P: GenericProvider> Transitions for ImageVerificationFailed<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for ImageVerificationFailed<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // The modules are never run, so the pod is not retried
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("ImageVerificationFailed")
+            .message(&self.message)
+            .build())
+    }
+}
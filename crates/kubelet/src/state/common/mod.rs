@@ -6,13 +6,20 @@
 use crate::plugin_watcher::PluginRegistry;
 use crate::pod::state::prelude::PodStatus;
 use crate::pod::Pod;
+use crate::secret::credential_helper::CredentialHelpers;
+use crate::store::verification::ContentVerifier;
 use krator::{ObjectState, State};
 use std::collections::HashMap;
 
 pub mod crash_loop_backoff;
+pub mod ephemeral_storage_exceeded;
 pub mod error;
 pub mod image_pull;
 pub mod image_pull_backoff;
+pub mod image_verification_failed;
+pub mod node_shutdown;
+pub mod out_of_resource;
+pub mod policy_violation;
 pub mod registered;
 pub mod terminated;
 pub mod volume_mount;
@@ -48,6 +55,36 @@ pub trait GenericProviderState: 'static + Send + Sync {
     fn plugin_registry(&self) -> Option<std::sync::Arc<PluginRegistry>> {
         None
     }
+    /// Gets the verifier used to check modules after they are pulled and
+    /// before they are run. Modules are not verified if this returns `None`.
+    fn content_verifier(&self) -> Option<std::sync::Arc<dyn ContentVerifier>> {
+        None
+    }
+    /// Gets the Docker credential helpers used to authenticate to registries
+    /// that the pod's image pull secrets have no credentials for.
+    fn credential_helpers(&self) -> Option<std::sync::Arc<CredentialHelpers>> {
+        None
+    }
+    /// Gets the queue that orders the admission of pods by their priority.
+    /// Pods are admitted as soon as they arrive if this returns `None`.
+    fn admission_queue(&self) -> Option<std::sync::Arc<crate::pod::admission::AdmissionQueue>> {
+        None
+    }
+    /// Gets the minimum interval between updates to a container's status
+    /// while its image is being pulled.
+    fn pull_progress_interval(&self) -> std::time::Duration {
+        crate::config::DEFAULT_PULL_PROGRESS_INTERVAL
+    }
+    /// Whether secret volumes are backed by memory, so that secrets are
+    /// never written to the node's disk.
+    fn secrets_in_memory(&self) -> bool {
+        false
+    }
+    /// Gets the interval at which the conditions of a running pod's
+    /// readiness gates are checked.
+    fn readiness_gate_poll_interval(&self) -> std::time::Duration {
+        crate::config::DEFAULT_READINESS_GATE_POLL_INTERVAL
+    }
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
@@ -0,0 +1,52 @@
+//! The Pod was rejected because the node is shutting down.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
+
+/// The Pod was rejected because the node is shutting down.
+pub struct NodeShutdown<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for NodeShutdown<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "NodeShutdown".fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> Default for NodeShutdown<P> {
+    fn default() -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+/// NodeShutdown ends the state machine
+impl<P: GenericProvider> Transitions for NodeShutdown<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for NodeShutdown<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // The node won't run the pod again, so there is nothing to retry
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("NodeShutdown")
+            .message("Pod was rejected as the node is shutting down.")
+            .build())
+    }
+}
@@ -0,0 +1,60 @@
+//! The Pod requested more of an extended resource than the node had free.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
+
+/// The Pod requested more of an extended resource than the node had free.
+pub struct OutOfResource<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    reason: String,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for OutOfResource<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("{}: {}", self.reason, self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> OutOfResource<P> {
+    /// Creates an instance of the OutOfResource state, with the reason the
+    /// pod is failed with, such as `OutOfexample.com/wasm-gpu`.
+    pub fn new(reason: String, message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            reason,
+            message,
+        }
+    }
+}
+
+/// OutOfResource ends the state machine
+impl<P: GenericProvider> Transitions for OutOfResource<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for OutOfResource<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // As with other kubelets, pods that aren't admitted are failed
+        // rather than waiting for resources to be freed
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(&self.reason)
+            .message(&self.message)
+            .build())
+    }
+}
@@ -0,0 +1,57 @@
+//! The Pod was rejected by the Pod Security Standards of its namespace.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
+
+/// The Pod was rejected by the Pod Security Standards of its namespace.
+pub struct PolicyViolation<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for PolicyViolation<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("PolicyViolation: {}", self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> PolicyViolation<P> {
+    /// Creates an instance of the PolicyViolation state.
+    pub fn new(message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            message,
+        }
+    }
+}
+
+/// PolicyViolation ends the state machine
+impl<P: GenericProvider> Transitions for PolicyViolation<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for PolicyViolation<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // The pod spec can't be changed in a way that would make it
+        // admissible, so there is nothing to retry
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason("PolicyViolation")
+            .message(&self.message)
+            .build())
+    }
+}
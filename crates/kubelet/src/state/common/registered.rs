@@ -1,11 +1,23 @@
 //! The Kubelet is aware of the Pod.
 
+use crate::extended_resources;
+use crate::node;
+use crate::pod::event::{record_event, EventType};
 use crate::pod::state::prelude::*;
-use tracing::{debug, error, info};
+use crate::pod::{admission, security};
+use krator::diagram::{StateNode, Transitions};
+use tracing::{debug, error, info, warn};
 
 use super::error::Error;
 use super::image_pull::ImagePull;
-use super::GenericProvider;
+use super::node_shutdown::NodeShutdown;
+use super::out_of_resource::OutOfResource;
+use super::policy_violation::PolicyViolation;
+use super::{GenericProvider, GenericProviderState};
+
+/// The reason of the event recorded on pods that run without the pod
+/// security standards of their namespace being enforced
+const POD_SECURITY_UNKNOWN: &str = "PodSecurityUnknown";
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,13 +42,17 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         _pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
         debug!("Preparing to register pod: {}", pod.name());
+        if node::is_shutting_down() {
+            info!("Rejecting pod {} as the node is shutting down", pod.name());
+            return Transition::next(self, NodeShutdown::<P>::default());
+        }
         match P::validate_pod_and_containers_runnable(&pod) {
             Ok(_) => (),
             Err(e) => {
@@ -45,6 +61,80 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
                 return Transition::next(self, next);
             }
         }
+
+        let (client, admission_queue) = {
+            let provider_state = provider_state.read().await;
+            (provider_state.client(), provider_state.admission_queue())
+        };
+        // Pods waiting to be admitted are admitted by priority, the next one
+        // once this one has been
+        let _admission = match admission_queue {
+            Some(queue) => {
+                let priority = admission::priority(&client, &pod).await;
+                Some(queue.admit(&pod, priority).await)
+            }
+            None => None,
+        };
+        match security::namespace_level(&client, pod.namespace()).await {
+            Ok(level) => {
+                let violations = level.violations(&pod);
+                if !violations.is_empty() {
+                    let message = format!(
+                        "Pod violates PodSecurity {:?}: {}",
+                        level.to_string(),
+                        violations.join(", ")
+                    );
+                    error!("{}", message);
+                    let next = PolicyViolation::<P>::new(message);
+                    return Transition::next(self, next);
+                }
+            }
+            // The label may name a level stricter than any this node knows
+            // of, so the pod can't be known to keep to it
+            Err(e @ security::LevelError::InvalidLabel { .. }) => {
+                let message = format!("Unable to enforce pod security standards: {}", e);
+                error!("{}", message);
+                let next = PolicyViolation::<P>::new(message);
+                return Transition::next(self, next);
+            }
+            // Nodes are not always allowed to read namespaces, and pods that
+            // reach the node have already been through API server admission,
+            // so don't stop the pod from running here, but say so on it
+            Err(e) => {
+                let message = format!("Pod security standards were not enforced: {}", e);
+                warn!("Pod {}: {}", pod.name(), message);
+                if let Err(event_error) = record_event(
+                    &client,
+                    &pod,
+                    EventType::Warning,
+                    POD_SECURITY_UNKNOWN,
+                    &message,
+                )
+                .await
+                {
+                    warn!(
+                        "Unable to record event for pod {}: {:?}",
+                        pod.name(),
+                        event_error
+                    );
+                }
+            }
+        }
+        if let Err(e) = extended_resources::admit(&pod) {
+            let message = format!("Pod {} was rejected: {}", pod.name(), e);
+            error!("{}", message);
+            if let Err(event_error) =
+                record_event(&client, &pod, EventType::Warning, &e.reason(), &message).await
+            {
+                warn!(
+                    "Unable to record event for pod {}: {:?}",
+                    pod.name(),
+                    event_error
+                );
+            }
+            let next = OutOfResource::<P>::new(e.reason(), message);
+            return Transition::next(self, next);
+        }
         info!("Pod registered: {}", pod.name());
         let next = ImagePull::<P>::default();
         Transition::next(self, next)
@@ -57,3 +147,21 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for Registered<P> {}
 impl<P: GenericProvider> TransitionTo<ImagePull<P>> for Registered<P> {}
+impl<P: GenericProvider> TransitionTo<NodeShutdown<P>> for Registered<P> {}
+impl<P: GenericProvider> TransitionTo<OutOfResource<P>> for Registered<P> {}
+impl<P: GenericProvider> TransitionTo<PolicyViolation<P>> for Registered<P> {}
+
+impl<P: GenericProvider> Transitions for Registered<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![
+            StateNode::of::<Error<P>>(),
+            StateNode::of::<ImagePull<P>>(),
+            StateNode::of::<NodeShutdown<P>>(),
+            StateNode::of::<OutOfResource<P>>(),
+            StateNode::of::<PolicyViolation<P>>(),
+        ]
+    }
+}
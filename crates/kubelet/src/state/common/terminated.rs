@@ -1,7 +1,9 @@
 //! Pod was deleted.
 
 use super::{GenericProvider, GenericProviderState};
+use crate::node;
 use crate::pod::state::prelude::*;
+use krator::diagram::{StateNode, Transitions};
 
 /// Pod was deleted.
 pub struct Terminated<P: GenericProvider> {
@@ -22,6 +24,13 @@ impl<P: GenericProvider> Default for Terminated<P> {
     }
 }
 
+/// Terminated ends the state machine
+impl<P: GenericProvider> Transitions for Terminated<P> {
+    fn transitions() -> Vec<StateNode> {
+        vec![]
+    }
+}
+
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
     async fn next(
@@ -41,6 +50,15 @@ impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        // Pods evicted as the node shuts down say so, as other kubelets'
+        // pods do
+        if node::is_shutting_down() {
+            return Ok(StatusBuilder::new()
+                .phase(Phase::Failed)
+                .reason(node::NODE_SHUTDOWN_REASON)
+                .message("Pod was terminated in response to node shutdown.")
+                .build());
+        }
         Ok(make_status(Phase::Succeeded, "Terminated"))
     }
 }
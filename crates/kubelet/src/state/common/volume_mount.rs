@@ -1,11 +1,16 @@
 //! Kubelet is pulling container images.
 
-use tracing::error;
+use tracing::{error, warn};
 
 use super::{GenericPodState, GenericProvider, GenericProviderState};
+use crate::pod::event::{record_event, EventType};
 use crate::pod::state::prelude::*;
 use crate::state::common::error::Error;
 use crate::volume::Ref;
+use krator::diagram::{StateNode, Transitions};
+
+/// The reason of the event recorded when the volumes of a pod can't be set up
+const FAILED_MOUNT: &str = "FailedMount";
 
 /// Kubelet is pulling container images.
 pub struct VolumeMount<P: GenericProvider> {
@@ -36,23 +41,37 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
 
-        let (client, volume_path, plugin_registry) = {
+        let (client, volume_path, plugin_registry, secrets_in_memory) = {
             let state_reader = provider_state.read().await;
             (
                 state_reader.client(),
                 state_reader.volume_path(),
                 state_reader.plugin_registry(),
+                state_reader.secrets_in_memory(),
             )
         };
-        let volumes =
-            match Ref::volumes_from_pod(&volume_path, &pod, &client, plugin_registry).await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("{:?}", e);
-                    let next = Error::<P>::new(e.to_string());
-                    return Transition::next(self, next);
+        let volumes = match Ref::volumes_from_pod(
+            &volume_path,
+            &pod,
+            &client,
+            plugin_registry,
+            secrets_in_memory,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{:?}", e);
+                let message = format!("MountVolume.SetUp failed: {}", e);
+                if let Err(e) =
+                    record_event(&client, &pod, EventType::Warning, FAILED_MOUNT, &message).await
+                {
+                    warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
                 }
-            };
+                let next = Error::<P>::new(format!("{}: {}", FAILED_MOUNT, message));
+                return Transition::next(self, next);
+            }
+        };
         pod_state.set_volumes(volumes).await;
         Transition::next_unchecked(self, P::RunState::default())
     }
@@ -63,3 +82,13 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for VolumeMount<P> {}
+
+// The run state is entered with `next_unchecked`, so it has no `TransitionTo`
+impl<P: GenericProvider> Transitions for VolumeMount<P>
+where
+    P::RunState: Transitions,
+{
+    fn transitions() -> Vec<StateNode> {
+        vec![StateNode::of::<Error<P>>(), StateNode::of::<P::RunState>()]
+    }
+}
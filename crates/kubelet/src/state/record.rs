@@ -0,0 +1,460 @@
+//! Recording the states pods' state machines enter, and replaying them.
+//!
+//! A [`TransitionRecorder`] appends a [`RecordedTransition`] to a file each
+//! time a pod's state machine enters a state, which the kubelet does when
+//! `--record-transitions` is set. Each is written as a CBOR value as soon as
+//! the state is entered, so the file can be read back after the kubelet
+//! crashes.
+//!
+//! [`replay`] drives a state machine of [`MockState`]s through each pod's
+//! recorded states, and checks each transition against the graph of the
+//! current state machine, so that a reported sequence of states can be
+//! checked against the code without a cluster:
+//!
+//! ```console
+//! $ krustlet-wasi replay transitions.cbor
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use krator::diagram::{StateNode, Transitions};
+use krator::ObjectState;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::pod::Pod;
+use crate::state::testing::{MockState, TestStateRunner};
+
+/// A state that a pod's state machine entered
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedTransition {
+    /// The pod, as `namespace/name`
+    pub pod: String,
+    /// The name of the state
+    pub state: String,
+    /// The [`pod_spec_hash`] of the pod when it entered the state
+    pub pod_spec_hash: String,
+    /// When the pod entered the state
+    pub timestamp: DateTime<Utc>,
+    /// When the kubelet that recorded the transition started. A pod's state
+    /// machine is resumed in a state it entered before when the kubelet
+    /// restarts, rather than transitioning to it.
+    pub kubelet_started: DateTime<Utc>,
+}
+
+/// The SHA-256 digest of the pod's spec, serialized as JSON, which changes
+/// when the spec does
+pub fn pod_spec_hash(pod: &Pod) -> String {
+    let spec = serde_json::to_vec(&pod.as_kube_pod().spec).unwrap_or_default();
+    format!("{:x}", Sha256::digest(&spec))
+}
+
+/// Appends the states pods enter to a file
+pub struct TransitionRecorder {
+    file: Mutex<std::fs::File>,
+    started: DateTime<Utc>,
+}
+
+impl TransitionRecorder {
+    /// Opens the file at `path` to append to, creating it and its directory
+    /// if they don't exist
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "unable to open transition recording {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+        Ok(TransitionRecorder {
+            file: Mutex::new(file),
+            started: Utc::now(),
+        })
+    }
+
+    /// Records that `pod` entered `state` now
+    pub fn record(&self, pod: &Pod, state: &str) -> anyhow::Result<()> {
+        let transition = RecordedTransition {
+            pod: format!("{}/{}", pod.namespace(), pod.name()),
+            state: state.to_owned(),
+            pod_spec_hash: pod_spec_hash(pod),
+            timestamp: Utc::now(),
+            kubelet_started: self.started,
+        };
+        // Each transition is written with one write, so that transitions
+        // recorded at the same time aren't interleaved
+        let bytes = serde_cbor::to_vec(&transition)?;
+        let mut file = self
+            .file
+            .lock()
+            .expect("transition recording lock poisoned");
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+/// Reads the transitions recorded in the file at `path`, in the order they
+/// were recorded
+pub fn read_recording(path: impl AsRef<Path>) -> anyhow::Result<Vec<RecordedTransition>> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path).map_err(|e| {
+        anyhow::anyhow!(
+            "unable to open transition recording {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    read_transitions(std::io::BufReader::new(file))
+}
+
+/// Reads recorded transitions from `reader` until it ends
+pub fn read_transitions<R: std::io::Read>(reader: R) -> anyhow::Result<Vec<RecordedTransition>> {
+    serde_cbor::Deserializer::from_reader(reader)
+        .into_iter::<RecordedTransition>()
+        .enumerate()
+        .map(|(i, transition)| {
+            transition.map_err(|e| anyhow::anyhow!("invalid recorded transition {}: {}", i, e))
+        })
+        .collect()
+}
+
+/// Why a pod's recorded states can't be replayed by the current state machine
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReplayError {
+    /// A recorded state is not a state of the state machine
+    #[error("{state} is not a state of the state machine")]
+    UnknownState {
+        /// The name of the state
+        state: String,
+    },
+    /// A recorded transition is not one the state machine can make
+    #[error("{from} can no longer transition to {to}")]
+    UnlistedTransition {
+        /// The state that transitioned
+        from: &'static str,
+        /// The state it transitioned to
+        to: &'static str,
+    },
+}
+
+/// How the recorded states of one pod replayed
+#[derive(Debug)]
+pub struct PodReplay {
+    /// The pod, as `namespace/name`
+    pub pod: String,
+    /// The states the mock state machine ran, in order
+    pub states: Vec<&'static str>,
+    /// How many times the pod's spec changed while it was recorded
+    pub spec_changes: usize,
+    /// Whether the current state machine can make every recorded transition
+    pub result: Result<(), ReplayError>,
+}
+
+/// The state of the mock state machine pods are replayed with
+struct ReplayState;
+
+#[async_trait::async_trait]
+impl ObjectState for ReplayState {
+    type Manifest = String;
+    type Status = ();
+    type SharedState = ();
+    async fn async_drop(self, _shared: &mut ()) {}
+}
+
+/// The states of a pod's state machine, and how pods move between them
+struct PodStateMachine {
+    graph: BTreeMap<&'static str, Vec<&'static str>>,
+    initial: &'static str,
+    deleted: &'static str,
+}
+
+impl PodStateMachine {
+    /// Whether a pod can go from `from` to `to`: by a transition, by being
+    /// deleted, which interrupts any state, or by a pod with the same name
+    /// being created after its state machine completed
+    fn allows(&self, from: &'static str, to: &'static str) -> bool {
+        let transitions = &self.graph[from];
+        transitions.contains(&to)
+            || to == self.deleted
+            || (to == self.initial && transitions.is_empty())
+    }
+}
+
+/// Replays the states recorded in `recording` for each pod, in the order the
+/// pods were first recorded, through a mock state machine, checking them
+/// against the state machine that starts pods in state `I` and moves deleted
+/// pods to state `D`. Pods are not checked for how they were resumed after
+/// the kubelet restarted.
+pub async fn replay<I, D>(recording: &[RecordedTransition]) -> Vec<PodReplay>
+where
+    I: Transitions + ?Sized,
+    D: Transitions + ?Sized,
+{
+    let mut graph = krator::diagram::graph::<I>();
+    graph.extend(krator::diagram::graph::<D>());
+    let machine = PodStateMachine {
+        graph,
+        initial: StateNode::of::<I>().name(),
+        deleted: StateNode::of::<D>().name(),
+    };
+
+    let mut pods: Vec<(&str, Vec<&RecordedTransition>)> = vec![];
+    for transition in recording {
+        match pods.iter_mut().find(|(pod, _)| *pod == transition.pod) {
+            Some((_, transitions)) => transitions.push(transition),
+            None => pods.push((&transition.pod, vec![transition])),
+        }
+    }
+
+    let mut replays = vec![];
+    for (pod, transitions) in pods {
+        let spec_changes = transitions
+            .windows(2)
+            .filter(|pair| pair[0].pod_spec_hash != pair[1].pod_spec_hash)
+            .count();
+        let (states, result) = replay_pod(&machine, pod, &transitions).await;
+        replays.push(PodReplay {
+            pod: pod.to_owned(),
+            states,
+            spec_changes,
+            result,
+        });
+    }
+    replays
+}
+
+/// Runs mocks named after the recorded states of `pod`, and checks how the
+/// pod moved between the states they ran against `machine`
+async fn replay_pod(
+    machine: &PodStateMachine,
+    pod: &str,
+    transitions: &[&RecordedTransition],
+) -> (Vec<&'static str>, Result<(), ReplayError>) {
+    // Mocks are named by the graph, so that names outlive the recording
+    let mut mocks = vec![];
+    for transition in transitions {
+        match machine.graph.get_key_value(transition.state.as_str()) {
+            Some((name, _)) => mocks.push(MockState::<ReplayState>::new(name)),
+            None => {
+                return (
+                    vec![],
+                    Err(ReplayError::UnknownState {
+                        state: transition.state.clone(),
+                    }),
+                )
+            }
+        }
+    }
+    let mocks = MockState::sequence(mocks);
+    let calls = mocks.calls();
+    // Mocks complete successfully after the last one
+    let _ = TestStateRunner::new(())
+        .run_to_completion(mocks, &mut ReplayState, &pod.to_owned())
+        .await;
+
+    let states = calls.states_run();
+    for (i, pair) in states.windows(2).enumerate() {
+        let (from, to) = (pair[0], pair[1]);
+        let resumed = transitions[i].kubelet_started != transitions[i + 1].kubelet_started;
+        if !resumed && !machine.allows(from, to) {
+            return (states, Err(ReplayError::UnlistedTransition { from, to }));
+        }
+    }
+    (states, Ok(()))
+}
+
+#[cfg(test)]
+// The states are only named, and never constructed
+#[allow(dead_code)]
+mod test {
+    use super::*;
+    use crate::pod::test_util;
+    use chrono::TimeZone;
+
+    struct Registered;
+    struct Running;
+    struct Error;
+    struct Completed;
+    struct Terminated;
+
+    impl Transitions for Registered {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Running>(), StateNode::of::<Error>()]
+        }
+    }
+
+    impl Transitions for Running {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Completed>(), StateNode::of::<Error>()]
+        }
+    }
+
+    impl Transitions for Error {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Registered>()]
+        }
+    }
+
+    impl Transitions for Completed {
+        fn transitions() -> Vec<StateNode> {
+            vec![]
+        }
+    }
+
+    impl Transitions for Terminated {
+        fn transitions() -> Vec<StateNode> {
+            vec![]
+        }
+    }
+
+    fn pod(name: &str, image: &str) -> Pod {
+        let spec = serde_json::json!({ "containers": [{ "name": "main", "image": image }] });
+        test_util::pod(name, serde_json::from_value(spec).unwrap())
+    }
+
+    fn transition(pod: &str, state: &str, hash: &str) -> RecordedTransition {
+        RecordedTransition {
+            pod: format!("default/{}", pod),
+            state: state.to_owned(),
+            pod_spec_hash: hash.to_owned(),
+            timestamp: Utc::now(),
+            kubelet_started: Utc.timestamp(0, 0),
+        }
+    }
+
+    async fn replay_pods(recording: &[RecordedTransition]) -> Vec<PodReplay> {
+        replay::<Registered, Terminated>(recording).await
+    }
+
+    #[test]
+    fn recorded_transitions_are_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recordings").join("transitions.cbor");
+        let web = pod("web", "hello-wasm:v1");
+        {
+            let recorder = TransitionRecorder::create(&path).unwrap();
+            recorder.record(&web, "Registered").unwrap();
+            recorder.record(&pod("db", "db:v1"), "Registered").unwrap();
+        }
+        // Recording again appends
+        TransitionRecorder::create(&path)
+            .unwrap()
+            .record(&web, "Running")
+            .unwrap();
+
+        let recording = read_recording(&path).unwrap();
+        let states: Vec<(&str, &str)> = recording
+            .iter()
+            .map(|t| (t.pod.as_str(), t.state.as_str()))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("default/web", "Registered"),
+                ("default/db", "Registered"),
+                ("default/web", "Running"),
+            ]
+        );
+        assert_eq!(recording[0].pod_spec_hash, pod_spec_hash(&web));
+        assert_eq!(recording[0].pod_spec_hash, recording[2].pod_spec_hash);
+        assert_ne!(recording[0].pod_spec_hash, recording[1].pod_spec_hash);
+    }
+
+    #[test]
+    fn truncated_recordings_are_rejected() {
+        let mut bytes = serde_cbor::to_vec(&transition("web", "Registered", "a")).unwrap();
+        bytes.extend(serde_cbor::to_vec(&transition("web", "Running", "a")).unwrap());
+        bytes.truncate(bytes.len() - 3);
+        assert!(read_transitions(&bytes[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn recorded_states_are_replayed_per_pod() {
+        let recording = vec![
+            transition("web", "Registered", "a"),
+            transition("db", "Registered", "b"),
+            transition("web", "Running", "a"),
+            transition("db", "Error", "b"),
+            transition("web", "Completed", "c"),
+            transition("db", "Registered", "b"),
+        ];
+        let replays = replay_pods(&recording).await;
+        assert_eq!(replays.len(), 2);
+        assert_eq!(replays[0].pod, "default/web");
+        assert_eq!(
+            replays[0].states,
+            vec!["Registered", "Running", "Completed"]
+        );
+        assert_eq!(replays[0].spec_changes, 1);
+        assert_eq!(replays[0].result, Ok(()));
+        assert_eq!(replays[1].states, vec!["Registered", "Error", "Registered"]);
+        assert_eq!(replays[1].spec_changes, 0);
+        assert_eq!(replays[1].result, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn deleted_resumed_and_recreated_pods_are_replayed() {
+        let mut recording = vec![
+            transition("web", "Registered", "a"),
+            transition("web", "Running", "a"),
+            // The kubelet restarted, and resumed the pod where it registered
+            transition("web", "Registered", "a"),
+            transition("web", "Terminated", "a"),
+            // The pod was recreated
+            transition("web", "Registered", "b"),
+        ];
+        recording[2].kubelet_started = Utc.timestamp(60, 0);
+        let replays = replay_pods(&recording).await;
+        assert_eq!(replays[0].result, Ok(()));
+        assert_eq!(replays[0].states.len(), 5);
+
+        // Without a restart, the pod went back to Registered itself
+        recording[2].kubelet_started = recording[1].kubelet_started;
+        let replays = replay_pods(&recording).await;
+        assert_eq!(
+            replays[0].result,
+            Err(ReplayError::UnlistedTransition {
+                from: "Running",
+                to: "Registered"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn transitions_the_state_machine_no_longer_makes_are_reported() {
+        let recording = vec![
+            transition("web", "Registered", "a"),
+            transition("web", "Completed", "a"),
+        ];
+        let replays = replay_pods(&recording).await;
+        assert_eq!(
+            replays[0].result,
+            Err(ReplayError::UnlistedTransition {
+                from: "Registered",
+                to: "Completed"
+            })
+        );
+
+        let recording = vec![transition("web", "ImagePull", "a")];
+        let replays = replay_pods(&recording).await;
+        assert_eq!(
+            replays[0].result,
+            Err(ReplayError::UnknownState {
+                state: "ImagePull".to_owned()
+            })
+        );
+    }
+}
@@ -0,0 +1,565 @@
+//! Running single states of a state machine in unit tests.
+//!
+//! A [`TestStateRunner`] calls `next` on one state, without a kubelet, an
+//! API server or the rest of the state machine, and returns the
+//! [`TestTransition`] it made, which tests can check with
+//! [`assert_next_state!`](crate::assert_next_state):
+//!
+//! ```
+//! use kubelet::assert_next_state;
+//! use kubelet::pod::state::prelude::*;
+//! use kubelet::state::testing::TestStateRunner;
+//!
+//! #[derive(Debug)]
+//! struct Starting;
+//!
+//! #[derive(Debug)]
+//! struct Running;
+//!
+//! impl TransitionTo<Running> for Starting {}
+//!
+//! struct PodState;
+//!
+//! #[async_trait::async_trait]
+//! impl ObjectState for PodState {
+//!     type Manifest = String;
+//!     type Status = ();
+//!     type SharedState = ();
+//!     async fn async_drop(self, _shared: &mut ()) {}
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl State<PodState> for Starting {
+//!     async fn next(
+//!         self: Box<Self>,
+//!         _shared: SharedState<()>,
+//!         _state: &mut PodState,
+//!         _manifest: Manifest<String>,
+//!     ) -> Transition<PodState> {
+//!         Transition::next(self, Running)
+//!     }
+//!
+//!     async fn status(&self, _state: &mut PodState, _manifest: &String) -> anyhow::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # #[async_trait::async_trait]
+//! # impl State<PodState> for Running {
+//! #     async fn next(
+//! #         self: Box<Self>,
+//! #         _shared: SharedState<()>,
+//! #         _state: &mut PodState,
+//! #         _manifest: Manifest<String>,
+//! #     ) -> Transition<PodState> {
+//! #         Transition::Complete(Ok(()))
+//! #     }
+//! #
+//! #     async fn status(&self, _state: &mut PodState, _manifest: &String) -> anyhow::Result<()> {
+//! #         Ok(())
+//! #     }
+//! # }
+//! #
+//! # async fn starting_pods_run() {
+//! let runner = TestStateRunner::new(());
+//! let transition = runner
+//!     .next(Starting, &mut PodState, &"pod".to_owned())
+//!     .await;
+//! assert_next_state!(transition, Running);
+//! # }
+//! ```
+//!
+//! States can also be replaced with [`MockState`]s, which transition and
+//! report statuses as the test tells them to, and record the calls the
+//! state machine made to them, so that tests can check how states are run
+//! with [`TestStateRunner::run_to_completion`].
+//!
+//! With the `proptest` feature, [`StateProperties`] checks that state
+//! machines keep to their graph over random runs.
+
+use std::any::TypeId;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use krator::{Manifest, ObjectState, SharedState, State, Transition, TransitionTo};
+
+#[cfg(any(test, feature = "proptest"))]
+mod properties;
+
+#[cfg(any(test, feature = "proptest"))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "proptest")))]
+pub use properties::{check_status, PropertyViolation, StateProperties};
+
+/// Runs single states with the same shared state, for testing
+pub struct TestStateRunner<S: ObjectState> {
+    shared: SharedState<S::SharedState>,
+}
+
+impl<S: ObjectState> TestStateRunner<S> {
+    /// Create a runner that gives states `shared` as their shared state
+    pub fn new(shared: S::SharedState) -> Self {
+        TestStateRunner {
+            shared: std::sync::Arc::new(tokio::sync::RwLock::new(shared)),
+        }
+    }
+
+    /// Create a runner that gives states `shared` as their shared state,
+    /// for shared states that are made by a provider rather than by the test
+    pub fn with_shared(shared: SharedState<S::SharedState>) -> Self {
+        TestStateRunner { shared }
+    }
+
+    /// The shared state the runner gives states, so that tests can set it up
+    /// or check what states did to it
+    pub fn shared(&self) -> SharedState<S::SharedState> {
+        self.shared.clone()
+    }
+
+    /// Call `next` on `state` once, with `object_state` and `manifest`, and
+    /// return the transition it made. The manifest doesn't change while the
+    /// state runs.
+    pub async fn next(
+        &self,
+        state: impl State<S>,
+        object_state: &mut S,
+        manifest: &S::Manifest,
+    ) -> TestTransition<S> {
+        self.next_boxed(Box::new(state), object_state, manifest)
+            .await
+    }
+
+    async fn next_boxed(
+        &self,
+        state: Box<dyn State<S>>,
+        object_state: &mut S,
+        manifest: &S::Manifest,
+    ) -> TestTransition<S> {
+        let (_manifest_tx, manifest) = Manifest::new(manifest.clone());
+        let transition = state
+            .next(self.shared.clone(), object_state, manifest)
+            .await;
+        match transition {
+            Transition::Next(next) => TestTransition::Next(next.into()),
+            Transition::Complete(result) => TestTransition::Complete(result),
+        }
+    }
+
+    /// Run the state machine from `state` until it completes, with
+    /// `object_state` and `manifest`, and return the result it completed
+    /// with. Each state's status is asked for when it is entered, as the
+    /// operator does, but is not used.
+    pub async fn run_to_completion(
+        &self,
+        state: impl State<S>,
+        object_state: &mut S,
+        manifest: &S::Manifest,
+    ) -> anyhow::Result<()> {
+        let (_manifest_tx, manifest_rx) = Manifest::new(manifest.clone());
+        let mut state: Box<dyn State<S>> = Box::new(state);
+        loop {
+            // Statuses are only patched by the operator
+            let _ = state.status(object_state, manifest).await;
+            match state
+                .next(self.shared.clone(), object_state, manifest_rx.clone())
+                .await
+            {
+                Transition::Next(next) => state = next.into(),
+                Transition::Complete(result) => return result,
+            }
+        }
+    }
+}
+
+/// The transition a state made when run by a [`TestStateRunner`]
+pub enum TestTransition<S: ObjectState> {
+    /// The state transitioned to this state
+    Next(Box<dyn State<S>>),
+    /// The state ended the state machine with this result
+    Complete(anyhow::Result<()>),
+}
+
+impl<S: ObjectState> TestTransition<S> {
+    /// Whether the state transitioned to a state of type `T`
+    pub fn is_next<T: State<S>>(&self) -> bool {
+        match self {
+            TestTransition::Next(state) => state.state_type_id() == TypeId::of::<T>(),
+            TestTransition::Complete(_) => false,
+        }
+    }
+
+    /// The state the state transitioned to, if it didn't end the state
+    /// machine
+    pub fn next_state(&self) -> Option<&dyn State<S>> {
+        match self {
+            TestTransition::Next(state) => Some(state.as_ref()),
+            TestTransition::Complete(_) => None,
+        }
+    }
+
+    /// The result the state ended the state machine with, if it did
+    pub fn into_result(self) -> Option<anyhow::Result<()>> {
+        match self {
+            TestTransition::Next(_) => None,
+            TestTransition::Complete(result) => Some(result),
+        }
+    }
+}
+
+impl<S: ObjectState> fmt::Debug for TestTransition<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestTransition::Next(state) => f.debug_tuple("Next").field(state).finish(),
+            TestTransition::Complete(result) => f.debug_tuple("Complete").field(result).finish(),
+        }
+    }
+}
+
+/// Assert that a [`TestTransition`] is to a state of the given type.
+///
+/// ```ignore
+/// assert_next_state!(transition, ImagePull<P>);
+/// ```
+#[macro_export]
+macro_rules! assert_next_state {
+    ($transition:expr, $state:ty) => {
+        match &$transition {
+            transition => {
+                if !transition.is_next::<$state>() {
+                    panic!(
+                        "expected a transition to {}, but got {:?}",
+                        stringify!($state),
+                        transition
+                    );
+                }
+            }
+        }
+    };
+}
+
+/// A call a state machine made to a [`MockState`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockCall {
+    /// The status of the named mock was asked for
+    Status(&'static str),
+    /// The named mock was run
+    Next(&'static str),
+}
+
+/// The calls made to a group of [`MockState`]s, in the order they were made
+#[derive(Clone, Debug, Default)]
+pub struct MockCalls(Arc<Mutex<Vec<MockCall>>>);
+
+impl MockCalls {
+    fn record(&self, call: MockCall) {
+        self.0.lock().expect("mock calls lock poisoned").push(call);
+    }
+
+    /// The calls made so far
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.0.lock().expect("mock calls lock poisoned").clone()
+    }
+
+    /// The names of the mocks that were run, in order
+    pub fn states_run(&self) -> Vec<&'static str> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                MockCall::Next(name) => Some(name),
+                MockCall::Status(_) => None,
+            })
+            .collect()
+    }
+}
+
+type NextFn<S> =
+    Box<dyn FnOnce(&mut S, &<S as ObjectState>::Manifest) -> Transition<S> + Send + Sync + 'static>;
+type StatusFn<S> = Box<
+    dyn Fn(&mut S, &<S as ObjectState>::Manifest) -> anyhow::Result<<S as ObjectState>::Status>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+/// A state that makes the transition and reports the status the test gives
+/// it, and records the calls made to it.
+///
+/// ```
+/// use kubelet::pod::state::prelude::*;
+/// use kubelet::state::testing::{MockCall, MockState, TestStateRunner};
+///
+/// struct PodState;
+///
+/// #[async_trait::async_trait]
+/// impl ObjectState for PodState {
+///     type Manifest = String;
+///     type Status = String;
+///     type SharedState = ();
+///     async fn async_drop(self, _shared: &mut ()) {}
+/// }
+///
+/// # async fn mocks_are_run_in_sequence() {
+/// let first = MockState::<PodState>::new("Registered").with_status(|_, _| Ok("Pending".to_owned()));
+/// let calls = first.calls();
+/// let machine = MockState::sequence(vec![
+///     first,
+///     MockState::new("Running"),
+///     MockState::new("Failed")
+///         .with_next(|_, _| Transition::Complete(Err(anyhow::anyhow!("failed")))),
+/// ]);
+///
+/// let runner = TestStateRunner::new(());
+/// let result = runner
+///     .run_to_completion(machine, &mut PodState, &"pod".to_owned())
+///     .await;
+/// assert!(result.is_err());
+/// assert_eq!(calls.states_run(), vec!["Registered", "Running", "Failed"]);
+/// assert_eq!(calls.calls()[0], MockCall::Status("Registered"));
+/// # }
+/// ```
+pub struct MockState<S: ObjectState> {
+    name: &'static str,
+    next: Option<NextFn<S>>,
+    status: Option<StatusFn<S>>,
+    then: Option<Box<MockState<S>>>,
+    calls: MockCalls,
+}
+
+impl<S: ObjectState> MockState<S> {
+    /// Create a mock named `name` that completes the state machine
+    /// successfully, and fails to report a status
+    pub fn new(name: &'static str) -> Self {
+        MockState {
+            name,
+            next: None,
+            status: None,
+            then: None,
+            calls: MockCalls::default(),
+        }
+    }
+
+    /// Make the transition that `next` returns when run, rather than
+    /// completing
+    pub fn with_next(
+        mut self,
+        next: impl FnOnce(&mut S, &S::Manifest) -> Transition<S> + Send + Sync + 'static,
+    ) -> Self {
+        self.next = Some(Box::new(next));
+        self
+    }
+
+    /// Report the status that `status` returns
+    pub fn with_status(
+        mut self,
+        status: impl Fn(&mut S, &S::Manifest) -> anyhow::Result<S::Status> + Send + Sync + 'static,
+    ) -> Self {
+        self.status = Some(Box::new(status));
+        self
+    }
+
+    /// Record calls in `calls`, such as the calls of other mocks
+    pub fn with_calls(mut self, calls: MockCalls) -> Self {
+        self.calls = calls;
+        self
+    }
+
+    /// The calls made to this mock
+    pub fn calls(&self) -> MockCalls {
+        self.calls.clone()
+    }
+
+    /// Chain `mocks` so that each transitions to the one after it, unless it
+    /// was given a transition with [`MockState::with_next`], and return the
+    /// first. The mocks record their calls with the first one's.
+    ///
+    /// # Panics
+    ///
+    /// If `mocks` is empty.
+    pub fn sequence(mocks: Vec<MockState<S>>) -> Self {
+        let calls = mocks
+            .first()
+            .expect("a sequence of mocks needs at least one mock")
+            .calls();
+        let mut then: Option<MockState<S>> = None;
+        for mock in mocks.into_iter().rev() {
+            let mut mock = mock.with_calls(calls.clone());
+            mock.then = then.map(Box::new);
+            then = Some(mock);
+        }
+        then.expect("a sequence of mocks needs at least one mock")
+    }
+
+    /// A transition to `state`, for mocks to return from
+    /// [`MockState::with_next`]
+    pub fn transition_to(state: impl State<S>) -> Transition<S> {
+        Transition::next_unchecked(Box::new(MockState::<S>::new("mock")), state)
+    }
+}
+
+impl<S: ObjectState> fmt::Debug for MockState<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MockState").field(&self.name).finish()
+    }
+}
+
+impl<S: ObjectState> TransitionTo<MockState<S>> for MockState<S> {}
+
+#[async_trait::async_trait]
+impl<S: ObjectState> State<S> for MockState<S> {
+    async fn next(
+        self: Box<Self>,
+        _shared: SharedState<S::SharedState>,
+        state: &mut S,
+        manifest: Manifest<S::Manifest>,
+    ) -> Transition<S> {
+        let mut this = self;
+        this.calls.record(MockCall::Next(this.name));
+        if let Some(next) = this.next.take() {
+            return next(state, &manifest.latest());
+        }
+        match this.then.take() {
+            Some(then) => Transition::next(this, *then),
+            None => Transition::Complete(Ok(())),
+        }
+    }
+
+    async fn status(&self, state: &mut S, manifest: &S::Manifest) -> anyhow::Result<S::Status> {
+        self.calls.record(MockCall::Status(self.name));
+        match &self.status {
+            Some(status) => status(state, manifest),
+            None => Err(anyhow::anyhow!("{} has no status", self.name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use krator::TransitionTo;
+
+    #[derive(Debug)]
+    struct Counting;
+    #[derive(Debug)]
+    struct Counted;
+
+    impl TransitionTo<Counted> for Counting {}
+
+    #[derive(Default)]
+    struct CountState {
+        seen: Vec<String>,
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectState for CountState {
+        type Manifest = String;
+        type Status = ();
+        type SharedState = usize;
+        async fn async_drop(self, _shared: &mut usize) {}
+    }
+
+    #[async_trait::async_trait]
+    impl State<CountState> for Counting {
+        async fn next(
+            self: Box<Self>,
+            shared: SharedState<usize>,
+            state: &mut CountState,
+            manifest: Manifest<String>,
+        ) -> Transition<CountState> {
+            *shared.write().await += 1;
+            state.seen.push(manifest.latest());
+            Transition::next(self, Counted)
+        }
+
+        async fn status(&self, _state: &mut CountState, _manifest: &String) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl State<CountState> for Counted {
+        async fn next(
+            self: Box<Self>,
+            _shared: SharedState<usize>,
+            _state: &mut CountState,
+            _manifest: Manifest<String>,
+        ) -> Transition<CountState> {
+            Transition::Complete(Err(anyhow::anyhow!("counted")))
+        }
+
+        async fn status(&self, _state: &mut CountState, _manifest: &String) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn states_are_run_once_with_the_shared_and_object_state() {
+        let runner = TestStateRunner::new(0);
+        let mut state = CountState::default();
+        let transition = runner.next(Counting, &mut state, &"pod".to_owned()).await;
+
+        assert_next_state!(transition, Counted);
+        assert!(!transition.is_next::<Counting>());
+        assert_eq!(format!("{:?}", transition.next_state().unwrap()), "Counted");
+        assert_eq!(*runner.shared().read().await, 1);
+        assert_eq!(state.seen, vec!["pod"]);
+    }
+
+    #[tokio::test]
+    async fn states_that_end_the_state_machine_complete() {
+        let runner = TestStateRunner::new(0);
+        let transition = runner
+            .next(Counted, &mut CountState::default(), &"pod".to_owned())
+            .await;
+        assert!(!transition.is_next::<Counted>());
+        assert!(transition.next_state().is_none());
+        let result = transition.into_result().unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "counted");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "expected a transition to Counting, but got Next(Counted)")]
+    async fn transitions_to_other_states_fail_the_assertion() {
+        let runner = TestStateRunner::new(0);
+        let transition = runner
+            .next(Counting, &mut CountState::default(), &"pod".to_owned())
+            .await;
+        assert_next_state!(transition, Counting);
+    }
+
+    #[tokio::test]
+    async fn mocks_record_their_calls_in_sequence() {
+        let first = MockState::<CountState>::new("Counting").with_status(|state, _| {
+            state.seen.push("status".to_owned());
+            Ok(())
+        });
+        let calls = first.calls();
+        let machine = MockState::sequence(vec![
+            first,
+            MockState::new("Waiting"),
+            MockState::new("Ending").with_next(|_, _| MockState::transition_to(Counted)),
+        ]);
+        let runner = TestStateRunner::new(0);
+        let mut state = CountState::default();
+        let result = runner
+            .run_to_completion(machine, &mut state, &"pod".to_owned())
+            .await;
+
+        assert_eq!(result.unwrap_err().to_string(), "counted");
+        assert_eq!(calls.states_run(), vec!["Counting", "Waiting", "Ending"]);
+        assert_eq!(
+            calls.calls(),
+            vec![
+                MockCall::Status("Counting"),
+                MockCall::Next("Counting"),
+                MockCall::Status("Waiting"),
+                MockCall::Next("Waiting"),
+                MockCall::Status("Ending"),
+                MockCall::Next("Ending"),
+            ]
+        );
+        assert_eq!(state.seen, vec!["status"]);
+    }
+}
@@ -0,0 +1,426 @@
+//! Property testing of state machines with `proptest`.
+//!
+//! A [`StateProperties`] knows the graph of a state machine from the
+//! [`Transitions`] of its states, the same graph its diagram is drawn from.
+//! It generates random walks through the graph, to run [`MockState`]s
+//! along, and runs real states with a [`TestStateRunner`], checking that
+//! they keep to the invariants every state machine should:
+//!
+//! * each transition is an edge of the graph, so a state only transitions to
+//!   itself if it lists itself
+//! * no state runs after one that completes the state machine
+//! * the status of each state, and the failed status reported when the state
+//!   machine completes with an error, are JSON objects that come back the
+//!   same from serializing them
+//!
+//! ```ignore
+//! let runner = TestStateRunner::with_shared(provider.provider_state());
+//! let properties = StateProperties::new::<Registered<MyProvider>>(runner);
+//! proptest!(|(pod in pods())| {
+//!     let mut pod_state = block_on(provider.initialize_pod_state(&pod))?;
+//!     block_on(properties.check(Registered::default(), &mut pod_state, &pod))?;
+//! });
+//! ```
+//!
+//! [`MockState`]: super::MockState
+
+use std::collections::BTreeMap;
+
+use krator::diagram::{StateNode, Transitions};
+use krator::{ObjectState, ObjectStatus, State};
+use proptest::sample::Index;
+use proptest::strategy::Strategy;
+use thiserror::Error;
+
+use super::{TestStateRunner, TestTransition};
+
+/// The most states a run is checked for, by default
+const DEFAULT_MAX_STEPS: usize = 32;
+
+/// An invariant of state machines that a run of one broke
+#[derive(Debug, Error)]
+pub enum PropertyViolation {
+    /// A state transitioned to a state it doesn't list in its transitions
+    #[error("{from} transitioned to {to}, which is not one of its transitions")]
+    UnlistedTransition {
+        /// The state that transitioned
+        from: &'static str,
+        /// The state it transitioned to
+        to: &'static str,
+    },
+    /// A state ran after a state that completes the state machine
+    #[error("{state} ran after {completed}, which completes the state machine")]
+    RanAfterComplete {
+        /// The state that completed the state machine
+        completed: &'static str,
+        /// The state that ran after it
+        state: &'static str,
+    },
+    /// A state that isn't in the state machine's graph ran
+    #[error("{state} is not a state of the state machine")]
+    UnknownState {
+        /// The state
+        state: &'static str,
+    },
+    /// A state reported a status that isn't a JSON object, or that doesn't
+    /// come back the same from serializing it
+    #[error("{state} reported a status that is not a valid JSON object: {patch}")]
+    InvalidStatus {
+        /// The state
+        state: &'static str,
+        /// The status patch, as serialized
+        patch: String,
+    },
+}
+
+/// Checks that a state machine keeps to its graph, for property tests
+pub struct StateProperties<S: ObjectState> {
+    runner: TestStateRunner<S>,
+    initial: &'static str,
+    graph: BTreeMap<&'static str, Vec<&'static str>>,
+    max_steps: usize,
+}
+
+impl<S: ObjectState> StateProperties<S> {
+    /// Check the state machine starting in state `T`, running states with
+    /// `runner`
+    pub fn new<T: Transitions + ?Sized>(runner: TestStateRunner<S>) -> Self {
+        StateProperties {
+            runner,
+            initial: StateNode::of::<T>().name(),
+            graph: krator::diagram::graph::<T>(),
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+
+    /// Run at most `max_steps` states of each run, and make walks of at most
+    /// that many states, for state machines that don't complete on their own
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// The runner states are run with
+    pub fn runner(&self) -> &TestStateRunner<S> {
+        &self.runner
+    }
+
+    /// The names of the states of the state machine
+    pub fn states(&self) -> Vec<&'static str> {
+        self.graph.keys().copied().collect()
+    }
+
+    /// The names of the states the named state can transition to, or `None`
+    /// if it isn't a state of the state machine
+    pub fn transitions(&self, state: &str) -> Option<&[&'static str]> {
+        self.graph.get(state).map(Vec::as_slice)
+    }
+
+    /// Random walks through the graph from the initial state, as the names
+    /// of the states in the order they are entered. Walks end at a state
+    /// that completes the state machine, or after the most steps, so that
+    /// [`check`](Self::check) runs every state of a walk.
+    pub fn walks(&self) -> impl Strategy<Value = Vec<&'static str>> {
+        let initial = self.initial;
+        let graph = self.graph.clone();
+        // The initial state is the first step, so the rest are one fewer
+        let transitions = 0..=self.max_steps.saturating_sub(1);
+        proptest::collection::vec(proptest::arbitrary::any::<Index>(), transitions).prop_map(
+            move |choices| {
+                let mut walk = vec![initial];
+                let mut state = initial;
+                for choice in choices {
+                    let transitions = &graph[state];
+                    if transitions.is_empty() {
+                        break;
+                    }
+                    state = transitions[choice.index(transitions.len())];
+                    walk.push(state);
+                }
+                walk
+            },
+        )
+    }
+
+    /// Check that `from` can transition to `to`
+    pub fn check_transition(
+        &self,
+        from: &'static str,
+        to: &'static str,
+    ) -> Result<(), PropertyViolation> {
+        let transitions = self
+            .transitions(from)
+            .ok_or(PropertyViolation::UnknownState { state: from })?;
+        if transitions.contains(&to) {
+            Ok(())
+        } else {
+            Err(PropertyViolation::UnlistedTransition { from, to })
+        }
+    }
+
+    /// Check that the named states ran in an order the graph allows, such
+    /// as the states a sequence of mocks ran, from
+    /// [`MockCalls::states_run`](super::MockCalls::states_run)
+    pub fn check_run(&self, states: &[&'static str]) -> Result<(), PropertyViolation> {
+        for pair in states.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            if self.transitions(from).is_some_and(<[_]>::is_empty) {
+                return Err(PropertyViolation::RanAfterComplete {
+                    completed: from,
+                    state: to,
+                });
+            }
+            self.check_transition(from, to)?;
+        }
+        Ok(())
+    }
+
+    /// Run the state machine from `state` with `object_state` and
+    /// `manifest` until it completes or has run the most states, checking
+    /// each transition and status. Returns the names of the states entered,
+    /// in order, including the one it stopped in without running.
+    pub async fn check(
+        &self,
+        state: impl State<S>,
+        object_state: &mut S,
+        manifest: &S::Manifest,
+    ) -> Result<Vec<&'static str>, PropertyViolation>
+    where
+        S::Status: ObjectStatus,
+    {
+        let mut state: Box<dyn State<S>> = Box::new(state);
+        let mut run = vec![state.name()];
+        for _ in 0..self.max_steps {
+            let name = state.name();
+            // States that can't report a status leave the object's status as
+            // it was
+            if let Ok(status) = state.status(object_state, manifest).await {
+                check_status(name, &status)?;
+            }
+            match self.runner.next_boxed(state, object_state, manifest).await {
+                TestTransition::Next(next) => {
+                    self.check_transition(name, next.name())?;
+                    run.push(next.name());
+                    state = next;
+                }
+                TestTransition::Complete(result) => {
+                    if let Err(e) = result {
+                        check_status(name, &S::Status::failed(&format!("{:?}", e)))?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(run)
+    }
+}
+
+/// Check that the status `state` reported patches the object with a JSON
+/// object that comes back the same from serializing it
+pub fn check_status<T: ObjectStatus>(
+    state: &'static str,
+    status: &T,
+) -> Result<(), PropertyViolation> {
+    let patch = status.json_patch();
+    let serialized = patch.to_string();
+    let round_tripped: Option<serde_json::Value> = serde_json::from_str(&serialized).ok();
+    if patch.is_object() && round_tripped.as_ref() == Some(&patch) {
+        Ok(())
+    } else {
+        Err(PropertyViolation::InvalidStatus {
+            state,
+            patch: serialized,
+        })
+    }
+}
+
+#[cfg(test)]
+// The states are only named, and never constructed
+#[allow(dead_code)]
+mod test {
+    use super::*;
+    use crate::state::testing::MockState;
+    use krator::Transition;
+    use proptest::prelude::*;
+
+    /// Mocks don't need a runtime to run on, but proptest bodies aren't async
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime should build")
+            .block_on(future)
+    }
+
+    struct Tagged;
+    struct Roam;
+    struct Eat;
+    struct Released;
+
+    impl Transitions for Tagged {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Roam>()]
+        }
+    }
+
+    impl Transitions for Roam {
+        fn transitions() -> Vec<StateNode> {
+            vec![
+                StateNode::of::<Roam>(),
+                StateNode::of::<Eat>(),
+                StateNode::of::<Released>(),
+            ]
+        }
+    }
+
+    impl Transitions for Eat {
+        fn transitions() -> Vec<StateNode> {
+            vec![StateNode::of::<Roam>()]
+        }
+    }
+
+    impl Transitions for Released {
+        fn transitions() -> Vec<StateNode> {
+            vec![]
+        }
+    }
+
+    struct MooseState;
+
+    #[derive(Debug)]
+    struct MooseStatus(serde_json::Value);
+
+    impl ObjectStatus for MooseStatus {
+        fn json_patch(&self) -> serde_json::Value {
+            self.0.clone()
+        }
+
+        fn failed(e: &str) -> Self {
+            MooseStatus(serde_json::json!({ "status": { "message": e } }))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectState for MooseState {
+        type Manifest = String;
+        type Status = MooseStatus;
+        type SharedState = ();
+        async fn async_drop(self, _shared: &mut ()) {}
+    }
+
+    fn properties() -> StateProperties<MooseState> {
+        StateProperties::new::<Tagged>(TestStateRunner::new(())).with_max_steps(8)
+    }
+
+    /// A mock for each state of `walk`, reporting a status and transitioning
+    /// to the next, and the last completing the state machine
+    fn mocks(walk: &[&'static str]) -> Vec<MockState<MooseState>> {
+        walk.iter()
+            .map(|&name| {
+                MockState::new(name)
+                    .with_status(move |_, _| Ok(MooseStatus(serde_json::json!({ "phase": name }))))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_graph_is_read_from_the_initial_state() {
+        let properties = properties();
+        assert_eq!(
+            properties.states(),
+            vec!["Eat", "Released", "Roam", "Tagged"]
+        );
+        assert_eq!(properties.transitions("Tagged"), Some(&["Roam"][..]));
+        assert_eq!(properties.transitions("Released"), Some(&[][..]));
+        assert_eq!(properties.transitions("Debugging"), None);
+    }
+
+    #[test]
+    fn self_transitions_must_be_listed() {
+        let properties = properties();
+        assert!(properties.check_transition("Roam", "Roam").is_ok());
+        assert!(matches!(
+            properties.check_transition("Eat", "Eat"),
+            Err(PropertyViolation::UnlistedTransition {
+                from: "Eat",
+                to: "Eat"
+            })
+        ));
+        assert!(matches!(
+            properties.check_run(&["Tagged", "Roam", "Released", "Roam"]),
+            Err(PropertyViolation::RanAfterComplete {
+                completed: "Released",
+                state: "Roam"
+            })
+        ));
+    }
+
+    #[test]
+    fn unlisted_transitions_are_found_when_checking_runs() {
+        let properties = properties();
+        let machine = MockState::sequence(vec![
+            MockState::new("Tagged"),
+            MockState::new("Roam"),
+            MockState::new("Eat").with_next(|_, _| MockState::transition_to(MockState::new("Eat"))),
+        ]);
+        let violation =
+            block_on(properties.check(machine, &mut MooseState, &"moose".to_owned())).unwrap_err();
+        assert!(matches!(
+            violation,
+            PropertyViolation::UnlistedTransition {
+                from: "Eat",
+                to: "Eat"
+            }
+        ));
+    }
+
+    #[test]
+    fn statuses_must_be_json_objects() {
+        assert!(check_status("Roam", &MooseStatus(serde_json::json!({ "phase": "Roam" }))).is_ok());
+        assert!(matches!(
+            check_status("Roam", &MooseStatus(serde_json::json!("Roam"))),
+            Err(PropertyViolation::InvalidStatus { state: "Roam", .. })
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn walks_keep_to_the_graph(walk in properties().walks()) {
+            let properties = properties();
+            prop_assert_eq!(walk[0], "Tagged");
+            prop_assert!(walk.len() <= 8);
+            properties.check_run(&walk)?;
+        }
+
+        #[test]
+        fn mocks_run_along_walks_are_checked(walk in properties().walks()) {
+            let properties = properties();
+            let mocks = mocks(&walk);
+            let calls = mocks[0].calls();
+            let run = block_on(properties.check(
+                MockState::sequence(mocks),
+                &mut MooseState,
+                &"moose".to_owned(),
+            ))?;
+            prop_assert_eq!(&run, &walk);
+            prop_assert_eq!(calls.states_run(), walk);
+        }
+
+        #[test]
+        fn no_state_runs_after_completing(walk in properties().walks(), error in ".*") {
+            let properties = properties();
+            let mut mocks = mocks(&walk);
+            let calls = mocks[0].calls();
+            let last = mocks.pop().unwrap();
+            mocks.push(last.with_next(move |_, _| Transition::Complete(Err(anyhow::anyhow!(error)))));
+            // Mocks after the one that completes would transition on from it
+            mocks.push(MockState::new("Roam"));
+            let run = block_on(properties.runner().run_to_completion(
+                MockState::sequence(mocks),
+                &mut MooseState,
+                &"moose".to_owned(),
+            ));
+            prop_assert!(run.is_err());
+            prop_assert_eq!(calls.states_run(), walk);
+        }
+    }
+}
@@ -0,0 +1,708 @@
+//! The resource usage of the node and its pods, as served by the kubelet's
+//! Summary API at `/stats/summary`.
+//!
+//! metrics-server and `kubectl top` read the CPU and memory used by the node
+//! and its pods from the summary. The node's usage is sampled from the host
+//! each time the summary is asked for: CPU time from `/proc/stat`, memory
+//! from `/proc/meminfo`, and the filesystem the kubelet's data directory is
+//! on. The usage of pods and their containers comes from the provider (see
+//! [`Provider::pod_stats`](crate::provider::Provider::pod_stats)).
+//!
+//! Values that aren't known, such as the node's CPU on hosts other than
+//! Linux, are left out of the summary rather than reported as zero, and each
+//! value is stamped with the time it was sampled. CPU usage rates are worked
+//! out from the cumulative CPU time of consecutive samples, so they are left
+//! out of the first summary after a pod or container starts.
+//!
+//! The same usage is served in the Prometheus text format at
+//! `/metrics/resource`, which newer versions of metrics-server read instead.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::node;
+use crate::provider::{NotImplementedError, Provider};
+use resource_metrics::CpuCounters;
+pub(crate) use resource_metrics::CONTENT_TYPE as RESOURCE_METRICS_CONTENT_TYPE;
+
+mod resource_metrics;
+
+#[cfg(target_os = "linux")]
+const PROC_STAT: &str = "/proc/stat";
+
+/// The resource usage of the node and its pods
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// The usage of the node as a whole
+    pub node: NodeStats,
+    /// The usage of each of the node's running pods
+    pub pods: Vec<PodStats>,
+}
+
+/// The resource usage of the node
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeStats {
+    /// The name of the node
+    pub node_name: String,
+    /// When the host booted
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_time"
+    )]
+    pub start_time: Option<DateTime<Utc>>,
+    /// The CPU used by the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// The memory used by the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+    /// The filesystem the kubelet's data directory, where pods keep their
+    /// scratch files, is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs: Option<FsStats>,
+    /// The storage used by the runtime
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeStats>,
+}
+
+/// The storage used by the runtime
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeStats {
+    /// The filesystem modules are stored on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_fs: Option<FsStats>,
+}
+
+/// Identifies the pod that stats are for
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PodReference {
+    /// The name of the pod
+    pub name: String,
+    /// The namespace of the pod
+    pub namespace: String,
+    /// The UID of the pod
+    pub uid: String,
+}
+
+/// The resource usage of a pod
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodStats {
+    /// The pod the stats are for
+    pub pod_ref: PodReference,
+    /// When the pod started
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_time"
+    )]
+    pub start_time: Option<DateTime<Utc>>,
+    /// The usage of each of the pod's running containers
+    pub containers: Vec<ContainerStats>,
+    /// The CPU used by all of the pod's containers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// The memory used by all of the pod's containers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+    /// The storage used by each of the pod's volumes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub volume: Vec<VolumeStats>,
+    /// The ephemeral storage used by the pod, such as by its container logs
+    /// and emptyDir volumes
+    #[serde(rename = "ephemeral-storage", skip_serializing_if = "Option::is_none")]
+    pub ephemeral_storage: Option<FsStats>,
+}
+
+/// The resource usage of a container
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStats {
+    /// The name of the container
+    pub name: String,
+    /// When the container started
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_time"
+    )]
+    pub start_time: Option<DateTime<Utc>>,
+    /// The CPU used by the container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuStats>,
+    /// The memory used by the container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryStats>,
+}
+
+/// CPU usage, as sampled at `time`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuStats {
+    /// When the usage was sampled
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    /// The CPU used since the last sample, in billionths of a core
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_nano_cores: Option<u64>,
+    /// The CPU time used since the start, in nanoseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_core_nano_seconds: Option<u64>,
+}
+
+/// Memory usage, as sampled at `time`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    /// When the usage was sampled
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    /// The bytes that can still be used before reaching the limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_bytes: Option<u64>,
+    /// The bytes in use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_bytes: Option<u64>,
+    /// The bytes in use that can't be reclaimed, which is what limits and
+    /// evictions are measured against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_set_bytes: Option<u64>,
+}
+
+/// The usage of a filesystem or of directories on it, as sampled at `time`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsStats {
+    /// When the usage was sampled
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    /// The bytes that can still be written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_bytes: Option<u64>,
+    /// The size of the filesystem in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_bytes: Option<u64>,
+    /// The bytes in use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_bytes: Option<u64>,
+    /// The inodes that can still be used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inodes_free: Option<u64>,
+    /// The number of inodes of the filesystem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inodes: Option<u64>,
+    /// The inodes in use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inodes_used: Option<u64>,
+}
+
+impl FsStats {
+    /// The usage of directories that hold `used_bytes`
+    pub fn used(time: DateTime<Utc>, used_bytes: u64) -> Self {
+        FsStats {
+            time,
+            available_bytes: None,
+            capacity_bytes: None,
+            used_bytes: Some(used_bytes),
+            inodes_free: None,
+            inodes: None,
+            inodes_used: None,
+        }
+    }
+}
+
+/// The storage used by a volume of a pod
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VolumeStats {
+    /// The name of the volume in the pod's spec
+    pub name: String,
+    /// The storage the volume uses
+    #[serde(flatten)]
+    pub fs: FsStats,
+}
+
+/// The options of a summary request
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SummaryOptions {
+    /// Only report CPU and memory, as metrics-server asks for
+    #[serde(default)]
+    pub only_cpu_and_memory: bool,
+}
+
+/// Serializes times as Kubernetes does, to the second
+fn serialize_time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn serialize_optional_time<S: Serializer>(
+    time: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize_time(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The cumulative CPU time of something at the time it was sampled
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CpuSample {
+    time: DateTime<Utc>,
+    usage_core_nano_seconds: u64,
+}
+
+/// Gathers the summary of the node and its pods
+pub(crate) struct StatsCollector {
+    node_name: String,
+    data_dir: PathBuf,
+    /// The CPU time of the node, and of each pod and container, when the
+    /// summary was last gathered, to work out usage rates from
+    previous: Mutex<HashMap<String, CpuSample>>,
+    /// The CPU time counted for each pod and container across restarts, for
+    /// `/metrics/resource`
+    counters: Mutex<CpuCounters>,
+}
+
+/// The sample of `what` the node has, or `None` if it couldn't be taken
+fn sampled<T>(what: &str, sample: anyhow::Result<T>) -> Option<T> {
+    match sample {
+        Ok(sample) => Some(sample),
+        Err(e) => {
+            debug!("Unable to sample {} of the node: {:?}", what, e);
+            None
+        }
+    }
+}
+
+impl StatsCollector {
+    pub(crate) fn new(config: &Config) -> Self {
+        StatsCollector {
+            node_name: config.node_name.clone(),
+            data_dir: config.data_dir.clone(),
+            previous: Mutex::new(HashMap::new()),
+            counters: Mutex::new(CpuCounters::default()),
+        }
+    }
+
+    /// Gathers the usage of the node, and of the pods the provider runs
+    pub(crate) async fn summary<P: Provider>(
+        &self,
+        provider: &P,
+        options: &SummaryOptions,
+    ) -> Summary {
+        let mut node = self.node_stats();
+        let mut pods = provider_pod_stats(provider).await.unwrap_or_else(|e| {
+            warn!("Unable to get the stats of pods from the provider: {:?}", e);
+            vec![]
+        });
+        for pod in &mut pods {
+            add_up_containers(pod);
+        }
+        self.add_usage_rates(&mut node, &mut pods);
+        if options.only_cpu_and_memory {
+            node.fs = None;
+            node.runtime = None;
+            for pod in &mut pods {
+                pod.volume.clear();
+                pod.ephemeral_storage = None;
+            }
+        }
+        Summary { node, pods }
+    }
+
+    /// Samples the usage of the host
+    fn node_stats(&self) -> NodeStats {
+        let now = Utc::now();
+        let fs = sampled("filesystem", node_fs_stats(now, &self.data_dir));
+        NodeStats {
+            node_name: self.node_name.clone(),
+            start_time: sampled("boot time", boot_time()),
+            cpu: sampled("CPU", node_cpu_stats(now)),
+            memory: sampled("memory", node_memory_stats(now)),
+            // Modules are stored in the data directory too
+            runtime: fs.clone().map(|fs| RuntimeStats { image_fs: Some(fs) }),
+            fs,
+        }
+    }
+
+    /// Works out how much CPU the node, and each pod and container, used
+    /// since the summary was last gathered
+    fn add_usage_rates(&self, node: &mut NodeStats, pods: &mut [PodStats]) {
+        let mut previous = self
+            .previous
+            .lock()
+            .expect("stats collector lock should not be poisoned");
+        let mut current = HashMap::new();
+        let mut add_rate = |key: String, cpu: Option<&mut CpuStats>| {
+            if let Some(cpu) = cpu {
+                add_usage_rate(&key, cpu, &previous, &mut current);
+            }
+        };
+        add_rate("node".to_owned(), node.cpu.as_mut());
+        for pod in pods {
+            let uid = pod.pod_ref.uid.clone();
+            add_rate(format!("pod/{}", uid), pod.cpu.as_mut());
+            for container in &mut pod.containers {
+                add_rate(
+                    format!("pod/{}/{}", uid, container.name),
+                    container.cpu.as_mut(),
+                );
+            }
+        }
+        // Pods and containers that are gone are forgotten
+        *previous = current;
+    }
+}
+
+/// Gets the usage of the pods the provider runs, which is none if the provider
+/// doesn't report it
+async fn provider_pod_stats<P: Provider>(provider: &P) -> anyhow::Result<Vec<PodStats>> {
+    match provider.pod_stats().await {
+        Ok(pods) => Ok(pods),
+        Err(e) if e.is::<NotImplementedError>() => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sets the CPU used since the previous sample under `key`, if there is one,
+/// remembering this sample in `current`
+fn add_usage_rate(
+    key: &str,
+    cpu: &mut CpuStats,
+    previous: &HashMap<String, CpuSample>,
+    current: &mut HashMap<String, CpuSample>,
+) {
+    let usage = match cpu.usage_core_nano_seconds {
+        Some(usage) => usage,
+        None => return,
+    };
+    let sample = CpuSample {
+        time: cpu.time,
+        usage_core_nano_seconds: usage,
+    };
+    if cpu.usage_nano_cores.is_none() {
+        cpu.usage_nano_cores = previous.get(key).and_then(|p| usage_nano_cores(p, &sample));
+    }
+    current.insert(key.to_owned(), sample);
+}
+
+/// The billionths of a core used between two samples. A container that
+/// restarted has used less since it started, which says nothing of the rate.
+fn usage_nano_cores(previous: &CpuSample, sample: &CpuSample) -> Option<u64> {
+    let elapsed = (sample.time - previous.time).num_nanoseconds()?;
+    let used = sample
+        .usage_core_nano_seconds
+        .checked_sub(previous.usage_core_nano_seconds)?;
+    if elapsed <= 0 {
+        return None;
+    }
+    Some((used as u128 * 1_000_000_000 / elapsed as u128) as u64)
+}
+
+/// Adds up the CPU and memory used by a pod's containers, if the provider
+/// didn't report what the pod uses as a whole
+fn add_up_containers(pod: &mut PodStats) {
+    let latest = |times: Vec<&DateTime<Utc>>| times.into_iter().max().copied();
+    let sum = |values: Vec<Option<u64>>| -> Option<u64> {
+        values.into_iter().fold(None, |total, value| match value {
+            Some(value) => Some(total.unwrap_or(0) + value),
+            None => total,
+        })
+    };
+    if pod.cpu.is_none() {
+        let cpus: Vec<&CpuStats> = pod
+            .containers
+            .iter()
+            .filter_map(|c| c.cpu.as_ref())
+            .collect();
+        pod.cpu = latest(cpus.iter().map(|cpu| &cpu.time).collect()).map(|time| CpuStats {
+            time,
+            usage_nano_cores: None,
+            usage_core_nano_seconds: sum(cpus.iter().map(|c| c.usage_core_nano_seconds).collect()),
+        });
+    }
+    if pod.memory.is_none() {
+        let memories: Vec<&MemoryStats> = pod
+            .containers
+            .iter()
+            .filter_map(|c| c.memory.as_ref())
+            .collect();
+        pod.memory =
+            latest(memories.iter().map(|memory| &memory.time).collect()).map(|time| MemoryStats {
+                time,
+                available_bytes: None,
+                usage_bytes: sum(memories.iter().map(|m| m.usage_bytes).collect()),
+                working_set_bytes: sum(memories.iter().map(|m| m.working_set_bytes).collect()),
+            });
+    }
+}
+
+fn node_fs_stats(time: DateTime<Utc>, data_dir: &std::path::Path) -> anyhow::Result<FsStats> {
+    let stats = node::filesystem_stats(data_dir)?;
+    Ok(FsStats {
+        time,
+        available_bytes: Some(stats.available_bytes),
+        capacity_bytes: Some(stats.total_bytes),
+        used_bytes: Some(stats.total_bytes.saturating_sub(stats.available_bytes)),
+        inodes_free: Some(stats.available_inodes),
+        inodes: Some(stats.total_inodes),
+        inodes_used: Some(stats.total_inodes.saturating_sub(stats.available_inodes)),
+    })
+}
+
+fn node_memory_stats(time: DateTime<Utc>) -> anyhow::Result<MemoryStats> {
+    let meminfo = node::read_meminfo()?;
+    let total = node::parse_meminfo(&meminfo, "MemTotal")?;
+    let available = node::parse_meminfo(&meminfo, "MemAvailable")?;
+    let free = node::parse_meminfo(&meminfo, "MemFree")?;
+    Ok(MemoryStats {
+        time,
+        available_bytes: Some(available),
+        usage_bytes: Some(total.saturating_sub(free)),
+        working_set_bytes: Some(total.saturating_sub(available)),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn node_cpu_stats(time: DateTime<Utc>) -> anyhow::Result<CpuStats> {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return Err(anyhow::anyhow!("unable to read the clock tick rate"));
+    }
+    let busy_ticks = parse_busy_ticks(&std::fs::read_to_string(PROC_STAT)?)?;
+    Ok(CpuStats {
+        time,
+        usage_nano_cores: None,
+        usage_core_nano_seconds: Some(
+            (busy_ticks as u128 * 1_000_000_000 / ticks_per_second as u128) as u64,
+        ),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn node_cpu_stats(_time: DateTime<Utc>) -> anyhow::Result<CpuStats> {
+    Err(anyhow::anyhow!("CPU usage is only sampled on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn boot_time() -> anyhow::Result<DateTime<Utc>> {
+    parse_boot_time(&std::fs::read_to_string(PROC_STAT)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boot_time() -> anyhow::Result<DateTime<Utc>> {
+    Err(anyhow::anyhow!("the boot time is only read on Linux"))
+}
+
+/// The clock ticks all CPUs have spent busy, from the `cpu` line of
+/// `/proc/stat`: the time spent in user and system mode, servicing
+/// interrupts, and stolen by the hypervisor, but not idle or waiting for I/O
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_busy_ticks(stat: &str) -> anyhow::Result<u64> {
+    let fields: Vec<u64> = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("cpu "))
+        .ok_or_else(|| anyhow::anyhow!("no cpu line in stat"))?
+        .split_whitespace()
+        .map(|field| field.parse())
+        .collect::<Result<_, _>>()?;
+    // user, nice, system, idle, iowait, irq, softirq and steal
+    if fields.len() < 8 {
+        return Err(anyhow::anyhow!("too few fields in the cpu line of stat"));
+    }
+    Ok(fields[0] + fields[1] + fields[2] + fields[5] + fields[6] + fields[7])
+}
+
+/// The time the host booted, from the `btime` line of `/proc/stat`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_boot_time(stat: &str) -> anyhow::Result<DateTime<Utc>> {
+    let seconds: i64 = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .ok_or_else(|| anyhow::anyhow!("no btime line in stat"))?
+        .trim()
+        .parse()?;
+    Ok(Utc.timestamp(seconds, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const STAT: &str = "cpu  100 20 30 4000 50 6 7 8 0 0
+cpu0 50 10 15 2000 25 3 3 4 0 0
+intr 12345
+btime 1600000000
+processes 4321
+";
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_600_000_000 + seconds, 0)
+    }
+
+    fn cpu(seconds: i64, usage: u64) -> CpuStats {
+        CpuStats {
+            time: time(seconds),
+            usage_nano_cores: None,
+            usage_core_nano_seconds: Some(usage),
+        }
+    }
+
+    fn container(name: &str, cpu_usage: Option<u64>, memory_usage: u64) -> ContainerStats {
+        ContainerStats {
+            name: name.to_owned(),
+            start_time: Some(time(0)),
+            cpu: cpu_usage.map(|usage| cpu(10, usage)),
+            memory: Some(MemoryStats {
+                time: time(10),
+                available_bytes: None,
+                usage_bytes: Some(memory_usage),
+                working_set_bytes: Some(memory_usage),
+            }),
+        }
+    }
+
+    fn pod(containers: Vec<ContainerStats>) -> PodStats {
+        PodStats {
+            pod_ref: PodReference {
+                name: "app".to_owned(),
+                namespace: "default".to_owned(),
+                uid: "1234".to_owned(),
+            },
+            start_time: Some(time(0)),
+            containers,
+            cpu: None,
+            memory: None,
+            volume: vec![],
+            ephemeral_storage: None,
+        }
+    }
+
+    #[test]
+    fn cpu_time_and_boot_time_are_read_from_stat() {
+        assert_eq!(parse_busy_ticks(STAT).unwrap(), 100 + 20 + 30 + 6 + 7 + 8);
+        assert_eq!(
+            parse_boot_time(STAT).unwrap(),
+            Utc.timestamp(1_600_000_000, 0)
+        );
+        assert!(parse_busy_ticks("cpu0 1 2 3 4 5 6 7 8\n").is_err());
+        assert!(parse_busy_ticks("cpu  1 2 3\n").is_err());
+    }
+
+    #[test]
+    fn usage_rates_are_worked_out_from_consecutive_samples() {
+        let sample = |seconds, usage| CpuSample {
+            time: time(seconds),
+            usage_core_nano_seconds: usage,
+        };
+        // Half a core over ten seconds
+        assert_eq!(
+            usage_nano_cores(&sample(0, 1_000_000_000), &sample(10, 6_000_000_000)),
+            Some(500_000_000)
+        );
+        // A restarted container has used less than before
+        assert_eq!(
+            usage_nano_cores(&sample(0, 6_000_000_000), &sample(10, 1_000_000_000)),
+            None
+        );
+        assert_eq!(usage_nano_cores(&sample(10, 0), &sample(10, 0)), None);
+
+        let previous = vec![("node".to_owned(), sample(0, 0))]
+            .into_iter()
+            .collect();
+        let mut current = HashMap::new();
+        let mut node_cpu = cpu(10, 20_000_000_000);
+        add_usage_rate("node", &mut node_cpu, &previous, &mut current);
+        assert_eq!(node_cpu.usage_nano_cores, Some(2_000_000_000));
+        let mut new_cpu = cpu(10, 1);
+        add_usage_rate("pod/new", &mut new_cpu, &previous, &mut current);
+        assert_eq!(new_cpu.usage_nano_cores, None);
+        assert_eq!(current.len(), 2);
+    }
+
+    #[test]
+    fn pods_add_up_what_their_containers_use() {
+        let mut stats = pod(vec![
+            container("a", Some(100), 65536),
+            container("b", None, 131072),
+        ]);
+        add_up_containers(&mut stats);
+        assert_eq!(stats.cpu, Some(cpu(10, 100)));
+        let memory = stats.memory.unwrap();
+        assert_eq!(memory.usage_bytes, Some(196608));
+        assert_eq!(memory.working_set_bytes, Some(196608));
+        assert_eq!(memory.available_bytes, None);
+
+        let mut without_cpu = pod(vec![container("a", None, 65536)]);
+        add_up_containers(&mut without_cpu);
+        assert_eq!(without_cpu.cpu, None);
+    }
+
+    #[test]
+    fn summaries_have_the_shape_of_the_summary_api() {
+        let mut stats = pod(vec![container("a", Some(100), 65536)]);
+        stats.volume = vec![VolumeStats {
+            name: "cache".to_owned(),
+            fs: FsStats::used(time(5), 4096),
+        }];
+        stats.ephemeral_storage = Some(FsStats::used(time(5), 8192));
+        let summary = Summary {
+            node: NodeStats {
+                node_name: "krustlet".to_owned(),
+                start_time: None,
+                cpu: None,
+                memory: None,
+                fs: None,
+                runtime: None,
+            },
+            pods: vec![stats],
+        };
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            json!({
+                "node": { "nodeName": "krustlet" },
+                "pods": [{
+                    "podRef": { "name": "app", "namespace": "default", "uid": "1234" },
+                    "startTime": "2020-09-13T12:26:40Z",
+                    "containers": [{
+                        "name": "a",
+                        "startTime": "2020-09-13T12:26:40Z",
+                        "cpu": { "time": "2020-09-13T12:26:50Z", "usageCoreNanoSeconds": 100 },
+                        "memory": {
+                            "time": "2020-09-13T12:26:50Z",
+                            "usageBytes": 65536,
+                            "workingSetBytes": 65536,
+                        },
+                    }],
+                    "volume": [{ "name": "cache", "time": "2020-09-13T12:26:45Z", "usedBytes": 4096 }],
+                    "ephemeral-storage": { "time": "2020-09-13T12:26:45Z", "usedBytes": 8192 },
+                }],
+            })
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn the_node_is_sampled() {
+        let dir = tempfile::tempdir().unwrap();
+        let collector = StatsCollector {
+            node_name: "krustlet".to_owned(),
+            data_dir: dir.path().to_owned(),
+            previous: Mutex::new(HashMap::new()),
+            counters: Mutex::new(CpuCounters::default()),
+        };
+        let node = collector.node_stats();
+        assert!(node.start_time.is_some());
+        assert!(node.cpu.unwrap().usage_core_nano_seconds.unwrap() > 0);
+        assert!(node.memory.unwrap().working_set_bytes.unwrap() > 0);
+        let fs = node.fs.unwrap();
+        assert!(fs.capacity_bytes.unwrap() >= fs.available_bytes.unwrap());
+        assert_eq!(node.runtime.unwrap().image_fs, Some(fs));
+    }
+}
@@ -0,0 +1,500 @@
+//! The resource usage of the node and its pods in the Prometheus text format,
+//! as served at `/metrics/resource`.
+//!
+//! The metric families and their labels are those that other kubelets serve
+//! at this path for metrics-server: the cumulative CPU time and the working
+//! set of the node, of each pod and of each container, and when each
+//! container started. Usage that isn't known is left out, and samples are
+//! stamped with the time they were taken.
+//!
+//! CPU time is a counter, so it must never go down between scrapes. A
+//! container that restarts counts its CPU time from zero again, so when the
+//! CPU time of a container goes down or its start time changes, what it used
+//! before it restarted is carried over and added to what it reports from
+//! then on. The same goes for pods as a whole. Counters are forgotten once
+//! their pod is gone.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use super::{
+    add_up_containers, provider_pod_stats, CpuStats, MemoryStats, NodeStats, PodStats,
+    StatsCollector,
+};
+use crate::provider::Provider;
+
+/// The content type of the Prometheus text format
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A metric family: the name, help and type of a metric
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+}
+
+const NODE_CPU_USAGE: Family = Family {
+    name: "node_cpu_usage_seconds_total",
+    help: "Cumulative cpu time consumed by the node in core-seconds",
+    kind: "counter",
+};
+const NODE_MEMORY_WORKING_SET: Family = Family {
+    name: "node_memory_working_set_bytes",
+    help: "Current working set of the node in bytes",
+    kind: "gauge",
+};
+const POD_CPU_USAGE: Family = Family {
+    name: "pod_cpu_usage_seconds_total",
+    help: "Cumulative cpu time consumed by the pod in core-seconds",
+    kind: "counter",
+};
+const POD_MEMORY_WORKING_SET: Family = Family {
+    name: "pod_memory_working_set_bytes",
+    help: "Current working set of the pod in bytes",
+    kind: "gauge",
+};
+const CONTAINER_CPU_USAGE: Family = Family {
+    name: "container_cpu_usage_seconds_total",
+    help: "Cumulative cpu time consumed by the container in core-seconds",
+    kind: "counter",
+};
+const CONTAINER_MEMORY_WORKING_SET: Family = Family {
+    name: "container_memory_working_set_bytes",
+    help: "Current working set of the container in bytes",
+    kind: "gauge",
+};
+const CONTAINER_START_TIME: Family = Family {
+    name: "container_start_time_seconds",
+    help: "Start time of the container since unix epoch in seconds",
+    kind: "gauge",
+};
+const SCRAPE_ERROR: Family = Family {
+    name: "scrape_error",
+    help: "1 if there was an error while getting container metrics, 0 otherwise",
+    kind: "gauge",
+};
+
+/// A sample of a metric, with its labels and the time it was taken
+struct Sample<'a> {
+    labels: Vec<(&'static str, &'a str)>,
+    value: f64,
+    time: Option<DateTime<Utc>>,
+}
+
+impl StatsCollector {
+    /// Gathers the usage of the node, and of the pods the provider runs, in
+    /// the Prometheus text format
+    pub(crate) async fn resource_metrics<P: Provider>(&self, provider: &P) -> String {
+        let node = self.node_stats();
+        let (mut pods, scrape_error) = match provider_pod_stats(provider).await {
+            Ok(pods) => (pods, false),
+            Err(e) => {
+                warn!("Unable to get the stats of pods from the provider: {:?}", e);
+                (vec![], true)
+            }
+        };
+        self.counters
+            .lock()
+            .expect("stats collector lock should not be poisoned")
+            .count(&mut pods);
+        render(&node, &pods, scrape_error)
+    }
+}
+
+/// The CPU time something has used since it started, counted across its
+/// restarts
+#[derive(Clone, Copy, Debug, Default)]
+struct CpuCounter {
+    /// What it last reported
+    last: u64,
+    /// When it started, when it last reported
+    start_time: Option<DateTime<Utc>>,
+    /// What it used before it last restarted
+    carried_over: u64,
+}
+
+impl CpuCounter {
+    fn count(&mut self, usage: u64, start_time: Option<DateTime<Utc>>) -> u64 {
+        let restarted = usage < self.last
+            || matches!((self.start_time, start_time), (Some(before), Some(now)) if before != now);
+        if restarted {
+            self.carried_over += self.last;
+        }
+        self.last = usage;
+        self.start_time = start_time;
+        self.carried_over + usage
+    }
+}
+
+/// The CPU time counters of each pod and container, by pod UID
+#[derive(Default)]
+pub(crate) struct CpuCounters {
+    pods: HashMap<String, PodCounters>,
+}
+
+#[derive(Default)]
+struct PodCounters {
+    pod: CpuCounter,
+    containers: HashMap<String, CpuCounter>,
+}
+
+impl CpuCounters {
+    /// Replaces the CPU time of each pod and container with what it has used
+    /// across restarts, adding up what the containers of each pod use if
+    /// the provider didn't report what it uses as a whole
+    fn count(&mut self, pods: &mut [PodStats]) {
+        let uids: HashSet<String> = pods.iter().map(|pod| pod.pod_ref.uid.clone()).collect();
+        self.pods.retain(|uid, _| uids.contains(uid));
+        for pod in pods {
+            let counters = self.pods.entry(pod.pod_ref.uid.clone()).or_default();
+            for container in &mut pod.containers {
+                let start_time = container.start_time;
+                if let Some(usage) = usage_mut(container.cpu.as_mut()) {
+                    let counter = counters
+                        .containers
+                        .entry(container.name.clone())
+                        .or_default();
+                    *usage = counter.count(*usage, start_time);
+                }
+            }
+            add_up_containers(pod);
+            if let Some(usage) = usage_mut(pod.cpu.as_mut()) {
+                *usage = counters.pod.count(*usage, pod.start_time);
+            }
+        }
+    }
+}
+
+fn usage_mut(cpu: Option<&mut CpuStats>) -> Option<&mut u64> {
+    cpu.and_then(|cpu| cpu.usage_core_nano_seconds.as_mut())
+}
+
+/// Formats the usage of the node and its pods in the Prometheus text format
+fn render(node: &NodeStats, pods: &[PodStats], scrape_error: bool) -> String {
+    let containers = || {
+        pods.iter().flat_map(|pod| {
+            pod.containers.iter().map(move |container| {
+                let labels = vec![
+                    ("container", container.name.as_str()),
+                    ("namespace", pod.pod_ref.namespace.as_str()),
+                    ("pod", pod.pod_ref.name.as_str()),
+                ];
+                (container, labels)
+            })
+        })
+    };
+
+    let mut out = String::new();
+    write_family(
+        &mut out,
+        &CONTAINER_CPU_USAGE,
+        containers().filter_map(|(container, labels)| cpu_sample(labels, container.cpu.as_ref())),
+    );
+    write_family(
+        &mut out,
+        &CONTAINER_MEMORY_WORKING_SET,
+        containers()
+            .filter_map(|(container, labels)| memory_sample(labels, container.memory.as_ref())),
+    );
+    write_family(
+        &mut out,
+        &CONTAINER_START_TIME,
+        containers().filter_map(|(container, labels)| {
+            let start_time = container.start_time?;
+            Some(Sample {
+                labels,
+                value: start_time.timestamp_millis() as f64 / 1e3,
+                time: None,
+            })
+        }),
+    );
+    write_family(
+        &mut out,
+        &NODE_CPU_USAGE,
+        cpu_sample(vec![], node.cpu.as_ref()),
+    );
+    write_family(
+        &mut out,
+        &NODE_MEMORY_WORKING_SET,
+        memory_sample(vec![], node.memory.as_ref()),
+    );
+    write_family(
+        &mut out,
+        &POD_CPU_USAGE,
+        pods.iter()
+            .filter_map(|pod| cpu_sample(pod_labels(pod), pod.cpu.as_ref())),
+    );
+    write_family(
+        &mut out,
+        &POD_MEMORY_WORKING_SET,
+        pods.iter()
+            .filter_map(|pod| memory_sample(pod_labels(pod), pod.memory.as_ref())),
+    );
+    write_family(
+        &mut out,
+        &SCRAPE_ERROR,
+        Some(Sample {
+            labels: vec![],
+            value: if scrape_error { 1.0 } else { 0.0 },
+            time: None,
+        }),
+    );
+    out
+}
+
+fn pod_labels(pod: &PodStats) -> Vec<(&'static str, &str)> {
+    vec![
+        ("namespace", pod.pod_ref.namespace.as_str()),
+        ("pod", pod.pod_ref.name.as_str()),
+    ]
+}
+
+/// The CPU time used, in seconds
+fn cpu_sample<'a>(
+    labels: Vec<(&'static str, &'a str)>,
+    cpu: Option<&CpuStats>,
+) -> Option<Sample<'a>> {
+    let cpu = cpu?;
+    Some(Sample {
+        labels,
+        value: cpu.usage_core_nano_seconds? as f64 / 1e9,
+        time: Some(cpu.time),
+    })
+}
+
+fn memory_sample<'a>(
+    labels: Vec<(&'static str, &'a str)>,
+    memory: Option<&MemoryStats>,
+) -> Option<Sample<'a>> {
+    let memory = memory?;
+    Some(Sample {
+        labels,
+        value: memory.working_set_bytes? as f64,
+        time: Some(memory.time),
+    })
+}
+
+fn write_family<'a>(
+    out: &mut String,
+    family: &Family,
+    samples: impl IntoIterator<Item = Sample<'a>>,
+) {
+    // Writing to a string doesn't fail
+    let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+    let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+    for sample in samples {
+        out.push_str(family.name);
+        if !sample.labels.is_empty() {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+                .collect();
+            let _ = write!(out, "{{{}}}", labels.join(","));
+        }
+        let _ = write!(out, " {}", sample.value);
+        if let Some(time) = sample.time {
+            let _ = write!(out, " {}", time.timestamp_millis());
+        }
+        out.push('\n');
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::{ContainerStats, PodReference};
+    use chrono::TimeZone;
+    use prometheus_parse::{Scrape, Value};
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_600_000_000 + seconds, 0)
+    }
+
+    fn container(name: &str, started: i64, cpu_usage: u64) -> ContainerStats {
+        ContainerStats {
+            name: name.to_owned(),
+            start_time: Some(time(started)),
+            cpu: Some(CpuStats {
+                time: time(100),
+                usage_nano_cores: None,
+                usage_core_nano_seconds: Some(cpu_usage),
+            }),
+            memory: Some(MemoryStats {
+                time: time(100),
+                available_bytes: None,
+                usage_bytes: Some(2048),
+                working_set_bytes: Some(1024),
+            }),
+        }
+    }
+
+    fn pod(uid: &str, containers: Vec<ContainerStats>) -> PodStats {
+        PodStats {
+            pod_ref: PodReference {
+                name: "web".to_owned(),
+                namespace: "default".to_owned(),
+                uid: uid.to_owned(),
+            },
+            start_time: Some(time(0)),
+            containers,
+            cpu: None,
+            memory: None,
+            volume: vec![],
+            ephemeral_storage: None,
+        }
+    }
+
+    fn node() -> NodeStats {
+        NodeStats {
+            node_name: "krustlet".to_owned(),
+            start_time: None,
+            cpu: Some(CpuStats {
+                time: time(100),
+                usage_nano_cores: None,
+                usage_core_nano_seconds: Some(3_000_000_000),
+            }),
+            memory: None,
+            fs: None,
+            runtime: None,
+        }
+    }
+
+    fn scrape(metrics: &str) -> Scrape {
+        Scrape::parse(metrics.lines().map(|line| Ok(line.to_owned()))).unwrap()
+    }
+
+    fn cpu_usage(pods: &[PodStats], container: usize) -> Option<u64> {
+        pods[0].containers[container]
+            .cpu
+            .as_ref()
+            .and_then(|cpu| cpu.usage_core_nano_seconds)
+    }
+
+    #[test]
+    fn scrapes_have_the_families_metrics_server_reads() {
+        let mut pods = vec![pod(
+            "1234",
+            vec![
+                container("app", 0, 1_500_000_000),
+                container("sidecar", 0, 500_000_000),
+            ],
+        )];
+        CpuCounters::default().count(&mut pods);
+        let scrape = scrape(&render(&node(), &pods, false));
+
+        for family in &[
+            NODE_CPU_USAGE,
+            NODE_MEMORY_WORKING_SET,
+            POD_CPU_USAGE,
+            POD_MEMORY_WORKING_SET,
+            CONTAINER_CPU_USAGE,
+            CONTAINER_MEMORY_WORKING_SET,
+            CONTAINER_START_TIME,
+            SCRAPE_ERROR,
+        ] {
+            assert_eq!(
+                scrape.docs.get(family.name).map(String::as_str),
+                Some(family.help)
+            );
+        }
+
+        let samples = |name: &str| -> Vec<&prometheus_parse::Sample> {
+            scrape.samples.iter().filter(|s| s.metric == name).collect()
+        };
+
+        let container_cpu = samples(CONTAINER_CPU_USAGE.name);
+        assert_eq!(container_cpu.len(), 2);
+        assert_eq!(container_cpu[0].labels.get("container"), Some("app"));
+        assert_eq!(container_cpu[0].labels.get("pod"), Some("web"));
+        assert_eq!(container_cpu[0].labels.get("namespace"), Some("default"));
+        assert!(matches!(container_cpu[0].value, Value::Counter(v) if (v - 1.5).abs() < 1e-9));
+        assert_eq!(container_cpu[0].timestamp, time(100));
+
+        let container_memory = samples(CONTAINER_MEMORY_WORKING_SET.name);
+        assert!(matches!(container_memory[1].value, Value::Gauge(v) if v == 1024.0));
+
+        let start_time = samples(CONTAINER_START_TIME.name);
+        assert!(matches!(start_time[0].value, Value::Gauge(v) if v == 1_600_000_000.0));
+
+        let pod_cpu = samples(POD_CPU_USAGE.name);
+        assert_eq!(pod_cpu.len(), 1);
+        assert_eq!(pod_cpu[0].labels.get("container"), None);
+        assert!(matches!(pod_cpu[0].value, Value::Counter(v) if (v - 2.0).abs() < 1e-9));
+        let pod_memory = samples(POD_MEMORY_WORKING_SET.name);
+        assert!(matches!(pod_memory[0].value, Value::Gauge(v) if v == 2048.0));
+
+        let node_cpu = samples(NODE_CPU_USAGE.name);
+        assert!(matches!(node_cpu[0].value, Value::Counter(v) if (v - 3.0).abs() < 1e-9));
+        // The node's memory isn't known
+        assert!(samples(NODE_MEMORY_WORKING_SET.name).is_empty());
+
+        let scrape_error = samples(SCRAPE_ERROR.name);
+        assert!(matches!(scrape_error[0].value, Value::Gauge(v) if v == 0.0));
+    }
+
+    #[test]
+    fn failing_to_get_the_stats_of_pods_is_a_scrape_error() {
+        let scrape = scrape(&render(&node(), &[], true));
+        let scrape_error = scrape
+            .samples
+            .iter()
+            .find(|s| s.metric == SCRAPE_ERROR.name)
+            .unwrap();
+        assert!(matches!(scrape_error.value, Value::Gauge(v) if v == 1.0));
+    }
+
+    #[test]
+    fn cpu_time_is_counted_across_restarts() {
+        let mut counters = CpuCounters::default();
+
+        let mut pods = vec![pod("1234", vec![container("app", 0, 5)])];
+        counters.count(&mut pods);
+        assert_eq!(cpu_usage(&pods, 0), Some(5));
+
+        // The container restarted and counts from zero again
+        let mut pods = vec![pod("1234", vec![container("app", 50, 3)])];
+        counters.count(&mut pods);
+        assert_eq!(cpu_usage(&pods, 0), Some(8));
+
+        // It restarted again, and has already used more than before
+        let mut pods = vec![pod("1234", vec![container("app", 60, 4)])];
+        counters.count(&mut pods);
+        assert_eq!(cpu_usage(&pods, 0), Some(12));
+
+        let mut pods = vec![pod("1234", vec![container("app", 60, 10)])];
+        counters.count(&mut pods);
+        assert_eq!(cpu_usage(&pods, 0), Some(18));
+        assert_eq!(
+            pods[0].cpu.as_ref().unwrap().usage_core_nano_seconds,
+            Some(18)
+        );
+    }
+
+    #[test]
+    fn counters_of_pods_that_are_gone_are_forgotten() {
+        let mut counters = CpuCounters::default();
+        let mut pods = vec![pod("1234", vec![container("app", 0, 5)])];
+        counters.count(&mut pods);
+        counters.count(&mut []);
+
+        let mut pods = vec![pod("1234", vec![container("app", 50, 3)])];
+        counters.count(&mut pods);
+        assert_eq!(cpu_usage(&pods, 0), Some(3));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape_label_value(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape_label_value("a\nb"), r"a\nb");
+    }
+}
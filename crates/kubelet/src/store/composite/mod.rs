@@ -1,8 +1,9 @@
 //! `composite` implements building complex stores from simpler ones.
 
 use crate::store::PullPolicy;
-use crate::store::Store;
+use crate::store::{ImageDigests, PullOutcome, Store, StoredImage};
 use async_trait::async_trait;
+use oci_distribution::client::PullProgress;
 use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
 use std::sync::Arc;
@@ -73,6 +74,38 @@ impl Store for CompositeStore {
             self.base.get(image_ref, pull_policy, auth).await
         }
     }
+
+    async fn get_with_progress(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<(Vec<u8>, PullOutcome)> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor
+                .get_with_progress(image_ref, pull_policy, auth, progress)
+                .await
+        } else {
+            self.base
+                .get_with_progress(image_ref, pull_policy, auth, progress)
+                .await
+        }
+    }
+
+    async fn image_digests(&self, image_ref: &Reference) -> anyhow::Result<Option<ImageDigests>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.image_digests(image_ref).await
+        } else {
+            self.base.image_digests(image_ref).await
+        }
+    }
+
+    async fn images(&self) -> anyhow::Result<Vec<StoredImage>> {
+        // Intercepted images aren't held by a store, as they are read from
+        // elsewhere each time
+        self.base.images().await
+    }
 }
 
 #[cfg(test)]
@@ -2,8 +2,10 @@
 pub mod composite;
 pub mod fs;
 pub mod oci;
+pub mod verification;
 
-use oci_distribution::client::ImageData;
+use oci_distribution::client::{ImageData, PullProgress};
+use oci_distribution::manifest::OciManifest;
 use oci_distribution::secrets::RegistryAuth;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -59,6 +61,40 @@ pub trait Store: Sync {
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>>;
 
+    /// Get a module's data like `get`, calling `progress` if the module is
+    /// pulled from a registry. Also returns how the module was obtained.
+    ///
+    /// The default implementation calls `get`, and does not report progress
+    /// or how the module was obtained.
+    async fn get_with_progress(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        _progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<(Vec<u8>, PullOutcome)> {
+        let module = self.get(image_ref, pull_policy, auth).await?;
+        Ok((module, PullOutcome::Unknown))
+    }
+
+    /// Get the digests of the image a module was fetched from, given its
+    /// image `Reference`. This must be called after the module has been
+    /// fetched with `get`.
+    ///
+    /// Returns `None` if the store does not know the digests, which is the
+    /// default.
+    async fn image_digests(&self, _image_ref: &Reference) -> anyhow::Result<Option<ImageDigests>> {
+        Ok(None)
+    }
+
+    /// List the images held by the store, as reported in the node's status.
+    ///
+    /// Returns an empty list if the store does not keep images locally,
+    /// which is the default.
+    async fn images(&self) -> anyhow::Result<Vec<StoredImage>> {
+        Ok(vec![])
+    }
+
     /// Fetch all container modules for a given `Pod` storing the name of the
     /// container and the module's data as key/value pairs in a hashmap.
     ///
@@ -104,6 +140,52 @@ pub trait Store: Sync {
     }
 }
 
+/// How a module was obtained by `Store::get_with_progress`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PullOutcome {
+    /// The module was pulled from a registry. Contains the number of bytes
+    /// downloaded
+    Pulled(u64),
+    /// The module was already present in the store
+    AlreadyPresent,
+    /// The store does not report how the module was obtained
+    Unknown,
+}
+
+/// The digests identifying the content of an image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageDigests {
+    /// The digest of the image manifest
+    pub manifest: String,
+    /// The digest of the image config
+    pub config: String,
+    /// The digests of the image layers, in the order they appear in the
+    /// manifest
+    pub layers: Vec<String>,
+}
+
+impl ImageDigests {
+    /// Creates the digests for an image from its manifest and the digest of
+    /// that manifest
+    pub fn from_manifest(manifest_digest: &str, manifest: &OciManifest) -> Self {
+        ImageDigests {
+            manifest: manifest_digest.to_owned(),
+            config: manifest.config.digest.clone(),
+            layers: manifest.layers.iter().map(|l| l.digest.clone()).collect(),
+        }
+    }
+}
+
+/// An image held by a store
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredImage {
+    /// The references the image is known by. Images stored with a digest
+    /// are also known by their repository and digest.
+    pub names: Vec<String>,
+    /// The size of the image's module, in bytes
+    pub size_bytes: u64,
+}
+
 /// A `Store` implementation which obtains module data from remote registries
 /// but caches it in local storage.
 pub struct LocalStore<S: Storer, C: Client> {
@@ -112,15 +194,54 @@ pub struct LocalStore<S: Storer, C: Client> {
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
-    async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+    /// Imports all of the images in an OCI image layout directory, or in a
+    /// tarball of an OCI image layout or `docker save` archive, into the
+    /// store.
+    ///
+    /// Imported images are stored exactly as if they had been pulled, so pods
+    /// using them with an `IfNotPresent` or `Never` pull policy can start
+    /// without access to a registry. Returns the references of the imported
+    /// images.
+    pub async fn import_oci_layout<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> anyhow::Result<Vec<Reference>> {
+        let path = path.as_ref().to_owned();
+        let images = tokio::task::spawn_blocking(move || oci::layout::read_images(&path)).await??;
+        let mut storer = self.storer.write().await;
+        let mut references = Vec::with_capacity(images.len());
+        for (image_ref, image_data) in images {
+            debug!("Importing image ref '{:?}' into the store", image_ref);
+            storer.store(&image_ref, image_data).await?;
+            references.push(image_ref);
+        }
+        Ok(references)
+    }
+}
+
+impl<S: Storer, C: Client + Send> LocalStore<S, C> {
+    /// Pulls an image into the store, returning the number of bytes
+    /// downloaded
+    async fn pull(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<u64> {
         debug!("Pulling image ref '{:?}' from registry", image_ref);
-        let image_data = self.client.lock().await.pull(image_ref, auth).await?;
+        let image_data = self
+            .client
+            .lock()
+            .await
+            .pull_with_progress(image_ref, auth, progress)
+            .await?;
+        let size = image_data.layers.iter().map(|l| l.data.len() as u64).sum();
         self.storer
             .write()
             .await
             .store(image_ref, image_data)
             .await?;
-        Ok(())
+        Ok(size)
     }
 }
 
@@ -132,10 +253,25 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
         pull_policy: PullPolicy,
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>> {
-        match pull_policy {
+        let (module, _) = self
+            .get_with_progress(image_ref, pull_policy, auth, &|_| ())
+            .await?;
+        Ok(module)
+    }
+
+    async fn get_with_progress(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<(Vec<u8>, PullOutcome)> {
+        let outcome = match pull_policy {
             PullPolicy::IfNotPresent => {
                 if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
+                    PullOutcome::Pulled(self.pull(image_ref, auth, progress).await?)
+                } else {
+                    PullOutcome::AlreadyPresent
                 }
             }
             PullPolicy::Always => {
@@ -152,13 +288,24 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
                     .is_present_with_digest(image_ref, digest)
                     .await;
                 if !already_got_with_digest {
-                    self.pull(image_ref, auth).await?
+                    PullOutcome::Pulled(self.pull(image_ref, auth, progress).await?)
+                } else {
+                    PullOutcome::AlreadyPresent
                 }
             }
-            PullPolicy::Never => (),
+            PullPolicy::Never => PullOutcome::AlreadyPresent,
         };
 
-        self.storer.read().await.get_local(image_ref).await
+        let module = self.storer.read().await.get_local(image_ref).await?;
+        Ok((module, outcome))
+    }
+
+    async fn image_digests(&self, image_ref: &Reference) -> anyhow::Result<Option<ImageDigests>> {
+        self.storer.read().await.get_local_digests(image_ref).await
+    }
+
+    async fn images(&self) -> anyhow::Result<Vec<StoredImage>> {
+        self.storer.read().await.list_local().await
     }
 }
 
@@ -176,6 +323,26 @@ pub trait Storer {
     /// remote fetch is handled at the `Store` level.
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>>;
 
+    /// Get the digests of a module's image from the backing store given its
+    /// image `Reference`.
+    ///
+    /// Returns `None` if the image was stored without its digest or manifest,
+    /// or if the backing store does not record them, which is the default.
+    async fn get_local_digests(
+        &self,
+        _image_ref: &Reference,
+    ) -> anyhow::Result<Option<ImageDigests>> {
+        Ok(None)
+    }
+
+    /// List the images in the backing store, with the same content under
+    /// different references listed once with all of their names.
+    ///
+    /// Returns an empty list by default.
+    async fn list_local(&self) -> anyhow::Result<Vec<StoredImage>> {
+        Ok(vec![])
+    }
+
     /// Whether the specified module is already present in the backing store.
     async fn is_present(&self, image_ref: &Reference) -> bool;
 
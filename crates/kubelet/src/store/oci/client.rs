@@ -1,7 +1,6 @@
 //! Client for fetching container modules from OCI
 use async_trait::async_trait;
-use oci_distribution::client::ImageData;
-use oci_distribution::manifest;
+use oci_distribution::client::{ImageData, PullProgress};
 use oci_distribution::secrets::RegistryAuth;
 
 use oci_distribution::Reference;
@@ -39,6 +38,19 @@ pub trait Client {
         auth: &RegistryAuth,
     ) -> anyhow::Result<ImageData>;
 
+    /// Fetch the image data like `pull`, calling `progress` as the image is
+    /// downloaded.
+    ///
+    /// The default implementation calls `pull` and does not report progress.
+    async fn pull_with_progress(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        _progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<ImageData> {
+        self.pull(image_ref, auth).await
+    }
+
     /// Fetch the digest for the given image reference from a storage location.
     ///
     /// The default implementation pulls the image data and digest, and returns
@@ -59,8 +71,29 @@ pub trait Client {
 #[async_trait]
 impl Client for oci_distribution::Client {
     async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
-        self.pull(image, auth, vec![manifest::WASM_LAYER_MEDIA_TYPE])
-            .await
+        let accepted_media_types = self.accepted_media_types();
+        self.pull(
+            image,
+            auth,
+            accepted_media_types.iter().map(|t| t.as_str()).collect(),
+        )
+        .await
+    }
+
+    async fn pull_with_progress(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
+    ) -> anyhow::Result<ImageData> {
+        let accepted_media_types = self.accepted_media_types();
+        self.pull_with_progress(
+            image,
+            auth,
+            accepted_media_types.iter().map(|t| t.as_str()).collect(),
+            progress,
+        )
+        .await
     }
 
     async fn fetch_digest(
@@ -1,5 +1,6 @@
-use crate::store::Storer;
+use crate::store::{ImageDigests, StoredImage, Storer};
 use oci_distribution::client::ImageData;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -57,6 +58,69 @@ impl FileStorer {
     fn digest_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("digest.txt")
     }
+
+    fn manifest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("manifest.json")
+    }
+}
+
+/// Finds the modules under `root_dir`, grouping those with the same digest
+/// into one image. Modules are stored at `registry/repository/tag`, so the
+/// reference of each is the path of its directory relative to `root_dir`.
+fn list_images(root_dir: &Path) -> anyhow::Result<Vec<StoredImage>> {
+    let mut by_digest: BTreeMap<String, StoredImage> = BTreeMap::new();
+    let mut undigested = vec![];
+    let mut dirs = vec![root_dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+        let module_path = dir.join("module.wasm");
+        if !module_path.is_file() {
+            continue;
+        }
+        let components: Vec<_> = match dir.strip_prefix(root_dir) {
+            Ok(relative) => relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => continue,
+        };
+        // At least one repository path segment, and a tag
+        if components.len() < 2 {
+            continue;
+        }
+        let (tag, repository) = components.split_last().unwrap();
+        let repository = repository.join("/");
+        let size_bytes = std::fs::metadata(&module_path)?.len();
+        let name = format!("{}:{}", repository, tag);
+        match std::fs::read_to_string(dir.join("digest.txt")) {
+            Ok(digest) => {
+                let image = by_digest.entry(digest.clone()).or_insert(StoredImage {
+                    names: vec![],
+                    size_bytes,
+                });
+                let digest_name = format!("{}@{}", repository, digest);
+                if !image.names.contains(&digest_name) {
+                    image.names.push(digest_name);
+                }
+                image.names.push(name);
+            }
+            Err(_) => undigested.push(StoredImage {
+                names: vec![name],
+                size_bytes,
+            }),
+        }
+    }
+    let mut images: Vec<_> = by_digest.into_values().collect();
+    images.extend(undigested);
+    for image in images.iter_mut() {
+        image.names.sort();
+    }
+    Ok(images)
 }
 
 #[async_trait]
@@ -84,18 +148,57 @@ impl Storer for FileStorer {
         if digest_path.exists() {
             tokio::fs::remove_file(&digest_path).await?;
         }
-        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
+        let manifest_path = self.manifest_file_path(image_ref);
+        if manifest_path.exists() {
+            tokio::fs::remove_file(&manifest_path).await?;
+        }
         let module_path = self.pull_file_path(image_ref);
         if image_data.layers.is_empty() {
             return Err(anyhow::anyhow!("No module layer present in image data"));
         }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
+        let module = super::layer::module_data(&image_data)?;
+        tokio::fs::write(&module_path, &module).await?;
+        // The manifest is stored as the registry served it, so that it still
+        // matches its digest
+        if let Some(m) = image_data.raw_manifest {
+            tokio::fs::write(&manifest_path, m).await?;
+        }
         if let Some(d) = image_data.digest {
             tokio::fs::write(&digest_path, d).await?;
         }
         Ok(())
     }
 
+    async fn get_local_digests(
+        &self,
+        image_ref: &Reference,
+    ) -> anyhow::Result<Option<ImageDigests>> {
+        let digest_path = self.digest_file_path(image_ref);
+        let manifest_path = self.manifest_file_path(image_ref);
+        if !digest_path.exists() || !manifest_path.exists() {
+            return Ok(None);
+        }
+        let digest = tokio::fs::read_to_string(digest_path).await?;
+        let raw_manifest = tokio::fs::read(manifest_path).await?;
+        super::layout::verify_digest(&raw_manifest, &digest).map_err(|e| {
+            anyhow::anyhow!(
+                "stored manifest for {} does not match its digest: {}",
+                image_ref,
+                e
+            )
+        })?;
+        let manifest = serde_json::from_slice(&raw_manifest)?;
+        Ok(Some(ImageDigests::from_manifest(&digest, &manifest)))
+    }
+
+    async fn list_local(&self) -> anyhow::Result<Vec<StoredImage>> {
+        if !self.root_dir.exists() {
+            return Ok(vec![]);
+        }
+        let root_dir = self.root_dir.clone();
+        tokio::task::spawn_blocking(move || list_images(&root_dir)).await?
+    }
+
     async fn is_present(&self, image_ref: &Reference) -> bool {
         let path = self.pull_file_path(image_ref);
         path.exists()
@@ -130,9 +233,11 @@ async fn file_content_is(path: PathBuf, text: String) -> bool {
 mod test {
     use super::*;
     use crate::container::PullPolicy;
-    use crate::store::Store;
+    use crate::store::{PullOutcome, Store};
     use oci_distribution::client::{ImageData, ImageLayer};
+    use oci_distribution::manifest;
     use oci_distribution::secrets::RegistryAuth;
+    use sha2::Digest;
     use std::collections::HashMap;
     use std::convert::TryFrom;
     use std::sync::RwLock;
@@ -176,8 +281,13 @@ mod test {
                 images.insert(
                     name.to_owned(),
                     ImageData {
-                        layers: vec![ImageLayer::oci_v1(content)],
+                        layers: vec![ImageLayer::new(
+                            content,
+                            manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+                        )],
                         digest: Some(digest.to_owned()),
+                        manifest: None,
+                        raw_manifest: None,
                     },
                 );
             }
@@ -192,8 +302,13 @@ mod test {
             images.insert(
                 key.to_owned(),
                 ImageData {
-                    layers: vec![ImageLayer::oci_v1(content)],
+                    layers: vec![ImageLayer::new(
+                        content,
+                        manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+                    )],
                     digest: Some(digest.to_owned()),
+                    manifest: None,
+                    raw_manifest: None,
                 },
             );
         }
@@ -354,6 +469,75 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn file_module_store_reports_whether_modules_were_pulled() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        let (module_bytes, outcome) = store
+            .get_with_progress(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                &|_| (),
+            )
+            .await?;
+        assert_eq!(3, module_bytes.len());
+        assert_eq!(PullOutcome::Pulled(3), outcome);
+        let (_, outcome) = store
+            .get_with_progress(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                &|_| (),
+            )
+            .await?;
+        assert_eq!(PullOutcome::AlreadyPresent, outcome);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_lists_images_by_digest() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/bar:stable", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz/qux:2.0", vec![4, 5], "sha256:45"),
+        ]);
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        assert_eq!(store.images().await?, vec![]);
+        for name in &["foo/bar:1.0", "foo/bar:stable", "foo/baz/qux:2.0"] {
+            let image_ref = Reference::try_from(*name)?;
+            store
+                .get(&image_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+                .await?;
+        }
+        let mut images = store.images().await?;
+        images.sort_by_key(|image| image.size_bytes);
+        assert_eq!(
+            images,
+            vec![
+                StoredImage {
+                    names: vec![
+                        "foo/baz/qux:2.0".to_owned(),
+                        "foo/baz/qux@sha256:45".to_owned(),
+                    ],
+                    size_bytes: 2,
+                },
+                StoredImage {
+                    names: vec![
+                        "foo/bar:1.0".to_owned(),
+                        "foo/bar:stable".to_owned(),
+                        "foo/bar@sha256:123".to_owned(),
+                    ],
+                    size_bytes: 3,
+                },
+            ]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_copes_with_no_tag() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar", vec![2, 3], "sha256:23")]);
@@ -434,4 +618,48 @@ mod test {
         assert_eq!(6, module_bytes_after[1]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn file_module_store_keeps_manifest_as_served() -> anyhow::Result<()> {
+        // Formatted differently from how serde would write it back out
+        let raw_manifest = br#"{
+  "schemaVersion": 2,
+  "config": { "mediaType": "application/vnd.wasm.config.v1+json", "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a", "size": 2 },
+  "layers": [ { "mediaType": "application/vnd.wasm.content.layer.v1+wasm", "digest": "sha256:039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81", "size": 3 } ]
+}"#
+        .to_vec();
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(&raw_manifest));
+        let image_data = ImageData {
+            layers: vec![ImageLayer::new(
+                vec![1, 2, 3],
+                manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            digest: Some(digest.clone()),
+            manifest: Some(serde_json::from_slice(&raw_manifest)?),
+            raw_manifest: Some(raw_manifest.clone()),
+        };
+        let image_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let mut storer = FileStorer {
+            root_dir: scratch_dir.path.clone(),
+        };
+        storer.store(&image_ref, image_data).await?;
+
+        let manifest_path = storer.manifest_file_path(&image_ref);
+        assert_eq!(raw_manifest, tokio::fs::read(&manifest_path).await?);
+        let digests = storer
+            .get_local_digests(&image_ref)
+            .await?
+            .expect("digests should be stored");
+        assert_eq!(digest, digests.manifest);
+        assert_eq!(
+            vec!["sha256:039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81"],
+            digests.layers
+        );
+
+        // A manifest that no longer matches its digest is not trusted
+        tokio::fs::write(&manifest_path, &raw_manifest[1..]).await?;
+        assert!(storer.get_local_digests(&image_ref).await.is_err());
+        Ok(())
+    }
 }
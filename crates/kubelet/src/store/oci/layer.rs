@@ -0,0 +1,198 @@
+//! Selection of the layer that holds a module from the layers of an image
+use std::io::Read;
+
+use oci_distribution::client::{ImageData, ImageLayer};
+use oci_distribution::manifest;
+
+/// The extension of module files inside tar layers
+const WASM_FILE_EXTENSION: &str = "wasm";
+
+/// Returns the module contained in the given image.
+///
+/// A layer with the wasm layer media type is always preferred, followed by one
+/// with the wasm text layer media type, whose module is left in the text
+/// format for the provider to compile. Images without either, such as those
+/// built with Docker tooling, are searched for a tar layer that contains a
+/// single `.wasm` file.
+pub(crate) fn module_data(image_data: &ImageData) -> anyhow::Result<Vec<u8>> {
+    for media_type in &[
+        manifest::WASM_LAYER_MEDIA_TYPE,
+        manifest::WASM_TEXT_LAYER_MEDIA_TYPE,
+    ] {
+        if let Some(layer) = image_data
+            .layers
+            .iter()
+            .find(|l| l.media_type == *media_type)
+        {
+            return Ok(layer.data.clone());
+        }
+    }
+
+    for layer in &image_data.layers {
+        if let Some(module) = module_from_tar_layer(layer)? {
+            return Ok(module);
+        }
+    }
+
+    let media_types: Vec<&str> = image_data
+        .layers
+        .iter()
+        .map(|l| l.media_type.as_str())
+        .collect();
+    Err(anyhow::anyhow!(
+        "image does not contain a module: expected a layer with media type {}, or a tar layer containing a .{} file, but found layers with media types [{}]",
+        manifest::WASM_LAYER_MEDIA_TYPE,
+        WASM_FILE_EXTENSION,
+        media_types.join(", ")
+    ))
+}
+
+/// Returns the `.wasm` file in the given layer, or `None` if the layer is not
+/// a tar layer or does not contain one.
+fn module_from_tar_layer(layer: &ImageLayer) -> anyhow::Result<Option<Vec<u8>>> {
+    match layer.media_type.as_str() {
+        manifest::IMAGE_LAYER_MEDIA_TYPE | manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE => {
+            wasm_file_in_tar(layer.data.as_slice())
+        }
+        manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE | manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE => {
+            wasm_file_in_tar(flate2::read::GzDecoder::new(layer.data.as_slice()))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn wasm_file_in_tar<R: Read>(reader: R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut modules = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if path.extension().and_then(|e| e.to_str()) != Some(WASM_FILE_EXTENSION) {
+            continue;
+        }
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        modules.push((path, data));
+    }
+
+    match modules.len() {
+        0 => Ok(None),
+        1 => Ok(modules.pop().map(|(_, data)| data)),
+        _ => Err(anyhow::anyhow!(
+            "image layer contains more than one module, so the module to run is ambiguous: {}",
+            modules
+                .iter()
+                .map(|(path, _)| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn tar_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, *data)
+                .expect("should be able to append to tar");
+        }
+        builder.into_inner().expect("should be able to finish tar")
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).expect("should be able to gzip");
+        encoder.finish().expect("should be able to finish gzip")
+    }
+
+    fn image(layers: Vec<ImageLayer>) -> ImageData {
+        ImageData {
+            layers,
+            digest: None,
+            manifest: None,
+            raw_manifest: None,
+        }
+    }
+
+    #[test]
+    fn artifact_layers_are_used_as_is() {
+        let image = image(vec![ImageLayer::new(
+            MODULE.to_vec(),
+            manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+        )]);
+        assert_eq!(MODULE, module_data(&image).unwrap().as_slice());
+    }
+
+    #[test]
+    fn text_artifact_layers_are_used_as_is() {
+        let text = b"(module)";
+        let image = image(vec![
+            ImageLayer::oci_v1(tar_with(&[("app/other.wasm", b"other")])),
+            ImageLayer::new(
+                text.to_vec(),
+                manifest::WASM_TEXT_LAYER_MEDIA_TYPE.to_owned(),
+            ),
+        ]);
+        assert_eq!(text, module_data(&image).unwrap().as_slice());
+    }
+
+    #[test]
+    fn artifact_layers_are_preferred_over_tar_layers() {
+        let image = image(vec![
+            ImageLayer::oci_v1(tar_with(&[("app/other.wasm", b"other")])),
+            ImageLayer::new(MODULE.to_vec(), manifest::WASM_LAYER_MEDIA_TYPE.to_owned()),
+        ]);
+        assert_eq!(MODULE, module_data(&image).unwrap().as_slice());
+    }
+
+    #[test]
+    fn modules_are_found_in_tar_layers() {
+        let tar = tar_with(&[("README.md", b"docs"), ("app/module.wasm", MODULE)]);
+        let image = image(vec![ImageLayer::new(
+            tar,
+            manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE.to_owned(),
+        )]);
+        assert_eq!(MODULE, module_data(&image).unwrap().as_slice());
+    }
+
+    #[test]
+    fn modules_are_found_in_gzipped_tar_layers() {
+        let tar = tar_with(&[("module.wasm", MODULE)]);
+        let image = image(vec![
+            ImageLayer::oci_v1_gzip(gzip(&tar_with(&[("etc/config", b"config")]))),
+            ImageLayer::oci_v1_gzip(gzip(&tar)),
+        ]);
+        assert_eq!(MODULE, module_data(&image).unwrap().as_slice());
+    }
+
+    #[test]
+    fn tar_layers_with_several_modules_are_rejected() {
+        let tar = tar_with(&[("a.wasm", MODULE), ("b.wasm", MODULE)]);
+        let err = module_data(&image(vec![ImageLayer::oci_v1(tar)])).unwrap_err();
+        assert!(err.to_string().contains("more than one module"));
+    }
+
+    #[test]
+    fn images_without_modules_are_rejected() {
+        let tar = tar_with(&[("README.md", b"docs")]);
+        let err = module_data(&image(vec![ImageLayer::oci_v1(tar)])).unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "but found layers with media types [{}]",
+            manifest::IMAGE_LAYER_MEDIA_TYPE
+        )));
+    }
+}
@@ -0,0 +1,458 @@
+//! Reading images from OCI image layouts and image tarballs
+//!
+//! This supports directories in the [OCI image layout] format, `docker save`
+//! tarballs, and tarballs (optionally gzipped) of either of these.
+//!
+//! [OCI image layout]: https://github.com/opencontainers/image-spec/blob/master/image-layout.md
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use oci_distribution::client::{ImageData, ImageLayer};
+use oci_distribution::manifest::{self, OciDescriptor, OciImageIndex, OciManifest};
+use oci_distribution::Reference;
+use sha2::Digest;
+use tracing::debug;
+
+/// The annotation containerd, nerdctl and `docker save` use for the full
+/// reference of an image in a layout
+const CONTAINERD_IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+/// The annotation the image spec defines for the name of an image in a layout
+const OCI_REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// The first bytes of a gzipped file
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// An entry in the `manifest.json` of a `docker save` tarball
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerSaveManifest {
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Reads all of the images in the layout or tarball at `path`, returning
+/// each image with the reference it was saved under.
+///
+/// The digest of every blob in an OCI layout is verified. The images returned
+/// have the digest of their manifest, just as images pulled from a registry.
+///
+/// This does blocking I/O.
+pub(crate) fn read_images(path: &Path) -> anyhow::Result<Vec<(Reference, ImageData)>> {
+    if path.is_dir() {
+        return read_layout_dir(path);
+    }
+
+    let mut file = std::fs::File::open(path)?;
+    let mut magic = [0; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    let file = std::fs::File::open(path)?;
+    let extracted = tempdir::TempDir::new("krustlet-import")?;
+    debug!(
+        "Extracting {} to {}",
+        path.display(),
+        extracted.path().display()
+    );
+    if is_gzip {
+        tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(extracted.path())?;
+    } else {
+        tar::Archive::new(file).unpack(extracted.path())?;
+    }
+    read_layout_dir(extracted.path())
+}
+
+fn read_layout_dir(dir: &Path) -> anyhow::Result<Vec<(Reference, ImageData)>> {
+    if dir.join("index.json").exists() {
+        read_oci_layout(dir)
+    } else if dir.join("manifest.json").exists() {
+        read_docker_save(dir)
+    } else {
+        Err(anyhow::anyhow!(
+            "{} is not an OCI image layout or docker save archive: it has no index.json or manifest.json",
+            dir.display()
+        ))
+    }
+}
+
+fn read_oci_layout(dir: &Path) -> anyhow::Result<Vec<(Reference, ImageData)>> {
+    let index: OciImageIndex = serde_json::from_slice(&std::fs::read(dir.join("index.json"))?)
+        .map_err(|e| anyhow::anyhow!("unable to parse index.json: {}", e))?;
+    index
+        .manifests
+        .iter()
+        .map(|descriptor| {
+            let annotations = descriptor.annotations.clone().unwrap_or_default();
+            let name = annotations
+                .get(CONTAINERD_IMAGE_NAME_ANNOTATION)
+                .or_else(|| annotations.get(OCI_REF_NAME_ANNOTATION))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "manifest {} in the image layout has no {} or {} annotation naming the image",
+                        descriptor.digest,
+                        CONTAINERD_IMAGE_NAME_ANNOTATION,
+                        OCI_REF_NAME_ANNOTATION
+                    )
+                })?;
+            // The image spec allows the ref name to be just a tag, which
+            // doesn't tell us which repository the image belongs to
+            if !name.contains('/') && !name.contains(':') && !name.contains('@') {
+                return Err(anyhow::anyhow!(
+                    "manifest {} in the image layout is named {}, which is not a full image reference",
+                    descriptor.digest,
+                    name
+                ));
+            }
+            let reference = Reference::try_from(name.as_str())
+                .map_err(|e| anyhow::anyhow!("invalid image reference {}: {}", name, e))?;
+            let image_data =
+                read_oci_image(dir, &descriptor.media_type, &descriptor.digest, descriptor.size)?;
+            Ok((reference, image_data))
+        })
+        .collect()
+}
+
+/// Reads the image with the given manifest or image index from the layout.
+/// For an image index, the first manifest containing a module is used.
+fn read_oci_image(
+    dir: &Path,
+    media_type: &str,
+    digest: &str,
+    size: i64,
+) -> anyhow::Result<ImageData> {
+    let blob = read_blob(dir, digest, size)?;
+    if media_type == manifest::OCI_IMAGE_INDEX_MEDIA_TYPE
+        || media_type == manifest::IMAGE_MANIFEST_LIST_MEDIA_TYPE
+    {
+        let index: OciImageIndex = serde_json::from_slice(&blob)
+            .map_err(|e| anyhow::anyhow!("unable to parse image index {}: {}", digest, e))?;
+        let mut errors = vec![];
+        for entry in &index.manifests {
+            match read_oci_image(dir, &entry.media_type, &entry.digest, entry.size)
+                .and_then(|image_data| super::layer::module_data(&image_data).map(|_| image_data))
+            {
+                Ok(image_data) => return Ok(image_data),
+                Err(e) => errors.push(format!("{}: {}", entry.digest, e)),
+            }
+        }
+        return Err(anyhow::anyhow!(
+            "no manifest in image index {} contains a module: [{}]",
+            digest,
+            errors.join("; ")
+        ));
+    }
+
+    let manifest: OciManifest = serde_json::from_slice(&blob)
+        .map_err(|e| anyhow::anyhow!("unable to parse image manifest {}: {}", digest, e))?;
+    let layers = manifest
+        .layers
+        .iter()
+        .map(|layer: &OciDescriptor| {
+            Ok(ImageLayer::new(
+                read_blob(dir, &layer.digest, layer.size)?,
+                layer.media_type.clone(),
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ImageData {
+        layers,
+        digest: Some(digest.to_owned()),
+        manifest: Some(manifest),
+        raw_manifest: Some(blob),
+    })
+}
+
+/// Reads a blob from the layout, verifying its size and digest
+fn read_blob(dir: &Path, digest: &str, size: i64) -> anyhow::Result<Vec<u8>> {
+    let path = blob_path(dir, digest)?;
+    let data = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("unable to read blob {}: {}", digest, e))?;
+    if data.len() as i64 != size {
+        return Err(anyhow::anyhow!(
+            "blob {} is {} bytes, but its descriptor has a size of {} bytes",
+            digest,
+            data.len(),
+            size
+        ));
+    }
+    verify_digest(&data, digest)?;
+    Ok(data)
+}
+
+fn blob_path(dir: &Path, digest: &str) -> anyhow::Result<PathBuf> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("invalid digest {}", digest))?;
+    // The digest is used as a path, so make sure it can't escape the layout
+    if !algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+        || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(anyhow::anyhow!("invalid digest {}", digest));
+    }
+    Ok(dir.join("blobs").join(algorithm).join(hex))
+}
+
+/// Checks that `data` has the given digest
+pub(super) fn verify_digest(data: &[u8], digest: &str) -> anyhow::Result<()> {
+    let actual = match digest.split_once(':') {
+        Some(("sha256", _)) => format!("sha256:{:x}", sha2::Sha256::digest(data)),
+        Some(("sha512", _)) => format!("sha512:{:x}", sha2::Sha512::digest(data)),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "unsupported digest algorithm in {}",
+                digest
+            ))
+        }
+    };
+    if actual != digest {
+        return Err(anyhow::anyhow!(
+            "blob digest mismatch: expected {}, got {}",
+            digest,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+fn read_docker_save(dir: &Path) -> anyhow::Result<Vec<(Reference, ImageData)>> {
+    let manifests: Vec<DockerSaveManifest> =
+        serde_json::from_slice(&std::fs::read(dir.join("manifest.json"))?)
+            .map_err(|e| anyhow::anyhow!("unable to parse manifest.json: {}", e))?;
+    let mut images = vec![];
+    for manifest in manifests {
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| {
+                let path = Path::new(layer);
+                if path.is_absolute()
+                    || path
+                        .components()
+                        .any(|c| c == std::path::Component::ParentDir)
+                {
+                    return Err(anyhow::anyhow!("invalid layer path {}", layer));
+                }
+                let data = std::fs::read(dir.join(path))
+                    .map_err(|e| anyhow::anyhow!("unable to read layer {}: {}", layer, e))?;
+                // Newer versions of docker store layers as blobs named by
+                // their digest
+                if let Some(hex) = layer.strip_prefix("blobs/sha256/") {
+                    verify_digest(&data, &format!("sha256:{}", hex))?;
+                }
+                Ok(ImageLayer::new(
+                    data,
+                    manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE.to_owned(),
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let image_data = ImageData {
+            layers,
+            // The archive doesn't contain the manifest the registry would
+            // serve, so there is no digest to compare with later pulls
+            digest: None,
+            manifest: None,
+            raw_manifest: None,
+        };
+        for tag in manifest.repo_tags.unwrap_or_default() {
+            let reference = Reference::try_from(tag.as_str())
+                .map_err(|e| anyhow::anyhow!("invalid image reference {}: {}", tag, e))?;
+            images.push((reference, image_data.clone()));
+        }
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", sha2::Sha256::digest(data))
+    }
+
+    /// Writes a blob into the layout, returning its descriptor
+    fn write_blob(dir: &Path, media_type: &str, data: &[u8]) -> serde_json::Value {
+        let digest = sha256(data);
+        let path = blob_path(dir, &digest).unwrap();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+        serde_json::json!({
+            "mediaType": media_type,
+            "digest": digest,
+            "size": data.len(),
+        })
+    }
+
+    fn write_image(dir: &Path, module: &[u8]) -> serde_json::Value {
+        let config = write_blob(dir, manifest::WASM_CONFIG_MEDIA_TYPE, b"{}");
+        let layer = write_blob(dir, manifest::WASM_LAYER_MEDIA_TYPE, module);
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "config": config,
+            "layers": [layer],
+        });
+        write_blob(
+            dir,
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest.to_string().as_bytes(),
+        )
+    }
+
+    fn write_index(dir: &Path, manifests: Vec<serde_json::Value>) {
+        std::fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion": "1.0.0"}"#).unwrap();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": manifests,
+        });
+        std::fs::write(dir.join("index.json"), index.to_string()).unwrap();
+    }
+
+    fn named(mut descriptor: serde_json::Value, name: &str) -> serde_json::Value {
+        descriptor["annotations"] = serde_json::json!({ OCI_REF_NAME_ANNOTATION: name });
+        descriptor
+    }
+
+    #[test]
+    fn reads_every_image_in_a_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_image(dir.path(), MODULE);
+        let second = write_image(dir.path(), b"\0asm\x01\0\0\0second");
+        let second_digest = second["digest"].as_str().unwrap().to_owned();
+        write_index(
+            dir.path(),
+            vec![
+                named(first, "myregistry.io/first:v1"),
+                named(second, "myregistry.io/second:v1"),
+            ],
+        );
+
+        let images = read_images(dir.path()).expect("should read layout");
+        assert_eq!(2, images.len());
+        assert_eq!("myregistry.io/first:v1", images[0].0.whole());
+        assert_eq!(MODULE, images[0].1.layers[0].data.as_slice());
+        assert_eq!(Some(second_digest), images[1].1.digest);
+    }
+
+    #[test]
+    fn rejects_blobs_that_do_not_match_their_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = write_image(dir.path(), MODULE);
+        write_index(dir.path(), vec![named(image, "myregistry.io/first:v1")]);
+        let layer_digest = sha256(MODULE);
+        std::fs::write(
+            blob_path(dir.path(), &layer_digest).unwrap(),
+            b"\0asm\x01\0\0\x01",
+        )
+        .unwrap();
+
+        let err = read_images(dir.path()).err().expect("read should fail");
+        assert!(err.to_string().contains("blob digest mismatch"));
+    }
+
+    #[test]
+    fn rejects_images_named_only_by_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = write_image(dir.path(), MODULE);
+        write_index(dir.path(), vec![named(image, "v1")]);
+
+        let err = read_images(dir.path()).err().expect("read should fail");
+        assert!(err.to_string().contains("not a full image reference"));
+    }
+
+    #[test]
+    fn reads_layouts_from_tarballs() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = dir.path().join("layout");
+        std::fs::create_dir(&layout).unwrap();
+        let image = write_image(&layout, MODULE);
+        write_index(&layout, vec![named(image, "myregistry.io/first:v1")]);
+
+        let tarball = dir.path().join("layout.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tarball).unwrap());
+        builder.append_dir_all(".", &layout).unwrap();
+        builder.finish().unwrap();
+
+        let images = read_images(&tarball).expect("should read tarball");
+        assert_eq!(1, images.len());
+        assert_eq!(MODULE, images[0].1.layers[0].data.as_slice());
+    }
+
+    #[test]
+    fn reads_docker_save_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut layer = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(MODULE.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        layer
+            .append_data(&mut header, "module.wasm", MODULE)
+            .unwrap();
+        std::fs::create_dir(dir.path().join("abc")).unwrap();
+        std::fs::write(
+            dir.path().join("abc/layer.tar"),
+            layer.into_inner().unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("manifest.json"),
+            r#"[{"Config": "config.json", "RepoTags": ["myregistry.io/saved:v1"], "Layers": ["abc/layer.tar"]}]"#,
+        )
+        .unwrap();
+
+        let images = read_images(dir.path()).expect("should read docker save archive");
+        assert_eq!(1, images.len());
+        assert_eq!("myregistry.io/saved:v1", images[0].0.whole());
+        assert_eq!(
+            MODULE,
+            super::super::layer::module_data(&images[0].1)
+                .unwrap()
+                .as_slice()
+        );
+    }
+
+    struct OfflineClient;
+
+    #[async_trait::async_trait]
+    impl super::super::Client for OfflineClient {
+        async fn pull(
+            &mut self,
+            image_ref: &Reference,
+            _auth: &oci_distribution::secrets::RegistryAuth,
+        ) -> anyhow::Result<ImageData> {
+            Err(anyhow::anyhow!("no network access to pull {}", image_ref))
+        }
+    }
+
+    #[tokio::test]
+    async fn imported_images_are_available_without_pulling() {
+        use crate::container::PullPolicy;
+        use crate::store::Store;
+
+        let dir = tempfile::tempdir().unwrap();
+        let layout = dir.path().join("layout");
+        std::fs::create_dir(&layout).unwrap();
+        let image = write_image(&layout, MODULE);
+        write_index(&layout, vec![named(image, "myregistry.io/first:v1")]);
+        let store = super::super::FileStore::new(OfflineClient, dir.path().join("store"));
+
+        let imported = store
+            .import_oci_layout(&layout)
+            .await
+            .expect("should import layout");
+        assert_eq!(
+            vec![Reference::try_from("myregistry.io/first:v1").unwrap()],
+            imported
+        );
+
+        let auth = oci_distribution::secrets::RegistryAuth::Anonymous;
+        for policy in [PullPolicy::Never, PullPolicy::IfNotPresent] {
+            let module = store
+                .get(&imported[0], policy, &auth)
+                .await
+                .expect("should get imported module");
+            assert_eq!(MODULE, module.as_slice());
+        }
+    }
+}
@@ -1,6 +1,8 @@
 //! `oci` implements different storage methods for fetching modules from an OCI registry.
 mod client;
 mod file;
+mod layer;
+pub(crate) mod layout;
 
 pub use client::Client;
 pub use file::FileStore;
@@ -0,0 +1,275 @@
+//! Verification of cosign signatures stored alongside images in a registry
+use std::path::Path;
+
+use async_trait::async_trait;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::ContentVerifier;
+use crate::store::ImageDigests;
+
+/// The media type of the layers holding signed payloads
+const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The layer annotation holding the base64 encoded signature of the payload
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+/// The suffix of the tag that signatures for a manifest digest are pushed to
+const SIGNATURE_TAG_SUFFIX: &str = "sig";
+/// The DER encoding of the SubjectPublicKeyInfo header for an ECDSA P-256
+/// public key, which is followed by the uncompressed curve point
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// A `ContentVerifier` that requires images to have a cosign signature made
+/// with the private key matching a configured public key.
+///
+/// Cosign pushes the signatures for an image with manifest digest
+/// `sha256:<hex>` to the `sha256-<hex>.sig` tag of the image's repository.
+/// Each layer of that artifact is a payload naming the signed digest, with
+/// the signature of the payload in an annotation.
+pub struct CosignVerifier {
+    public_key: Vec<u8>,
+    client: Mutex<oci_distribution::Client>,
+}
+
+impl CosignVerifier {
+    /// Creates a verifier for signatures made with the private key matching
+    /// the given PEM encoded ECDSA P-256 public key. Signatures are fetched
+    /// with `client`.
+    pub fn new(public_key_pem: &[u8], client: oci_distribution::Client) -> anyhow::Result<Self> {
+        let pem = pem::parse(public_key_pem)
+            .map_err(|e| anyhow::anyhow!("unable to parse public key PEM: {}", e))?;
+        if pem.tag != "PUBLIC KEY" || !pem.contents.starts_with(P256_SPKI_PREFIX) {
+            return Err(anyhow::anyhow!(
+                "unsupported public key: only ECDSA P-256 public keys are supported"
+            ));
+        }
+        Ok(CosignVerifier {
+            public_key: pem.contents[P256_SPKI_PREFIX.len()..].to_vec(),
+            client: Mutex::new(client),
+        })
+    }
+
+    /// Creates a verifier using the PEM encoded public key in the given file
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        client: oci_distribution::Client,
+    ) -> anyhow::Result<Self> {
+        let pem = std::fs::read(path.as_ref()).map_err(|e| {
+            anyhow::anyhow!(
+                "unable to read image verification key {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Self::new(&pem, client)
+    }
+
+    /// Checks that `signature` is a signature of `payload` made with our key,
+    /// and that the payload names the expected manifest digest.
+    fn verify_payload(
+        &self,
+        manifest_digest: &str,
+        payload: &[u8],
+        signature: &str,
+    ) -> anyhow::Result<()> {
+        let signature = base64::decode(signature)
+            .map_err(|e| anyhow::anyhow!("signature is not valid base64: {}", e))?;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &self.public_key)
+            .verify(payload, &signature)
+            .map_err(|_| anyhow::anyhow!("signature was not made with the configured key"))?;
+
+        let payload: SimpleSigningPayload = serde_json::from_slice(payload)
+            .map_err(|e| anyhow::anyhow!("unable to parse signed payload: {}", e))?;
+        let signed_digest = payload.critical.image.docker_manifest_digest;
+        if signed_digest != manifest_digest {
+            return Err(anyhow::anyhow!(
+                "signature is for digest {}, not {}",
+                signed_digest,
+                manifest_digest
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ContentVerifier for CosignVerifier {
+    async fn verify(
+        &self,
+        image_ref: &Reference,
+        digests: &ImageDigests,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        let signature_ref = signature_reference(image_ref, &digests.manifest)?;
+        debug!("Fetching signatures from '{:?}'", signature_ref);
+        let signatures = self
+            .client
+            .lock()
+            .await
+            .pull(&signature_ref, auth, vec![SIMPLE_SIGNING_MEDIA_TYPE])
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("unable to fetch signatures from {}: {}", signature_ref, e)
+            })?;
+        let manifest = signatures
+            .manifest
+            .ok_or_else(|| anyhow::anyhow!("no manifest for signatures at {}", signature_ref))?;
+
+        let mut errors = vec![];
+        for (descriptor, layer) in manifest.layers.iter().zip(signatures.layers.iter()) {
+            let signature = match descriptor
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+            {
+                Some(signature) => signature,
+                None => {
+                    errors.push(format!("{}: no signature annotation", descriptor.digest));
+                    continue;
+                }
+            };
+            match self.verify_payload(&digests.manifest, &layer.data, signature) {
+                Ok(()) => return Ok(()),
+                Err(e) => errors.push(format!("{}: {}", descriptor.digest, e)),
+            }
+        }
+        Err(anyhow::anyhow!(
+            "no valid signature found at {}: [{}]",
+            signature_ref,
+            errors.join("; ")
+        ))
+    }
+}
+
+/// Returns the reference that cosign pushes the signatures for the given
+/// manifest digest to
+fn signature_reference(image_ref: &Reference, manifest_digest: &str) -> anyhow::Result<Reference> {
+    let mut parts = manifest_digest.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(hex)) => {
+            Ok(image_ref.with_tag(&format!("{}-{}.{}", algorithm, hex, SIGNATURE_TAG_SUFFIX)))
+        }
+        _ => Err(anyhow::anyhow!(
+            "invalid manifest digest {}",
+            manifest_digest
+        )),
+    }
+}
+
+/// The payload signed by cosign, in the "simple signing" format
+#[derive(serde::Deserialize)]
+struct SimpleSigningPayload {
+    critical: SimpleSigningCritical,
+}
+
+#[derive(serde::Deserialize)]
+struct SimpleSigningCritical {
+    image: SimpleSigningImage,
+}
+
+#[derive(serde::Deserialize)]
+struct SimpleSigningImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use std::convert::TryFrom;
+
+    const DIGEST: &str = "sha256:3f1e9a4c6bbd2d7f3c5e4b1a9f0e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e";
+
+    fn key_pair() -> EcdsaKeyPair {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+                .expect("should be able to generate key");
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+            .expect("should be able to load key")
+    }
+
+    fn verifier_for(key_pair: &EcdsaKeyPair) -> CosignVerifier {
+        let mut spki = P256_SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(key_pair.public_key().as_ref());
+        let pem = pem::encode(&pem::Pem {
+            tag: "PUBLIC KEY".to_owned(),
+            contents: spki,
+        });
+        CosignVerifier::new(pem.as_bytes(), oci_distribution::Client::default())
+            .expect("should be able to create verifier")
+    }
+
+    fn payload(digest: &str) -> Vec<u8> {
+        format!(
+            r#"{{"critical":{{"identity":{{"docker-reference":"webassembly.azurecr.io/hello"}},"image":{{"docker-manifest-digest":"{}"}},"type":"cosign container image signature"}},"optional":null}}"#,
+            digest
+        )
+        .into_bytes()
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, payload: &[u8]) -> String {
+        let signature = key_pair
+            .sign(&SystemRandom::new(), payload)
+            .expect("should be able to sign");
+        base64::encode(signature.as_ref())
+    }
+
+    #[test]
+    fn signatures_are_found_at_digest_tag() {
+        let image_ref = Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap();
+        assert_eq!(
+            "webassembly.azurecr.io/hello:sha256-3f1e9a4c6bbd2d7f3c5e4b1a9f0e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e.sig",
+            signature_reference(&image_ref, DIGEST).unwrap().whole()
+        );
+        assert!(signature_reference(&image_ref, "nodigest").is_err());
+    }
+
+    #[test]
+    fn valid_signatures_are_accepted() {
+        let key_pair = key_pair();
+        let payload = payload(DIGEST);
+        let signature = sign(&key_pair, &payload);
+        verifier_for(&key_pair)
+            .verify_payload(DIGEST, &payload, &signature)
+            .expect("signature should be valid");
+    }
+
+    #[test]
+    fn signatures_from_other_keys_are_rejected() {
+        let payload = payload(DIGEST);
+        let signature = sign(&key_pair(), &payload);
+        let err = verifier_for(&key_pair())
+            .verify_payload(DIGEST, &payload, &signature)
+            .unwrap_err();
+        assert!(err.to_string().contains("not made with the configured key"));
+    }
+
+    #[test]
+    fn signatures_for_other_digests_are_rejected() {
+        let key_pair = key_pair();
+        let payload = payload("sha256:0000");
+        let signature = sign(&key_pair, &payload);
+        let err = verifier_for(&key_pair)
+            .verify_payload(DIGEST, &payload, &signature)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("signature is for digest sha256:0000"));
+    }
+
+    #[test]
+    fn non_p256_keys_are_rejected() {
+        let pem = pem::encode(&pem::Pem {
+            tag: "PUBLIC KEY".to_owned(),
+            contents: vec![0x30, 0x00],
+        });
+        assert!(CosignVerifier::new(pem.as_bytes(), oci_distribution::Client::default()).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! `verification` contains the hook for checking the provenance of modules
+//! before they are run.
+//!
+//! Providers that use the generic pod states verify modules by returning a
+//! [`ContentVerifier`] from
+//! [`GenericProviderState::content_verifier`](crate::state::common::GenericProviderState::content_verifier).
+//! The [`CosignVerifier`] checks cosign signatures, and users can plug in
+//! their own policy engines by implementing `ContentVerifier`.
+mod cosign;
+
+pub use cosign::CosignVerifier;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use oci_distribution::client::ClientConfigSource;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::store::ImageDigests;
+
+/// Checks the content of an image before the modules in it are run.
+///
+/// # Example
+/// ```rust
+/// use async_trait::async_trait;
+/// use kubelet::store::verification::ContentVerifier;
+/// use kubelet::store::ImageDigests;
+/// use oci_distribution::secrets::RegistryAuth;
+/// use oci_distribution::Reference;
+///
+/// /// Only allows images from a single registry
+/// struct RegistryVerifier {
+///     registry: String,
+/// }
+///
+/// #[async_trait]
+/// impl ContentVerifier for RegistryVerifier {
+///     async fn verify(
+///         &self,
+///         image_ref: &Reference,
+///         _digests: &ImageDigests,
+///         _auth: &RegistryAuth,
+///     ) -> anyhow::Result<()> {
+///         if image_ref.registry() != self.registry {
+///             anyhow::bail!("images must come from {}", self.registry);
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait ContentVerifier: Send + Sync {
+    /// Verifies the image with the given reference and digests. An error
+    /// prevents the pod using the image from running.
+    ///
+    /// `auth` is the authentication the image was pulled with, for
+    /// verifiers that need to fetch more data from the registry.
+    async fn verify(
+        &self,
+        image_ref: &Reference,
+        digests: &ImageDigests,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()>;
+}
+
+/// A `ContentVerifier` that remembers which manifest digests another
+/// verifier has accepted, so that the same content is only verified once.
+///
+/// Failures are not cached, so content that failed verification, for example
+/// because the registry was unavailable, is verified again the next time it
+/// is used.
+pub struct CachingVerifier<V: ContentVerifier> {
+    verifier: V,
+    verified: RwLock<HashSet<String>>,
+}
+
+impl<V: ContentVerifier> CachingVerifier<V> {
+    /// Creates a verifier that caches the results of `verifier`
+    pub fn new(verifier: V) -> Self {
+        CachingVerifier {
+            verifier,
+            verified: RwLock::new(HashSet::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<V: ContentVerifier> ContentVerifier for CachingVerifier<V> {
+    async fn verify(
+        &self,
+        image_ref: &Reference,
+        digests: &ImageDigests,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        if self.verified.read().await.contains(&digests.manifest) {
+            debug!(
+                "Image ref '{:?}' with digest {} was already verified",
+                image_ref, digests.manifest
+            );
+            return Ok(());
+        }
+        self.verifier.verify(image_ref, digests, auth).await?;
+        self.verified.write().await.insert(digests.manifest.clone());
+        Ok(())
+    }
+}
+
+/// Creates the verifier described by the kubelet configuration. Returns
+/// `None` if no image verification key is configured.
+pub fn configured_verifier(config: &Config) -> anyhow::Result<Option<Arc<dyn ContentVerifier>>> {
+    let key_file = match &config.image_verification_key_file {
+        Some(key_file) => key_file,
+        None => return Ok(None),
+    };
+    let client = oci_distribution::Client::new(config.client_config());
+    let verifier = CosignVerifier::from_file(key_file, client)?;
+    Ok(Some(Arc::new(CachingVerifier::new(verifier))))
+}
+
+/// The failure to verify one of the images used by a pod
+#[derive(Debug)]
+pub struct VerificationFailure {
+    /// The image that failed verification
+    pub image_ref: Reference,
+    /// The manifest digest of the image, if it is known
+    pub digest: Option<String>,
+    /// Why verification failed
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for VerificationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to verify image {} with digest {}: {:#}",
+            self.image_ref,
+            self.digest.as_deref().unwrap_or("unknown"),
+            self.error
+        )
+    }
+}
+
+/// Verifies each of the images used by the containers of a pod, which must
+/// already have been fetched into `store`.
+pub(crate) async fn verify_pod_images(
+    pod: &crate::pod::Pod,
+    store: &(dyn crate::store::Store + Send + Sync),
+    verifier: &dyn ContentVerifier,
+    auth: &crate::secret::RegistryAuthResolver,
+) -> Result<(), VerificationFailure> {
+    let mut image_refs: Vec<Reference> = vec![];
+    for container in pod.all_containers() {
+        if let Ok(Some(image_ref)) = container.image() {
+            if !image_refs.contains(&image_ref) {
+                image_refs.push(image_ref);
+            }
+        }
+    }
+
+    for image_ref in image_refs {
+        let failure = |digest: Option<String>, error: anyhow::Error| VerificationFailure {
+            image_ref: image_ref.clone(),
+            digest,
+            error,
+        };
+        let digests = match store.image_digests(&image_ref).await {
+            Ok(Some(digests)) => digests,
+            Ok(None) => {
+                return Err(failure(
+                    None,
+                    anyhow::anyhow!("the module store has no digests for the image"),
+                ))
+            }
+            Err(e) => return Err(failure(None, e)),
+        };
+        let registry_auth = auth
+            .resolve_registry_auth(&image_ref)
+            .await
+            .map_err(|e| failure(Some(digests.manifest.clone()), e))?;
+        verifier
+            .verify(&image_ref, &digests, &registry_auth)
+            .await
+            .map_err(|e| failure(Some(digests.manifest.clone()), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingVerifier {
+        calls: Arc<AtomicUsize>,
+        accept: bool,
+    }
+
+    #[async_trait]
+    impl ContentVerifier for CountingVerifier {
+        async fn verify(
+            &self,
+            _image_ref: &Reference,
+            _digests: &ImageDigests,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.accept {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("rejected"))
+            }
+        }
+    }
+
+    fn digests(manifest: &str) -> ImageDigests {
+        ImageDigests {
+            manifest: manifest.to_owned(),
+            config: "sha256:c0".to_owned(),
+            layers: vec!["sha256:1a".to_owned()],
+        }
+    }
+
+    #[tokio::test]
+    async fn successful_verifications_are_cached_per_digest() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let verifier = CachingVerifier::new(CountingVerifier {
+            calls: calls.clone(),
+            accept: true,
+        });
+        let image_ref = Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap();
+        for _ in 0..3 {
+            verifier
+                .verify(&image_ref, &digests("sha256:aa"), &RegistryAuth::Anonymous)
+                .await
+                .unwrap();
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        verifier
+            .verify(&image_ref, &digests("sha256:bb"), &RegistryAuth::Anonymous)
+            .await
+            .unwrap();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn failed_verifications_are_not_cached() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let verifier = CachingVerifier::new(CountingVerifier {
+            calls: calls.clone(),
+            accept: false,
+        });
+        let image_ref = Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap();
+        for _ in 0..2 {
+            assert!(verifier
+                .verify(&image_ref, &digests("sha256:aa"), &RegistryAuth::Anonymous)
+                .await
+                .is_err());
+        }
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn failures_name_the_digest() {
+        let failure = VerificationFailure {
+            image_ref: Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap(),
+            digest: Some("sha256:aa".to_owned()),
+            error: anyhow::anyhow!("bad signature"),
+        };
+        assert_eq!(
+            "Failed to verify image webassembly.azurecr.io/hello:v1 with digest sha256:aa: bad signature",
+            failure.to_string()
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! Assertions shared by the tests of the kubelet's modules
+
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// Asserts that each of the `parsed` names parses to its value, and that the
+/// `rejected` names don't parse
+pub(crate) fn assert_parses<T>(parsed: &[(&str, T)], rejected: &[&str])
+where
+    T: FromStr + PartialEq + Debug,
+{
+    for (name, expected) in parsed {
+        match name.parse::<T>() {
+            Ok(value) => assert_eq!(&value, expected, "{}", name),
+            Err(_) => panic!("{} should parse", name),
+        }
+    }
+    for name in rejected {
+        assert!(name.parse::<T>().is_err(), "{} should be rejected", name);
+    }
+}
@@ -0,0 +1,593 @@
+//! The Kubelet topology manager, which aligns the CPUs, memory and devices
+//! given to a pod on the same NUMA nodes, like the [topology
+//! manager](https://kubernetes.io/docs/tasks/administer-cluster/topology-manager/)
+//! of other kubelets.
+//!
+//! Before a pod's resources are allocated, each [`HintProvider`], such as the
+//! [CPU manager](crate::cpu_manager), gives [`TopologyHint`]s for the
+//! resources of the pod it allocates: the sets of NUMA nodes the resources
+//! could be allocated from, and whether each set is one of the smallest that
+//! could hold them. The hints of every provider are merged by intersecting
+//! their sets of nodes, and the best merged hint is the preferred one with
+//! the fewest nodes. What happens to a pod depends on the policy:
+//!
+//! - `none`, the default, doesn't align resources at all.
+//! - `best-effort` aligns the resources on the best merged hint, and admits
+//!   the pod even if that isn't preferred.
+//! - `restricted` only admits the pod if the best merged hint is preferred.
+//! - `single-numa-node` only admits the pod if its resources can all be
+//!   allocated from a single NUMA node.
+//!
+//! On nodes with a single NUMA node, or where the topology can't be read,
+//! there is nothing to align, and every pod is admitted.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, warn};
+
+use crate::memory_manager::{numa_topology, NumaNode};
+use crate::pod::Pod;
+
+/// The most NUMA nodes that all the combinations of are considered. Nodes
+/// with more than this are aligned on all their NUMA nodes.
+const MAX_HINT_NODES: usize = 8;
+
+/// How the topology manager aligns the resources of pods
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TopologyManagerPolicy {
+    /// Resources are not aligned
+    #[default]
+    None,
+    /// Resources are aligned where possible
+    BestEffort,
+    /// Pods are only admitted if their resources can be aligned on the
+    /// fewest NUMA nodes that could hold them
+    Restricted,
+    /// Pods are only admitted if their resources can be aligned on a single
+    /// NUMA node
+    SingleNumaNode,
+}
+
+impl std::str::FromStr for TopologyManagerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TopologyManagerPolicy::None),
+            "best-effort" => Ok(TopologyManagerPolicy::BestEffort),
+            "restricted" => Ok(TopologyManagerPolicy::Restricted),
+            "single-numa-node" => Ok(TopologyManagerPolicy::SingleNumaNode),
+            _ => Err(anyhow::anyhow!(
+                "unknown topology manager policy {:?}: expected none, best-effort, restricted or single-numa-node",
+                s
+            )),
+        }
+    }
+}
+
+/// A set of NUMA nodes, by ID, as a bit mask
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct NumaMask(u64);
+
+impl NumaMask {
+    /// The set of the given nodes. Nodes with IDs of 64 or more can't be
+    /// included, as they don't fit in the mask.
+    pub fn new(nodes: impl IntoIterator<Item = usize>) -> Self {
+        NumaMask(
+            nodes
+                .into_iter()
+                .filter(|node| *node < 64)
+                .fold(0, |mask, node| mask | 1 << node),
+        )
+    }
+
+    /// The IDs of the nodes in the set
+    pub fn nodes(&self) -> Vec<usize> {
+        (0..64).filter(|node| self.contains(*node)).collect()
+    }
+
+    /// Whether the node is in the set
+    pub fn contains(&self, node: usize) -> bool {
+        node < 64 && self.0 & (1 << node) != 0
+    }
+
+    /// The number of nodes in the set
+    pub fn count(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// Whether there are no nodes in the set
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The nodes that are in both sets
+    pub fn and(&self, other: NumaMask) -> NumaMask {
+        NumaMask(self.0 & other.0)
+    }
+
+    /// Whether this set has fewer nodes than the other, or the same number
+    /// of lower numbered nodes
+    fn is_narrower_than(&self, other: NumaMask) -> bool {
+        match self.count().cmp(&other.count()) {
+            std::cmp::Ordering::Equal => self.0 < other.0,
+            ordering => ordering == std::cmp::Ordering::Less,
+        }
+    }
+}
+
+/// The NUMA nodes a resource could be allocated from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TopologyHint {
+    /// The nodes, or `None` if it could be allocated from any of them
+    pub affinity: Option<NumaMask>,
+    /// Whether these are one of the smallest sets of nodes that could hold
+    /// the resource
+    pub preferred: bool,
+}
+
+/// Something that allocates resources on NUMA nodes, and gives hints for
+/// aligning them with other resources
+pub trait HintProvider: Send + Sync {
+    /// Returns hints for each of the resources of the pod that this provider
+    /// allocates, given the NUMA nodes of the system. A resource whose hints
+    /// are empty can't be allocated from any of the nodes. Resources the pod
+    /// doesn't need, or that could be allocated from any node equally well,
+    /// are left out.
+    fn topology_hints(
+        &self,
+        pod: &Pod,
+        topology: &[NumaNode],
+    ) -> anyhow::Result<HashMap<String, Vec<TopologyHint>>>;
+}
+
+/// Returns a hint for each set of the NUMA nodes that a resource `fits` in.
+/// Sets are preferred if they have as few nodes as the smallest set the
+/// resource `could_fit` in, such as if none of it were allocated yet.
+pub fn generate_hints(
+    topology: &[NumaNode],
+    fits: impl Fn(NumaMask) -> bool,
+    could_fit: impl Fn(NumaMask) -> bool,
+) -> Vec<TopologyHint> {
+    let masks = masks(topology);
+    let min_nodes = masks
+        .iter()
+        .filter(|mask| could_fit(**mask))
+        .map(|mask| mask.count())
+        .min();
+    masks
+        .into_iter()
+        .filter(|mask| fits(*mask))
+        .map(|mask| TopologyHint {
+            affinity: Some(mask),
+            preferred: Some(mask.count()) == min_nodes,
+        })
+        .collect()
+}
+
+/// The CPUs of the NUMA nodes in the set
+pub fn cpus_in(topology: &[NumaNode], mask: NumaMask) -> Vec<usize> {
+    topology
+        .iter()
+        .filter(|node| mask.contains(node.id))
+        .flat_map(|node| node.cpus.iter().copied())
+        .collect()
+}
+
+/// The set of the NUMA nodes that the given CPUs belong to
+pub fn mask_of_cpus(topology: &[NumaNode], cpus: &[usize]) -> NumaMask {
+    NumaMask::new(
+        topology
+            .iter()
+            .filter(|node| node.cpus.iter().any(|cpu| cpus.contains(cpu)))
+            .map(|node| node.id),
+    )
+}
+
+/// Every set of the NUMA nodes, or just the set of all of them if there are
+/// too many to consider every set
+fn masks(topology: &[NumaNode]) -> Vec<NumaMask> {
+    if topology.len() > MAX_HINT_NODES {
+        return vec![NumaMask::new(topology.iter().map(|node| node.id))];
+    }
+    (1..1u32 << topology.len())
+        .map(|subset| {
+            NumaMask::new(
+                topology
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| subset & (1 << i) != 0)
+                    .map(|(_, node)| node.id),
+            )
+        })
+        .collect()
+}
+
+/// Decides which NUMA nodes the resources of pods are aligned on, and whether
+/// pods can be admitted
+pub struct TopologyManager {
+    policy: TopologyManagerPolicy,
+    topology: Vec<NumaNode>,
+    providers: Vec<Arc<dyn HintProvider>>,
+    /// The nodes each admitted pod's resources are aligned on, by pod UID
+    admitted: Mutex<HashMap<String, Option<NumaMask>>>,
+}
+
+impl TopologyManager {
+    /// Creates a topology manager with the given policy, reading the NUMA
+    /// topology of the system if the policy needs it
+    pub fn new(policy: TopologyManagerPolicy) -> Self {
+        let topology = match policy {
+            TopologyManagerPolicy::None => vec![],
+            _ => numa_topology().unwrap_or_else(|e| {
+                warn!(
+                    "Unable to read the NUMA topology, so resources will not be aligned: {}",
+                    e
+                );
+                vec![]
+            }),
+        };
+        Self::with_topology(policy, topology)
+    }
+
+    fn with_topology(policy: TopologyManagerPolicy, topology: Vec<NumaNode>) -> Self {
+        TopologyManager {
+            policy,
+            topology,
+            providers: vec![],
+            admitted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a provider of hints for the resources it allocates
+    pub fn with_provider(mut self, provider: Arc<dyn HintProvider>) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// The policy resources are aligned with
+    pub fn policy(&self) -> TopologyManagerPolicy {
+        self.policy
+    }
+
+    /// Decides which NUMA nodes the resources of the pod should be allocated
+    /// from, returning `None` if they can come from any node. Pods that have
+    /// already been admitted get the same nodes again. Fails if the policy
+    /// doesn't allow the pod on this node, as its resources can't be aligned.
+    pub fn admit(&self, pod: &Pod) -> anyhow::Result<Option<NumaMask>> {
+        if self.policy == TopologyManagerPolicy::None || self.topology.len() < 2 {
+            return Ok(None);
+        }
+        if let Some(affinity) = self.lock().get(pod.pod_uid()) {
+            return Ok(*affinity);
+        }
+
+        let mut hints = vec![];
+        for provider in &self.providers {
+            for (resource, resource_hints) in provider.topology_hints(pod, &self.topology)? {
+                debug!(
+                    "Topology hints for resource {} of pod {}: {:?}",
+                    resource,
+                    pod.name(),
+                    resource_hints
+                );
+                hints.push(resource_hints);
+            }
+        }
+        let best = self.merge(hints);
+        let admitted = match self.policy {
+            TopologyManagerPolicy::Restricted | TopologyManagerPolicy::SingleNumaNode => {
+                best.preferred
+            }
+            _ => true,
+        };
+        if !admitted {
+            return Err(anyhow::anyhow!(
+                "TopologyAffinityError: the resources of pod {} can't be aligned on NUMA nodes under the {:?} topology manager policy",
+                pod.name(),
+                self.policy
+            ));
+        }
+        debug!(
+            "Aligning the resources of pod {} on NUMA nodes {:?}",
+            pod.name(),
+            best.affinity.map(|mask| mask.nodes())
+        );
+        self.lock().insert(pod.pod_uid().to_owned(), best.affinity);
+        Ok(best.affinity)
+    }
+
+    /// The CPUs of the NUMA nodes the resources of a pod are aligned on, or
+    /// none if they aren't aligned
+    pub fn cpus(&self, affinity: Option<NumaMask>) -> Vec<usize> {
+        affinity
+            .map(|mask| cpus_in(&self.topology, mask))
+            .unwrap_or_default()
+    }
+
+    /// Forgets the pod with the given UID, such as once it is deleted
+    pub fn release(&self, pod_uid: &str) {
+        self.lock().remove(pod_uid);
+    }
+
+    /// Merges the hints for each resource into the best hint for all of them
+    fn merge(&self, hints: Vec<Vec<TopologyHint>>) -> TopologyHint {
+        let single_node = self.policy == TopologyManagerPolicy::SingleNumaNode;
+        let hints: Vec<Vec<TopologyHint>> = hints
+            .into_iter()
+            .map(|resource_hints| {
+                // Under the single-numa-node policy, only hints for a single
+                // node are considered
+                let resource_hints: Vec<TopologyHint> = resource_hints
+                    .into_iter()
+                    .filter(|hint| {
+                        !single_node
+                            || (hint.preferred
+                                && hint.affinity.map(|mask| mask.count() == 1).unwrap_or(true))
+                    })
+                    .collect();
+                if resource_hints.is_empty() {
+                    // The resource can't be allocated anywhere
+                    vec![TopologyHint {
+                        affinity: None,
+                        preferred: false,
+                    }]
+                } else {
+                    resource_hints
+                }
+            })
+            .collect();
+
+        let all = NumaMask::new(self.topology.iter().map(|node| node.id));
+        let mut best = TopologyHint {
+            affinity: Some(all),
+            preferred: false,
+        };
+        let mut best_mask = all;
+        for_each_permutation(&hints, &mut vec![], &mut |permutation| {
+            let mask = permutation
+                .iter()
+                .filter_map(|hint| hint.affinity)
+                .fold(all, |mask, affinity| mask.and(affinity));
+            if mask.is_empty() {
+                return;
+            }
+            let preferred = permutation.iter().all(|hint| hint.preferred);
+            let better = (preferred && !best.preferred)
+                || (preferred == best.preferred && mask.is_narrower_than(best_mask));
+            if better {
+                best = TopologyHint {
+                    affinity: Some(mask),
+                    preferred,
+                };
+                best_mask = mask;
+            }
+        });
+        if single_node && best_mask.count() > 1 {
+            best.preferred = false;
+        }
+        best
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Option<NumaMask>>> {
+        self.admitted
+            .lock()
+            .expect("topology manager lock should not be poisoned")
+    }
+}
+
+/// Calls `f` with each combination of one hint for each resource
+fn for_each_permutation(
+    hints: &[Vec<TopologyHint>],
+    permutation: &mut Vec<TopologyHint>,
+    f: &mut dyn FnMut(&[TopologyHint]),
+) {
+    match hints.split_first() {
+        None => f(permutation),
+        Some((resource_hints, rest)) => {
+            for hint in resource_hints {
+                permutation.push(*hint);
+                for_each_permutation(rest, permutation, f);
+                permutation.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util::pod;
+    use k8s_openapi::api::core::v1::PodSpec;
+
+    fn topology() -> Vec<NumaNode> {
+        vec![
+            NumaNode {
+                id: 0,
+                cpus: vec![0, 1],
+            },
+            NumaNode {
+                id: 1,
+                cpus: vec![2, 3],
+            },
+        ]
+    }
+
+    fn hint(nodes: &[usize], preferred: bool) -> TopologyHint {
+        TopologyHint {
+            affinity: Some(NumaMask::new(nodes.iter().copied())),
+            preferred,
+        }
+    }
+
+    /// Gives the same hints for every pod
+    struct StaticHints(HashMap<String, Vec<TopologyHint>>);
+
+    impl HintProvider for StaticHints {
+        fn topology_hints(
+            &self,
+            _pod: &Pod,
+            _topology: &[NumaNode],
+        ) -> anyhow::Result<HashMap<String, Vec<TopologyHint>>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn manager(
+        policy: TopologyManagerPolicy,
+        hints: Vec<(&str, Vec<TopologyHint>)>,
+    ) -> TopologyManager {
+        let hints = hints
+            .into_iter()
+            .map(|(resource, hints)| (resource.to_owned(), hints))
+            .collect();
+        TopologyManager::with_topology(policy, topology())
+            .with_provider(Arc::new(StaticHints(hints)))
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        crate::test_util::assert_parses(
+            &[
+                ("none", TopologyManagerPolicy::None),
+                ("best-effort", TopologyManagerPolicy::BestEffort),
+                ("restricted", TopologyManagerPolicy::Restricted),
+                ("single-numa-node", TopologyManagerPolicy::SingleNumaNode),
+            ],
+            &["strict", "BestEffort"],
+        );
+    }
+
+    #[test]
+    fn masks_are_sets_of_nodes() {
+        let mask = NumaMask::new(vec![0, 2, 3]);
+        assert_eq!(mask.nodes(), vec![0, 2, 3]);
+        assert_eq!(mask.count(), 3);
+        assert_eq!(mask.and(NumaMask::new(vec![2, 5])).nodes(), vec![2]);
+        assert!(mask.and(NumaMask::new(vec![1])).is_empty());
+        assert!(NumaMask::new(vec![1]).is_narrower_than(NumaMask::new(vec![0, 1])));
+        assert!(NumaMask::new(vec![0]).is_narrower_than(NumaMask::new(vec![1])));
+    }
+
+    #[test]
+    fn hints_prefer_the_fewest_nodes() {
+        // Each node has 2 CPUs, but node 0 only has 1 free
+        let free = [1, 2, 3];
+        let hints = generate_hints(
+            &topology(),
+            |mask| {
+                cpus_in(&topology(), mask)
+                    .iter()
+                    .filter(|c| free.contains(c))
+                    .count()
+                    >= 2
+            },
+            |mask| cpus_in(&topology(), mask).len() >= 2,
+        );
+        assert_eq!(hints, vec![hint(&[1], true), hint(&[0, 1], false)]);
+    }
+
+    #[test]
+    fn hints_of_resources_are_aligned() {
+        let manager = manager(
+            TopologyManagerPolicy::Restricted,
+            vec![
+                ("cpu", vec![hint(&[0], true), hint(&[1], true)]),
+                (
+                    "example.com/gpu",
+                    vec![hint(&[1], true), hint(&[0, 1], false)],
+                ),
+            ],
+        );
+        assert_eq!(
+            manager.admit(&pod("pod", PodSpec::default())).unwrap(),
+            Some(NumaMask::new(vec![1]))
+        );
+        assert_eq!(manager.cpus(Some(NumaMask::new(vec![1]))), vec![2, 3]);
+    }
+
+    #[test]
+    fn pods_that_cant_be_aligned_are_rejected_by_strict_policies() {
+        let hints = || {
+            vec![
+                ("cpu", vec![hint(&[0], true)]),
+                (
+                    "example.com/gpu",
+                    vec![hint(&[1], true), hint(&[0, 1], false)],
+                ),
+            ]
+        };
+        // The only alignment uses both nodes, which isn't preferred
+        assert!(manager(TopologyManagerPolicy::Restricted, hints())
+            .admit(&pod("pod", PodSpec::default()))
+            .is_err());
+        assert!(manager(TopologyManagerPolicy::SingleNumaNode, hints())
+            .admit(&pod("pod", PodSpec::default()))
+            .is_err());
+        assert_eq!(
+            manager(TopologyManagerPolicy::BestEffort, hints())
+                .admit(&pod("pod", PodSpec::default()))
+                .unwrap(),
+            Some(NumaMask::new(vec![0]))
+        );
+        assert_eq!(
+            manager(TopologyManagerPolicy::None, hints())
+                .admit(&pod("pod", PodSpec::default()))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn single_numa_node_requires_a_single_node() {
+        // Both resources prefer two nodes, as neither fits on one
+        let hints = vec![
+            ("cpu", vec![hint(&[0, 1], true)]),
+            ("memory", vec![hint(&[0, 1], true)]),
+        ];
+        assert!(manager(TopologyManagerPolicy::Restricted, hints.clone())
+            .admit(&pod("pod", PodSpec::default()))
+            .is_ok());
+        assert!(manager(TopologyManagerPolicy::SingleNumaNode, hints)
+            .admit(&pod("pod", PodSpec::default()))
+            .is_err());
+    }
+
+    #[test]
+    fn resources_without_hints_are_not_aligned() {
+        let manager = manager(TopologyManagerPolicy::Restricted, vec![]);
+        assert_eq!(
+            manager.admit(&pod("pod", PodSpec::default())).unwrap(),
+            Some(NumaMask::new(vec![0, 1]))
+        );
+
+        // Nodes without NUMA admit every pod
+        let manager = TopologyManager::with_topology(
+            TopologyManagerPolicy::SingleNumaNode,
+            vec![NumaNode {
+                id: 0,
+                cpus: vec![0],
+            }],
+        )
+        .with_provider(Arc::new(StaticHints(
+            vec![("cpu".to_owned(), vec![])].into_iter().collect(),
+        )));
+        assert_eq!(
+            manager.admit(&pod("pod", PodSpec::default())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn admitted_pods_keep_their_alignment() {
+        let manager = manager(
+            TopologyManagerPolicy::BestEffort,
+            vec![("cpu", vec![hint(&[1], true)])],
+        );
+        let affinity = manager.admit(&pod("pod", PodSpec::default())).unwrap();
+        assert_eq!(manager.lock().get("pod"), Some(&affinity));
+        manager.release("pod");
+        assert!(manager.lock().is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+//! Writes the files of a volume so that readers never see them half updated.
+//!
+//! Like other kubelets, the files are written into a new hidden directory,
+//! named after the time it was written, and a `..data` symlink is switched to
+//! it in one rename. The files, or the top-level directories containing them,
+//! are symlinks into `..data`, so a reader that opens a file gets either all
+//! of the old files or all of the new ones. The previous directory, and
+//! symlinks to files that no longer exist, are then removed.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The symlink to the directory holding the current files
+const DATA_DIR: &str = "..data";
+/// The symlink that replaces `..data` once it is ready
+const DATA_DIR_TMP: &str = "..data_tmp";
+
+/// A file to be written into the volume
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct File {
+    /// The path of the file, relative to the volume
+    pub(crate) path: PathBuf,
+    pub(crate) content: Vec<u8>,
+    /// The permissions of the file, such as `0o644`
+    pub(crate) mode: u32,
+}
+
+/// Replaces the files in the volume at `dir` with the given ones, creating
+/// the directory if it doesn't exist yet
+pub(crate) async fn write(dir: PathBuf, files: Vec<File>) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || write_files(&dir, &files)).await??;
+    Ok(())
+}
+
+fn write_files(dir: &Path, files: &[File]) -> io::Result<()> {
+    for file in files {
+        validate(&file.path)?;
+    }
+    std::fs::create_dir_all(dir)?;
+    let previous = std::fs::read_link(dir.join(DATA_DIR)).ok();
+
+    let ts_dir_name = format!("..{}", chrono::Utc::now().format("%Y_%m_%d_%H_%M_%S%.9f"));
+    let ts_dir = dir.join(&ts_dir_name);
+    std::fs::create_dir(&ts_dir)?;
+    for file in files {
+        write_file(&ts_dir.join(&file.path), &file.content, file.mode)?;
+    }
+
+    let tmp_link = dir.join(DATA_DIR_TMP);
+    remove_if_exists(&tmp_link)?;
+    symlink(Path::new(&ts_dir_name), &tmp_link)?;
+    std::fs::rename(&tmp_link, dir.join(DATA_DIR))?;
+
+    // Each top-level entry is linked through `..data`, so links that already
+    // exist point at the new files as soon as `..data` does
+    let entries: HashSet<PathBuf> = files.iter().map(|file| top_level(&file.path)).collect();
+    for entry in &entries {
+        let link = dir.join(entry);
+        if std::fs::symlink_metadata(&link).is_err() {
+            symlink(&Path::new(DATA_DIR).join(entry), &link)?;
+        }
+    }
+    for existing in std::fs::read_dir(dir)? {
+        let existing = existing?;
+        let name = PathBuf::from(existing.file_name());
+        if name.to_string_lossy().starts_with("..") || entries.contains(&name) {
+            continue;
+        }
+        let is_ours = std::fs::read_link(existing.path())
+            .map(|target| target.starts_with(DATA_DIR))
+            .unwrap_or(false);
+        if is_ours {
+            std::fs::remove_file(existing.path())?;
+        }
+    }
+
+    if let Some(previous) = previous {
+        if previous != Path::new(&ts_dir_name) {
+            std::fs::remove_dir_all(dir.join(previous))?;
+        }
+    }
+    Ok(())
+}
+
+/// Only relative paths inside the volume can be written, and they can't be
+/// hidden in the same way as the volume's own entries
+pub(crate) fn validate(path: &Path) -> io::Result<()> {
+    let invalid = |reason| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid volume file path {:?}: {}", path, reason),
+        ))
+    };
+    if path.as_os_str().is_empty() {
+        return invalid("the path is empty");
+    }
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return invalid("the path must be relative, and must not contain '..'");
+    }
+    if path.to_string_lossy().starts_with("..") {
+        return invalid("the path must not start with '..'");
+    }
+    Ok(())
+}
+
+fn top_level(path: &Path) -> PathBuf {
+    path.components()
+        .next()
+        .map(|component| PathBuf::from(component.as_os_str()))
+        .unwrap_or_default()
+}
+
+fn write_file(path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, content)?;
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(target_family = "unix"))]
+    tracing::warn!(
+        "file modes are not supported on this platform, ignoring mode {:o} of {:?}",
+        mode,
+        path
+    );
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(target_family = "windows")]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    // Targets are relative to the directory the link is in
+    let resolved = link
+        .parent()
+        .map(|dir| dir.join(target))
+        .unwrap_or_default();
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(path: &str, content: &str) -> File {
+        File {
+            path: PathBuf::from(path),
+            content: content.as_bytes().to_vec(),
+            mode: 0o644,
+        }
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut entries: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with("..") || name == DATA_DIR)
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[tokio::test]
+    async fn files_are_replaced_through_the_data_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let volume = dir.path().join("volume");
+        write(
+            volume.clone(),
+            vec![file("labels", "a=\"1\""), file("config/name", "web")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(entries(&volume), vec!["..data", "config", "labels"]);
+        assert_eq!(
+            std::fs::read_to_string(volume.join("config/name")).unwrap(),
+            "web"
+        );
+        let first = std::fs::read_link(volume.join(DATA_DIR)).unwrap();
+
+        write(volume.clone(), vec![file("labels", "a=\"2\"")])
+            .await
+            .unwrap();
+        assert_eq!(entries(&volume), vec!["..data", "labels"]);
+        assert_eq!(
+            std::fs::read_to_string(volume.join("labels")).unwrap(),
+            "a=\"2\""
+        );
+        // The previous files are removed once they are no longer linked
+        assert!(!volume.join(first).exists());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn files_have_their_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut secret = file("token", "secret");
+        secret.mode = 0o600;
+        write(dir.path().to_owned(), vec![secret]).await.unwrap();
+        let mode = std::fs::metadata(dir.path().join("token"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn paths_outside_the_volume_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in &["../escape", "/etc/passwd", "..data/name", ""] {
+            assert!(
+                write(dir.path().to_owned(), vec![file(path, "")])
+                    .await
+                    .is_err(),
+                "{} should be rejected",
+                path
+            );
+        }
+    }
+}
@@ -1,42 +1,64 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::Path;
 
-use k8s_openapi::api::core::v1::{ConfigMap, KeyToPath};
+use k8s_openapi::api::core::v1::{ConfigMap, ConfigMapVolumeSource};
+use tokio::task::JoinHandle;
 
+use super::object_watch::{self, MountedObject};
 use super::*;
 
+/// Writes the files of the ConfigMap of a volume into the directory at
+/// `path`, which are kept up to date with it for as long as the returned
+/// tasks run. The directory is left empty if the ConfigMap doesn't exist and
+/// the volume is optional.
 pub(crate) async fn populate(
-    config_map: ConfigMap,
-    path: &PathBuf,
-    items: &Option<Vec<KeyToPath>>,
-) -> anyhow::Result<VolumeType> {
-    tokio::fs::create_dir_all(path).await?;
-    let binary_data = config_map.binary_data.unwrap_or_default();
-    let binary_data = binary_data.into_iter().map(|(key, data)| async move {
-        match mount_setting_for(&key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, &data.0).await
-            }
-            ItemMount::DoNotMount => Ok(()),
+    source: &ConfigMapVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<(VolumeType, Vec<JoinHandle<()>>)> {
+    let name = source
+        .name
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?;
+    let cm_client: Api<ConfigMap> = Api::namespaced(client.clone(), pod.namespace());
+    let config_map = match get_optional(&cm_client, name, source.optional).await? {
+        Some(config_map) => config_map,
+        None => {
+            tokio::fs::create_dir_all(path).await?;
+            return Ok((VolumeType::ConfigMap, vec![]));
         }
-    });
-    let binary_data = futures::future::join_all(binary_data);
-    let data = config_map.data.unwrap_or_default();
-    let data = data.into_iter().map(|(key, data)| async move {
-        match mount_setting_for(&key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, data).await
-            }
-            ItemMount::DoNotMount => Ok(()),
-        }
-    });
-    let data = futures::future::join_all(data);
-    let (binary_data, data) = futures::future::join(binary_data, data).await;
-    binary_data
-        .into_iter()
-        .chain(data)
-        .collect::<tokio::io::Result<_>>()?;
+    };
+    let default_mode = source.default_mode.unwrap_or(DEFAULT_MODE);
+    let refresh_tasks = object_watch::mount(
+        config_map,
+        &source.items,
+        default_mode,
+        source.optional,
+        pod,
+        client,
+        path,
+    )
+    .await?;
+    Ok((VolumeType::ConfigMap, refresh_tasks))
+}
+
+impl MountedObject for ConfigMap {
+    fn is_immutable(&self) -> bool {
+        self.immutable.unwrap_or(false)
+    }
 
-    Ok(VolumeType::ConfigMap)
+    fn data(&self) -> BTreeMap<&str, Vec<u8>> {
+        let binary_data = self
+            .binary_data
+            .iter()
+            .flatten()
+            .map(|(key, data)| (key.as_str(), data.0.clone()));
+        let data = self
+            .data
+            .iter()
+            .flatten()
+            .map(|(key, data)| (key.as_str(), data.clone().into_bytes()));
+        binary_data.chain(data).collect()
+    }
 }
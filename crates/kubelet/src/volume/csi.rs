@@ -0,0 +1,378 @@
+//! Mounts PersistentVolumes of CSI drivers through the node service of the
+//! driver's plugin, which registers with the kubelet in the plugin
+//! registration directory.
+//!
+//! If the driver can stage volumes, a volume is staged once on the node, in
+//! a staging directory of its own, and then published into the volume
+//! directory of each pod that uses it. Once the last pod is done with it,
+//! the volume is unstaged. Calls for the same volume are made one at a
+//! time, and, as CSI calls are idempotent, calls that fail with an error
+//! the driver can recover from are retried.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_csi::v1_3_0::node_client::NodeClient;
+use k8s_csi::v1_3_0::node_service_capability::{rpc, Rpc, Type as CapabilityType};
+use k8s_csi::v1_3_0::volume_capability::access_mode::Mode as CSIMode;
+use k8s_csi::v1_3_0::volume_capability::{
+    AccessMode as CSIAccessMode, AccessType as CSIAccessType, MountVolume as CSIMountVolume,
+};
+use k8s_csi::v1_3_0::{
+    NodeGetCapabilitiesRequest, NodePublishVolumeRequest, NodeStageVolumeRequest,
+    NodeUnpublishVolumeRequest, NodeUnstageVolumeRequest, VolumeCapability,
+};
+use k8s_openapi::api::core::v1::{
+    CSIPersistentVolumeSource, PersistentVolume, Secret, SecretReference,
+};
+use kube::api::Api;
+use sha2::Digest;
+use tonic::transport::Channel;
+use tonic::Code;
+use tracing::{debug, warn};
+
+use crate::grpc_sock;
+use crate::plugin_watcher::PluginRegistry;
+
+/// How many times an idempotent CSI call is made before giving up
+const CALL_ATTEMPTS: u32 = 5;
+/// How long to wait before retrying a CSI call the first time. The wait
+/// doubles with each retry.
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The pods' volume directories a volume is published in
+type Publications = HashSet<PathBuf>;
+/// A CSI volume, locked while calls for it are made
+type VolumeLock = Arc<tokio::sync::Mutex<Publications>>;
+
+lazy_static::lazy_static! {
+    /// The CSI volumes of this node, by driver and volume handle
+    static ref VOLUMES: Mutex<HashMap<(String, String), VolumeLock>> =
+        Mutex::new(HashMap::new());
+}
+
+fn volume_lock(csi: &CSIPersistentVolumeSource) -> VolumeLock {
+    let mut volumes = VOLUMES
+        .lock()
+        .expect("CSI volumes lock should not be poisoned");
+    Arc::clone(
+        volumes
+            .entry((csi.driver.clone(), csi.volume_handle.clone()))
+            .or_default(),
+    )
+}
+
+/// The directory the volume is staged in. Volume handles can be any string,
+/// so the directory is named after a hash of the handle.
+fn staging_path(volume_dir: &Path, csi: &CSIPersistentVolumeSource) -> PathBuf {
+    let handle = sha2::Sha256::digest(csi.volume_handle.as_bytes());
+    volume_dir
+        .join(super::CSI_STAGING_DIR)
+        .join(&csi.driver)
+        .join(format!("{:x}", handle))
+}
+
+/// Stages the volume, if the driver stages volumes, and publishes it at
+/// `target`, in the pod's volume directory
+pub(crate) async fn publish(
+    client: &kube::Client,
+    pv: &PersistentVolume,
+    csi: &CSIPersistentVolumeSource,
+    read_only: bool,
+    plugin_registry: &PluginRegistry,
+    volume_dir: &Path,
+    target: &Path,
+) -> anyhow::Result<()> {
+    let lock = volume_lock(csi);
+    let mut publications = lock.lock().await;
+    let mut node = connect(csi, plugin_registry).await?;
+    let capability = capability(pv, csi);
+    let volume_context = csi.volume_attributes.clone().unwrap_or_default();
+
+    let staging_target_path = if supports_stage_unstage(&mut node).await? {
+        let staging = staging_path(volume_dir, csi);
+        tokio::fs::create_dir_all(&staging).await?;
+        // Staging a volume that is already staged does nothing, so the
+        // volume is staged whether or not other pods use it
+        let request = NodeStageVolumeRequest {
+            volume_id: csi.volume_handle.clone(),
+            staging_target_path: staging.to_string_lossy().into_owned(),
+            volume_capability: Some(capability.clone()),
+            secrets: secrets(client, csi.node_stage_secret_ref.as_ref()).await?,
+            // TODO: grab the publish_context using the volume attachments API
+            publish_context: Default::default(),
+            volume_context: volume_context.clone(),
+        };
+        retry("NodeStageVolume", || {
+            let mut node = node.clone();
+            let request = request.clone();
+            async move { node.node_stage_volume(request).await }
+        })
+        .await?;
+        staging.to_string_lossy().into_owned()
+    } else {
+        String::new()
+    };
+
+    tokio::fs::create_dir_all(target).await?;
+    let request = NodePublishVolumeRequest {
+        volume_id: csi.volume_handle.clone(),
+        publish_context: Default::default(),
+        staging_target_path,
+        target_path: target.to_string_lossy().into_owned(),
+        volume_capability: Some(capability),
+        readonly: read_only || csi.read_only.unwrap_or(false),
+        secrets: secrets(client, csi.node_publish_secret_ref.as_ref()).await?,
+        volume_context,
+    };
+    retry("NodePublishVolume", || {
+        let mut node = node.clone();
+        let request = request.clone();
+        async move { node.node_publish_volume(request).await }
+    })
+    .await?;
+    publications.insert(target.to_owned());
+    debug!(
+        "published CSI volume {} of driver {} at {:?}",
+        csi.volume_handle, csi.driver, target
+    );
+    Ok(())
+}
+
+/// Unpublishes the volume from `target`, and unstages it if no other pod
+/// uses it
+pub(crate) async fn unpublish(
+    csi: &CSIPersistentVolumeSource,
+    plugin_registry: &PluginRegistry,
+    volume_dir: &Path,
+    target: &Path,
+) -> anyhow::Result<()> {
+    let lock = volume_lock(csi);
+    let mut publications = lock.lock().await;
+    let mut node = connect(csi, plugin_registry).await?;
+
+    // https://github.com/kubernetes/kubernetes/blob/6d5cb36d36f34cb4f5735b6adcd5ea8ebb4440ba/pkg/volume/csi/csi_mounter.go#L390
+    let request = NodeUnpublishVolumeRequest {
+        volume_id: csi.volume_handle.clone(),
+        target_path: target.to_string_lossy().into_owned(),
+    };
+    retry("NodeUnpublishVolume", || {
+        let mut node = node.clone();
+        let request = request.clone();
+        async move { node.node_unpublish_volume(request).await }
+    })
+    .await?;
+    remove_dir(target).await?;
+    publications.remove(target);
+
+    if publications.is_empty() && supports_stage_unstage(&mut node).await? {
+        let staging = staging_path(volume_dir, csi);
+        let request = NodeUnstageVolumeRequest {
+            volume_id: csi.volume_handle.clone(),
+            staging_target_path: staging.to_string_lossy().into_owned(),
+        };
+        retry("NodeUnstageVolume", || {
+            let mut node = node.clone();
+            let request = request.clone();
+            async move { node.node_unstage_volume(request).await }
+        })
+        .await?;
+        remove_dir(&staging).await?;
+    }
+    Ok(())
+}
+
+async fn remove_dir(path: &Path) -> anyhow::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn connect(
+    csi: &CSIPersistentVolumeSource,
+    plugin_registry: &PluginRegistry,
+) -> anyhow::Result<NodeClient<Channel>> {
+    let endpoint = plugin_registry
+        .get_endpoint(&csi.driver)
+        .await
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "could not get CSI plugin endpoint: driver {} is not registered",
+                csi.driver
+            )
+        })?;
+    let chan = grpc_sock::client::socket_channel(endpoint).await?;
+    Ok(NodeClient::new(chan))
+}
+
+/// Checks if the plugin supports the node_stage/unstage_volume API. Assume
+/// false if not specified.
+async fn supports_stage_unstage(node: &mut NodeClient<Channel>) -> anyhow::Result<bool> {
+    let response = retry("NodeGetCapabilities", || {
+        let mut node = node.clone();
+        async move {
+            node.node_get_capabilities(NodeGetCapabilitiesRequest {})
+                .await
+        }
+    })
+    .await?;
+    Ok(response.capabilities.iter().any(|capability| {
+        matches!(
+            capability.r#type,
+            Some(CapabilityType::Rpc(Rpc { r#type }))
+                if r#type == rpc::Type::StageUnstageVolume as i32
+        )
+    }))
+}
+
+/// The capability the volume is used with, from its access modes
+fn capability(pv: &PersistentVolume, csi: &CSIPersistentVolumeSource) -> VolumeCapability {
+    let spec = pv.spec.as_ref();
+    let access_modes = spec
+        .and_then(|spec| spec.access_modes.clone())
+        .unwrap_or_default();
+    let has = |mode: &str| access_modes.iter().any(|m| m == mode);
+    let mode = if has("ReadWriteMany") {
+        CSIMode::MultiNodeMultiWriter
+    } else if has("ReadOnlyMany") && !has("ReadWriteOnce") {
+        CSIMode::MultiNodeReaderOnly
+    } else {
+        CSIMode::SingleNodeWriter
+    };
+    VolumeCapability {
+        access_mode: Some(CSIAccessMode { mode: mode as i32 }),
+        access_type: Some(CSIAccessType::Mount(CSIMountVolume {
+            fs_type: csi.fs_type.clone().unwrap_or_default(),
+            mount_flags: spec
+                .and_then(|spec| spec.mount_options.clone())
+                .unwrap_or_default(),
+        })),
+    }
+}
+
+/// Reads the secrets the driver is given for a call, if the volume names a
+/// secret for it
+async fn secrets(
+    client: &kube::Client,
+    secret_ref: Option<&SecretReference>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let (name, namespace) = match secret_ref {
+        Some(SecretReference {
+            name: Some(name),
+            namespace: Some(namespace),
+        }) => (name, namespace),
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "CSI secret references must have a name and a namespace"
+            ))
+        }
+        None => return Ok(BTreeMap::new()),
+    };
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+    let secret = secrets.get(name).await?;
+    Ok(secret
+        .data
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| (key, String::from_utf8_lossy(&value.0).into_owned()))
+        .collect())
+}
+
+/// Whether the driver may succeed if the call is made again
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Aborted | Code::ResourceExhausted
+    )
+}
+
+/// Makes the call until it succeeds, fails with an error that isn't
+/// retryable, or has been made `CALL_ATTEMPTS` times
+async fn retry<T, F, Fut>(call: &str, mut make_call: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+{
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match make_call().await {
+            Ok(response) => return Ok(response.into_inner()),
+            Err(status) if attempt < CALL_ATTEMPTS && is_retryable(&status) => {
+                warn!(
+                    "CSI {} failed, retrying in {:?}: {}",
+                    call,
+                    delay,
+                    status.message()
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(status) => {
+                return Err(anyhow::anyhow!(
+                    "CSI {} failed: {:?}: {}",
+                    call,
+                    status.code(),
+                    status.message()
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn source(handle: &str) -> CSIPersistentVolumeSource {
+        CSIPersistentVolumeSource {
+            driver: "hostpath.csi.k8s.io".to_owned(),
+            volume_handle: handle.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn volumes_are_staged_in_their_own_directories() {
+        let volume_dir = Path::new("/var/lib/krustlet/volumes");
+        let first = staging_path(volume_dir, &source("vol-1"));
+        let second = staging_path(volume_dir, &source("../vol-2"));
+        assert_ne!(first, second);
+        for path in &[first, second] {
+            assert!(path.starts_with("/var/lib/krustlet/volumes/csi-staging/hostpath.csi.k8s.io"));
+            assert_eq!(path.components().count(), 8);
+        }
+    }
+
+    #[tokio::test]
+    async fn recoverable_failures_are_retried() {
+        let calls = AtomicU32::new(0);
+        let result = retry("NodePublishVolume", || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if call < 2 {
+                    Err(tonic::Status::unavailable("driver is restarting"))
+                } else {
+                    Ok(tonic::Response::new(call))
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        calls.store(0, Ordering::SeqCst);
+        let result: anyhow::Result<()> = retry("NodePublishVolume", || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(tonic::Status::invalid_argument("no such volume")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::{
+    DownwardAPIVolumeFile, DownwardAPIVolumeSource, Node, Pod as KubePod, ResourceFieldSelector,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::ListParams;
+use kube_runtime::watcher::{watcher, Event};
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+use super::atomic_writer::{self, File};
+use super::*;
+use crate::resources::{parse_milli_quantity, parse_quantity};
+
+/// How long to wait before watching the pod again when the watch fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Writes the files of a downward API volume into the directory at `path`.
+/// If any of them contain the pod's labels or annotations, they are rewritten
+/// whenever those change for as long as the returned task runs, which is
+/// until the volume is dropped.
+pub(crate) async fn populate(
+    source: &DownwardAPIVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<(VolumeType, Vec<JoinHandle<()>>)> {
+    let items = source.items.clone().unwrap_or_default();
+    let default_mode = source.default_mode.unwrap_or(DEFAULT_MODE);
+    let allocatable = node_allocatable(&items, pod, client).await?;
+    let files = render(&items, default_mode, pod, &allocatable)?;
+    atomic_writer::write(path.to_owned(), files).await?;
+
+    let mut refresh_tasks = vec![];
+    if items.iter().any(refers_to_metadata) {
+        let volume = DownwardApiVolume {
+            items,
+            default_mode,
+            allocatable,
+            path: path.to_owned(),
+        };
+        refresh_tasks.push(tokio::spawn(volume.refresh(pod.clone(), client.clone())));
+    }
+    Ok((VolumeType::DownwardAPI, refresh_tasks))
+}
+
+/// Writes the files for the given downward API items of a projected volume
+/// into the directory at `path`, along with the files of its other sources
+pub(crate) async fn populate_items(
+    items: &[DownwardAPIVolumeFile],
+    default_mode: Option<i32>,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let allocatable = node_allocatable(items, pod, client).await?;
+    let files = render(
+        items,
+        default_mode.unwrap_or(DEFAULT_MODE),
+        pod,
+        &allocatable,
+    )?;
+    write_files(path, files).await
+}
+
+/// A downward API volume, whose files are kept up to date with the pod
+struct DownwardApiVolume {
+    items: Vec<DownwardAPIVolumeFile>,
+    default_mode: i32,
+    allocatable: BTreeMap<String, Quantity>,
+    path: PathBuf,
+}
+
+impl DownwardApiVolume {
+    /// Rewrites the files whenever the labels or annotations of the pod
+    /// change, for as long as the task runs
+    async fn refresh(self, pod: Pod, client: kube::Client) {
+        let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+        let params = ListParams::default().fields(&format!("metadata.name={}", pod.name()));
+        let mut written = metadata(&pod);
+        let mut events = watcher(api, params).boxed();
+        loop {
+            let updated = match events.try_next().await {
+                Ok(Some(Event::Applied(updated))) => vec![updated],
+                Ok(Some(Event::Restarted(updated))) => updated,
+                Ok(Some(Event::Deleted(_))) => vec![],
+                Ok(None) => return,
+                Err(e) => {
+                    error!(
+                        "Unable to watch pod {} for the downward API volume at {:?}: {:?}",
+                        pod.name(),
+                        self.path,
+                        e
+                    );
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            for updated in updated {
+                let updated = Pod::from(updated);
+                // A pod recreated with the same name is a different pod
+                if updated.pod_uid() != pod.pod_uid() || metadata(&updated) == written {
+                    continue;
+                }
+                match self.write(&updated).await {
+                    Ok(()) => {
+                        debug!(
+                            "Updated the downward API volume at {:?} for pod {}",
+                            self.path,
+                            pod.name()
+                        );
+                        written = metadata(&updated);
+                    }
+                    Err(e) => error!(
+                        "Unable to update the downward API volume at {:?} for pod {}: {:?}",
+                        self.path,
+                        pod.name(),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    async fn write(&self, pod: &Pod) -> anyhow::Result<()> {
+        let files = render(&self.items, self.default_mode, pod, &self.allocatable)?;
+        atomic_writer::write(self.path.clone(), files).await
+    }
+}
+
+/// The labels and annotations of the pod, which are the only fields files
+/// can contain that change while it runs
+fn metadata(pod: &Pod) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    (pod.labels().clone(), pod.annotations().clone())
+}
+
+fn refers_to_metadata(item: &DownwardAPIVolumeFile) -> bool {
+    item.field_ref
+        .as_ref()
+        .map(|field_ref| {
+            field_ref.field_path.starts_with("metadata.labels")
+                || field_ref.field_path.starts_with("metadata.annotations")
+        })
+        .unwrap_or(false)
+}
+
+/// Renders the file for each item, with the item's mode, or `default_mode`
+/// if it has none
+fn render(
+    items: &[DownwardAPIVolumeFile],
+    default_mode: i32,
+    pod: &Pod,
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<Vec<File>> {
+    items
+        .iter()
+        .map(|item| {
+            let value = match (&item.field_ref, &item.resource_field_ref) {
+                (Some(field_ref), _) => field_value(pod, &field_ref.field_path)?,
+                (None, Some(selector)) => resource_value(pod, selector, allocatable)?,
+                (None, None) => {
+                    return Err(anyhow::anyhow!(
+                        "downward API item {} has neither a fieldRef nor a resourceFieldRef",
+                        item.path
+                    ))
+                }
+            };
+            Ok(File {
+                path: PathBuf::from(&item.path),
+                content: value.into_bytes(),
+                mode: item.mode.unwrap_or(default_mode) as u32,
+            })
+        })
+        .collect()
+}
+
+/// The allocatable resources of the pod's node, which are the values of
+/// limits that a container referred to by the items doesn't set. The node is
+/// only looked up if it is needed.
+async fn node_allocatable(
+    items: &[DownwardAPIVolumeFile],
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<BTreeMap<String, Quantity>> {
+    let needed = items
+        .iter()
+        .filter_map(|item| item.resource_field_ref.as_ref())
+        .any(|selector| {
+            selector.resource.starts_with("limits.")
+                && container_quantity(pod, selector, &BTreeMap::new()).is_err()
+        });
+    if !needed {
+        return Ok(BTreeMap::new());
+    }
+    let node_name = pod
+        .node_name()
+        .ok_or_else(|| anyhow::anyhow!("pod {} is not scheduled to a node", pod.name()))?;
+    let node = Api::<Node>::all(client.clone()).get(node_name).await?;
+    Ok(node.status.and_then(|s| s.allocatable).unwrap_or_default())
+}
+
+/// The value of a pod field, as the downward API writes it to a file. Only
+/// the fields other kubelets allow in volumes are supported.
+fn field_value(pod: &Pod, field_path: &str) -> anyhow::Result<String> {
+    match field_path {
+        "metadata.name" => return Ok(pod.name().to_owned()),
+        "metadata.namespace" => return Ok(pod.namespace().to_owned()),
+        "metadata.uid" => return Ok(pod.pod_uid().to_owned()),
+        "metadata.labels" => return Ok(format_map(pod.labels())),
+        "metadata.annotations" => return Ok(format_map(pod.annotations())),
+        _ => (),
+    }
+    if let Some(key) = subscript(field_path, "metadata.labels") {
+        return Ok(pod.labels().get(key).cloned().unwrap_or_default());
+    }
+    if let Some(key) = subscript(field_path, "metadata.annotations") {
+        return Ok(pod.annotations().get(key).cloned().unwrap_or_default());
+    }
+    Err(anyhow::anyhow!(
+        "Unsupported downward API field {}. Currently supported fields: metadata.name, metadata.namespace, metadata.uid, metadata.labels and metadata.annotations",
+        field_path
+    ))
+}
+
+/// The key of a field path like `metadata.labels['key']`
+fn subscript<'a>(field_path: &'a str, field: &str) -> Option<&'a str> {
+    field_path
+        .strip_prefix(field)?
+        .strip_prefix("['")?
+        .strip_suffix("']")
+}
+
+/// The value of a container's resource limit or request, divided by the
+/// selector's divisor and rounded up, as other kubelets write it
+fn resource_value(
+    pod: &Pod,
+    selector: &ResourceFieldSelector,
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<String> {
+    let quantity = container_quantity(pod, selector, allocatable)?;
+    let divisor = selector
+        .divisor
+        .as_ref()
+        .map(|d| d.0.as_str())
+        .unwrap_or("1");
+    let (value, divisor) = match resource_name(&selector.resource)? {
+        "cpu" => (
+            parse_milli_quantity(&quantity)?,
+            parse_milli_quantity(divisor)?,
+        ),
+        _ => (parse_quantity(&quantity)?, parse_quantity(divisor)?),
+    };
+    if divisor == 0 {
+        return Err(anyhow::anyhow!(
+            "the divisor for {} must be greater than 0",
+            selector.resource
+        ));
+    }
+    Ok(value.div_ceil(divisor).to_string())
+}
+
+/// The quantity of the selected resource that the container requests or is
+/// limited to. Limits the container doesn't set are the node's allocatable
+/// resources, and requests it doesn't set are its limits, or 0 if it isn't
+/// limited either.
+fn container_quantity(
+    pod: &Pod,
+    selector: &ResourceFieldSelector,
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<String> {
+    let container_name = selector.container_name.as_deref().ok_or_else(|| {
+        anyhow::anyhow!(
+            "a container name is required for resource {} in a volume",
+            selector.resource
+        )
+    })?;
+    let container = pod
+        .all_containers()
+        .into_iter()
+        .find(|c| c.name() == container_name)
+        .ok_or_else(|| anyhow::anyhow!("pod has no container named {}", container_name))?;
+    let name = resource_name(&selector.resource)?;
+    let resources = container.resources();
+    let limit = resources
+        .and_then(|r| r.limits.as_ref())
+        .and_then(|limits| limits.get(name));
+    let quantity = if selector.resource.starts_with("limits.") {
+        limit.or_else(|| allocatable.get(name)).ok_or_else(|| {
+            anyhow::anyhow!(
+                "container {} has no {} and the node's allocatable {} is unknown",
+                container_name,
+                selector.resource,
+                name
+            )
+        })?
+    } else {
+        match resources
+            .and_then(|r| r.requests.as_ref())
+            .and_then(|requests| requests.get(name))
+            .or(limit)
+        {
+            Some(quantity) => quantity,
+            None => return Ok("0".to_owned()),
+        }
+    };
+    Ok(quantity.0.clone())
+}
+
+/// The name of the resource in a selector such as `limits.memory`
+fn resource_name(resource: &str) -> anyhow::Result<&str> {
+    match resource.split_once('.') {
+        Some(("limits", name)) | Some(("requests", name))
+            if matches!(name, "cpu" | "memory" | "ephemeral-storage") =>
+        {
+            Ok(name)
+        }
+        _ => Err(anyhow::anyhow!(
+            "Unsupported downward API resource {}. Currently supported resources: limits and requests of cpu, memory and ephemeral-storage",
+            resource
+        )),
+    }
+}
+
+/// Formats labels or annotations as other kubelets do, with a `key="value"`
+/// line for each, in key order. Values are quoted like Go's `%q` verb, so
+/// that values spanning lines or containing quotes can be read back.
+fn format_map(map: &BTreeMap<String, String>) -> String {
+    map.iter()
+        .map(|(key, value)| format!("{}={}", key, quote(value)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Quotes a string like Go's `strconv.Quote`
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\u{7}' => quoted.push_str("\\a"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\u{c}' => quoted.push_str("\\f"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{b}' => quoted.push_str("\\v"),
+            c if c.is_control() && (c as u32) < 0x80 => {
+                quoted.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod() -> Pod {
+        let pod = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": "web",
+                "namespace": "apps",
+                "uid": "1234",
+                "labels": {"app": "web", "tier": "front\"end"},
+                "annotations": {"note": "two\nlines"}
+            },
+            "spec": {
+                "containers": [{
+                    "name": "server",
+                    "resources": {
+                        "requests": {"cpu": "250m"},
+                        "limits": {"cpu": "1500m", "memory": "64Mi"}
+                    }
+                }]
+            }
+        });
+        Pod::from(serde_json::from_value::<KubePod>(pod).unwrap())
+    }
+
+    fn selector(resource: &str, divisor: Option<&str>) -> ResourceFieldSelector {
+        ResourceFieldSelector {
+            container_name: Some("server".to_owned()),
+            resource: resource.to_owned(),
+            divisor: divisor.map(|d| Quantity(d.to_owned())),
+        }
+    }
+
+    #[test]
+    fn fields_are_rendered() {
+        let pod = pod();
+        assert_eq!(field_value(&pod, "metadata.name").unwrap(), "web");
+        assert_eq!(field_value(&pod, "metadata.namespace").unwrap(), "apps");
+        assert_eq!(field_value(&pod, "metadata.uid").unwrap(), "1234");
+        assert_eq!(
+            field_value(&pod, "metadata.labels").unwrap(),
+            "app=\"web\"\ntier=\"front\\\"end\""
+        );
+        assert_eq!(
+            field_value(&pod, "metadata.annotations").unwrap(),
+            "note=\"two\\nlines\""
+        );
+        assert_eq!(field_value(&pod, "metadata.labels['app']").unwrap(), "web");
+        assert_eq!(
+            field_value(&pod, "metadata.annotations['note']").unwrap(),
+            "two\nlines"
+        );
+        assert!(field_value(&pod, "spec.nodeName").is_err());
+    }
+
+    #[test]
+    fn maps_are_formatted_like_other_kubelets() {
+        let map = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(format_map(&map(&[])), "");
+        assert_eq!(
+            format_map(&map(&[("kubernetes.io/name", "foo")])),
+            r#"kubernetes.io/name="foo""#
+        );
+        assert_eq!(
+            format_map(&map(&[("key2", "value2"), ("key1", "value1")])),
+            "key1=\"value1\"\nkey2=\"value2\""
+        );
+        assert_eq!(
+            format_map(&map(&[("multiline", "first\nsecond\r\n")])),
+            r#"multiline="first\nsecond\r\n""#
+        );
+        assert_eq!(
+            format_map(&map(&[("quoted", r#"say "hi" \o/"#)])),
+            r#"quoted="say \"hi\" \\o/""#
+        );
+        assert_eq!(
+            format_map(&map(&[("control", "bell\u{7}tab\tnul\u{0}del\u{7f}")])),
+            r#"control="bell\atab\tnul\x00del\x7f""#
+        );
+        assert_eq!(
+            format_map(&map(&[("unicode", "héllo ☃")])),
+            r#"unicode="héllo ☃""#
+        );
+    }
+
+    #[test]
+    fn resources_are_divided_and_rounded_up() {
+        let pod = pod();
+        let none = BTreeMap::new();
+        let value = |resource, divisor| resource_value(&pod, &selector(resource, divisor), &none);
+        assert_eq!(value("limits.cpu", None).unwrap(), "2");
+        assert_eq!(value("limits.cpu", Some("1m")).unwrap(), "1500");
+        assert_eq!(value("requests.cpu", Some("100m")).unwrap(), "3");
+        assert_eq!(value("limits.memory", Some("1Mi")).unwrap(), "64");
+        assert_eq!(value("limits.memory", None).unwrap(), "67108864");
+        // Requests default to limits, and are otherwise 0
+        assert_eq!(value("requests.memory", Some("1Mi")).unwrap(), "64");
+        assert_eq!(value("requests.ephemeral-storage", None).unwrap(), "0");
+        assert!(value("limits.memory", Some("0")).is_err());
+        assert!(value("limits.gpu", None).is_err());
+    }
+
+    #[test]
+    fn unset_limits_are_the_nodes_allocatable_resources() {
+        let pod = pod();
+        let allocatable = vec![("ephemeral-storage".to_owned(), Quantity("10Gi".to_owned()))]
+            .into_iter()
+            .collect();
+        let selector = selector("limits.ephemeral-storage", Some("1Gi"));
+        assert!(resource_value(&pod, &selector, &BTreeMap::new()).is_err());
+        assert_eq!(resource_value(&pod, &selector, &allocatable).unwrap(), "10");
+    }
+
+    #[test]
+    fn files_have_the_default_mode_unless_given_one() {
+        let item = |path: &str, mode| DownwardAPIVolumeFile {
+            path: path.to_owned(),
+            field_ref: Some(k8s_openapi::api::core::v1::ObjectFieldSelector {
+                field_path: "metadata.name".to_owned(),
+                ..Default::default()
+            }),
+            mode,
+            ..Default::default()
+        };
+        let files = render(
+            &[item("name", None), item("private", Some(0o600))],
+            0o640,
+            &pod(),
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        assert_eq!(files[0].mode, 0o640);
+        assert_eq!(files[1].mode, 0o600);
+        assert_eq!(files[1].content, b"web");
+    }
+
+    #[test]
+    fn only_labels_and_annotations_are_refreshed() {
+        let item = |field_path: &str| DownwardAPIVolumeFile {
+            field_ref: Some(k8s_openapi::api::core::v1::ObjectFieldSelector {
+                field_path: field_path.to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(refers_to_metadata(&item("metadata.labels")));
+        assert!(refers_to_metadata(&item("metadata.annotations['note']")));
+        assert!(!refers_to_metadata(&item("metadata.name")));
+    }
+}
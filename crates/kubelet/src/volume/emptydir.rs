@@ -0,0 +1,77 @@
+//! emptyDir volumes, which start empty and are deleted with their pod.
+//!
+//! Volumes on the node's disk count towards the pod's ephemeral storage, and
+//! the pod is evicted if one holds more than its `sizeLimit`. Volumes with the
+//! `Memory` medium are backed by a tmpfs instead, which can't hold more than
+//! its `sizeLimit`.
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::EmptyDirVolumeSource;
+
+use super::*;
+use crate::resources::parse_quantity;
+
+/// The medium of emptyDir volumes backed by memory
+const MEMORY_MEDIUM: &str = "Memory";
+
+pub(crate) async fn populate(
+    source: &EmptyDirVolumeSource,
+    path: &Path,
+) -> anyhow::Result<(VolumeType, Option<u64>)> {
+    let size_limit = size_limit(source)?;
+    match source.medium.as_deref() {
+        None | Some("") => {
+            tokio::fs::create_dir_all(path).await?;
+            Ok((VolumeType::EmptyDir, size_limit))
+        }
+        Some(MEMORY_MEDIUM) => {
+            tmpfs::mount(path, size_limit)
+                .await
+                .map_err(|e| anyhow::anyhow!("unable to create emptyDir volume: {}", e))?;
+            Ok((VolumeType::MemoryEmptyDir, size_limit))
+        }
+        Some(medium) => Err(anyhow::anyhow!(
+            "Unsupported emptyDir medium {}. Currently supported mediums: the node's default medium and Memory",
+            medium
+        )),
+    }
+}
+
+fn size_limit(source: &EmptyDirVolumeSource) -> anyhow::Result<Option<u64>> {
+    source
+        .size_limit
+        .as_ref()
+        .map(|q| parse_quantity(&q.0))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("invalid emptyDir sizeLimit: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    #[tokio::test]
+    async fn disk_volumes_have_their_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let source = EmptyDirVolumeSource {
+            size_limit: Some(Quantity("1Mi".to_owned())),
+            ..Default::default()
+        };
+        let (volume_type, size_limit) = populate(&source, &path).await.unwrap();
+        assert!(matches!(volume_type, VolumeType::EmptyDir));
+        assert_eq!(size_limit, Some(1024 * 1024));
+        assert!(path.is_dir());
+    }
+
+    #[tokio::test]
+    async fn unknown_mediums_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = EmptyDirVolumeSource {
+            medium: Some("HugePages".to_owned()),
+            ..Default::default()
+        };
+        assert!(populate(&source, &dir.path().join("pages")).await.is_err());
+    }
+}
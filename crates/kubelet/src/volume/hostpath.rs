@@ -1,9 +1,217 @@
+use std::path::Path;
+
 use k8s_openapi::api::core::v1::HostPathVolumeSource;
 
 use super::*;
 
+/// The permissions of directories created for `DirectoryOrCreate` volumes
+const CREATED_DIRECTORY_MODE: u32 = 0o755;
+/// The permissions of files created for `FileOrCreate` volumes
+const CREATED_FILE_MODE: u32 = 0o644;
+
 pub(crate) async fn populate(hostpath: &HostPathVolumeSource) -> anyhow::Result<VolumeType> {
-    // Check the the directory exists on the host
-    tokio::fs::metadata(&hostpath.path).await?;
+    let path = Path::new(&hostpath.path);
+    match hostpath.type_.as_deref().unwrap_or_default() {
+        "" => {
+            // Check the the directory exists on the host
+            tokio::fs::metadata(path).await?;
+        }
+        "DirectoryOrCreate" => {
+            if tokio::fs::symlink_metadata(path).await.is_err() {
+                create_dir(path).await?;
+            }
+            check_type(path, "a directory", |t| t.is_dir()).await?;
+        }
+        "Directory" => check_type(path, "a directory", |t| t.is_dir()).await?,
+        "FileOrCreate" => {
+            if tokio::fs::symlink_metadata(path).await.is_err() {
+                create_file(path).await?;
+            }
+            check_type(path, "a file", |t| t.is_file()).await?;
+        }
+        "File" => check_type(path, "a file", |t| t.is_file()).await?,
+        #[cfg(target_family = "unix")]
+        "Socket" => {
+            use std::os::unix::fs::FileTypeExt;
+            check_type(path, "a socket", |t| t.is_socket()).await?
+        }
+        #[cfg(target_family = "unix")]
+        "CharDevice" => {
+            use std::os::unix::fs::FileTypeExt;
+            check_type(path, "a character device", |t| t.is_char_device()).await?
+        }
+        #[cfg(target_family = "unix")]
+        "BlockDevice" => {
+            use std::os::unix::fs::FileTypeExt;
+            check_type(path, "a block device", |t| t.is_block_device()).await?
+        }
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unsupported hostPath type {}. Currently supported types: DirectoryOrCreate, Directory, FileOrCreate, File, Socket, CharDevice and BlockDevice",
+                other
+            ))
+        }
+    }
     Ok(VolumeType::HostPath)
 }
+
+/// Checks that what is at `path` is of the type `described`
+async fn check_type(
+    path: &Path,
+    described: &str,
+    is_type: impl Fn(std::fs::FileType) -> bool,
+) -> anyhow::Result<()> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("hostPath type check failed: {:?}: {}", path, e))?;
+    if !is_type(metadata.file_type()) {
+        return Err(anyhow::anyhow!(
+            "hostPath type check failed: {:?} is not {}",
+            path,
+            described
+        ));
+    }
+    Ok(())
+}
+
+async fn create_dir(path: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(path).await?;
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(
+            path,
+            std::fs::Permissions::from_mode(CREATED_DIRECTORY_MODE),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn create_file(path: &Path) -> anyhow::Result<()> {
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(CREATED_FILE_MODE))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source(path: &Path, type_: Option<&str>) -> HostPathVolumeSource {
+        HostPathVolumeSource {
+            path: path.to_string_lossy().into_owned(),
+            type_: type_.map(ToOwned::to_owned),
+        }
+    }
+
+    async fn check(path: &Path, type_: &str) -> anyhow::Result<VolumeType> {
+        populate(&source(path, Some(type_))).await
+    }
+
+    #[cfg(target_family = "unix")]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn unset_types_only_need_the_path_to_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(populate(&source(dir.path(), None)).await.is_ok());
+        assert!(populate(&source(dir.path(), Some(""))).await.is_ok());
+        assert!(populate(&source(&dir.path().join("missing"), None))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn directory_or_create_creates_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = dir.path().join("a/b");
+        check(&created, "DirectoryOrCreate").await.unwrap();
+        assert!(created.is_dir());
+        #[cfg(target_family = "unix")]
+        assert_eq!(mode(&created), 0o755);
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(check(&file, "DirectoryOrCreate").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn directories_must_be_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        check(dir.path(), "Directory").await.unwrap();
+        assert!(check(&file, "Directory").await.is_err());
+        assert!(check(&dir.path().join("missing"), "Directory")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn file_or_create_creates_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = dir.path().join("file");
+        check(&created, "FileOrCreate").await.unwrap();
+        assert!(created.is_file());
+        #[cfg(target_family = "unix")]
+        assert_eq!(mode(&created), 0o644);
+
+        // Existing files are kept as they are
+        std::fs::write(&created, b"data").unwrap();
+        check(&created, "FileOrCreate").await.unwrap();
+        assert_eq!(std::fs::read(&created).unwrap(), b"data");
+
+        assert!(check(dir.path(), "FileOrCreate").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn files_must_be_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        check(&file, "File").await.unwrap();
+        assert!(check(dir.path(), "File").await.is_err());
+        assert!(check(&dir.path().join("missing"), "File").await.is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn sockets_must_be_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("plugin.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        check(&socket, "Socket").await.unwrap();
+        assert!(check(dir.path(), "Socket").await.is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn devices_must_be_devices_of_their_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let null = Path::new("/dev/null");
+        check(null, "CharDevice").await.unwrap();
+        assert!(check(dir.path(), "CharDevice").await.is_err());
+        assert!(check(null, "BlockDevice").await.is_err());
+        assert!(check(dir.path(), "BlockDevice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_types_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check(dir.path(), "Pipe").await.is_err());
+    }
+}
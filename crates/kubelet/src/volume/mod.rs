@@ -1,23 +1,53 @@
 //! A module for use in managing volumes in providers. Use of this module is not
 //! mandatory to create a Provider, but it does provide common implementation
 //! logic for supported volume providers.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use k8s_openapi::api::core::v1::KeyToPath;
-use k8s_openapi::api::core::v1::{ConfigMap, PersistentVolumeClaim, Secret, Volume as KubeVolume};
+use k8s_openapi::api::core::v1::{
+    ConfigMap, PersistentVolumeClaim, Secret, Volume as KubeVolume, VolumeMount,
+};
 use kube::api::Api;
+use tokio::task::JoinHandle;
 use tracing::{debug, error};
 
 use crate::plugin_watcher::PluginRegistry;
 use crate::pod::Pod;
 
+mod atomic_writer;
 mod configmap;
+#[cfg(feature = "csi")]
+mod csi;
+mod downwardapi;
+mod emptydir;
 mod hostpath;
+mod object_watch;
+mod orphans;
 mod persistentvolumeclaim;
+mod projected;
 mod secret;
+mod sub_path;
+mod tmpfs;
+
+pub(crate) use orphans::remove_orphaned_volumes_periodically;
+pub use persistentvolumeclaim::volumes_in_use;
+pub(crate) use secret::unmount_all_in_memory as unmount_in_memory_secrets;
+pub use sub_path::CONFIG_ERROR;
+
+/// The permissions of files whose item and volume don't give a mode, which is
+/// the same default as the API server's
+const DEFAULT_MODE: i32 = 0o644;
+
+/// The directory, in the kubelet's volume directory, that CSI volumes are
+/// staged in
+const CSI_STAGING_DIR: &str = "csi-staging";
+
+/// The directory, in a pod's volume directory, that files mounted on their
+/// own with a sub path are linked into for each container
+const SUB_PATH_FILES_DIR: &str = "..sub-path-files";
 
 /// type of volume
 #[derive(Debug)]
@@ -30,28 +60,43 @@ pub enum VolumeType {
     PersistentVolumeClaim,
     /// hostpath volume
     HostPath,
+    /// projected volume
+    Projected,
+    /// downward API volume
+    DownwardAPI,
+    /// emptyDir volume on the node's disk
+    EmptyDir,
+    /// emptyDir volume backed by memory
+    MemoryEmptyDir,
 }
 
 /// A smart wrapper around the location of a volume on the host system. If this
-/// is a ConfigMap or Secret volume, dropping this reference will clean up the
-/// temporary volume. [AsRef] and [std::ops::Deref] are implemented for this
+/// is a ConfigMap, Secret, projected, downward API or emptyDir volume, dropping this
+/// reference will clean up the temporary volume, and stop refreshing any
+/// objects, tokens or pod fields in it. [AsRef] and [std::ops::Deref] are implemented for this
 /// type so you can still use it like a normal PathBuf
 #[derive(Debug)]
 pub struct Ref {
     host_path: PathBuf,
     volume_type: VolumeType,
+    refresh_tasks: Vec<JoinHandle<()>>,
+    size_limit: Option<u64>,
+    /// Whether the volume's secrets are kept in memory
+    secrets_in_memory: bool,
 }
 
 impl Ref {
     /// Resolves the volumes for a pod, including preparing temporary
     /// directories containing the contents of secrets and configmaps. Returns a
     /// HashMap of volume names to a PathBuf for the directory where the volume
-    /// is mounted
+    /// is mounted. If `secrets_in_memory` is set, secret volumes, and
+    /// projected volumes with secrets or tokens, are backed by memory.
     pub async fn volumes_from_pod(
         volume_dir: &PathBuf,
         pod: &Pod,
         client: &kube::Client,
         plugin_registry: Option<Arc<PluginRegistry>>,
+        secrets_in_memory: bool,
     ) -> anyhow::Result<HashMap<String, Self>> {
         let base_path = volume_dir.join(pod_dir_name(pod));
         tokio::fs::create_dir_all(&base_path).await?;
@@ -61,21 +106,47 @@ impl Ref {
                 host_path.push(&v.name);
                 let pr = plugin_registry.clone();
                 async move {
-                    let volume_type = configure(v, pod.namespace(), client, pr, &host_path).await?;
+                    let mut size_limit = None;
+                    // hostPath volumes, and claims bound to hostPath and
+                    // local volumes, are used where they are on the host
+                    let mut backing_path = v.host_path.as_ref().map(|hp| PathBuf::from(&hp.path));
+                    let (volume_type, refresh_tasks) = if let Some(projected) = &v.projected {
+                        projected::populate(projected, pod, client, &host_path, secrets_in_memory)
+                            .await?
+                    } else if let Some(empty_dir) = &v.empty_dir {
+                        let (volume_type, limit) =
+                            emptydir::populate(empty_dir, &host_path).await?;
+                        size_limit = limit;
+                        (volume_type, vec![])
+                    } else if let Some(downward_api) = &v.downward_api {
+                        downwardapi::populate(downward_api, pod, client, &host_path).await?
+                    } else if let Some(cm) = &v.config_map {
+                        configmap::populate(cm, pod, client, &host_path).await?
+                    } else if let Some(s) = &v.secret {
+                        secret::populate(s, pod, client, &host_path, secrets_in_memory).await?
+                    } else if let Some(pvc_source) = &v.persistent_volume_claim {
+                        let (volume_type, path) = persistentvolumeclaim::populate(
+                            pvc_source, pod, client, pr, volume_dir, &host_path,
+                        )
+                        .await?;
+                        backing_path = path;
+                        (volume_type, vec![])
+                    } else {
+                        (configure(v).await?, vec![])
+                    };
                     Ok((
                         v.name.to_owned(),
                         // Every other volume type should mount to the given
-                        // host_path except for a hostpath volume type. So we
-                        // need to handle that special case here
-                        match &v.host_path {
-                            Some(hostpath) => Ref {
-                                host_path: PathBuf::from(&hostpath.path),
-                                volume_type,
-                            },
-                            None => Ref {
-                                host_path,
-                                volume_type,
-                            },
+                        // host_path except for volumes backed by a path on
+                        // the host. So we need to handle that special case
+                        // here
+                        Ref {
+                            host_path: backing_path.unwrap_or(host_path),
+                            volume_type,
+                            refresh_tasks,
+                            size_limit,
+                            secrets_in_memory: secrets_in_memory
+                                && (v.secret.is_some() || v.projected.is_some()),
                         },
                     ))
                 }
@@ -97,9 +168,23 @@ impl Ref {
         client: &kube::Client,
         plugin_registry: Option<Arc<PluginRegistry>>,
     ) -> anyhow::Result<()> {
+        persistentvolumeclaim::release(pod.pod_uid());
+        let files_dir = volume_dir.join(pod_dir_name(pod)).join(SUB_PATH_FILES_DIR);
+        match tokio::fs::remove_dir_all(&files_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
         if let Some(vols) = pod.volumes() {
             let base_path = volume_dir.join(pod_dir_name(pod));
             for vol in vols {
+                // The kubelet may have stopped before the volume's reference
+                // could be dropped
+                if vol.empty_dir.is_some() {
+                    tmpfs::unmount(&base_path.join(&vol.name))?;
+                }
+                if vol.secret.is_some() || vol.projected.is_some() {
+                    secret::unmount_in_memory(&base_path.join(&vol.name))?;
+                }
                 if let Some(pvc_source) = &vol.persistent_volume_claim {
                     let vol_path = base_path.join(&vol.name);
                     persistentvolumeclaim::unpopulate(
@@ -107,6 +192,7 @@ impl Ref {
                         client,
                         pod.namespace(),
                         plugin_registry.clone(),
+                        volume_dir,
                         &vol_path,
                     )
                     .await?;
@@ -115,6 +201,55 @@ impl Ref {
         }
         Ok(())
     }
+
+    /// Returns the path on the host to mount for a container's volume mount
+    /// of this volume, which is inside the volume if the mount has a
+    /// `subPath` or a `subPathExpr`. Expressions are expanded with the
+    /// container's environment `env`. Fails if the path is outside of the
+    /// volume, or is missing from a volume whose files come from the cluster.
+    pub async fn mount_path(
+        &self,
+        mount: &VolumeMount,
+        env: &HashMap<String, String>,
+    ) -> anyhow::Result<PathBuf> {
+        sub_path::resolve(&self.host_path, mount, env, !self.is_read_only(mount)).await
+    }
+
+    /// Whether containers can only read what `mount` mounts of this volume,
+    /// which is the case for read-only mounts, and, as with other kubelets,
+    /// for volumes whose files come from the cluster whether or not the
+    /// mount is read-only
+    pub fn is_read_only(&self, mount: &VolumeMount) -> bool {
+        !matches!(
+            self.volume_type,
+            VolumeType::PersistentVolumeClaim
+                | VolumeType::HostPath
+                | VolumeType::EmptyDir
+                | VolumeType::MemoryEmptyDir
+        ) || mount.read_only.unwrap_or(false)
+    }
+
+    /// Links the file at `path`, which [`Ref::mount_path`] returned for a
+    /// mount of a single file, to `link`, for providers that can only mount
+    /// directories. The file is hard linked where it can be and copied
+    /// otherwise, except for secrets kept in memory, which are never copied
+    /// to disk.
+    pub async fn link_file(&self, path: &Path, link: &Path) -> anyhow::Result<()> {
+        sub_path::link_file(path, link, !self.secrets_in_memory).await
+    }
+
+    /// Whether the files in this volume are on the node's disk and count
+    /// towards the ephemeral storage of the pod, which is the case for
+    /// emptyDir volumes that aren't backed by memory
+    pub fn is_ephemeral_storage(&self) -> bool {
+        matches!(self.volume_type, VolumeType::EmptyDir)
+    }
+
+    /// The most this volume may hold in bytes, from the `sizeLimit` of an
+    /// emptyDir volume
+    pub fn size_limit(&self) -> Option<u64> {
+        self.size_limit
+    }
 }
 
 impl AsRef<PathBuf> for Ref {
@@ -133,7 +268,26 @@ impl Deref for Ref {
 
 impl Drop for Ref {
     fn drop(&mut self) {
-        if matches!(self.volume_type, VolumeType::ConfigMap | VolumeType::Secret) {
+        for task in &self.refresh_tasks {
+            task.abort();
+        }
+        if matches!(self.volume_type, VolumeType::MemoryEmptyDir) {
+            tmpfs::unmount(&self.host_path)
+                .unwrap_or_else(|e| error!("unable to unmount volume on volume cleanup: {:?}", e));
+        }
+        if self.secrets_in_memory {
+            secret::unmount_in_memory(&self.host_path)
+                .unwrap_or_else(|e| error!("unable to unmount volume on volume cleanup: {:?}", e));
+        }
+        if matches!(
+            self.volume_type,
+            VolumeType::ConfigMap
+                | VolumeType::Secret
+                | VolumeType::Projected
+                | VolumeType::DownwardAPI
+                | VolumeType::EmptyDir
+                | VolumeType::MemoryEmptyDir
+        ) {
             // TODO: Currently there is no way to do this async (though there is
             // an async destructors proposal)
             debug!(
@@ -150,69 +304,124 @@ impl Drop for Ref {
     }
 }
 
+/// The directory, in the kubelet's volume directory `volume_dir`, that the
+/// files a container of the pod mounts on their own can be linked into with
+/// [`Ref::link_file`]. It is removed with the pod's volumes.
+pub fn sub_path_files_dir(volume_dir: &Path, pod: &Pod, container: &str) -> PathBuf {
+    volume_dir
+        .join(pod_dir_name(pod))
+        .join(SUB_PATH_FILES_DIR)
+        .join(container)
+}
+
 fn pod_dir_name(pod: &Pod) -> String {
     format!("{}-{}", pod.name(), pod.namespace())
 }
 
-fn mount_setting_for(key: &str, items_to_mount: &Option<Vec<KeyToPath>>) -> ItemMount {
-    match items_to_mount {
-        None => ItemMount::MountAt(key.to_string()),
-        Some(items) => ItemMount::from(
-            items
-                .iter()
-                .find(|kp| kp.key == key)
-                .map(|kp| kp.path.to_string()),
-        ),
+/// Writes the files into the directory at `path` as they are, for volumes
+/// whose files come from several places
+async fn write_files(path: &Path, files: Vec<atomic_writer::File>) -> anyhow::Result<()> {
+    for file in files {
+        let file_path = path.join(&file.path);
+        if let Some(dir) = file_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&file_path, file.content).await?;
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(file.mode))
+                .await?;
+        }
+        #[cfg(not(target_family = "unix"))]
+        tracing::warn!(
+            "file modes are not supported on this platform, ignoring mode {:o} of {:?}",
+            file.mode,
+            file_path
+        );
     }
+    Ok(())
 }
 
-enum ItemMount {
-    MountAt(String),
-    DoNotMount,
+/// The files for an object's keys. Without `items`, each key is a file named
+/// after it, with the permissions `default_mode`. With them, only the listed
+/// keys are, at their paths and with their own modes if they have one. A
+/// listed key that the object doesn't have fails, unless the source mounting
+/// the object is `optional`, and paths outside of the volume fail.
+fn project_keys(
+    kind: &str,
+    data: BTreeMap<&str, Vec<u8>>,
+    items: &Option<Vec<KeyToPath>>,
+    default_mode: i32,
+    optional: Option<bool>,
+) -> anyhow::Result<Vec<atomic_writer::File>> {
+    let items = match items {
+        None => {
+            return Ok(data
+                .into_iter()
+                .map(|(key, content)| atomic_writer::File {
+                    path: PathBuf::from(key),
+                    content,
+                    mode: default_mode as u32,
+                })
+                .collect())
+        }
+        Some(items) => items,
+    };
+    let mut files = vec![];
+    for item in items {
+        let content = match data.get(item.key.as_str()) {
+            Some(content) => content.clone(),
+            None if optional.unwrap_or(false) => continue,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "{} references non-existent key {}",
+                    kind,
+                    item.key
+                ))
+            }
+        };
+        let path = PathBuf::from(&item.path);
+        atomic_writer::validate(&path)?;
+        files.push(atomic_writer::File {
+            path,
+            content,
+            mode: item.mode.unwrap_or(default_mode) as u32,
+        });
+    }
+    Ok(files)
 }
 
-impl From<Option<String>> for ItemMount {
-    fn from(option: Option<String>) -> Self {
-        match option {
-            None => ItemMount::DoNotMount,
-            Some(path) => ItemMount::MountAt(path),
+/// Gets the named object, or nothing if it doesn't exist and the source
+/// mounting it is optional
+async fn get_optional<K>(
+    api: &Api<K>,
+    name: &str,
+    optional: Option<bool>,
+) -> anyhow::Result<Option<K>>
+where
+    K: Clone + serde::de::DeserializeOwned + kube::api::Meta,
+{
+    match api.get(name).await {
+        Ok(object) => Ok(Some(object)),
+        Err(kube::Error::Api(kube::error::ErrorResponse { code: 404, .. }))
+            if optional.unwrap_or(false) =>
+        {
+            Ok(None)
         }
+        Err(e) => Err(e.into()),
     }
 }
 
 /// This is a gnarly function to check all of the supported data members of the
 /// Volume struct. Because it isn't a HashMap, we need to check all fields
 /// individually
-async fn configure(
-    vol: &KubeVolume,
-    namespace: &str,
-    client: &kube::Client,
-    plugin_registry: Option<Arc<PluginRegistry>>,
-    path: &PathBuf,
-) -> anyhow::Result<VolumeType> {
-    if let Some(cm) = &vol.config_map {
-        let name = &cm
-            .name
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?;
-        let cm_client: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
-        let config_map = cm_client.get(name).await?;
-        configmap::populate(config_map, path, &cm.items).await
-    } else if let Some(s) = &vol.secret {
-        let name = &s
-            .secret_name
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?;
-        let secret_client: Api<Secret> = Api::namespaced(client.clone(), namespace);
-        let secret = secret_client.get(name).await?;
-        secret::populate(secret, path, &s.items).await
-    } else if let Some(pvc_source) = &vol.persistent_volume_claim {
-        persistentvolumeclaim::populate(pvc_source, client, namespace, plugin_registry, path).await
-    } else if let Some(hp) = &vol.host_path {
+async fn configure(vol: &KubeVolume) -> anyhow::Result<VolumeType> {
+    if let Some(hp) = &vol.host_path {
         hostpath::populate(hp).await
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, and HostPath"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, Projected, DownwardAPI, and EmptyDir"
         ))
     }
 }
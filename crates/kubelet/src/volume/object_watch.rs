@@ -0,0 +1,395 @@
+//! Keeps ConfigMap and Secret volumes up to date with the objects they mount.
+//!
+//! Each mounted object is watched once, however many volumes mount it, and
+//! every update is written to each of its volumes with the
+//! [atomic writer](super::atomic_writer). As the files are switched under the
+//! volume's directory, rather than the directory itself being replaced,
+//! runtimes that opened the volume's directory for a container see the new
+//! files without opening it again. Objects that are immutable can't change,
+//! so they aren't watched.
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::KeyToPath;
+use kube::api::{ListParams, Meta};
+use kube_runtime::watcher::{watcher, Event};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+use super::atomic_writer::{self, File};
+use super::*;
+use crate::pod::event::{record_event, EventType};
+
+/// How long to wait before watching an object again when the watch fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// The reason of the event recorded when a volume can't be updated
+const FAILED_UPDATE: &str = "FailedVolumeUpdate";
+
+/// The mounted objects being watched, by kind, namespace and name. Each is a
+/// `Weak<SharedWatch<K>>` for the object's type, so that the watch stops once
+/// no volume mounts the object.
+type Watches = HashMap<(&'static str, String, String), Box<dyn Any + Send + Sync>>;
+
+lazy_static::lazy_static! {
+    static ref WATCHES: Mutex<Watches> = Mutex::new(HashMap::new());
+}
+
+/// An object that can be mounted as a volume, with a file for each of its
+/// keys
+pub(crate) trait MountedObject:
+    k8s_openapi::Resource + Meta + Clone + serde::de::DeserializeOwned + Send + Sync + 'static
+{
+    /// Whether the object can't be changed once it is created
+    fn is_immutable(&self) -> bool;
+
+    /// The object's keys and their contents
+    fn data(&self) -> BTreeMap<&str, Vec<u8>>;
+
+    /// The files for the object's keys, with the names and permissions given
+    /// by `items` if any, or else the permissions `mode`. Fails if `items`
+    /// lists a key the object doesn't have, unless `optional` is set.
+    fn files(
+        &self,
+        items: &Option<Vec<KeyToPath>>,
+        mode: i32,
+        optional: Option<bool>,
+    ) -> anyhow::Result<Vec<File>> {
+        project_keys(Self::KIND, self.data(), items, mode, optional)
+    }
+}
+
+/// Writes the files of the object into the volume at `path`. Unless the
+/// object is immutable, they are rewritten whenever it changes for as long as
+/// the returned task runs, which is until the volume is dropped.
+pub(crate) async fn mount<K: MountedObject>(
+    object: K,
+    items: &Option<Vec<KeyToPath>>,
+    default_mode: i32,
+    optional: Option<bool>,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<Vec<JoinHandle<()>>> {
+    atomic_writer::write(
+        path.to_owned(),
+        object.files(items, default_mode, optional)?,
+    )
+    .await?;
+    if object.is_immutable() {
+        return Ok(vec![]);
+    }
+    let volume = ObjectVolume {
+        items: items.clone(),
+        default_mode,
+        optional,
+        path: path.to_owned(),
+    };
+    let updates = subscribe::<K>(client, pod.namespace(), &object.name());
+    Ok(vec![tokio::spawn(volume.refresh(
+        updates,
+        object,
+        pod.clone(),
+        client.clone(),
+    ))])
+}
+
+/// A volume with the files of an object, which are kept up to date with it
+struct ObjectVolume {
+    items: Option<Vec<KeyToPath>>,
+    default_mode: i32,
+    optional: Option<bool>,
+    path: PathBuf,
+}
+
+impl ObjectVolume {
+    /// Rewrites the files whenever the object changes, until it becomes
+    /// immutable or the task stops
+    async fn refresh<K: MountedObject>(
+        self,
+        mut updates: Subscription<K>,
+        written: K,
+        pod: Pod,
+        client: kube::Client,
+    ) {
+        let mut written_version = Meta::resource_ver(&written);
+        while let Some(object) = updates.next().await {
+            let version = Meta::resource_ver(&object);
+            if version.is_some() && version == written_version {
+                continue;
+            }
+            let result = match object.files(&self.items, self.default_mode, self.optional) {
+                Ok(files) => atomic_writer::write(self.path.clone(), files).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "Updated the volume at {:?} for {} {} of pod {}",
+                        self.path,
+                        K::KIND,
+                        object.name(),
+                        pod.name()
+                    );
+                    written_version = version;
+                    if object.is_immutable() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let message = format!(
+                        "Unable to update the volume at {:?} for {} {}: {:?}",
+                        self.path,
+                        K::KIND,
+                        object.name(),
+                        e
+                    );
+                    error!("{}", message);
+                    if let Err(e) =
+                        record_event(&client, &pod, EventType::Warning, FAILED_UPDATE, &message)
+                            .await
+                    {
+                        warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The watch of an object, which stops once no volume mounts it
+struct SharedWatch<K: 'static> {
+    key: (&'static str, String, String),
+    updates: watch::Receiver<Option<K>>,
+    task: JoinHandle<()>,
+}
+
+impl<K: 'static> Drop for SharedWatch<K> {
+    fn drop(&mut self) {
+        self.task.abort();
+        let mut watches = WATCHES
+            .lock()
+            .expect("volume watch lock should not be poisoned");
+        // The object may have been mounted again since this was last used, in
+        // which case the new watch is kept
+        let replaced = watches
+            .get(&self.key)
+            .and_then(|w| w.downcast_ref::<Weak<SharedWatch<K>>>())
+            .map(|w| w.strong_count() > 0)
+            .unwrap_or(true);
+        if !replaced {
+            watches.remove(&self.key);
+        }
+    }
+}
+
+/// The updates to an object, from the watch shared by all the volumes that
+/// mount it
+pub(crate) struct Subscription<K: 'static> {
+    updates: watch::Receiver<Option<K>>,
+    _watch: Option<Arc<SharedWatch<K>>>,
+}
+
+impl<K: Clone + 'static> Subscription<K> {
+    /// Waits for the object to change, returning it, or nothing if the watch
+    /// has stopped
+    async fn next(&mut self) -> Option<K> {
+        loop {
+            self.updates.changed().await.ok()?;
+            if let Some(object) = self.updates.borrow().clone() {
+                return Some(object);
+            }
+        }
+    }
+}
+
+/// Subscribes to the updates of the named object, watching it if no other
+/// volume mounts it yet
+fn subscribe<K: MountedObject>(
+    client: &kube::Client,
+    namespace: &str,
+    name: &str,
+) -> Subscription<K> {
+    let key = (K::KIND, namespace.to_owned(), name.to_owned());
+    let mut watches = WATCHES
+        .lock()
+        .expect("volume watch lock should not be poisoned");
+    let existing = watches
+        .get(&key)
+        .and_then(|w| w.downcast_ref::<Weak<SharedWatch<K>>>())
+        .and_then(Weak::upgrade);
+    let shared = match existing {
+        Some(shared) => shared,
+        None => {
+            debug!("Watching {} {} in namespace {}", K::KIND, name, namespace);
+            let (sender, updates) = watch::channel(None);
+            let api = Api::namespaced(client.clone(), namespace);
+            let task = tokio::spawn(watch_object(api, name.to_owned(), sender));
+            let shared = Arc::new(SharedWatch {
+                key: key.clone(),
+                updates,
+                task,
+            });
+            watches.insert(key, Box::new(Arc::downgrade(&shared)));
+            shared
+        }
+    };
+    Subscription {
+        updates: shared.updates.clone(),
+        _watch: Some(shared),
+    }
+}
+
+/// Sends each version of the named object, for as long as the task runs.
+/// Deleted objects aren't sent, so that their volumes keep the last files
+/// written, as with other kubelets.
+async fn watch_object<K: MountedObject>(
+    api: Api<K>,
+    name: String,
+    sender: watch::Sender<Option<K>>,
+) {
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    let mut events = watcher(api, params).boxed();
+    loop {
+        let updated = match events.try_next().await {
+            Ok(Some(Event::Applied(updated))) => Some(updated),
+            Ok(Some(Event::Restarted(updated))) => updated.into_iter().next(),
+            Ok(Some(Event::Deleted(_))) => None,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Unable to watch {} {}: {:?}", K::KIND, name, e);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        if let Some(updated) = updated {
+            if sender.send(Some(updated)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::test_util::pod;
+    use k8s_openapi::api::core::v1::{ConfigMap, PodSpec};
+    use kube::api::ObjectMeta;
+
+    fn mock_client() -> kube::Client {
+        kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:8080").unwrap(),
+        ))
+    }
+
+    fn config_map(version: &str, value: &str, immutable: bool) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("config".to_owned()),
+                namespace: Some("default".to_owned()),
+                resource_version: Some(version.to_owned()),
+                ..Default::default()
+            },
+            data: Some(
+                vec![("level".to_owned(), value.to_owned())]
+                    .into_iter()
+                    .collect(),
+            ),
+            immutable: Some(immutable),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn volumes_are_rewritten_when_the_object_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        let first = config_map("1", "info", false);
+        atomic_writer::write(
+            path.clone(),
+            first.files(&None, DEFAULT_MODE, None).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let (sender, updates) = watch::channel(None);
+        let volume = ObjectVolume {
+            items: None,
+            default_mode: DEFAULT_MODE,
+            optional: None,
+            path: path.clone(),
+        };
+        let updates = Subscription {
+            updates,
+            _watch: None,
+        };
+        let refresh = tokio::spawn(volume.refresh(
+            updates,
+            first,
+            pod("pod", PodSpec::default()),
+            mock_client(),
+        ));
+
+        sender.send(Some(config_map("2", "debug", false))).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while std::fs::read_to_string(path.join("level")).unwrap() != "debug" {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the volume should have been updated");
+
+        // Once the object is immutable, it can't change again
+        sender.send(Some(config_map("3", "trace", true))).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), refresh)
+            .await
+            .expect("the refresh should have stopped")
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.join("level")).unwrap(),
+            "trace"
+        );
+    }
+
+    #[tokio::test]
+    async fn immutable_objects_are_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let tasks = mount(
+            config_map("1", "info", true),
+            &None,
+            DEFAULT_MODE,
+            None,
+            &pod("pod", PodSpec::default()),
+            &mock_client(),
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        assert!(tasks.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("level")).unwrap(),
+            "info"
+        );
+    }
+
+    #[tokio::test]
+    async fn watches_are_shared_by_volumes() {
+        let key = ("ConfigMap", "shared".to_owned(), "config".to_owned());
+        let first = subscribe::<ConfigMap>(&mock_client(), "shared", "config");
+        let second = subscribe::<ConfigMap>(&mock_client(), "shared", "config");
+        assert!(Arc::ptr_eq(
+            first._watch.as_ref().unwrap(),
+            second._watch.as_ref().unwrap()
+        ));
+
+        drop(first);
+        assert!(WATCHES.lock().unwrap().contains_key(&key));
+        drop(second);
+        assert!(!WATCHES.lock().unwrap().contains_key(&key));
+    }
+}
@@ -0,0 +1,286 @@
+//! Removal of the volume directories of pods that no longer exist.
+//!
+//! Each pod's volumes are kept in a directory of its own, which is removed
+//! when the pod is. Directories are left behind if the kubelet stops while a
+//! pod is being started or deleted, or if pods are force deleted while the
+//! node is offline. The kubelet looks for them when it starts, and
+//! periodically after that, and removes the directories of pods that are no
+//! longer bound to the node, unmounting whatever is still mounted in them
+//! first. As the pods on the node are listed from the API server, a pod that
+//! the kubelet hasn't started yet keeps its directory. Directories modified
+//! in the last [`ORPHAN_GRACE_PERIOD`] are left alone, in case their pod is
+//! bound after the pods are listed, nothing is removed if the pods can't be
+//! listed, and a directory that something is still mounted in is never
+//! removed.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use super::{pod_dir_name, tmpfs, CSI_STAGING_DIR};
+use crate::pod::Pod;
+
+/// How often the volume directories are checked for orphans
+const ORPHAN_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long a volume directory is left alone after it was last modified
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// What a pass over the volume directories removed
+#[derive(Debug, Default, PartialEq)]
+struct Cleanup {
+    /// The number of pod directories removed
+    dirs: usize,
+    /// The bytes the removed directories held
+    bytes: u64,
+}
+
+/// Removes the orphaned volume directories in `volume_path` when called, and
+/// every [`ORPHAN_CHECK_INTERVAL`] after that
+pub(crate) async fn remove_orphaned_volumes_periodically(
+    clients: watch::Receiver<kube::Client>,
+    node_name: String,
+    volume_path: PathBuf,
+) {
+    loop {
+        let client = clients.borrow().clone();
+        match remove_orphaned_volumes(&client, &node_name, &volume_path).await {
+            Ok(cleanup) if cleanup.dirs > 0 => info!(
+                "Removed the volume directories of {} pods that no longer exist, freeing {} bytes",
+                cleanup.dirs, cleanup.bytes
+            ),
+            Ok(_) => debug!("No orphaned volume directories found"),
+            Err(e) => warn!("Unable to look for orphaned volume directories: {:?}", e),
+        }
+        tokio::time::sleep(ORPHAN_CHECK_INTERVAL).await;
+    }
+}
+
+async fn remove_orphaned_volumes(
+    client: &kube::Client,
+    node_name: &str,
+    volume_path: &Path,
+) -> anyhow::Result<Cleanup> {
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let known: HashSet<String> = api
+        .list(&params)
+        .await?
+        .items
+        .into_iter()
+        .map(|pod| pod_dir_name(&Pod::from(pod)))
+        .collect();
+    let volume_path = volume_path.to_owned();
+    tokio::task::spawn_blocking(move || remove_orphans(&volume_path, &known, ORPHAN_GRACE_PERIOD))
+        .await?
+}
+
+/// Removes the directories in `volume_path` that aren't named after a `known`
+/// pod and haven't been modified for `grace`
+fn remove_orphans(
+    volume_path: &Path,
+    known: &HashSet<String>,
+    grace: Duration,
+) -> anyhow::Result<Cleanup> {
+    let mut cleanup = Cleanup::default();
+    let entries = match std::fs::read_dir(volume_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cleanup),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == CSI_STAGING_DIR || known.contains(&name) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age < grace {
+            debug!(
+                "Leaving volume directory {:?} without a pod alone, as it was modified {:?} ago",
+                path, age
+            );
+            continue;
+        }
+        match remove_pod_dir(&path) {
+            Ok(bytes) => {
+                info!(
+                    "Removed volume directory {:?} of a pod that no longer exists",
+                    path
+                );
+                cleanup.dirs += 1;
+                cleanup.bytes += bytes;
+            }
+            Err(e) => warn!(
+                "Unable to remove orphaned volume directory {:?}: {:?}",
+                path, e
+            ),
+        }
+    }
+    Ok(cleanup)
+}
+
+/// Unmounts what is mounted in a pod's volume directory and removes it,
+/// returning how many bytes it held
+fn remove_pod_dir(path: &Path) -> anyhow::Result<u64> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            tmpfs::unmount(&entry.path())?;
+        }
+    }
+    for mount_point in mounts_under(path)? {
+        debug!(
+            "Unmounting {:?} of a pod that no longer exists",
+            mount_point
+        );
+        unmount(&mount_point)?;
+    }
+    // Removing the directory with something mounted in it would remove what
+    // is on the mounted filesystem
+    if !mounts_under(path)?.is_empty() {
+        return Err(anyhow::anyhow!("volumes are still mounted in it"));
+    }
+    let bytes = dir_size(path)?;
+    std::fs::remove_dir_all(path)?;
+    Ok(bytes)
+}
+
+/// The bytes of the files in the directory, without following symbolic links
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            bytes += dir_size(&entry.path())?;
+        } else {
+            bytes += metadata.len();
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(target_os = "linux")]
+fn mounts_under(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mount_points_under(&mountinfo, dir))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mounts_under(_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    Ok(vec![])
+}
+
+/// The mount points in `mountinfo` that are at or under `dir`, deepest first
+/// so that they can be unmounted in order
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn mount_points_under(mountinfo: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut mount_points: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|mount_point| PathBuf::from(unescape(mount_point)))
+        .filter(|mount_point| mount_point.starts_with(dir))
+        .collect();
+    mount_points.sort_by_key(|mount_point| std::cmp::Reverse(mount_point.components().count()));
+    mount_points
+}
+
+/// Replaces the octal escapes, such as `\040` for a space, that the kernel
+/// writes in place of whitespace and backslashes in mount points
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let escape = rest.get(index + 1..index + 4);
+        match escape.and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(target_os = "linux")]
+fn unmount(path: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let target = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(anyhow::anyhow!(
+            "unable to unmount {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_old_directories_of_unknown_pods_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["running-default", "deleted-default", CSI_STAGING_DIR] {
+            std::fs::create_dir_all(dir.path().join(name).join("data")).unwrap();
+        }
+        std::fs::write(dir.path().join("deleted-default/data/file"), b"12345").unwrap();
+        std::fs::write(dir.path().join("stray-file"), b"").unwrap();
+        let known: HashSet<String> = vec!["running-default".to_owned()].into_iter().collect();
+
+        // Directories modified recently may belong to pods being started
+        let cleanup = remove_orphans(dir.path(), &known, Duration::from_secs(60)).unwrap();
+        assert_eq!(cleanup, Cleanup::default());
+        assert!(dir.path().join("deleted-default").exists());
+
+        let cleanup = remove_orphans(dir.path(), &known, Duration::from_secs(0)).unwrap();
+        assert_eq!(cleanup, Cleanup { dirs: 1, bytes: 5 });
+        assert!(!dir.path().join("deleted-default").exists());
+        assert!(dir.path().join("running-default/data").exists());
+        assert!(dir.path().join(CSI_STAGING_DIR).exists());
+        assert!(dir.path().join("stray-file").exists());
+    }
+
+    #[test]
+    fn mount_points_under_a_directory_are_found_deepest_first() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /var/lib/krustlet/volumes/web-default/cache rw - tmpfs tmpfs rw
+41 40 0:36 / /var/lib/krustlet/volumes/web-default/cache/nested rw - tmpfs tmpfs rw
+42 22 0:37 / /var/lib/krustlet/volumes/web-default/my\\040data rw - ext4 /dev/sdb1 rw
+43 22 0:38 / /var/lib/krustlet/volumes/web-default-2/cache rw - tmpfs tmpfs rw
+";
+        assert_eq!(
+            mount_points_under(
+                mountinfo,
+                Path::new("/var/lib/krustlet/volumes/web-default")
+            ),
+            vec![
+                PathBuf::from("/var/lib/krustlet/volumes/web-default/cache/nested"),
+                PathBuf::from("/var/lib/krustlet/volumes/web-default/cache"),
+                PathBuf::from("/var/lib/krustlet/volumes/web-default/my data"),
+            ]
+        );
+    }
+}
@@ -1,28 +1,17 @@
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use k8s_csi::v1_3_0::node_client::NodeClient;
-use k8s_csi::v1_3_0::node_service_capability::{rpc, Rpc, Type as CapabilityType};
-use k8s_csi::v1_3_0::volume_capability::access_mode::Mode as CSIMode;
-use k8s_csi::v1_3_0::volume_capability::{
-    AccessMode as CSIAccessMode, AccessType as CSIAccessType, MountVolume as CSIMountVolume,
-};
-use k8s_csi::v1_3_0::{
-    NodeGetCapabilitiesRequest, NodePublishVolumeRequest, NodeStageVolumeRequest,
-    NodeUnpublishVolumeRequest, VolumeCapability,
-};
+use std::collections::HashSet;
+use std::sync::Mutex;
 
 use k8s_openapi::api::core::v1::{
-    CSIPersistentVolumeSource, PersistentVolume, PersistentVolumeClaimSpec,
-    PersistentVolumeClaimVolumeSource,
+    CSIPersistentVolumeSource, HostPathVolumeSource, LocalVolumeSource, PersistentVolume,
+    PersistentVolumeClaimSpec, PersistentVolumeClaimVolumeSource,
 };
-use k8s_openapi::api::storage::v1::StorageClass;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 
-use tempdir::TempDir;
 use thiserror::Error;
 
-use crate::grpc_sock;
 use crate::plugin_watcher::PluginRegistry;
 
 use super::*;
@@ -149,28 +138,6 @@ pub(crate) fn validate(spec: &PersistentVolumeClaimSpec) -> anyhow::Result<()> {
         validate_label_selector(selector)?;
     }
 
-    match &spec.storage_class_name {
-        // no storage class name specified or set to the empty string
-        // means the user requested the "default" storage class. there
-        // is no "default" storage class for Krustlet at this time, so
-        // we treat this as an error case.
-        //
-        // TODO: implement a "default" storage class
-        None => {
-            return Err(anyhow::anyhow!(
-                "PersistentVolumeClaim must specify a storage class"
-            ));
-        }
-        Some(s) => {
-            if s.is_empty() {
-                return Err(anyhow::anyhow!(
-                    "PersistentVolumeClaim must specify a storage class"
-                ));
-            }
-            s
-        }
-    };
-
     // TODO: validate volume mode
 
     // TODO: validate data source
@@ -185,45 +152,210 @@ fn validate_label_selector(_selector: &LabelSelector) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The fields of a PersistentVolume spec that aren't the volume's source
+const PERSISTENT_VOLUME_SPEC_FIELDS: &[&str] = &[
+    "accessModes",
+    "capacity",
+    "claimRef",
+    "mountOptions",
+    "nodeAffinity",
+    "persistentVolumeReclaimPolicy",
+    "storageClassName",
+    "volumeMode",
+];
+
+/// Where the data of a PersistentVolume is
+enum Source {
+    /// A volume of a CSI driver, published into the pod's volume directory
+    Csi(Box<CSIPersistentVolumeSource>),
+    /// A directory or file on the node
+    HostPath(HostPathVolumeSource),
+    /// A disk, partition or directory on the node
+    Local(LocalVolumeSource),
+}
+
+impl Source {
+    /// Returns the source of the PersistentVolume, or an error naming its
+    /// source if that isn't supported
+    fn of(pv: &PersistentVolume) -> anyhow::Result<Self> {
+        let name = pv.metadata.name.as_deref().unwrap_or_default();
+        let spec = pv
+            .spec
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no PersistentVolume spec defined"))?;
+        if let Some(csi) = &spec.csi {
+            return Ok(Source::Csi(Box::new(csi.clone())));
+        }
+        if let Some(host_path) = &spec.host_path {
+            return Ok(Source::HostPath(host_path.clone()));
+        }
+        if let Some(local) = &spec.local {
+            return Ok(Source::Local(local.clone()));
+        }
+        let source = serde_json::to_value(spec)?
+            .as_object()
+            .and_then(|fields| {
+                fields
+                    .keys()
+                    .find(|field| !PERSISTENT_VOLUME_SPEC_FIELDS.contains(&field.as_str()))
+                    .cloned()
+            })
+            .unwrap_or_else(|| "none".to_owned());
+        Err(anyhow::anyhow!(
+            "PersistentVolume {} has an unsupported source {}. Currently supported sources: csi, hostPath and local",
+            name,
+            source
+        ))
+    }
+
+    /// The unique name of the volume reported in the node's `volumesInUse`
+    fn unique_name(&self, pv_name: &str) -> String {
+        match self {
+            Source::Csi(csi) => format!("kubernetes.io/csi/{}^{}", csi.driver, csi.volume_handle),
+            Source::HostPath(_) => format!("kubernetes.io/host-path/{}", pv_name),
+            Source::Local(_) => format!("kubernetes.io/local-volume/{}", pv_name),
+        }
+    }
+}
+
+/// The pods using a PersistentVolume on this node
+#[derive(Debug)]
+struct VolumeUse {
+    /// The UIDs of the pods
+    pods: HashSet<String>,
+    /// Whether the volume can only be used by one pod at a time
+    read_write_once: bool,
+}
+
+lazy_static::lazy_static! {
+    /// The PersistentVolumes in use on this node, by their unique names
+    static ref IN_USE: Mutex<HashMap<String, VolumeUse>> = Mutex::new(HashMap::new());
+}
+
+fn in_use() -> std::sync::MutexGuard<'static, HashMap<String, VolumeUse>> {
+    IN_USE
+        .lock()
+        .expect("volumes in use lock should not be poisoned")
+}
+
+/// Records that the pod with the UID uses the volume, unless the volume is
+/// ReadWriteOnce and another pod on the node already uses it
+fn claim(volume: &str, pod_uid: &str, read_write_once: bool) -> anyhow::Result<()> {
+    let mut in_use = in_use();
+    let volume_use = in_use
+        .entry(volume.to_owned())
+        .or_insert_with(|| VolumeUse {
+            pods: HashSet::new(),
+            read_write_once,
+        });
+    if (read_write_once || volume_use.read_write_once)
+        && volume_use.pods.iter().any(|pod| pod != pod_uid)
+    {
+        return Err(anyhow::anyhow!(
+            "volume {} is ReadWriteOnce and is already in use by another pod on the node",
+            volume
+        ));
+    }
+    volume_use.pods.insert(pod_uid.to_owned());
+    volume_use.read_write_once |= read_write_once;
+    Ok(())
+}
+
+/// Records that the pod with the UID no longer uses any volumes
+pub(crate) fn release(pod_uid: &str) {
+    in_use().retain(|_, volume_use| {
+        volume_use.pods.remove(pod_uid);
+        !volume_use.pods.is_empty()
+    });
+}
+
+/// The unique names of the PersistentVolumes the node's pods use, for the
+/// node's `volumesInUse` status
+pub fn volumes_in_use() -> Vec<String> {
+    let mut volumes: Vec<String> = in_use().keys().cloned().collect();
+    volumes.sort();
+    volumes
+}
+
+/// Whether the volume can only be used by one pod at a time, as ReadWriteOnce
+/// is its only access mode
+fn is_read_write_once(pv: &PersistentVolume) -> bool {
+    match pv.spec.as_ref().and_then(|spec| spec.access_modes.as_ref()) {
+        Some(modes) => {
+            !modes.is_empty()
+                && modes
+                    .iter()
+                    .all(|mode| matches!(AccessMode::from_str(mode), Ok(AccessMode::ReadWriteOnce)))
+        }
+        None => false,
+    }
+}
+
+/// Mounts the volume bound to the claim for the pod. Returns the path of the
+/// data on the node for hostPath and local volumes, which are used where they
+/// are rather than in the pod's volume directory `path`.
 pub(crate) async fn populate(
     pvc_source: &PersistentVolumeClaimVolumeSource,
+    pod: &Pod,
     client: &kube::Client,
-    namespace: &str,
     pr: Option<Arc<PluginRegistry>>,
+    volume_dir: &Path,
     path: &PathBuf,
-) -> anyhow::Result<VolumeType> {
-    if pr.is_none() {
-        return Err(anyhow::anyhow!(format!(
-            "failed to mount volume {}: CSI driver support not implemented",
-            &pvc_source.claim_name
-        )));
+) -> anyhow::Result<(VolumeType, Option<PathBuf>)> {
+    let pv = get_bound_volume(pvc_source, client, pod.namespace()).await?;
+    let pv_name = pv.metadata.name.clone().unwrap_or_default();
+    let source = Source::of(&pv)?;
+    let volume_mode = pv
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.volume_mode.as_deref())
+        .unwrap_or_default();
+    if let VolumeMode::Block = VolumeMode::from_str(volume_mode)? {
+        return Err(anyhow::anyhow!(
+            "PersistentVolume {} has volumeMode Block, which is not supported",
+            pv_name
+        ));
     }
-    let plugin_registry = pr.unwrap();
-
-    let spec = get_pvc_spec(pvc_source, client, namespace).await?;
-    let mut csi_client = get_csi_client(client, &spec, plugin_registry).await?;
-    let csi = get_csi(client, pvc_source, &spec).await?;
-    let stage_unstage_volume = supports_stage_unstage(&mut csi_client).await?;
-
-    // we keep this around even if the driver does not support STAGE_UNSTAGE_VOLUME. unmount() still needs it.
-    tokio::fs::create_dir_all(path).await?;
-    // TODO(bacongobbler): implement node_unstage_volume(). We'll need to
-    // persist the staging_path somewhere so we can recall that information
-    // during unpopulate()
-    let staging_path = TempDir::new(&csi.volume_handle)?;
-    if stage_unstage_volume {
-        stage_volume(&mut csi_client, &csi, staging_path.path()).await?;
+    claim(
+        &source.unique_name(&pv_name),
+        pod.pod_uid(),
+        is_read_write_once(&pv),
+    )?;
+
+    match source {
+        Source::Csi(csi) => {
+            populate_csi(pvc_source, &pv, &csi, client, pr, volume_dir, path).await?;
+            Ok((VolumeType::PersistentVolumeClaim, None))
+        }
+        Source::HostPath(host_path) => {
+            hostpath::populate(&host_path).await?;
+            Ok((
+                VolumeType::PersistentVolumeClaim,
+                Some(PathBuf::from(host_path.path)),
+            ))
+        }
+        Source::Local(local) => {
+            let metadata = tokio::fs::metadata(&local.path).await.map_err(|e| {
+                anyhow::anyhow!(
+                    "path {} of local PersistentVolume {} can't be found: {}",
+                    local.path,
+                    pv_name,
+                    e
+                )
+            })?;
+            if !metadata.is_dir() {
+                return Err(anyhow::anyhow!(
+                    "path {} of local PersistentVolume {} is not a directory",
+                    local.path,
+                    pv_name
+                ));
+            }
+            Ok((
+                VolumeType::PersistentVolumeClaim,
+                Some(PathBuf::from(local.path)),
+            ))
+        }
     }
-    publish_volume(
-        &mut csi_client,
-        &csi,
-        staging_path.path(),
-        stage_unstage_volume,
-        path,
-    )
-    .await?;
-
-    Ok(VolumeType::PersistentVolumeClaim)
 }
 
 pub(crate) async fn unpopulate(
@@ -231,160 +363,133 @@ pub(crate) async fn unpopulate(
     client: &kube::Client,
     namespace: &str,
     pr: Option<Arc<PluginRegistry>>,
+    volume_dir: &Path,
     path: &PathBuf,
 ) -> anyhow::Result<()> {
-    if pr.is_none() {
-        return Err(anyhow::anyhow!(format!(
-            "failed to unmount volume {}: CSI driver support not implemented",
-            &pvc_source.claim_name
-        )));
-    }
-    let plugin_registry = pr.unwrap();
-
-    let spec = get_pvc_spec(pvc_source, client, namespace).await?;
-    let mut csi_client = get_csi_client(client, &spec, plugin_registry).await?;
-    let csi = get_csi(client, pvc_source, &spec).await?;
-
-    // https://github.com/kubernetes/kubernetes/blob/6d5cb36d36f34cb4f5735b6adcd5ea8ebb4440ba/pkg/volume/csi/csi_mounter.go#L390
-    unpublish_volume(&mut csi_client, &csi, path).await?;
-    std::fs::remove_dir_all(path)?;
-
-    Ok(())
-}
-
-// checks if the plugin supports the node_stage/unstage_volume API. Assume false if not specified.
-async fn supports_stage_unstage(
-    csi_client: &mut NodeClient<tonic::transport::Channel>,
-) -> anyhow::Result<bool> {
-    let mut stage_unstage_volume = false;
-    let response = csi_client
-        .node_get_capabilities(NodeGetCapabilitiesRequest {})
-        .await?;
-    for capability in &response.get_ref().capabilities {
-        if let Some(typ) = &capability.r#type {
-            let _typ_stage_unstage_volume = rpc::Type::StageUnstageVolume as i32;
-            match typ {
-                CapabilityType::Rpc(Rpc {
-                    r#type: _typ_stage_unstage_volume,
-                }) => {
-                    stage_unstage_volume = true;
-                }
-            }
+    let pv = get_bound_volume(pvc_source, client, namespace).await?;
+    match Source::of(&pv)? {
+        Source::Csi(csi) => unpopulate_csi(pvc_source, &csi, pr, volume_dir, path).await,
+        // The data is left where it is, whatever the reclaim policy of the
+        // volume, as reclaiming volumes is up to the cluster
+        Source::HostPath(_) | Source::Local(_) => {
+            let reclaim_policy = pv
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.persistent_volume_reclaim_policy.as_deref())
+                .unwrap_or_default();
+            debug!(
+                "leaving the data of PersistentVolume {:?} with reclaim policy {:?} in place",
+                pv.metadata.name,
+                ReclaimPolicy::from_str(reclaim_policy)?
+            );
+            Ok(())
         }
     }
-    Ok(stage_unstage_volume)
 }
 
-async fn stage_volume(
-    csi_client: &mut NodeClient<tonic::transport::Channel>,
+#[cfg(feature = "csi")]
+async fn populate_csi(
+    pvc_source: &PersistentVolumeClaimVolumeSource,
+    pv: &PersistentVolume,
     csi: &CSIPersistentVolumeSource,
-    staging_path: &Path,
+    client: &kube::Client,
+    pr: Option<Arc<PluginRegistry>>,
+    volume_dir: &Path,
+    path: &Path,
 ) -> anyhow::Result<()> {
-    // TODO: grab the publish_context and volume_context using the volume attachments API
-    csi_client
-        .node_stage_volume(NodeStageVolumeRequest {
-            volume_id: csi.volume_handle.clone(),
-            staging_target_path: staging_path.to_string_lossy().to_string(),
-            volume_capability: Some(VolumeCapability {
-                // TODO: determine the correct access mode and mount flags from the volume
-                // https://github.com/kubernetes/kubernetes/blob/734889ed822d1a60c6dd61ccd8f1ed0e8ab31ea5/pkg/volume/csi/csi_attacher.go#L325-L333
-                access_mode: Some(CSIAccessMode {
-                    mode: CSIMode::SingleNodeWriter as i32,
-                }),
-                access_type: Some(CSIAccessType::Mount(CSIMountVolume {
-                    fs_type: csi.fs_type.as_ref().map_or("".to_owned(), |s| s.clone()),
-                    mount_flags: Default::default(),
-                })),
-            }),
-            secrets: Default::default(),
-            publish_context: Default::default(),
-            volume_context: Default::default(),
-        })
-        .await?;
-    Ok(())
+    let plugin_registry = pr.ok_or_else(|| {
+        anyhow::anyhow!(
+            "failed to mount volume {}: CSI driver support not implemented",
+            &pvc_source.claim_name
+        )
+    })?;
+    let read_only = pvc_source.read_only.unwrap_or(false);
+    super::csi::publish(
+        client,
+        pv,
+        csi,
+        read_only,
+        &plugin_registry,
+        volume_dir,
+        path,
+    )
+    .await
 }
 
-async fn publish_volume(
-    csi_client: &mut NodeClient<tonic::transport::Channel>,
+#[cfg(feature = "csi")]
+async fn unpopulate_csi(
+    pvc_source: &PersistentVolumeClaimVolumeSource,
     csi: &CSIPersistentVolumeSource,
-    staging_path: &Path,
-    stage_unstage_volume: bool,
-    path: &PathBuf,
+    pr: Option<Arc<PluginRegistry>>,
+    volume_dir: &Path,
+    path: &Path,
 ) -> anyhow::Result<()> {
-    let mut req = NodePublishVolumeRequest {
-        volume_id: csi.volume_handle.clone(),
-        target_path: path.to_string_lossy().to_string(),
-        staging_target_path: "".to_owned(),
-        volume_capability: Some(VolumeCapability {
-            // TODO: determine the correct access mode and mount flags from the volume
-            // https://github.com/kubernetes/kubernetes/blob/734889ed822d1a60c6dd61ccd8f1ed0e8ab31ea5/pkg/volume/csi/csi_attacher.go#L325-L333
-            access_mode: Some(CSIAccessMode {
-                mode: CSIMode::SingleNodeWriter as i32,
-            }),
-            access_type: Some(CSIAccessType::Mount(CSIMountVolume {
-                fs_type: csi.fs_type.clone().unwrap_or_default(),
-                mount_flags: Default::default(),
-            })),
-        }),
-        // hardcode to read/write for now
-        // TODO: determine the correct access mode and mount flags from the volume
-        // https://github.com/kubernetes/kubernetes/blob/734889ed822d1a60c6dd61ccd8f1ed0e8ab31ea5/pkg/volume/csi/csi_attacher.go#L325-L333
-        readonly: false,
-        secrets: Default::default(),
-        publish_context: Default::default(),
-        volume_context: Default::default(),
-    };
-    if stage_unstage_volume {
-        req.staging_target_path = staging_path.to_string_lossy().to_string();
-    }
-    csi_client.node_publish_volume(req).await?;
-    Ok(())
+    let plugin_registry = pr.ok_or_else(|| {
+        anyhow::anyhow!(
+            "failed to unmount volume {}: CSI driver support not implemented",
+            &pvc_source.claim_name
+        )
+    })?;
+    super::csi::unpublish(csi, &plugin_registry, volume_dir, path).await
 }
 
-async fn unpublish_volume(
-    csi_client: &mut NodeClient<tonic::transport::Channel>,
+#[cfg(not(feature = "csi"))]
+async fn populate_csi(
+    pvc_source: &PersistentVolumeClaimVolumeSource,
+    _pv: &PersistentVolume,
     csi: &CSIPersistentVolumeSource,
-    path: &PathBuf,
+    _client: &kube::Client,
+    _pr: Option<Arc<PluginRegistry>>,
+    _volume_dir: &Path,
+    _path: &Path,
 ) -> anyhow::Result<()> {
-    let req = NodeUnpublishVolumeRequest {
-        volume_id: csi.volume_handle.clone(),
-        target_path: path.to_string_lossy().to_string(),
-    };
-    csi_client.node_unpublish_volume(req).await?;
-    Ok(())
+    Err(anyhow::anyhow!(
+        "failed to mount volume {}: the volume is provisioned by CSI driver {}, and this kubelet was built without the csi feature",
+        &pvc_source.claim_name,
+        csi.driver
+    ))
 }
 
-async fn get_csi_client(
-    client: &kube::Client,
-    spec: &PersistentVolumeClaimSpec,
-    plugin_registry: Arc<PluginRegistry>,
-) -> anyhow::Result<NodeClient<tonic::transport::Channel>> {
-    let storage_class_client: Api<StorageClass> = Api::all(client.clone());
-    // NOTE(bacongobbler): should be safe to unwrap() here after calling
-    // validate(). Storage class names are required as we do not define
-    // a default storage class.
-    //
-    // If we do implement a default storage class, then we'll have to
-    // revisit this assumption (omission of this field implies the
-    // default).
-    //
-    // https://kubernetes.io/docs/concepts/storage/persistent-volumes/#class-1
-    let storage_class = storage_class_client
-        .get(spec.storage_class_name.as_ref().unwrap())
-        .await?;
-    let endpoint = plugin_registry
-        .get_endpoint(&storage_class.provisioner)
-        .await
-        .ok_or_else(|| anyhow::anyhow!("could not get CSI plugin endpoint"))?;
-    let chan = grpc_sock::client::socket_channel(endpoint).await?;
-    Ok(NodeClient::new(chan))
+#[cfg(not(feature = "csi"))]
+async fn unpopulate_csi(
+    _pvc_source: &PersistentVolumeClaimVolumeSource,
+    _csi: &CSIPersistentVolumeSource,
+    _pr: Option<Arc<PluginRegistry>>,
+    _volume_dir: &Path,
+    _path: &Path,
+) -> anyhow::Result<()> {
+    // The volume can't have been published
+    Ok(())
 }
 
-async fn get_csi(
-    client: &kube::Client,
+/// Returns the PersistentVolume the claim is bound to
+async fn get_bound_volume(
     pvc_source: &PersistentVolumeClaimVolumeSource,
-    spec: &PersistentVolumeClaimSpec,
-) -> anyhow::Result<CSIPersistentVolumeSource> {
+    client: &kube::Client,
+    namespace: &str,
+) -> anyhow::Result<PersistentVolume> {
+    let pvc_client: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
+
+    let pvc = pvc_client.get(&pvc_source.claim_name).await?;
+    let phase = pvc
+        .status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref());
+    if phase != Some("Bound") {
+        return Err(anyhow::anyhow!(
+            "PersistentVolumeClaim {} is not bound (phase {})",
+            pvc_source.claim_name,
+            phase.unwrap_or("unknown")
+        ));
+    }
+    let spec = match pvc.spec {
+        Some(s) => s,
+        None => {
+            return Err(anyhow::anyhow!("PersistentVolumeClaim must specify a spec"));
+        }
+    };
+    validate(&spec)?;
+
     let volume_name = spec.volume_name.as_ref().ok_or(anyhow::anyhow!(format!(
         "volume name for PVC {} must exist",
         pvc_source.claim_name
@@ -395,31 +500,91 @@ async fn get_csi(
     // bound to the PVC.
     // https://kubernetes.io/docs/concepts/storage/persistent-volumes/#class-1
     let pv_client: Api<PersistentVolume> = Api::all(client.clone());
-    let pv = pv_client.get(&volume_name).await?;
-
-    // https://github.com/kubernetes/kubernetes/blob/734889ed822d1a60c6dd61ccd8f1ed0e8ab31ea5/pkg/volume/csi/csi_attacher.go#L295-L298
-    let csi = pv
-        .spec
-        .ok_or_else(|| anyhow::anyhow!("no PersistentVolume spec defined"))?
-        .csi
-        .ok_or_else(|| anyhow::anyhow!("no CSI spec defined"))?;
-    Ok(csi)
+    let pv = pv_client.get(volume_name).await?;
+    Ok(pv)
 }
 
-async fn get_pvc_spec(
-    pvc_source: &PersistentVolumeClaimVolumeSource,
-    client: &kube::Client,
-    namespace: &str,
-) -> anyhow::Result<PersistentVolumeClaimSpec> {
-    let pvc_client: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
-
-    let pvc = pvc_client.get(&pvc_source.claim_name).await?;
-    let spec = match pvc.spec {
-        Some(s) => s,
-        None => {
-            return Err(anyhow::anyhow!("PersistentVolumeClaim must specify a spec"));
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{NFSVolumeSource, PersistentVolumeSpec};
+    use kube::api::ObjectMeta;
+
+    fn pv(spec: PersistentVolumeSpec) -> PersistentVolume {
+        PersistentVolume {
+            metadata: ObjectMeta {
+                name: Some("data".to_owned()),
+                ..Default::default()
+            },
+            spec: Some(spec),
+            ..Default::default()
         }
-    };
-    validate(&spec)?;
-    Ok(spec)
+    }
+
+    #[test]
+    fn unsupported_sources_are_named() {
+        let nfs = pv(PersistentVolumeSpec {
+            storage_class_name: Some("nfs".to_owned()),
+            nfs: Some(NFSVolumeSource {
+                path: "/exports".to_owned(),
+                server: "nfs.local".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let error = Source::of(&nfs).err().unwrap().to_string();
+        assert!(error.contains("unsupported source nfs"), "{}", error);
+
+        let local = pv(PersistentVolumeSpec {
+            local: Some(LocalVolumeSource {
+                path: "/mnt/disks/ssd1".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let source = Source::of(&local).unwrap();
+        assert_eq!(
+            source.unique_name("data"),
+            "kubernetes.io/local-volume/data"
+        );
+    }
+
+    #[test]
+    fn read_write_once_volumes_are_used_by_one_pod_at_a_time() {
+        let volume = "kubernetes.io/host-path/rwo-test";
+        claim(volume, "pod-a", true).unwrap();
+        // The same pod can mount the volume again
+        claim(volume, "pod-a", true).unwrap();
+        assert!(claim(volume, "pod-b", true).is_err());
+        assert!(volumes_in_use().contains(&volume.to_owned()));
+
+        release("pod-a");
+        assert!(!volumes_in_use().contains(&volume.to_owned()));
+        claim(volume, "pod-b", true).unwrap();
+        release("pod-b");
+
+        let shared = "kubernetes.io/host-path/rwx-test";
+        claim(shared, "pod-c", false).unwrap();
+        claim(shared, "pod-d", false).unwrap();
+        release("pod-c");
+        assert!(volumes_in_use().contains(&shared.to_owned()));
+        release("pod-d");
+    }
+
+    #[test]
+    fn only_volumes_with_just_read_write_once_are_exclusive() {
+        let with_modes = |modes: &[&str]| {
+            pv(PersistentVolumeSpec {
+                access_modes: Some(modes.iter().map(|m| m.to_string()).collect()),
+                ..Default::default()
+            })
+        };
+        assert!(is_read_write_once(&with_modes(&["ReadWriteOnce"])));
+        assert!(!is_read_write_once(&with_modes(&[
+            "ReadWriteOnce",
+            "ReadOnlyMany"
+        ])));
+        assert!(!is_read_write_once(&with_modes(&["ReadWriteMany"])));
+        assert!(!is_read_write_once(&with_modes(&[])));
+    }
 }
@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use k8s_openapi::api::authentication::v1::{BoundObjectReference, TokenRequest, TokenRequestSpec};
+use k8s_openapi::api::core::v1::{ProjectedVolumeSource, ServiceAccountTokenProjection};
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+use super::object_watch::MountedObject;
+use super::*;
+
+/// How long tokens are requested for if the projection doesn't say, which is
+/// the same default as the API server's
+const DEFAULT_EXPIRATION_SECONDS: i64 = 60 * 60;
+/// How long to wait before trying again when a token can't be refreshed
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Writes the files of all the projected sources into one directory at
+/// `path`. Tokens are kept fresh for as long as the returned tasks run, which
+/// is until the volume is dropped. If `in_memory` is set, volumes with
+/// secrets or tokens are backed by memory.
+pub(crate) async fn populate(
+    projected: &ProjectedVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &PathBuf,
+    in_memory: bool,
+) -> anyhow::Result<(VolumeType, Vec<JoinHandle<()>>)> {
+    if in_memory && holds_secrets(projected) {
+        secret::mount_in_memory(path).await?;
+    } else {
+        tokio::fs::create_dir_all(path).await?;
+    }
+    let result = write_sources(projected, pod, client, path).await;
+    if result.is_err() {
+        secret::unmount_in_memory(path).ok();
+    }
+    result
+}
+
+/// Whether any of the projected sources are secrets or tokens
+fn holds_secrets(projected: &ProjectedVolumeSource) -> bool {
+    projected
+        .sources
+        .iter()
+        .any(|source| source.secret.is_some() || source.service_account_token.is_some())
+}
+
+async fn write_sources(
+    projected: &ProjectedVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+) -> anyhow::Result<(VolumeType, Vec<JoinHandle<()>>)> {
+    let default_mode = projected.default_mode.unwrap_or(DEFAULT_MODE);
+    let mut refresh_tasks = vec![];
+    for source in &projected.sources {
+        if let Some(projection) = &source.service_account_token {
+            let token = ServiceAccountToken::new(projection, pod, client.clone(), path);
+            let lifetime = token.write().await?;
+            refresh_tasks.push(tokio::spawn(token.refresh(lifetime)));
+        } else if let Some(cm) = &source.config_map {
+            let name = cm
+                .name
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?;
+            let cm_client: Api<ConfigMap> = Api::namespaced(client.clone(), pod.namespace());
+            if let Some(config_map) = get_optional(&cm_client, name, cm.optional).await? {
+                write_files(
+                    path,
+                    config_map.files(&cm.items, default_mode, cm.optional)?,
+                )
+                .await?;
+            }
+        } else if let Some(s) = &source.secret {
+            let name = s
+                .name
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?;
+            let secret_client: Api<Secret> = Api::namespaced(client.clone(), pod.namespace());
+            if let Some(secret) = get_optional(&secret_client, name, s.optional).await? {
+                write_files(path, secret.files(&s.items, default_mode, s.optional)?).await?;
+            }
+        } else if let Some(downward_api) = &source.downward_api {
+            let items = downward_api.items.as_deref().unwrap_or_default();
+            downwardapi::populate_items(items, projected.default_mode, pod, client, path).await?;
+        } else {
+            return Err(anyhow::anyhow!(
+                "Unsupported projected volume source. Currently supported sources: ServiceAccountToken, ConfigMap, Secret, and DownwardAPI"
+            ));
+        }
+    }
+    Ok((VolumeType::Projected, refresh_tasks))
+}
+
+/// A token for the pod's service account, projected into a file
+struct ServiceAccountToken {
+    client: kube::Client,
+    namespace: String,
+    service_account: String,
+    request: TokenRequest,
+    file_path: PathBuf,
+}
+
+impl ServiceAccountToken {
+    fn new(
+        projection: &ServiceAccountTokenProjection,
+        pod: &Pod,
+        client: kube::Client,
+        path: &Path,
+    ) -> Self {
+        // The token is bound to the pod, so that it stops being valid once
+        // the pod is deleted even if it hasn't expired
+        let request = TokenRequest {
+            spec: TokenRequestSpec {
+                audiences: projection.audience.iter().cloned().collect(),
+                bound_object_ref: Some(BoundObjectReference {
+                    api_version: Some("v1".to_owned()),
+                    kind: Some("Pod".to_owned()),
+                    name: Some(pod.name().to_owned()),
+                    uid: pod.as_kube_pod().metadata.uid.clone(),
+                }),
+                expiration_seconds: Some(
+                    projection
+                        .expiration_seconds
+                        .unwrap_or(DEFAULT_EXPIRATION_SECONDS),
+                ),
+            },
+            ..Default::default()
+        };
+        ServiceAccountToken {
+            client,
+            namespace: pod.namespace().to_owned(),
+            service_account: pod.service_account_name().unwrap_or("default").to_owned(),
+            request,
+            file_path: path.join(&projection.path),
+        }
+    }
+
+    /// Requests a new token and writes it to the file, returning how long it
+    /// is valid for
+    async fn write(&self) -> anyhow::Result<Duration> {
+        let request = http::Request::post(format!(
+            "/api/v1/namespaces/{}/serviceaccounts/{}/token",
+            self.namespace, self.service_account
+        ))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&self.request)?)?;
+        let requested_at = chrono::Utc::now();
+        let response: TokenRequest = self.client.request(request).await?;
+        let status = response.status.ok_or_else(|| {
+            anyhow::anyhow!(
+                "token request for {} returned no token",
+                self.service_account
+            )
+        })?;
+
+        if let Some(dir) = self.file_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // The token is replaced in one go, so that it is never read half
+        // written
+        let mut temp_path = self.file_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, status.token).await?;
+        // Only the pod's user may read the token
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+        tokio::fs::rename(&temp_path, &self.file_path).await?;
+        debug!(
+            "Wrote token for service account {} to {:?}",
+            self.service_account, self.file_path
+        );
+
+        Ok((status.expiration_timestamp.0 - requested_at)
+            .to_std()
+            .unwrap_or_default())
+    }
+
+    /// Replaces the token before it expires, for as long as the task runs
+    async fn refresh(self, mut lifetime: Duration) {
+        loop {
+            tokio::time::sleep(refresh_delay(lifetime)).await;
+            lifetime = match self.write().await {
+                Ok(lifetime) => lifetime,
+                Err(e) => {
+                    error!(
+                        "Unable to refresh token for service account {} at {:?}: {:?}",
+                        self.service_account, self.file_path, e
+                    );
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    Duration::default()
+                }
+            };
+        }
+    }
+}
+
+/// How long to wait before replacing a token that is valid for `lifetime`.
+/// Like other kubelets, tokens are replaced once 80% of their lifetime has
+/// passed, so that there is time to try again if the request fails.
+fn refresh_delay(lifetime: Duration) -> Duration {
+    lifetime.mul_f64(0.8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_are_refreshed_before_they_expire() {
+        assert_eq!(
+            refresh_delay(Duration::from_secs(3600)),
+            Duration::from_secs(2880)
+        );
+        assert_eq!(refresh_delay(Duration::default()), Duration::default());
+    }
+}
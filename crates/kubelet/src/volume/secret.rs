@@ -1,30 +1,232 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use k8s_openapi::api::core::v1::{KeyToPath, Secret};
+use k8s_openapi::api::core::v1::{Secret, SecretVolumeSource};
 use k8s_openapi::ByteString;
+use tokio::task::JoinHandle;
 
+use super::object_watch::{self, MountedObject};
 use super::*;
 
+lazy_static::lazy_static! {
+    /// The volumes whose secrets are kept in memory, which are zeroized and
+    /// unmounted if the node shuts down before their pods are gone
+    static ref IN_MEMORY: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+fn in_memory() -> std::sync::MutexGuard<'static, HashSet<PathBuf>> {
+    IN_MEMORY
+        .lock()
+        .expect("in-memory secret volumes lock should not be poisoned")
+}
+
+/// Writes the files of the Secret of a volume into the directory at `path`,
+/// which are kept up to date with it for as long as the returned tasks run.
+/// If `in_memory` is set, the directory is backed by memory. The directory
+/// is left empty if the Secret doesn't exist and the volume is optional.
 pub(crate) async fn populate(
-    secret: Secret,
-    path: &PathBuf,
-    items: &Option<Vec<KeyToPath>>,
-) -> anyhow::Result<VolumeType> {
-    tokio::fs::create_dir_all(path).await?;
-    let data = secret.data.unwrap_or_default();
-    let data = data.into_iter().map(|(key, ByteString(data))| async move {
-        match mount_setting_for(&key, items) {
-            ItemMount::MountAt(mount_path) => {
-                let file_path = path.join(mount_path);
-                tokio::fs::write(file_path, &data).await
-            }
-            ItemMount::DoNotMount => Ok(()),
+    source: &SecretVolumeSource,
+    pod: &Pod,
+    client: &kube::Client,
+    path: &Path,
+    in_memory: bool,
+) -> anyhow::Result<(VolumeType, Vec<JoinHandle<()>>)> {
+    let name = source
+        .secret_name
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?;
+    let secret_client: Api<Secret> = Api::namespaced(client.clone(), pod.namespace());
+    let secret = get_optional(&secret_client, name, source.optional).await?;
+    let default_mode = source.default_mode.unwrap_or(DEFAULT_MODE);
+    if in_memory {
+        mount_in_memory(path).await?;
+    }
+    let secret = match secret {
+        Some(secret) => secret,
+        None => {
+            tokio::fs::create_dir_all(path).await?;
+            return Ok((VolumeType::Secret, vec![]));
+        }
+    };
+    let mounted = object_watch::mount(
+        secret,
+        &source.items,
+        default_mode,
+        source.optional,
+        pod,
+        client,
+        path,
+    )
+    .await;
+    match mounted {
+        Ok(refresh_tasks) => Ok((VolumeType::Secret, refresh_tasks)),
+        Err(e) => {
+            unmount_in_memory(path).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Backs the directory at `path` with memory, so that the secrets written
+/// into it never reach the node's disk. If no tmpfs can be mounted, the
+/// volume fails rather than being written to disk.
+pub(crate) async fn mount_in_memory(path: &Path) -> anyhow::Result<()> {
+    tmpfs::mount(path, None).await.map_err(|e| {
+        anyhow::anyhow!(
+            "unable to keep the secrets of the volume in memory, and they are not written to disk: {}",
+            e
+        )
+    })?;
+    in_memory().insert(path.to_owned());
+    Ok(())
+}
+
+/// Overwrites the secrets in the directory at `path` with zeros and unmounts
+/// it, if it is backed by memory
+pub(crate) fn unmount_in_memory(path: &Path) -> anyhow::Result<()> {
+    in_memory().remove(path);
+    if !tmpfs::is_recorded(path) {
+        return Ok(());
+    }
+    if path.is_dir() {
+        tmpfs::zeroize(path)?;
+    }
+    tmpfs::unmount(path)
+}
+
+/// Zeroizes and unmounts all the volumes whose secrets are kept in memory,
+/// for when the node shuts down
+pub(crate) fn unmount_all_in_memory() {
+    let paths: Vec<PathBuf> = in_memory().drain().collect();
+    for path in paths {
+        unmount_in_memory(&path).unwrap_or_else(|e| {
+            error!(
+                "unable to unmount in-memory secret volume {:?}: {:?}",
+                path, e
+            )
+        });
+    }
+}
+
+impl MountedObject for Secret {
+    fn is_immutable(&self) -> bool {
+        self.immutable.unwrap_or(false)
+    }
+
+    fn data(&self) -> BTreeMap<&str, Vec<u8>> {
+        // Secret data is written as it is, whether or not it is text
+        self.data
+            .iter()
+            .flatten()
+            .map(|(key, ByteString(data))| (key.as_str(), data.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::volume::atomic_writer;
+    use k8s_openapi::api::core::v1::KeyToPath;
+
+    fn secret() -> Secret {
+        Secret {
+            data: Some(
+                vec![
+                    ("username".to_owned(), ByteString(b"admin".to_vec())),
+                    (
+                        "keystore".to_owned(),
+                        ByteString(vec![0, 159, 146, 150, 255]),
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn item(key: &str, path: &str, mode: Option<i32>) -> KeyToPath {
+        KeyToPath {
+            key: key.to_owned(),
+            path: path.to_owned(),
+            mode,
         }
-    });
-    futures::future::join_all(data)
-        .await
-        .into_iter()
-        .collect::<tokio::io::Result<_>>()?;
+    }
+
+    #[tokio::test]
+    async fn items_are_written_verbatim_with_their_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let items = Some(vec![
+            item("username", "user", None),
+            item("keystore", "tls/keystore.jks", Some(0o400)),
+        ]);
+        let files = secret().files(&items, 0o640, None).unwrap();
+        atomic_writer::write(dir.path().to_owned(), files)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(dir.path().join("user")).unwrap(), b"admin");
+        assert_eq!(
+            std::fs::read(dir.path().join("tls/keystore.jks")).unwrap(),
+            vec![0, 159, 146, 150, 255]
+        );
+        assert!(!dir.path().join("username").exists());
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &str| {
+                std::fs::metadata(dir.path().join(path))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o777
+            };
+            assert_eq!(mode("user"), 0o640);
+            assert_eq!(mode("tls/keystore.jks"), 0o400);
+        }
+    }
+
+    #[test]
+    fn missing_keys_fail_unless_optional() {
+        let items = Some(vec![
+            item("username", "user", None),
+            item("password", "password", None),
+        ]);
+        assert!(secret().files(&items, DEFAULT_MODE, None).is_err());
+        let files = secret().files(&items, DEFAULT_MODE, Some(true)).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("user"));
+    }
+
+    #[test]
+    fn item_paths_must_stay_in_the_volume() {
+        for path in &["../user", "/etc/user", "tls/../../user"] {
+            let items = Some(vec![item("username", path, None)]);
+            assert!(secret().files(&items, DEFAULT_MODE, None).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn secrets_are_not_written_to_disk_when_no_tmpfs_can_be_mounted() {
+        let dir = tempfile::tempdir().unwrap();
+        // The volume's directory can't be made where there's a file, so no
+        // tmpfs can be mounted for it
+        let path = dir.path().join("creds");
+        std::fs::write(&path, b"").unwrap();
+        assert!(mount_in_memory(&path).await.is_err());
+        assert!(!tmpfs::is_recorded(&path));
+        assert!(!in_memory().contains(&path));
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+    }
 
-    Ok(VolumeType::Secret)
+    #[test]
+    fn unrecorded_volumes_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("creds");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("password"), b"hunter2").unwrap();
+        unmount_in_memory(&path).unwrap();
+        assert_eq!(std::fs::read(path.join("password")).unwrap(), b"hunter2");
+    }
 }
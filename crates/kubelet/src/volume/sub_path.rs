@@ -0,0 +1,317 @@
+//! Resolves the `subPath` and `subPathExpr` of volume mounts, which mount a
+//! path inside a volume rather than the whole volume.
+//!
+//! A sub path can be a single file, such as one key of a ConfigMap, which is
+//! mounted at the mount's path on its own. Providers that can only mount
+//! directories link such files into a directory of the container's with
+//! [`Ref::link_file`](super::Ref::link_file), and mount that at the parent of
+//! the mount's path instead.
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use k8s_openapi::api::core::v1::VolumeMount;
+
+/// The brief CamelCase reason given when a container can't be started
+/// because of how it is configured, such as a sub path outside its volume
+pub const CONFIG_ERROR: &str = "CreateContainerConfigError";
+
+/// The part of a volume that a mount mounts. A mount can have a `subPath` or
+/// a `subPathExpr` but not both, so once a mount's sub path has been read
+/// the two can't be confused.
+#[derive(Debug, PartialEq)]
+enum SubPath<'a> {
+    /// The whole volume
+    Volume,
+    /// A path inside the volume
+    Path(&'a str),
+    /// A path inside the volume, with references to the container's
+    /// variables
+    Expr(&'a str),
+}
+
+impl<'a> SubPath<'a> {
+    fn of(mount: &'a VolumeMount) -> anyhow::Result<Self> {
+        match (mount.sub_path.as_deref(), mount.sub_path_expr.as_deref()) {
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "volume mount {} must not set both subPath and subPathExpr",
+                mount.name
+            )),
+            (Some(sub_path), None) if !sub_path.is_empty() => Ok(SubPath::Path(sub_path)),
+            (None, Some(expr)) if !expr.is_empty() => Ok(SubPath::Expr(expr)),
+            _ => Ok(SubPath::Volume),
+        }
+    }
+}
+
+/// The path on the host to mount for `mount`, which is the volume at `root`
+/// or the path given by the mount's sub path inside it. A `subPathExpr` is
+/// expanded with the container's variables `env`. Directories of the sub
+/// path that don't exist are created if `writable`, as a container writes
+/// into them, while anything else must already be in the volume.
+pub(crate) async fn resolve(
+    root: &Path,
+    mount: &VolumeMount,
+    env: &HashMap<String, String>,
+    writable: bool,
+) -> anyhow::Result<PathBuf> {
+    let sub_path = match SubPath::of(mount)? {
+        SubPath::Volume => String::new(),
+        SubPath::Path(sub_path) => sub_path.to_owned(),
+        SubPath::Expr(expr) => expand(expr, env),
+    };
+    if sub_path.is_empty() {
+        return Ok(root.to_owned());
+    }
+    validate(Path::new(&sub_path))?;
+
+    let path = root.join(&sub_path);
+    if writable && tokio::fs::metadata(&path).await.is_err() {
+        tokio::fs::create_dir_all(&path).await?;
+    }
+    // Symlinks in the volume could still point outside of it, so the path
+    // they lead to is checked too
+    let resolved = tokio::fs::canonicalize(&path).await.map_err(|e| {
+        anyhow::anyhow!(
+            "sub path {:?} of volume mount {} can't be found: {}",
+            sub_path,
+            mount.name,
+            e
+        )
+    })?;
+    let resolved_root = tokio::fs::canonicalize(root).await?;
+    if !resolved.starts_with(resolved_root) {
+        return Err(anyhow::anyhow!(
+            "sub path {:?} of volume mount {} is outside of the volume",
+            sub_path,
+            mount.name
+        ));
+    }
+    Ok(path)
+}
+
+/// Links the file at `path` to `link`. The file is hard linked, so that
+/// writes to it are seen in the volume, and copied if it can't be, such as
+/// when it is on another filesystem, if `may_copy`. As with the bind mounts
+/// of other kubelets, the link keeps the file it was made for, and doesn't
+/// follow the updates of ConfigMaps and Secrets.
+pub(crate) async fn link_file(path: &Path, link: &Path, may_copy: bool) -> anyhow::Result<()> {
+    // The items of volumes whose files come from the cluster are symbolic
+    // links to their current version, which is what is linked
+    let path = tokio::fs::canonicalize(path).await?;
+    match tokio::fs::hard_link(&path, link).await {
+        Ok(()) => Ok(()),
+        Err(e) if !may_copy => Err(anyhow::anyhow!(
+            "unable to link file {:?}, which must not be copied: {}",
+            path,
+            e
+        )),
+        Err(_) => {
+            tokio::fs::copy(&path, link).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Checks that the sub path stays inside its volume
+fn validate(sub_path: &Path) -> anyhow::Result<()> {
+    if !sub_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!(
+            "sub path {:?} must be relative, and must not contain '..'",
+            sub_path
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces the `$(VAR)` references in `expr` with the values of the
+/// variables. As with other kubelets, references to variables that aren't
+/// defined are replaced with nothing, and `$$` escapes a `$`.
+pub(crate) fn expand(expr: &str, env: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(expr.len());
+    let mut rest = expr;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = escaped;
+            continue;
+        }
+        let reference = rest
+            .strip_prefix("$(")
+            .and_then(|r| r.find(')').map(|end| &r[..end]));
+        match reference {
+            Some(name) => {
+                if let Some(value) = env.get(name) {
+                    expanded.push_str(value);
+                }
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                expanded.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mount(sub_path: Option<&str>, sub_path_expr: Option<&str>) -> VolumeMount {
+        VolumeMount {
+            name: "data".to_owned(),
+            mount_path: "/data".to_owned(),
+            sub_path: sub_path.map(ToOwned::to_owned),
+            sub_path_expr: sub_path_expr.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sub_paths_outside_the_volume_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("volume");
+        std::fs::create_dir(&root).unwrap();
+        let env = HashMap::new();
+
+        for escape in &["../other", "logs/../../other", "/etc"] {
+            assert!(
+                resolve(&root, &mount(Some(escape), None), &env, true)
+                    .await
+                    .is_err(),
+                "{} should be rejected",
+                escape
+            );
+        }
+        assert!(!dir.path().join("other").exists());
+
+        #[cfg(target_family = "unix")]
+        {
+            std::os::unix::fs::symlink(dir.path(), root.join("link")).unwrap();
+            assert!(resolve(&root, &mount(Some("link"), None), &env, false)
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn sub_paths_can_mount_config_map_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = crate::volume::atomic_writer::File {
+            path: PathBuf::from("app.conf"),
+            content: b"level=debug".to_vec(),
+            mode: 0o644,
+        };
+        crate::volume::atomic_writer::write(dir.path().to_owned(), vec![file])
+            .await
+            .unwrap();
+
+        let path = resolve(
+            dir.path(),
+            &mount(Some("app.conf"), None),
+            &HashMap::new(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.path().join("app.conf"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "level=debug");
+
+        // Read-only volumes aren't changed for missing items
+        assert!(resolve(
+            dir.path(),
+            &mount(Some("missing"), None),
+            &HashMap::new(),
+            false
+        )
+        .await
+        .is_err());
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn linked_files_keep_the_item_they_were_linked_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let volume = dir.path().join("volume");
+        let item = |content: &[u8]| crate::volume::atomic_writer::File {
+            path: PathBuf::from("app.conf"),
+            content: content.to_vec(),
+            mode: 0o644,
+        };
+        crate::volume::atomic_writer::write(volume.clone(), vec![item(b"level=debug")])
+            .await
+            .unwrap();
+
+        let link = dir.path().join("app.conf");
+        link_file(&volume.join("app.conf"), &link, false)
+            .await
+            .unwrap();
+        assert!(!std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&link).unwrap(), "level=debug");
+
+        crate::volume::atomic_writer::write(volume.clone(), vec![item(b"level=info")])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&link).unwrap(), "level=debug");
+    }
+
+    #[tokio::test]
+    async fn sub_path_exprs_are_expanded_and_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = vec![("POD_NAME".to_owned(), "web-0".to_owned())]
+            .into_iter()
+            .collect();
+
+        let path = resolve(
+            dir.path(),
+            &mount(None, Some("logs/$(POD_NAME)")),
+            &env,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.path().join("logs/web-0"));
+        assert!(path.is_dir());
+
+        assert!(
+            resolve(dir.path(), &mount(Some("a"), Some("b")), &env, true)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn sub_paths_are_read_from_either_field() {
+        assert_eq!(SubPath::of(&mount(None, None)).unwrap(), SubPath::Volume);
+        assert_eq!(
+            SubPath::of(&mount(Some(""), None)).unwrap(),
+            SubPath::Volume
+        );
+        assert_eq!(
+            SubPath::of(&mount(Some("a"), None)).unwrap(),
+            SubPath::Path("a")
+        );
+        assert_eq!(
+            SubPath::of(&mount(None, Some("$(A)"))).unwrap(),
+            SubPath::Expr("$(A)")
+        );
+        assert!(SubPath::of(&mount(Some(""), Some(""))).is_err());
+    }
+
+    #[test]
+    fn expand_removes_undefined_and_leaves_escaped_references() {
+        let env = vec![("A".to_owned(), "1".to_owned())].into_iter().collect();
+        assert_eq!(expand("$(A)/$(B)/$$(A)/$/$(", &env), "1//$(A)/$/$(");
+        assert_eq!(expand("logs/$(B)", &env), "logs/");
+    }
+}
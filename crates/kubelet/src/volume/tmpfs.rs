@@ -0,0 +1,178 @@
+//! tmpfs mounts, which back volumes with memory instead of the node's disk.
+//!
+//! A tmpfs is recorded before it's mounted, so that it's unmounted when the
+//! volume is removed even if the kubelet stopped before the volume could be
+//! used.
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+/// Mounts a tmpfs at `path`, which holds at most `size_limit` bytes. A tmpfs
+/// left over from before the kubelet restarted is replaced, as the volume
+/// starts empty.
+pub(crate) async fn mount(path: &Path, size_limit: Option<u64>) -> anyhow::Result<()> {
+    unmount(path)?;
+    tokio::fs::create_dir_all(path).await?;
+    tokio::fs::write(record_path(path), path.to_string_lossy().as_bytes()).await?;
+    if let Err(e) = mount_tmpfs(path, size_limit) {
+        unmount(path).ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The file that records that a tmpfs is mounted at `path`, which is kept
+/// next to the volume, in the pod's volume directory
+fn record_path(path: &Path) -> PathBuf {
+    let mut record = path.as_os_str().to_owned();
+    record.push(".tmpfs");
+    PathBuf::from(record)
+}
+
+/// Whether a tmpfs is recorded for the volume at `path`
+pub(crate) fn is_recorded(path: &Path) -> bool {
+    record_path(path).exists()
+}
+
+/// Unmounts the tmpfs recorded for the volume at `path`, if any, and
+/// removes the record
+pub(crate) fn unmount(path: &Path) -> anyhow::Result<()> {
+    let record = record_path(path);
+    if !record.exists() {
+        return Ok(());
+    }
+    debug!("unmounting tmpfs at {:?}", path);
+    unmount_tmpfs(path)?;
+    match std::fs::remove_file(&record) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Overwrites the files in the directory at `path` with zeros, so that what
+/// they held isn't left in the memory the tmpfs frees. Symbolic links are
+/// not followed.
+pub(crate) fn zeroize(path: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            zeroize(&entry.path())?;
+        } else if file_type.is_file() {
+            let mut file = std::fs::OpenOptions::new().write(true).open(entry.path())?;
+            let len = file.metadata()?.len();
+            std::io::copy(&mut std::io::repeat(0).take(len), &mut file)?;
+            file.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn mount_tmpfs(path: &Path, size_limit: Option<u64>) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let target = CString::new(path.as_os_str().as_bytes())?;
+    let fstype = CString::new("tmpfs")?;
+    // Without a size, the tmpfs can use as much memory as the kernel allows
+    // by default, which is half of the node's memory
+    let options = CString::new(match size_limit {
+        Some(bytes) => format!("mode=0777,size={}", bytes),
+        None => "mode=0777".to_owned(),
+    })?;
+    let result = unsafe {
+        libc::mount(
+            fstype.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if result != 0 {
+        return Err(anyhow::anyhow!(
+            "unable to mount tmpfs at {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_tmpfs(path: &Path, _size_limit: Option<u64>) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "unable to mount tmpfs at {:?}: tmpfs is only supported on Linux",
+        path
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn unmount_tmpfs(path: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    // The record may have been written without the tmpfs being mounted, or
+    // the volume's directory may already be gone. A mounted tmpfs is on a
+    // device of its own.
+    let mounted = match (path.metadata(), path.parent().map(Path::metadata)) {
+        (Ok(volume), Some(Ok(parent))) => volume.dev() != parent.dev(),
+        _ => false,
+    };
+    if !mounted {
+        warn!("tmpfs at {:?} was not mounted", path);
+        return Ok(());
+    }
+    let target = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(anyhow::anyhow!(
+            "unable to unmount tmpfs at {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount_tmpfs(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recorded_mounts_are_removed_even_if_not_mounted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scratch");
+        std::fs::create_dir(&path).unwrap();
+        // As if the kubelet stopped between recording the tmpfs and mounting
+        // it
+        std::fs::write(record_path(&path), b"scratch").unwrap();
+        unmount(&path).unwrap();
+        assert!(!record_path(&path).exists());
+        // Volumes without a record are left alone
+        unmount(&path).unwrap();
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn files_are_overwritten_with_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(dir.path().join("token"), b"secret").unwrap();
+        std::fs::write(dir.path().join("nested/key"), b"hunter2").unwrap();
+        zeroize(dir.path()).unwrap();
+        assert_eq!(std::fs::read(dir.path().join("token")).unwrap(), vec![0; 6]);
+        assert_eq!(
+            std::fs::read(dir.path().join("nested/key")).unwrap(),
+            vec![0; 7]
+        );
+    }
+}
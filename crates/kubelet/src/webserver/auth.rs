@@ -0,0 +1,284 @@
+//! Authentication of Kubelet server clients with client certificates or
+//! bearer tokens
+//!
+//! The certificate chain is verified against the client CA during the TLS
+//! handshake. The identity of the client is then read from the certificate
+//! the same way the Kubernetes API server does: the subject common name is the
+//! user and the subject organizations are its groups. Clients without a
+//! certificate may instead send an `Authorization: Bearer` header with an
+//! OpenID Connect ID token, or with a token that the API server validates,
+//! such as a service account token.
+//!
+//! Authenticated requests may then need authorizing by the API server.
+
+use std::sync::Arc;
+
+use http::status::StatusCode;
+use tracing::{debug, warn};
+use warp::{Filter, Rejection};
+use yasna::TagClass;
+
+use super::x509::Certificate;
+use crate::auth::{
+    OidcAuthenticator, RequestAttributes, TokenReviewAuthenticator, UserInfo, WebhookAuthorizer,
+};
+
+/// The user that requests from clients that didn't authenticate are
+/// authorized as
+const ANONYMOUS_USER: &str = "system:anonymous";
+/// The group that requests from clients that didn't authenticate are
+/// authorized as
+const UNAUTHENTICATED_GROUP: &str = "system:unauthenticated";
+
+/// The OID of the common name attribute of a name
+const COMMON_NAME_OID: &[u64] = &[2, 5, 4, 3];
+/// The OID of the organization attribute of a name
+const ORGANIZATION_OID: &[u64] = &[2, 5, 4, 10];
+/// The OID of the subject alternative name extension
+const SUBJECT_ALT_NAME_OID: &[u64] = &[2, 5, 29, 17];
+
+/// The identity of a client, read from the certificate it authenticated with
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ClientIdentity {
+    /// The user, from the subject common name
+    pub user: String,
+    /// The groups of the user, from the subject organizations
+    pub groups: Vec<String>,
+    /// The DNS names, email addresses, URIs and IP addresses in the subject
+    /// alternative names
+    pub subject_alt_names: Vec<String>,
+}
+
+impl ClientIdentity {
+    /// Reads the identity from a DER encoded certificate. This doesn't verify
+    /// the certificate, which must already have been verified against the
+    /// client CA.
+    pub(crate) fn from_der(certificate: &[u8]) -> anyhow::Result<Self> {
+        let Certificate {
+            subject,
+            extensions,
+            ..
+        } = Certificate::from_der(certificate)
+            .map_err(|e| anyhow::anyhow!("unable to parse client certificate: {}", e))?;
+        let mut user = None;
+        let mut groups = vec![];
+        for (oid, value) in subject {
+            if oid.components().as_slice() == COMMON_NAME_OID {
+                user = Some(value);
+            } else if oid.components().as_slice() == ORGANIZATION_OID {
+                groups.push(value);
+            }
+        }
+        let user = user
+            .filter(|u| !u.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("client certificate has no subject common name"))?;
+        let subject_alt_names = extensions
+            .into_iter()
+            .find(|(oid, _)| oid.components().as_slice() == SUBJECT_ALT_NAME_OID)
+            .map(|(_, value)| parse_subject_alt_names(&value))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("unable to parse client certificate names: {}", e))?
+            .unwrap_or_default();
+        Ok(ClientIdentity {
+            user,
+            groups,
+            subject_alt_names,
+        })
+    }
+}
+
+impl From<ClientIdentity> for UserInfo {
+    fn from(identity: ClientIdentity) -> Self {
+        UserInfo {
+            username: identity.user,
+            groups: identity.groups,
+        }
+    }
+}
+
+/// How clients may authenticate
+#[derive(Clone, Default)]
+pub(crate) struct Authenticators {
+    /// Whether requests from clients that didn't authenticate are rejected
+    pub required: bool,
+    /// Validates bearer tokens that are OpenID Connect ID tokens
+    pub oidc: Option<Arc<OidcAuthenticator>>,
+    /// Validates bearer tokens with the API server
+    pub token_review: Option<Arc<TokenReviewAuthenticator>>,
+}
+
+impl Authenticators {
+    /// Returns the user `token` belongs to, trying each authenticator in
+    /// turn, or `None` if bearer tokens aren't validated at all
+    async fn authenticate_token(&self, token: &str) -> Option<anyhow::Result<UserInfo>> {
+        let mut result = None;
+        if let Some(oidc) = &self.oidc {
+            result = Some(oidc.authenticate(token).await);
+        }
+        if let Some(token_review) = &self.token_review {
+            if !matches!(result, Some(Ok(_))) {
+                result = Some(token_review.authenticate(token).await);
+            }
+        }
+        result
+    }
+}
+
+/// A request without a valid client certificate or bearer token
+#[derive(Debug)]
+struct Unauthenticated;
+
+impl warp::reject::Reject for Unauthenticated {}
+
+/// Extracts the user the client authenticated as, if it authenticated.
+/// Bearer tokens are validated with `authenticators`, and are ignored if
+/// none of them validate tokens. Requests with invalid bearer tokens are
+/// rejected, as are requests from clients that didn't authenticate if
+/// authentication is required, and should be answered with
+/// [`recover_unauthenticated`].
+pub(crate) fn authenticate(
+    authenticators: Authenticators,
+) -> impl Filter<Extract = (Option<UserInfo>,), Error = Rejection> + Clone {
+    warp::ext::optional::<ClientIdentity>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |identity: Option<ClientIdentity>, authorization: Option<String>| {
+                let authenticators = authenticators.clone();
+                async move {
+                    let token = authorization
+                        .as_deref()
+                        .and_then(|value| value.strip_prefix("Bearer "));
+                    let user = match (identity, token) {
+                        (Some(identity), _) => Some(identity.into()),
+                        (None, Some(token)) => {
+                            match authenticators.authenticate_token(token.trim()).await {
+                                Some(Ok(user)) => Some(user),
+                                Some(Err(e)) => {
+                                    debug!("Rejecting bearer token: {:?}", e);
+                                    return Err(warp::reject::custom(Unauthenticated));
+                                }
+                                None => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    if authenticators.required && user.is_none() {
+                        Err(warp::reject::custom(Unauthenticated))
+                    } else {
+                        Ok(user)
+                    }
+                }
+            },
+        )
+}
+
+/// A request that the user isn't allowed to make, with the reason the API
+/// server gave
+#[derive(Debug)]
+struct Forbidden(RequestAttributes, Option<String>);
+
+impl warp::reject::Reject for Forbidden {}
+
+/// A request that couldn't be authorized
+#[derive(Debug)]
+struct AuthorizationFailed;
+
+impl warp::reject::Reject for AuthorizationFailed {}
+
+/// Authenticates requests like [`authenticate`], then authorizes them with
+/// `authorizer`, if it is set. Requests that aren't allowed are rejected, and
+/// should be answered with [`recover_unauthenticated`].
+pub(crate) fn authorize(
+    authenticators: Authenticators,
+    authorizer: Option<Arc<WebhookAuthorizer>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    authenticate(authenticators)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(
+            move |user: Option<UserInfo>, method: http::Method, path: warp::path::FullPath| {
+                let authorizer = authorizer.clone();
+                async move {
+                    let authorizer = match authorizer {
+                        Some(authorizer) => authorizer,
+                        None => return Ok(()),
+                    };
+                    let user = user.unwrap_or_else(|| UserInfo {
+                        username: ANONYMOUS_USER.to_owned(),
+                        groups: vec![UNAUTHENTICATED_GROUP.to_owned()],
+                    });
+                    let attributes = RequestAttributes::new(user, &method, path.as_str());
+                    match authorizer.authorize(&attributes).await {
+                        Ok(decision) if decision.allowed => Ok(()),
+                        Ok(decision) => {
+                            Err(warp::reject::custom(Forbidden(attributes, decision.reason)))
+                        }
+                        Err(e) => {
+                            warn!("Unable to authorize {:?}: {:?}", attributes, e);
+                            Err(warp::reject::custom(AuthorizationFailed))
+                        }
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Answers requests rejected by [`authenticate`] with 401 Unauthorized, and
+/// requests rejected by [`authorize`] with 403 Forbidden, or 500 Internal
+/// Server Error if they couldn't be authorized
+pub(crate) async fn recover_unauthenticated(
+    rejection: Rejection,
+) -> Result<http::Response<hyper::Body>, Rejection> {
+    if rejection.find::<Unauthenticated>().is_some() {
+        Ok(super::return_with_code(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized: a valid client certificate or bearer token is required".to_owned(),
+        ))
+    } else if let Some(Forbidden(attributes, reason)) = rejection.find() {
+        let mut message = format!(
+            "Forbidden (user={}, verb={}, resource=nodes, subresource={})",
+            attributes.user.username, attributes.verb, attributes.subresource
+        );
+        if let Some(reason) = reason {
+            message.push_str(": ");
+            message.push_str(reason);
+        }
+        Ok(super::return_with_code(StatusCode::FORBIDDEN, message))
+    } else if rejection.find::<AuthorizationFailed>().is_some() {
+        Ok(super::return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Authorization error".to_owned(),
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+fn parse_subject_alt_names(extension: &[u8]) -> yasna::ASN1Result<Vec<String>> {
+    let names = yasna::parse_der(extension, |r| {
+        r.collect_sequence_of(|r| r.read_tagged_der())
+    })?;
+    Ok(names
+        .into_iter()
+        .filter(|name| name.tag().tag_class == TagClass::ContextSpecific)
+        .filter_map(|name| match name.tag().tag_number {
+            // rfc822Name, dNSName and uniformResourceIdentifier
+            1 | 2 | 6 => Some(String::from_utf8_lossy(name.value()).into_owned()),
+            // iPAddress
+            7 => ip_address(name.value()).map(|ip| ip.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+fn ip_address(octets: &[u8]) -> Option<std::net::IpAddr> {
+    use std::convert::TryFrom;
+    match octets.len() {
+        4 => <[u8; 4]>::try_from(octets).ok().map(std::net::IpAddr::from),
+        16 => <[u8; 16]>::try_from(octets)
+            .ok()
+            .map(std::net::IpAddr::from),
+        _ => None,
+    }
+}
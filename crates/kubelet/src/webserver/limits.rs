@@ -0,0 +1,257 @@
+//! Limits on the streams the server keeps open.
+//!
+//! Followed logs, exec and attach sessions and forwarded ports keep their
+//! connection open for as long as the client wants, whichever provider
+//! serves them, so each kind of stream may only have so many open at once.
+//! Requests for more are answered straight away with 429 Too Many Requests
+//! and a `Retry-After` header, rather than waiting for a stream to close.
+//! How many of each kind are open is served with the resource metrics as
+//! `kubelet_active_streams`.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use http::status::StatusCode;
+use http::Response;
+use hyper::Body;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use super::return_with_code;
+use crate::config::ServerConfig;
+
+/// How many seconds clients that are refused a stream are told to wait
+/// before asking again
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// A kind of stream that the server limits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StreamKind {
+    /// Logs that are followed
+    LogFollow,
+    /// Exec and attach sessions
+    Exec,
+    /// Port forwarding connections
+    PortForward,
+}
+
+impl StreamKind {
+    /// The name the kind is labelled with in metrics
+    fn name(self) -> &'static str {
+        match self {
+            StreamKind::LogFollow => "log_follow",
+            StreamKind::Exec => "exec",
+            StreamKind::PortForward => "port_forward",
+        }
+    }
+}
+
+/// The streams of one kind that may be open
+#[derive(Clone)]
+struct Limit {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+}
+
+impl Limit {
+    fn new(max: usize) -> Self {
+        Limit {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.max - self.semaphore.available_permits()
+    }
+}
+
+/// The streams that may be open, which clones share
+#[derive(Clone)]
+pub(crate) struct StreamLimits {
+    log_follows: Limit,
+    exec_sessions: Limit,
+    port_forwards: Limit,
+}
+
+impl StreamLimits {
+    /// Creates limits allowing at most `max_log_follows` followed logs,
+    /// `max_exec_sessions` exec and attach sessions, and `max_port_forwards`
+    /// port forwarding connections
+    pub(crate) fn new(
+        max_log_follows: usize,
+        max_exec_sessions: usize,
+        max_port_forwards: usize,
+    ) -> Self {
+        StreamLimits {
+            log_follows: Limit::new(max_log_follows),
+            exec_sessions: Limit::new(max_exec_sessions),
+            port_forwards: Limit::new(max_port_forwards),
+        }
+    }
+
+    /// Creates the limits `config` sets
+    pub(crate) fn from_config(config: &ServerConfig) -> Self {
+        Self::new(
+            config.max_log_follows,
+            config.max_exec_sessions,
+            config.max_port_forwards,
+        )
+    }
+
+    fn limit(&self, kind: StreamKind) -> &Limit {
+        match kind {
+            StreamKind::LogFollow => &self.log_follows,
+            StreamKind::Exec => &self.exec_sessions,
+            StreamKind::PortForward => &self.port_forwards,
+        }
+    }
+
+    /// Opens a stream of `kind`, which stays open until the returned permit
+    /// is dropped. If as many are already open as are allowed, the request
+    /// should be answered with the response of the returned error instead.
+    pub(crate) fn acquire(&self, kind: StreamKind) -> Result<StreamPermit, TooManyStreams> {
+        let limit = self.limit(kind);
+        match limit.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(StreamPermit { _permit: permit }),
+            Err(_) => {
+                debug!(
+                    "Refusing {} stream: {} are already open",
+                    kind.name(),
+                    limit.max
+                );
+                Err(TooManyStreams {
+                    kind,
+                    max: limit.max,
+                })
+            }
+        }
+    }
+
+    /// How many streams of `kind` are open
+    pub(crate) fn active(&self, kind: StreamKind) -> usize {
+        self.limit(kind).active()
+    }
+
+    /// How many streams of each kind are open, in the Prometheus text format
+    pub(crate) fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP kubelet_active_streams Number of log follows, exec sessions and port forwards the kubelet server has open"
+        );
+        let _ = writeln!(out, "# TYPE kubelet_active_streams gauge");
+        for kind in &[
+            StreamKind::LogFollow,
+            StreamKind::Exec,
+            StreamKind::PortForward,
+        ] {
+            let _ = writeln!(
+                out,
+                "kubelet_active_streams{{stream=\"{}\"}} {}",
+                kind.name(),
+                self.active(*kind)
+            );
+        }
+        out
+    }
+}
+
+/// An open stream, which is closed when this is dropped
+#[derive(Debug)]
+pub(crate) struct StreamPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl StreamPermit {
+    /// Keeps the stream open until `body` has been sent, or the client has
+    /// gone
+    pub(crate) fn hold(self, body: Body) -> Body {
+        Body::wrap_stream(body.map(move |chunk| {
+            let _permit = &self;
+            chunk
+        }))
+    }
+}
+
+/// A refused request for a stream of `kind`, because `max` are open
+#[derive(Debug)]
+pub(crate) struct TooManyStreams {
+    kind: StreamKind,
+    max: usize,
+}
+
+impl TooManyStreams {
+    /// The answer to the refused request
+    pub(crate) fn into_response(self) -> Response<Body> {
+        let mut response = return_with_code(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "Too many requests: at most {} {} streams may be open at once",
+                self.max,
+                self.kind.name()
+            ),
+        );
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, RETRY_AFTER_SECONDS.into());
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streams_are_refused_once_the_limit_is_reached() {
+        let limits = StreamLimits::new(1, 2, 0);
+        let follow = limits.acquire(StreamKind::LogFollow).unwrap();
+        let refused = limits
+            .acquire(StreamKind::LogFollow)
+            .unwrap_err()
+            .into_response();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()[http::header::RETRY_AFTER], "5");
+
+        // Each kind has its own limit
+        let _exec = limits.acquire(StreamKind::Exec).unwrap();
+        let _attach = limits.acquire(StreamKind::Exec).unwrap();
+        assert!(limits.acquire(StreamKind::Exec).is_err());
+        assert!(limits.acquire(StreamKind::PortForward).is_err());
+
+        drop(follow);
+        assert!(limits.acquire(StreamKind::LogFollow).is_ok());
+    }
+
+    #[test]
+    fn active_streams_are_served_as_gauges() {
+        let limits = StreamLimits::new(4, 4, 4);
+        let _follows = (
+            limits.acquire(StreamKind::LogFollow).unwrap(),
+            limits.acquire(StreamKind::LogFollow).unwrap(),
+        );
+        let _forward = limits.acquire(StreamKind::PortForward).unwrap();
+        let metrics = limits.metrics();
+        assert!(metrics.contains("# TYPE kubelet_active_streams gauge\n"));
+        assert!(metrics.contains("kubelet_active_streams{stream=\"log_follow\"} 2\n"));
+        assert!(metrics.contains("kubelet_active_streams{stream=\"exec\"} 0\n"));
+        assert!(metrics.contains("kubelet_active_streams{stream=\"port_forward\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn held_bodies_keep_streams_open_until_they_are_sent() {
+        let limits = StreamLimits::new(1, 1, 1);
+        let permit = limits.acquire(StreamKind::LogFollow).unwrap();
+        let (mut sender, body) = Body::channel();
+        let body = permit.hold(body);
+        assert_eq!(limits.active(StreamKind::LogFollow), 1);
+
+        sender.send_data("line\n".into()).await.unwrap();
+        drop(sender);
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(sent, "line\n");
+        assert_eq!(limits.active(StreamKind::LogFollow), 0);
+    }
+}
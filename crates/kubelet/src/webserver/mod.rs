@@ -1,59 +1,389 @@
 //! Server is an HTTP(S) server for answering Kubelet callbacks.
 //!
-//! Logs and exec calls are the main things that a server should handle.
+//! Logs, exec and attach calls are the main things that a server should
+//! handle. The resource usage of the node and its pods is served at
+//! `/stats/summary`, as described in [`crate::stats`], and in the Prometheus
+//! text format at `/metrics/resource`. Exec and attach calls are streamed
+//! over WebSockets, as described in [`remotecommand`], as are forwarded
+//! ports, as described in [`portforward`].
+//!
+//! The health of the kubelet's components is served at `/healthz` and
+//! `/readyz`, as described in [`crate::health`]. The pods the kubelet is
+//! running are served at `/pods` as a `PodList`, each annotated with the
+//! state its state machine is in, for debugging.
+//!
+//! Unless anonymous requests are allowed, every request other than
+//! `/healthz` and `/readyz` must come from a client that authenticated with a certificate
+//! signed by the client CA, an ID token issued by the OpenID Connect
+//! provider, or a bearer token that the API server validates with a
+//! `TokenReview`. Other requests are answered with 401 Unauthorized. If
+//! webhook authorization is configured, requests are then only answered if
+//! the API server allows them, and are otherwise answered with 403 Forbidden
+//! and the reason the API server gave.
+//!
+//! Followed logs, exec and attach sessions and forwarded ports are limited
+//! as described in [`limits`], whichever provider serves them. Exec and
+//! attach sessions are also closed once they have gone without traffic for
+//! the configured idle timeout.
+//!
+//! If a read-only port is configured, `/healthz`, `/readyz`, `/pods`,
+//! `/stats/summary` and `/metrics/resource` are also served on it over plain
+//! HTTP, to every client.
 
+use crate::auth::{OidcAuthenticator, TokenReviewAuthenticator, WebhookAuthorizer};
 use crate::config::ServerConfig;
-use crate::log::{Options, Sender};
-use crate::provider::{NotImplementedError, Provider};
+use crate::health::HealthChecks;
+use crate::log::{LogOptions, Sender};
+use crate::node;
+use crate::pod::{PodKey, PodStore};
+use crate::provider::{NotImplementedError, Provider, ProviderError};
+use crate::stats::{StatsCollector, SummaryOptions, RESOURCE_METRICS_CONTENT_TYPE};
 use http::status::StatusCode;
 use http::Response;
 use hyper::Body;
+use limits::{StreamKind, StreamLimits, StreamPermit};
+use remotecommand::Operation;
 use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tracing::{debug, error};
 use warp::Filter;
 
+mod auth;
+mod limits;
+mod portforward;
+mod remotecommand;
+mod tls;
+mod x509;
+
+pub(crate) use tls::{
+    certificate_validity, tls_config, tls_config_with_certificate, SharedTlsConfig,
+};
+
 const PING: &str = "this is the Krustlet HTTP server";
 
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
+/// Connections use the TLS configuration in `tls_config` at the time they are
+/// accepted. Bearer tokens are validated with `token_review` if it is set,
+/// and requests are authorized with `authorizer` if it is set. `health` is
+/// run for `/healthz` and `/readyz`, and `pods` are listed at `/pods`.
+/// Streams are limited to the numbers `config` allows.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn start<T: Provider>(
     provider: Arc<T>,
     config: &ServerConfig,
+    tls_config: SharedTlsConfig,
+    health: HealthChecks,
+    pods: PodStore,
+    token_review: Option<TokenReviewAuthenticator>,
+    authorizer: Option<WebhookAuthorizer>,
+    stats: Arc<StatsCollector>,
 ) -> anyhow::Result<()> {
-    let health = warp::get().and(warp::path("healthz")).map(|| PING);
     let ping = warp::get().and(warp::path::end()).map(|| PING);
+    let limits = StreamLimits::from_config(config);
 
     let logs_provider = provider.clone();
+    let logs_limits = limits.clone();
     let logs = warp::get()
         .and(warp::path!("containerLogs" / String / String / String))
-        .and(warp::query::<Options>())
-        .and_then(move |namespace, pod, container, opts| {
+        .and(warp::query::<LogOptions>())
+        .and_then(move |namespace, pod, container, opts: LogOptions| {
             let provider = logs_provider.clone();
-            get_container_logs(provider, namespace, pod, container, opts)
+            // Only followed logs stay open, so only they are limited
+            let permit = if opts.follow {
+                logs_limits.acquire(StreamKind::LogFollow).map(Some)
+            } else {
+                Ok(None)
+            };
+            async move {
+                match permit {
+                    Ok(permit) => {
+                        get_container_logs(provider, namespace, pod, container, opts, permit).await
+                    }
+                    Err(too_many_streams) => Ok(too_many_streams.into_response()),
+                }
+            }
+        });
+
+    let summary_provider = provider.clone();
+    let resource_metrics_stats = stats.clone();
+    let summary = warp::get()
+        .and(warp::path!("stats" / "summary"))
+        .and(warp::query::<SummaryOptions>())
+        .and_then(move |opts| {
+            let provider = summary_provider.clone();
+            get_summary(provider, stats.clone(), opts)
         });
 
-    let exec_provider = provider.clone();
-    let exec = warp::post()
-        .and(warp::path!("exec" / String / String / String))
-        .and_then(move |namespace, pod, container| {
-            let provider = exec_provider.clone();
-            post_exec(provider, namespace, pod, container)
+    let resource_metrics_provider = provider.clone();
+    let resource_metrics_limits = limits.clone();
+    let resource_metrics = warp::get()
+        .and(warp::path!("metrics" / "resource"))
+        .and_then(move || {
+            let provider = resource_metrics_provider.clone();
+            get_resource_metrics(
+                provider,
+                resource_metrics_stats.clone(),
+                resource_metrics_limits.clone(),
+            )
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let pods = list_pods(pods);
 
-    warp::serve(routes)
-        .tls()
-        .cert_path(&config.cert_file)
-        .key_path(&config.private_key_file)
-        .run((config.addr, config.port))
-        .await;
+    let idle_timeout = config.streaming_connection_idle_timeout;
+    let exec = streaming(
+        Operation::Exec,
+        provider.clone(),
+        limits.clone(),
+        idle_timeout,
+    );
+    let attach = streaming(
+        Operation::Attach,
+        provider.clone(),
+        limits.clone(),
+        idle_timeout,
+    );
+    let port_forward = port_forward(provider.clone(), limits);
+
+    let oidc = config
+        .oidc
+        .as_ref()
+        .map(OidcAuthenticator::new)
+        .transpose()?
+        .map(Arc::new);
+    let authenticators = auth::Authenticators {
+        required: !config.anonymous_auth,
+        oidc,
+        token_review: token_review.map(Arc::new),
+    };
+    let read_only_routes = read_only(
+        pods.clone()
+            .or(summary.clone())
+            .or(resource_metrics.clone()),
+        health.clone(),
+    );
+    let routes = with_auth(
+        ping.or(logs)
+            .or(pods)
+            .or(summary)
+            .or(resource_metrics)
+            .or(exec)
+            .or(attach)
+            .or(port_forward),
+        health,
+        authenticators,
+        authorizer.map(Arc::new),
+    );
+
+    let service = warp::service(routes);
+    let servers = bind(config.addr, config.port)
+        .await?
+        .into_iter()
+        .map(|listener| tls::serve(listener, tls_config.clone(), service.clone()));
+    let read_only_listeners = match config.read_only_port {
+        0 => vec![],
+        port => bind(config.addr, port).await?,
+    };
+    let read_only_servers = async {
+        if read_only_listeners.is_empty() {
+            return futures::future::pending().await;
+        }
+        let servers = read_only_listeners.into_iter().map(|listener| {
+            warp::serve(read_only_routes.clone())
+                .run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+        });
+        futures::future::join_all(servers).await;
+    };
+    tokio::select! {
+        res = futures::future::try_join_all(servers) => {
+            res?;
+        }
+        _ = read_only_servers => (),
+    }
     Ok(())
 }
 
-/// Get the logs from the running container.
+/// Answers `/healthz`, `/readyz` and `routes` for every client, without
+/// authenticating them, as the read-only port does
+fn read_only<F, R>(
+    routes: F,
+    health: HealthChecks,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    health_checks(health).or(routes)
+}
+
+/// Answers `/healthz` and `/readyz` for every client, and `routes` for the
+/// clients `authenticators` authenticate that `authorizer` allows
+fn with_auth<F, R>(
+    routes: F,
+    health: HealthChecks,
+    authenticators: auth::Authenticators,
+    authorizer: Option<Arc<WebhookAuthorizer>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    health_checks(health)
+        .or(auth::authorize(authenticators, authorizer).and(routes))
+        .recover(auth::recover_unauthenticated)
+}
+
+/// The routes that run `checks` at `/healthz` and `/readyz`
+fn health_checks(
+    checks: HealthChecks,
+) -> impl Filter<Extract = (Response<Body>,), Error = warp::Rejection> + Clone {
+    let endpoint = warp::path("healthz")
+        .map(|| "healthz")
+        .or(warp::path("readyz").map(|| "readyz"))
+        .unify();
+    warp::get()
+        .and(endpoint)
+        .and(warp::path::end())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(move |endpoint: &'static str, query: String| {
+            let checks = checks.clone();
+            async move { Ok::<_, Infallible>(health(endpoint, &checks, &query).await) }
+        })
+}
+
+/// The route that lists `pods` at `/pods`
+fn list_pods(
+    pods: PodStore,
+) -> impl Filter<Extract = (Response<Body>,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("pods"))
+        .and(warp::path::end())
+        .map(move || get_pods(&pods))
+}
+
+/// Binds the server's listeners. Listening on the unspecified IPv6 address
+/// also listens on the unspecified IPv4 address, which is a separate listener
+/// unless the IPv6 one accepts IPv4 connections as v4-mapped addresses
+/// itself, as it does by default on Linux.
+async fn bind(addr: IpAddr, port: u16) -> std::io::Result<Vec<TcpListener>> {
+    let mut listeners = vec![TcpListener::bind((addr, port)).await?];
+    if addr == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                debug!("Listening on IPv4 through the IPv6 listener")
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(listeners)
+}
+
+/// The route that exec or attach requests are streamed over, at
+/// /{exec,attach}/{namespace}/{pod}/{container}, as long as `limits` allows
+/// another session. Sessions are closed after `idle_timeout` without traffic.
+fn streaming<T: Provider>(
+    operation: Operation,
+    provider: Arc<T>,
+    limits: StreamLimits,
+    idle_timeout: Duration,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let path = warp::path(operation.name())
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end());
+    let upgrade = path
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace: String,
+                  pod: String,
+                  container,
+                  query: String,
+                  protocols: Option<String>,
+                  ws| {
+                let permit = match limits.acquire(StreamKind::Exec) {
+                    Ok(permit) => permit,
+                    Err(too_many_streams) => return too_many_streams.into_response(),
+                };
+                remotecommand::upgrade(
+                    operation,
+                    provider.clone(),
+                    PodKey::new(namespace, pod),
+                    container,
+                    &query,
+                    protocols.as_deref(),
+                    ws,
+                    permit,
+                    idle_timeout,
+                )
+            },
+        );
+    // These are only streamed over WebSockets, which other clients, such as
+    // ones that only speak SPDY, are told
+    let without_websocket = path.map(move |_, _, _| {
+        return_with_code(
+            StatusCode::BAD_REQUEST,
+            format!("{} requires a WebSocket connection", operation.name()),
+        )
+    });
+    upgrade.or(without_websocket).unify()
+}
+
+/// The route that ports are forwarded over, at
+/// /portForward/{namespace}/{pod}, which may be followed by the pod's UID,
+/// as long as `limits` allows another connection
+fn port_forward<T: Provider>(
+    provider: Arc<T>,
+    limits: StreamLimits,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let uid = warp::path::param::<String>()
+        .map(|_| ())
+        .untuple_one()
+        .or(warp::any())
+        .unify();
+    let path = warp::path("portForward")
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(uid)
+        .and(warp::path::end());
+    let upgrade = path
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace: String, pod: String, query: String, protocols: Option<String>, ws| {
+                let permit = match limits.acquire(StreamKind::PortForward) {
+                    Ok(permit) => permit,
+                    Err(too_many_streams) => return too_many_streams.into_response(),
+                };
+                portforward::upgrade(
+                    provider.clone(),
+                    PodKey::new(namespace, pod),
+                    &query,
+                    protocols.as_deref(),
+                    ws,
+                    permit,
+                )
+            },
+        );
+    let without_websocket = path.map(|_, _| {
+        return_with_code(
+            StatusCode::BAD_REQUEST,
+            "port forwarding requires a WebSocket connection".to_owned(),
+        )
+    });
+    upgrade.or(without_websocket).unify()
+}
+
+/// Get the logs from the running container. Followed logs hold `permit`
+/// until they have been sent.
 ///
 /// Implements the kubelet path /containerLogs/{namespace}/{pod}/{container}
 async fn get_container_logs<T: Provider>(
@@ -61,7 +391,8 @@ async fn get_container_logs<T: Provider>(
     namespace: String,
     pod: String,
     container: String,
-    opts: Options,
+    opts: LogOptions,
+    permit: Option<StreamPermit>,
 ) -> Result<Response<Body>, Infallible> {
     debug!(
         "Got container log request for container {} in pod {} in namespace {}. Options: {:?}.",
@@ -71,7 +402,10 @@ async fn get_container_logs<T: Provider>(
     let log_sender = Sender::new(sender, opts);
 
     match provider.logs(namespace, pod, container, log_sender).await {
-        Ok(()) => Ok(Response::new(log_body)),
+        Ok(()) => match permit {
+            Some(permit) => Ok(Response::new(permit.hold(log_body))),
+            None => Ok(Response::new(log_body)),
+        },
         Err(e) => {
             error!("Error fetching logs: {}", e);
             if e.is::<NotImplementedError>() {
@@ -79,6 +413,10 @@ async fn get_container_logs<T: Provider>(
                     StatusCode::NOT_IMPLEMENTED,
                     "Logs not implemented in provider.".to_owned(),
                 ))
+            } else if let Some(ProviderError::PreviousContainerNotFound { .. }) =
+                e.downcast_ref::<ProviderError>()
+            {
+                Ok(return_with_code(StatusCode::BAD_REQUEST, e.to_string()))
             } else {
                 Ok(return_with_code(
                     StatusCode::INTERNAL_SERVER_ERROR,
@@ -89,19 +427,97 @@ async fn get_container_logs<T: Provider>(
     }
 }
 
-/// Run a pod exec command and get the output
+/// Get the pods the kubelet is running, as a `PodList`.
+///
+/// Implements the kubelet path /pods
+fn get_pods(pods: &PodStore) -> Response<Body> {
+    match serde_json::to_vec(&pods.pod_list()) {
+        Ok(body) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(e) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        ),
+    }
+}
+
+/// Get the resource usage of the node and its pods.
 ///
-/// Implements the kubelet path /exec/{namespace}/{pod}/{container}
-async fn post_exec<T: Provider>(
-    _provider: Arc<T>,
-    _namespace: String,
-    _pod: String,
-    _container: String,
+/// Implements the kubelet path /stats/summary
+async fn get_summary<T: Provider>(
+    provider: Arc<T>,
+    stats: Arc<StatsCollector>,
+    opts: SummaryOptions,
 ) -> Result<Response<Body>, Infallible> {
-    Ok(return_with_code(
-        StatusCode::NOT_IMPLEMENTED,
-        "Exec not implemented.".to_string(),
-    ))
+    let summary = stats.summary(provider.as_ref(), &opts).await;
+    match serde_json::to_vec(&summary) {
+        Ok(body) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+            Ok(response)
+        }
+        Err(e) => Ok(return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        )),
+    }
+}
+
+/// Get the resource usage of the node and its pods, and how many streams are
+/// open, in the Prometheus text format.
+///
+/// Implements the kubelet path /metrics/resource
+async fn get_resource_metrics<T: Provider>(
+    provider: Arc<T>,
+    stats: Arc<StatsCollector>,
+    limits: StreamLimits,
+) -> Result<Response<Body>, Infallible> {
+    let mut metrics = stats.resource_metrics(provider.as_ref()).await;
+    metrics.push_str(&limits.metrics());
+    let mut response = Response::new(metrics.into());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(RESOURCE_METRICS_CONTENT_TYPE),
+    );
+    Ok(response)
+}
+
+/// Answers health checks at `endpoint` by running `checks`, listing each of
+/// them if `query` has `verbose` and skipping those it names in `exclude`.
+/// They fail while the node is shutting down so that it isn't mistaken for a
+/// healthy node while its pods are drained.
+async fn health(endpoint: &str, checks: &HealthChecks, query: &str) -> Response<Body> {
+    if node::is_shutting_down() {
+        return return_with_code(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "node is shutting down".to_owned(),
+        );
+    }
+    let mut verbose = false;
+    let mut exclude = vec![];
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "verbose" => verbose = true,
+            "exclude" => exclude.push(value.into_owned()),
+            _ => (),
+        }
+    }
+    let report = checks.run(&exclude).await;
+    let code = if report.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    return_with_code(code, report.render(endpoint, verbose))
 }
 
 fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
@@ -109,3 +525,460 @@ fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
     *response.status_mut() = code;
     response
 }
+
+#[cfg(test)]
+mod test {
+    use super::auth::ClientIdentity;
+    use super::*;
+    use crate::auth::{service_account, FakeAccessReviewer, FakeTokenReviewer, UserInfo};
+    use chrono::TimeZone;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    };
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::webpki::DNSNameRef;
+
+    /// A CA that signs test server and client certificates
+    struct TestCa {
+        certificate: Certificate,
+    }
+
+    impl TestCa {
+        fn new() -> Self {
+            let mut params = CertificateParams::default();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "krustlet test CA");
+            TestCa {
+                certificate: Certificate::from_params(params).unwrap(),
+            }
+        }
+
+        fn pem(&self) -> String {
+            self.certificate.serialize_pem().unwrap()
+        }
+
+        fn der(&self) -> Vec<u8> {
+            self.certificate.serialize_der().unwrap()
+        }
+
+        /// Returns a PEM encoded serving certificate for `localhost` that
+        /// expires at `not_after`, and its private key
+        fn server_certificate(&self, not_after: chrono::DateTime<chrono::Utc>) -> (String, String) {
+            let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+            params.not_after = not_after;
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            let certificate = Certificate::from_params(params).unwrap();
+            (
+                certificate
+                    .serialize_pem_with_signer(&self.certificate)
+                    .unwrap(),
+                certificate.serialize_private_key_pem(),
+            )
+        }
+    }
+
+    /// A client certificate, signed by a test CA, for authenticating to a
+    /// test server
+    pub(crate) struct TestClientCert {
+        certificate: Vec<u8>,
+        private_key: Vec<u8>,
+    }
+
+    impl TestClientCert {
+        fn new(ca: &TestCa, user: Option<&str>, groups: &[&str]) -> Self {
+            let mut params = CertificateParams::new(vec!["client.krustlet.test".to_owned()]);
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            if let Some(user) = user {
+                params.distinguished_name.push(DnType::CommonName, user);
+            }
+            // rcgen only supports one organization per name
+            if let Some(group) = groups.first() {
+                params
+                    .distinguished_name
+                    .push(DnType::OrganizationName, *group);
+            }
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let certificate = Certificate::from_params(params).unwrap();
+            TestClientCert {
+                certificate: certificate
+                    .serialize_der_with_signer(&ca.certificate)
+                    .unwrap(),
+                private_key: certificate.serialize_private_key_der(),
+            }
+        }
+    }
+
+    /// A server using certificates signed by a test CA, which also signs
+    /// the client certificates it accepts. It answers every request with the
+    /// user and groups of the client.
+    struct TestServer {
+        ca: TestCa,
+        port: u16,
+        config: ServerConfig,
+        tls_config: SharedTlsConfig,
+        _dir: tempdir::TempDir,
+    }
+
+    impl TestServer {
+        async fn start() -> Self {
+            let ca = TestCa::new();
+            let dir = tempdir::TempDir::new("krustlet-webserver-test").unwrap();
+            let (certificate, private_key) =
+                ca.server_certificate(chrono::Utc::now() + chrono::Duration::days(1));
+            let write = |name: &str, contents: String| -> PathBuf {
+                let path = dir.path().join(name);
+                std::fs::write(&path, contents).unwrap();
+                path
+            };
+            let config = ServerConfig {
+                addr: "127.0.0.1".parse().unwrap(),
+                port: 0,
+                cert_file: write("server.crt", certificate),
+                private_key_file: write("server.key", private_key),
+                client_ca_file: Some(write("ca.crt", ca.pem())),
+                rotate_certificates: false,
+                renewal_threshold_days: None,
+                oidc: None,
+                authentication_token_webhook: false,
+                anonymous_auth: false,
+                read_only_port: 0,
+                max_log_follows: 256,
+                max_exec_sessions: 128,
+                max_port_forwards: 128,
+                streaming_connection_idle_timeout: std::time::Duration::from_secs(4 * 60 * 60),
+                authorization_mode: crate::config::AuthorizationMode::AlwaysAllow,
+            };
+
+            let routes = auth::authenticate(auth::Authenticators {
+                required: true,
+                ..Default::default()
+            })
+            .map(|user: Option<UserInfo>| {
+                let user = user.unwrap();
+                format!("{}:{}", user.username, user.groups.join(","))
+            })
+            .recover(auth::recover_unauthenticated);
+            let listener = TcpListener::bind((config.addr, 0)).await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let tls_config = Arc::new(tokio::sync::RwLock::new(tls_config(&config).unwrap()));
+            tokio::spawn(tls::serve(
+                listener,
+                tls_config.clone(),
+                warp::service(routes),
+            ));
+            TestServer {
+                ca,
+                port,
+                config,
+                tls_config,
+                _dir: dir,
+            }
+        }
+
+        async fn connect(
+            &self,
+            client_cert: Option<&TestClientCert>,
+        ) -> anyhow::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+            let mut client_config = rustls::ClientConfig::new();
+            client_config
+                .root_store
+                .add(&rustls::Certificate(self.ca.der()))?;
+            if let Some(client_cert) = client_cert {
+                client_config.set_single_client_cert(
+                    vec![rustls::Certificate(client_cert.certificate.clone())],
+                    rustls::PrivateKey(client_cert.private_key.clone()),
+                )?;
+            }
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+            let stream = tokio::net::TcpStream::connect(("127.0.0.1", self.port)).await?;
+            Ok(connector
+                .connect(DNSNameRef::try_from_ascii_str("localhost")?, stream)
+                .await?)
+        }
+
+        /// Sends a request with the given client certificate, returning the
+        /// status code and body of the response
+        async fn get(&self, client_cert: Option<&TestClientCert>) -> anyhow::Result<(u16, String)> {
+            let mut stream = self.connect(client_cert).await?;
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            let status = response
+                .split(' ')
+                .nth(1)
+                .ok_or_else(|| anyhow::anyhow!("invalid response {:?}", response))?
+                .parse()?;
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+            Ok((status, body.to_owned()))
+        }
+
+        /// Returns the DER encoded certificate the server presents
+        async fn served_certificate(&self) -> Vec<u8> {
+            use rustls::Session;
+            let stream = self.connect(None).await.unwrap();
+            let certificates = stream.get_ref().1.get_peer_certificates().unwrap();
+            certificates[0].0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_with_client_certificates_are_authenticated() {
+        let server = TestServer::start().await;
+        let client_cert =
+            TestClientCert::new(&server.ca, Some("kube-apiserver"), &["system:masters"]);
+        let (status, body) = server.get(Some(&client_cert)).await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, "kube-apiserver:system:masters");
+    }
+
+    #[tokio::test]
+    async fn requests_without_client_certificates_are_unauthorized() {
+        let server = TestServer::start().await;
+        let (status, _) = server.get(None).await.unwrap();
+        assert_eq!(status, 401);
+    }
+
+    #[tokio::test]
+    async fn client_certificates_without_a_user_are_unauthorized() {
+        let server = TestServer::start().await;
+        let client_cert = TestClientCert::new(&server.ca, None, &["system:masters"]);
+        let (status, _) = server.get(Some(&client_cert)).await.unwrap();
+        assert_eq!(status, 401);
+    }
+
+    #[tokio::test]
+    async fn client_certificates_from_other_cas_are_rejected() {
+        let server = TestServer::start().await;
+        let client_cert = TestClientCert::new(&TestCa::new(), Some("mallory"), &[]);
+        assert!(server.get(Some(&client_cert)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replaced_certificates_are_served_to_new_connections() {
+        let server = TestServer::start().await;
+        let original = server.served_certificate().await;
+        let (certificate, private_key) = server
+            .ca
+            .server_certificate(chrono::Utc::now() + chrono::Duration::days(30));
+        *server.tls_config.write().await = tls_config_with_certificate(
+            &server.config,
+            certificate.as_bytes(),
+            private_key.as_bytes(),
+        )
+        .unwrap();
+        let renewed = server.served_certificate().await;
+        assert_ne!(renewed, original);
+        assert_eq!(
+            renewed,
+            rustls::internal::pemfile::certs(&mut certificate.as_bytes()).unwrap()[0].0
+        );
+    }
+
+    #[test]
+    fn certificate_validity_is_read() {
+        let not_after = chrono::Utc.ymd(2031, 4, 5).and_hms(6, 7, 8);
+        let (certificate, _) = TestCa::new().server_certificate(not_after);
+        let (not_before, expiry) = certificate_validity(certificate.as_bytes()).unwrap();
+        assert_eq!(expiry, not_after);
+        // rcgen certificates are valid from 1975 unless told otherwise
+        assert_eq!(not_before, chrono::Utc.ymd(1975, 1, 1).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn client_identities_include_subject_alt_names() {
+        let ca = TestCa::new();
+        let client_cert = TestClientCert::new(&ca, Some("kubectl"), &["devs"]);
+        let identity = ClientIdentity::from_der(&client_cert.certificate).unwrap();
+        assert_eq!(identity.user, "kubectl");
+        assert_eq!(identity.groups, vec!["devs"]);
+        assert_eq!(identity.subject_alt_names, vec!["client.krustlet.test"]);
+    }
+
+    /// Routes answering every request with "ok", that clients authenticate
+    /// to with bearer tokens the fake API server validates, and that the fake
+    /// API server only allows for `nodes/stats`
+    fn reviewed_routes(
+        anonymous_auth: bool,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let reviews = Arc::new(AtomicUsize::new(0));
+        let token_review = TokenReviewAuthenticator::with_reviewer(FakeTokenReviewer {
+            token: "valid-token",
+            user: service_account(),
+            reviews: reviews.clone(),
+        });
+        let authorizer = WebhookAuthorizer::with_reviewer(
+            FakeAccessReviewer {
+                allowed_subresources: vec!["stats"],
+                reviews,
+            },
+            "krustlet".to_owned(),
+        );
+        with_auth(
+            warp::get().map(|| "ok"),
+            HealthChecks::new(),
+            auth::Authenticators {
+                required: !anonymous_auth,
+                token_review: Some(Arc::new(token_review)),
+                ..Default::default()
+            },
+            Some(Arc::new(authorizer)),
+        )
+    }
+
+    async fn request(
+        routes: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static),
+        path: &str,
+        token: Option<&str>,
+    ) -> (u16, String) {
+        let mut request = warp::test::request().path(path);
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let response = request.reply(routes).await;
+        (
+            response.status().as_u16(),
+            String::from_utf8_lossy(response.body()).into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn requests_with_reviewed_tokens_are_authorized() {
+        let routes = reviewed_routes(false);
+        assert_eq!(
+            request(&routes, "/stats/summary", Some("valid-token")).await,
+            (200, "ok".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_with_invalid_tokens_are_unauthorized() {
+        let routes = reviewed_routes(true);
+        let (status, _) = request(&routes, "/stats/summary", Some("invalid-token")).await;
+        assert_eq!(status, 401);
+    }
+
+    #[tokio::test]
+    async fn denied_requests_are_forbidden_with_the_reason() {
+        let routes = reviewed_routes(false);
+        let (status, body) = request(&routes, "/containerLogs/ns/pod/c", Some("valid-token")).await;
+        assert_eq!(status, 403);
+        assert_eq!(
+            body,
+            "Forbidden (user=system:serviceaccount:monitoring:prometheus, verb=get, resource=nodes, subresource=proxy): no RBAC policy matched nodes/proxy"
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymous_requests_are_only_answered_at_healthz_unless_allowed() {
+        let routes = reviewed_routes(false);
+        let (status, _) = request(&routes, "/stats/summary", None).await;
+        assert_eq!(status, 401);
+        let (status, _) = request(&routes, "/healthz", None).await;
+        assert_eq!(status, 200);
+        let (status, _) = request(&routes, "/readyz", None).await;
+        assert_eq!(status, 200);
+
+        // Anonymous requests are still authorized as system:anonymous
+        let routes = reviewed_routes(true);
+        assert_eq!(
+            request(&routes, "/stats/summary", None).await,
+            (200, "ok".to_owned())
+        );
+        let (status, body) = request(&routes, "/containerLogs/ns/pod/c", None).await;
+        assert_eq!(status, 403);
+        assert!(body.starts_with("Forbidden (user=system:anonymous,"));
+    }
+
+    struct Broken;
+
+    #[async_trait::async_trait]
+    impl crate::health::HealthCheck for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        async fn check(&self) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("broken"))
+        }
+    }
+
+    #[tokio::test]
+    async fn health_checks_fail_when_a_component_is_unhealthy() {
+        let mut checks = HealthChecks::new();
+        checks.register(crate::health::DiskCheck::new(std::env::temp_dir()));
+        checks.register(Broken);
+        let routes = with_auth(warp::get().map(|| "ok"), checks, Default::default(), None);
+
+        let (status, body) = request(&routes, "/healthz", None).await;
+        assert_eq!(status, 500);
+        assert_eq!(
+            body,
+            "[+]disk ok\n[-]broken failed: reason withheld\nhealthz check failed\n"
+        );
+        assert_eq!(
+            request(&routes, "/readyz?exclude=broken", None).await,
+            (200, "ok".to_owned())
+        );
+        assert_eq!(
+            request(&routes, "/readyz?verbose=1&exclude=broken", None).await,
+            (
+                200,
+                "[+]disk ok\n[+]broken excluded: ok\nreadyz check passed\n".to_owned()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn pods_are_listed_as_they_come_and_go() {
+        let store = PodStore::new();
+        let routes = list_pods(store.clone());
+        let list = |body: String| -> Vec<String> {
+            let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(list["kind"], "PodList");
+            list["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|pod| pod["metadata"]["name"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        let pod: crate::pod::Pod = serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web", "namespace": "default" },
+        }))
+        .unwrap();
+        let (_, manifest) = krator::Manifest::new(pod.clone());
+
+        let (status, body) = request(&routes, "/pods", None).await;
+        assert_eq!(status, 200);
+        assert!(list(body).is_empty());
+
+        store.entered(&manifest, "Running");
+        let (_, body) = request(&routes, "/pods", None).await;
+        assert_eq!(list(body), vec!["web"]);
+
+        store.remove(&pod);
+        let (_, body) = request(&routes, "/pods", None).await;
+        assert!(list(body).is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_read_only_routes_are_not_authenticated() {
+        let routes = read_only(list_pods(PodStore::new()), HealthChecks::new());
+        let (status, _) = request(&routes, "/pods", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            request(&routes, "/healthz", None).await,
+            (200, "ok".to_owned())
+        );
+        let (status, _) = request(&routes, "/containerLogs/ns/pod/c", None).await;
+        assert_eq!(status, 404);
+    }
+}
@@ -0,0 +1,417 @@
+//! The protocol that `kubectl port-forward` speaks over WebSockets.
+//!
+//! The ports to forward are given as `port` query parameters, and each gets a
+//! pair of channels, numbered in the order the ports were given: a data
+//! channel, whose bytes are copied to and from a connection to the port, and
+//! an error channel, which a message is written to if forwarding the port
+//! fails, such as when the pod resets the connection. As with exec, each
+//! message is for one channel, given by its first byte. The first message on
+//! each channel is the port it is for, as two little-endian bytes.
+
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use http::status::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Reply;
+
+use super::limits::StreamPermit;
+use super::remotecommand::frame;
+use super::return_with_code;
+use crate::pod::PodKey;
+use crate::provider::{PortForwardProvider, Provider};
+
+const V4_PROTOCOL: &str = "v4.channel.k8s.io";
+
+/// The most ports that can be forwarded on one connection, as there are two
+/// channels for each
+const MAX_PORTS: usize = 128;
+
+/// How much of a connection is read at a time
+const READ_SIZE: usize = 32 * 1024;
+
+/// Parses the ports to forward from the query. Each `port` parameter may
+/// list several ports, separated by commas.
+fn parse_ports(query: &str) -> Result<Vec<u16>, String> {
+    let mut ports = Vec::new();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if key != "port" {
+            continue;
+        }
+        if value.is_empty() {
+            return Err("query parameter \"port\" cannot be empty".to_owned());
+        }
+        for port in value.split(',') {
+            match port.parse::<u16>() {
+                Ok(0) => return Err(format!("port {:?} must be > 0", port)),
+                Ok(port) => ports.push(port),
+                Err(e) => return Err(format!("unable to parse {:?} as a port: {}", port, e)),
+            }
+        }
+    }
+    if ports.is_empty() {
+        return Err("query parameter \"port\" is required".to_owned());
+    }
+    if ports.len() > MAX_PORTS {
+        return Err(format!(
+            "at most {} ports can be forwarded on one connection",
+            MAX_PORTS
+        ));
+    }
+    Ok(ports)
+}
+
+/// Answers a port forward request by upgrading it to a WebSocket that the
+/// ports are forwarded over, which holds `permit` until it is closed
+pub(crate) fn upgrade<T: Provider>(
+    provider: Arc<T>,
+    pod: PodKey,
+    query: &str,
+    protocols: Option<&str>,
+    ws: Ws,
+    permit: StreamPermit,
+) -> warp::reply::Response {
+    // Only the binary protocol is supported. Clients that don't offer any
+    // protocols get it too
+    if let Some(protocols) = protocols {
+        if !protocols.split(',').any(|p| p.trim() == V4_PROTOCOL) {
+            return return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "none of the requested protocols are supported, expected {}",
+                    V4_PROTOCOL
+                ),
+            );
+        }
+    }
+    let ports = match parse_ports(query) {
+        Ok(ports) => ports,
+        Err(message) => return return_with_code(StatusCode::BAD_REQUEST, message),
+    };
+    debug!(
+        "Got port forward request for ports {:?} of pod {} in namespace {}",
+        ports,
+        pod.name(),
+        pod.namespace()
+    );
+
+    let reply = ws.on_upgrade(move |socket| async move {
+        forward_ports(socket, provider.port_forward_provider(), pod, ports).await;
+        drop(permit);
+    });
+    match protocols {
+        Some(_) => {
+            warp::reply::with_header(reply, "Sec-WebSocket-Protocol", V4_PROTOCOL).into_response()
+        }
+        None => reply.into_response(),
+    }
+}
+
+/// Forwards each port over its channels, until the client closes the
+/// connection or the pod has closed the connection to every port
+async fn forward_ports(
+    socket: WebSocket,
+    provider: Option<&dyn PortForwardProvider>,
+    pod: PodKey,
+    ports: Vec<u16>,
+) {
+    let (mut sink, mut incoming) = socket.split();
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Vec<u8>>(16);
+
+    let mut inputs = Vec::with_capacity(ports.len());
+    let mut forwards = Vec::with_capacity(ports.len());
+    for (index, port) in ports.into_iter().enumerate() {
+        let (input, input_rx) = mpsc::channel(16);
+        inputs.push(Some(input));
+        forwards.push(forward(
+            provider,
+            pod.clone(),
+            index,
+            port,
+            input_rx,
+            outgoing.clone(),
+        ));
+    }
+    drop(outgoing);
+
+    // Data is read until the client closes the connection. Data for a port
+    // that is no longer forwarded is dropped
+    let mut receive_input = tokio::spawn(async move {
+        while let Some(Ok(message)) = incoming.next().await {
+            if message.is_close() {
+                break;
+            }
+            let (channel, data) = match message.as_bytes().split_first() {
+                Some((channel, data)) if channel % 2 == 0 && !data.is_empty() => {
+                    (*channel as usize, data)
+                }
+                _ => continue,
+            };
+            if let Some(input) = inputs.get_mut(channel / 2) {
+                if let Some(sender) = input.as_ref() {
+                    if sender.send(data.to_vec()).await.is_err() {
+                        *input = None;
+                    }
+                }
+            }
+        }
+    });
+
+    let forward_output = async move {
+        while let Some(frame) = outgoing_rx.recv().await {
+            if sink.send(Message::binary(frame)).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    };
+
+    let forwarding = futures::future::join(futures::future::join_all(forwards), forward_output);
+    tokio::select! {
+        _ = forwarding => (),
+        _ = &mut receive_input => debug!("Client closed the port forward connection"),
+    }
+    receive_input.abort();
+}
+
+/// Forwards one port, writing why to its error channel if that fails
+async fn forward(
+    provider: Option<&dyn PortForwardProvider>,
+    pod: PodKey,
+    index: usize,
+    port: u16,
+    input: mpsc::Receiver<Vec<u8>>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+) {
+    let data_channel = (index * 2) as u8;
+    let error_channel = data_channel + 1;
+    let prefix = port.to_le_bytes();
+    let _ = outgoing.send(frame(data_channel, &prefix)).await;
+    let _ = outgoing.send(frame(error_channel, &prefix)).await;
+
+    let result = match provider {
+        Some(provider) => proxy(provider, &pod, port, input, data_channel, &outgoing).await,
+        None => Err(anyhow::anyhow!(
+            "port forwarding not supported by this provider"
+        )),
+    };
+    if let Err(e) = result {
+        let message = format!(
+            "error forwarding port {} to pod {}: {}",
+            port,
+            pod.name(),
+            e
+        );
+        error!("{}", message);
+        let _ = outgoing
+            .send(frame(error_channel, message.as_bytes()))
+            .await;
+    }
+}
+
+/// Copies bytes between the data channel and a connection to the port, until
+/// the pod closes the connection. The connection is half closed once the
+/// client has no more data for it.
+async fn proxy(
+    provider: &dyn PortForwardProvider,
+    pod: &PodKey,
+    port: u16,
+    mut input: mpsc::Receiver<Vec<u8>>,
+    data_channel: u8,
+    outgoing: &mpsc::Sender<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let address = provider.port_forward(pod.clone(), port).await?;
+    let (mut reader, mut writer) = TcpStream::connect(address).await?.into_split();
+
+    let send = async move {
+        while let Some(data) = input.recv().await {
+            writer.write_all(&data).await?;
+        }
+        writer.shutdown().await
+    };
+    let receive = async move {
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            match reader.read(&mut buf).await? {
+                0 => return Ok::<(), std::io::Error>(()),
+                n => {
+                    if outgoing.send(frame(data_channel, &buf[..n])).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    };
+    tokio::select! {
+        result = receive => Ok(result?),
+        Err(e) = send => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use warp::Filter;
+
+    /// Forwards ports to the listeners they are mapped to
+    struct MockForwarder {
+        ports: HashMap<u16, SocketAddr>,
+    }
+
+    #[async_trait::async_trait]
+    impl PortForwardProvider for MockForwarder {
+        async fn port_forward(&self, _pod: PodKey, port: u16) -> anyhow::Result<SocketAddr> {
+            self.ports
+                .get(&port)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("pod has no port {}", port))
+        }
+    }
+
+    /// Listens for one connection, and writes back what is read from it
+    /// after `prefix`
+    async fn echo(prefix: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let mut reply = prefix.as_bytes().to_vec();
+            reply.extend_from_slice(&buf[..n]);
+            stream.write_all(&reply).await.unwrap();
+        });
+        address
+    }
+
+    /// Listens for one connection, and resets it once something is read
+    /// from it
+    async fn reset() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            // Closing without lingering sends a reset
+            stream.set_linger(Some(Duration::from_secs(0))).unwrap();
+        });
+        address
+    }
+
+    async fn connect(forwarder: MockForwarder, ports: &[u16]) -> warp::test::WsClient {
+        let forwarder = Arc::new(forwarder);
+        let ports = ports.to_vec();
+        let route = warp::ws().map(move |ws: Ws| {
+            let (forwarder, ports) = (forwarder.clone(), ports.clone());
+            ws.on_upgrade(move |socket| async move {
+                forward_ports(
+                    socket,
+                    Some(forwarder.as_ref()),
+                    PodKey::new("default", "pod"),
+                    ports,
+                )
+                .await
+            })
+        });
+        warp::test::ws()
+            .handshake(route)
+            .await
+            .expect("handshake should succeed")
+    }
+
+    /// Receives messages until the connection closes, returning what was
+    /// written to each channel
+    async fn receive_all(client: &mut warp::test::WsClient) -> HashMap<u8, Vec<u8>> {
+        let mut channels: HashMap<u8, Vec<u8>> = HashMap::new();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(message) = client.recv().await {
+                if message.is_close() {
+                    break;
+                }
+                if let Some((channel, data)) = message.as_bytes().split_first() {
+                    channels
+                        .entry(*channel)
+                        .or_default()
+                        .extend_from_slice(data);
+                }
+            }
+        })
+        .await;
+        assert!(received.is_ok(), "connection should have closed");
+        channels
+    }
+
+    fn send(channel: u8, data: &str) -> Message {
+        Message::binary(frame(channel, data.as_bytes()))
+    }
+
+    #[test]
+    fn ports_are_parsed_from_repeated_and_listed_parameters() {
+        assert_eq!(
+            parse_ports("port=80&port=8080,9090"),
+            Ok(vec![80, 8080, 9090])
+        );
+        assert!(parse_ports("").is_err());
+        assert!(parse_ports("port=").is_err());
+        assert!(parse_ports("port=0").is_err());
+        assert!(parse_ports("port=http").is_err());
+        assert!(parse_ports("port=65536").is_err());
+    }
+
+    #[tokio::test]
+    async fn multiple_ports_are_forwarded_concurrently() {
+        let forwarder = MockForwarder {
+            ports: vec![(80, echo("first:").await), (8080, echo("second:").await)]
+                .into_iter()
+                .collect(),
+        };
+        let mut client = connect(forwarder, &[80, 8080]).await;
+        // Data for the second port is sent first, so that it is only answered
+        // if the ports are forwarded independently
+        client.send(send(2, "world")).await;
+        client.send(send(0, "hello")).await;
+
+        let channels = receive_all(&mut client).await;
+        let with_prefix = |port: u16, data: &str| {
+            let mut bytes = port.to_le_bytes().to_vec();
+            bytes.extend_from_slice(data.as_bytes());
+            bytes
+        };
+        assert_eq!(channels[&0], with_prefix(80, "first:hello"));
+        assert_eq!(channels[&1], with_prefix(80, ""));
+        assert_eq!(channels[&2], with_prefix(8080, "second:world"));
+        assert_eq!(channels[&3], with_prefix(8080, ""));
+    }
+
+    #[tokio::test]
+    async fn errors_are_written_to_the_error_channel() {
+        let forwarder = MockForwarder {
+            ports: vec![(80, reset().await)].into_iter().collect(),
+        };
+        let mut client = connect(forwarder, &[80, 81]).await;
+        client.send(send(0, "hello")).await;
+
+        let channels = receive_all(&mut client).await;
+        let reset_error = String::from_utf8_lossy(&channels[&1][2..]).to_lowercase();
+        assert!(
+            reset_error.starts_with("error forwarding port 80 to pod pod")
+                && reset_error.contains("reset"),
+            "unexpected error {:?}",
+            reset_error
+        );
+        let missing_error = String::from_utf8_lossy(&channels[&3][2..]).to_string();
+        assert_eq!(
+            missing_error,
+            "error forwarding port 81 to pod pod: pod has no port 81"
+        );
+    }
+}
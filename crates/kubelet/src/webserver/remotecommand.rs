@@ -0,0 +1,534 @@
+//! The streaming protocol that `kubectl exec` and `kubectl attach` speak over
+//! WebSockets.
+//!
+//! Each message is for one channel, given by its first byte: stdin, stdout
+//! and stderr, then an error channel that the outcome of the command is
+//! written to once it exits, and a channel for terminal resizes. With the
+//! `v4.channel.k8s.io` protocol the outcome is a JSON `Status`, and with the
+//! original `channel.k8s.io` protocol it is an error message, written only if
+//! the command failed. Attaching is the same, except that the streams are
+//! those of the container itself, and that the client may detach by closing
+//! the connection, which leaves the container running.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use http::status::StatusCode;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Status, StatusCause, StatusDetails};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, error};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Reply;
+
+use super::limits::StreamPermit;
+use super::return_with_code;
+use crate::pod::PodKey;
+use crate::provider::{ExecInput, ExecOutput, ExitCode, Provider};
+
+/// What a session streams to and from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Operation {
+    /// Running a command in a container, for `kubectl exec`
+    Exec,
+    /// Attaching to a running container, for `kubectl attach`
+    Attach,
+}
+
+impl Operation {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Operation::Exec => "exec",
+            Operation::Attach => "attach",
+        }
+    }
+}
+
+const STDIN: u8 = 0;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const ERROR: u8 = 3;
+const RESIZE: u8 = 4;
+
+const V4_PROTOCOL: &str = "v4.channel.k8s.io";
+const V1_PROTOCOL: &str = "channel.k8s.io";
+
+/// How much of a stream is buffered between the client and the command
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// The version of the streaming protocol spoken on a connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    V1,
+    V4,
+}
+
+impl Protocol {
+    /// Chooses the first protocol the client offered that is supported.
+    /// Clients that don't offer any protocols get the original one.
+    fn negotiate(offered: Option<&str>) -> Option<Self> {
+        let offered = match offered {
+            Some(offered) => offered,
+            None => return Some(Protocol::V1),
+        };
+        offered.split(',').map(str::trim).find_map(|p| match p {
+            V4_PROTOCOL => Some(Protocol::V4),
+            V1_PROTOCOL => Some(Protocol::V1),
+            _ => None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::V1 => V1_PROTOCOL,
+            Protocol::V4 => V4_PROTOCOL,
+        }
+    }
+
+    /// What to write to the error channel once the command has finished, if
+    /// anything
+    fn outcome(&self, command: &[String], result: &anyhow::Result<ExitCode>) -> Option<Vec<u8>> {
+        let (message, exit_code) = match result {
+            Ok(code) if code.success() => {
+                return match self {
+                    Protocol::V1 => None,
+                    Protocol::V4 => Some(status_json(Status {
+                        status: Some("Success".to_owned()),
+                        ..Default::default()
+                    })),
+                }
+            }
+            Ok(ExitCode(code)) => (
+                format!(
+                    "command terminated with non-zero exit code: error executing command {:?}, exit code {}",
+                    command, code
+                ),
+                Some(*code),
+            ),
+            Err(e) => (format!("{}", e), None),
+        };
+        match self {
+            Protocol::V1 => Some(message.into_bytes()),
+            Protocol::V4 => Some(status_json(Status {
+                status: Some("Failure".to_owned()),
+                message: Some(message),
+                reason: Some(
+                    match exit_code {
+                        Some(_) => "NonZeroExitCode",
+                        None => "InternalError",
+                    }
+                    .to_owned(),
+                ),
+                details: exit_code.map(|code| StatusDetails {
+                    causes: Some(vec![StatusCause {
+                        reason: Some("ExitCode".to_owned()),
+                        message: Some(code.to_string()),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+fn status_json(status: Status) -> Vec<u8> {
+    serde_json::to_vec(&status).expect("statuses should serialize")
+}
+
+/// The query parameters of an exec or attach request
+#[derive(Debug, Default, PartialEq)]
+struct StreamOptions {
+    command: Vec<String>,
+    stdin: bool,
+    stdout: bool,
+    stderr: bool,
+    tty: bool,
+}
+
+impl StreamOptions {
+    /// Parses the query, in which each argument of the command is given as a
+    /// separate `command` parameter
+    fn parse(query: &str) -> Self {
+        let mut options = StreamOptions::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let flag = value == "1" || value.eq_ignore_ascii_case("true");
+            match key.as_ref() {
+                "command" => options.command.push(value.into_owned()),
+                "input" | "stdin" => options.stdin = flag,
+                "output" | "stdout" => options.stdout = flag,
+                "error" | "stderr" => options.stderr = flag,
+                "tty" => options.tty = flag,
+                _ => (),
+            }
+        }
+        options
+    }
+
+    fn validate(&self, operation: Operation) -> Result<(), &'static str> {
+        if operation == Operation::Exec && self.command.is_empty() {
+            return Err("you must specify a command");
+        }
+        if !(self.stdin || self.stdout || self.stderr) {
+            return Err("you must specify at least 1 of stdin, stdout, stderr");
+        }
+        Ok(())
+    }
+}
+
+/// Answers an exec or attach request by upgrading it to a WebSocket that the
+/// streams are connected over, which holds `permit` until it is closed. The
+/// connection is closed once it has gone `idle_timeout` without traffic,
+/// unless that is zero.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn upgrade<T: Provider>(
+    operation: Operation,
+    provider: Arc<T>,
+    pod: PodKey,
+    container: String,
+    query: &str,
+    protocols: Option<&str>,
+    ws: Ws,
+    permit: StreamPermit,
+    idle_timeout: Duration,
+) -> warp::reply::Response {
+    let protocol = match Protocol::negotiate(protocols) {
+        Some(protocol) => protocol,
+        None => {
+            return return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "none of the requested protocols are supported, expected one of {}, {}",
+                    V4_PROTOCOL, V1_PROTOCOL
+                ),
+            )
+        }
+    };
+    let options = StreamOptions::parse(query);
+    if let Err(message) = options.validate(operation) {
+        return return_with_code(StatusCode::BAD_REQUEST, message.to_owned());
+    }
+    debug!(
+        "Got {} request for container {} in pod {} in namespace {}. Options: {:?}.",
+        operation.name(),
+        container,
+        pod.name(),
+        pod.namespace(),
+        options
+    );
+
+    let reply = ws.on_upgrade(move |socket| async move {
+        session(
+            socket,
+            operation,
+            protocol,
+            provider,
+            pod,
+            container,
+            options,
+            idle_timeout,
+        )
+        .await;
+        drop(permit);
+    });
+    // The protocol is only confirmed to clients that offered one
+    match protocols {
+        Some(_) => warp::reply::with_header(reply, "Sec-WebSocket-Protocol", protocol.name())
+            .into_response(),
+        None => reply.into_response(),
+    }
+}
+
+/// Runs the command, or attaches to the container, with its streams connected
+/// to the WebSocket, and then writes its outcome to the error channel. If
+/// nothing is sent either way for `idle_timeout`, the session is dropped
+/// instead, as it is when a client detaches.
+#[allow(clippy::too_many_arguments)]
+async fn session<T: Provider>(
+    socket: WebSocket,
+    operation: Operation,
+    protocol: Protocol,
+    provider: Arc<T>,
+    pod: PodKey,
+    container: String,
+    options: StreamOptions,
+    idle_timeout: Duration,
+) {
+    let (mut sink, mut incoming) = socket.split();
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Vec<u8>>(16);
+    let activity = Arc::new(Notify::new());
+
+    let (stdin, mut stdin_writer) = pipe_if(options.stdin);
+    let (stdout, stdout_reader) = pipe_if(options.stdout);
+    let (stderr, stderr_reader) = pipe_if(options.stderr);
+
+    // Input is read until the client closes the connection, or the command
+    // stops reading it
+    let input_activity = activity.clone();
+    let mut receive_input = tokio::spawn(async move {
+        while let Some(Ok(message)) = incoming.next().await {
+            if message.is_close() {
+                break;
+            }
+            input_activity.notify_one();
+            match message.as_bytes().split_first() {
+                Some((&STDIN, input)) => {
+                    if let Some(writer) = stdin_writer.as_mut() {
+                        if writer.write_all(input).await.is_err() {
+                            stdin_writer = None;
+                        }
+                    }
+                }
+                Some((&RESIZE, _)) => debug!("Ignoring terminal resize"),
+                _ => (),
+            }
+        }
+    });
+
+    let output_activity = activity.clone();
+    let forward_output = async move {
+        while let Some(frame) = outgoing_rx.recv().await {
+            if sink.send(Message::binary(frame)).await.is_err() {
+                break;
+            }
+            output_activity.notify_one();
+        }
+        let _ = sink.close().await;
+    };
+
+    let run = async move {
+        let command = options.command.clone();
+        let stdin = stdin.map(|r| Box::new(r) as ExecInput);
+        let stdout = stdout.map(|w| Box::new(w) as ExecOutput);
+        let stderr = stderr.map(|w| Box::new(w) as ExecOutput);
+        let streams = async move {
+            match operation {
+                Operation::Exec => match provider.exec_provider() {
+                    Some(exec_provider) => {
+                        exec_provider
+                            .exec(
+                                pod,
+                                container,
+                                options.command,
+                                stdin,
+                                stdout,
+                                stderr,
+                                options.tty,
+                            )
+                            .await
+                    }
+                    None => Err(anyhow::anyhow!("exec not supported by this provider")),
+                },
+                Operation::Attach => match provider.attach_provider() {
+                    Some(attach_provider) => attach_provider
+                        .attach(pod, container, stdin, stdout, stderr, options.tty)
+                        .await
+                        .map(|()| ExitCode(0)),
+                    None => Err(anyhow::anyhow!("attach not supported by this provider")),
+                },
+            }
+        };
+        let (result, _, _) = futures::join!(
+            streams,
+            send_output(stdout_reader, STDOUT, outgoing.clone()),
+            send_output(stderr_reader, STDERR, outgoing.clone()),
+        );
+        if let Err(e) = &result {
+            error!("Error running {}: {:?}", operation.name(), e);
+        }
+        if let Some(outcome) = protocol.outcome(&command, &result) {
+            let _ = outgoing.send(frame(ERROR, &outcome)).await;
+        }
+    };
+
+    let streams = async {
+        match operation {
+            Operation::Exec => {
+                futures::join!(run, forward_output);
+            }
+            // Once the client has gone, there is nothing left to attach to
+            // its streams, so the attachment is dropped, which detaches from
+            // the container without stopping it
+            Operation::Attach => {
+                let detached = &mut receive_input;
+                let attached = async move {
+                    tokio::select! {
+                        _ = run => (),
+                        _ = detached => debug!("Client detached"),
+                    }
+                };
+                futures::join!(attached, forward_output);
+            }
+        }
+    };
+    tokio::select! {
+        _ = streams => (),
+        _ = idle(&activity, idle_timeout) => debug!(
+            "Closing {} session after {}s without traffic",
+            operation.name(),
+            idle_timeout.as_secs()
+        ),
+    }
+    receive_input.abort();
+}
+
+/// Waits until nothing has been sent either way for `timeout`, which never
+/// happens if it is zero
+async fn idle(activity: &Notify, timeout: Duration) {
+    if timeout == Duration::from_secs(0) {
+        return futures::future::pending().await;
+    }
+    while tokio::time::timeout(timeout, activity.notified())
+        .await
+        .is_ok()
+    {}
+}
+
+/// Creates a pipe for a stream, if the client asked for it
+fn pipe_if(requested: bool) -> (Option<DuplexStream>, Option<DuplexStream>) {
+    if requested {
+        let (a, b) = tokio::io::duplex(PIPE_SIZE);
+        (Some(a), Some(b))
+    } else {
+        (None, None)
+    }
+}
+
+/// A message for `channel`, which is its first byte
+pub(super) fn frame(channel: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(channel);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Sends output to the client on `channel` until the command closes it
+async fn send_output(output: Option<DuplexStream>, channel: u8, outgoing: mpsc::Sender<Vec<u8>>) {
+    let mut output = match output {
+        Some(output) => output,
+        None => return,
+    };
+    let mut buf = vec![0; PIPE_SIZE];
+    loop {
+        match output.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            // Output is still read if the client has gone, so that the
+            // command isn't blocked writing it
+            Ok(n) => {
+                let _ = outgoing.send(frame(channel, &buf[..n])).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocols_are_negotiated() {
+        assert_eq!(Protocol::negotiate(None), Some(Protocol::V1));
+        assert_eq!(
+            Protocol::negotiate(Some("v4.channel.k8s.io, channel.k8s.io")),
+            Some(Protocol::V4)
+        );
+        assert_eq!(
+            Protocol::negotiate(Some("v3.channel.k8s.io,channel.k8s.io")),
+            Some(Protocol::V1)
+        );
+        assert_eq!(Protocol::negotiate(Some("base64.channel.k8s.io")), None);
+    }
+
+    #[test]
+    fn commands_are_parsed_from_repeated_parameters() {
+        let options =
+            StreamOptions::parse("command=ls&command=-l&command=%2Fdata&input=1&output=1&tty=true");
+        assert_eq!(
+            options,
+            StreamOptions {
+                command: vec!["ls".to_owned(), "-l".to_owned(), "/data".to_owned()],
+                stdin: true,
+                stdout: true,
+                stderr: false,
+                tty: true,
+            }
+        );
+        assert!(options.validate(Operation::Exec).is_ok());
+        assert!(StreamOptions::parse("output=1")
+            .validate(Operation::Exec)
+            .is_err());
+        assert!(StreamOptions::parse("command=ls")
+            .validate(Operation::Exec)
+            .is_err());
+    }
+
+    #[test]
+    fn attach_does_not_need_a_command() {
+        let options = StreamOptions::parse("stdin=true&stdout=true");
+        assert!(options.validate(Operation::Attach).is_ok());
+        assert!(StreamOptions::parse("tty=1")
+            .validate(Operation::Attach)
+            .is_err());
+    }
+
+    #[test]
+    fn outcomes_are_reported_like_other_kubelets() {
+        let command = vec!["false".to_owned()];
+        let status = |bytes: Option<Vec<u8>>| -> serde_json::Value {
+            serde_json::from_slice(&bytes.unwrap()).unwrap()
+        };
+
+        let success = status(Protocol::V4.outcome(&command, &Ok(ExitCode(0))));
+        assert_eq!(success["status"], "Success");
+
+        let failure = status(Protocol::V4.outcome(&command, &Ok(ExitCode(2))));
+        assert_eq!(failure["status"], "Failure");
+        assert_eq!(failure["reason"], "NonZeroExitCode");
+        assert_eq!(failure["details"]["causes"][0]["reason"], "ExitCode");
+        assert_eq!(failure["details"]["causes"][0]["message"], "2");
+
+        let unsupported = status(Protocol::V4.outcome(
+            &command,
+            &Err(anyhow::anyhow!("exec not supported by this provider")),
+        ));
+        assert_eq!(unsupported["status"], "Failure");
+        assert_eq!(
+            unsupported["message"],
+            "exec not supported by this provider"
+        );
+
+        assert_eq!(Protocol::V1.outcome(&command, &Ok(ExitCode(0))), None);
+        assert_eq!(
+            Protocol::V1.outcome(&command, &Err(anyhow::anyhow!("no such container"))),
+            Some(b"no such container".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_are_idle_once_nothing_has_been_sent_for_the_timeout() {
+        let activity = Notify::new();
+        let timeout = Duration::from_millis(50);
+        let traffic = async {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                activity.notify_one();
+            }
+        };
+        let start = std::time::Instant::now();
+        futures::join!(idle(&activity, timeout), traffic);
+        // The last message is sent after 80ms, and the session is idle 50ms
+        // after that
+        assert!(start.elapsed() >= Duration::from_millis(130));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_never_idle_without_a_timeout() {
+        let activity = Notify::new();
+        let idle = idle(&activity, Duration::from_secs(0));
+        assert!(tokio::time::timeout(Duration::from_millis(50), idle)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Serving the Kubelet server over TLS
+//!
+//! Warp's TLS server doesn't tell handlers which client certificate a
+//! connection authenticated with, so connections are accepted here instead.
+//! The identity of each authenticated connection is added to the extensions
+//! of its requests as a [`ClientIdentity`].
+//!
+//! The TLS configuration is read for each connection, so that it can be
+//! replaced when the serving certificate is renewed.
+
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use hyper::server::conn::Http;
+use hyper::{Body, Request, Response};
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore,
+    Session,
+};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tokio_rustls::TlsAcceptor;
+use tower::{Service, ServiceExt};
+use tracing::{debug, warn};
+
+use super::auth::ClientIdentity;
+use crate::config::ServerConfig;
+
+/// The TLS configuration of a running server
+pub(crate) type SharedTlsConfig = Arc<RwLock<rustls::ServerConfig>>;
+
+/// Creates the TLS configuration of the server from the configured
+/// certificate and private key files. If a client CA is configured, clients
+/// may authenticate with a certificate signed by it.
+pub(crate) fn tls_config(config: &ServerConfig) -> anyhow::Result<rustls::ServerConfig> {
+    tls_config_with_certificate(
+        config,
+        &read(&config.cert_file)?,
+        &read(&config.private_key_file)?,
+    )
+    .map_err(|e| {
+        anyhow::anyhow!(
+            "unable to load {} and {}: {}",
+            config.cert_file.display(),
+            config.private_key_file.display(),
+            e
+        )
+    })
+}
+
+/// Creates the TLS configuration of the server with the given PEM encoded
+/// certificate chain and private key
+pub(crate) fn tls_config_with_certificate(
+    config: &ServerConfig,
+    certificate: &[u8],
+    private_key: &[u8],
+) -> anyhow::Result<rustls::ServerConfig> {
+    let verifier = match &config.client_ca_file {
+        Some(path) => AllowAnyAnonymousOrAuthenticatedClient::new(load_client_ca(path)?),
+        None => NoClientAuth::new(),
+    };
+    let mut tls = rustls::ServerConfig::new(verifier);
+    tls.set_single_cert(
+        load_certificates(certificate)?,
+        load_private_key(private_key)?,
+    )
+    .map_err(|e| anyhow::anyhow!("invalid TLS certificate or private key: {}", e))?;
+    tls.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(tls)
+}
+
+/// Returns when the first certificate in a PEM encoded certificate chain
+/// becomes valid and when it expires
+pub(crate) fn certificate_validity(
+    certificate: &[u8],
+) -> anyhow::Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let certificate = load_certificates(certificate)?.remove(0);
+    let certificate = super::x509::Certificate::from_der(&certificate.0)
+        .map_err(|e| anyhow::anyhow!("unable to parse certificate: {}", e))?;
+    Ok((certificate.not_before, certificate.not_after))
+}
+
+/// Serves the given service over TLS to connections accepted by the
+/// listener
+pub(crate) async fn serve<S>(
+    listener: TcpListener,
+    tls: SharedTlsConfig,
+    service: S,
+) -> anyhow::Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Unable to accept connection: {:?}", e);
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(tls.read().await.clone()));
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {:?}", peer, e);
+                    return;
+                }
+            };
+            let identity = client_identity(stream.get_ref().1);
+            let service = hyper::service::service_fn(move |mut request: Request<Body>| {
+                if let Some(identity) = &identity {
+                    request.extensions_mut().insert(identity.clone());
+                }
+                service.clone().oneshot(request)
+            });
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Error serving connection from {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Returns the identity of the client if it authenticated with a certificate
+/// that names a user
+fn client_identity(session: &rustls::ServerSession) -> Option<ClientIdentity> {
+    let certificate = session.get_peer_certificates()?.into_iter().next()?;
+    match ClientIdentity::from_der(&certificate.0) {
+        Ok(identity) => Some(identity),
+        Err(e) => {
+            warn!("Rejecting client certificate: {:?}", e);
+            None
+        }
+    }
+}
+
+fn load_certificates(mut pem: &[u8]) -> anyhow::Result<Vec<Certificate>> {
+    match pemfile::certs(&mut pem) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(anyhow::anyhow!("no PEM encoded certificates found")),
+    }
+}
+
+fn load_private_key(pem: &[u8]) -> anyhow::Result<PrivateKey> {
+    let invalid = || anyhow::anyhow!("no PEM encoded private key found");
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..]).map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..]).map_err(|_| invalid())?;
+    }
+    keys.into_iter().next().ok_or_else(invalid)
+}
+
+fn load_client_ca(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut &read(path)?[..]) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(anyhow::anyhow!(
+            "no valid PEM encoded CA certificates found in {}",
+            path.display()
+        )),
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("unable to read {}: {}", path.display(), e))
+}
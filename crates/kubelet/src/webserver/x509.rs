@@ -0,0 +1,113 @@
+//! Reading the fields of X.509 certificates that the server needs
+//!
+//! Certificates are only parsed here, not verified. Client certificates are
+//! verified during the TLS handshake, and the serving certificate is issued by
+//! the cluster.
+
+use chrono::{DateTime, Utc};
+use yasna::models::{GeneralizedTime, ObjectIdentifier, UTCTime};
+use yasna::tags::{TAG_GENERALIZEDTIME, TAG_UTCTIME};
+use yasna::{ASN1Error, ASN1ErrorKind, Tag};
+
+/// The fields of a certificate
+pub(crate) struct Certificate {
+    /// The attributes of the subject name, with their values
+    pub subject: Vec<(ObjectIdentifier, String)>,
+    /// When the certificate becomes valid
+    pub not_before: DateTime<Utc>,
+    /// When the certificate expires
+    pub not_after: DateTime<Utc>,
+    /// The extensions, with their DER encoded values
+    pub extensions: Vec<(ObjectIdentifier, Vec<u8>)>,
+}
+
+impl Certificate {
+    /// Parses a DER encoded certificate
+    pub(crate) fn from_der(certificate: &[u8]) -> yasna::ASN1Result<Self> {
+        let tbs_certificate = yasna::parse_der(certificate, |r| {
+            r.read_sequence(|r| {
+                let tbs_certificate = r.next().read_der()?;
+                // The signature algorithm and signature
+                r.next().read_der()?;
+                r.next().read_der()?;
+                Ok(tbs_certificate)
+            })
+        })?;
+        let (validity, subject, extensions) = yasna::parse_der(&tbs_certificate, |r| {
+            r.read_sequence(|r| {
+                // The version is only present for v2 and v3 certificates
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                // The serial number, signature algorithm and issuer
+                for _ in 0..3 {
+                    r.next().read_der()?;
+                }
+                let validity = r.next().read_der()?;
+                let subject = r.next().read_der()?;
+                // The subject public key info
+                r.next().read_der()?;
+                // The optional unique IDs and extensions
+                let mut extensions = None;
+                while let Some(value) = r.read_optional(|r| r.read_tagged_der())? {
+                    if value.tag() == Tag::context(3) {
+                        extensions = Some(value.value().to_vec());
+                    }
+                }
+                Ok((validity, subject, extensions))
+            })
+        })?;
+
+        let (not_before, not_after) = yasna::parse_der(&validity, |r| {
+            r.read_sequence(|r| {
+                let not_before = read_time(r.next())?;
+                let not_after = read_time(r.next())?;
+                Ok((not_before, not_after))
+            })
+        })?;
+        let subject = yasna::parse_der(&subject, |r| {
+            let mut attributes = vec![];
+            r.read_sequence_of(|r| {
+                r.read_set_of(|r| {
+                    attributes.push(r.read_sequence(|r| {
+                        let oid = r.next().read_oid()?;
+                        let value = r.next().read_tagged_der()?;
+                        Ok((oid, String::from_utf8_lossy(value.value()).into_owned()))
+                    })?);
+                    Ok(())
+                })
+            })?;
+            Ok(attributes)
+        })?;
+        let extensions = match extensions {
+            Some(extensions) => yasna::parse_der(&extensions, |r| {
+                r.collect_sequence_of(|r| {
+                    r.read_sequence(|r| {
+                        let oid = r.next().read_oid()?;
+                        r.read_optional(|r| r.read_bool())?;
+                        let value = r.next().read_bytes()?;
+                        Ok((oid, value))
+                    })
+                })
+            })?,
+            None => vec![],
+        };
+        Ok(Certificate {
+            subject,
+            not_before,
+            not_after,
+            extensions,
+        })
+    }
+}
+
+/// Reads a time that is either a `UTCTime` or a `GeneralizedTime`
+fn read_time(r: yasna::BERReader) -> yasna::ASN1Result<DateTime<Utc>> {
+    let time = r.read_tagged_der()?;
+    let parsed = if time.tag() == TAG_UTCTIME {
+        UTCTime::parse(time.value()).map(|t| *t.datetime())
+    } else if time.tag() == TAG_GENERALIZEDTIME {
+        GeneralizedTime::parse(time.value()).map(|t| *t.datetime())
+    } else {
+        None
+    };
+    parsed.ok_or_else(|| ASN1Error::new(ASN1ErrorKind::Invalid))
+}
@@ -5,11 +5,14 @@
 
 use crate::errors::*;
 use crate::manifest::{
-    OciDescriptor, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
-    IMAGE_MANIFEST_MEDIA_TYPE,
+    OciDescriptor, OciImageIndex, OciManifest, Platform, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
 };
+use crate::proxy::ProxyConfig;
 use crate::secrets::RegistryAuth;
 use crate::secrets::*;
+use crate::token_cache::{RegistryToken, TokenCache, TokenCacheKey};
 use crate::Reference;
 
 use anyhow::Context;
@@ -19,10 +22,40 @@ use hyperx::header::Header;
 use reqwest::header::HeaderMap;
 use sha2::Digest;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tracing::debug;
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
 
+/// The client ID sent to auth services when exchanging an identity token
+const OAUTH2_CLIENT_ID: &str = "oci-distribution";
+
+/// The progress of pulling the layers of an image or module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PullProgress {
+    /// The number of layer bytes downloaded so far
+    pub downloaded_bytes: u64,
+    /// The total size of the layers being pulled, as given by the manifest
+    pub total_bytes: u64,
+}
+
+/// Counts the bytes downloaded across all of the layers of a pull
+struct ProgressTracker<'a> {
+    downloaded_bytes: AtomicU64,
+    total_bytes: u64,
+    callback: &'a (dyn Fn(PullProgress) + Send + Sync),
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn add(&self, bytes: u64) {
+        let downloaded_bytes = self.downloaded_bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        (self.callback)(PullProgress {
+            downloaded_bytes,
+            total_bytes: self.total_bytes,
+        });
+    }
+}
+
 /// The data for an image or module.
 #[derive(Clone)]
 pub struct ImageData {
@@ -30,6 +63,11 @@ pub struct ImageData {
     pub layers: Vec<ImageLayer>,
     /// The digest of the image or module.
     pub digest: Option<String>,
+    /// The manifest the image or module was pulled with, if known.
+    pub manifest: Option<OciManifest>,
+    /// The manifest exactly as the registry served it, if known. Its digest
+    /// is `digest`.
+    pub raw_manifest: Option<Vec<u8>>,
 }
 
 impl ImageData {
@@ -99,10 +137,9 @@ impl ImageLayer {
 ///
 /// For true anonymous access, you can skip `auth()`. This is not recommended
 /// unless you are sure that the remote registry does not require Oauth2.
-#[derive(Default)]
 pub struct Client {
     config: ClientConfig,
-    tokens: HashMap<String, RegistryToken>,
+    tokens: TokenCache,
     client: reqwest::Client,
 }
 
@@ -114,13 +151,29 @@ pub trait ClientConfigSource {
     fn client_config(&self) -> ClientConfig;
 }
 
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(ClientConfig::default())
+    }
+}
+
 impl Client {
     /// Create a new client with the supplied config
+    ///
+    /// Proxy settings are read from the environment, with any proxy settings
+    /// in the config taking precedence.
     pub fn new(config: ClientConfig) -> Self {
+        let proxy = ProxyConfig::from_env().with_override(config.proxy.clone());
+        // NOTE: Setting a proxy disables reqwest's own environment based proxy
+        // detection, so all proxy decisions are made by our `ProxyConfig`
+        let client = reqwest::Client::builder()
+            .proxy(proxy.to_reqwest())
+            .build()
+            .expect("unable to build HTTP client");
         Self {
             config,
-            tokens: HashMap::new(),
-            client: reqwest::Client::new(),
+            tokens: TokenCache::default(),
+            client,
         }
     }
 
@@ -129,6 +182,15 @@ impl Client {
         Self::new(config_source.client_config())
     }
 
+    /// The layer media types this client was configured to accept
+    pub fn accepted_media_types(&self) -> Vec<String> {
+        if self.config.accepted_media_types.is_empty() {
+            vec![WASM_LAYER_MEDIA_TYPE.to_owned()]
+        } else {
+            self.config.accepted_media_types.clone()
+        }
+    }
+
     /// Pull an image and return the bytes
     ///
     /// The client will check if it's already been authenticated and if
@@ -138,28 +200,54 @@ impl Client {
         image: &Reference,
         auth: &RegistryAuth,
         accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<ImageData> {
+        self.pull_with_progress(image, auth, accepted_media_types, &|_| ())
+            .await
+    }
+
+    /// Pull an image and return the bytes, calling `progress` as the layers
+    /// are downloaded
+    ///
+    /// Layers are pulled in parallel, so the progress is the total across
+    /// all of them.
+    pub async fn pull_with_progress(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        progress: &(dyn Fn(PullProgress) + Send + Sync),
     ) -> anyhow::Result<ImageData> {
         debug!("Pulling image: {:?}", image);
 
-        if !self.tokens.contains_key(image.registry()) {
+        if !self
+            .tokens
+            .contains_fresh(&TokenCacheKey::new(image, &RegistryOperation::Pull))
+        {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
-        let (manifest, digest) = self.pull_manifest(image).await?;
+        let (manifest, raw_manifest, digest) = self.pull_manifest(image, auth).await?;
 
         self.validate_layers(&manifest, accepted_media_types)
             .await?;
 
-        let layers = manifest.layers.into_iter().map(|layer| {
+        let tracker = ProgressTracker {
+            downloaded_bytes: AtomicU64::new(0),
+            total_bytes: manifest.layers.iter().map(|l| l.size.max(0) as u64).sum(),
+            callback: progress,
+        };
+        let layers = manifest.layers.iter().map(|layer| {
             // This avoids moving `self` which is &mut Self
             // into the async block. We only want to capture
             // as &Self
             let this = &self;
+            let tracker = &tracker;
             async move {
                 let mut out: Vec<u8> = Vec::new();
                 debug!("Pulling image layer");
-                this.pull_layer(image, &layer.digest, &mut out).await?;
-                Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
+                this.pull_layer(image, auth, &layer.digest, &mut out, Some(tracker))
+                    .await?;
+                Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type.clone()))
             }
         });
 
@@ -168,6 +256,8 @@ impl Client {
         Ok(ImageData {
             layers,
             digest: Some(digest),
+            manifest: Some(manifest),
+            raw_manifest: Some(raw_manifest.into_bytes()),
         })
     }
 
@@ -191,7 +281,10 @@ impl Client {
     ) -> anyhow::Result<String> {
         debug!("Pushing image: {:?}", image_ref);
 
-        if !self.tokens.contains_key(image_ref.registry()) {
+        if !self
+            .tokens
+            .contains_fresh(&TokenCacheKey::new(image_ref, &RegistryOperation::Push))
+        {
             self.auth(image_ref, auth, &RegistryOperation::Push).await?;
         }
 
@@ -229,9 +322,10 @@ impl Client {
     /// Perform an OAuth v2 auth request if necessary.
     ///
     /// This performs authorization and then stores the token internally to be used
-    /// on other requests.
+    /// on other requests. Tokens are cached per registry, repository and
+    /// operation until they expire.
     async fn auth(
-        &mut self,
+        &self,
         image: &Reference,
         authentication: &RegistryAuth,
         operation: &RegistryOperation,
@@ -273,17 +367,32 @@ impl Client {
             query.push(("service", s))
         }
 
-        // TODO: At some point in the future, we should support sending a secret to the
-        // server for auth. This particular workflow is for read-only public auth.
         debug!("Making authentication call to {}", realm);
 
-        let auth_res = self
-            .client
-            .get(realm)
-            .query(&query)
-            .apply_authentication(authentication)
-            .send()
-            .await?;
+        let auth_res = match authentication {
+            // Identity tokens are OAuth2 refresh tokens, which must be exchanged
+            // using a POST to the realm rather than the usual GET
+            RegistryAuth::IdentityToken(identity_token) => {
+                let mut form = vec![
+                    ("grant_type", "refresh_token"),
+                    ("client_id", OAUTH2_CLIENT_ID),
+                    ("refresh_token", identity_token),
+                    ("scope", &scope),
+                ];
+                if let Some(s) = service {
+                    form.push(("service", s))
+                }
+                self.client.post(realm).form(&form).send().await?
+            }
+            _ => {
+                self.client
+                    .get(realm)
+                    .query(&query)
+                    .apply_authentication(authentication)
+                    .send()
+                    .await?
+            }
+        };
 
         match auth_res.status() {
             reqwest::StatusCode::OK => {
@@ -292,7 +401,8 @@ impl Client {
                 let token: RegistryToken = serde_json::from_str(&text)
                     .context("Failed to decode registry token from auth request")?;
                 debug!("Succesfully authorized for image '{:?}'", image);
-                self.tokens.insert(image.registry().to_owned(), token);
+                self.tokens
+                    .insert(TokenCacheKey::new(image, operation), token);
                 Ok(())
             }
             _ => {
@@ -312,33 +422,19 @@ impl Client {
         image: &Reference,
         auth: &RegistryAuth,
     ) -> anyhow::Result<String> {
-        if !self.tokens.contains_key(image.registry()) {
+        if !self
+            .tokens
+            .contains_fresh(&TokenCacheKey::new(image, &RegistryOperation::Pull))
+        {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
-        let url = self.to_v2_manifest_url(image);
-        debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
-
-        // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
-        // Obviously, HTTP servers are going to send other codes. This tries to catch the
-        // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
-        match res.status() {
-            reqwest::StatusCode::OK => digest_header_value(&res),
-            s if s.is_client_error() => {
-                // According to the OCI spec, we should see an error in the message body.
-                let err = res.json::<OciEnvelope>().await?;
-                // FIXME: This should not have to wrap the error.
-                Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
-            }
-            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
-            s => Err(anyhow::anyhow!(
-                "An unexpected error occured: code={}, message='{}'",
-                s,
-                res.text().await?
-            )),
+        let (text, digest) = self.pull_manifest_text(image, auth).await?;
+        // For a manifest list, the digest that identifies the image is that of
+        // the manifest for our platform
+        match self.select_index_entry(image, &text)? {
+            Some(child_digest) => Ok(child_digest),
+            None => Ok(digest),
         }
     }
 
@@ -365,14 +461,58 @@ impl Client {
 
     /// Pull a manifest from the remote OCI Distribution service.
     ///
+    /// If the reference points to a manifest list or image index, the
+    /// manifest for the first matching platform in the client config is
+    /// pulled. The manifest is returned along with its raw text and digest.
+    ///
     /// If the connection has already gone through authentication, this will
     /// use the bearer token. Otherwise, this will attempt an anonymous pull.
-    async fn pull_manifest(&self, image: &Reference) -> anyhow::Result<(OciManifest, String)> {
+    async fn pull_manifest(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<(OciManifest, String, String)> {
+        let (text, digest) = self.pull_manifest_text(image, auth).await?;
+        let (text, digest) = match self.select_index_entry(image, &text)? {
+            Some(child_digest) => {
+                debug!(
+                    "Image index for '{:?}' resolved to manifest {}",
+                    image, child_digest
+                );
+                self.pull_manifest_text(&image.with_digest(&child_digest), auth)
+                    .await?
+            }
+            None => (text, digest),
+        };
+
+        self.validate_image_manifest(&text).await?;
+
+        debug!("Parsing response as OciManifest: {}", text);
+        let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
+                image
+            )
+        })?;
+        Ok((manifest, text, digest))
+    }
+
+    /// Pull the raw text and digest of a manifest or image index.
+    ///
+    /// The text is checked against the digest the registry gives for it, and
+    /// against the digest in the reference, if there is one.
+    async fn pull_manifest_text(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<(String, String)> {
         let url = self.to_v2_manifest_url(image);
         debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
+        let res = self
+            .send_with_token_refresh(image, auth, &RegistryOperation::Pull, |headers| {
+                self.client.get(&url).headers(headers)
+            })
+            .await?;
 
         // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
         // Obviously, HTTP servers are going to send other codes. This tries to catch the
@@ -380,18 +520,18 @@ impl Client {
         match res.status() {
             reqwest::StatusCode::OK => {
                 let digest = digest_header_value(&res)?;
-                let text = res.text().await?;
-
-                self.validate_image_manifest(&text).await?;
-
-                debug!("Parsing response as OciManifest: {}", text);
-                let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
-                    format!(
-                        "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
-                        image
-                    )
+                let body = res.bytes().await?;
+                verify_digest(&body, &digest).with_context(|| {
+                    format!("manifest from {} does not match its digest header", url)
                 })?;
-                Ok((manifest, digest))
+                if let Some(reference_digest) = image.digest() {
+                    verify_digest(&body, reference_digest).with_context(|| {
+                        format!("manifest from {} does not match the image reference", url)
+                    })?;
+                }
+                let text = String::from_utf8(body.to_vec())
+                    .with_context(|| format!("manifest from {} is not valid UTF-8", url))?;
+                Ok((text, digest))
             }
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
@@ -419,8 +559,7 @@ impl Client {
             ));
         }
         if let Some(media_type) = versioned.media_type {
-            // TODO: support manifest lists?
-            if media_type != IMAGE_MANIFEST_MEDIA_TYPE {
+            if media_type != IMAGE_MANIFEST_MEDIA_TYPE && media_type != OCI_IMAGE_MEDIA_TYPE {
                 return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
             }
         }
@@ -428,32 +567,80 @@ impl Client {
         Ok(())
     }
 
+    /// If the given manifest text is a manifest list or image index, returns
+    /// the digest of the manifest for the first matching platform in the
+    /// client config. Returns `None` if the text is a plain manifest.
+    fn select_index_entry(&self, image: &Reference, text: &str) -> anyhow::Result<Option<String>> {
+        let versioned: Versioned = serde_json::from_str(text)
+            .with_context(|| "Failed to parse manifest as a Versioned object")?;
+        let index: OciImageIndex = match versioned.media_type.as_deref() {
+            Some(IMAGE_MANIFEST_LIST_MEDIA_TYPE) | Some(OCI_IMAGE_INDEX_MEDIA_TYPE) => {
+                serde_json::from_str(text).with_context(|| {
+                    format!(
+                        "Failed to parse response from pulling manifest for '{:?}' as an image index",
+                        image
+                    )
+                })?
+            }
+            // The media type is optional in OCI image indexes, so fall back to
+            // checking the structure of the document
+            None => match serde_json::from_str(text) {
+                Ok(index) => index,
+                Err(_) => return Ok(None),
+            },
+            Some(_) => return Ok(None),
+        };
+        let entry = index
+            .select_platform(&self.config.platforms)
+            .with_context(|| format!("Unable to select a manifest for '{:?}'", image))?;
+        Ok(Some(entry.digest.clone()))
+    }
+
     /// Pull a single layer from an OCI registry.
     ///
     /// This pulls the layer for a particular image that is identified by
     /// the given digest. The image reference is used to find the
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
-    /// used for that.)
+    /// used for that.) Downloaded bytes are added to `tracker`, if given.
+    ///
+    /// The layer is checked against the digest once it has been downloaded,
+    /// and an error is returned if it does not match, so callers must discard
+    /// what has been written to `out` if this fails.
     async fn pull_layer<T: AsyncWrite + Unpin>(
         &self,
         image: &Reference,
+        auth: &RegistryAuth,
         digest: &str,
         mut out: T,
+        tracker: Option<&ProgressTracker<'_>>,
     ) -> anyhow::Result<()> {
         let url = self.to_v2_blob_url(image.registry(), image.repository(), digest);
         let mut stream = self
-            .client
-            .get(&url)
-            .headers(self.auth_headers(image))
-            .send()
+            .send_with_token_refresh(image, auth, &RegistryOperation::Pull, |headers| {
+                self.client.get(&url).headers(headers)
+            })
             .await?
             .bytes_stream();
 
+        let mut hasher = DigestHasher::new(digest)?;
         while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+            let bytes = bytes?;
+            hasher.update(&bytes);
+            out.write_all(&bytes).await?;
+            if let Some(tracker) = tracker {
+                tracker.add(bytes.len() as u64);
+            }
         }
 
+        let actual = hasher.finish();
+        if actual != digest {
+            return Err(anyhow::anyhow!(
+                "layer digest mismatch: expected {}, got {}",
+                digest,
+                actual
+            ));
+        }
         Ok(())
     }
 
@@ -462,7 +649,7 @@ impl Client {
     /// Returns URL with session UUID
     async fn begin_push_session(&self, image: &Reference) -> anyhow::Result<String> {
         let url = &self.to_v2_blob_upload_url(image);
-        let mut headers = self.auth_headers(image);
+        let mut headers = self.auth_headers(image, &RegistryOperation::Push);
         headers.insert("Content-Length", "0".parse().unwrap());
 
         let res = self.client.post(url).headers(headers).send().await?;
@@ -482,7 +669,7 @@ impl Client {
         digest: &str,
     ) -> anyhow::Result<String> {
         let url = format!("{}&digest={}", location, digest);
-        let mut close_headers = self.auth_headers(image);
+        let mut close_headers = self.auth_headers(image, &RegistryOperation::Push);
         close_headers.insert("Content-Length", "0".parse().unwrap());
 
         let res = self.client.put(&url).headers(close_headers).send().await?;
@@ -504,7 +691,7 @@ impl Client {
             return Err(anyhow::anyhow!("cannot push a layer without data"));
         };
         let end_byte = start_byte + layer.len() - 1;
-        let mut headers = self.auth_headers(image);
+        let mut headers = self.auth_headers(image, &RegistryOperation::Push);
         headers.insert(
             "Content-Range",
             format!("{}-{}", start_byte, end_byte).parse().unwrap(),
@@ -558,7 +745,7 @@ impl Client {
     ) -> anyhow::Result<String> {
         let url = self.to_v2_manifest_url(image);
 
-        let mut headers = self.auth_headers(image);
+        let mut headers = self.auth_headers(image, &RegistryOperation::Push);
         headers.insert(
             "Content-Type",
             "application/vnd.oci.image.manifest.v1+json"
@@ -694,17 +881,53 @@ impl Client {
         self.to_v2_blob_url(&reference.registry(), &reference.repository(), "uploads/")
     }
 
+    /// Sends the request built by `make_request`, retrying once with a fresh
+    /// token if the registry rejects the cached one.
+    ///
+    /// `make_request` is given the authentication headers for the request and
+    /// may be called twice.
+    async fn send_with_token_refresh<F>(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        operation: &RegistryOperation,
+        make_request: F,
+    ) -> anyhow::Result<reqwest::Response>
+    where
+        F: Fn(HeaderMap) -> reqwest::RequestBuilder,
+    {
+        let res = make_request(self.auth_headers(image, operation))
+            .send()
+            .await?;
+        if res.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+
+        debug!(
+            "Registry rejected token for image '{:?}', refreshing token",
+            image
+        );
+        self.tokens.remove(&TokenCacheKey::new(image, operation));
+        self.auth(image, auth, operation).await?;
+        Ok(make_request(self.auth_headers(image, operation))
+            .send()
+            .await?)
+    }
+
     /// Generate the headers necessary for authentication.
     ///
-    /// If the struct has Some(bearer), this will insert the bearer token in an
-    /// Authorization header. It will also set the Accept header, which must
-    /// be set on all OCI Registry request.
-    fn auth_headers(&self, image: &Reference) -> HeaderMap {
+    /// If a token for the image and operation is cached, this will insert the
+    /// bearer token in an Authorization header. It will also set the Accept
+    /// header, which must be set on all OCI Registry request.
+    fn auth_headers(&self, image: &Reference, operation: &RegistryOperation) -> HeaderMap {
         let mut headers = HeaderMap::new();
-        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json".parse().unwrap());
+        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json,application/vnd.oci.image.index.v1+json".parse().unwrap());
 
-        if let Some(token) = self.tokens.get(image.registry()) {
-            headers.insert("Authorization", token.bearer_token().parse().unwrap());
+        if let Some(token) = self
+            .tokens
+            .bearer_token(&TokenCacheKey::new(image, operation))
+        {
+            headers.insert("Authorization", token.parse().unwrap());
         }
         headers
     }
@@ -715,6 +938,17 @@ impl Client {
 pub struct ClientConfig {
     /// Which protocol the client should use
     pub protocol: ClientProtocol,
+    /// Proxy settings to use in addition to the standard proxy environment
+    /// variables. Values set here take precedence over the environment.
+    pub proxy: ProxyConfig,
+    /// The platforms to pull, in order of preference, when an image
+    /// reference points to a manifest list or image index. Pulling such an
+    /// image fails if none of the platforms are in the index.
+    pub platforms: Vec<Platform>,
+    /// The layer media types returned by [`Client::accepted_media_types`],
+    /// for callers that do not choose the media types they pass to `pull`
+    /// themselves. If empty, only the wasm layer media type is accepted.
+    pub accepted_media_types: Vec<String>,
 }
 
 /// The protocol that the client should use to connect
@@ -750,19 +984,6 @@ impl ClientProtocol {
     }
 }
 
-/// A token granted during the OAuth2-like workflow for OCI registries.
-#[derive(serde::Deserialize, Default)]
-struct RegistryToken {
-    #[serde(alias = "access_token")]
-    token: String,
-}
-
-impl RegistryToken {
-    fn bearer_token(&self) -> String {
-        format!("Bearer {}", self.token)
-    }
-}
-
 #[derive(Clone)]
 struct BearerChallenge {
     pub realm: Option<String>,
@@ -818,6 +1039,55 @@ fn sha256_digest(bytes: &[u8]) -> String {
     format!("sha256:{:x}", sha2::Sha256::digest(bytes))
 }
 
+/// Computes the digest of content as it is downloaded, using the algorithm of
+/// the digest the content is expected to have
+enum DigestHasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl DigestHasher {
+    fn new(expected: &str) -> anyhow::Result<Self> {
+        match expected.split_once(':') {
+            Some(("sha256", _)) => Ok(DigestHasher::Sha256(sha2::Sha256::new())),
+            Some(("sha512", _)) => Ok(DigestHasher::Sha512(sha2::Sha512::new())),
+            _ => Err(anyhow::anyhow!(
+                "unsupported digest algorithm in {}",
+                expected
+            )),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            DigestHasher::Sha256(h) => h.update(bytes),
+            DigestHasher::Sha512(h) => h.update(bytes),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            DigestHasher::Sha256(h) => format!("sha256:{:x}", h.finalize()),
+            DigestHasher::Sha512(h) => format!("sha512:{:x}", h.finalize()),
+        }
+    }
+}
+
+/// Checks that `bytes` have the given digest
+fn verify_digest(bytes: &[u8], digest: &str) -> anyhow::Result<()> {
+    let mut hasher = DigestHasher::new(digest)?;
+    hasher.update(bytes);
+    let actual = hasher.finish();
+    if actual != digest {
+        return Err(anyhow::anyhow!(
+            "digest mismatch: expected {}, got {}",
+            digest,
+            actual
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -882,6 +1152,7 @@ mod test {
     fn manifest_url_generation_respects_http_protocol() {
         let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
             .expect("Could not parse reference");
@@ -895,6 +1166,7 @@ mod test {
     fn blob_url_generation_respects_http_protocol() {
         let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
             .expect("Could not parse reference");
@@ -912,7 +1184,10 @@ mod test {
     fn manifest_url_generation_uses_https_if_not_on_exception_list() {
         let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
         let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
         let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
             .expect("Could not parse reference");
         assert_eq!(
@@ -925,7 +1200,10 @@ mod test {
     fn manifest_url_generation_uses_http_if_on_exception_list() {
         let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
         let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
         let reference = Reference::try_from("oci.registry.local/hello:v1".to_owned())
             .expect("Could not parse reference");
         assert_eq!(
@@ -938,7 +1216,10 @@ mod test {
     fn blob_url_generation_uses_https_if_not_on_exception_list() {
         let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
         let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
         let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
             .expect("Could not parse reference");
         assert_eq!(
@@ -955,7 +1236,10 @@ mod test {
     fn blob_url_generation_uses_http_if_on_exception_list() {
         let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
         let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
         let reference = Reference::try_from("oci.registry.local/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
             .expect("Could not parse reference");
         assert_eq!(
@@ -991,7 +1275,7 @@ mod test {
     async fn test_auth() {
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
-            let mut c = Client::default();
+            let c = Client::default();
             c.auth(
                 &reference,
                 &RegistryAuth::Anonymous,
@@ -1002,25 +1286,510 @@ mod test {
 
             let tok = c
                 .tokens
-                .get(reference.registry())
+                .bearer_token(&TokenCacheKey::new(&reference, &RegistryOperation::Pull))
                 .expect("token is available");
             // We test that the token is longer than a minimal hash.
-            assert!(tok.token.len() > 64);
+            assert!(tok.len() > 64);
         }
     }
 
+    #[tokio::test]
+    async fn test_https_requests_are_tunneled_through_proxy() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("unable to bind proxy listener");
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("no proxy connection");
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.expect("unable to read request");
+            socket
+                .write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .expect("unable to write response");
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let c = Client::new(ClientConfig {
+            proxy: ProxyConfig::new(&format!("http://user:secret@{}", proxy_addr)),
+            ..Default::default()
+        });
+        let reference = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        // The proxy refuses to open the tunnel, so we only care about what it received
+        let _ = c
+            .auth(
+                &reference,
+                &RegistryAuth::Anonymous,
+                &RegistryOperation::Pull,
+            )
+            .await;
+
+        let request = proxy.await.expect("proxy task failed");
+        assert!(request.starts_with("CONNECT webassembly.azurecr.io:443 HTTP/1.1"));
+        assert!(request
+            .to_ascii_lowercase()
+            .contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+    }
+
+    /// A registry that requires bearer tokens, counting how many tokens it has issued
+    struct FakeRegistry {
+        addr: std::net::SocketAddr,
+        state: std::sync::Arc<FakeRegistryState>,
+    }
+
+    #[derive(Default)]
+    struct FakeRegistryState {
+        token_requests: std::sync::atomic::AtomicUsize,
+        // Tokens numbered at or below this are rejected by the registry
+        rejected_tokens: usize,
+        issued_at: Option<String>,
+        token_request_bodies: std::sync::Mutex<Vec<String>>,
+        // Serve the image's tag as an image index pointing to the manifest
+        serve_index: bool,
+        // Serve a digest header that doesn't match the manifest
+        wrong_manifest_digest: bool,
+        // Serve different content for the layer than the manifest names
+        corrupt_layer: bool,
+    }
+
+    const FAKE_LAYER: &[u8] = b"hello";
+
+    /// The manifest of the fake registry's image
+    fn fake_manifest() -> String {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                "digest": sha256_digest(b"{}"),
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                "digest": sha256_digest(FAKE_LAYER),
+                "size": FAKE_LAYER.len(),
+            }],
+        })
+        .to_string()
+    }
+
+    impl FakeRegistry {
+        async fn start(state: FakeRegistryState) -> Self {
+            use hyper::service::{make_service_fn, service_fn};
+            let state = std::sync::Arc::new(state);
+            let service_state = state.clone();
+            let make_svc = make_service_fn(move |_| {
+                let state = service_state.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        let state = state.clone();
+                        async move { Ok::<_, std::convert::Infallible>(state.handle(req).await) }
+                    }))
+                }
+            });
+            let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            FakeRegistry { addr, state }
+        }
+
+        fn reference(&self) -> Reference {
+            Reference::try_from(format!("{}/hello:v1", self.addr)).unwrap()
+        }
+
+        fn token_requests(&self) -> usize {
+            self.state
+                .token_requests
+                .load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl FakeRegistryState {
+        async fn handle(&self, req: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+            let host = req.headers()["host"].to_str().unwrap().to_owned();
+            let path = req.uri().path().to_owned();
+            if path == "/token" {
+                let n = self
+                    .token_requests
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                    + 1;
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                self.token_request_bodies
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&body).to_string());
+                let token = serde_json::json!({
+                    "token": format!("token-{}", n),
+                    "expires_in": 300,
+                    "issued_at": self.issued_at,
+                });
+                return hyper::Response::new(token.to_string().into());
+            }
+
+            let authorized = req
+                .headers()
+                .get("authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Bearer token-"))
+                .and_then(|n| n.parse::<usize>().ok())
+                .map(|n| n > self.rejected_tokens)
+                .unwrap_or(false);
+            if !authorized {
+                return hyper::Response::builder()
+                    .status(401)
+                    .header(
+                        "WWW-Authenticate",
+                        format!(
+                            r#"Bearer realm="http://{}/token",service="fake-registry""#,
+                            host
+                        ),
+                    )
+                    .body(r#"{"errors":[]}"#.into())
+                    .unwrap();
+            }
+
+            let layer_digest = sha256_digest(FAKE_LAYER);
+            let manifest = fake_manifest();
+            let manifest_digest = sha256_digest(manifest.as_bytes());
+            if path == "/v2/hello/manifests/v1" && self.serve_index {
+                let index = serde_json::json!({
+                    "schemaVersion": 2,
+                    "mediaType": manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+                    "manifests": [
+                        {
+                            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                            "digest": sha256_digest(b"linux"),
+                            "size": 10,
+                            "platform": { "os": "linux", "architecture": "amd64" },
+                        },
+                        {
+                            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                            "digest": manifest_digest,
+                            "size": manifest.len(),
+                            "platform": { "os": "wasi", "architecture": "wasm" },
+                        },
+                    ],
+                })
+                .to_string();
+                hyper::Response::builder()
+                    .header("Docker-Content-Digest", sha256_digest(index.as_bytes()))
+                    .body(index.into())
+                    .unwrap()
+            } else if path == "/v2/hello/manifests/v1"
+                // Whatever digest is asked for, the one manifest is served
+                || path.starts_with("/v2/hello/manifests/sha256:")
+            {
+                let digest = if self.wrong_manifest_digest {
+                    sha256_digest(b"another manifest")
+                } else {
+                    manifest_digest
+                };
+                hyper::Response::builder()
+                    .header("Docker-Content-Digest", digest)
+                    .body(manifest.into())
+                    .unwrap()
+            } else if path == format!("/v2/hello/blobs/{}", layer_digest) {
+                if self.corrupt_layer {
+                    hyper::Response::new("jello".into())
+                } else {
+                    hyper::Response::new(FAKE_LAYER.into())
+                }
+            } else {
+                hyper::Response::builder()
+                    .status(404)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            }
+        }
+    }
+
+    fn http_client() -> Client {
+        Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_reused_across_pulls() {
+        let registry = FakeRegistry::start(FakeRegistryState::default()).await;
+        let reference = registry.reference();
+        let mut c = http_client();
+
+        for _ in 0..3 {
+            let image = c
+                .pull(
+                    &reference,
+                    &RegistryAuth::Anonymous,
+                    vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                )
+                .await
+                .expect("failed to pull image");
+            assert_eq!(FAKE_LAYER, image.layers[0].data.as_slice());
+        }
+
+        assert_eq!(1, registry.token_requests());
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens_are_refreshed() {
+        let issued_at = chrono::Utc::now() - chrono::Duration::seconds(600);
+        let registry = FakeRegistry::start(FakeRegistryState {
+            issued_at: Some(issued_at.to_rfc3339()),
+            ..Default::default()
+        })
+        .await;
+        let reference = registry.reference();
+        let mut c = http_client();
+
+        for _ in 0..2 {
+            c.pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("failed to pull image");
+        }
+
+        // Each pull authenticates because the token had already expired when
+        // it was issued
+        assert_eq!(2, registry.token_requests());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_tokens_are_refreshed_once() {
+        let registry = FakeRegistry::start(FakeRegistryState {
+            rejected_tokens: 1,
+            ..Default::default()
+        })
+        .await;
+        let reference = registry.reference();
+        let mut c = http_client();
+
+        let image = c
+            .pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("failed to pull image");
+        assert_eq!(FAKE_LAYER, image.layers[0].data.as_slice());
+        assert_eq!(2, registry.token_requests());
+    }
+
+    #[tokio::test]
+    async fn test_identity_tokens_use_oauth2_post_flow() {
+        let registry = FakeRegistry::start(FakeRegistryState::default()).await;
+        let reference = registry.reference();
+        let mut c = http_client();
+
+        c.pull(
+            &reference,
+            &RegistryAuth::IdentityToken("my-refresh-token".to_owned()),
+            vec![manifest::WASM_LAYER_MEDIA_TYPE],
+        )
+        .await
+        .expect("failed to pull image");
+
+        let bodies = registry.state.token_request_bodies.lock().unwrap();
+        assert_eq!(1, bodies.len());
+        assert!(bodies[0].contains("grant_type=refresh_token"));
+        assert!(bodies[0].contains("refresh_token=my-refresh-token"));
+        assert!(bodies[0].contains("service=fake-registry"));
+        assert!(bodies[0].contains("scope=repository%3Ahello%3Apull"));
+    }
+
+    fn http_client_for_platforms(platforms: Vec<Platform>) -> Client {
+        Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            platforms,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pull_reports_progress() {
+        let registry = FakeRegistry::start(FakeRegistryState::default()).await;
+        let reference = registry.reference();
+        let updates = std::sync::Mutex::new(vec![]);
+
+        http_client()
+            .pull_with_progress(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &|progress| updates.lock().unwrap().push(progress),
+            )
+            .await
+            .expect("failed to pull image");
+
+        let updates = updates.into_inner().unwrap();
+        assert!(!updates.is_empty());
+        assert_eq!(
+            Some(&PullProgress {
+                downloaded_bytes: FAKE_LAYER.len() as u64,
+                total_bytes: FAKE_LAYER.len() as u64,
+            }),
+            updates.last()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_image_index_resolves_to_platform_manifest() {
+        let registry = FakeRegistry::start(FakeRegistryState {
+            serve_index: true,
+            ..Default::default()
+        })
+        .await;
+        let reference = registry.reference();
+        let mut c = http_client_for_platforms(vec![
+            Platform::new("wasi", "wasm32"),
+            Platform::new("wasi", "wasm"),
+        ]);
+
+        let image = c
+            .pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("failed to pull image");
+        assert_eq!(FAKE_LAYER, image.layers[0].data.as_slice());
+
+        let digest = c
+            .fetch_manifest_digest(&reference, &RegistryAuth::Anonymous)
+            .await
+            .expect("failed to fetch digest");
+        assert_eq!(sha256_digest(fake_manifest().as_bytes()), digest);
+        assert_eq!(image.digest, Some(digest));
+    }
+
+    #[tokio::test]
+    async fn test_pull_keeps_raw_manifest() {
+        let registry = FakeRegistry::start(FakeRegistryState::default()).await;
+        let image = http_client()
+            .pull(
+                &registry.reference(),
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("failed to pull image");
+
+        let raw_manifest = image.raw_manifest.expect("pull should keep raw manifest");
+        assert_eq!(fake_manifest().as_bytes(), raw_manifest.as_slice());
+        assert_eq!(Some(sha256_digest(&raw_manifest)), image.digest);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_not_matching_digest_is_rejected() {
+        let registry = FakeRegistry::start(FakeRegistryState {
+            wrong_manifest_digest: true,
+            ..Default::default()
+        })
+        .await;
+        let reference = registry.reference();
+        let mut c = http_client();
+
+        let err = c
+            .pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .err()
+            .expect("pull should fail");
+        assert!(format!("{:#}", err).contains("does not match its digest header"));
+
+        let err = c
+            .fetch_manifest_digest(&reference, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("fetching digest should fail");
+        assert!(format!("{:#}", err).contains("does not match its digest header"));
+    }
+
+    #[tokio::test]
+    async fn test_manifest_not_matching_reference_digest_is_rejected() {
+        let registry = FakeRegistry::start(FakeRegistryState::default()).await;
+        // The registry serves its manifest whatever digest is asked for
+        let reference = Reference::try_from(format!(
+            "{}/hello:v1@{}",
+            registry.addr,
+            sha256_digest(b"another manifest")
+        ))
+        .unwrap();
+
+        let err = http_client()
+            .fetch_manifest_digest(&reference, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("fetching digest should fail");
+        assert!(format!("{:#}", err).contains("does not match the image reference"));
+    }
+
+    #[tokio::test]
+    async fn test_layer_not_matching_digest_is_rejected() {
+        let registry = FakeRegistry::start(FakeRegistryState {
+            corrupt_layer: true,
+            ..Default::default()
+        })
+        .await;
+
+        let err = http_client()
+            .pull(
+                &registry.reference(),
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .err()
+            .expect("pull should fail");
+        assert!(format!("{:#}", err).contains(&format!(
+            "layer digest mismatch: expected {}",
+            sha256_digest(FAKE_LAYER)
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_image_index_without_matching_platform() {
+        let registry = FakeRegistry::start(FakeRegistryState {
+            serve_index: true,
+            ..Default::default()
+        })
+        .await;
+        let reference = registry.reference();
+        let mut c = http_client_for_platforms(vec![Platform::new("windows", "amd64")]);
+
+        let err = c
+            .pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .err()
+            .expect("pull should fail");
+        assert!(format!("{:#}", err).contains(
+            "no matching platform in image index, wanted: [windows/amd64], available: [linux/amd64, wasi/wasm]"
+        ));
+    }
+
     #[tokio::test]
     async fn test_pull_manifest() {
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
-            // Currently, pull_manifest does not perform Authz, so this will fail.
+            // pull_manifest authenticates when the registry rejects the request
             let c = Client::default();
-            c.pull_manifest(&reference)
+            c.pull_manifest(&reference, &RegistryAuth::Anonymous)
                 .await
-                .expect_err("pull manifest should fail");
+                .expect("pull manifest should authenticate and not fail");
 
-            // But this should pass
-            let mut c = Client::default();
+            // And this should pass
+            let c = Client::default();
             c.auth(
                 &reference,
                 &RegistryAuth::Anonymous,
@@ -1028,8 +1797,8 @@ mod test {
             )
             .await
             .expect("authenticated");
-            let (manifest, _) = c
-                .pull_manifest(&reference)
+            let (manifest, _, _) = c
+                .pull_manifest(&reference, &RegistryAuth::Anonymous)
                 .await
                 .expect("pull manifest should not fail");
 
@@ -1073,7 +1842,7 @@ mod test {
 
     #[tokio::test]
     async fn test_pull_layer() {
-        let mut c = Client::default();
+        let c = Client::default();
 
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
@@ -1084,8 +1853,8 @@ mod test {
             )
             .await
             .expect("authenticated");
-            let (manifest, _) = c
-                .pull_manifest(&reference)
+            let (manifest, _, _) = c
+                .pull_manifest(&reference, &RegistryAuth::Anonymous)
                 .await
                 .expect("failed to pull manifest");
 
@@ -1096,7 +1865,16 @@ mod test {
             // This call likes to flake, so we try it at least 5 times
             let mut last_error = None;
             for i in 1..6 {
-                if let Err(e) = c.pull_layer(&reference, &layer0.digest, &mut file).await {
+                if let Err(e) = c
+                    .pull_layer(
+                        &reference,
+                        &RegistryAuth::Anonymous,
+                        &layer0.digest,
+                        &mut file,
+                        None,
+                    )
+                    .await
+                {
                     println!(
                         "Got error on pull_layer call attempt {}. Will retry in 1s: {:?}",
                         i, e
@@ -1128,6 +1906,8 @@ mod test {
             let mut image_data = ImageData {
                 layers: Vec::with_capacity(0),
                 digest: None,
+                manifest: None,
+                raw_manifest: None,
             };
             for i in 1..6 {
                 match Client::default()
@@ -1160,6 +1940,7 @@ mod test {
 
             assert!(!image_data.layers.is_empty());
             assert!(image_data.digest.is_some());
+            assert!(image_data.manifest.is_some());
         }
     }
 
@@ -1191,8 +1972,9 @@ mod test {
     #[ignore]
     /// Requires local registry resolveable at `oci.registry.local`
     async fn can_push_layer() {
-        let mut c = Client::new(ClientConfig {
+        let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let url = "oci.registry.local/hello-wasm:v1";
         let image: Reference = url.parse().unwrap();
@@ -1232,8 +2014,9 @@ mod test {
     #[ignore]
     /// Requires local registry resolveable at `oci.registry.local`
     async fn can_push_multiple_layers() {
-        let mut c = Client::new(ClientConfig {
+        let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let sample_uuid = "6987887f-0196-45ee-91a1-2dfad901bea0";
         let url = "oci.registry.local/hello-wasm:v1";
@@ -1295,6 +2078,7 @@ mod test {
     async fn test_image_roundtrip() {
         let mut c = Client::new(ClientConfig {
             protocol: ClientProtocol::HttpsExcept(vec!["oci.registry.local".to_string()]),
+            ..Default::default()
         });
 
         let image: Reference = HELLO_IMAGE_TAG_AND_DIGEST.parse().unwrap();
@@ -1302,8 +2086,8 @@ mod test {
             .await
             .expect("authenticated");
 
-        let (manifest, _digest) = c
-            .pull_manifest(&image)
+        let (manifest, _, _digest) = c
+            .pull_manifest(&image, &RegistryAuth::Anonymous)
             .await
             .expect("failed to pull manifest");
 
@@ -1354,8 +2138,8 @@ mod test {
             .await
             .expect("failed to pull pushed image");
 
-        let (pulled_manifest, _digest) = c
-            .pull_manifest(&push_image)
+        let (pulled_manifest, _, _digest) = c
+            .pull_manifest(&push_image, &RegistryAuth::Anonymous)
             .await
             .expect("failed to pull pushed image manifest");
 
@@ -4,9 +4,11 @@
 pub mod client;
 pub mod errors;
 pub mod manifest;
+pub mod proxy;
 mod reference;
 mod regexp;
 pub mod secrets;
+mod token_cache;
 
 #[doc(inline)]
 pub use client::Client;
@@ -3,10 +3,19 @@ use std::collections::HashMap;
 
 /// The mediatype for WASM layers.
 pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// The mediatype for WASM layers in the WebAssembly text format.
+pub const WASM_TEXT_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wat";
 /// The mediatype for a WASM image config.
 pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
 pub const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The mediatype for an OCI image manifest.
+pub const OCI_IMAGE_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The mediatype for a Docker manifest list.
+pub const IMAGE_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+/// The mediatype for an OCI image index.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
 /// The mediatype for an image config (manifest).
 pub const IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
 /// The mediatype that Docker uses for image configs.
@@ -15,6 +24,11 @@ pub const IMAGE_DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.contain
 pub const IMAGE_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
 /// The mediatype for a layer that is gzipped.
 pub const IMAGE_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// The mediatype Docker uses for a layer.
+pub const IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+/// The mediatype Docker uses for a layer that is gzipped.
+pub const IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
 /// The mediatype for a layer that is nondistributable.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar";
@@ -141,6 +155,147 @@ impl Default for OciDescriptor {
     }
 }
 
+/// The OCI image index points to the manifests of an image for different
+/// platforms. Docker manifest lists have the same structure.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciImageIndex {
+    /// This is a schema version.
+    ///
+    /// The only version allowed by the specification is `2`.
+    pub schema_version: u8,
+
+    /// This is an optional media type describing this index.
+    pub media_type: Option<String>,
+
+    /// The manifests in this index
+    pub manifests: Vec<ImageIndexEntry>,
+
+    /// The annotations for this index
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl OciImageIndex {
+    /// Returns the manifest for the first of the given platforms that has one
+    /// in the index.
+    ///
+    /// The platforms are tried in order, so later platforms act as fallbacks
+    /// for earlier ones. An error listing the available platforms is returned
+    /// if none of them match.
+    pub fn select_platform(&self, platforms: &[Platform]) -> anyhow::Result<&ImageIndexEntry> {
+        platforms
+            .iter()
+            .find_map(|wanted| {
+                self.manifests.iter().find(|entry| {
+                    entry
+                        .platform
+                        .as_ref()
+                        .map(|p| wanted.matches(p))
+                        .unwrap_or(false)
+                })
+            })
+            .ok_or_else(|| {
+                let available: Vec<String> = self
+                    .manifests
+                    .iter()
+                    .map(|entry| match &entry.platform {
+                        Some(p) => p.to_string(),
+                        None => "unknown".to_owned(),
+                    })
+                    .collect();
+                anyhow::anyhow!(
+                    "no matching platform in image index, wanted: [{}], available: [{}]",
+                    platforms
+                        .iter()
+                        .map(|p| p.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    available.join(", ")
+                )
+            })
+    }
+}
+
+/// An entry in an image index, describing the manifest for one platform.
+///
+/// It is defined in the OCI Image Specification:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md#image-index-property-descriptions
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndexEntry {
+    /// The media type of the manifest this entry points to.
+    pub media_type: String,
+    /// The digest of the manifest this entry points to.
+    pub digest: String,
+    /// The size, in bytes, of the manifest this entry points to.
+    pub size: i64,
+    /// The platform the manifest is for.
+    ///
+    /// This property is OPTIONAL. Entries without a platform never match a
+    /// requested platform.
+    pub platform: Option<Platform>,
+    /// This OPTIONAL property contains arbitrary metadata for this entry.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The platform a manifest in an image index is built for.
+///
+/// It is defined in the OCI Image Specification:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md#image-index-property-descriptions
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Platform {
+    /// The CPU architecture, such as `amd64` or `wasm32`.
+    pub architecture: String,
+    /// The operating system, such as `linux` or `wasi`.
+    pub os: String,
+    /// The version of the operating system.
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    /// The features the operating system must support.
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
+    pub os_features: Option<Vec<String>>,
+    /// The variant of the CPU architecture, such as `v8` for `arm64`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Creates a platform for the given operating system and architecture.
+    pub fn new(os: &str, architecture: &str) -> Self {
+        Platform {
+            architecture: architecture.to_owned(),
+            os: os.to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: None,
+        }
+    }
+
+    /// Returns whether the `candidate` platform satisfies this one.
+    ///
+    /// The operating system and architecture must be equal. The variant and
+    /// OS version only have to match if they are set on `self`.
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        self.os == candidate.os
+            && self.architecture == candidate.architecture
+            && (self.variant.is_none() || self.variant == candidate.variant)
+            && (self.os_version.is_none() || self.os_version == candidate.os_version)
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -195,4 +350,107 @@ mod test {
                 .len()
         );
     }
+
+    const TEST_INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 7143,
+                "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                "platform": {
+                    "architecture": "amd64",
+                    "os": "linux"
+                }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 7682,
+                "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+                "platform": {
+                    "architecture": "arm64",
+                    "os": "linux",
+                    "variant": "v8"
+                }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 512,
+                "digest": "sha256:f9c91f4c280ab92aff9eb03b279c4774a80b84428741ab20855d32004b2b983f",
+                "platform": {
+                    "architecture": "wasm",
+                    "os": "wasi"
+                }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 100,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn test_index() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        assert_eq!(2, index.schema_version);
+        assert_eq!(4, index.manifests.len());
+        let arm = index.manifests[1].platform.as_ref().expect("platform");
+        assert_eq!("linux/arm64/v8", arm.to_string());
+        assert!(index.manifests[3].platform.is_none());
+    }
+
+    #[test]
+    fn index_selects_exact_platform_match() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let entry = index
+            .select_platform(&[Platform::new("linux", "amd64")])
+            .expect("matching platform");
+        assert_eq!(
+            "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+            entry.digest
+        );
+    }
+
+    #[test]
+    fn index_falls_back_to_later_platforms() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let entry = index
+            .select_platform(&[
+                Platform::new("wasi", "wasm32"),
+                Platform::new("wasi", "wasm"),
+            ])
+            .expect("matching platform");
+        assert_eq!(
+            "sha256:f9c91f4c280ab92aff9eb03b279c4774a80b84428741ab20855d32004b2b983f",
+            entry.digest
+        );
+    }
+
+    #[test]
+    fn index_platform_variant_must_match_when_requested() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let v7 = Platform {
+            variant: Some("v7".to_owned()),
+            ..Platform::new("linux", "arm64")
+        };
+        assert!(index.select_platform(&[v7]).is_err());
+        assert!(index
+            .select_platform(&[Platform::new("linux", "arm64")])
+            .is_ok());
+    }
+
+    #[test]
+    fn index_lists_available_platforms_when_nothing_matches() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let err = index
+            .select_platform(&[Platform::new("windows", "amd64")])
+            .expect_err("no matching platform");
+        assert_eq!(
+            "no matching platform in image index, wanted: [windows/amd64], available: [linux/amd64, linux/arm64/v8, wasi/wasm, unknown]",
+            err.to_string()
+        );
+    }
 }
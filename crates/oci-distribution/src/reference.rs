@@ -87,6 +87,27 @@ impl Reference {
         self.digest.as_deref()
     }
 
+    /// with_digest returns a reference to the given digest in the same
+    /// repository.
+    pub(crate) fn with_digest(&self, digest: &str) -> Reference {
+        Reference {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: None,
+            digest: Some(digest.to_owned()),
+        }
+    }
+
+    /// with_tag returns a reference to the given tag in the same repository.
+    pub fn with_tag(&self, tag: &str) -> Reference {
+        Reference {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: Some(tag.to_owned()),
+            digest: None,
+        }
+    }
+
     /// full_name returns the full repository name and path.
     fn full_name(&self) -> String {
         if self.registry() == "" {
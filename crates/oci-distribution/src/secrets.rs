@@ -6,9 +6,14 @@ pub enum RegistryAuth {
     Anonymous,
     /// Access the registry using HTTP Basic authentication
     Basic(String, String),
+    /// Access the registry using an identity token (an OAuth2 refresh token),
+    /// as stored in the `identitytoken` field of a Docker config file. The
+    /// token is exchanged for a bearer token using the OAuth2 POST flow.
+    IdentityToken(String),
 }
 
 /// Desired operation for registry authentication
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum RegistryOperation {
     /// Authenticate for push operations
     Push,
@@ -23,7 +28,8 @@ pub(crate) trait Authenticable {
 impl Authenticable for reqwest::RequestBuilder {
     fn apply_authentication(self, auth: &RegistryAuth) -> Self {
         match auth {
-            RegistryAuth::Anonymous => self,
+            // Identity tokens are sent in the body of the token request
+            RegistryAuth::Anonymous | RegistryAuth::IdentityToken(_) => self,
             RegistryAuth::Basic(username, password) => self.basic_auth(username, Some(password)),
         }
     }
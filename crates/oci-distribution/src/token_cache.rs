@@ -0,0 +1,216 @@
+//! Caching of bearer tokens granted by registry auth services
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::secrets::RegistryOperation;
+use crate::Reference;
+
+/// The lifetime of a token when the auth service does not give one, as
+/// specified by the Docker registry token authentication spec
+const DEFAULT_TOKEN_EXPIRATION_SECS: u64 = 60;
+/// Tokens are treated as expired slightly before the auth service says they
+/// are, so a token does not expire between being read from the cache and
+/// arriving at the registry
+const TOKEN_EXPIRATION_LEEWAY: Duration = Duration::from_secs(5);
+
+/// A token granted during the OAuth2-like workflow for OCI registries.
+#[derive(serde::Deserialize, Default)]
+pub(crate) struct RegistryToken {
+    #[serde(alias = "access_token")]
+    pub token: String,
+    /// The number of seconds the token is valid for after it was issued
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// When the token was issued
+    #[serde(default)]
+    pub issued_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl RegistryToken {
+    pub fn bearer_token(&self) -> String {
+        format!("Bearer {}", self.token)
+    }
+
+    /// Returns how long the token remains valid for, measured from now
+    fn remaining_lifetime(&self) -> Duration {
+        let lifetime = Duration::from_secs(
+            self.expires_in
+                .filter(|e| *e > 0)
+                .unwrap_or(DEFAULT_TOKEN_EXPIRATION_SECS),
+        );
+        let elapsed = match self.issued_at {
+            // A negative duration means the token was issued "in the future"
+            // according to our clock, so treat it as just issued
+            Some(issued_at) => chrono::Utc::now()
+                .signed_duration_since(issued_at)
+                .to_std()
+                .unwrap_or_default(),
+            None => Duration::default(),
+        };
+        lifetime
+            .checked_sub(elapsed)
+            .and_then(|remaining| remaining.checked_sub(TOKEN_EXPIRATION_LEEWAY))
+            .unwrap_or_default()
+    }
+}
+
+/// Identifies the registry, repository and operation a token was granted for
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct TokenCacheKey {
+    registry: String,
+    repository: String,
+    operation: RegistryOperation,
+}
+
+impl TokenCacheKey {
+    pub fn new(image: &Reference, operation: &RegistryOperation) -> Self {
+        TokenCacheKey {
+            registry: image.registry().to_owned(),
+            repository: image.repository().to_owned(),
+            operation: operation.clone(),
+        }
+    }
+}
+
+struct CachedToken {
+    token: RegistryToken,
+    expires_at: Instant,
+}
+
+/// A cache of bearer tokens and when they expire.
+///
+/// The cache uses interior mutability so that tokens can be refreshed from
+/// requests that only have shared access to the client.
+#[derive(Default)]
+pub(crate) struct TokenCache {
+    tokens: RwLock<HashMap<TokenCacheKey, CachedToken>>,
+}
+
+impl TokenCache {
+    pub fn insert(&self, key: TokenCacheKey, token: RegistryToken) {
+        let expires_at = Instant::now() + token.remaining_lifetime();
+        self.tokens
+            .write()
+            .expect("token cache lock poisoned")
+            .insert(key, CachedToken { token, expires_at });
+    }
+
+    /// Returns the `Authorization` header value for the given key, if a token
+    /// is cached.
+    ///
+    /// This returns the token even if it has expired, as a token that was just
+    /// granted should be used for the requests of the operation it was granted
+    /// for. Callers should use `contains_fresh` to decide whether to fetch a
+    /// new token before starting an operation.
+    pub fn bearer_token(&self, key: &TokenCacheKey) -> Option<String> {
+        self.tokens
+            .read()
+            .expect("token cache lock poisoned")
+            .get(key)
+            .map(|cached| cached.token.bearer_token())
+    }
+
+    /// Returns whether an unexpired token is cached for the given key
+    pub fn contains_fresh(&self, key: &TokenCacheKey) -> bool {
+        self.tokens
+            .read()
+            .expect("token cache lock poisoned")
+            .get(key)
+            .map(|cached| cached.expires_at > Instant::now())
+            .unwrap_or(false)
+    }
+
+    pub fn remove(&self, key: &TokenCacheKey) {
+        self.tokens
+            .write()
+            .expect("token cache lock poisoned")
+            .remove(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn key(image: &str, operation: RegistryOperation) -> TokenCacheKey {
+        TokenCacheKey::new(&Reference::try_from(image).unwrap(), &operation)
+    }
+
+    fn token(json: &str) -> RegistryToken {
+        serde_json::from_str(json).expect("unable to parse token")
+    }
+
+    #[test]
+    fn tokens_are_scoped_to_repository_and_operation() {
+        let cache = TokenCache::default();
+        cache.insert(
+            key("myregistry.io/hello:v1", RegistryOperation::Pull),
+            token(r#"{"token": "abc"}"#),
+        );
+
+        assert_eq!(
+            Some("Bearer abc".to_owned()),
+            cache.bearer_token(&key("myregistry.io/hello:v2", RegistryOperation::Pull))
+        );
+        assert!(!cache.contains_fresh(&key("myregistry.io/hello:v1", RegistryOperation::Push)));
+        assert!(!cache.contains_fresh(&key("myregistry.io/other:v1", RegistryOperation::Pull)));
+        assert!(!cache.contains_fresh(&key("other.io/hello:v1", RegistryOperation::Pull)));
+    }
+
+    #[test]
+    fn access_token_is_accepted() {
+        let token = token(r#"{"access_token": "abc", "expires_in": 300}"#);
+        assert_eq!("abc", token.token);
+        assert_eq!(Some(300), token.expires_in);
+    }
+
+    #[test]
+    fn lifetime_defaults_when_not_given() {
+        let token = token(r#"{"token": "abc"}"#);
+        assert_eq!(
+            Duration::from_secs(DEFAULT_TOKEN_EXPIRATION_SECS) - TOKEN_EXPIRATION_LEEWAY,
+            token.remaining_lifetime()
+        );
+    }
+
+    #[test]
+    fn lifetime_accounts_for_issue_time() {
+        let issued_at = chrono::Utc::now() - chrono::Duration::seconds(100);
+        let token = token(&format!(
+            r#"{{"token": "abc", "expires_in": 300, "issued_at": "{}"}}"#,
+            issued_at.to_rfc3339()
+        ));
+        let remaining = token.remaining_lifetime();
+        assert!(remaining <= Duration::from_secs(195));
+        assert!(remaining > Duration::from_secs(190));
+    }
+
+    #[test]
+    fn expired_tokens_are_not_fresh() {
+        let cache = TokenCache::default();
+        let issued_at = chrono::Utc::now() - chrono::Duration::seconds(600);
+        let key = key("myregistry.io/hello:v1", RegistryOperation::Pull);
+        cache.insert(
+            key.clone(),
+            token(&format!(
+                r#"{{"token": "abc", "expires_in": 300, "issued_at": "{}"}}"#,
+                issued_at.to_rfc3339()
+            )),
+        );
+        assert!(!cache.contains_fresh(&key));
+        assert_eq!(Some("Bearer abc".to_owned()), cache.bearer_token(&key));
+    }
+
+    #[test]
+    fn removed_tokens_are_not_returned() {
+        let cache = TokenCache::default();
+        let key = key("myregistry.io/hello:v1", RegistryOperation::Pull);
+        cache.insert(key.clone(), token(r#"{"token": "abc"}"#));
+        cache.remove(&key);
+        assert!(!cache.contains_fresh(&key));
+        assert_eq!(None, cache.bearer_token(&key));
+    }
+}
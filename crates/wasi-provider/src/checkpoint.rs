@@ -0,0 +1,452 @@
+//! Checkpointing and restoring modules
+//!
+//! wasmtime can't save the whole state of a running module, but a module
+//! that keeps everything it needs to carry on in its linear memory and
+//! globals can be stopped at a point of its choosing and resumed later,
+//! possibly on another node. Pods opt in with the
+//! `krustlet.dev/checkpoint-path` annotation, which gives the path, in the
+//! guest, of the file the checkpoint is written to. This must be inside one
+//! of the container's volumes that it can write to, and in a volume that
+//! outlives the pod, such as a persistent volume, for the module to be
+//! resumed once the pod is rescheduled.
+//!
+//! Modules call `krustlet::checkpoint`, which takes nothing and returns an
+//! `i32`, at the points they can be resumed from. It returns 0 until the
+//! container is stopped, and then writes the exported memories and mutable
+//! globals of the module to the file and stops the module, rather than
+//! interrupting it wherever it is. A module that doesn't yield within the
+//! pod's grace period is interrupted as usual, without a checkpoint. When a
+//! container starts and the file exists, the memories and globals are
+//! restored from it and the module's `krustlet_resume` export is called
+//! instead of `_start`. The file is removed once the module completes.
+//!
+//! The stack of the module isn't saved, as wasmtime has no access to it, so
+//! modules must not rely on anything on the stack, or in globals they don't
+//! export, when they resume.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use kubelet::container::Status;
+use kubelet::pod::Pod;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tracing::{info, warn};
+
+use crate::wasi_runtime::send;
+
+/// Pod annotation giving the guest path checkpoints are written to
+pub(crate) const CHECKPOINT_ANNOTATION: &str = "krustlet.dev/checkpoint-path";
+/// The module the checkpoint function is imported from
+pub(crate) const CHECKPOINT_MODULE: &str = "krustlet";
+/// The function modules call where they can be checkpointed
+pub(crate) const CHECKPOINT_FUNCTION: &str = "checkpoint";
+/// The function restored modules are resumed at
+pub(crate) const RESUME: &str = "krustlet_resume";
+/// The reason given when a container stopped after writing a checkpoint
+pub(crate) const CHECKPOINTED: &str = "Checkpointed";
+/// The status message of a container while it writes a checkpoint
+pub(crate) const CHECKPOINTING: &str = "Writing checkpoint";
+
+/// The first line of checkpoint files, which changes with their format
+const SNAPSHOT_MAGIC: &str = "krustlet-checkpoint-v1";
+/// The size of WebAssembly pages
+const WASM_PAGE_SIZE: usize = 65536;
+
+/// Returns the host path of the file the container's checkpoints are written
+/// to, if the pod asks for them, given the container's volumes as mapped by
+/// `volume_path_map`.
+///
+/// This returns an error if the guest path isn't in a volume the container
+/// can write to.
+pub(crate) fn checkpoint_path(
+    pod: &Pod,
+    dirs: &HashMap<PathBuf, Option<PathBuf>>,
+    read_only_dirs: &HashSet<PathBuf>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let guest_path = match pod.get_annotation(CHECKPOINT_ANNOTATION) {
+        Some(path) => Path::new(path),
+        None => return Ok(None),
+    };
+    // The innermost volume the path is in holds it
+    let (host_dir, relative) = dirs
+        .iter()
+        .filter_map(|(host, guest)| {
+            let guest = guest.as_ref().unwrap_or(host);
+            let relative = guest_path.strip_prefix(guest).ok()?;
+            Some((host, guest.components().count(), relative))
+        })
+        .max_by_key(|(_, depth, _)| *depth)
+        .map(|(host, _, relative)| (host, relative))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "checkpoint path {} is not in any of the container's volumes",
+                guest_path.display()
+            )
+        })?;
+    if relative.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!(
+            "checkpoint path {} must be a file in a volume, not the volume itself",
+            guest_path.display()
+        ));
+    }
+    if read_only_dirs.contains(host_dir) {
+        return Err(anyhow::anyhow!(
+            "checkpoint path {} is in a read-only volume",
+            guest_path.display()
+        ));
+    }
+    Ok(Some(host_dir.join(relative)))
+}
+
+/// The checkpointing of a container's module
+#[derive(Debug)]
+pub(crate) struct Checkpointing {
+    /// The file the checkpoint is written to
+    pub(crate) path: PathBuf,
+    /// Whether the module has been asked to checkpoint
+    pub(crate) request: Arc<CheckpointRequest>,
+    /// The snapshot the module resumes from, if it is restored
+    pub(crate) restore: Option<Snapshot>,
+}
+
+impl Checkpointing {
+    /// Removes the checkpoint, once the module has nothing to resume
+    pub(crate) fn remove(&self) {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => warn!(
+                "Unable to remove checkpoint {}: {:?}",
+                self.path.display(),
+                e
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// Creates the `krustlet::checkpoint` function for the instance that will be
+/// put in `instance`. Without checkpointing, it always returns 0, so that
+/// modules that checkpoint can run in pods that don't ask for it.
+pub(crate) fn checkpoint_function(
+    store: &wasmtime::Store,
+    name: &str,
+    checkpointing: Option<(&Checkpointing, Rc<RefCell<Option<wasmtime::Instance>>>)>,
+    status_sender: Option<Sender<Status>>,
+) -> wasmtime::Func {
+    let (path, request, instance) = match checkpointing {
+        Some((checkpointing, instance)) => (
+            checkpointing.path.clone(),
+            checkpointing.request.clone(),
+            instance,
+        ),
+        None => return wasmtime::Func::wrap(store, || 0i32),
+    };
+    let name = name.to_owned();
+    wasmtime::Func::wrap(store, move || -> Result<i32, wasmtime::Trap> {
+        if !request.is_requested() {
+            return Ok(0);
+        }
+        info!("{} writing checkpoint to {}", name, path.display());
+        send(
+            status_sender.as_ref(),
+            &name,
+            Status::waiting(CHECKPOINTING),
+        );
+        let instance = instance.borrow();
+        let instance = instance
+            .as_ref()
+            .ok_or_else(|| wasmtime::Trap::new("module checkpointed while it was instantiated"))?;
+        Snapshot::capture(instance)
+            .and_then(|snapshot| snapshot.write_to(&path))
+            .map_err(|e| wasmtime::Trap::new(format!("unable to write checkpoint: {}", e)))?;
+        request.set_taken();
+        // Trapping unwinds the module, which is stopped rather than returned to
+        Err(wasmtime::Trap::new(
+            "module stopped after writing a checkpoint",
+        ))
+    })
+}
+
+/// Whether the running module should be checkpointed, and whether it has
+/// been
+#[derive(Debug, Default)]
+pub(crate) struct CheckpointRequest {
+    requested: AtomicBool,
+    taken: AtomicBool,
+}
+
+impl CheckpointRequest {
+    /// Asks the module to write a checkpoint and stop, returning whether it
+    /// had not been asked already
+    pub(crate) fn request(&self) -> bool {
+        !self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_taken(&self) {
+        self.taken.store(true, Ordering::SeqCst)
+    }
+
+    /// Whether the module stopped after writing a checkpoint
+    pub(crate) fn is_taken(&self) -> bool {
+        self.taken.load(Ordering::SeqCst)
+    }
+}
+
+/// The value of a global. Globals holding references can't be saved.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    /// The bits of the float, so that NaNs are kept as they are
+    F32(u32),
+    F64(u64),
+    V128(u128),
+}
+
+impl GlobalValue {
+    fn from_val(val: wasmtime::Val) -> Option<Self> {
+        match val {
+            wasmtime::Val::I32(v) => Some(GlobalValue::I32(v)),
+            wasmtime::Val::I64(v) => Some(GlobalValue::I64(v)),
+            wasmtime::Val::F32(v) => Some(GlobalValue::F32(v)),
+            wasmtime::Val::F64(v) => Some(GlobalValue::F64(v)),
+            wasmtime::Val::V128(v) => Some(GlobalValue::V128(v)),
+            _ => None,
+        }
+    }
+
+    fn into_val(self) -> wasmtime::Val {
+        match self {
+            GlobalValue::I32(v) => wasmtime::Val::I32(v),
+            GlobalValue::I64(v) => wasmtime::Val::I64(v),
+            GlobalValue::F32(v) => wasmtime::Val::F32(v),
+            GlobalValue::F64(v) => wasmtime::Val::F64(v),
+            GlobalValue::V128(v) => wasmtime::Val::V128(v),
+        }
+    }
+}
+
+/// What a checkpoint file holds before the contents of the memories
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotHeader {
+    /// The names and sizes in bytes of the memories, in the order their
+    /// contents follow
+    memories: Vec<(String, u64)>,
+    globals: Vec<(String, GlobalValue)>,
+}
+
+/// The exported memories and mutable globals of an instance
+#[derive(Debug, PartialEq)]
+pub(crate) struct Snapshot {
+    memories: Vec<(String, Vec<u8>)>,
+    globals: Vec<(String, GlobalValue)>,
+}
+
+impl Snapshot {
+    /// Saves the exported memories and mutable globals of the instance
+    pub(crate) fn capture(instance: &wasmtime::Instance) -> anyhow::Result<Self> {
+        let mut memories = vec![];
+        let mut globals = vec![];
+        for export in instance.exports() {
+            let name = export.name().to_owned();
+            match export.into_extern() {
+                wasmtime::Extern::Memory(memory) => {
+                    let mut data = vec![0; memory.data_size()];
+                    memory.read(0, &mut data)?;
+                    memories.push((name, data));
+                }
+                wasmtime::Extern::Global(global)
+                    if global.mutability() == wasmtime::Mutability::Var =>
+                {
+                    let value = GlobalValue::from_val(global.get()).ok_or_else(|| {
+                        anyhow::anyhow!("global {} holds a reference, which can't be saved", name)
+                    })?;
+                    globals.push((name, value));
+                }
+                _ => (),
+            }
+        }
+        Ok(Snapshot { memories, globals })
+    }
+
+    /// Restores the memories and globals of a new instance of the module the
+    /// snapshot was captured from
+    pub(crate) fn restore(&self, instance: &wasmtime::Instance) -> anyhow::Result<()> {
+        for (name, data) in &self.memories {
+            let memory = instance
+                .get_memory(name)
+                .ok_or_else(|| anyhow::anyhow!("module doesn't export memory {}", name))?;
+            let pages = (data.len() / WASM_PAGE_SIZE) as u32;
+            if pages > memory.size() {
+                memory.grow(pages - memory.size())?;
+            }
+            memory.write(0, data)?;
+        }
+        for (name, value) in &self.globals {
+            let global = instance
+                .get_global(name)
+                .ok_or_else(|| anyhow::anyhow!("module doesn't export global {}", name))?;
+            global.set(value.into_val())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the snapshot to the file, replacing any earlier one only once
+    /// it has been written in full
+    pub(crate) fn write_to(&self, path: &Path) -> anyhow::Result<()> {
+        let header = SnapshotHeader {
+            memories: self
+                .memories
+                .iter()
+                .map(|(name, data)| (name.clone(), data.len() as u64))
+                .collect(),
+            globals: self.globals.clone(),
+        };
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&partial)?);
+        writeln!(file, "{}", SNAPSHOT_MAGIC)?;
+        serde_json::to_writer(&mut file, &header)?;
+        file.write_all(b"\n")?;
+        for (_, data) in &self.memories {
+            file.write_all(data)?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Reads the snapshot in the file
+    pub(crate) fn read_from(path: &Path) -> anyhow::Result<Self> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end() != SNAPSHOT_MAGIC {
+            return Err(anyhow::anyhow!(
+                "{} is not a checkpoint written by this version of krustlet",
+                path.display()
+            ));
+        }
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header: SnapshotHeader = serde_json::from_str(&line)?;
+        let mut memories = vec![];
+        for (name, len) in header.memories {
+            let mut data = vec![0; len as usize];
+            reader.read_exact(&mut data)?;
+            memories.push((name, data));
+        }
+        Ok(Snapshot {
+            memories,
+            globals: header.globals,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::pod;
+
+    fn pod_with_checkpoint_path(path: &str) -> Pod {
+        pod(serde_json::json!({ CHECKPOINT_ANNOTATION: path }))
+    }
+
+    #[test]
+    fn checkpoint_path_is_mapped_into_the_innermost_volume() {
+        let mut dirs = HashMap::new();
+        dirs.insert(PathBuf::from("/volumes/data"), Some(PathBuf::from("/data")));
+        dirs.insert(
+            PathBuf::from("/volumes/state"),
+            Some(PathBuf::from("/data/state")),
+        );
+        dirs.insert(
+            PathBuf::from("/volumes/config"),
+            Some(PathBuf::from("/etc")),
+        );
+        let mut read_only = HashSet::new();
+        read_only.insert(PathBuf::from("/volumes/config"));
+
+        let pod = pod_with_checkpoint_path("/data/state/checkpoint");
+        assert_eq!(
+            checkpoint_path(&pod, &dirs, &read_only).unwrap(),
+            Some(PathBuf::from("/volumes/state/checkpoint"))
+        );
+        let pod = pod_with_checkpoint_path("/data/checkpoint");
+        assert_eq!(
+            checkpoint_path(&pod, &dirs, &read_only).unwrap(),
+            Some(PathBuf::from("/volumes/data/checkpoint"))
+        );
+
+        assert!(checkpoint_path(
+            &pod_with_checkpoint_path("/etc/checkpoint"),
+            &dirs,
+            &read_only
+        )
+        .is_err());
+        assert!(checkpoint_path(
+            &pod_with_checkpoint_path("/tmp/checkpoint"),
+            &dirs,
+            &read_only
+        )
+        .is_err());
+        assert!(checkpoint_path(&pod_with_checkpoint_path("/data"), &dirs, &read_only).is_err());
+
+        let pod = Pod::default();
+        assert_eq!(checkpoint_path(&pod, &dirs, &read_only).unwrap(), None);
+    }
+
+    #[test]
+    fn snapshots_are_restored_into_new_instances() {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(
+            &engine,
+            r#"(module
+                (memory (export "memory") 1)
+                (global (export "counter") (mut i64) (i64.const 0))
+                (global (export "limit") i32 (i32.const 10))
+                (func (export "run")
+                    (global.set 0 (i64.const 42))
+                    (drop (memory.grow (i32.const 1)))
+                    (i32.store8 (i32.const 70000) (i32.const 7))))"#,
+        )
+        .unwrap();
+        let store = wasmtime::Store::new(&engine);
+        let instance = wasmtime::Instance::new(&store, &module, &[]).unwrap();
+        instance.get_func("run").unwrap().call(&[]).unwrap();
+        let snapshot = Snapshot::capture(&instance).unwrap();
+        assert_eq!(
+            snapshot.globals,
+            vec![("counter".to_owned(), GlobalValue::I64(42))]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint");
+        snapshot.write_to(&path).unwrap();
+        let read = Snapshot::read_from(&path).unwrap();
+        assert_eq!(read, snapshot);
+
+        let store = wasmtime::Store::new(&engine);
+        let restored = wasmtime::Instance::new(&store, &module, &[]).unwrap();
+        read.restore(&restored).unwrap();
+        let memory = restored.get_memory("memory").unwrap();
+        assert_eq!(memory.size(), 2);
+        let mut byte = [0];
+        memory.read(70000, &mut byte).unwrap();
+        assert_eq!(byte, [7]);
+        assert_eq!(
+            restored.get_global("counter").unwrap().get().i64(),
+            Some(42)
+        );
+    }
+}
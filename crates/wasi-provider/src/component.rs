@@ -0,0 +1,128 @@
+//! Checking the interfaces that components import against those the provider
+//! implements.
+//!
+//! Components, and core modules built with `wit-bindgen` to be wrapped into
+//! one, import the interfaces of their WIT world by name, such as
+//! `wasi:cli/environment@0.2.0`. A component importing an interface that the
+//! provider doesn't implement would fail to link when its container starts,
+//! so pods are checked as they initialize and fail with the interfaces that
+//! are missing instead.
+
+use std::collections::BTreeSet;
+
+use crate::wasm_binary;
+
+/// The interfaces the provider implements for components, by name without
+/// their version. wasmtime 0.24 has no component model support, and only
+/// implements WASI preview 1 for core modules, so there are none yet.
+const PROVIDED_INTERFACES: &[&str] = &[];
+
+/// Interfaces that a container's component imports but the provider doesn't
+/// implement
+#[derive(Debug, PartialEq)]
+pub(crate) struct MissingInterfaces {
+    pub container_name: String,
+    pub interfaces: Vec<String>,
+}
+
+impl std::fmt::Display for MissingInterfaces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "component of container {} imports interfaces this provider doesn't implement: {}",
+            self.container_name,
+            self.interfaces.join(", ")
+        )
+    }
+}
+
+/// Checks the interfaces imported by components against those the provider
+/// implements
+pub(crate) struct ComponentInterfaceChecker {
+    provided: BTreeSet<String>,
+}
+
+impl Default for ComponentInterfaceChecker {
+    fn default() -> Self {
+        ComponentInterfaceChecker::new(PROVIDED_INTERFACES.iter().map(|i| i.to_string()))
+    }
+}
+
+impl ComponentInterfaceChecker {
+    fn new(provided: impl IntoIterator<Item = String>) -> Self {
+        ComponentInterfaceChecker {
+            provided: provided.into_iter().collect(),
+        }
+    }
+
+    /// Returns an error listing the interfaces the container's module imports
+    /// that the provider doesn't implement, if there are any. Modules that
+    /// aren't components import no interfaces.
+    pub(crate) fn check(
+        &self,
+        container_name: &str,
+        module_data: &[u8],
+    ) -> Result<(), MissingInterfaces> {
+        let interfaces: Vec<String> = wasm_binary::imported_interfaces(module_data)
+            .into_iter()
+            .filter(|interface| !self.provided.contains(without_version(interface)))
+            .collect();
+        if interfaces.is_empty() {
+            Ok(())
+        } else {
+            Err(MissingInterfaces {
+                container_name: container_name.to_owned(),
+                interfaces,
+            })
+        }
+    }
+}
+
+/// Strips the version, such as `@0.2.0`, from the name of an interface
+fn without_version(interface: &str) -> &str {
+    interface.split('@').next().unwrap_or(interface)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn module(imports: &[&str]) -> Vec<u8> {
+        let imports: String = imports
+            .iter()
+            .map(|module| format!(r#"(import "{}" "f" (func))"#, module))
+            .collect();
+        wat::parse_str(format!("(module {})", imports)).unwrap()
+    }
+
+    #[test]
+    fn interfaces_the_provider_does_not_implement_are_reported() {
+        let checker = ComponentInterfaceChecker::new(vec!["wasi:cli/environment".to_owned()]);
+        let data = module(&[
+            "wasi:cli/environment@0.2.0",
+            "wasi:io/streams@0.2.0",
+            "wasi:clocks/wall-clock@0.2.0",
+        ]);
+        let missing = checker.check("app", &data).unwrap_err();
+        assert_eq!(
+            missing.interfaces,
+            vec!["wasi:clocks/wall-clock@0.2.0", "wasi:io/streams@0.2.0"]
+        );
+        assert_eq!(
+            missing.to_string(),
+            "component of container app imports interfaces this provider doesn't implement: wasi:clocks/wall-clock@0.2.0, wasi:io/streams@0.2.0"
+        );
+
+        assert!(checker
+            .check("app", &module(&["wasi:cli/environment@0.2.0"]))
+            .is_ok());
+    }
+
+    #[test]
+    fn core_modules_import_no_interfaces() {
+        let checker = ComponentInterfaceChecker::default();
+        assert!(checker
+            .check("app", &module(&["wasi_snapshot_preview1"]))
+            .is_ok());
+    }
+}
@@ -0,0 +1,344 @@
+//! Enforcement of container CPU limits
+//!
+//! Wasmtime 0.24 predates epoch interruption, and interrupting a store traps
+//! the module rather than pausing it, so limits are enforced on the threads
+//! modules run on instead. A scheduler thread wakes every tick, reads how much
+//! CPU time each module's thread has used from the thread's CPU clock, and
+//! pauses threads that have used more than their share of the ticks so far (a
+//! limit of `500m` is a share of half of each tick) by sending them a signal
+//! whose handler sleeps for one tick. Modules that are blocked, such as in
+//! `poll_oneoff`, use no CPU time, so only busy modules are throttled, and they
+//! carry on where they left off once their usage is back within the limit.
+//!
+//! The CPU time used by each running container is also recorded, whether or
+//! not it has a limit. Limits are only enforced, and usage only recorded, on
+//! Linux.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+/// How long the signal handler pauses a thread for, in nanoseconds. This is
+/// the tick of the scheduler.
+#[cfg(target_os = "linux")]
+static PAUSE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Distinguishes containers with the same name, so that the guard of a
+/// container that has exited doesn't stop tracking a restarted one
+static NEXT_TRACKING_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Tracks the CPU time used by the threads running modules, and throttles the
+/// ones with CPU limits
+pub struct CpuScheduler {
+    tick: Duration,
+    containers: Mutex<HashMap<String, TrackedThread>>,
+}
+
+struct TrackedThread {
+    id: u64,
+    /// The limit in millicores, if any
+    limit_millis: Option<u64>,
+    /// The CPU time the thread had used when tracking started, as the thread
+    /// may have run other tasks before the module
+    started: Duration,
+    /// The CPU time the thread had used at the last tick
+    last: Duration,
+    /// How much CPU time the thread has used beyond its share
+    debt: Duration,
+    #[cfg(target_os = "linux")]
+    thread: libc::pthread_t,
+    #[cfg(target_os = "linux")]
+    clock: libc::clockid_t,
+}
+
+impl CpuScheduler {
+    /// Creates a scheduler checking CPU usage every `tick`. The scheduler
+    /// thread stops once the returned scheduler is dropped.
+    pub fn new(tick: Duration) -> Arc<Self> {
+        let scheduler = Arc::new(CpuScheduler {
+            tick,
+            containers: Mutex::new(HashMap::new()),
+        });
+        #[cfg(target_os = "linux")]
+        {
+            install_pause_handler(tick);
+            let weak = Arc::downgrade(&scheduler);
+            std::thread::Builder::new()
+                .name("cpu-scheduler".to_owned())
+                .spawn(move || run_scheduler(weak, tick))
+                .expect("unable to start CPU scheduler thread");
+        }
+        scheduler
+    }
+
+    /// How often the scheduler checks CPU usage
+    pub(crate) fn tick_interval(&self) -> Duration {
+        self.tick
+    }
+
+    /// Starts tracking the CPU time used by the current thread for the named
+    /// container, throttling it to `limit_millis` millicores if set. Tracking
+    /// stops when the returned guard is dropped, which must happen on the same
+    /// thread.
+    pub fn track(self: &Arc<Self>, name: &str, limit_millis: Option<u64>) -> CpuGuard {
+        let id = NEXT_TRACKING_ID.fetch_add(1, Ordering::SeqCst);
+        match current_thread(id, limit_millis) {
+            Ok(tracked) => {
+                debug!(
+                    "{} tracking CPU usage with limit {:?} millicores",
+                    name, limit_millis
+                );
+                self.containers
+                    .lock()
+                    .expect("CPU scheduler lock should not be poisoned")
+                    .insert(name.to_owned(), tracked);
+            }
+            Err(e) => warn!("{} running without CPU tracking or limits: {:?}", name, e),
+        }
+        CpuGuard {
+            scheduler: self.clone(),
+            name: name.to_owned(),
+            id,
+        }
+    }
+
+    /// Changes the limit of the named container while it runs, removing it if
+    /// `limit_millis` is `None`. The container's share is counted afresh
+    /// from now on. Containers that aren't tracked are left alone.
+    pub fn set_limit(&self, name: &str, limit_millis: Option<u64>) {
+        let mut containers = self
+            .containers
+            .lock()
+            .expect("CPU scheduler lock should not be poisoned");
+        if let Some(tracked) = containers.get_mut(name) {
+            debug!(
+                "{} changing CPU limit from {:?} to {:?} millicores",
+                name, tracked.limit_millis, limit_millis
+            );
+            tracked.limit_millis = limit_millis;
+            tracked.debt = Duration::from_secs(0);
+            if let Ok(now) = thread_cpu_time(tracked) {
+                tracked.last = now;
+            }
+        }
+    }
+
+    /// Returns the CPU time the named container has used, if it is running
+    pub fn usage(&self, name: &str) -> Option<Duration> {
+        let containers = self
+            .containers
+            .lock()
+            .expect("CPU scheduler lock should not be poisoned");
+        let tracked = containers.get(name)?;
+        // The thread is still running the module, as it stops being tracked
+        // before it finishes, so its clock is valid
+        thread_cpu_time(tracked)
+            .ok()
+            .map(|now| now.saturating_sub(tracked.started))
+    }
+
+    /// Checks each thread with a limit against its share of the last tick,
+    /// pausing the ones that are over
+    #[cfg(target_os = "linux")]
+    fn tick(&self) {
+        let mut containers = self
+            .containers
+            .lock()
+            .expect("CPU scheduler lock should not be poisoned");
+        for tracked in containers.values_mut() {
+            let limit_millis = match tracked.limit_millis {
+                Some(limit) => limit,
+                None => continue,
+            };
+            let now = match thread_cpu_time(tracked) {
+                Ok(now) => now,
+                Err(_) => continue,
+            };
+            let used = now.saturating_sub(tracked.last);
+            tracked.last = now;
+            let share = self.tick.mul_f64(limit_millis as f64 / 1000.0);
+            tracked.debt = (tracked.debt + used).saturating_sub(share);
+            if tracked.debt > Duration::from_secs(0) {
+                unsafe { libc::pthread_kill(tracked.thread, pause_signal()) };
+            }
+        }
+    }
+}
+
+/// Stops tracking a container's thread when dropped
+pub struct CpuGuard {
+    scheduler: Arc<CpuScheduler>,
+    name: String,
+    id: u64,
+}
+
+impl Drop for CpuGuard {
+    fn drop(&mut self) {
+        let mut containers = self
+            .scheduler
+            .containers
+            .lock()
+            .expect("CPU scheduler lock should not be poisoned");
+        if containers.get(&self.name).map(|t| t.id) == Some(self.id) {
+            containers.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run_scheduler(scheduler: Weak<CpuScheduler>, tick: Duration) {
+    loop {
+        std::thread::sleep(tick);
+        match scheduler.upgrade() {
+            Some(scheduler) => scheduler.tick(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn current_thread(id: u64, limit_millis: Option<u64>) -> anyhow::Result<TrackedThread> {
+    let thread = unsafe { libc::pthread_self() };
+    let mut clock: libc::clockid_t = 0;
+    let result = unsafe { libc::pthread_getcpuclockid(thread, &mut clock) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result).into());
+    }
+    let mut tracked = TrackedThread {
+        id,
+        limit_millis,
+        started: Duration::from_secs(0),
+        last: Duration::from_secs(0),
+        debt: Duration::from_secs(0),
+        thread,
+        clock,
+    };
+    tracked.started = thread_cpu_time(&tracked)?;
+    tracked.last = tracked.started;
+    Ok(tracked)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_thread(_id: u64, _limit_millis: Option<u64>) -> anyhow::Result<TrackedThread> {
+    Err(anyhow::anyhow!("CPU limits are only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn thread_cpu_time(tracked: &TrackedThread) -> anyhow::Result<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(tracked.clock, &mut time) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_cpu_time(_tracked: &TrackedThread) -> anyhow::Result<Duration> {
+    Err(anyhow::anyhow!("CPU usage is only recorded on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn pause_signal() -> libc::c_int {
+    libc::SIGRTMIN()
+}
+
+#[cfg(target_os = "linux")]
+fn install_pause_handler(tick: Duration) {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    PAUSE_NANOS.store(tick.as_nanos() as u64, Ordering::SeqCst);
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = pause_thread as extern "C" fn(libc::c_int) as usize;
+        // Restart system calls interrupted by a pause, so that modules doing
+        // I/O are slowed down rather than seeing errors
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(pause_signal(), &action, std::ptr::null_mut()) != 0 {
+            warn!(
+                "Unable to install CPU limit signal handler, CPU limits will not be enforced: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    });
+}
+
+/// Pauses the thread the signal was delivered to for one tick. This only
+/// calls functions that are safe to call from a signal handler.
+#[cfg(target_os = "linux")]
+extern "C" fn pause_thread(_signal: libc::c_int) {
+    let nanos = PAUSE_NANOS.load(Ordering::Relaxed);
+    let pause = libc::timespec {
+        tv_sec: (nanos / 1_000_000_000) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as libc::c_long,
+    };
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::nanosleep(&pause, std::ptr::null_mut());
+        *libc::__errno_location() = errno;
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    /// Spins on a thread tracked with the given limit, returning the CPU time
+    /// it used
+    fn spin(scheduler: &Arc<CpuScheduler>, name: &str, limit_millis: Option<u64>) -> Duration {
+        let scheduler = scheduler.clone();
+        let name = name.to_owned();
+        std::thread::spawn(move || {
+            let _guard = scheduler.track(&name, limit_millis);
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_millis(500) {
+                std::hint::spin_loop();
+            }
+            scheduler.usage(&name).expect("thread should be tracked")
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn limited_threads_are_throttled() {
+        let scheduler = CpuScheduler::new(Duration::from_millis(10));
+        let used = spin(&scheduler, "limited", Some(250));
+        // The thread can use at most a quarter of the time, but a loaded
+        // machine may give it less
+        assert!(used > Duration::from_secs(0));
+        assert!(
+            used < Duration::from_millis(250),
+            "throttled thread used {:?}",
+            used
+        );
+        assert!(scheduler.usage("limited").is_none());
+    }
+
+    #[test]
+    fn limits_can_change_while_tracked() {
+        let scheduler = CpuScheduler::new(Duration::from_millis(10));
+        let limit = |name: &str| {
+            scheduler
+                .containers
+                .lock()
+                .unwrap()
+                .get(name)
+                .map(|tracked| tracked.limit_millis)
+        };
+        let guard = scheduler.track("resized", None);
+        scheduler.set_limit("resized", Some(500));
+        assert_eq!(limit("resized"), Some(Some(500)));
+        scheduler.set_limit("resized", None);
+        assert_eq!(limit("resized"), Some(None));
+        drop(guard);
+        scheduler.set_limit("resized", Some(500));
+        assert_eq!(limit("resized"), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Lifecycle hooks of containers
+//!
+//! WASI modules can't run other programs, so the command of an `exec` hook
+//! names a function that the container's module exports, which is run in a
+//! new instance of the module. `httpGet` hooks are sent as by any kubelet.
+//!
+//! A container's `postStart` hook runs once it has started, before it is
+//! considered running. When a pod is deleted, the `preStop` hooks of its
+//! running containers run first, sharing the pod's grace period, and the
+//! containers are then stopped and given what is left of it to exit.
+use std::sync::Arc;
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use kubelet::container::hook::{http_get, Hook};
+use kubelet::container::Container;
+use kubelet::pod::{Pod, PodKey};
+
+use crate::cpu_limit::CpuScheduler;
+use crate::wasi_runtime::WasiRuntime;
+use crate::ProviderState;
+
+/// What the hooks of a container are run with, taken from the provider's
+/// state so that it isn't locked while they run
+pub(crate) struct HookRunner {
+    /// The runtime and module of the container, if it is running
+    target: Option<(Arc<WasiRuntime>, wasmtime::Module)>,
+    cpu_scheduler: Arc<CpuScheduler>,
+}
+
+impl HookRunner {
+    pub(crate) async fn new(provider_state: &ProviderState, pod: &Pod, container: &str) -> Self {
+        let target = provider_state
+            .exec_targets
+            .read()
+            .await
+            .get(&PodKey::from(pod))
+            .and_then(|containers| containers.get(container))
+            .map(|target| (target.runtime.clone(), target.module.clone()));
+        HookRunner {
+            target,
+            cpu_scheduler: provider_state.cpu_scheduler.clone(),
+        }
+    }
+
+    /// Whether the container is running, which `exec` hooks need
+    pub(crate) fn is_running(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Runs the hook, failing if it can't be run or fails
+    pub(crate) async fn run(
+        &self,
+        pod: &Pod,
+        container: &Container,
+        hook: Hook,
+    ) -> anyhow::Result<()> {
+        match hook {
+            Hook::Exec(command) => {
+                let (runtime, module) = self
+                    .target
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("container is not running"))?;
+                let exit_code = runtime
+                    .run_hook(module, self.cpu_scheduler.clone(), command)
+                    .await?;
+                if !exit_code.success() {
+                    anyhow::bail!("hook exited with {}", exit_code.0);
+                }
+                Ok(())
+            }
+            Hook::HttpGet(action) => http_get(pod, container, &action).await,
+        }
+    }
+}
+
+/// Runs the `preStop` hooks of the pod's running containers at the same
+/// time, giving up on them at `deadline`. Hooks that fail are only logged,
+/// as the containers are stopped either way.
+pub(crate) async fn run_pre_stop_hooks(
+    provider_state: &ProviderState,
+    pod: &Pod,
+    deadline: Instant,
+) {
+    let mut hooks = vec![];
+    for container in pod.containers() {
+        let handler = container
+            .lifecycle()
+            .and_then(|lifecycle| lifecycle.pre_stop.as_ref());
+        if let Some(handler) = handler {
+            let runner = HookRunner::new(provider_state, pod, container.name()).await;
+            if runner.is_running() {
+                hooks.push((container.clone(), Hook::from_handler(handler), runner));
+            }
+        }
+    }
+    let runs = hooks
+        .into_iter()
+        .map(|(container, hook, runner)| async move {
+            info!(
+                "Running preStop hook of container {} for pod {}",
+                container.name(),
+                pod.name()
+            );
+            let result = match hook {
+                Ok(hook) => tokio::time::timeout_at(deadline, runner.run(pod, &container, hook))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!("hook did not complete in the grace period"))
+                    }),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "PreStop hook failed for container {} of pod {}: {:?}",
+                    container.name(),
+                    pod.name(),
+                    e
+                );
+            }
+        });
+    futures::future::join_all(runs).await;
+}
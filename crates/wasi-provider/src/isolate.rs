@@ -0,0 +1,535 @@
+//! Running modules in worker processes
+//!
+//! Modules normally run on threads of the provider process, so a bug in
+//! wasmtime or in the host functions a module calls could affect the
+//! provider and every other module. Pods annotated with
+//! `krustlet.dev/isolate: process` run each instance of their modules in a
+//! `krustlet-wasm-worker` process of its own instead, which the provider
+//! starts next to its own executable (or from `PATH`).
+//!
+//! The provider passes the worker one end of a Unix socket pair as descriptor
+//! 3. It sends the worker what it needs to run the instance as a JSON line,
+//! followed by the compiled module, and the worker reports the statuses of the
+//! instance back as JSON lines. The worker's standard streams are the module's,
+//! which the provider copies to and from the streams of the instance. Before
+//! it loads the module, the worker switches to the user and group of the
+//! container for good and drops all its capabilities (see
+//! [`RunAs::drop_privileges`]). Closing the socket stops the worker, which also
+//! happens if the provider exits.
+//!
+//! Workers enforce the memory and CPU limits the instance had when it started
+//! themselves, so limits resized while the module runs, CPU pinning, NUMA
+//! bindings and huge pages don't apply to isolated modules, and the CPU time
+//! they use isn't recorded.
+//!
+//! [`RunAs::drop_privileges`]: crate::run_as::RunAs::drop_privileges
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kubelet::container::Status;
+use kubelet::pod::Pod;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+use crate::stdio::Stdio;
+use crate::wasi_runtime::ModuleConfig;
+
+/// Pod annotation used to run the pod's modules in worker processes
+pub(crate) const ISOLATE_ANNOTATION: &str = "krustlet.dev/isolate";
+
+/// The executable that runs isolated modules
+const WORKER_BINARY: &str = "krustlet-wasm-worker";
+
+/// The descriptor the worker's end of the socket is passed as
+#[cfg(unix)]
+const CONTROL_FD: std::os::unix::io::RawFd = 3;
+
+/// Where the modules of a pod run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// On threads of the provider process
+    Thread,
+    /// In a worker process of their own
+    Process,
+}
+
+impl std::str::FromStr for Isolation {
+    type Err = anyhow::Error;
+
+    /// Parses the value of the pod annotation, which only asks for process
+    /// isolation, as threads are the default
+    fn from_str(isolation: &str) -> Result<Self, Self::Err> {
+        match isolation.to_lowercase().as_str() {
+            "process" => Ok(Isolation::Process),
+            other => Err(anyhow::anyhow!(
+                "unknown isolation {}. Supported isolation: process",
+                other
+            )),
+        }
+    }
+}
+
+/// Returns where the modules of the pod should run.
+///
+/// This returns an error if the pod's annotation asks for an unknown kind of
+/// isolation.
+pub(crate) fn pod_isolation(pod: &Pod) -> anyhow::Result<Isolation> {
+    match pod.get_annotation(ISOLATE_ANNOTATION) {
+        Some(isolation) => isolation.parse(),
+        None => Ok(Isolation::Thread),
+    }
+}
+
+/// What a worker needs to run an instance of a module, apart from the
+/// compiled module itself
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WorkerSpec {
+    pub(crate) name: String,
+    pub(crate) entry: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) config: ModuleConfig,
+    /// The memory limit in bytes, which is `u64::MAX` for instances without one
+    pub(crate) memory_limit: u64,
+    /// The CPU limit in millicores, if any
+    pub(crate) cpu_limit: Option<u64>,
+    /// How often the worker checks the CPU usage of the module
+    pub(crate) cpu_tick: Duration,
+    /// Whether the instance has each of the standard streams
+    pub(crate) stdin: bool,
+    pub(crate) stdout: bool,
+    pub(crate) stderr: bool,
+    /// Whether the module was compiled with SIMD enabled
+    pub(crate) simd: bool,
+}
+
+/// What a worker reports to the provider
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum WorkerEvent {
+    /// The module has started running
+    Running,
+    /// The module has terminated
+    Terminated {
+        message: String,
+        failed: bool,
+        reason: Option<String>,
+        exit_code: Option<i32>,
+    },
+    /// Running the module failed with an error, after any status for it was
+    /// reported
+    Failed {
+        error: String,
+        /// The code the module exited with, if it failed by exiting
+        exit_code: Option<i32>,
+    },
+}
+
+impl WorkerEvent {
+    /// The event reporting a status of the module, if it is one that is
+    /// reported
+    fn from_status(status: Status) -> Option<Self> {
+        match status {
+            Status::Waiting { .. } => None,
+            Status::Running { .. } => Some(WorkerEvent::Running),
+            Status::Terminated {
+                message,
+                failed,
+                reason,
+                exit_code,
+                ..
+            } => Some(WorkerEvent::Terminated {
+                message,
+                failed,
+                reason,
+                exit_code,
+            }),
+        }
+    }
+
+    /// The status the event reports, as of now, if it reports one
+    fn into_status(self) -> Option<Status> {
+        match self {
+            WorkerEvent::Running => Some(Status::running()),
+            WorkerEvent::Terminated {
+                message,
+                failed,
+                reason,
+                exit_code,
+            } => Some(Status::Terminated {
+                timestamp: chrono::Utc::now(),
+                message,
+                failed,
+                reason,
+                started_at: None,
+                exit_code,
+            }),
+            WorkerEvent::Failed { .. } => None,
+        }
+    }
+}
+
+/// Turns the error a worker reported back into one, which keeps the trap of
+/// a module that exited so that its exit code can be told apart from other
+/// errors
+fn worker_error(error: String, exit_code: Option<i32>) -> anyhow::Error {
+    match exit_code {
+        Some(code) => anyhow::Error::from(wasmtime::Trap::i32_exit(code)).context(error),
+        None => anyhow::anyhow!(error),
+    }
+}
+
+/// Stops the worker process a module runs in
+pub(crate) struct WorkerStopper {
+    #[cfg(unix)]
+    control: std::os::unix::net::UnixStream,
+    stopped: Arc<AtomicBool>,
+}
+
+impl WorkerStopper {
+    /// Stops the worker by closing its socket, which it exits on
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        #[cfg(unix)]
+        let _ = self.control.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Starts a worker process running an instance of `module` as `spec`
+/// describes, with the given standard streams. The returned handle finishes
+/// once the worker has exited and its output has been copied, after its
+/// statuses have been sent to `status_sender`.
+#[cfg(unix)]
+pub(crate) async fn spawn_worker(
+    spec: WorkerSpec,
+    module: &wasmtime::Module,
+    stdio: Stdio,
+    status_sender: Option<Sender<Status>>,
+) -> anyhow::Result<(WorkerStopper, JoinHandle<anyhow::Result<()>>)> {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio as ProcessStdio};
+
+    let piped = |wanted| match wanted {
+        true => ProcessStdio::piped(),
+        false => ProcessStdio::null(),
+    };
+    let mut message = serde_json::to_vec(&spec)?;
+    message.push(b'\n');
+    let artifact = module.serialize()?;
+    message.extend_from_slice(&(artifact.len() as u64).to_be_bytes());
+    message.extend_from_slice(&artifact);
+
+    // Both ends are closed on exec, so that other workers don't inherit
+    // them, and the worker's end is duplicated onto its descriptor without
+    // that flag
+    let (mut control, worker_end) = UnixStream::pair()?;
+    let worker_fd = worker_end.as_raw_fd();
+    let path = worker_path();
+    let mut command = Command::new(&path);
+    command
+        .env_clear()
+        .stdin(piped(spec.stdin))
+        .stdout(piped(spec.stdout))
+        .stderr(piped(spec.stderr));
+    unsafe {
+        command.pre_exec(move || {
+            let result = if worker_fd == CONTROL_FD {
+                libc::fcntl(worker_fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(worker_fd, CONTROL_FD)
+            };
+            match result {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    let mut process = command
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("unable to start worker process {}: {}", path.display(), e))?;
+    drop(worker_end);
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopper = WorkerStopper {
+        control: control.try_clone()?,
+        stopped: stopped.clone(),
+    };
+    let name = spec.name;
+    let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let Stdio {
+            stdin,
+            stdout,
+            stderr,
+        } = stdio;
+        // Input is copied until either end closes it, which may not happen
+        // before the worker exits, so the copy isn't waited for
+        if let (Some(stdin), Some(mut pipe)) = (stdin, process.stdin.take()) {
+            let mut input = stdin
+                .try_into_inner()
+                .map_err(|_| anyhow::anyhow!("stdin of {} is shared", name))?;
+            std::thread::spawn(move || std::io::copy(&mut input, &mut pipe));
+        }
+        let mut outputs = vec![];
+        for (output, pipe) in vec![(stdout, process.stdout.take().map(box_read))]
+            .into_iter()
+            .chain(vec![(stderr, process.stderr.take().map(box_read))])
+        {
+            if let (Some(output), Some(mut pipe)) = (output, pipe) {
+                let mut output = output
+                    .try_into_inner()
+                    .map_err(|_| anyhow::anyhow!("output of {} is shared", name))?;
+                outputs.push(std::thread::spawn(move || {
+                    std::io::copy(&mut pipe, &mut output)
+                }));
+            }
+        }
+
+        if let Err(e) = control.write_all(&message) {
+            // The worker may have exited already, which is reported below
+            tracing::debug!("{} unable to send module to worker: {:?}", name, e);
+        }
+        drop(message);
+        let mut terminated = None;
+        let mut failure = None;
+        for line in std::io::BufRead::lines(std::io::BufReader::new(&control)) {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let event = match serde_json::from_str::<WorkerEvent>(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("{} unknown event from worker: {:?}", name, e);
+                    continue;
+                }
+            };
+            match event {
+                WorkerEvent::Running => {
+                    crate::wasi_runtime::send(status_sender.as_ref(), &name, Status::running())
+                }
+                WorkerEvent::Failed { error, exit_code } => {
+                    failure = Some(worker_error(error, exit_code))
+                }
+                terminal => terminated = terminal.into_status(),
+            }
+        }
+        let exit = process.wait()?;
+        // The container's output is all written before it is reported terminated
+        for output in outputs {
+            let _ = output.join();
+        }
+
+        let terminated = terminated.unwrap_or_else(|| {
+            let message = match stopped.load(Ordering::SeqCst) {
+                true => "module was stopped".to_owned(),
+                false => format!("worker process exited with {}", exit),
+            };
+            tracing::error!("{} unable to run module: {}", name, message);
+            failure.get_or_insert_with(|| anyhow::anyhow!("unable to run module: {}", message));
+            Status::terminated(&format!("unable to run module: {}", message), true)
+        });
+        crate::wasi_runtime::send(status_sender.as_ref(), &name, terminated);
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    });
+    Ok((stopper, handle))
+}
+
+#[cfg(unix)]
+fn box_read<R: std::io::Read + Send + 'static>(read: R) -> Box<dyn std::io::Read + Send> {
+    Box::new(read)
+}
+
+/// Worker processes are started with Unix sockets, which this platform
+/// doesn't have
+#[cfg(not(unix))]
+pub(crate) async fn spawn_worker(
+    _spec: WorkerSpec,
+    _module: &wasmtime::Module,
+    _stdio: Stdio,
+    _status_sender: Option<Sender<Status>>,
+) -> anyhow::Result<(WorkerStopper, JoinHandle<anyhow::Result<()>>)> {
+    Err(anyhow::anyhow!(
+        "process isolation is only supported on Unix"
+    ))
+}
+
+/// Where the worker executable is, which is next to the provider's own
+/// executable if it is there
+fn worker_path() -> std::path::PathBuf {
+    let beside_provider = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(WORKER_BINARY)))
+        .filter(|path| path.exists());
+    // Workers are started without the provider's environment, so the binary
+    // is looked up on the provider's path
+    let on_path = || {
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(WORKER_BINARY))
+                .find(|path| path.exists())
+        })
+    };
+    beside_provider
+        .or_else(on_path)
+        .unwrap_or_else(|| WORKER_BINARY.into())
+}
+
+/// Runs the module the provider sends over the socket on descriptor 3,
+/// returning the code the worker process should exit with. This is the whole
+/// of the `krustlet-wasm-worker` executable. Nothing is logged, as the
+/// worker's standard streams are the module's.
+#[cfg(unix)]
+pub fn run_worker() -> i32 {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let control = unsafe { UnixStream::from_raw_fd(CONTROL_FD) };
+    let mut events = match control.try_clone() {
+        Ok(events) => events,
+        Err(_) => return 1,
+    };
+    match run_worker_module(control, &mut events) {
+        Ok(()) => 0,
+        Err(e) => {
+            let event = WorkerEvent::Failed {
+                error: format!("{:#}", e),
+                exit_code: crate::wasi_runtime::exit_status(&e),
+            };
+            let _ = serde_json::to_writer(&mut events, &event)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| Ok(events.write_all(b"\n")?));
+            1
+        }
+    }
+}
+
+/// Worker processes are started by the provider, which doesn't start them on
+/// this platform
+#[cfg(not(unix))]
+pub fn run_worker() -> i32 {
+    1
+}
+
+#[cfg(unix)]
+fn run_worker_module(
+    control: std::os::unix::net::UnixStream,
+    events: &mut std::os::unix::net::UnixStream,
+) -> anyhow::Result<()> {
+    use std::io::{BufRead, Read, Write};
+
+    let mut reader = std::io::BufReader::new(control);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let spec: WorkerSpec = serde_json::from_str(&line)?;
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let mut artifact = vec![0; u64::from_be_bytes(len) as usize];
+    reader.read_exact(&mut artifact)?;
+    // The provider sends nothing more, and closes the socket to stop the
+    // worker, or when it exits itself
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut reader, &mut std::io::sink());
+        std::process::exit(1);
+    });
+
+    spec.config.run_as.drop_privileges()?;
+    let engine = wasmtime::Engine::new(&crate::module_cache::engine_config(spec.simd));
+    let module = wasmtime::Module::deserialize(&engine, &artifact)?;
+    drop(artifact);
+
+    let (status_sender, mut statuses) = tokio::sync::mpsc::channel(1);
+    let mut status_events = events.try_clone()?;
+    let forwarder = std::thread::spawn(move || -> anyhow::Result<()> {
+        while let Some(status) = statuses.blocking_recv() {
+            if let Some(event) = WorkerEvent::from_status(status) {
+                serde_json::to_writer(&mut status_events, &event)?;
+                status_events.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    });
+
+    let memory_limit = crate::memory_limit::MemoryLimit::new(spec.memory_limit);
+    let _memory_limit = memory_limit.enter();
+    let cpu_scheduler = crate::cpu_limit::CpuScheduler::new(spec.cpu_tick);
+    let _cpu = cpu_scheduler.track(&spec.name, spec.cpu_limit);
+    let result = crate::wasi_runtime::run_instance(
+        &spec.name,
+        &spec.entry,
+        &module,
+        &spec.config,
+        &memory_limit,
+        &spec.args,
+        Stdio::process(spec.stdin, spec.stdout, spec.stderr),
+        None,
+        Some(&status_sender),
+        None,
+    );
+    drop(status_sender);
+    forwarder
+        .join()
+        .map_err(|_| anyhow::anyhow!("forwarding statuses panicked"))??;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_process_isolation_can_be_requested() {
+        assert_eq!("process".parse::<Isolation>().unwrap(), Isolation::Process);
+        assert_eq!("Process".parse::<Isolation>().unwrap(), Isolation::Process);
+        assert!("thread".parse::<Isolation>().is_err());
+        assert!("vm".parse::<Isolation>().is_err());
+    }
+
+    #[test]
+    fn statuses_are_reported_through_events() {
+        let line = serde_json::to_string(
+            &WorkerEvent::from_status(Status::terminated_with_reason(
+                "unable to run module",
+                true,
+                "OOMKilled",
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let event: WorkerEvent = serde_json::from_str(&line).unwrap();
+        match event.into_status().unwrap() {
+            Status::Terminated {
+                message,
+                failed,
+                reason,
+                exit_code,
+                ..
+            } => {
+                assert_eq!(message, "unable to run module");
+                assert!(failed);
+                assert_eq!(reason.as_deref(), Some("OOMKilled"));
+                assert_eq!(exit_code, None);
+            }
+            other => panic!("unexpected status {:?}", other),
+        }
+        assert!(WorkerEvent::from_status(Status::waiting("waiting")).is_none());
+        assert_eq!(
+            WorkerEvent::from_status(Status::running()),
+            Some(WorkerEvent::Running)
+        );
+
+        let exited = worker_error("unable to run module".to_owned(), Some(3));
+        assert_eq!(crate::wasi_runtime::exit_status(&exited), Some(3));
+        assert_eq!(
+            crate::wasi_runtime::exit_status(&worker_error("failed".to_owned(), None)),
+            None
+        );
+    }
+}
@@ -6,6 +6,7 @@
 //! # Example
 //! ```rust,no_run
 //! use kubelet::{Kubelet, config::Config};
+//! use kubelet::device_plugin_manager::DevicePluginManager;
 //! use kubelet::store::oci::FileStore;
 //! use std::sync::Arc;
 //! use wasi_provider::WasiProvider;
@@ -19,9 +20,20 @@
 //!     // Load a kubernetes configuration
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
 //!     let plugin_registry = Arc::new(Default::default());
+//!     let device_plugin_manager = Arc::new(DevicePluginManager::new(
+//!         &kubelet_config.device_plugins_dir,
+//!         kube::Client::new(kubeconfig.clone()),
+//!         &kubelet_config.node_name,
+//!     ));
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasiProvider::new(store, &kubelet_config, kubeconfig.clone(), plugin_registry).await.unwrap();
+//!     let provider = WasiProvider::new(
+//!         store,
+//!         &kubelet_config,
+//!         kubeconfig.clone(),
+//!         plugin_registry,
+//!         device_plugin_manager,
+//!     ).await.unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
@@ -32,32 +44,114 @@
 
 #![deny(missing_docs)]
 
+mod checkpoint;
+mod component;
+mod cpu_limit;
+mod hooks;
+mod isolate;
+mod memory_limit;
+mod module_cache;
+mod read_only;
+mod resize;
+mod run_as;
+mod seccomp;
+mod simd;
+mod stats;
+mod stdio;
+#[cfg(test)]
+mod test_util;
+mod wasi_nn;
 mod wasi_runtime;
+mod wasm_binary;
 
 use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use cpu_limit::CpuScheduler;
+use kubelet::cpu_manager::CpuManager;
+use kubelet::device_plugin_manager::DevicePluginManager;
+use kubelet::ephemeral_storage::StorageTracker;
+use kubelet::hugepages::HugePagesManager;
+use kubelet::memory_manager::MemoryManager;
 use kubelet::node::Builder;
 use kubelet::plugin_watcher::PluginRegistry;
-use kubelet::pod::state::prelude::SharedState;
+use kubelet::pod::admission::AdmissionQueue;
+use kubelet::pod::state::prelude::{SharedState, State};
 use kubelet::pod::{Handle, Pod, PodKey};
-use kubelet::provider::{Provider, ProviderError};
+use kubelet::provider::{
+    AttachProvider, ExecInput, ExecOutput, ExecProvider, ExitCode, PortForwardProvider, Provider,
+    ProviderError,
+};
+use kubelet::secret::credential_helper::CredentialHelpers;
 use kubelet::state::common::registered::Registered;
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
+use kubelet::stats::PodStats;
+use kubelet::store::verification::ContentVerifier;
 use kubelet::store::Store;
+use kubelet::topology_manager::TopologyManager;
 use kubelet::volume::Ref;
+use module_cache::ModuleCache;
 use tokio::sync::RwLock;
-use wasi_runtime::Runtime;
+use tracing::warn;
+use wasi_runtime::{Runtime, WasiRuntime};
+
+pub use isolate::run_worker;
 
 mod states;
+use states::pod::completed::Completed;
 use states::pod::PodState;
 
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
 const VOLUME_DIR: &str = "volumes";
+/// Pod annotation used to request the wasi-sockets capability
+const WASI_SOCKETS_ANNOTATION: &str = "krustlet.dev/wasi-sockets";
+/// Pod annotation used to request the wasi-http outbound request capability
+const WASI_HTTP_ANNOTATION: &str = "krustlet.dev/wasi-http";
+/// Pod annotation listing the URL prefixes wasi-http requests may be made to
+const WASI_HTTP_ALLOW_ANNOTATION: &str = "krustlet.dev/wasi-http-allow";
+/// Capabilities pods can request through annotations that are not implemented
+/// by the version of wasmtime-wasi we currently build against
+const UNSUPPORTED_CAPABILITY_ANNOTATIONS: &[&str] =
+    &[WASI_SOCKETS_ANNOTATION, WASI_HTTP_ANNOTATION];
+
+/// Returns the image platforms this provider can run, in order of preference.
+///
+/// These should be set as the `platforms` of the OCI client used by the
+/// provider's store, so that pulling a multi-platform image selects a WASI
+/// module. Toolchains publish WASI modules under several platform names, so
+/// the less common names are used as fallbacks.
+pub fn supported_platforms() -> Vec<oci_distribution::manifest::Platform> {
+    use oci_distribution::manifest::Platform;
+    vec![
+        Platform::new("wasi", "wasm32"),
+        Platform::new("wasi", "wasm"),
+        Platform::new("wasip1", "wasm"),
+    ]
+}
+
+/// Returns the image layer media types this provider can run modules from,
+/// in order of preference.
+///
+/// These should be set as the `accepted_media_types` of the OCI client used
+/// by the provider's store. Besides the wasm artifact media types, for
+/// modules in the binary and text formats, this accepts the tar layers of images built with container tooling, which the
+/// store searches for a `.wasm` file.
+pub fn supported_media_types() -> Vec<String> {
+    use oci_distribution::manifest;
+    vec![
+        manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+        manifest::WASM_TEXT_LAYER_MEDIA_TYPE.to_owned(),
+        manifest::IMAGE_LAYER_MEDIA_TYPE.to_owned(),
+        manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+        manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE.to_owned(),
+        manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+    ]
+}
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
@@ -68,15 +162,58 @@ pub struct WasiProvider {
 
 type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, wasi_runtime::HandleFactory>>>>>;
 
+/// What is needed to run commands in a running container for `kubectl exec`,
+/// and to attach to it for `kubectl attach`
+#[derive(Clone)]
+struct ExecTarget {
+    runtime: Arc<WasiRuntime>,
+    module: wasmtime::Module,
+    attachment: stdio::Attachment,
+    /// When the container started
+    started_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The running containers of each pod that commands can be run in and
+/// attached to, by name
+type ExecTargetMap = Arc<RwLock<HashMap<PodKey, HashMap<String, ExecTarget>>>>;
+
 /// Provider-level state shared between all pods
 #[derive(Clone)]
 pub struct ProviderState {
     handles: PodHandleMap,
+    exec_targets: ExecTargetMap,
     store: Arc<dyn Store + Sync + Send>,
     log_path: PathBuf,
     kubeconfig: kube::Config,
     volume_path: PathBuf,
     plugin_registry: Arc<PluginRegistry>,
+    device_plugin_manager: Arc<DevicePluginManager>,
+    content_verifier: Option<Arc<dyn ContentVerifier>>,
+    pull_progress_interval: std::time::Duration,
+    readiness_gate_poll_interval: std::time::Duration,
+    secrets_in_memory: bool,
+    seccomp_profile_dir: PathBuf,
+    credential_helpers: Option<Arc<CredentialHelpers>>,
+    module_cache: Arc<ModuleCache>,
+    default_container_memory_limit: Option<u64>,
+    cpu_scheduler: Arc<CpuScheduler>,
+    cpu_manager: Arc<CpuManager>,
+    memory_manager: Arc<MemoryManager>,
+    topology_manager: Arc<TopologyManager>,
+    huge_pages_manager: Arc<HugePagesManager>,
+    storage_tracker: Arc<StorageTracker>,
+    admission_queue: Arc<AdmissionQueue>,
+    container_log_max_size: u64,
+    container_log_max_files: usize,
+}
+
+impl ProviderState {
+    /// The directory that the logs of the containers in a pod are kept in,
+    /// named like the pod's volume directory
+    fn pod_log_dir(&self, pod_key: &PodKey) -> PathBuf {
+        self.log_path
+            .join(format!("{}-{}", pod_key.name(), pod_key.namespace()))
+    }
 }
 
 #[async_trait]
@@ -93,14 +230,49 @@ impl GenericProviderState for ProviderState {
     fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
         Some(self.plugin_registry.clone())
     }
+    fn content_verifier(&self) -> Option<Arc<dyn ContentVerifier>> {
+        self.content_verifier.clone()
+    }
+    fn credential_helpers(&self) -> Option<Arc<CredentialHelpers>> {
+        self.credential_helpers.clone()
+    }
+    fn admission_queue(&self) -> Option<Arc<AdmissionQueue>> {
+        Some(self.admission_queue.clone())
+    }
+    fn pull_progress_interval(&self) -> std::time::Duration {
+        self.pull_progress_interval
+    }
+    fn readiness_gate_poll_interval(&self) -> std::time::Duration {
+        self.readiness_gate_poll_interval
+    }
+    fn secrets_in_memory(&self) -> bool {
+        self.secrets_in_memory
+    }
+    /// Runs the `preStop` hooks of the pod's running containers, then stops
+    /// them and waits for them to exit. The hooks and the containers share
+    /// the pod's grace period, so the time the hooks take comes out of what
+    /// is left for the containers.
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
-        let mut handle_writer = self.handles.write().await;
-        if let Some(handle) = handle_writer.get_mut(&key) {
-            handle.stop().await
-        } else {
-            Ok(())
+        let handle = self.handles.read().await.get(&key).cloned();
+        let handle = match handle {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        let deadline = tokio::time::Instant::now() + pod.deletion_grace_period();
+        hooks::run_pre_stop_hooks(self, pod, deadline).await;
+        handle.stop().await?;
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if !handle.wait_within(remaining).await {
+            warn!(
+                "Containers of pod {} did not exit within its grace period",
+                pod.name()
+            );
+            // Modules that checkpoint are only asked to stop the first time,
+            // so they are interrupted now that they have had their chance
+            handle.stop().await?;
         }
+        Ok(())
     }
 }
 
@@ -111,24 +283,87 @@ impl WasiProvider {
         config: &kubelet::config::Config,
         kubeconfig: kube::Config,
         plugin_registry: Arc<PluginRegistry>,
+        device_plugin_manager: Arc<DevicePluginManager>,
     ) -> anyhow::Result<Self> {
         let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
+        let module_cache =
+            ModuleCache::new(config.data_dir.join(module_cache::COMPILED_MODULE_DIR));
+        let content_verifier = kubelet::store::verification::configured_verifier(config)?;
+        let credential_helpers =
+            kubelet::secret::credential_helper::configured_credential_helpers(config)?;
+        let cpu_manager = CpuManager::new(config.cpu_manager_policy)?;
+        let memory_manager = MemoryManager::new(config.memory_manager_policy);
+        let topology_manager = TopologyManager::new(config.topology_manager_policy)
+            .with_provider(cpu_manager.clone())
+            .with_provider(memory_manager.clone())
+            .with_provider(device_plugin_manager.clone());
         Ok(Self {
             shared: ProviderState {
                 handles: Default::default(),
+                exec_targets: Default::default(),
                 store,
                 log_path,
                 volume_path,
                 kubeconfig,
                 plugin_registry,
+                device_plugin_manager,
+                content_verifier,
+                pull_progress_interval: config.pull_progress_interval,
+                readiness_gate_poll_interval: config.readiness_gate_poll_interval,
+                secrets_in_memory: config.secrets_in_memory,
+                seccomp_profile_dir: config.data_dir.join(seccomp::SECCOMP_PROFILE_DIR),
+                credential_helpers,
+                module_cache: Arc::new(module_cache),
+                default_container_memory_limit: config.default_container_memory_limit,
+                cpu_scheduler: CpuScheduler::new(config.cpu_limit_tick_interval),
+                cpu_manager,
+                memory_manager,
+                topology_manager: Arc::new(topology_manager),
+                huge_pages_manager: HugePagesManager::new(),
+                storage_tracker: StorageTracker::new(config.ephemeral_storage_check_interval),
+                admission_queue: AdmissionQueue::new(),
+                container_log_max_size: config.container_log_max_size,
+                container_log_max_files: config.container_log_max_files,
             },
         })
     }
 }
 
+impl WasiProvider {
+    /// Returns the CPU time used by a running container, if it is running on
+    /// this node
+    pub fn container_cpu_usage(
+        &self,
+        namespace: &str,
+        pod_name: &str,
+        container_name: &str,
+    ) -> Option<std::time::Duration> {
+        self.shared
+            .cpu_scheduler
+            .usage(&format!("{}:{}:{}", namespace, pod_name, container_name))
+    }
+
+    /// Looks up what is needed to exec in or attach to a running container
+    async fn exec_target(&self, pod: &PodKey, container: &str) -> anyhow::Result<ExecTarget> {
+        let target = self
+            .shared
+            .exec_targets
+            .read()
+            .await
+            .get(pod)
+            .and_then(|containers| containers.get(container))
+            .cloned()
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: pod.name(),
+                container_name: container.to_owned(),
+            })?;
+        Ok(target)
+    }
+}
+
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, Ref>,
@@ -148,9 +383,12 @@ impl Provider for WasiProvider {
     }
 
     async fn node(&self, builder: &mut Builder) -> anyhow::Result<()> {
-        builder.set_architecture("wasm-wasi");
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        builder.add_label(
+            simd::WASM_SIMD_LABEL,
+            &simd::host_supports_simd().to_string(),
+        );
         Ok(())
     }
 
@@ -174,21 +412,151 @@ impl Provider for WasiProvider {
         handle.output(&container_name, sender).await
     }
 
+    fn container_runtime_version(&self) -> String {
+        format!("mvp://krustlet-wasi-{}", env!("CARGO_PKG_VERSION"))
+    }
+
+    async fn pod_stats(&self) -> anyhow::Result<Vec<PodStats>> {
+        Ok(stats::pod_stats(self).await)
+    }
+
+    fn resume_state(&self, name: &str) -> Option<Box<dyn State<PodState>>> {
+        // A pod's modules are only kept in memory, so only pods that have
+        // completed can be resumed. Resuming them keeps their modules from
+        // being run again when the kubelet restarts.
+        let resumable: Vec<Box<dyn State<PodState>>> = vec![Box::new(Completed)];
+        resumable.into_iter().find(|state| state.name() == name)
+    }
+
+    fn exec_provider(&self) -> Option<&dyn ExecProvider> {
+        Some(self)
+    }
+
+    fn attach_provider(&self) -> Option<&dyn AttachProvider> {
+        Some(self)
+    }
+
+    fn port_forward_provider(&self) -> Option<&dyn PortForwardProvider> {
+        Some(self)
+    }
+
     fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
         Some(self.shared.plugin_registry.clone())
     }
 
+    fn device_plugin_manager(&self) -> Option<Arc<DevicePluginManager>> {
+        Some(self.shared.device_plugin_manager.clone())
+    }
+
+    fn store(&self) -> Option<Arc<dyn Store + Send + Sync>> {
+        Some(self.shared.store.clone())
+    }
+
     fn volume_path(&self) -> Option<PathBuf> {
         Some(self.shared.volume_path())
     }
 }
 
+#[async_trait]
+impl ExecProvider for WasiProvider {
+    /// Runs a new instance of the container's module with the command as its
+    /// arguments, since a module can't run other programs. Terminals aren't
+    /// supported, so `tty` is ignored.
+    #[allow(clippy::too_many_arguments)]
+    async fn exec(
+        &self,
+        pod: PodKey,
+        container: String,
+        command: Vec<String>,
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+        _tty: bool,
+    ) -> anyhow::Result<ExitCode> {
+        let target = self.exec_target(&pod, &container).await?;
+        target
+            .runtime
+            .exec(
+                target.module,
+                self.shared.cpu_scheduler.clone(),
+                command,
+                stdio::Stdio::exec(stdin, stdout, stderr),
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl AttachProvider for WasiProvider {
+    /// Attaches to the streams of the container's running module. Terminals
+    /// aren't supported, so `tty` is ignored.
+    async fn attach(
+        &self,
+        pod: PodKey,
+        container: String,
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+        _tty: bool,
+    ) -> anyhow::Result<()> {
+        let target = self.exec_target(&pod, &container).await?;
+        target.attachment.attach(stdin, stdout, stderr).await
+    }
+}
+
+#[async_trait]
+impl PortForwardProvider for WasiProvider {
+    /// Modules aren't given a network of their own, so a module serving a
+    /// port binds it on the node, where it is connected to over loopback.
+    async fn port_forward(&self, pod: PodKey, port: u16) -> anyhow::Result<SocketAddr> {
+        let running = self
+            .shared
+            .exec_targets
+            .read()
+            .await
+            .get(&pod)
+            .map(|containers| !containers.is_empty())
+            .unwrap_or(false);
+        if !running {
+            return Err(ProviderError::PodNotFound {
+                pod_name: pod.name(),
+            }
+            .into());
+        }
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+}
+
 impl GenericProvider for WasiProvider {
     type ProviderState = ProviderState;
     type PodState = PodState;
     type RunState = crate::states::pod::initializing::Initializing;
 
-    fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
+    fn validate_pod_runnable(pod: &Pod) -> anyhow::Result<()> {
+        // NOTE: Rather than silently giving the module a context without the
+        // capability it asked for, fail the pod up front so the user knows why
+        // its calls would never work.
+        for annotation in UNSUPPORTED_CAPABILITY_ANNOTATIONS {
+            if let Some(value) = pod.get_annotation(annotation) {
+                if value.eq_ignore_ascii_case("true") {
+                    return Err(anyhow::anyhow!(
+                        "Pod requested a capability via the {} annotation, but it is not supported by this provider",
+                        annotation
+                    ));
+                }
+            }
+        }
+        // An allowlist only makes sense with wasi-http, so rather than
+        // ignoring it, tell the user it can't be enforced
+        if pod.get_annotation(WASI_HTTP_ALLOW_ANNOTATION).is_some() {
+            return Err(anyhow::anyhow!(
+                "Pod set the {} annotation, but wasi-http is not supported by this provider",
+                WASI_HTTP_ALLOW_ANNOTATION
+            ));
+        }
+        wasi_nn::requested_backend(pod)?;
+        wasi_nn::models_volume(pod)?;
+        simd::pod_uses_simd(pod)?;
         Ok(())
     }
 
@@ -203,3 +571,53 @@ impl GenericProvider for WasiProvider {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::pod;
+
+    #[test]
+    fn pods_requesting_wasi_sockets_are_rejected() {
+        let err = WasiProvider::validate_pod_runnable(&pod(serde_json::json!({
+            WASI_SOCKETS_ANNOTATION: "true"
+        })))
+        .unwrap_err();
+        assert!(err.to_string().contains(WASI_SOCKETS_ANNOTATION));
+
+        WasiProvider::validate_pod_runnable(&pod(serde_json::json!({
+            WASI_SOCKETS_ANNOTATION: "false"
+        })))
+        .expect("pods not requesting wasi-sockets should be runnable");
+        WasiProvider::validate_pod_runnable(&pod(serde_json::json!({})))
+            .expect("pods without annotations should be runnable");
+    }
+
+    #[test]
+    fn pods_requesting_wasi_http_are_rejected() {
+        let err = WasiProvider::validate_pod_runnable(&pod(serde_json::json!({
+            WASI_HTTP_ANNOTATION: "true"
+        })))
+        .unwrap_err();
+        assert!(err.to_string().contains(WASI_HTTP_ANNOTATION));
+
+        WasiProvider::validate_pod_runnable(&pod(serde_json::json!({
+            WASI_HTTP_ANNOTATION: "false"
+        })))
+        .expect("pods not requesting wasi-http should be runnable");
+    }
+
+    #[test]
+    fn pods_with_a_wasi_http_allowlist_are_rejected() {
+        for annotations in [
+            serde_json::json!({ WASI_HTTP_ALLOW_ANNOTATION: "https://api.example.com" }),
+            serde_json::json!({
+                WASI_HTTP_ANNOTATION: "false",
+                WASI_HTTP_ALLOW_ANNOTATION: "https://api.example.com",
+            }),
+        ] {
+            let err = WasiProvider::validate_pod_runnable(&pod(annotations)).unwrap_err();
+            assert!(err.to_string().contains(WASI_HTTP_ALLOW_ANNOTATION));
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Enforcement of container memory limits
+//!
+//! The wasmtime version we build against has no per-store resource limits,
+//! so the engine shared by all containers creates linear memories through
+//! [`LimitedMemoryCreator`]. Memories take the limit of the container whose
+//! module is being instantiated on the current thread (see
+//! [`MemoryLimit::enter`]) and refuse to grow past it, so `memory.grow` fails
+//! inside the module rather than the node running out of memory. Memories are
+//! also bound to the NUMA nodes the memory manager chose for the container,
+//! if any (see [`kubelet::memory_manager::bind_memory`]), and backed with the
+//! huge pages reserved for the container's pod, if any (see
+//! [`kubelet::hugepages::map_huge_pages`]).
+//!
+//! Every container's memories are counted, whether or not it has a limit, so
+//! that a limit can be set, changed or removed while the module runs (see
+//! [`MemoryLimit::resize`]). Linear memories never shrink, so a limit can't be
+//! made smaller than what the module already uses.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use kubelet::hugepages::{map_huge_pages, HugePageMapping};
+use kubelet::memory_manager::bind_memory;
+use wasmtime::{LinearMemory, MemoryCreator, MemoryType};
+use wasmtime_runtime::Mmap;
+
+/// The termination reason for containers that exceeded their memory limit
+pub(crate) const OOM_KILLED: &str = "OOMKilled";
+
+const WASM_PAGE_SIZE: usize = 0x10000;
+/// The number of pages a 32-bit linear memory can address
+const WASM_MAX_PAGES: u32 = 0x10000;
+
+thread_local! {
+    static CURRENT_LIMIT: RefCell<Option<Arc<MemoryLimit>>> = const { RefCell::new(None) };
+}
+
+/// The memory limit of a container, shared by all the linear memories of its
+/// module
+pub struct MemoryLimit {
+    /// The limit in bytes, which is `u64::MAX` for containers without one
+    limit_bytes: AtomicU64,
+    max_pages: AtomicU32,
+    used_pages: AtomicU32,
+    exceeded: AtomicBool,
+}
+
+impl MemoryLimit {
+    /// Creates a limit of the given number of bytes. Memories are allocated
+    /// in whole pages, so the limit is rounded down to a number of pages.
+    pub fn new(limit_bytes: u64) -> Arc<Self> {
+        Arc::new(MemoryLimit {
+            limit_bytes: AtomicU64::new(limit_bytes),
+            max_pages: AtomicU32::new(max_pages(limit_bytes)),
+            used_pages: AtomicU32::new(0),
+            exceeded: AtomicBool::new(false),
+        })
+    }
+
+    /// Creates a limit for a container without one, which only counts the
+    /// memory the module uses
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(u64::MAX)
+    }
+
+    /// The limit in bytes
+    pub fn limit_bytes(&self) -> u64 {
+        self.limit_bytes.load(Ordering::SeqCst)
+    }
+
+    /// The bytes of linear memory the module uses
+    pub fn used_bytes(&self) -> u64 {
+        self.used_pages.load(Ordering::SeqCst) as u64 * WASM_PAGE_SIZE as u64
+    }
+
+    /// Changes the limit to `limit_bytes`, or removes it if `None`, while the
+    /// module runs. Fails, leaving the limit as it was, if the module already
+    /// uses more memory than the new limit allows.
+    pub fn resize(&self, limit_bytes: Option<u64>) -> anyhow::Result<()> {
+        let limit_bytes = limit_bytes.unwrap_or(u64::MAX);
+        let max_pages = max_pages(limit_bytes);
+        let previous = self.max_pages.swap(max_pages, Ordering::SeqCst);
+        let used_pages = self.used_pages.load(Ordering::SeqCst);
+        if used_pages > max_pages {
+            self.max_pages.store(previous, Ordering::SeqCst);
+            return Err(anyhow::anyhow!(
+                "the module already uses {} bytes of memory, more than the new limit of {} bytes",
+                used_pages as u64 * WASM_PAGE_SIZE as u64,
+                limit_bytes
+            ));
+        }
+        self.limit_bytes.store(limit_bytes, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns whether the module has tried to use more memory than the limit
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Applies this limit to the memories created on the current thread
+    /// until the returned guard is dropped
+    pub fn enter(self: &Arc<Self>) -> MemoryLimitGuard {
+        CURRENT_LIMIT.with(|current| current.replace(Some(self.clone())));
+        MemoryLimitGuard {}
+    }
+
+    /// Takes the given number of pages from the limit, returning false if
+    /// there are not enough left
+    fn reserve(&self, pages: u32) -> bool {
+        let max_pages = self.max_pages.load(Ordering::SeqCst);
+        let reserved = self
+            .used_pages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(pages).filter(|total| *total <= max_pages)
+            })
+            .is_ok();
+        if !reserved {
+            self.exceeded.store(true, Ordering::SeqCst);
+        }
+        reserved
+    }
+
+    fn release(&self, pages: u32) {
+        self.used_pages.fetch_sub(pages, Ordering::SeqCst);
+    }
+}
+
+/// The whole pages that fit in the given number of bytes, up to the most a
+/// linear memory can have
+fn max_pages(limit_bytes: u64) -> u32 {
+    (limit_bytes / WASM_PAGE_SIZE as u64).min(WASM_MAX_PAGES as u64) as u32
+}
+
+/// Stops applying a memory limit to the current thread when dropped
+pub struct MemoryLimitGuard {}
+
+impl Drop for MemoryLimitGuard {
+    fn drop(&mut self) {
+        CURRENT_LIMIT.with(|current| current.replace(None));
+    }
+}
+
+/// Creates linear memories that are limited by the memory limit of the
+/// current thread, if any
+pub struct LimitedMemoryCreator;
+
+unsafe impl MemoryCreator for LimitedMemoryCreator {
+    fn new_memory(
+        &self,
+        ty: MemoryType,
+        reserved_size_in_bytes: Option<u64>,
+        guard_size_in_bytes: u64,
+    ) -> Result<Box<dyn LinearMemory>, String> {
+        let limit = CURRENT_LIMIT.with(|current| current.borrow().clone());
+        let minimum = ty.limits().min();
+        if let Some(limit) = &limit {
+            if !limit.reserve(minimum) {
+                return Err(format!(
+                    "the module's initial memory of {} bytes exceeds the memory limit of {} bytes",
+                    minimum as u64 * WASM_PAGE_SIZE as u64,
+                    limit.limit_bytes()
+                ));
+            }
+        }
+        let memory = LimitedMemory::new(
+            minimum,
+            ty.limits().max(),
+            reserved_size_in_bytes,
+            guard_size_in_bytes as usize,
+        );
+        if let (Err(_), Some(limit)) = (&memory, &limit) {
+            limit.release(minimum);
+        }
+        memory.map(|mut memory| {
+            memory.limit = limit;
+            Box::new(memory) as Box<dyn LinearMemory>
+        })
+    }
+}
+
+/// A linear memory, allocated in the same way as wasmtime's default memories
+struct LimitedMemory {
+    allocation: RefCell<Allocation>,
+    maximum: Option<u32>,
+    guard_bytes: usize,
+    limit: Option<Arc<MemoryLimit>>,
+}
+
+struct Allocation {
+    mmap: Mmap,
+    /// The huge pages backing the mapping, which are given back once it is
+    /// unmapped
+    huge_pages: Vec<HugePageMapping>,
+    /// The current size in pages
+    size: u32,
+}
+
+impl LimitedMemory {
+    fn new(
+        minimum: u32,
+        maximum: Option<u32>,
+        reserved_bytes: Option<u64>,
+        guard_bytes: usize,
+    ) -> Result<Self, String> {
+        let minimum_bytes = minimum as usize * WASM_PAGE_SIZE;
+        // Static memories reserve their whole address range up front so that
+        // they never move when they grow
+        let reserved_bytes = match reserved_bytes {
+            Some(reserved) => reserved as usize,
+            None => minimum_bytes,
+        };
+        let mut mmap = Mmap::accessible_reserved(minimum_bytes, reserved_bytes + guard_bytes)?;
+        let huge_pages = map_huge_pages(mmap.as_mut_ptr(), minimum_bytes);
+        bind_memory(mmap.as_mut_ptr(), mmap.len());
+        Ok(LimitedMemory {
+            allocation: RefCell::new(Allocation {
+                mmap,
+                huge_pages,
+                size: minimum,
+            }),
+            maximum,
+            guard_bytes,
+            limit: None,
+        })
+    }
+
+    fn grow_allocation(allocation: &mut Allocation, delta: u32, guard_bytes: usize) -> bool {
+        let prev_bytes = allocation.size as usize * WASM_PAGE_SIZE;
+        let delta_bytes = delta as usize * WASM_PAGE_SIZE;
+        let new_bytes = prev_bytes + delta_bytes;
+        if new_bytes > allocation.mmap.len() - guard_bytes {
+            // Dynamic memories are moved to a larger allocation
+            let mut mmap = match Mmap::accessible_reserved(new_bytes, new_bytes + guard_bytes) {
+                Ok(mmap) => mmap,
+                Err(_) => return false,
+            };
+            let huge_pages = map_huge_pages(mmap.as_mut_ptr(), new_bytes);
+            bind_memory(mmap.as_mut_ptr(), mmap.len());
+            let copy_len = allocation.mmap.len() - guard_bytes;
+            mmap.as_mut_slice()[..copy_len]
+                .copy_from_slice(&allocation.mmap.as_slice()[..copy_len]);
+            allocation.mmap = mmap;
+            allocation.huge_pages = huge_pages;
+            true
+        } else {
+            if allocation
+                .mmap
+                .make_accessible(prev_bytes, delta_bytes)
+                .is_err()
+            {
+                return false;
+            }
+            // The new pages haven't been written yet, so they can be replaced
+            // with huge pages
+            let grown = unsafe { allocation.mmap.as_mut_ptr().add(prev_bytes) };
+            let huge_pages = map_huge_pages(grown, delta_bytes);
+            if !huge_pages.is_empty() {
+                bind_memory(grown, delta_bytes);
+                allocation.huge_pages.extend(huge_pages);
+            }
+            true
+        }
+    }
+}
+
+unsafe impl LinearMemory for LimitedMemory {
+    fn size(&self) -> u32 {
+        self.allocation.borrow().size
+    }
+
+    fn grow(&self, delta: u32) -> Option<u32> {
+        let mut allocation = self.allocation.borrow_mut();
+        let prev_pages = allocation.size;
+        if delta == 0 {
+            return Some(prev_pages);
+        }
+        let new_pages = prev_pages.checked_add(delta)?;
+        if self.maximum.map(|max| new_pages > max).unwrap_or(false) || new_pages > WASM_MAX_PAGES {
+            return None;
+        }
+        if let Some(limit) = &self.limit {
+            if !limit.reserve(delta) {
+                return None;
+            }
+        }
+        if !Self::grow_allocation(&mut allocation, delta, self.guard_bytes) {
+            if let Some(limit) = &self.limit {
+                limit.release(delta);
+            }
+            return None;
+        }
+        allocation.size = new_pages;
+        Some(prev_pages)
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.allocation.borrow_mut().mmap.as_mut_ptr()
+    }
+}
+
+impl Drop for LimitedMemory {
+    fn drop(&mut self) {
+        if let Some(limit) = &self.limit {
+            limit.release(self.allocation.borrow().size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A module that grows its memory by the number of pages passed to
+    /// `grow`, trapping if the growth is denied, as modules that fail to
+    /// allocate usually do
+    const GROW_MEMORY_MODULE: &str = r#"
+        (module
+          (memory 1)
+          (func (export "grow") (param $pages i32)
+            (if (i32.eq (memory.grow (local.get $pages)) (i32.const -1))
+              (then unreachable))))
+    "#;
+
+    fn run_grow(limit: &Arc<MemoryLimit>, pages: i32) -> anyhow::Result<()> {
+        let mut config = wasmtime::Config::new();
+        config.with_host_memory(Arc::new(LimitedMemoryCreator));
+        let engine = wasmtime::Engine::new(&config);
+        let store = wasmtime::Store::new(&engine);
+        let module = wasmtime::Module::new(&engine, wat::parse_str(GROW_MEMORY_MODULE)?)?;
+        let _guard = limit.enter();
+        let instance = wasmtime::Instance::new(&store, &module, &[])?;
+        let grow = instance
+            .get_func("grow")
+            .ok_or_else(|| anyhow::anyhow!("no grow export"))?;
+        grow.call(&[wasmtime::Val::I32(pages)])?;
+        Ok(())
+    }
+
+    #[test]
+    fn memory_can_grow_within_the_limit() {
+        let limit = MemoryLimit::new(4 * WASM_PAGE_SIZE as u64);
+        run_grow(&limit, 3).expect("growing within the limit should succeed");
+        assert!(!limit.exceeded());
+    }
+
+    #[test]
+    fn memory_cannot_grow_past_the_limit() {
+        let limit = MemoryLimit::new(4 * WASM_PAGE_SIZE as u64);
+        let error = run_grow(&limit, 8).expect_err("growing past the limit should trap");
+        assert!(error.downcast_ref::<wasmtime::Trap>().is_some());
+        assert!(limit.exceeded());
+    }
+
+    #[test]
+    fn limits_can_change_while_the_module_runs() {
+        let limit = MemoryLimit::new(2 * WASM_PAGE_SIZE as u64);
+        assert!(run_grow(&limit, 3).is_err());
+        limit.resize(Some(8 * WASM_PAGE_SIZE as u64)).unwrap();
+        run_grow(&limit, 3).expect("growing within the new limit should succeed");
+        limit.resize(None).unwrap();
+        run_grow(&limit, 16).expect("growing without a limit should succeed");
+    }
+
+    #[test]
+    fn limits_cannot_shrink_below_the_memory_in_use() {
+        let limit = MemoryLimit::new(8 * WASM_PAGE_SIZE as u64);
+        assert!(limit.reserve(4));
+        assert!(limit.resize(Some(2 * WASM_PAGE_SIZE as u64)).is_err());
+        assert_eq!(limit.limit_bytes(), 8 * WASM_PAGE_SIZE as u64);
+        assert!(limit.reserve(4));
+        limit.resize(Some(4 * WASM_PAGE_SIZE as u64)).unwrap_err();
+        limit.release(4);
+        limit.resize(Some(4 * WASM_PAGE_SIZE as u64)).unwrap();
+        assert!(!limit.reserve(1));
+    }
+
+    #[test]
+    fn initial_memory_past_the_limit_fails_instantiation() {
+        let limit = MemoryLimit::new(WASM_PAGE_SIZE as u64 / 2);
+        assert!(run_grow(&limit, 0).is_err());
+        assert!(limit.exceeded());
+    }
+}
@@ -0,0 +1,301 @@
+//! A cache of compiled modules
+//!
+//! Compiling a module with cranelift can take seconds for large modules, so
+//! rather than compiling on every container start the serialized compilation
+//! artifacts are stored on disk. Artifacts are keyed by the digest of the
+//! module together with a hash of the engine configuration, target and
+//! wasmtime version, as artifacts compiled under any other combination cannot
+//! be loaded. Artifacts that are corrupt or that wasmtime refuses to load are
+//! recompiled and replaced. Modules of pods that opt in to SIMD are compiled
+//! with a second engine that has SIMD enabled, if the host supports it.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use wasmtime::{Engine, Module};
+
+use crate::memory_limit::LimitedMemoryCreator;
+
+/// The directory under the kubelet data directory that compiled modules are
+/// stored in
+pub(crate) const COMPILED_MODULE_DIR: &str = "compiled-modules";
+
+/// The version of wasmtime the artifacts are compiled with. Wasmtime also
+/// checks its exact version when loading an artifact, so patch releases are
+/// caught by recompiling
+const WASMTIME_VERSION: &str = "0.24";
+/// The extension of compiled module files
+const ARTIFACT_EXTENSION: &str = "cwasm";
+/// The length of the checksum at the start of each compiled module file
+const CHECKSUM_LEN: usize = 32;
+
+/// Where a module was loaded from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModuleSource {
+    /// The module was loaded from a previously compiled artifact
+    Cache,
+    /// The module was compiled, as no usable artifact was found
+    Compiled,
+}
+
+/// A module that is ready to be instantiated
+pub struct LoadedModule {
+    /// The module
+    pub module: Module,
+    /// Where the module was loaded from
+    pub source: ModuleSource,
+    /// How long loading or compiling the module took
+    pub elapsed: Duration,
+}
+
+impl LoadedModule {
+    /// Describes how the module was loaded, for use in events
+    pub fn describe(&self) -> String {
+        match self.source {
+            ModuleSource::Cache => format!(
+                "module loaded from compilation cache in {:.2?}",
+                self.elapsed
+            ),
+            ModuleSource::Compiled => format!("module compiled in {:.2?}", self.elapsed),
+        }
+    }
+}
+
+/// An engine modules are compiled with, and the key of its configuration
+struct CacheEngine {
+    engine: Engine,
+    key: String,
+}
+
+impl CacheEngine {
+    fn new(simd: bool) -> Self {
+        // Every option set by engine_config must be part of this
+        // description, so that changing them invalidates existing artifacts
+        let engine_description = format!(
+            "interruptable=true;host_memory=limited;simd={};wasmtime={};target={}-{}",
+            simd,
+            WASMTIME_VERSION,
+            std::env::consts::ARCH,
+            std::env::consts::OS
+        );
+        CacheEngine {
+            engine: Engine::new(&engine_config(simd)),
+            key: hex_digest(engine_description.as_bytes()),
+        }
+    }
+}
+
+/// Compiles modules with a shared engine, storing the compiled artifacts so
+/// that each module only needs to be compiled once
+pub struct ModuleCache {
+    dir: PathBuf,
+    engine: CacheEngine,
+    /// The engine with SIMD enabled, if the host supports it
+    simd_engine: Option<CacheEngine>,
+}
+
+impl ModuleCache {
+    /// Creates a cache storing compiled modules in the given directory
+    pub fn new(dir: PathBuf) -> Self {
+        ModuleCache {
+            dir,
+            engine: CacheEngine::new(false),
+            simd_engine: match crate::simd::host_supports_simd() {
+                true => Some(CacheEngine::new(true)),
+                false => None,
+            },
+        }
+    }
+
+    /// Returns the compiled module for the given module data, compiled with
+    /// SIMD enabled if `simd`. This compiles the module if there is no
+    /// usable artifact for it, so it should be called on a blocking thread.
+    pub fn load(&self, module_data: &[u8], simd: bool) -> anyhow::Result<LoadedModule> {
+        let started = Instant::now();
+        let engine = match (simd, &self.simd_engine) {
+            (false, _) => &self.engine,
+            (true, Some(simd_engine)) => simd_engine,
+            (true, None) => {
+                return Err(anyhow::anyhow!(
+                    "this node's CPU doesn't support WebAssembly SIMD"
+                ))
+            }
+        };
+        let path = self.artifact_path(engine, module_data);
+        match self.load_artifact(engine, &path) {
+            Ok(Some(module)) => {
+                return Ok(LoadedModule {
+                    module,
+                    source: ModuleSource::Cache,
+                    elapsed: started.elapsed(),
+                })
+            }
+            Ok(None) => debug!("No compiled module found at {}", path.display()),
+            Err(e) => warn!(
+                "Recompiling module as compiled module {} is unusable: {:?}",
+                path.display(),
+                e
+            ),
+        }
+
+        let module = Module::new(&engine.engine, module_data)?;
+        let elapsed = started.elapsed();
+        // Failing to store the artifact only means the module is compiled
+        // again next time, so it doesn't fail the load
+        if let Err(e) = self.store_artifact(&path, &module) {
+            warn!(
+                "Unable to store compiled module {}: {:?}",
+                path.display(),
+                e
+            );
+        }
+        Ok(LoadedModule {
+            module,
+            source: ModuleSource::Compiled,
+            elapsed,
+        })
+    }
+
+    fn artifact_path(&self, engine: &CacheEngine, module_data: &[u8]) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}.{}",
+            hex_digest(module_data),
+            engine.key,
+            ARTIFACT_EXTENSION
+        ))
+    }
+
+    /// Loads the artifact at the given path, returning `None` if it doesn't
+    /// exist
+    fn load_artifact(&self, engine: &CacheEngine, path: &Path) -> anyhow::Result<Option<Module>> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // Wasmtime does not check artifacts for corruption, and loading a
+        // corrupt artifact could run arbitrary code, so artifacts start with
+        // a checksum of their contents
+        if contents.len() < CHECKSUM_LEN {
+            return Err(anyhow::anyhow!("compiled module is truncated"));
+        }
+        let (checksum, serialized) = contents.split_at(CHECKSUM_LEN);
+        if Sha256::digest(serialized).as_slice() != checksum {
+            return Err(anyhow::anyhow!("compiled module checksum does not match"));
+        }
+        Ok(Some(Module::deserialize(&engine.engine, serialized)?))
+    }
+
+    fn store_artifact(&self, path: &Path, module: &Module) -> anyhow::Result<()> {
+        let serialized = module.serialize()?;
+        std::fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first so that a concurrent load never
+        // sees a partially written artifact
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        file.write_all(&Sha256::digest(&serialized))?;
+        file.write_all(&serialized)?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+/// The configuration of the engine modules are compiled with, with SIMD
+/// enabled if `simd`. Worker processes running isolated modules create their
+/// engine with the same configuration, so that they can load the modules
+/// compiled here.
+pub(crate) fn engine_config(simd: bool) -> wasmtime::Config {
+    let mut config = wasmtime::Config::new();
+    config.interruptable(true);
+    config.wasm_simd(simd);
+    // Containers with memory limits are enforced by the memories this
+    // engine creates, as modules are shared by containers with and
+    // without limits
+    config.with_host_memory(Arc::new(LimitedMemoryCreator));
+    config
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULE: &str = r#"(module (func (export "_start")))"#;
+
+    fn module_data() -> Vec<u8> {
+        wat::parse_str(MODULE).unwrap()
+    }
+
+    fn artifact(cache: &ModuleCache) -> PathBuf {
+        cache.artifact_path(&cache.engine, &module_data())
+    }
+
+    #[test]
+    fn compiled_modules_are_loaded_from_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path().to_owned());
+        let compiled = cache.load(&module_data(), false).unwrap();
+        assert_eq!(compiled.source, ModuleSource::Compiled);
+        assert!(artifact(&cache).exists());
+
+        let cached = cache.load(&module_data(), false).unwrap();
+        assert_eq!(cached.source, ModuleSource::Cache);
+        assert!(cached.module.get_export("_start").is_some());
+    }
+
+    #[test]
+    fn truncated_artifacts_are_recompiled() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path().to_owned());
+        cache.load(&module_data(), false).unwrap();
+        std::fs::write(artifact(&cache), [0; CHECKSUM_LEN - 1]).unwrap();
+
+        let loaded = cache.load(&module_data(), false).unwrap();
+        assert_eq!(loaded.source, ModuleSource::Compiled);
+        // The unusable artifact is replaced
+        let loaded = cache.load(&module_data(), false).unwrap();
+        assert_eq!(loaded.source, ModuleSource::Cache);
+    }
+
+    #[test]
+    fn artifacts_with_a_bad_checksum_are_recompiled() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path().to_owned());
+        cache.load(&module_data(), false).unwrap();
+        let mut contents = std::fs::read(artifact(&cache)).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        std::fs::write(artifact(&cache), contents).unwrap();
+
+        let err = cache
+            .load_artifact(&cache.engine, &artifact(&cache))
+            .err()
+            .expect("the corrupt artifact should not load");
+        assert_eq!(err.to_string(), "compiled module checksum does not match");
+        let loaded = cache.load(&module_data(), false).unwrap();
+        assert_eq!(loaded.source, ModuleSource::Compiled);
+    }
+
+    #[test]
+    fn artifacts_of_other_engine_configurations_are_not_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ModuleCache::new(dir.path().to_owned());
+        cache.load(&module_data(), false).unwrap();
+
+        let other = ModuleCache {
+            dir: dir.path().to_owned(),
+            engine: CacheEngine::new(true),
+            simd_engine: None,
+        };
+        assert_ne!(artifact(&other), artifact(&cache));
+        let loaded = other.load(&module_data(), false).unwrap();
+        assert_eq!(loaded.source, ModuleSource::Compiled);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
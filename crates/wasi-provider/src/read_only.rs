@@ -0,0 +1,127 @@
+//! Read-only directories for read-only mounts
+//!
+//! WASI modules have no root filesystem of their own, so when a container sets
+//! `securityContext.readOnlyRootFilesystem` the directory mounted at `/` is
+//! preopened through [`ReadOnlyDir`], which rejects any operation that would
+//! modify it with `EACCES`. So are read-only volume mounts, and the mounts of
+//! ConfigMap, Secret, projected and downward API volumes, whose files come
+//! from the cluster.
+
+use std::any::Any;
+use std::path::PathBuf;
+
+use wasi_common::dir::{ReaddirCursor, ReaddirEntity, WasiDir};
+use wasi_common::file::{FdFlags, Filestat, OFlags, WasiFile};
+use wasi_common::{Error, SystemTimeSpec};
+
+/// The Windows error code that WASI reports as `EACCES`
+#[cfg(windows)]
+const ERROR_ACCESS_DENIED: i32 = 5;
+
+/// A directory, and all the directories beneath it, that can be read but not
+/// modified
+pub struct ReadOnlyDir(Box<dyn WasiDir>);
+
+impl ReadOnlyDir {
+    /// Makes the given directory read-only
+    pub fn new(dir: Box<dyn WasiDir>) -> Self {
+        ReadOnlyDir(dir)
+    }
+}
+
+/// The error returned for attempts to modify a read-only directory
+fn read_only() -> Error {
+    #[cfg(unix)]
+    let error = std::io::Error::from_raw_os_error(libc::EACCES);
+    #[cfg(windows)]
+    let error = std::io::Error::from_raw_os_error(ERROR_ACCESS_DENIED);
+    Error::new(error).context("the directory is read-only")
+}
+
+impl WasiDir for ReadOnlyDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        if write
+            || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE)
+            || fdflags.contains(FdFlags::APPEND)
+        {
+            return Err(read_only());
+        }
+        self.0
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+    }
+
+    fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.0.open_dir(symlink_follow, path)?;
+        Ok(Box::new(ReadOnlyDir(dir)))
+    }
+
+    fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>>>, Error> {
+        self.0.readdir(cursor)
+    }
+
+    fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path)
+    }
+
+    fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat()
+    }
+
+    fn get_path_filestat(&self, path: &str, follow_symlinks: bool) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks)
+    }
+
+    fn rename(&self, _path: &str, _dest_dir: &dyn WasiDir, _dest_path: &str) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(read_only())
+    }
+
+    fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(read_only())
+    }
+}
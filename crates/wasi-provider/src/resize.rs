@@ -0,0 +1,147 @@
+//! Resizing of the resources of running containers
+//!
+//! Changes to the resources of a running pod's containers are applied as
+//! they run (see [`kubelet::pod::resize`]). Memory limits are changed on the
+//! containers' [`MemoryLimit`](crate::memory_limit::MemoryLimit)s and CPU
+//! limits on the CPU scheduler. If the exclusive CPUs the pod needs change,
+//! the CPU manager assigns them and affines the threads the pod's modules run
+//! on to them. As linear memories never shrink, a memory limit below what a
+//! module already uses is infeasible, and a pod that needs more exclusive
+//! CPUs than the shared pool has left is deferred until other pods give
+//! theirs back.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use kubelet::cpu_manager::exclusive_cpus;
+use kubelet::pod::resize::{
+    container_resources, patch_resize_status, ContainerResources, ResizeError, ResizeStatus,
+};
+use kubelet::pod::{Pod, PodKey};
+
+use crate::ProviderState;
+
+/// How long to wait before retrying a deferred resize
+pub(crate) const DEFERRED_RESIZE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Applies changes to the resources of a running pod's containers
+pub(crate) struct PodResizer {
+    /// The node's default memory limit, for containers without their own
+    default_memory_limit: Option<u64>,
+    /// The resources last applied to each container, by name
+    applied: BTreeMap<String, ContainerResources>,
+    /// How the last resize went, as last reported in the pod's status
+    status: Option<ResizeStatus>,
+}
+
+impl PodResizer {
+    /// Creates a resizer for a pod whose containers were started with the
+    /// resources it has now
+    pub(crate) fn new(pod: &Pod, default_memory_limit: Option<u64>) -> Self {
+        let mut resizer = PodResizer {
+            default_memory_limit,
+            applied: BTreeMap::new(),
+            status: None,
+        };
+        resizer.applied = resizer.resources(pod).unwrap_or_default();
+        resizer
+    }
+
+    /// Whether the last resize was deferred, and should be retried
+    pub(crate) fn is_deferred(&self) -> bool {
+        self.status == Some(ResizeStatus::Deferred)
+    }
+
+    /// Applies the pod's resources to its containers if they changed since
+    /// they were last applied, and reports how the resize went in the pod's
+    /// status if that changed
+    pub(crate) async fn resize(
+        &mut self,
+        provider_state: &ProviderState,
+        client: &kube::Client,
+        pod: &Pod,
+    ) {
+        let status = match self.resources(pod) {
+            Ok(resources) if resources == self.applied => None,
+            Ok(resources) => match self.apply(provider_state, pod, &resources).await {
+                Ok(()) => {
+                    info!("Pod {} resized to {:?}", pod.name(), resources);
+                    self.applied = resources;
+                    None
+                }
+                Err(e) => {
+                    info!("Pod {} not resized: {}", pod.name(), e);
+                    Some(e.status())
+                }
+            },
+            Err(e) => {
+                warn!("Pod {} has invalid resources: {:?}", pod.name(), e);
+                Some(ResizeStatus::Infeasible)
+            }
+        };
+        if status != self.status {
+            patch_resize_status(client, pod, status).await;
+            self.status = status;
+        }
+    }
+
+    fn resources(&self, pod: &Pod) -> anyhow::Result<BTreeMap<String, ContainerResources>> {
+        let mut resources = container_resources(pod)?;
+        for container in resources.values_mut() {
+            container.memory_limit = container.memory_limit.or(self.default_memory_limit);
+        }
+        Ok(resources)
+    }
+
+    /// Applies the resources of the containers that changed. Whether the new
+    /// memory limits fit is checked before anything is changed, so that an
+    /// infeasible resize leaves the containers as they were.
+    async fn apply(
+        &self,
+        provider_state: &ProviderState,
+        pod: &Pod,
+        resources: &BTreeMap<String, ContainerResources>,
+    ) -> Result<(), ResizeError> {
+        let changed: Vec<(&String, &ContainerResources)> = resources
+            .iter()
+            .filter(|(name, resources)| self.applied.get(*name) != Some(resources))
+            .collect();
+        let exec_targets = provider_state.exec_targets.read().await;
+        let running = exec_targets.get(&PodKey::from(pod));
+        let memory_limit = |name: &str| {
+            running
+                .and_then(|containers| containers.get(name))
+                .map(|target| target.runtime.memory_limit().clone())
+        };
+        for (name, resources) in &changed {
+            if let (Some(limit), Some(new_limit)) = (memory_limit(name), resources.memory_limit) {
+                if limit.used_bytes() > new_limit {
+                    return Err(ResizeError::Infeasible(format!(
+                        "container {} uses {} bytes of memory, more than its new limit of {} bytes",
+                        name,
+                        limit.used_bytes(),
+                        new_limit
+                    )));
+                }
+            }
+        }
+        let num_cpus = exclusive_cpus(pod).map_err(|e| ResizeError::Infeasible(e.to_string()))?;
+        provider_state
+            .cpu_manager
+            .resize(pod.pod_uid(), num_cpus)
+            .map_err(|e| ResizeError::Deferred(e.to_string()))?;
+        for (name, resources) in changed {
+            if let Some(limit) = memory_limit(name) {
+                limit
+                    .resize(resources.memory_limit)
+                    .map_err(|e| ResizeError::Infeasible(e.to_string()))?;
+            }
+            provider_state.cpu_scheduler.set_limit(
+                &format!("{}:{}:{}", pod.namespace(), pod.name(), name),
+                resources.cpu_limit_millis,
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! The user and group that a module accesses files as
+//!
+//! Containers select them with the `runAsUser` and `runAsGroup` fields of the
+//! container or pod security context. Modules run on threads of the provider
+//! process, so rather than changing the user of the whole process the thread
+//! running the module switches its filesystem user and group (see
+//! `setfsuid(2)`). Filesystem permission checks then use these IDs, and files
+//! the module creates are owned by them.
+//!
+//! Modules of pods isolated in a worker process (see [`crate::isolate`]) run
+//! in a process of their own, which switches its real user and group to these
+//! IDs and drops its capabilities instead.
+
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use serde_derive::{Deserialize, Serialize};
+
+/// The user and group IDs a module accesses files as. IDs that are not set
+/// are left as the provider's own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunAs {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+/// Returns the user and group a container's module should access files as.
+///
+/// This returns an error if the container runs as root without allowing
+/// privilege escalation, as a module running as root can access every file on
+/// the node that is mounted into it, or if it runs as root despite
+/// `runAsNonRoot`. Containers that don't set a user run as the provider's.
+pub(crate) fn container_run_as(pod: &Pod, container: &Container) -> anyhow::Result<RunAs> {
+    let security_context = container.security_context();
+    let pod_security_context = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.security_context.as_ref());
+    let run_as_user = security_context
+        .and_then(|s| s.run_as_user)
+        .or_else(|| pod_security_context.and_then(|s| s.run_as_user));
+    let run_as_group = security_context
+        .and_then(|s| s.run_as_group)
+        .or_else(|| pod_security_context.and_then(|s| s.run_as_group));
+    let run_as_non_root = security_context
+        .and_then(|s| s.run_as_non_root)
+        .or_else(|| pod_security_context.and_then(|s| s.run_as_non_root))
+        .unwrap_or(false);
+
+    if run_as_non_root && run_as_user.unwrap_or_else(provider_uid) == 0 {
+        return Err(anyhow::anyhow!(
+            "runAsNonRoot is true but the container would run as root (uid 0)"
+        ));
+    }
+    let allow_privilege_escalation = security_context
+        .and_then(|s| s.allow_privilege_escalation)
+        .unwrap_or(false);
+    if run_as_user == Some(0) && !allow_privilege_escalation {
+        return Err(anyhow::anyhow!(
+            "runAsUser 0 (root) requires allowPrivilegeEscalation to be true"
+        ));
+    }
+
+    let run_as = RunAs {
+        uid: run_as_user.map(|id| to_id(id, "runAsUser")).transpose()?,
+        gid: run_as_group.map(|id| to_id(id, "runAsGroup")).transpose()?,
+    };
+    if !cfg!(target_os = "linux") && run_as != RunAs::default() {
+        return Err(anyhow::anyhow!(
+            "runAsUser and runAsGroup are only supported on Linux"
+        ));
+    }
+    Ok(run_as)
+}
+
+/// The user the provider runs as
+fn provider_uid() -> i64 {
+    unsafe { libc::geteuid() }.into()
+}
+
+fn to_id(id: i64, field: &str) -> anyhow::Result<u32> {
+    use std::convert::TryFrom;
+    u32::try_from(id).map_err(|_| anyhow::anyhow!("{} {} is not a valid ID", field, id))
+}
+
+impl RunAs {
+    /// Switches the filesystem user and group of the current thread to these
+    /// IDs. The returned guard switches them back when it is dropped.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(&self) -> anyhow::Result<FsIdentityGuard> {
+        let mut guard = FsIdentityGuard {
+            uid: None,
+            gid: None,
+        };
+        // The group is switched first, as switching the user away from root
+        // drops the capability to switch the group
+        if let Some(gid) = self.gid {
+            // setfsgid always returns the previous ID, so calling it again
+            // tells us whether the first call took effect
+            guard.gid = Some(unsafe { libc::setfsgid(gid) } as u32);
+            if unsafe { libc::setfsgid(gid) } as u32 != gid {
+                return Err(anyhow::anyhow!(
+                    "unable to access files as group {}: the provider needs the CAP_SETGID capability",
+                    gid
+                ));
+            }
+        }
+        if let Some(uid) = self.uid {
+            guard.uid = Some(unsafe { libc::setfsuid(uid) } as u32);
+            if unsafe { libc::setfsuid(uid) } as u32 != uid {
+                return Err(anyhow::anyhow!(
+                    "unable to access files as user {}: the provider needs the CAP_SETUID capability",
+                    uid
+                ));
+            }
+        }
+        Ok(guard)
+    }
+
+    /// IDs are only set on Linux, so there is nothing to switch
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(&self) -> anyhow::Result<FsIdentityGuard> {
+        Ok(FsIdentityGuard {})
+    }
+}
+
+impl RunAs {
+    /// Switches the user and group of the whole process to these IDs for
+    /// good, and drops every capability the process has left, so that
+    /// nothing it runs can regain the provider's privileges. Supplementary
+    /// groups are cleared when switching away from root. This is only used
+    /// by worker processes, before they load the module.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn drop_privileges(&self) -> anyhow::Result<()> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(anyhow::anyhow!(
+                "unable to set no_new_privs: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        if *self != RunAs::default()
+            && unsafe { libc::geteuid() } == 0
+            && unsafe { libc::setgroups(0, std::ptr::null()) } != 0
+        {
+            return Err(anyhow::anyhow!(
+                "unable to clear supplementary groups: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        // The group is switched first, as switching the user away from root
+        // drops the capability to switch the group
+        if let Some(gid) = self.gid {
+            if unsafe { libc::setgid(gid) } != 0 {
+                return Err(anyhow::anyhow!(
+                    "unable to run as group {}: {}",
+                    gid,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(anyhow::anyhow!(
+                    "unable to run as user {}: {}",
+                    uid,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        drop_capabilities()
+    }
+
+    /// IDs are only set on Linux, so there is nothing to switch
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn drop_privileges(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Clears the effective, permitted and inheritable capabilities of the
+/// current process (see `capset(2)`)
+#[cfg(target_os = "linux")]
+fn drop_capabilities() -> anyhow::Result<()> {
+    /// `_LINUX_CAPABILITY_VERSION_3`, which has two sets of data
+    const CAPABILITY_VERSION: u32 = 0x2008_0522;
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+    let header = Header {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+    };
+    let data = [Data {
+        effective: 0,
+        permitted: 0,
+        inheritable: 0,
+    }; 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(anyhow::anyhow!(
+            "unable to drop capabilities: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Restores the filesystem user and group of the current thread when dropped
+pub(crate) struct FsIdentityGuard {
+    #[cfg(target_os = "linux")]
+    uid: Option<u32>,
+    #[cfg(target_os = "linux")]
+    gid: Option<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for FsIdentityGuard {
+    fn drop(&mut self) {
+        // The user is restored first to regain the capability to switch the
+        // group
+        if let Some(uid) = self.uid {
+            unsafe { libc::setfsuid(uid) };
+        }
+        if let Some(gid) = self.gid {
+            unsafe { libc::setfsgid(gid) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_as(
+        pod_security_context: serde_json::Value,
+        security_context: serde_json::Value,
+    ) -> anyhow::Result<RunAs> {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "running" },
+            "spec": {
+                "securityContext": pod_security_context,
+                "containers": [{
+                    "name": "app",
+                    "image": "app:v1",
+                    "securityContext": security_context,
+                }],
+            },
+        }))
+        .unwrap();
+        container_run_as(&pod, &pod.containers()[0])
+    }
+
+    #[test]
+    fn root_is_rejected_with_run_as_non_root() {
+        let err = run_as(
+            serde_json::json!({ "runAsNonRoot": true }),
+            serde_json::json!({ "runAsUser": 0, "allowPrivilegeEscalation": true }),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "runAsNonRoot is true but the container would run as root (uid 0)"
+        );
+        // The container can turn it off for itself
+        assert!(run_as(
+            serde_json::json!({ "runAsNonRoot": true }),
+            serde_json::json!({
+                "runAsNonRoot": false,
+                "runAsUser": 0,
+                "allowPrivilegeEscalation": true,
+            }),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn root_needs_privilege_escalation() {
+        let err = run_as(serde_json::json!({ "runAsUser": 0 }), serde_json::json!({})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "runAsUser 0 (root) requires allowPrivilegeEscalation to be true"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn containers_fall_back_to_the_pod_security_context() {
+        let pod_security_context = serde_json::json!({ "runAsUser": 1000, "runAsGroup": 2000 });
+        assert_eq!(
+            run_as(pod_security_context.clone(), serde_json::json!({})).unwrap(),
+            RunAs {
+                uid: Some(1000),
+                gid: Some(2000),
+            }
+        );
+        assert_eq!(
+            run_as(
+                pod_security_context,
+                serde_json::json!({ "runAsUser": 1001 })
+            )
+            .unwrap(),
+            RunAs {
+                uid: Some(1001),
+                gid: Some(2000),
+            }
+        );
+        assert_eq!(
+            run_as(serde_json::json!({}), serde_json::json!({})).unwrap(),
+            RunAs::default()
+        );
+    }
+
+    #[test]
+    fn ids_out_of_range_are_rejected() {
+        let err = run_as(
+            serde_json::json!({ "runAsUser": -1 }),
+            serde_json::json!({}),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "runAsUser -1 is not a valid ID");
+        let err = run_as(
+            serde_json::json!({}),
+            serde_json::json!({ "runAsGroup": 4_294_967_296_i64 }),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "runAsGroup 4294967296 is not a valid ID");
+    }
+}
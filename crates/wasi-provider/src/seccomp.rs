@@ -0,0 +1,269 @@
+//! Restriction of the WASI functions available to a module using seccomp
+//! profiles
+//!
+//! Pods select a profile with the `seccomp.security.alpha.kubernetes.io/pod`
+//! or `container.seccomp.security.alpha.kubernetes.io/<container>`
+//! annotation. WASI modules cannot make system calls directly, so the names in
+//! the `syscalls` rules of a `localhost/<path>` profile are matched against
+//! the names of the WASI functions a module imports, such as `fd_write` or
+//! `path_open`. Calls to functions the profile does not allow trap.
+
+use std::collections::HashMap;
+use std::path::{Component, Path};
+
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use serde_derive::{Deserialize, Serialize};
+
+/// The directory under the kubelet data directory that `localhost/` profiles
+/// are loaded from
+pub(crate) const SECCOMP_PROFILE_DIR: &str = "seccomp";
+
+const UNCONFINED: &str = "unconfined";
+const RUNTIME_DEFAULT_PROFILES: &[&str] = &["runtime/default", "docker/default"];
+const LOCALHOST_PREFIX: &str = "localhost/";
+
+/// The WASI functions a module is allowed to call
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WasiPolicy {
+    default_allow: bool,
+    rules: HashMap<String, bool>,
+}
+
+impl WasiPolicy {
+    /// A policy that allows every WASI function. This is used for
+    /// unconfined containers and for the runtime default profile, as the WASI
+    /// capability model already confines modules to the directories and
+    /// resources granted to them
+    pub fn allow_all() -> Self {
+        WasiPolicy {
+            default_allow: true,
+            rules: HashMap::new(),
+        }
+    }
+
+    /// Parses a seccomp profile in the JSON format used by container runtimes
+    pub fn from_profile(profile: &[u8]) -> anyhow::Result<Self> {
+        let profile: SeccompProfile = serde_json::from_slice(profile)
+            .map_err(|e| anyhow::anyhow!("unable to parse seccomp profile: {}", e))?;
+        let mut rules = HashMap::new();
+        for rule in profile.syscalls {
+            let allow = action_allows(&rule.action)?;
+            for name in rule.names.into_iter().chain(rule.name) {
+                rules.insert(name, allow);
+            }
+        }
+        Ok(WasiPolicy {
+            default_allow: action_allows(&profile.default_action)?,
+            rules,
+        })
+    }
+
+    /// Returns whether the module may call the WASI function with the given
+    /// name
+    pub fn allows(&self, function: &str) -> bool {
+        self.rules
+            .get(function)
+            .copied()
+            .unwrap_or(self.default_allow)
+    }
+}
+
+/// Returns the WASI policy for a container, loading `localhost/` profiles
+/// from `profile_dir`
+pub(crate) fn container_policy(
+    pod: &Pod,
+    container: &Container,
+    profile_dir: &Path,
+) -> anyhow::Result<WasiPolicy> {
+    let profile = match kubelet::pod::security::seccomp_profile(pod, container) {
+        None | Some(UNCONFINED) => return Ok(WasiPolicy::allow_all()),
+        Some(profile) if RUNTIME_DEFAULT_PROFILES.contains(&profile) => {
+            return Ok(WasiPolicy::allow_all())
+        }
+        Some(profile) => profile,
+    };
+    let path = match profile.strip_prefix(LOCALHOST_PREFIX) {
+        Some(path) => Path::new(path),
+        None => return Err(anyhow::anyhow!("unknown seccomp profile {}", profile)),
+    };
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow::anyhow!(
+            "seccomp profile {} must be a relative path without '..'",
+            profile
+        ));
+    }
+    let profile_path = profile_dir.join(path);
+    let profile = std::fs::read(&profile_path).map_err(|e| {
+        anyhow::anyhow!(
+            "unable to read seccomp profile {}: {}",
+            profile_path.display(),
+            e
+        )
+    })?;
+    WasiPolicy::from_profile(&profile)
+}
+
+/// Returns whether a seccomp action lets the call go ahead
+fn action_allows(action: &str) -> anyhow::Result<bool> {
+    match action {
+        "SCMP_ACT_ALLOW" | "SCMP_ACT_LOG" => Ok(true),
+        "SCMP_ACT_ERRNO"
+        | "SCMP_ACT_KILL"
+        | "SCMP_ACT_KILL_PROCESS"
+        | "SCMP_ACT_KILL_THREAD"
+        | "SCMP_ACT_TRAP"
+        | "SCMP_ACT_TRACE" => Ok(false),
+        other => Err(anyhow::anyhow!("unknown seccomp action {}", other)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SeccompProfile {
+    default_action: String,
+    #[serde(default)]
+    syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Deserialize)]
+struct SyscallRule {
+    #[serde(default)]
+    names: Vec<String>,
+    /// Older profiles name a single syscall per rule
+    #[serde(default)]
+    name: Option<String>,
+    action: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::pod;
+
+    fn policy(annotations: serde_json::Value, profile_dir: &Path) -> anyhow::Result<WasiPolicy> {
+        let pod = pod(annotations);
+        let container = &pod.containers()[0];
+        container_policy(&pod, container, profile_dir)
+    }
+
+    #[test]
+    fn unconfined_and_runtime_default_profiles_allow_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        for profile in &["unconfined", "runtime/default", "docker/default"] {
+            let annotations =
+                serde_json::json!({ "seccomp.security.alpha.kubernetes.io/pod": profile });
+            assert_eq!(
+                policy(annotations, dir.path()).unwrap(),
+                WasiPolicy::allow_all(),
+                "{}",
+                profile
+            );
+        }
+        assert_eq!(
+            policy(serde_json::json!({}), dir.path()).unwrap(),
+            WasiPolicy::allow_all()
+        );
+        assert!(policy(
+            serde_json::json!({ "seccomp.security.alpha.kubernetes.io/pod": "runtime/strict" }),
+            dir.path()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn localhost_profiles_are_loaded_from_the_profile_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("profiles")).unwrap();
+        std::fs::write(
+            dir.path().join("profiles/no-files.json"),
+            r#"{
+                "defaultAction": "SCMP_ACT_ALLOW",
+                "syscalls": [{ "names": ["path_open", "fd_write"], "action": "SCMP_ACT_ERRNO" }]
+            }"#,
+        )
+        .unwrap();
+        // The container's own profile is used ahead of the pod's
+        let annotations = serde_json::json!({
+            "seccomp.security.alpha.kubernetes.io/pod": "unconfined",
+            "container.seccomp.security.alpha.kubernetes.io/app": "localhost/profiles/no-files.json",
+        });
+        let policy = policy(annotations, dir.path()).unwrap();
+        assert!(!policy.allows("path_open"));
+        assert!(!policy.allows("fd_write"));
+        assert!(policy.allows("fd_read"));
+    }
+
+    #[test]
+    fn localhost_profiles_cannot_leave_the_profile_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        for profile in &["localhost/../secret.json", "localhost//etc/profile.json"] {
+            let annotations =
+                serde_json::json!({ "seccomp.security.alpha.kubernetes.io/pod": profile });
+            let err = policy(annotations, dir.path()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "seccomp profile {} must be a relative path without '..'",
+                    profile
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn actions_allow_or_deny_calls() {
+        for (action, allows) in &[
+            ("SCMP_ACT_ALLOW", true),
+            ("SCMP_ACT_LOG", true),
+            ("SCMP_ACT_ERRNO", false),
+            ("SCMP_ACT_KILL", false),
+            ("SCMP_ACT_KILL_PROCESS", false),
+            ("SCMP_ACT_KILL_THREAD", false),
+            ("SCMP_ACT_TRAP", false),
+            ("SCMP_ACT_TRACE", false),
+        ] {
+            let profile = format!(
+                r#"{{
+                    "defaultAction": "SCMP_ACT_ALLOW",
+                    "syscalls": [{{ "name": "sock_recv", "action": "{}" }}]
+                }}"#,
+                action
+            );
+            let policy = WasiPolicy::from_profile(profile.as_bytes()).unwrap();
+            assert_eq!(policy.allows("sock_recv"), *allows, "{}", action);
+        }
+        let err =
+            WasiPolicy::from_profile(br#"{ "defaultAction": "SCMP_ACT_NOTIFY" }"#).unwrap_err();
+        assert_eq!(err.to_string(), "unknown seccomp action SCMP_ACT_NOTIFY");
+        assert!(WasiPolicy::from_profile(b"not a profile").is_err());
+    }
+
+    #[test]
+    fn denied_calls_trap_naming_the_function() {
+        let store = wasmtime::Store::default();
+        let ty =
+            wasmtime::FuncType::new(vec![wasmtime::ValType::I32], vec![wasmtime::ValType::I32]);
+        let func = crate::wasi_runtime::denied_function(
+            &store,
+            "wasi_snapshot_preview1",
+            "path_open",
+            ty.into(),
+        )
+        .unwrap()
+        .into_func()
+        .unwrap();
+        let err = func.call(&[wasmtime::Val::I32(3)]).unwrap_err();
+        let trap = err.downcast_ref::<wasmtime::Trap>().unwrap();
+        assert!(
+            trap.to_string().contains(
+                "call to WASI function `wasi_snapshot_preview1::path_open` is not allowed by the seccomp profile"
+            ),
+            "{}",
+            trap
+        );
+    }
+}
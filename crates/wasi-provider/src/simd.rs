@@ -0,0 +1,107 @@
+//! WebAssembly SIMD support
+//!
+//! wasmtime compiles the 128-bit SIMD proposal to the host's vector
+//! instructions, which on x86_64 needs SSE4.2 and AVX2. Modules are only
+//! compiled with SIMD enabled when their pod opts in with the
+//! `krustlet.dev/wasm-simd` annotation: with `require`, the pod fails on
+//! hosts without SIMD support, and with `prefer`, SIMD is enabled only where
+//! it is supported. The node is labelled with whether it supports SIMD, so
+//! that pods requiring it can be scheduled on nodes that do.
+
+use kubelet::pod::Pod;
+
+/// Pod annotation used to enable SIMD for the pod's modules
+pub(crate) const WASM_SIMD_ANNOTATION: &str = "krustlet.dev/wasm-simd";
+/// Node label saying whether the host supports SIMD
+pub(crate) const WASM_SIMD_LABEL: &str = "krustlet.dev/wasm-simd";
+
+/// How a pod asks for SIMD
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SimdPreference {
+    /// The pod can only run with SIMD
+    Require,
+    /// The pod uses SIMD if the host supports it
+    Prefer,
+}
+
+impl std::str::FromStr for SimdPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(preference: &str) -> Result<Self, Self::Err> {
+        match preference.to_lowercase().as_str() {
+            "require" => Ok(SimdPreference::Require),
+            "prefer" => Ok(SimdPreference::Prefer),
+            other => Err(anyhow::anyhow!(
+                "unknown wasm-simd preference {}. Supported preferences: require, prefer",
+                other
+            )),
+        }
+    }
+}
+
+/// Returns whether the host has the instructions wasmtime compiles SIMD to
+pub(crate) fn host_supports_simd() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("sse4.2") && is_x86_feature_detected!("avx2")
+    }
+    // NEON is part of every aarch64 CPU
+    #[cfg(target_arch = "aarch64")]
+    {
+        true
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Returns whether the pod's modules are compiled with SIMD enabled.
+///
+/// This returns an error if the pod's annotation is unknown, or requires
+/// SIMD on a host that doesn't support it.
+pub(crate) fn pod_uses_simd(pod: &Pod) -> anyhow::Result<bool> {
+    let preference = match pod.get_annotation(WASM_SIMD_ANNOTATION) {
+        Some(preference) => Some(preference.parse()?),
+        None => None,
+    };
+    uses_simd(preference, host_supports_simd())
+}
+
+fn uses_simd(preference: Option<SimdPreference>, host_supports_simd: bool) -> anyhow::Result<bool> {
+    match preference {
+        Some(SimdPreference::Require) if !host_supports_simd => Err(anyhow::anyhow!(
+            "Pod requires WebAssembly SIMD via the {} annotation, but this node's CPU doesn't support it",
+            WASM_SIMD_ANNOTATION
+        )),
+        Some(_) => Ok(host_supports_simd),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simd_is_only_enabled_for_pods_that_opt_in() {
+        assert!(!uses_simd(None, true).unwrap());
+        assert!(uses_simd(Some(SimdPreference::Prefer), true).unwrap());
+        assert!(!uses_simd(Some(SimdPreference::Prefer), false).unwrap());
+        assert!(uses_simd(Some(SimdPreference::Require), true).unwrap());
+        assert!(uses_simd(Some(SimdPreference::Require), false).is_err());
+    }
+
+    #[test]
+    fn preferences_are_parsed() {
+        assert_eq!(
+            "Require".parse::<SimdPreference>().unwrap(),
+            SimdPreference::Require
+        );
+        assert_eq!(
+            "prefer".parse::<SimdPreference>().unwrap(),
+            SimdPreference::Prefer
+        );
+        assert!("always".parse::<SimdPreference>().is_err());
+    }
+}
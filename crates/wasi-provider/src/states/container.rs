@@ -1,9 +1,11 @@
 use crate::ModuleRunContext;
 use crate::ProviderState;
 use krator::{ObjectState, SharedState};
-use kubelet::container::{Container, ContainerKey, Status};
+use kubelet::container::{Container, ContainerKey, ContainerStateInfo, Status};
 use kubelet::pod::Pod;
 
+pub(crate) mod checkpoint;
+pub(crate) mod restore;
 pub(crate) mod running;
 pub(crate) mod terminated;
 pub(crate) mod waiting;
@@ -12,6 +14,9 @@ pub(crate) struct ContainerState {
     pod: Pod,
     container_key: ContainerKey,
     run_context: SharedState<ModuleRunContext>,
+    /// When the module started and, once it has terminated, when it
+    /// finished and what it exited with
+    state_info: ContainerStateInfo,
 }
 
 impl ContainerState {
@@ -24,6 +29,7 @@ impl ContainerState {
             pod,
             container_key,
             run_context,
+            state_info: ContainerStateInfo::default(),
         }
     }
 }
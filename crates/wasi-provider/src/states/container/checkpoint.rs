@@ -0,0 +1,42 @@
+use super::running::{recv_terminated, terminated};
+use super::terminated::Terminated;
+use super::ContainerState;
+use crate::checkpoint::CHECKPOINTING;
+use crate::ProviderState;
+use kubelet::container::state::prelude::*;
+use tokio::sync::mpsc::Receiver;
+
+/// The container was asked to stop and is writing a checkpoint that it can
+/// be resumed from.
+#[derive(Debug, TransitionTo)]
+#[transition_to(Terminated)]
+pub struct Checkpoint {
+    rx: Receiver<Status>,
+}
+
+impl Checkpoint {
+    pub fn new(rx: Receiver<Status>) -> Self {
+        Checkpoint { rx }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<ContainerState> for Checkpoint {
+    async fn next(
+        mut self: Box<Self>,
+        _shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        _container: Manifest<Container>,
+    ) -> Transition<ContainerState> {
+        let status = recv_terminated(&mut self.rx).await;
+        terminated(self, state, status)
+    }
+
+    async fn status(
+        &self,
+        _state: &mut ContainerState,
+        _container: &Container,
+    ) -> anyhow::Result<Status> {
+        Ok(Status::waiting(CHECKPOINTING))
+    }
+}
@@ -0,0 +1,110 @@
+use super::running::Running;
+use super::terminated::Terminated;
+use super::waiting::{start, Loaded};
+use super::ContainerState;
+use crate::checkpoint::Snapshot;
+use crate::ProviderState;
+use kubelet::container::state::prelude::*;
+use tracing::info;
+
+/// The container's module is being restored from the checkpoint it wrote
+/// when it was last stopped.
+#[derive(TransitionTo)]
+#[transition_to(Running, Terminated)]
+pub struct Restore {
+    /// The module to restore, until it is started
+    loaded: Option<Loaded>,
+}
+
+impl Restore {
+    pub(super) fn new(loaded: Loaded) -> Self {
+        Restore {
+            loaded: Some(loaded),
+        }
+    }
+}
+
+impl std::fmt::Debug for Restore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Restore").finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl State<ContainerState> for Restore {
+    async fn next(
+        mut self: Box<Self>,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
+    ) -> Transition<ContainerState> {
+        let container = container.latest();
+        let loaded = match self.loaded.take() {
+            Some(loaded) => loaded,
+            None => {
+                return Transition::next(
+                    self,
+                    Terminated::new("Module was already restored".to_owned(), true),
+                )
+            }
+        };
+        let path = loaded
+            .checkpoint_path()
+            .map(|path| path.to_owned())
+            .unwrap_or_default();
+        info!(
+            "Restoring container {} for pod {} from checkpoint {}",
+            container.name(),
+            state.pod.name(),
+            path.display()
+        );
+        let snapshot = match tokio::task::spawn_blocking(move || Snapshot::read_from(&path)).await {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(e)) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to read checkpoint: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to read checkpoint: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        start(
+            self,
+            shared_state,
+            state,
+            &container,
+            loaded,
+            Some(snapshot),
+        )
+        .await
+    }
+
+    async fn status(
+        &self,
+        _state: &mut ContainerState,
+        _container: &Container,
+    ) -> anyhow::Result<Status> {
+        Ok(Status::waiting("Restoring module from checkpoint."))
+    }
+}
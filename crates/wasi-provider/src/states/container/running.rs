@@ -1,20 +1,87 @@
+use super::checkpoint::Checkpoint;
 use super::terminated::Terminated;
 use super::ContainerState;
+use crate::checkpoint::CHECKPOINTING;
+use crate::hooks::HookRunner;
 use crate::ProviderState;
+use kubelet::container::hook::Hook;
 use kubelet::container::state::prelude::*;
+use kubelet::pod::event::{record_event, EventType};
+use kubelet::pod::PodKey;
+use kubelet::state::common::GenericProviderState;
 use tokio::sync::mpsc::Receiver;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
-/// The container is starting.
+/// The reason a container is terminated with when its `postStart` hook fails
+const POST_START_HOOK_ERROR: &str = "PostStartHookError";
+/// The reason of the event recorded when a container's `postStart` hook fails
+const FAILED_POST_START_HOOK: &str = "FailedPostStartHook";
+
+/// The container is running. A container with a `postStart` hook is only
+/// considered running once its hook has completed.
 #[derive(Debug, TransitionTo)]
-#[transition_to(Terminated)]
+#[transition_to(Running, Checkpoint, Terminated)]
 pub struct Running {
     rx: Receiver<Status>,
+    /// Whether the container's `postStart` hook, if it has one, has
+    /// completed
+    started: bool,
 }
 
 impl Running {
     pub fn new(rx: Receiver<Status>) -> Self {
-        Running { rx }
+        Running { rx, started: false }
+    }
+}
+
+/// Runs the container's `postStart` hook, failing if it can't be run or
+/// fails
+async fn run_post_start_hook(
+    shared: &SharedState<ProviderState>,
+    state: &ContainerState,
+    container: &Container,
+    hook: anyhow::Result<Hook>,
+) -> anyhow::Result<()> {
+    let runner = {
+        let provider_state = shared.read().await;
+        HookRunner::new(&provider_state, &state.pod, container.name()).await
+    };
+    runner.run(&state.pod, container, hook?).await
+}
+
+/// Stops a container whose `postStart` hook failed, and records why
+async fn kill_after_failed_hook(
+    shared: &SharedState<ProviderState>,
+    state: &ContainerState,
+    message: &str,
+) {
+    let (client, handle) = {
+        let provider_state = shared.read().await;
+        let handles = provider_state.handles.read().await;
+        (
+            provider_state.client(),
+            handles.get(&PodKey::from(&state.pod)).cloned(),
+        )
+    };
+    if let Some(handle) = handle {
+        if let Err(e) = handle.stop_container(&state.container_key).await {
+            warn!("Unable to stop container after its hook failed: {:?}", e);
+        }
+    }
+    if let Err(e) = record_event(
+        &client,
+        &state.pod,
+        EventType::Warning,
+        FAILED_POST_START_HOOK,
+        message,
+    )
+    .await
+    {
+        warn!(
+            "Unable to record event for pod {}: {:?}",
+            state.pod.name(),
+            e
+        );
     }
 }
 
@@ -22,32 +89,126 @@ impl Running {
 impl State<ContainerState> for Running {
     async fn next(
         mut self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
-        _container: Manifest<Container>,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
     ) -> Transition<ContainerState> {
+        let container = container.latest();
+        let hook = container
+            .lifecycle()
+            .and_then(|lifecycle| lifecycle.post_start.clone());
+        if let (false, Some(hook)) = (self.started, hook) {
+            info!(
+                "Running postStart hook of container {} for pod {}",
+                container.name(),
+                state.pod.name()
+            );
+            // The container may exit before its hook completes
+            let hook = Hook::from_handler(&hook);
+            let result = tokio::select! {
+                result = run_post_start_hook(&shared_state, state, &container, hook) => result,
+                status = recv_terminated(&mut self.rx) => {
+                    return terminated(self, state, status);
+                }
+            };
+            if let Err(e) = result {
+                let message = format!(
+                    "PostStartHook failed for container {} of pod {}: {:?}",
+                    container.name(),
+                    state.pod.name(),
+                    e
+                );
+                kill_after_failed_hook(&shared_state, state, &message).await;
+                return Transition::next(
+                    self,
+                    Terminated::new(message, true)
+                        .with_reason(Some(POST_START_HOOK_ERROR.to_owned())),
+                );
+            }
+            // Transition to a running state of its own so that the container's
+            // status is reported as running. The closed channel left behind is
+            // never read from.
+            let (_, closed) = tokio::sync::mpsc::channel(1);
+            let running = Running {
+                rx: std::mem::replace(&mut self.rx, closed),
+                started: true,
+            };
+            return Transition::next(self, running);
+        }
+
         debug!("Awaiting container status updates");
         while let Some(status) = self.rx.recv().await {
             debug!("Got status update from WASI Runtime: {:?}", &status);
-            if let Status::Terminated {
-                failed, message, ..
-            } = status
-            {
-                return Transition::next(self, Terminated::new(message, failed));
+            match &status {
+                Status::Terminated { .. } => return terminated(self, state, Some(status)),
+                Status::Waiting { message, .. } if message == CHECKPOINTING => {
+                    let (_, closed) = tokio::sync::mpsc::channel(1);
+                    let rx = std::mem::replace(&mut self.rx, closed);
+                    return Transition::next(self, Checkpoint::new(rx));
+                }
+                _ => (),
             }
         }
-        warn!("WASI Runtime hung up channel.");
-        Transition::next(
-            self,
-            Terminated::new("WASI Runtime hung up channel.".to_string(), true),
-        )
+        terminated(self, state, None)
     }
 
     async fn status(
         &self,
-        _state: &mut ContainerState,
-        _container: &Container,
+        state: &mut ContainerState,
+        container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::running())
+        let has_hook = container
+            .lifecycle()
+            .is_some_and(|lifecycle| lifecycle.post_start.is_some());
+        if has_hook && !self.started {
+            return Ok(Status::waiting("Running postStart hook"));
+        }
+        Ok(Status::running().with_state_info(&state.state_info))
+    }
+}
+
+/// Waits for the runtime to report that the container terminated, returning
+/// `None` if it hung up first
+pub(super) async fn recv_terminated(rx: &mut Receiver<Status>) -> Option<Status> {
+    while let Some(status) = rx.recv().await {
+        debug!("Got status update from WASI Runtime: {:?}", &status);
+        if let Status::Terminated { .. } = status {
+            return Some(status);
+        }
+    }
+    None
+}
+
+/// Transitions to the terminated state, recording when and with what the
+/// module exited
+pub(super) fn terminated<S: State<ContainerState> + TransitionTo<Terminated>>(
+    running: Box<S>,
+    state: &mut ContainerState,
+    status: Option<Status>,
+) -> Transition<ContainerState> {
+    match status {
+        Some(Status::Terminated {
+            timestamp,
+            failed,
+            message,
+            reason,
+            exit_code,
+            ..
+        }) => {
+            state.state_info.finished_at = Some(timestamp);
+            state.state_info.exit_code = exit_code;
+            Transition::next(
+                running,
+                Terminated::new(message, failed).with_reason(reason),
+            )
+        }
+        _ => {
+            warn!("WASI Runtime hung up channel.");
+            state.state_info.finished_at = Some(chrono::Utc::now());
+            Transition::next(
+                running,
+                Terminated::new("WASI Runtime hung up channel.".to_string(), true),
+            )
+        }
     }
 }
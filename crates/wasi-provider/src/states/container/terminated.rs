@@ -1,4 +1,5 @@
 use kubelet::container::state::prelude::*;
+use kubelet::pod::PodKey;
 use tracing::error;
 
 use crate::ProviderState;
@@ -11,11 +12,22 @@ use super::ContainerState;
 pub struct Terminated {
     message: String,
     failed: bool,
+    reason: Option<String>,
 }
 
 impl Terminated {
     pub fn new(message: String, failed: bool) -> Self {
-        Terminated { message, failed }
+        Terminated {
+            message,
+            failed,
+            reason: None,
+        }
+    }
+
+    /// Sets a brief CamelCase reason for the termination, such as `OOMKilled`
+    pub fn with_reason(mut self, reason: Option<String>) -> Self {
+        self.reason = reason;
+        self
     }
 }
 
@@ -23,12 +35,21 @@ impl Terminated {
 impl State<ContainerState> for Terminated {
     async fn next(
         self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
+        shared_state: SharedState<ProviderState>,
         state: &mut ContainerState,
         container: Manifest<Container>,
     ) -> Transition<ContainerState> {
         let container = container.latest();
 
+        // Commands can only be run in running containers
+        {
+            let provider_state = shared_state.read().await;
+            let mut exec_targets = provider_state.exec_targets.write().await;
+            if let Some(containers) = exec_targets.get_mut(&PodKey::from(&state.pod)) {
+                containers.remove(container.name());
+            }
+        }
+
         if self.failed {
             error!(
                 "Pod {} container {} exited with error: {}",
@@ -44,9 +65,13 @@ impl State<ContainerState> for Terminated {
 
     async fn status(
         &self,
-        _state: &mut ContainerState,
+        state: &mut ContainerState,
         _container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::terminated(&self.message, self.failed))
+        let status = match &self.reason {
+            Some(reason) => Status::terminated_with_reason(&self.message, self.failed, reason),
+            None => Status::terminated(&self.message, self.failed),
+        };
+        Ok(status.with_state_info(&state.state_info))
     }
 }
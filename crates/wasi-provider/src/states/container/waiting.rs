@@ -1,56 +1,153 @@
-use std::collections::HashMap;
-use std::ops::Deref;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use kubelet::container::state::prelude::*;
+use kubelet::cpu_manager::exclusive_cpus;
+use kubelet::device_plugin_manager::ContainerAllocation;
+use kubelet::pod::event::{record_event, EventType};
 use kubelet::pod::{Handle as PodHandle, PodKey};
 use kubelet::state::common::GenericProviderState;
 use kubelet::volume::Ref;
 
+use crate::checkpoint::{self, Snapshot};
+use crate::cpu_limit::CpuScheduler;
+use crate::isolate;
+use crate::module_cache::LoadedModule;
+use crate::run_as;
+use crate::seccomp;
+use crate::simd;
+use crate::wasi_nn;
 use crate::wasi_runtime::WasiRuntime;
-use crate::ProviderState;
+use crate::{ExecTarget, ProviderState};
 
+use super::restore::Restore;
 use super::running::Running;
 use super::terminated::Terminated;
 use super::ContainerState;
 
-fn volume_path_map(
+/// The reason given when a container has started
+const STARTED: &str = "Started";
+/// The environment variable holding the ordinal of pods in a StatefulSet
+const POD_ORDINAL: &str = "POD_ORDINAL";
+
+/// Maps the host path of each of the container's volume mounts, which is
+/// inside the volume for mounts with a sub path, to where it's mounted in the
+/// guest, along with the host paths that the container can only read. Sub
+/// path expressions are expanded with the container's `env`.
+///
+/// Only directories can be preopened, so files mounted on their own are
+/// linked into directories in `files_dir`, one for each directory they are
+/// mounted in, which are mounted there instead. Like the mounts that other
+/// kubelets bind, a file mounted at `/etc/app.conf` is then the only file the
+/// module sees in `/etc`. Such a directory is read-only if all of its files
+/// are.
+async fn volume_path_map(
     container: &Container,
     volumes: &HashMap<String, Ref>,
-) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
-    if let Some(volume_mounts) = container.volume_mounts().as_ref() {
-        volume_mounts
-            .iter()
-            .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-                // Check the volume exists first
-                let vol = volumes.get(&vm.name).ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "no volume with the name of {} found for container {}",
+    env: &HashMap<String, String>,
+    files_dir: &Path,
+) -> anyhow::Result<(HashMap<PathBuf, Option<PathBuf>>, HashSet<PathBuf>)> {
+    let mut paths = HashMap::default();
+    let mut read_only = HashSet::new();
+    let mut files: BTreeMap<PathBuf, Vec<(&Ref, PathBuf, OsString, bool)>> = BTreeMap::new();
+    for vm in container.volume_mounts().iter().flatten() {
+        // Check the volume exists first
+        let vol = volumes.get(&vm.name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "no volume with the name of {} found for container {}",
+                vm.name,
+                container.name()
+            )
+        })?;
+        let host_path = vol.mount_path(vm, env).await?;
+        let guest_path = PathBuf::from(&vm.mount_path);
+        let is_read_only = vol.is_read_only(vm);
+        if tokio::fs::metadata(&host_path).await?.is_file() {
+            match (guest_path.parent(), guest_path.file_name()) {
+                (Some(parent), Some(name)) => files.entry(parent.to_owned()).or_default().push((
+                    vol,
+                    host_path,
+                    name.to_owned(),
+                    is_read_only,
+                )),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "file {} of volume mount {} can't be mounted at {}",
+                        host_path.display(),
                         vm.name,
-                        container.name()
-                    )
-                })?;
-                let mut guest_path = PathBuf::from(&vm.mount_path);
-                if let Some(sub_path) = &vm.sub_path {
-                    guest_path.push(sub_path);
+                        vm.mount_path
+                    ))
                 }
-                // We can safely assume that this should be valid UTF-8 because it would have
-                // been validated by the k8s API
-                Ok((vol.deref().clone(), Some(guest_path)))
-            })
-            .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
-    } else {
-        Ok(HashMap::default())
+            }
+        } else {
+            if is_read_only {
+                read_only.insert(host_path.clone());
+            }
+            paths.insert(host_path, Some(guest_path));
+        }
+    }
+
+    // The links of the container's last run may be to files that have since
+    // been updated
+    match tokio::fs::remove_dir_all(files_dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    for (index, (guest_dir, files)) in files.into_iter().enumerate() {
+        let dir = files_dir.join(index.to_string());
+        tokio::fs::create_dir_all(&dir).await?;
+        if files.iter().all(|(_, _, _, is_read_only)| *is_read_only) {
+            read_only.insert(dir.clone());
+        }
+        for (vol, host_path, name, _) in files {
+            vol.link_file(&host_path, &dir.join(name)).await?;
+        }
+        paths.insert(dir, Some(guest_dir));
+    }
+    Ok((paths, read_only))
+}
+
+/// Gives the container what the device plugins of its devices directed. The
+/// variables they set take precedence over the container's own. WASI has no
+/// device files, so only devices that are directories can be made available,
+/// and are preopened like mounts. Annotations are meant for container runtimes
+/// and have no meaning to wasmtime, so they are only logged.
+async fn add_devices(
+    devices: ContainerAllocation,
+    env: &mut HashMap<String, String>,
+    container_volumes: &mut HashMap<PathBuf, Option<PathBuf>>,
+) {
+    if !devices.annotations.is_empty() {
+        debug!(
+            "Ignoring runtime annotations {:?} from device plugins",
+            devices.annotations
+        );
+    }
+    env.extend(devices.env);
+    for mount in devices.mounts {
+        container_volumes.insert(mount.host_path, Some(mount.container_path));
+    }
+    for device in devices.devices {
+        match tokio::fs::metadata(&device.host_path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                container_volumes.insert(device.host_path, Some(device.container_path));
+            }
+            _ => warn!(
+                "Device {} is not a directory, so it can't be made available to the module",
+                device.host_path.display()
+            ),
+        }
     }
 }
 
 /// The container is starting.
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Running, Terminated)]
+#[transition_to(Restore, Running, Terminated)]
 pub struct Waiting;
 
 #[async_trait::async_trait]
@@ -69,12 +166,138 @@ impl State<ContainerState> for Waiting {
             state.pod.name(),
         );
 
-        let (client, log_path) = {
+        let (
+            client,
+            log_path,
+            sub_path_files_dir,
+            seccomp_profile_dir,
+            module_cache,
+            default_memory_limit,
+            cpu_scheduler,
+            log_max_size,
+            log_max_files,
+            device_plugin_manager,
+            cpu_manager,
+            memory_manager,
+            topology_manager,
+            huge_pages_manager,
+        ) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state
+                    .pod_log_dir(&PodKey::from(&state.pod))
+                    .join(format!("{}.log", container.name())),
+                kubelet::volume::sub_path_files_dir(
+                    &provider_state.volume_path(),
+                    &state.pod,
+                    container.name(),
+                ),
+                provider_state.seccomp_profile_dir.clone(),
+                provider_state.module_cache.clone(),
+                provider_state.default_container_memory_limit,
+                provider_state.cpu_scheduler.clone(),
+                provider_state.container_log_max_size,
+                provider_state.container_log_max_files,
+                provider_state.device_plugin_manager.clone(),
+                provider_state.cpu_manager.clone(),
+                provider_state.memory_manager.clone(),
+                provider_state.topology_manager.clone(),
+                provider_state.huge_pages_manager.clone(),
+            )
+        };
+
+        // The NUMA nodes are chosen for the whole pod, so containers started
+        // after the first one get the same ones
+        let affinity = match topology_manager.admit(&state.pod) {
+            Ok(affinity) => affinity,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} was not admitted: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        // CPUs are assigned to the whole pod, so containers started after the
+        // first one get the CPUs already assigned
+        let aligned_cpus = topology_manager.cpus(affinity);
+        let assigned = exclusive_cpus(&state.pod).and_then(|num_cpus| {
+            cpu_manager.assign_aligned_cpus(state.pod.pod_uid(), num_cpus, &aligned_cpus)
+        });
+        let numa_binding = match assigned {
+            Ok(cpus) => memory_manager.binding(&cpus),
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to assign CPUs: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        // Huge pages are also reserved for the whole pod
+        let huge_pages = match huge_pages_manager.reserve(&state.pod) {
+            Ok(huge_pages) => huge_pages,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to reserve huge pages: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let devices = match device_plugin_manager
+            .allocate(&state.pod, &container, affinity)
+            .await
+        {
+            Ok(devices) => devices,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to allocate devices: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
         };
 
-        let (module_data, container_volumes) = {
+        let mut env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
+        // Containers that set the variable themselves keep their value
+        if let Some(ordinal) = state.pod.stateful_set_ordinal() {
+            env.entry(POD_ORDINAL.to_owned())
+                .or_insert_with(|| ordinal.to_string());
+        }
+        let (module_data, mut container_volumes, read_only_volumes) = {
             let mut run_context = state.run_context.write().await;
             let module_data = match run_context.modules.remove(container.name()) {
                 Some(data) => data,
@@ -92,14 +315,59 @@ impl State<ContainerState> for Waiting {
                     );
                 }
             };
-            let container_volumes = match volume_path_map(&container, &run_context.volumes) {
-                Ok(volumes) => volumes,
+            let (mut container_volumes, mut read_only_volumes) =
+                match volume_path_map(&container, &run_context.volumes, &env, &sub_path_files_dir)
+                    .await
+                {
+                    Ok(volumes) => volumes,
+                    Err(e) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Pod {} container {} failed to map volume paths: {:?}",
+                                    state.pod.name(),
+                                    container.name(),
+                                    e
+                                ),
+                                true,
+                            )
+                            .with_reason(Some(kubelet::volume::CONFIG_ERROR.to_owned())),
+                        )
+                    }
+                };
+            // Models are preopened as their own directory so that every
+            // container can load them without mounting the volume itself
+            match wasi_nn::models_volume(&state.pod) {
+                Ok(Some(volume)) => match run_context.volumes.get(volume) {
+                    Some(vol) => {
+                        let host_dir = PathBuf::clone(vol);
+                        container_volumes
+                            .insert(host_dir.clone(), Some(PathBuf::from(wasi_nn::MODELS_DIR)));
+                        read_only_volumes.insert(host_dir);
+                    }
+                    None => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                "Pod {} container {} failed to find the wasi-nn models volume {}",
+                                state.pod.name(),
+                                container.name(),
+                                volume
+                            ),
+                                true,
+                            ),
+                        )
+                    }
+                },
+                Ok(None) => (),
                 Err(e) => {
                     return Transition::next(
                         self,
                         Terminated::new(
                             format!(
-                                "Pod {} container {} failed to map volume paths: {:?}",
+                                "Pod {} container {} failed to configure wasi-nn: {:?}",
                                 state.pod.name(),
                                 container.name(),
                                 e
@@ -108,12 +376,159 @@ impl State<ContainerState> for Waiting {
                         ),
                     )
                 }
-            };
-            (module_data, container_volumes)
+            }
+            (module_data, container_volumes, read_only_volumes)
         };
 
-        let env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
+        add_devices(devices, &mut env, &mut container_volumes).await;
         let args = container.args().clone().unwrap_or_default();
+        let wasi_nn_backend = match wasi_nn::requested_backend(&state.pod) {
+            Ok(backend) => backend,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to configure wasi-nn: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let wasi_policy =
+            match seccomp::container_policy(&state.pod, &container, &seccomp_profile_dir) {
+                Ok(policy) => policy,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Pod {} container {} failed to load seccomp profile: {:?}",
+                                state.pod.name(),
+                                container.name(),
+                                e
+                            ),
+                            true,
+                        ),
+                    )
+                }
+            };
+
+        let run_as = match run_as::container_run_as(&state.pod, &container) {
+            Ok(run_as) => run_as,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid security context: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        let isolation = match isolate::pod_isolation(&state.pod) {
+            Ok(isolation) => isolation,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid isolation: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        let simd = match simd::pod_uses_simd(&state.pod) {
+            Ok(simd) => simd,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} can't enable SIMD: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+        let checkpoint_path =
+            match checkpoint::checkpoint_path(&state.pod, &container_volumes, &read_only_volumes) {
+                Ok(path) => path,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Pod {} container {} has an invalid checkpoint path: {:?}",
+                                state.pod.name(),
+                                container.name(),
+                                e
+                            ),
+                            true,
+                        ),
+                    )
+                }
+            };
+        let read_only_root = container
+            .security_context()
+            .and_then(|c| c.read_only_root_filesystem)
+            .unwrap_or(false);
+        // Containers without a limit of their own get the node's default, if
+        // one is configured
+        let memory_limit = match container.memory_limit() {
+            Ok(limit) => limit.or(default_memory_limit),
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid memory limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
+        let cpu_limit = match container.cpu_limit_millis() {
+            Ok(limit) => limit,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid CPU limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
 
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
@@ -130,7 +545,22 @@ impl State<ContainerState> for Waiting {
             env,
             args,
             container_volumes,
+            read_only_volumes,
+            wasi_nn_backend,
+            wasi_policy,
+            read_only_root,
+            run_as,
+            memory_limit,
+            cpu_limit,
+            cpu_manager.pod(state.pod.pod_uid()),
+            numa_binding,
+            huge_pages,
+            isolation,
+            simd,
+            checkpoint_path,
             log_path,
+            log_max_size,
+            log_max_files,
             tx,
         )
         .await
@@ -151,15 +581,14 @@ impl State<ContainerState> for Waiting {
                 )
             }
         };
-        debug!("Starting container {} on thread", container.name());
-        let container_handle = match runtime.start().await {
-            Ok(handle) => handle,
+        let loaded = match runtime.load_module(module_cache).await {
+            Ok(loaded) => loaded,
             Err(e) => {
                 return Transition::next(
                     self,
                     Terminated::new(
                         format!(
-                            "Pod {} container {} failed to start: {:?}",
+                            "Pod {} container {} failed to load module: {:?}",
                             state.pod.name(),
                             container.name(),
                             e
@@ -169,19 +598,21 @@ impl State<ContainerState> for Waiting {
                 )
             }
         };
-        debug!("Container {} WASI Runtime started", container.name());
-        let pod_key = PodKey::from(&state.pod);
-        {
-            let provider_state = shared.write().await;
-            let mut handles_writer = provider_state.handles.write().await;
-            let pod_handle = handles_writer.entry(pod_key).or_insert_with(|| {
-                Arc::new(PodHandle::new(HashMap::new(), state.pod.clone(), None))
-            });
-            pod_handle
-                .insert_container_handle(state.container_key.clone(), container_handle)
-                .await;
+        let loaded = Loaded {
+            client,
+            runtime,
+            loaded,
+            cpu_scheduler,
+            rx,
+        };
+        // A module that wrote a checkpoint before it was last stopped resumes
+        // from it
+        if let Some(path) = loaded.checkpoint_path() {
+            if tokio::fs::metadata(path).await.is_ok() {
+                return Transition::next(self, Restore::new(loaded));
+            }
         }
-        Transition::next(self, Running::new(rx))
+        start(self, shared, state, &container, loaded, None).await
     }
 
     async fn status(
@@ -192,3 +623,110 @@ impl State<ContainerState> for Waiting {
         Ok(Status::waiting("Module is starting."))
     }
 }
+
+/// A container whose module has been loaded, and is ready to start
+pub(super) struct Loaded {
+    client: kube::Client,
+    runtime: WasiRuntime,
+    loaded: LoadedModule,
+    cpu_scheduler: Arc<CpuScheduler>,
+    rx: mpsc::Receiver<Status>,
+}
+
+impl Loaded {
+    /// The file the module's checkpoints are written to, if it checkpoints
+    pub(super) fn checkpoint_path(&self) -> Option<&Path> {
+        self.runtime.checkpoint_path()
+    }
+}
+
+/// Starts the container's module, resuming it from `restore` if given
+pub(super) async fn start<S>(
+    from: Box<S>,
+    shared: SharedState<ProviderState>,
+    state: &mut ContainerState,
+    container: &Container,
+    loaded: Loaded,
+    restore: Option<Snapshot>,
+) -> Transition<ContainerState>
+where
+    S: State<ContainerState> + TransitionTo<Running> + TransitionTo<Terminated>,
+{
+    let Loaded {
+        client,
+        runtime,
+        loaded,
+        cpu_scheduler,
+        rx,
+    } = loaded;
+    let load_description = loaded.describe();
+    debug!("Starting container {} on thread", container.name());
+    let started = runtime
+        .start(
+            loaded.module.clone(),
+            cpu_scheduler,
+            container.stdin().unwrap_or(false),
+            container.stdin_once().unwrap_or(false),
+            restore,
+        )
+        .await;
+    let (container_handle, attachment) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            return Transition::next(
+                from,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} failed to start: {:?}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            )
+        }
+    };
+    debug!("Container {} WASI Runtime started", container.name());
+    let started_at = chrono::Utc::now();
+    state.state_info.started_at = Some(started_at);
+    let message = format!(
+        "Started container {} ({})",
+        container.name(),
+        load_description
+    );
+    if let Err(e) = record_event(&client, &state.pod, EventType::Normal, STARTED, &message).await {
+        warn!(
+            "Unable to record event for pod {}: {:?}",
+            state.pod.name(),
+            e
+        );
+    }
+    let pod_key = PodKey::from(&state.pod);
+    {
+        let provider_state = shared.write().await;
+        let mut handles_writer = provider_state.handles.write().await;
+        let pod_handle = handles_writer
+            .entry(pod_key.clone())
+            .or_insert_with(|| Arc::new(PodHandle::new(HashMap::new(), state.pod.clone(), None)));
+        pod_handle
+            .insert_container_handle(state.container_key.clone(), container_handle)
+            .await;
+        provider_state
+            .exec_targets
+            .write()
+            .await
+            .entry(pod_key)
+            .or_default()
+            .insert(
+                container.name().to_owned(),
+                ExecTarget {
+                    runtime: Arc::new(runtime),
+                    module: loaded.module,
+                    attachment,
+                    started_at,
+                },
+            );
+    }
+    Transition::next(from, Running::new(rx))
+}
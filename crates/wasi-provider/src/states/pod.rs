@@ -8,18 +8,25 @@ use kubelet::pod::Pod;
 use kubelet::pod::PodKey;
 use kubelet::pod::Status;
 use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::warn;
 
 pub(crate) mod completed;
 pub(crate) mod initializing;
 pub(crate) mod running;
 pub(crate) mod starting;
+pub(crate) mod unsupported_interfaces;
 
 /// State that is shared between pod state handlers.
 pub struct PodState {
     key: PodKey,
+    /// The pod's UID, which the CPUs assigned to it are kept under
+    uid: String,
+    /// The pod's ordinal in its StatefulSet, if it is part of one
+    ordinal: Option<u32>,
     run_context: SharedState<ModuleRunContext>,
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
@@ -36,9 +43,42 @@ impl ObjectState for PodState {
             let mut handles = provider_state.handles.write().await;
             handles.remove(&self.key);
         }
+        provider_state.exec_targets.write().await.remove(&self.key);
+        provider_state.device_plugin_manager.release(&self.key);
+        provider_state.cpu_manager.release(&self.uid);
+        provider_state.topology_manager.release(&self.uid);
+        provider_state.huge_pages_manager.release(&self.uid);
+        provider_state.storage_tracker.release(&self.uid);
+        let log_dir = provider_state.pod_log_dir(&self.key);
+        match tokio::fs::remove_dir_all(&log_dir).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Unable to remove pod log directory {:?}: {:?}", log_dir, e),
+        }
+    }
+
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(PodStateSnapshot {
+            errors: self.errors,
+        })
+        .ok()
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: PodStateSnapshot = serde_json::from_value(snapshot)?;
+        self.errors = snapshot.errors;
+        Ok(())
     }
 }
 
+/// The fields of a pod's state that are saved to resume it when the kubelet
+/// restarts. The rest is either worked out from the pod again or only kept
+/// in memory.
+#[derive(Serialize, Deserialize)]
+struct PodStateSnapshot {
+    errors: usize,
+}
+
 impl PodState {
     pub fn new(pod: &Pod) -> Self {
         let run_context = ModuleRunContext {
@@ -48,12 +88,20 @@ impl PodState {
         let key = PodKey::from(pod);
         PodState {
             key,
+            uid: pod.pod_uid().to_owned(),
+            ordinal: pod.stateful_set_ordinal(),
             run_context: Arc::new(RwLock::new(run_context)),
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
         }
     }
+
+    /// The pod's ordinal in its StatefulSet, such as 1 for `web-1`, for pods
+    /// that partition their data by it
+    pub fn ordinal(&self) -> Option<u32> {
+        self.ordinal
+    }
 }
 
 #[async_trait]
@@ -1,8 +1,9 @@
 use crate::{PodState, ProviderState};
+use krator::Transitions;
 use kubelet::pod::state::prelude::*;
 
 /// Pod was deleted.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Transitions)]
 pub struct Completed;
 
 #[async_trait::async_trait]
@@ -11,8 +12,10 @@ impl State<PodState> for Completed {
         self: Box<Self>,
         _provider_state: SharedState<ProviderState>,
         _pod_state: &mut PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<PodState> {
+        // Pods that have completed no longer need their extended resources
+        kubelet::extended_resources::release(&pod.latest());
         Transition::Complete(Ok(()))
     }
 
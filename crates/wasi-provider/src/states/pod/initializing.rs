@@ -1,22 +1,98 @@
 use std::sync::Arc;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+use krator::Transitions;
 use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
+use kubelet::pod::event::{record_event, EventType};
 use kubelet::pod::state::prelude::*;
 use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
 
+use crate::component::{ComponentInterfaceChecker, MissingInterfaces};
 use crate::states::container::waiting::Waiting;
 use crate::states::container::ContainerState;
 use crate::{PodState, ProviderState};
 
 use super::starting::Starting;
+use super::unsupported_interfaces::{UnsupportedInterfaces, UNSUPPORTED_INTERFACES};
 
-#[derive(Default, Debug, TransitionTo)]
-#[transition_to(Starting, Error<crate::WasiProvider>)]
+/// The reason given when a container's module has been compiled
+const COMPILED: &str = "Compiled";
+
+/// Checks that the provider implements the interfaces imported by the
+/// components of the pod's containers, returning those it doesn't for the
+/// first container that imports any
+async fn check_interfaces(pod_state: &PodState) -> Result<(), MissingInterfaces> {
+    let checker = ComponentInterfaceChecker::default();
+    let run_context = pod_state.run_context.read().await;
+    for (container_name, module_data) in &run_context.modules {
+        checker.check(container_name, module_data)?;
+    }
+    Ok(())
+}
+
+/// Compiles the modules of a pod that has just pulled them, so that the
+/// compiled modules are in the cache when its containers start
+async fn precompile_modules(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Pod,
+    client: &kube::Client,
+) {
+    let module_cache = provider_state.read().await.module_cache.clone();
+    // Pods that can't enable SIMD fail when their containers start
+    let simd = match crate::simd::pod_uses_simd(pod) {
+        Ok(simd) => simd,
+        Err(_) => return,
+    };
+    let modules: Vec<(String, Vec<u8>)> = {
+        let run_context = pod_state.run_context.read().await;
+        run_context
+            .modules
+            .iter()
+            .map(|(name, data)| (name.clone(), data.clone()))
+            .collect()
+    };
+    for (container_name, module_data) in modules {
+        let cache = module_cache.clone();
+        // Modules in the text format are cached by the binary they compile to,
+        // as when their containers start
+        let loaded = tokio::task::spawn_blocking(move || {
+            cache.load(&crate::wasm_binary::module_binary(module_data)?, simd)
+        })
+        .await;
+        let message = match loaded {
+            Ok(Ok(loaded)) => format!(
+                "Prepared module for container {} ({})",
+                container_name,
+                loaded.describe()
+            ),
+            // The container reports the error when it fails to start
+            Ok(Err(e)) => {
+                warn!(
+                    "Unable to compile module for container {} of pod {}: {:?}",
+                    container_name,
+                    pod.name(),
+                    e
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!("Module compilation task failed: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = record_event(client, pod, EventType::Normal, COMPILED, &message).await {
+            warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+        }
+    }
+}
+
+#[derive(Default, Debug, TransitionTo, Transitions)]
+#[transition_to(Starting, Error<crate::WasiProvider>, UnsupportedInterfaces)]
 pub struct Initializing;
 
 #[async_trait::async_trait]
@@ -34,6 +110,23 @@ impl State<PodState> for Initializing {
             let provider_state = provider_state.read().await;
             provider_state.client()
         };
+        if let Err(missing) = check_interfaces(pod_state).await {
+            let message = missing.to_string();
+            error!("Pod {} can't run: {}", pod.name(), message);
+            if let Err(e) = record_event(
+                &client,
+                &pod,
+                EventType::Warning,
+                UNSUPPORTED_INTERFACES,
+                &message,
+            )
+            .await
+            {
+                warn!("Unable to record event for pod {}: {:?}", pod.name(), e);
+            }
+            return Transition::next(self, UnsupportedInterfaces::new(message));
+        }
+        precompile_modules(&provider_state, pod_state, &pod, &client).await;
 
         for init_container in pod.init_containers() {
             info!(
@@ -83,4 +176,10 @@ impl State<PodState> for Initializing {
     async fn status(&self, _pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
         Ok(make_status(Phase::Running, "Initializing"))
     }
+
+    // The modules are only kept in memory, and are gone once the kubelet
+    // restarts
+    fn is_resumable(&self) -> bool {
+        false
+    }
 }
@@ -1,23 +1,87 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use kube::Api;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
 
+use krator::Transitions;
+use kubelet::ephemeral_storage::StorageExceeded;
+use kubelet::feature_gate::FeatureGate;
+use kubelet::pod::resize::IN_PLACE_POD_VERTICAL_SCALING;
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{patch_status, unready_readiness_gate};
+use kubelet::state::common::ephemeral_storage_exceeded::EphemeralStorageExceeded;
 use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
 
 use super::completed::Completed;
 use crate::fail_fatal;
+use crate::resize::{PodResizer, DEFERRED_RESIZE_RETRY_INTERVAL};
 use crate::{PodState, ProviderState};
 
 /// The Kubelet is running the Pod.
-#[derive(Debug, TransitionTo)]
-#[transition_to(Completed, Error<crate::WasiProvider>)]
+#[derive(Debug, TransitionTo, Transitions)]
+#[transition_to(
+    Completed,
+    Error<crate::WasiProvider>,
+    EphemeralStorageExceeded<crate::WasiProvider>
+)]
 pub struct Running {
     rx: Receiver<anyhow::Result<()>>,
+    /// Receives what the pod used if it goes over its ephemeral storage
+    /// limit, for pods that have one
+    storage_exceeded: Option<oneshot::Receiver<StorageExceeded>>,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<anyhow::Result<()>>) -> Self {
-        Running { rx }
+    pub fn new(
+        rx: Receiver<anyhow::Result<()>>,
+        storage_exceeded: Option<oneshot::Receiver<StorageExceeded>>,
+    ) -> Self {
+        Running {
+            rx,
+            storage_exceeded,
+        }
+    }
+}
+
+/// Waits for the pod to go over its ephemeral storage limit, which it never
+/// does if it has no limit
+async fn storage_exceeded(
+    receiver: &mut Option<oneshot::Receiver<StorageExceeded>>,
+) -> StorageExceeded {
+    if let Some(exceeded) = receiver.as_mut() {
+        let result = exceeded.await;
+        // The receiver can only be waited on until it's answered
+        *receiver = None;
+        if let Ok(exceeded) = result {
+            return exceeded;
+        }
+    }
+    futures::future::pending().await
+}
+
+/// Waits for the pod to become ready or stop being ready because of the
+/// conditions of its readiness gates, checking them at the given interval,
+/// and returns its new status. Pods without readiness gates are always ready.
+async fn readiness_changed(
+    manifest: &Manifest<Pod>,
+    interval: Duration,
+    ready: &mut bool,
+) -> PodStatus {
+    if manifest.latest().readiness_gates().is_empty() {
+        futures::future::pending::<()>().await;
+    }
+    loop {
+        tokio::time::sleep(interval).await;
+        let pod = manifest.latest();
+        let now_ready = unready_readiness_gate(&pod).is_none();
+        if now_ready != *ready {
+            *ready = now_ready;
+            return make_running_status(&pod);
+        }
     }
 }
 
@@ -27,14 +91,76 @@ impl State<PodState> for Running {
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
         _pod_state: &mut PodState,
-        pod: Manifest<Pod>,
+        manifest: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod = pod.latest();
+        let pod = manifest.latest();
+        let (client, readiness_gate_poll_interval, default_memory_limit) = {
+            let provider = provider_state.read().await;
+            (
+                provider.client(),
+                provider.readiness_gate_poll_interval(),
+                provider.default_container_memory_limit,
+            )
+        };
+        let api = Api::namespaced(client.clone(), pod.namespace());
+        let mut ready = unready_readiness_gate(&pod).is_none();
+        // Changes to the containers' resources are applied as they run
+        let mut resizing = FeatureGate::is_enabled(IN_PLACE_POD_VERTICAL_SCALING);
+        let mut resizer = PodResizer::new(&pod, default_memory_limit);
+        let mut updates = manifest.clone();
 
         let mut completed = 0;
         let total_containers = pod.containers().len();
 
-        while let Some(result) = self.rx.recv().await {
+        loop {
+            let result = tokio::select! {
+                result = self.rx.recv() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                status = readiness_changed(&manifest, readiness_gate_poll_interval, &mut ready) => {
+                    debug!("Pod {} readiness changed, ready: {}", pod.name(), ready);
+                    patch_status(&api, pod.name(), status).await;
+                    continue;
+                }
+                update = updates.next(), if resizing => {
+                    match update {
+                        Some(latest) => {
+                            let provider = provider_state.read().await;
+                            resizer.resize(&provider, &client, &latest).await;
+                        }
+                        None => resizing = false,
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep(DEFERRED_RESIZE_RETRY_INTERVAL), if resizer.is_deferred() => {
+                    let provider = provider_state.read().await;
+                    resizer.resize(&provider, &client, &manifest.latest()).await;
+                    continue;
+                }
+                exceeded = storage_exceeded(&mut self.storage_exceeded) => {
+                    {
+                        let provider = provider_state.write().await;
+                        provider.stop(&pod).await.ok();
+                    }
+                    for dir in &exceeded.dirs {
+                        match tokio::fs::remove_dir_all(dir).await {
+                            Ok(()) => (),
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                            Err(e) => warn!(
+                                "Unable to remove {:?} of evicted pod {}: {:?}",
+                                dir,
+                                pod.name(),
+                                e
+                            ),
+                        }
+                    }
+                    return Transition::next(
+                        self,
+                        EphemeralStorageExceeded::new(exceeded.to_string()),
+                    );
+                }
+            };
             match result {
                 Ok(()) => {
                     completed += 1;
@@ -61,7 +187,13 @@ impl State<PodState> for Running {
         )
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Running"))
+    async fn status(&self, _pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_running_status(pod))
+    }
+
+    // The modules are only kept in memory, and are gone once the kubelet
+    // restarts
+    fn is_resumable(&self) -> bool {
+        false
     }
 }
@@ -1,10 +1,13 @@
 use std::sync::Arc;
 
-use tracing::info;
+use tracing::{info, warn};
 
+use krator::Transitions;
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
+use kubelet::ephemeral_storage::{self, PodStorage};
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::PodKey;
 use kubelet::state::common::GenericProviderState;
 
 use crate::states::container::waiting::Waiting;
@@ -13,7 +16,7 @@ use crate::{PodState, ProviderState};
 
 use super::running::Running;
 
-#[derive(Default, Debug, TransitionTo)]
+#[derive(Default, Debug, TransitionTo, Transitions)]
 #[transition_to(Running)]
 /// The Kubelet is starting the Pod containers
 pub(crate) struct Starting;
@@ -31,6 +34,43 @@ impl State<PodState> for Starting {
 
         info!("Starting containers for pod {:?}.", pod.name());
         let containers = pod.containers();
+        let limit = ephemeral_storage::pod_limit(&pod).unwrap_or_else(|e| {
+            warn!(
+                "Unable to read ephemeral storage limit of pod {}, so only its volumes are limited: {:?}",
+                pod.name(),
+                e
+            );
+            None
+        });
+        let storage_exceeded = {
+            let provider_state = provider_state.read().await;
+            let mut storage = PodStorage {
+                dirs: vec![provider_state.pod_log_dir(&PodKey::from(&pod))],
+                limit,
+                volume_limits: vec![],
+                volumes: vec![],
+            };
+            for (name, volume) in &pod_state.run_context.read().await.volumes {
+                if volume.is_ephemeral_storage() {
+                    storage.dirs.push(volume.to_path_buf());
+                    storage.volumes.push((name.clone(), volume.to_path_buf()));
+                    if let Some(size_limit) = volume.size_limit() {
+                        storage
+                            .volume_limits
+                            .push((volume.to_path_buf(), size_limit));
+                    }
+                }
+            }
+            // Pods without limits are tracked too, so that what they use is
+            // reported in their stats
+            let limited = storage.is_limited();
+            let exceeded = provider_state.storage_tracker.track(pod.pod_uid(), storage);
+            if limited {
+                Some(exceeded)
+            } else {
+                None
+            }
+        };
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
         for container in containers {
             let initial_state = Waiting;
@@ -62,10 +102,16 @@ impl State<PodState> for Starting {
             });
         }
         info!("All containers started for pod {:?}.", pod.name());
-        Transition::next(self, Running::new(rx))
+        Transition::next(self, Running::new(rx, storage_exceeded))
     }
 
     async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
         Ok(make_status(Phase::Pending, "Starting"))
     }
+
+    // The modules are only kept in memory, and are gone once the kubelet
+    // restarts
+    fn is_resumable(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,41 @@
+use crate::{PodState, ProviderState};
+use krator::Transitions;
+use kubelet::pod::state::prelude::*;
+
+/// The reason given when a container's component imports interfaces the
+/// provider doesn't implement
+pub(crate) const UNSUPPORTED_INTERFACES: &str = "UnsupportedInterfaces";
+
+/// A container's component imports interfaces the provider doesn't implement.
+#[derive(Debug, Transitions)]
+pub struct UnsupportedInterfaces {
+    message: String,
+}
+
+impl UnsupportedInterfaces {
+    pub(crate) fn new(message: String) -> Self {
+        UnsupportedInterfaces { message }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for UnsupportedInterfaces {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<ProviderState>,
+        _pod_state: &mut PodState,
+        pod: Manifest<Pod>,
+    ) -> Transition<PodState> {
+        // The pod's modules won't change, so there is nothing to retry
+        kubelet::extended_resources::release(&pod.latest());
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(UNSUPPORTED_INTERFACES)
+            .message(&self.message)
+            .build())
+    }
+}
@@ -0,0 +1,106 @@
+//! The resource usage of running pods, served in the node's summary
+//!
+//! The CPU time of each container is read from the CPU scheduler, which
+//! tracks the threads modules run on (see [`crate::cpu_limit`]), and is only
+//! known on Linux. Its memory is the linear memory its module has allocated
+//! (see [`crate::memory_limit`]), which never shrinks, so it is all counted
+//! as the container's working set. What a pod's container logs and emptyDir
+//! volumes hold is what the ephemeral storage tracker last measured.
+use chrono::{DateTime, Utc};
+use kubelet::stats::{
+    ContainerStats, CpuStats, FsStats, MemoryStats, PodReference, PodStats, VolumeStats,
+};
+
+use crate::memory_limit::MemoryLimit;
+use crate::WasiProvider;
+
+/// The usage of the pods with running containers
+pub(crate) async fn pod_stats(provider: &WasiProvider) -> Vec<PodStats> {
+    let handles = provider.shared.handles.read().await;
+    let exec_targets = provider.shared.exec_targets.read().await;
+    let mut pods = vec![];
+    for (key, handle) in handles.iter() {
+        let pod = handle.pod();
+        let running = match exec_targets.get(key) {
+            Some(running) if !running.is_empty() => running,
+            _ => continue,
+        };
+        let mut containers: Vec<ContainerStats> = running
+            .iter()
+            .map(|(name, target)| {
+                let now = Utc::now();
+                let cpu = provider
+                    .container_cpu_usage(pod.namespace(), pod.name(), name)
+                    .map(|usage| CpuStats {
+                        time: now,
+                        usage_nano_cores: None,
+                        usage_core_nano_seconds: Some(usage.as_nanos() as u64),
+                    });
+                ContainerStats {
+                    name: name.clone(),
+                    start_time: Some(target.started_at),
+                    cpu,
+                    memory: Some(memory_stats(now, target.runtime.memory_limit())),
+                }
+            })
+            .collect();
+        containers.sort_by(|a, b| a.name.cmp(&b.name));
+        let storage = provider.shared.storage_tracker.last_usage(pod.pod_uid());
+        pods.push(PodStats {
+            pod_ref: PodReference {
+                name: pod.name().to_owned(),
+                namespace: pod.namespace().to_owned(),
+                uid: pod.pod_uid().to_owned(),
+            },
+            // The pod started when its first container did
+            start_time: running.values().map(|target| target.started_at).min(),
+            containers,
+            // Added up from the containers by the kubelet
+            cpu: None,
+            memory: None,
+            volume: storage
+                .iter()
+                .flat_map(|usage| {
+                    usage.volumes.iter().map(move |(name, used)| VolumeStats {
+                        name: name.clone(),
+                        fs: FsStats::used(usage.time, *used),
+                    })
+                })
+                .collect(),
+            ephemeral_storage: storage
+                .and_then(|usage| usage.used_bytes.map(|used| FsStats::used(usage.time, used))),
+        });
+    }
+    pods
+}
+
+/// The memory used by a container's module, and how much more it may use if
+/// it has a limit
+fn memory_stats(time: DateTime<Utc>, memory_limit: &MemoryLimit) -> MemoryStats {
+    let used = memory_limit.used_bytes();
+    let limit = memory_limit.limit_bytes();
+    MemoryStats {
+        time,
+        available_bytes: Some(limit.saturating_sub(used)).filter(|_| limit != u64::MAX),
+        usage_bytes: Some(used),
+        working_set_bytes: Some(used),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_is_reported_with_what_is_left_of_the_limit() {
+        let now = Utc::now();
+        let limited = memory_stats(now, &MemoryLimit::new(1024 * 1024));
+        assert_eq!(limited.available_bytes, Some(1024 * 1024));
+        assert_eq!(limited.usage_bytes, Some(0));
+        assert_eq!(limited.working_set_bytes, Some(0));
+        assert_eq!(limited.time, now);
+
+        let unlimited = memory_stats(now, &MemoryLimit::unlimited());
+        assert_eq!(unlimited.available_bytes, None);
+    }
+}
@@ -0,0 +1,369 @@
+//! The standard streams of module instances.
+//!
+//! Containers write their output to their log, which is also broadcast to
+//! any `kubectl attach` sessions, while commands run for `kubectl exec` are
+//! connected to the exec session. Modules read and write their streams
+//! synchronously on the thread they run on, so session streams are bridged
+//! to the async ones the session provides over channels. Modules isolated
+//! in a worker process use the worker's streams, which the provider copies
+//! to and from the streams given to the instance.
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+
+use kubelet::log::{RotatingFile, Stream, Writer};
+use kubelet::provider::{ExecInput, ExecOutput};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+
+type Input = ReadPipe<Box<dyn Read + Send + Sync>>;
+type Output = WritePipe<Box<dyn Write + Send + Sync>>;
+/// Where a container's input is sent, until it is closed
+type InputSender = Arc<Mutex<Option<mpsc::Sender<Vec<u8>>>>>;
+
+/// The standard streams given to a module instance. The pipes are shared by
+/// clones, so that the WASI contexts of an instance write to the same ones.
+pub(crate) struct Stdio {
+    pub(crate) stdin: Option<Input>,
+    pub(crate) stdout: Option<Output>,
+    pub(crate) stderr: Option<Output>,
+}
+
+impl Stdio {
+    /// Streams that write output to the container's log and to the sessions
+    /// attached to it. Unfinished lines are written to the log when the pipes
+    /// are dropped. If `stdin` is set, input is read from attached sessions,
+    /// and if `stdin_once` is also set, input ends once the first session
+    /// reading it detaches, as for the `stdin` and `stdinOnce` fields of a
+    /// container. Otherwise there is no input.
+    pub(crate) fn container(
+        file: RotatingFile,
+        stdin: bool,
+        stdin_once: bool,
+    ) -> (Self, Attachment) {
+        let (stdout, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (stderr, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (stdout, stderr) = (Arc::new(stdout), Arc::new(stderr));
+        let (input, input_sender) = if stdin {
+            let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+            (
+                Some(ReadPipe::new(
+                    Box::new(ChannelReader::receiving(rx)) as Box<dyn Read + Send + Sync>
+                )),
+                Some(Arc::new(Mutex::new(Some(tx)))),
+            )
+        } else {
+            (None, None)
+        };
+        let attachment = Attachment {
+            stdout: Arc::downgrade(&stdout),
+            stderr: Arc::downgrade(&stderr),
+            stdin: input_sender,
+            stdin_once,
+        };
+        let stdio = Stdio {
+            stdin: input,
+            stdout: Some(output(Broadcast {
+                writer: Writer::new(file.clone(), Stream::Stdout),
+                sender: stdout,
+            })),
+            stderr: Some(output(Broadcast {
+                writer: Writer::new(file, Stream::Stderr),
+                sender: stderr,
+            })),
+        };
+        (stdio, attachment)
+    }
+
+    /// Streams connected to those of an exec session. Streams the session
+    /// didn't ask for are left closed. Each stream is copied to or from the
+    /// session by a task, which module's thread hands data to over a channel.
+    pub(crate) fn exec(
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+    ) -> Self {
+        Stdio {
+            stdin: stdin.map(|input| {
+                ReadPipe::new(Box::new(ChannelReader::new(input)) as Box<dyn Read + Send + Sync>)
+            }),
+            stdout: stdout.map(|stdout| output(ChannelWriter::new(stdout))),
+            stderr: stderr.map(|stderr| output(ChannelWriter::new(stderr))),
+        }
+    }
+
+    /// The streams of the current process, for worker processes running a
+    /// module for the provider, which proxies them. Streams that aren't
+    /// wanted are left closed.
+    #[cfg(unix)]
+    pub(crate) fn process(stdin: bool, stdout: bool, stderr: bool) -> Self {
+        use std::os::unix::io::FromRawFd;
+        // The descriptors are written to directly, rather than through the
+        // buffered handles of the standard library, so that all output has
+        // been written by the time the worker exits
+        let file = |fd| unsafe { std::fs::File::from_raw_fd(fd) };
+        Stdio {
+            stdin: match stdin {
+                true => Some(ReadPipe::new(
+                    Box::new(file(0)) as Box<dyn Read + Send + Sync>
+                )),
+                false => None,
+            },
+            stdout: match stdout {
+                true => Some(output(file(1))),
+                false => None,
+            },
+            stderr: match stderr {
+                true => Some(output(file(2))),
+                false => None,
+            },
+        }
+    }
+}
+
+fn output<W: Write + Send + Sync + 'static>(writer: W) -> Output {
+    WritePipe::new(Box::new(writer) as Box<dyn Write + Send + Sync>)
+}
+
+/// How many chunks of a stream can be waiting to be copied
+const CHANNEL_CAPACITY: usize = 8;
+/// How many chunks of output an attached session can fall behind by before
+/// it misses some
+const BROADCAST_CAPACITY: usize = 64;
+/// The most bytes read from exec input at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// Reads exec input on the module's thread, as a task reads it from the
+/// session
+struct ChannelReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: std::io::Cursor<Vec<u8>>,
+}
+
+impl ChannelReader {
+    fn new(input: ExecInput) -> Self {
+        let (tx, chunks) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(copy_input(input, tx));
+        ChannelReader::receiving(chunks)
+    }
+
+    /// Reads the chunks sent on a channel, until every sender is dropped
+    fn receiving(chunks: mpsc::Receiver<Vec<u8>>) -> Self {
+        ChannelReader {
+            chunks,
+            chunk: Default::default(),
+        }
+    }
+}
+
+/// Sends input to a module in chunks, until either the input or the module's
+/// end of the channel is closed
+async fn copy_input(mut input: ExecInput, chunks: mpsc::Sender<Vec<u8>>) {
+    let mut buf = vec![0; READ_SIZE];
+    loop {
+        match input.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => {
+                if chunks.send(buf[..n].to_vec()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.chunk.position() as usize == self.chunk.get_ref().len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = std::io::Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+        Read::read(&mut self.chunk, buf)
+    }
+}
+
+/// Writes exec output on the module's thread, for a task to write to the
+/// session. The session's output is closed once the writer is dropped and
+/// everything written has been copied.
+struct ChannelWriter {
+    chunks: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChannelWriter {
+    fn new(mut output: ExecOutput) -> Self {
+        let (chunks, mut rx) = mpsc::channel::<Vec<u8>>(CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                if output.write_all(&chunk).await.is_err() || output.flush().await.is_err() {
+                    return;
+                }
+            }
+            let _ = output.shutdown().await;
+        });
+        ChannelWriter { chunks }
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.chunks
+            .blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a container's output, and broadcasts what was written to the
+/// sessions attached to it. Sessions that fall behind miss output rather than
+/// holding the container up.
+struct Broadcast {
+    writer: Writer<RotatingFile>,
+    sender: Arc<broadcast::Sender<Vec<u8>>>,
+}
+
+impl Write for Broadcast {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        // Sending only fails if nothing is attached
+        let _ = self.sender.send(buf[..n].to_vec());
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The streams of a running container, for `kubectl attach` sessions to
+/// connect to. Output can only be subscribed to while the container runs, as
+/// it is broadcast by the container's own pipes.
+#[derive(Clone)]
+pub(crate) struct Attachment {
+    stdout: Weak<broadcast::Sender<Vec<u8>>>,
+    stderr: Weak<broadcast::Sender<Vec<u8>>>,
+    /// Where input is sent to the container, if its spec has `stdin` set.
+    /// This is emptied once input is closed, which the module sees as the
+    /// end of its input.
+    stdin: Option<InputSender>,
+    stdin_once: bool,
+}
+
+impl Attachment {
+    /// Streams the container's output to the given outputs, and `stdin` to
+    /// the container, until the container exits. Dropping the returned
+    /// future detaches without affecting the container, except that its
+    /// input is closed if it is only read from one session.
+    pub(crate) async fn attach(
+        &self,
+        stdin: Option<ExecInput>,
+        stdout: Option<ExecOutput>,
+        stderr: Option<ExecOutput>,
+    ) -> anyhow::Result<()> {
+        let (stdout_rx, stderr_rx) = match (self.stdout.upgrade(), self.stderr.upgrade()) {
+            (Some(stdout), Some(stderr)) => (stdout.subscribe(), stderr.subscribe()),
+            _ => return Err(anyhow::anyhow!("container is not running")),
+        };
+        if stdin.is_some() && self.stdin.is_none() {
+            return Err(anyhow::anyhow!(
+                "container does not read stdin, as stdin is not set in its spec"
+            ));
+        }
+
+        // Input that ends doesn't end the session, which is attached until
+        // the container exits
+        let close = match stdin.is_some() && self.stdin_once {
+            true => Some(CloseStdin(self)),
+            false => None,
+        };
+        let input = async {
+            if let (Some(stdin), Some(sender)) = (stdin, &self.stdin) {
+                send_input(stdin, sender).await;
+            }
+            drop(close);
+            futures::future::pending::<()>().await
+        };
+        let outputs = futures::future::join(
+            copy_broadcast(stdout_rx, stdout),
+            copy_broadcast(stderr_rx, stderr),
+        );
+        tokio::select! {
+            _ = outputs => (),
+            _ = input => (),
+        }
+        Ok(())
+    }
+
+    /// Closes the container's input for every session. This is also how a
+    /// module that is waiting for input is stopped, as it can't be
+    /// interrupted until it has some.
+    pub(crate) fn close_stdin(&self) {
+        if let Some(sender) = &self.stdin {
+            lock(sender).take();
+        }
+    }
+}
+
+/// Closes the input of a container when dropped, once the session that is
+/// the only one to read it detaches or closes its input
+struct CloseStdin<'a>(&'a Attachment);
+
+impl Drop for CloseStdin<'_> {
+    fn drop(&mut self) {
+        self.0.close_stdin();
+    }
+}
+
+/// Sends a session's input to the container until either is closed. The
+/// sender is only held while sending, so that closing the container's input
+/// takes effect for every session.
+async fn send_input(mut input: ExecInput, stdin: &Mutex<Option<mpsc::Sender<Vec<u8>>>>) {
+    let mut buf = vec![0; READ_SIZE];
+    loop {
+        let n = match input.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        let sender = match lock(stdin).clone() {
+            Some(sender) => sender,
+            None => return,
+        };
+        if sender.send(buf[..n].to_vec()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .expect("container stdin lock should not be poisoned")
+}
+
+/// Copies broadcast output to a session until the container exits. Output
+/// the session didn't ask for is discarded.
+async fn copy_broadcast(mut rx: broadcast::Receiver<Vec<u8>>, mut output: Option<ExecOutput>) {
+    loop {
+        match rx.recv().await {
+            Ok(chunk) => {
+                if let Some(writer) = output.as_mut() {
+                    if writer.write_all(&chunk).await.is_err() || writer.flush().await.is_err() {
+                        output = None;
+                    }
+                }
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+    if let Some(mut writer) = output {
+        let _ = writer.shutdown().await;
+    }
+}
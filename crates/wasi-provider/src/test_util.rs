@@ -0,0 +1,21 @@
+//! Pods shared by the tests of the provider's modules
+
+use kubelet::pod::Pod;
+
+/// Returns a pod with the annotations `annotations` and a single container
+/// named `app`
+pub(crate) fn pod(annotations: serde_json::Value) -> Pod {
+    pod_with_volumes(annotations, serde_json::json!([]))
+}
+
+/// Like [`pod`], but with the volumes `volumes` too
+pub(crate) fn pod_with_volumes(annotations: serde_json::Value, volumes: serde_json::Value) -> Pod {
+    serde_json::from_value(serde_json::json!({
+        "metadata": { "name": "app", "namespace": "default", "annotations": annotations },
+        "spec": {
+            "containers": [{ "name": "app", "image": "app:v1" }],
+            "volumes": volumes,
+        },
+    }))
+    .unwrap()
+}
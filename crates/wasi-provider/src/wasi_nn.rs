@@ -0,0 +1,150 @@
+//! Selection of the wasi-nn backend used for a pod's modules
+//!
+//! wasi-nn support is only compiled in when the `wasi-nn` feature is enabled.
+//! Pods opt in with the `krustlet.dev/wasi-nn-backend` annotation, and can
+//! name a volume holding their model files with the
+//! `krustlet.dev/wasi-nn-models` annotation.
+
+use kubelet::pod::Pod;
+use serde_derive::{Deserialize, Serialize};
+
+/// Pod annotation used to select the wasi-nn backend for the pod's modules
+pub(crate) const WASI_NN_BACKEND_ANNOTATION: &str = "krustlet.dev/wasi-nn-backend";
+
+/// Pod annotation naming the volume that holds the models for the pod's
+/// modules
+pub(crate) const WASI_NN_MODELS_ANNOTATION: &str = "krustlet.dev/wasi-nn-models";
+
+/// The directory the models volume is preopened at for every container
+pub(crate) const MODELS_DIR: &str = "/models";
+
+/// A backend that can run wasi-nn inference
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WasiNnBackend {
+    OpenVino,
+}
+
+impl std::str::FromStr for WasiNnBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend.to_lowercase().as_str() {
+            "openvino" => Ok(WasiNnBackend::OpenVino),
+            // These are valid wasi-nn encodings, but the version of
+            // wasmtime-wasi-nn we build against only implements OpenVINO
+            "pytorch" | "tensorflow" => Err(anyhow::anyhow!(
+                "wasi-nn backend {} is not supported by this provider. Supported backends: openvino",
+                backend
+            )),
+            other => Err(anyhow::anyhow!("unknown wasi-nn backend {}", other)),
+        }
+    }
+}
+
+/// Returns the wasi-nn backend requested by the pod, if any.
+///
+/// This returns an error if the pod requests a backend that is unknown or not
+/// available in this build of the provider.
+pub(crate) fn requested_backend(pod: &Pod) -> anyhow::Result<Option<WasiNnBackend>> {
+    let backend = match pod.get_annotation(WASI_NN_BACKEND_ANNOTATION) {
+        Some(backend) => backend,
+        None => return Ok(None),
+    };
+    if !cfg!(feature = "wasi-nn") {
+        return Err(anyhow::anyhow!(
+            "Pod requested a wasi-nn backend via the {} annotation, but this provider was built without wasi-nn support",
+            WASI_NN_BACKEND_ANNOTATION
+        ));
+    }
+    backend.parse().map(Some)
+}
+
+/// Returns the name of the volume holding the pod's models, if it names one.
+///
+/// The volume is preopened read-only at [`MODELS_DIR`] for every container, so
+/// this returns an error if the pod doesn't request a wasi-nn backend or
+/// doesn't have the volume.
+pub(crate) fn models_volume(pod: &Pod) -> anyhow::Result<Option<&str>> {
+    let volume = match pod.get_annotation(WASI_NN_MODELS_ANNOTATION) {
+        Some(volume) => volume,
+        None => return Ok(None),
+    };
+    if pod.get_annotation(WASI_NN_BACKEND_ANNOTATION).is_none() {
+        return Err(anyhow::anyhow!(
+            "Pod set the {} annotation without requesting a wasi-nn backend via the {} annotation",
+            WASI_NN_MODELS_ANNOTATION,
+            WASI_NN_BACKEND_ANNOTATION
+        ));
+    }
+    let has_volume = pod
+        .volumes()
+        .map(|volumes| volumes.iter().any(|v| v.name == volume))
+        .unwrap_or(false);
+    if !has_volume {
+        return Err(anyhow::anyhow!(
+            "Pod named volume {} in the {} annotation, but has no volume with that name",
+            volume,
+            WASI_NN_MODELS_ANNOTATION
+        ));
+    }
+    Ok(Some(volume))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+
+    fn pod(annotations: serde_json::Value) -> Pod {
+        test_util::pod_with_volumes(annotations, json!([{ "name": "models", "emptyDir": {} }]))
+    }
+
+    #[test]
+    fn backends_are_parsed() {
+        for name in &["openvino", "OpenVINO"] {
+            assert_eq!(
+                name.parse::<WasiNnBackend>().unwrap(),
+                WasiNnBackend::OpenVino
+            );
+        }
+        for name in &["pytorch", "tensorflow"] {
+            let err = name.parse::<WasiNnBackend>().unwrap_err().to_string();
+            assert!(err.contains("not supported"), "{}: {}", name, err);
+        }
+        let err = "onnx".parse::<WasiNnBackend>().unwrap_err().to_string();
+        assert!(err.contains("unknown"), "{}", err);
+    }
+
+    #[test]
+    fn requested_backend_comes_from_the_annotation() {
+        assert_eq!(requested_backend(&pod(json!({}))).unwrap(), None);
+
+        let requested = requested_backend(&pod(json!({ WASI_NN_BACKEND_ANNOTATION: "openvino" })));
+        if cfg!(feature = "wasi-nn") {
+            assert_eq!(requested.unwrap(), Some(WasiNnBackend::OpenVino));
+        } else {
+            assert!(requested.is_err());
+        }
+        let requested = requested_backend(&pod(json!({ WASI_NN_BACKEND_ANNOTATION: "pytorch" })));
+        assert!(requested.is_err());
+    }
+
+    #[test]
+    fn models_volume_must_be_a_volume_of_a_wasi_nn_pod() {
+        assert_eq!(models_volume(&pod(json!({}))).unwrap(), None);
+        let models = pod(json!({
+            WASI_NN_BACKEND_ANNOTATION: "openvino",
+            WASI_NN_MODELS_ANNOTATION: "models",
+        }));
+        assert_eq!(models_volume(&models).unwrap(), Some("models"));
+
+        let missing = pod(json!({
+            WASI_NN_BACKEND_ANNOTATION: "openvino",
+            WASI_NN_MODELS_ANNOTATION: "weights",
+        }));
+        assert!(models_volume(&missing).is_err());
+        let without_backend = pod(json!({ WASI_NN_MODELS_ANNOTATION: "models" }));
+        assert!(models_volume(&without_backend).is_err());
+    }
+}
@@ -1,31 +1,89 @@
 use anyhow::bail;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
 use tracing::{debug, error, info, warn};
 
-use tempfile::NamedTempFile;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::dir::DirCaps;
+use wasi_common::file::FileCaps;
+use wasi_common::WasiCtx;
 use wasmtime::InterruptHandle;
 use wasmtime_wasi::snapshots::preview_0::Wasi as WasiUnstable;
 use wasmtime_wasi::snapshots::preview_1::Wasi;
 
+#[cfg(feature = "wasi-nn")]
+use wasmtime_wasi_nn::{WasiNn, WasiNnCtx};
+
+use crate::checkpoint::{
+    checkpoint_function, CheckpointRequest, Checkpointing, Snapshot, CHECKPOINTED,
+    CHECKPOINT_FUNCTION, CHECKPOINT_MODULE, RESUME,
+};
+use crate::cpu_limit::CpuScheduler;
+use crate::isolate::{spawn_worker, Isolation, WorkerSpec, WorkerStopper};
+use crate::memory_limit::{MemoryLimit, OOM_KILLED};
+use crate::module_cache::{LoadedModule, ModuleCache};
+use crate::read_only::ReadOnlyDir;
+use crate::run_as::RunAs;
+use crate::seccomp::WasiPolicy;
+use crate::stdio::{Attachment, Stdio};
+use crate::wasi_nn::WasiNnBackend;
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
+use kubelet::cpu_manager::PodCpus;
 use kubelet::handle::StopHandler;
+use kubelet::hugepages::PodHugePages;
+use kubelet::log::{RotatedFiles, RotatingFile, Stream};
+use kubelet::memory_manager::NumaBinding;
+use kubelet::provider::ExitCode;
+
+/// The function that WASI commands start at
+const START: &str = "_start";
 
 pub struct Runtime {
     handle: JoinHandle<anyhow::Result<()>>,
-    interrupt_handle: InterruptHandle,
+    interrupt: Interrupt,
+    attachment: Attachment,
+    /// Whether the module has been asked to checkpoint, if it can
+    checkpoint: Option<Arc<CheckpointRequest>>,
+}
+
+/// Stops a running module
+pub(crate) enum Interrupt {
+    /// Interrupts the store of a module running in the provider, which traps
+    Store(InterruptHandle),
+    /// Stops the worker process a module runs in
+    Worker(WorkerStopper),
+}
+
+impl Interrupt {
+    fn interrupt(&self) {
+        match self {
+            Interrupt::Store(handle) => handle.interrupt(),
+            Interrupt::Worker(stopper) => stopper.stop(),
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl StopHandler for Runtime {
     async fn stop(&mut self) -> anyhow::Result<()> {
-        self.interrupt_handle.interrupt();
+        // A module waiting for input is only interrupted once it has some
+        self.attachment.close_stdin();
+        // Modules that checkpoint are asked to stop the first time, and only
+        // interrupted if they are stopped again
+        if let Some(checkpoint) = &self.checkpoint {
+            if checkpoint.request() {
+                return Ok(());
+            }
+        }
+        self.interrupt.interrupt();
         Ok(())
     }
 
@@ -42,34 +100,103 @@ pub struct WasiRuntime {
     name: String,
     /// Data needed for the runtime
     data: Arc<Data>,
-    /// The tempfile that output from the wasmtime process writes to
-    output: Arc<NamedTempFile>,
+    /// The log file that output from the wasmtime process writes to, as CRI
+    /// log entries
+    output: LogFile,
     /// A channel to send status updates on the runtime
     status_sender: Sender<Status>,
+    /// The memory limit of the running module, which can be resized while
+    /// it runs
+    memory_limit: Arc<MemoryLimit>,
+    /// The file the module's checkpoints are written to, if it checkpoints
+    checkpoint_path: Option<PathBuf>,
 }
 
 struct Data {
     /// binary module data to be run as a wasm module
     module_data: Vec<u8>,
-    /// key/value environment variables made available to the wasm process
-    env: HashMap<String, String>,
     /// the arguments passed as the command-line arguments list
     args: Vec<String>,
+    /// how instances of the wasm process are set up
+    config: ModuleConfig,
+    /// the CPU the wasm process may use, in millicores, if limited
+    cpu_limit: Option<u64>,
+    /// the CPUs the threads the wasm process runs on are pinned to
+    cpus: PodCpus,
+    /// the NUMA nodes the memory of the wasm process is allocated from, if bound
+    numa_binding: Option<NumaBinding>,
+    /// the huge pages the memory of the wasm process is backed with, if reserved
+    huge_pages: Option<Arc<PodHugePages>>,
+    /// whether the wasm process runs in the provider or in a worker process
+    isolation: Isolation,
+    /// whether the wasm module is compiled with SIMD enabled
+    simd: bool,
+}
+
+/// How instances of a module are set up, which is sent to the worker
+/// processes of isolated modules
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ModuleConfig {
+    /// key/value environment variables made available to the wasm process
+    env: HashMap<String, String>,
     /// a hash map of local file system paths to optional path names in the runtime
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     /// the same path will be allowed in the runtime
     dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// the local file system paths in `dirs` that the wasm process can read
+    /// but not modify
+    read_only_dirs: HashSet<PathBuf>,
+    /// the wasi-nn backend made available to the wasm process, if any
+    wasi_nn: Option<WasiNnBackend>,
+    /// the WASI functions the wasm process is allowed to call
+    wasi_policy: WasiPolicy,
+    /// whether the directory mounted at `/`, if any, is read-only
+    read_only_root: bool,
+    /// the user and group the wasm process accesses files as
+    pub(crate) run_as: RunAs,
+}
+
+/// A container's log file
+struct LogFile {
+    path: PathBuf,
+    writer: RotatingFile,
+}
+
+/// Where the log of the previous instance of the container whose log is at
+/// `path` is kept
+fn previous_log_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".previous");
+    PathBuf::from(name)
 }
 
-/// Holds our tempfile handle.
+/// Holds the path to our log file.
 pub struct HandleFactory {
-    temp: Arc<NamedTempFile>,
+    path: PathBuf,
 }
 
-impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
-    /// Creates `tokio::fs::File` on demand for log reading.
-    fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
+impl kubelet::log::HandleFactory<RotatedFiles> for HandleFactory {
+    /// Opens the log file, and the files rotated from it, on demand for log
+    /// reading.
+    fn new_handle(&self) -> RotatedFiles {
+        RotatedFiles::open(&self.path).unwrap()
+    }
+
+    /// Opens the log file, and the files rotated from it, at the last `lines`
+    /// lines, so that tailing a long log doesn't read all of it.
+    fn new_tail_handle(&self, lines: usize, stream: Option<Stream>) -> RotatedFiles {
+        RotatedFiles::open_tail(&self.path, lines, stream).unwrap()
+    }
+
+    /// Reads the log the previous instance of the container left, if it has
+    /// been restarted.
+    fn previous(&self) -> Option<Self> {
+        let path = previous_log_path(&self.path);
+        if path.exists() {
+            Some(HandleFactory { path })
+        } else {
+            None
+        }
     }
 }
 
@@ -84,295 +211,1212 @@ impl WasiRuntime {
     /// * `dirs` - a map of local file system paths to optional path names in the runtime
     ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     ///     the same path will be allowed in the runtime
-    /// * `log_dir` - location for storing logs
+    /// * `read_only_dirs` - the local file system paths in `dirs` that the module can
+    ///   read but not modify
+    /// * `wasi_nn` - the wasi-nn backend to make available to the module, if any
+    /// * `wasi_policy` - the WASI functions the module is allowed to call
+    /// * `read_only_root` - whether the directory mounted at `/` is read-only
+    /// * `run_as` - the user and group the module accesses files as
+    /// * `memory_limit` - the maximum bytes of linear memory the module may use, if limited
+    /// * `cpu_limit` - the CPU the module may use, in millicores, if limited
+    /// * `cpus` - the CPUs of the pod, which the module's thread is pinned to
+    /// * `numa_binding` - the NUMA nodes to allocate the module's memory from, if bound
+    /// * `huge_pages` - the huge pages of the pod to back the module's memory with, if reserved
+    /// * `isolation` - whether the module runs in the provider or in a worker process
+    /// * `simd` - whether the module is compiled with SIMD enabled
+    /// * `checkpoint_path` - the file the module's checkpoints are written to, if it
+    ///   checkpoints
+    /// * `log_path` - the path of the log file. The log of a previous instance
+    ///   of the container at the same path, with the files rotated from it,
+    ///   is moved aside to be read as the previous log
+    /// * `log_max_size` - the size in bytes the log file can grow to before it is rotated
+    /// * `log_max_files` - the most log files to keep, including the one being written
+    #[allow(clippy::too_many_arguments)]
     pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
         name: String,
         module_data: Vec<u8>,
         env: HashMap<String, String>,
         args: Vec<String>,
         dirs: HashMap<PathBuf, Option<PathBuf>>,
-        log_dir: L,
+        read_only_dirs: HashSet<PathBuf>,
+        wasi_nn: Option<WasiNnBackend>,
+        wasi_policy: WasiPolicy,
+        read_only_root: bool,
+        run_as: RunAs,
+        memory_limit: Option<u64>,
+        cpu_limit: Option<u64>,
+        cpus: PodCpus,
+        numa_binding: Option<NumaBinding>,
+        huge_pages: Option<Arc<PodHugePages>>,
+        isolation: Isolation,
+        simd: bool,
+        checkpoint_path: Option<PathBuf>,
+        log_path: L,
+        log_max_size: u64,
+        log_max_files: usize,
         status_sender: Sender<Status>,
     ) -> anyhow::Result<Self> {
-        let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
-            Ok(NamedTempFile::new_in(log_dir)?)
+        let module_data = crate::wasm_binary::module_binary(module_data)?;
+        crate::wasm_binary::ensure_runnable(&module_data)?;
+        crate::wasm_binary::validate(&module_data, simd, wasi_nn.is_some())?;
+        if isolation == Isolation::Process && checkpoint_path.is_some() {
+            bail!("modules that run in worker processes can't be checkpointed");
+        }
+
+        // Like other kubelets, exactly one previous instance's log is kept,
+        // replacing any older one. The pod's log directory is removed when
+        // the pod is deleted
+        let output = tokio::task::spawn_blocking(move || -> anyhow::Result<LogFile> {
+            let path = log_path.as_ref().to_owned();
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            if path.exists() {
+                RotatingFile::rename(&path, &previous_log_path(&path))?;
+            }
+            let writer = RotatingFile::create(path.clone(), log_max_size, log_max_files)?;
+            Ok(LogFile { path, writer })
         })
         .await??;
 
-        // We need to use named temp file because we need multiple file handles
-        // and if we are running in the temp dir, we run the possibility of the
-        // temp file getting cleaned out from underneath us while running. If we
-        // think it necessary, we can make these permanent files with a cleanup
-        // loop that runs elsewhere. These will get deleted when the reference
-        // is dropped
+        let memory_limit = match memory_limit {
+            Some(limit) => MemoryLimit::new(limit),
+            None => MemoryLimit::unlimited(),
+        };
         Ok(WasiRuntime {
             name,
             data: Arc::new(Data {
                 module_data,
-                env,
                 args,
-                dirs,
+                config: ModuleConfig {
+                    env,
+                    dirs,
+                    read_only_dirs,
+                    wasi_nn,
+                    wasi_policy,
+                    read_only_root,
+                    run_as,
+                },
+                cpu_limit,
+                cpus,
+                numa_binding,
+                huge_pages,
+                isolation,
+                simd,
             }),
-            output: Arc::new(temp),
+            output,
             status_sender,
+            memory_limit,
+            checkpoint_path,
         })
     }
 
-    pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
-        let temp = self.output.clone();
-        // Because a reopen is blocking, run in a blocking task to get new
-        // handles to the tempfile
-        let output_write = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
-            Ok(temp.reopen()?)
-        })
-        .await??;
+    /// The file the module's checkpoints are written to, if it checkpoints
+    pub(crate) fn checkpoint_path(&self) -> Option<&Path> {
+        self.checkpoint_path.as_deref()
+    }
 
-        let (interrupt_handle, handle) = self.spawn_wasmtime(output_write).await?;
+    /// The memory limit of the container's module, once it has started
+    pub(crate) fn memory_limit(&self) -> &Arc<MemoryLimit> {
+        &self.memory_limit
+    }
+
+    /// Loads the module from the compilation cache, compiling it if needed
+    pub async fn load_module(&self, cache: Arc<ModuleCache>) -> anyhow::Result<LoadedModule> {
+        let data = self.data.clone();
+        tokio::task::spawn_blocking(move || cache.load(&data.module_data, data.simd))
+            .await?
+            .map_err(|e| anyhow::anyhow!("unable to create module: {}", e))
+    }
+
+    /// Starts running the given module, which must have been loaded with
+    /// `load_module`. The module's thread is tracked by the given scheduler,
+    /// which enforces its CPU limit. The returned attachment connects
+    /// `kubectl attach` sessions to the module's streams. `stdin` and
+    /// `stdin_once` are those of the container's spec. A module that
+    /// checkpoints resumes from `restore`, if given, rather than starting
+    /// afresh.
+    pub(crate) async fn start(
+        &self,
+        module: wasmtime::Module,
+        cpu_scheduler: Arc<CpuScheduler>,
+        stdin: bool,
+        stdin_once: bool,
+        restore: Option<Snapshot>,
+    ) -> anyhow::Result<(ContainerHandle<Runtime, HandleFactory>, Attachment)> {
+        let (stdio, attachment) = Stdio::container(self.output.writer.clone(), stdin, stdin_once);
+        let checkpointing = self.checkpoint_path.clone().map(|path| Checkpointing {
+            path,
+            request: Default::default(),
+            restore,
+        });
+        let checkpoint = checkpointing
+            .as_ref()
+            .map(|checkpointing| checkpointing.request.clone());
+        let (interrupt, handle) = self
+            .spawn_wasmtime(
+                self.name.clone(),
+                START.to_owned(),
+                module,
+                cpu_scheduler,
+                self.memory_limit.clone(),
+                self.data.args.clone(),
+                stdio,
+                Some(self.status_sender.clone()),
+                checkpointing,
+            )
+            .await?;
 
         let log_handle_factory = HandleFactory {
-            temp: self.output.clone(),
+            path: self.output.path.clone(),
         };
 
-        Ok(ContainerHandle::new(
-            Runtime {
-                handle,
-                interrupt_handle,
-            },
-            log_handle_factory,
+        Ok((
+            ContainerHandle::new(
+                Runtime {
+                    handle,
+                    interrupt,
+                    attachment: attachment.clone(),
+                    checkpoint,
+                },
+                log_handle_factory,
+            ),
+            attachment,
         ))
     }
 
+    /// Runs the module afresh with `command` as its arguments and the given
+    /// standard streams, for `kubectl exec`, returning its exit code once it
+    /// exits. It runs with the same environment, volumes and limits as the
+    /// container, but doesn't affect the container's status or log.
+    pub(crate) async fn exec(
+        &self,
+        module: wasmtime::Module,
+        cpu_scheduler: Arc<CpuScheduler>,
+        command: Vec<String>,
+        stdio: Stdio,
+    ) -> anyhow::Result<ExitCode> {
+        self.run_to_exit(
+            format!("{} exec", self.name),
+            START.to_owned(),
+            module,
+            cpu_scheduler,
+            command,
+            stdio,
+        )
+        .await
+    }
+
+    /// Runs the function named by the first element of `command`, which the
+    /// module must export, in a new instance of the module with `command` as
+    /// its arguments, for the container's lifecycle hooks. Returns its exit
+    /// code once it returns. Like commands run with [`WasiRuntime::exec`],
+    /// it runs with the same environment, volumes and limits as the
+    /// container, and its output is discarded.
+    pub(crate) async fn run_hook(
+        &self,
+        module: wasmtime::Module,
+        cpu_scheduler: Arc<CpuScheduler>,
+        command: Vec<String>,
+    ) -> anyhow::Result<ExitCode> {
+        let entry = command
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("hook has no command"))?;
+        self.run_to_exit(
+            format!("{} hook {}", self.name, entry),
+            entry,
+            module,
+            cpu_scheduler,
+            command,
+            Stdio::exec(None, None, None),
+        )
+        .await
+    }
+
+    /// Runs the function `entry` in a new instance of the module, returning
+    /// the exit code it exits with
+    async fn run_to_exit(
+        &self,
+        name: String,
+        entry: String,
+        module: wasmtime::Module,
+        cpu_scheduler: Arc<CpuScheduler>,
+        args: Vec<String>,
+        stdio: Stdio,
+    ) -> anyhow::Result<ExitCode> {
+        let (_, handle) = self
+            .spawn_wasmtime(
+                name,
+                entry,
+                module,
+                cpu_scheduler,
+                // The command's memory is limited separately from the
+                // container's, to the container's current limit
+                MemoryLimit::new(self.memory_limit.limit_bytes()),
+                args,
+                stdio,
+                None,
+                None,
+            )
+            .await?;
+        match handle.await? {
+            Ok(()) => Ok(ExitCode(0)),
+            Err(e) => match exit_status(&e) {
+                Some(status) => Ok(ExitCode(status)),
+                None => Err(e),
+            },
+        }
+    }
+
     // Spawns a running wasmtime instance with the given context and status
     // channel. Due to the Instance type not being Send safe, all of the logic
     // needs to be done within the spawned task
+    #[allow(clippy::too_many_arguments)]
     async fn spawn_wasmtime(
         &self,
-        output_write: std::fs::File,
-    ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
+        name: String,
+        entry: String,
+        module: wasmtime::Module,
+        cpu_scheduler: Arc<CpuScheduler>,
+        memory_limit: Arc<MemoryLimit>,
+        args: Vec<String>,
+        stdio: Stdio,
+        status_sender: Option<Sender<Status>>,
+        checkpointing: Option<Checkpointing>,
+    ) -> anyhow::Result<(Interrupt, JoinHandle<anyhow::Result<()>>)> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
-        let status_sender = self.status_sender.clone();
+        if data.isolation == Isolation::Process {
+            if data.numa_binding.is_some() || data.huge_pages.is_some() {
+                warn!(
+                    "{} runs in a worker process, which isn't bound to NUMA nodes or backed by huge pages",
+                    name
+                );
+            }
+            let spec = WorkerSpec {
+                name,
+                entry,
+                args,
+                config: data.config.clone(),
+                memory_limit: memory_limit.limit_bytes(),
+                cpu_limit: data.cpu_limit,
+                cpu_tick: cpu_scheduler.tick_interval(),
+                stdin: stdio.stdin.is_some(),
+                stdout: stdio.stdout.is_some(),
+                stderr: stdio.stderr.is_some(),
+                simd: data.simd,
+            };
+            let (stopper, handle) = spawn_worker(spec, &module, stdio, status_sender).await?;
+            return Ok((Interrupt::Worker(stopper), handle));
+        }
         let (tx, rx) = oneshot::channel();
 
-        let name = self.name.clone();
         let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let env: Vec<(String, String)> = data
-                .env
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect();
-            let stdout = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stdout = wasi_cap_std_sync::file::File::from_cap_std(stdout);
-            let stderr = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stderr = wasi_cap_std_sync::file::File::from_cap_std(stderr);
-
-            // Build the WASI instance and then generate a list of WASI modules
-            let ctx_builder_snapshot = WasiCtxBuilder::new();
-            let mut ctx_builder_snapshot = ctx_builder_snapshot
-                .args(&data.args)?
-                .envs(&env)?
-                .stdout(Box::new(stdout))
-                .stderr(Box::new(stderr));
-
-            let stdout = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stdout = wasi_cap_std_sync::file::File::from_cap_std(stdout);
-            let stderr = unsafe { cap_std::fs::File::from_std(output_write.try_clone()?) };
-            let stderr = wasi_cap_std_sync::file::File::from_cap_std(stderr);
-
-            let ctx_builder_unstable = WasiCtxBuilder::new();
-            let mut ctx_builder_unstable = ctx_builder_unstable
-                .args(&data.args)?
-                .envs(&env)?
-                .stdout(Box::new(stdout))
-                .stderr(Box::new(stderr));
-
-            for (key, value) in data.dirs.iter() {
-                let guest_dir = value.as_ref().unwrap_or(key);
-                debug!(
-                    "{} mounting hostpath {} as guestpath {}",
-                    &name,
-                    key.display(),
-                    guest_dir.display()
-                );
-                let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
-                ctx_builder_snapshot =
-                    ctx_builder_snapshot.preopened_dir(preopen_dir, guest_dir)?;
-                let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
-                ctx_builder_unstable =
-                    ctx_builder_unstable.preopened_dir(preopen_dir, guest_dir)?;
-            }
-            let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
-            let wasi_ctx_unstable = ctx_builder_unstable.build()?;
-            let mut config = wasmtime::Config::new();
-            config.interruptable(true);
-            let engine = wasmtime::Engine::new(&config);
-            let store = wasmtime::Store::new(&engine);
-            let interrupt = store.interrupt_handle()?;
-            tx.send(interrupt)
-                .map_err(|_| anyhow::anyhow!("Unable to send interrupt back to main thread"))?;
-
-            let wasi_snapshot = Wasi::new(
-                &store,
-                std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_snapshot)),
+            // Directories are opened, and the module runs, on this thread, so
+            // switching its filesystem identity applies to all the module's
+            // file access
+            let _identity = data.config.run_as.apply()?;
+            // Memories are created when the module is instantiated and grow
+            // while it runs, both of which happen on this thread
+            let _memory_limit = memory_limit.enter();
+            let _numa_binding = data.numa_binding.as_ref().map(|binding| binding.enter());
+            let _huge_pages = data.huge_pages.as_ref().map(|pages| pages.enter());
+            let _cpu = cpu_scheduler.track(&name, data.cpu_limit);
+            // Pinning applies to the thread the module runs on, which is the
+            // wasmtime worker thread for the module
+            let _cpus = data.cpus.pin_current_thread();
+            run_instance(
+                &name,
+                &entry,
+                &module,
+                &data.config,
+                &memory_limit,
+                &args,
+                stdio,
+                Some(tx),
+                status_sender.as_ref(),
+                checkpointing,
+            )
+        });
+        // Wait for the interrupt to be sent back to us
+        let interrupt = rx.await?;
+        Ok((Interrupt::Store(interrupt), handle))
+    }
+}
+
+/// Instantiates the module with the given configuration and standard streams,
+/// and runs its function `entry` on the current thread until it returns,
+/// reporting its statuses to `status_sender`. The store's interrupt handle is
+/// sent to `interrupt` before the module is instantiated. With
+/// `checkpointing`, the module can checkpoint and, if it is restored, is
+/// resumed rather than started at `entry`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_instance(
+    name: &str,
+    entry: &str,
+    module: &wasmtime::Module,
+    config: &ModuleConfig,
+    memory_limit: &MemoryLimit,
+    args: &[String],
+    stdio: Stdio,
+    interrupt: Option<oneshot::Sender<InterruptHandle>>,
+    status_sender: Option<&Sender<Status>>,
+    checkpointing: Option<Checkpointing>,
+) -> anyhow::Result<()> {
+    let env: Vec<(String, String)> = config
+        .env
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    // Both WASI contexts share the pipes, so that, when writing to
+    // the log, the rest of a line is written as one entry whichever
+    // context wrote its start
+    let Stdio {
+        stdin,
+        stdout,
+        stderr,
+    } = stdio;
+
+    // Build the WASI instance and then generate a list of WASI modules
+    let mut ctx_builder_snapshot = WasiCtxBuilder::new().args(args)?.envs(&env)?;
+    let mut ctx_builder_unstable = WasiCtxBuilder::new().args(args)?.envs(&env)?;
+    if let Some(stdin) = stdin {
+        ctx_builder_snapshot = ctx_builder_snapshot.stdin(Box::new(stdin.clone()));
+        ctx_builder_unstable = ctx_builder_unstable.stdin(Box::new(stdin));
+    }
+    if let Some(stdout) = stdout {
+        ctx_builder_snapshot = ctx_builder_snapshot.stdout(Box::new(stdout.clone()));
+        ctx_builder_unstable = ctx_builder_unstable.stdout(Box::new(stdout));
+    }
+    if let Some(stderr) = stderr {
+        ctx_builder_snapshot = ctx_builder_snapshot.stderr(Box::new(stderr.clone()));
+        ctx_builder_unstable = ctx_builder_unstable.stderr(Box::new(stderr));
+    }
+
+    let mut read_only_dirs = vec![];
+    for (key, value) in config.dirs.iter() {
+        let guest_dir = value.as_ref().unwrap_or(key);
+        if config.read_only_root && guest_dir == Path::new("/") {
+            debug!(
+                "{} mounting hostpath {} as read-only root",
+                name,
+                key.display()
             );
-            let wasi_unstable = WasiUnstable::new(
-                &store,
-                std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_unstable)),
+            read_only_dirs.push((key, guest_dir));
+            continue;
+        }
+        if config.read_only_dirs.contains(key) {
+            debug!(
+                "{} mounting hostpath {} as read-only guestpath {}",
+                name,
+                key.display(),
+                guest_dir.display()
             );
-            let module = match wasmtime::Module::new(&engine, &data.module_data) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to create module";
-                    error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
-            // Iterate through the module includes and resolve imports
-            let imports = module
-                .imports()
-                .map(|i| {
-                    let name = i.name().unwrap();
-                    // This is super funky logic, but it matches what is in 0.12.0
-                    let export = match i.module() {
-                        "wasi_snapshot_preview1" => wasi_snapshot.get_export(name),
-                        "wasi_unstable" => wasi_unstable.get_export(name),
-                        other => bail!("import module `{}` was not found", other),
-                    };
-                    match export {
-                        Some(export) => Ok(export.clone().into()),
-                        None => bail!("import `{}` was not found in module `{}`", name, i.module()),
-                    }
-                })
-                .collect::<Result<Vec<_>, _>>();
-            let imports = match imports {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to load module";
-                    error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    return Err(e);
-                }
-            };
+            read_only_dirs.push((key, guest_dir));
+            continue;
+        }
+        debug!(
+            "{} mounting hostpath {} as guestpath {}",
+            name,
+            key.display(),
+            guest_dir.display()
+        );
+        let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
+        ctx_builder_snapshot = ctx_builder_snapshot.preopened_dir(preopen_dir, guest_dir)?;
+        let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
+        ctx_builder_unstable = ctx_builder_unstable.preopened_dir(preopen_dir, guest_dir)?;
+    }
+    let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
+    let wasi_ctx_unstable = ctx_builder_unstable.build()?;
+    for (host_dir, guest_dir) in read_only_dirs {
+        preopen_read_only_dir(&wasi_ctx_snapshot, host_dir, guest_dir)?;
+        preopen_read_only_dir(&wasi_ctx_unstable, host_dir, guest_dir)?;
+    }
+    let store = wasmtime::Store::new(module.engine());
+    if let Some(tx) = interrupt {
+        tx.send(store.interrupt_handle()?)
+            .map_err(|_| anyhow::anyhow!("Unable to send interrupt back to main thread"))?;
+    }
 
-            let instance = match wasmtime::Instance::new(&store, &module, &imports) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to instantiate module";
-                    error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    // Converting from anyhow
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
+    let wasi_snapshot = Wasi::new(
+        &store,
+        std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_snapshot)),
+    );
+    let wasi_unstable = WasiUnstable::new(
+        &store,
+        std::rc::Rc::new(std::cell::RefCell::new(wasi_ctx_unstable)),
+    );
+    #[cfg(feature = "wasi-nn")]
+    let wasi_nn = match config.wasi_nn {
+        Some(WasiNnBackend::OpenVino) => Some(WasiNn::new(
+            &store,
+            std::rc::Rc::new(std::cell::RefCell::new(WasiNnCtx::new()?)),
+        )),
+        None => None,
+    };
+    #[cfg(not(feature = "wasi-nn"))]
+    if config.wasi_nn.is_some() {
+        bail!("wasi-nn was requested but this provider was built without wasi-nn support");
+    }
+    // The checkpoint function saves the instance, which only exists once
+    // its imports have been resolved
+    let checkpoint_instance = Rc::new(RefCell::new(None));
+    let checkpoint = checkpoint_function(
+        &store,
+        name,
+        checkpointing
+            .as_ref()
+            .map(|checkpointing| (checkpointing, checkpoint_instance.clone())),
+        status_sender.cloned(),
+    );
+    // Iterate through the module includes and resolve imports
+    let imports = module
+        .imports()
+        .map(|i| {
+            let name = i.name().unwrap();
+            if matches!(i.module(), "wasi_snapshot_preview1" | "wasi_unstable")
+                && !config.wasi_policy.allows(name)
+            {
+                return denied_function(&store, i.module(), name, i.ty());
+            }
+            if i.module() == CHECKPOINT_MODULE && name == CHECKPOINT_FUNCTION {
+                return Ok(checkpoint.clone().into());
+            }
+            // This is super funky logic, but it matches what is in 0.12.0
+            let export = match i.module() {
+                "wasi_snapshot_preview1" => wasi_snapshot.get_export(name),
+                "wasi_unstable" => wasi_unstable.get_export(name),
+                #[cfg(feature = "wasi-nn")]
+                "wasi_ephemeral_nn" => match &wasi_nn {
+                    Some(wasi_nn) => wasi_nn.get_export(name),
+                    None => bail!(
+                        "module imports wasi-nn, but no wasi-nn backend was requested with the {} annotation",
+                        crate::wasi_nn::WASI_NN_BACKEND_ANNOTATION
+                    ),
+                },
+                other => bail!("import module `{}` was not found", other),
             };
-
-            // NOTE(taylor): In the future, if we want to pass args directly, we'll
-            // need to do a bit more to pass them in here.
-            info!("{} starting run of module", &name);
+            match export {
+                Some(export) => Ok(export.clone().into()),
+                None => bail!("import `{}` was not found in module `{}`", name, i.module()),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let imports = match imports {
+        // We can't map errors here or it moves the send channel, so we
+        // do it in a match
+        Ok(m) => m,
+        Err(e) => {
+            let message = "unable to load module";
+            error!("{} {}: {:?}", name, message, e);
             send(
-                &status_sender,
-                &name,
-                Status::Running {
+                status_sender,
+                name,
+                Status::Terminated {
+                    failed: true,
+                    message: message.into(),
                     timestamp: chrono::Utc::now(),
+                    reason: None,
+                    started_at: None,
+                    exit_code: None,
                 },
             );
 
-            let export = instance
-                .get_export("_start")
-                .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
-            let func = match export {
-                wasmtime::Extern::Func(f) => f,
-                _ => {
-                    let message = "_start import was not a function. This is likely a problem with the module";
-                    error!("{} {}", &name, message);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    return Err(anyhow::anyhow!(message));
-                }
-            };
-            match func.call(&[]) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(_) => {}
-                Err(e) => {
-                    let message = "unable to run module";
-                    error!("{} {}: {:?}", &name, message, e);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
-
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
+            return Err(e);
+        }
+    };
+
+    let instance = match wasmtime::Instance::new(&store, module, &imports) {
+        // We can't map errors here or it moves the send channel, so we
+        // do it in a match
+        Ok(m) => m,
+        Err(e) => {
+            let message = "unable to instantiate module";
+            error!("{} {}: {:?}", name, message, e);
+            send(status_sender, name, failure_status(message, memory_limit));
+
+            // Converting from anyhow
+            return Err(anyhow::anyhow!("{}: {}", message, e));
+        }
+    };
+
+    let restore = checkpointing
+        .as_ref()
+        .and_then(|checkpointing| checkpointing.restore.as_ref());
+    let entry = match restore {
+        Some(snapshot) => {
+            if let Err(e) = snapshot.restore(&instance) {
+                let message = "unable to restore module from checkpoint";
+                error!("{} {}: {:?}", name, message, e);
+                send(status_sender, name, Status::terminated(message, true));
+                return Err(e.context(message));
+            }
+            info!("{} restored module from checkpoint", name);
+            RESUME
+        }
+        None => entry,
+    };
+
+    // NOTE(taylor): In the future, if we want to pass args directly, we'll
+    // need to do a bit more to pass them in here.
+    info!("{} starting run of module", name);
+    send(
+        status_sender,
+        name,
+        Status::Running {
+            timestamp: chrono::Utc::now(),
+        },
+    );
 
-            info!("{} module run complete", &name);
+    let export = instance
+        .get_export(entry)
+        .ok_or_else(|| anyhow::anyhow!("{} import doesn't exist in wasm module", entry))?;
+    let func = match export {
+        wasmtime::Extern::Func(f) => f,
+        _ => {
+            let message = format!(
+                "{} import was not a function. This is likely a problem with the module",
+                entry
+            );
+            error!("{} {}", name, message);
             send(
-                &status_sender,
-                &name,
+                status_sender,
+                name,
                 Status::Terminated {
-                    failed: false,
-                    message: "Module run completed".into(),
+                    failed: true,
+                    message: message.clone(),
                     timestamp: chrono::Utc::now(),
+                    reason: None,
+                    started_at: None,
+                    exit_code: None,
                 },
             );
-            Ok(())
-        });
-        // Wait for the interrupt to be sent back to us
-        let interrupt = rx.await?;
-        Ok((interrupt, handle))
+
+            return Err(anyhow::anyhow!(message));
+        }
+    };
+    checkpoint_instance.replace(Some(instance.clone()));
+    let result = func.call(&[]);
+    // The instance holds the checkpoint function, which would otherwise keep
+    // the instance alive
+    checkpoint_instance.replace(None);
+    match result {
+        // We can't map errors here or it moves the send channel, so we
+        // do it in a match
+        Ok(_) => {}
+        Err(_)
+            if checkpointing
+                .as_ref()
+                .is_some_and(|checkpointing| checkpointing.request.is_taken()) =>
+        {
+            info!("{} module stopped after writing a checkpoint", name);
+            send(
+                status_sender,
+                name,
+                Status::terminated_with_reason(
+                    "Module stopped after writing a checkpoint",
+                    false,
+                    CHECKPOINTED,
+                ),
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            let message = "unable to run module";
+            let status = match exit_status(&e) {
+                // Modules that call `proc_exit` exit with its code
+                Some(code) => {
+                    info!("{} module exited with code {}", name, code);
+                    exited_status(code)
+                }
+                None => {
+                    error!("{} {}: {:?}", name, message, e);
+                    failure_status(message, memory_limit)
+                }
+            };
+            send(status_sender, name, status);
+
+            // The trap is kept, so that exit statuses can be told
+            // apart from other errors
+            return Err(e.context(message));
+        }
+    };
+
+    info!("{} module run complete", name);
+    // A module that completed has nothing to resume
+    if let Some(checkpointing) = &checkpointing {
+        checkpointing.remove();
+    }
+    send(
+        status_sender,
+        name,
+        Status::Terminated {
+            failed: false,
+            message: "Module run completed".into(),
+            timestamp: chrono::Utc::now(),
+            reason: None,
+            started_at: None,
+            exit_code: Some(0),
+        },
+    );
+    Ok(())
+}
+
+/// Returns the code the module exited with if `error` is the trap of a call
+/// to `proc_exit`
+pub(crate) fn exit_status(error: &anyhow::Error) -> Option<i32> {
+    error
+        .downcast_ref::<wasmtime::Trap>()
+        .and_then(|trap| trap.i32_exit_status())
+}
+
+/// Returns the status of a module that exited with `code`, which failed
+/// unless the code is 0
+fn exited_status(code: i32) -> Status {
+    Status::Terminated {
+        failed: code != 0,
+        message: format!("Module exited with code {}", code),
+        timestamp: chrono::Utc::now(),
+        reason: None,
+        started_at: None,
+        exit_code: Some(code),
     }
 }
 
-fn send(sender: &Sender<Status>, name: &str, status: Status) {
+/// Returns the status of a module that failed, reporting it as `OOMKilled` if
+/// it had tried to use more memory than its limit
+fn failure_status(message: &str, memory_limit: &MemoryLimit) -> Status {
+    if memory_limit.exceeded() {
+        Status::terminated_with_reason(
+            &format!(
+                "{}: module exceeded its memory limit of {} bytes",
+                message,
+                memory_limit.limit_bytes()
+            ),
+            true,
+            OOM_KILLED,
+        )
+    } else {
+        Status::terminated(message, true)
+    }
+}
+
+/// Preopens a directory that the module can read but not modify. The
+/// directory gets the next free descriptor, following any other preopened
+/// directories
+fn preopen_read_only_dir(ctx: &WasiCtx, host_dir: &Path, guest_dir: &Path) -> anyhow::Result<()> {
+    let dir = unsafe { cap_std::fs::Dir::open_ambient_dir(host_dir) }?;
+    let dir = ReadOnlyDir::new(Box::new(wasi_cap_std_sync::dir::Dir::from_cap_std(dir)));
+    let fd = {
+        let table = ctx.table();
+        (3..u32::MAX)
+            .find(|fd| !table.contains_key(*fd))
+            .ok_or_else(|| anyhow::anyhow!("no free descriptor for {}", guest_dir.display()))?
+    };
+    ctx.insert_dir(
+        fd,
+        Box::new(dir),
+        DirCaps::all(),
+        FileCaps::all(),
+        guest_dir.to_owned(),
+    );
+    Ok(())
+}
+
+/// Creates a function to link in place of a WASI function that the seccomp
+/// profile does not allow, which traps when it is called
+pub(crate) fn denied_function(
+    store: &wasmtime::Store,
+    module: &str,
+    name: &str,
+    ty: wasmtime::ExternType,
+) -> anyhow::Result<wasmtime::Extern> {
+    let ty = match ty {
+        wasmtime::ExternType::Func(ty) => ty,
+        _ => bail!("import `{}` in module `{}` is not a function", name, module),
+    };
+    let message = format!(
+        "call to WASI function `{}::{}` is not allowed by the seccomp profile",
+        module, name
+    );
+    let func = wasmtime::Func::new(store, ty, move |_, _, _| {
+        Err(wasmtime::Trap::new(message.clone()))
+    });
+    Ok(func.into())
+}
+
+pub(crate) fn send(sender: Option<&Sender<Status>>, name: &str, status: Status) {
+    let sender = match sender {
+        Some(sender) => sender,
+        None => return,
+    };
     match sender.blocking_send(status) {
         Err(e) => warn!("{} error sending wasi status: {:?}", name, e),
         Ok(_) => debug!("{} send completed.", name),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kubelet::cpu_manager::{CpuManager, CpuManagerPolicy};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    /// A module that echoes its input to its output until its input ends.
+    /// The iovec at 0 points at a buffer at 16, and the number of bytes read
+    /// is stored at 8
+    const ECHO_MODULE: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_read"
+            (func $fd_read (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")
+            (loop $echo
+              (i32.store (i32.const 0) (i32.const 16))
+              (i32.store (i32.const 4) (i32.const 1024))
+              (if (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))
+                (then return))
+              (if (i32.eqz (i32.load (i32.const 8)))
+                (then return))
+              (i32.store (i32.const 4) (i32.load (i32.const 8)))
+              (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+              (br $echo))))
+    "#;
+
+    /// A module that opens `app.conf` in the directory preopened at 3 for
+    /// reading and then for writing, and writes `read` and `written` to its
+    /// output for the opens that succeed. `path_open` stores the opened
+    /// descriptor at 16, and the iovec for output is at 0.
+    const WRITE_MODULE: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "path_open"
+            (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 100) "app.conf")
+          (data (i32.const 120) "read\n")
+          (data (i32.const 130) "written\n")
+          (func $print (param $offset i32) (param $len i32)
+            (i32.store (i32.const 0) (local.get $offset))
+            (i32.store (i32.const 4) (local.get $len))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+          (func (export "_start")
+            ;; Rights of FD_READ
+            (if (i32.eqz (call $path_open (i32.const 3) (i32.const 0) (i32.const 100) (i32.const 8)
+                  (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 16)))
+              (then (call $print (i32.const 120) (i32.const 5))))
+            ;; Rights of FD_WRITE, and the TRUNC open flag
+            (if (i32.eqz (call $path_open (i32.const 3) (i32.const 0) (i32.const 100) (i32.const 8)
+                  (i32.const 8) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 16)))
+              (then (call $print (i32.const 130) (i32.const 8))))))
+    "#;
+
+    /// A module whose `post-start` function returns and whose `failing`
+    /// function exits with 3
+    const HOOK_MODULE: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_start"))
+          (func (export "post-start"))
+          (func (export "failing") (call $proc_exit (i32.const 3))))
+    "#;
+
+    /// Creates the runtime of a container named `name` that runs `module`
+    /// with the directories `dirs`, and loads its module, with its log and
+    /// compiled module kept in `dir`
+    async fn load(
+        dir: &Path,
+        name: &str,
+        module: &str,
+        dirs: HashMap<PathBuf, Option<PathBuf>>,
+        read_only_dirs: HashSet<PathBuf>,
+    ) -> (WasiRuntime, wasmtime::Module) {
+        let (tx, _) = tokio::sync::mpsc::channel(8);
+        load_reporting_to(dir, name, module, dirs, read_only_dirs, None, None, tx).await
+    }
+
+    /// Like [`load`], but with the container's memory limited to
+    /// `memory_limit` bytes and the wasi-nn backend `wasi_nn` made available,
+    /// if set, and its statuses sent to `tx`
+    #[allow(clippy::too_many_arguments)]
+    async fn load_reporting_to(
+        dir: &Path,
+        name: &str,
+        module: &str,
+        dirs: HashMap<PathBuf, Option<PathBuf>>,
+        read_only_dirs: HashSet<PathBuf>,
+        memory_limit: Option<u64>,
+        wasi_nn: Option<WasiNnBackend>,
+        tx: tokio::sync::mpsc::Sender<Status>,
+    ) -> (WasiRuntime, wasmtime::Module) {
+        let runtime = WasiRuntime::new(
+            format!("default:{}:{}", name, name),
+            wat::parse_str(module).unwrap(),
+            HashMap::new(),
+            vec![],
+            dirs,
+            read_only_dirs,
+            wasi_nn,
+            WasiPolicy::allow_all(),
+            false,
+            RunAs::default(),
+            memory_limit,
+            None,
+            CpuManager::new(CpuManagerPolicy::None).unwrap().pod(name),
+            None,
+            None,
+            Isolation::Thread,
+            false,
+            None,
+            dir.join(format!("{}.log", name)),
+            1024 * 1024,
+            1,
+            tx,
+        )
+        .await
+        .expect("runtime should be created");
+        let cache = Arc::new(ModuleCache::new(dir.join("cache")));
+        let loaded = runtime
+            .load_module(cache)
+            .await
+            .expect("module should load");
+        (runtime, loaded.module)
+    }
+
+    /// Starts a container named `name` that runs `module` with the
+    /// directories `dirs`, with its log and compiled module kept in `dir`
+    async fn start_module(
+        dir: &Path,
+        name: &str,
+        module: &str,
+        dirs: HashMap<PathBuf, Option<PathBuf>>,
+        read_only_dirs: HashSet<PathBuf>,
+        stdin_once: bool,
+    ) -> (ContainerHandle<Runtime, HandleFactory>, Attachment) {
+        let (runtime, module) = load(dir, name, module, dirs, read_only_dirs).await;
+        runtime
+            .start(
+                module,
+                CpuScheduler::new(Duration::from_millis(100)),
+                true,
+                stdin_once,
+                None,
+            )
+            .await
+            .expect("module should start")
+    }
+
+    /// Starts a container that runs the echo module and reads stdin, with
+    /// its log and compiled module kept in `dir`
+    async fn start_echo(
+        dir: &Path,
+        stdin_once: bool,
+    ) -> (ContainerHandle<Runtime, HandleFactory>, Attachment) {
+        start_module(
+            dir,
+            "echo",
+            ECHO_MODULE,
+            HashMap::new(),
+            HashSet::new(),
+            stdin_once,
+        )
+        .await
+    }
+
+    /// Attaches a session to the container's stdin and stdout, returning the
+    /// client's ends of them
+    fn attach(
+        attachment: &Attachment,
+    ) -> (DuplexStream, DuplexStream, JoinHandle<anyhow::Result<()>>) {
+        let (stdin, container_stdin) = tokio::io::duplex(1024);
+        let (container_stdout, stdout) = tokio::io::duplex(1024);
+        let attachment = attachment.clone();
+        let session = tokio::spawn(async move {
+            attachment
+                .attach(
+                    Some(Box::new(container_stdin)),
+                    Some(Box::new(container_stdout)),
+                    None,
+                )
+                .await
+        });
+        (stdin, stdout, session)
+    }
+
+    async fn assert_echoes(stdin: &mut DuplexStream, stdout: &mut DuplexStream, line: &str) {
+        stdin.write_all(line.as_bytes()).await.unwrap();
+        let mut echoed = vec![0; line.len()];
+        timeout(Duration::from_secs(10), stdout.read_exact(&mut echoed))
+            .await
+            .expect("timed out waiting for the echo")
+            .unwrap();
+        assert_eq!(String::from_utf8(echoed).unwrap(), line);
+    }
+
+    #[tokio::test]
+    async fn attached_sessions_stream_through_the_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut handle, attachment) = start_echo(dir.path(), true).await;
+
+        let (mut stdin, mut stdout, session) = attach(&attachment);
+        assert_echoes(&mut stdin, &mut stdout, "hello\n").await;
+
+        // With stdinOnce, closing the session's input closes the module's
+        drop(stdin);
+        timeout(Duration::from_secs(10), handle.wait())
+            .await
+            .expect("module should exit once its input is closed")
+            .unwrap();
+        timeout(Duration::from_secs(10), session)
+            .await
+            .expect("session should end once the module exits")
+            .unwrap()
+            .unwrap();
+
+        // Output goes to the log too
+        let log = std::fs::read_to_string(dir.path().join("echo.log")).unwrap();
+        assert!(log.contains("hello"), "log should contain output: {}", log);
+    }
+
+    #[tokio::test]
+    async fn detaching_leaves_the_container_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut handle, attachment) = start_echo(dir.path(), false).await;
+
+        let (mut stdin, mut stdout, session) = attach(&attachment);
+        assert_echoes(&mut stdin, &mut stdout, "first\n").await;
+        session.abort();
+        let _ = session.await;
+        drop(stdin);
+
+        let (mut stdin, mut stdout, _session) = attach(&attachment);
+        assert_echoes(&mut stdin, &mut stdout, "second\n").await;
+
+        // Stopping closes the input the module is waiting for, so that it
+        // either sees the end of its input or is interrupted
+        handle.stop().await.unwrap();
+        let _ = timeout(Duration::from_secs(10), handle.wait())
+            .await
+            .expect("module should exit once stopped");
+    }
+
+    #[tokio::test]
+    async fn read_only_dirs_can_be_read_but_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        std::fs::create_dir(&config).unwrap();
+        std::fs::write(config.join("app.conf"), "level=debug").unwrap();
+
+        for (name, read_only, output) in &[
+            ("writable", false, vec!["read", "written"]),
+            ("read-only", true, vec!["read"]),
+        ] {
+            let dirs = vec![(config.clone(), Some(PathBuf::from("/config")))]
+                .into_iter()
+                .collect();
+            let read_only_dirs = if *read_only {
+                vec![config.clone()].into_iter().collect()
+            } else {
+                HashSet::new()
+            };
+            let (mut handle, _) =
+                start_module(dir.path(), name, WRITE_MODULE, dirs, read_only_dirs, false).await;
+            timeout(Duration::from_secs(10), handle.wait())
+                .await
+                .expect("module should exit")
+                .unwrap();
+            let log = std::fs::read_to_string(dir.path().join(format!("{}.log", name))).unwrap();
+            let lines: Vec<&str> = log
+                .lines()
+                .filter_map(|line| line.splitn(4, ' ').nth(3))
+                .collect();
+            assert_eq!(&lines, output);
+        }
+        // Only the writable directory's file was truncated
+        assert_eq!(
+            std::fs::read_to_string(config.join("app.conf")).unwrap(),
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_run_the_function_their_command_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime, module) = load(
+            dir.path(),
+            "hooks",
+            HOOK_MODULE,
+            HashMap::new(),
+            HashSet::new(),
+        )
+        .await;
+        let run = |command: &[&str]| {
+            runtime.run_hook(
+                module.clone(),
+                CpuScheduler::new(Duration::from_millis(100)),
+                command.iter().map(|arg| arg.to_string()).collect(),
+            )
+        };
+        assert_eq!(run(&["post-start"]).await.unwrap().0, 0);
+        assert_eq!(run(&["failing", "--now"]).await.unwrap().0, 3);
+        assert!(run(&["missing"]).await.is_err());
+        assert!(run(&[]).await.is_err());
+    }
+
+    /// Runs a container named `name` that runs `module` until it
+    /// terminates, returning the status it terminated with
+    async fn run_until_terminated(
+        dir: &Path,
+        name: &str,
+        module: &str,
+        memory_limit: Option<u64>,
+        wasi_nn: Option<WasiNnBackend>,
+    ) -> Status {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let (runtime, module) = load_reporting_to(
+            dir,
+            name,
+            module,
+            HashMap::new(),
+            HashSet::new(),
+            memory_limit,
+            wasi_nn,
+            tx,
+        )
+        .await;
+        let (mut handle, _) = runtime
+            .start(
+                module,
+                CpuScheduler::new(Duration::from_millis(100)),
+                false,
+                false,
+                None,
+            )
+            .await
+            .expect("module should start");
+        let terminated = timeout(Duration::from_secs(10), async {
+            while let Some(status) = rx.recv().await {
+                if let Status::Terminated { .. } = status {
+                    return Some(status);
+                }
+            }
+            None
+        })
+        .await
+        .expect("module should exit");
+        let _ = handle.wait().await;
+        terminated.expect("module should report that it terminated")
+    }
+
+    #[tokio::test]
+    async fn modules_that_exit_report_their_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        for (code, failed) in &[(0, false), (3, true)] {
+            let name = format!("exit-{}", code);
+            let module = format!(
+                r#"(module
+                     (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                     (memory (export "memory") 1)
+                     (func (export "_start") (call $proc_exit (i32.const {}))))"#,
+                code
+            );
+            match run_until_terminated(dir.path(), &name, &module, None, None).await {
+                Status::Terminated {
+                    exit_code,
+                    failed: module_failed,
+                    ..
+                } => {
+                    assert_eq!(exit_code, Some(*code), "{}", name);
+                    assert_eq!(module_failed, *failed, "{}", name);
+                }
+                other => panic!("{} reported {:?}", name, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_that_run_out_of_memory_are_oom_killed() {
+        let grow_or_trap = r#"(module
+                 (memory (export "memory") 1)
+                 (func (export "_start")
+                   (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1))
+                     (then unreachable))))"#;
+        let dir = tempfile::tempdir().unwrap();
+        // Twice the module's initial memory, so it can't grow by 16 pages
+        match run_until_terminated(
+            dir.path(),
+            "grow-or-trap",
+            grow_or_trap,
+            Some(128 * 1024),
+            None,
+        )
+        .await
+        {
+            Status::Terminated { failed, reason, .. } => {
+                assert!(failed);
+                assert_eq!(reason.as_deref(), Some(OOM_KILLED));
+            }
+            other => panic!("grow-or-trap reported {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_bounds_accesses_are_errors_of_the_module() {
+        // The module never tries to grow its memory, so it didn't run out of
+        // memory, whether it has a limit or not
+        let store_past_end = r#"(module
+                 (memory (export "memory") 1)
+                 (func (export "_start") (i32.store (i32.const 1048576) (i32.const 1))))"#;
+        let dir = tempfile::tempdir().unwrap();
+        for (name, memory_limit) in &[("limited", Some(128 * 1024)), ("unlimited", None)] {
+            match run_until_terminated(dir.path(), name, store_past_end, *memory_limit, None).await
+            {
+                Status::Terminated { failed, reason, .. } => {
+                    assert!(failed, "{}", name);
+                    assert_eq!(reason, None, "{}", name);
+                }
+                other => panic!("{} reported {:?}", name, other),
+            }
+        }
+    }
+
+    #[cfg(feature = "wasi-nn")]
+    #[tokio::test]
+    async fn modules_can_call_wasi_nn() {
+        // No graph was loaded, so the call should fail with an error code
+        // rather than trap, and the module exits with 1 if it succeeds
+        let init_execution_context = r#"(module
+                 (import "wasi_ephemeral_nn" "init_execution_context"
+                   (func $init_execution_context (param i32 i32) (result i32)))
+                 (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                 (memory (export "memory") 1)
+                 (func (export "_start")
+                   (if (i32.eqz (call $init_execution_context (i32.const 0) (i32.const 16)))
+                     (then (call $proc_exit (i32.const 1))))))"#;
+        let dir = tempfile::tempdir().unwrap();
+        match run_until_terminated(
+            dir.path(),
+            "wasi-nn",
+            init_execution_context,
+            None,
+            Some(WasiNnBackend::OpenVino),
+        )
+        .await
+        {
+            Status::Terminated {
+                exit_code, failed, ..
+            } => {
+                assert!(!failed);
+                assert_eq!(exit_code, Some(0));
+            }
+            other => panic!("wasi-nn reported {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,631 @@
+//! Inspection of WebAssembly binaries before they are handed to wasmtime
+
+/// The magic bytes at the start of every WebAssembly binary
+const WASM_MAGIC: &[u8] = b"\0asm";
+/// The layer field of the binary header identifying a component. Core modules
+/// use layer `0`
+const COMPONENT_LAYER: &[u8] = &[0x01, 0x00];
+/// The custom section id in the binary format
+const CUSTOM_SECTION_ID: u8 = 0;
+/// The import section id in the binary format
+const IMPORT_SECTION_ID: u8 = 2;
+/// The memory section id in the binary format
+const MEMORY_SECTION_ID: u8 = 5;
+/// The import kind of memories in the binary format
+const MEMORY_IMPORT_KIND: u8 = 0x02;
+/// The flag of memory limits marking a memory as shared between threads
+const SHARED_LIMITS_FLAG: u8 = 0x02;
+/// The import section id in the component binary format
+const COMPONENT_IMPORT_SECTION_ID: u8 = 10;
+/// WASI preview 2 interfaces are imported from namespaced modules such as
+/// `wasi:cli/environment@0.2.0`, while preview 1 uses `wasi_snapshot_preview1`
+const PREVIEW2_IMPORT_PREFIX: &str = "wasi:";
+/// Custom sections with names starting with this hold the component type of a
+/// core module that was built to be wrapped into a component
+const COMPONENT_TYPE_SECTION_PREFIX: &str = "component-type";
+/// The module and name of the function that `wasi-threads` modules import to
+/// start a thread
+const THREAD_SPAWN_IMPORT: (&str, &str) = ("wasi", "thread-spawn");
+
+/// What kind of WebAssembly binary some module data contains
+#[derive(Debug, PartialEq)]
+pub(crate) enum BinaryKind {
+    /// A core WebAssembly module
+    CoreModule,
+    /// A component, or a core module carrying a `component-type` section
+    /// that expects to be instantiated as a component
+    Component,
+    /// A core module that imports WASI preview 2 interfaces directly. The
+    /// names of the imported interfaces are included
+    Preview2Module(Vec<String>),
+    /// A core module that starts threads with `wasi-threads`, or that has a
+    /// memory shared between threads for atomics to synchronize
+    ThreadsModule,
+}
+
+/// Returns the module in `data` in the binary format.
+///
+/// Modules are usually binaries, but may also be in the WebAssembly text
+/// format, such as when a `.wat` file is mounted or pulled from a layer with
+/// the wasm text layer media type. Those are compiled to a binary here, so
+/// that they can be inspected like any other module and the module cache is
+/// keyed by the binary wasmtime compiles.
+pub(crate) fn module_binary(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if data.starts_with(WASM_MAGIC) {
+        return Ok(data);
+    }
+    wat::parse_bytes(&data)
+        .map(|binary| binary.into_owned())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "module is neither a WebAssembly binary nor valid WebAssembly text: {}",
+                e
+            )
+        })
+}
+
+/// Returns the kind of WebAssembly binary in `data`.
+///
+/// Data that is not a WebAssembly binary is treated as a core module so that
+/// wasmtime reports why it cannot be compiled.
+pub(crate) fn binary_kind(data: &[u8]) -> BinaryKind {
+    if !data.starts_with(WASM_MAGIC) || data.len() < 8 {
+        return BinaryKind::CoreModule;
+    }
+    if &data[6..8] == COMPONENT_LAYER {
+        return BinaryKind::Component;
+    }
+    let sections = sections(&data[8..]);
+    if sections
+        .iter()
+        .filter(|(id, _)| *id == CUSTOM_SECTION_ID)
+        .filter_map(|(_, contents)| read_name(contents))
+        .any(|name| name.starts_with(COMPONENT_TYPE_SECTION_PREFIX))
+    {
+        return BinaryKind::Component;
+    }
+    let imports: Vec<(String, String)> = sections
+        .iter()
+        .filter(|(id, _)| *id == IMPORT_SECTION_ID)
+        .flat_map(|(_, contents)| import_names(contents))
+        .collect();
+    let mut preview2_imports: Vec<String> = imports
+        .iter()
+        .map(|(module, _)| module)
+        .filter(|module| module.starts_with(PREVIEW2_IMPORT_PREFIX))
+        .cloned()
+        .collect();
+    if !preview2_imports.is_empty() {
+        preview2_imports.sort();
+        preview2_imports.dedup();
+        return BinaryKind::Preview2Module(preview2_imports);
+    }
+    if imports
+        .iter()
+        .any(|(module, name)| (module.as_str(), name.as_str()) == THREAD_SPAWN_IMPORT)
+        || has_shared_memory(&sections)
+    {
+        return BinaryKind::ThreadsModule;
+    }
+    BinaryKind::CoreModule
+}
+
+/// Returns an error if the module data is in a format that this provider's
+/// version of wasmtime is unable to run.
+pub(crate) fn ensure_runnable(data: &[u8]) -> anyhow::Result<()> {
+    match binary_kind(data) {
+        BinaryKind::CoreModule => Ok(()),
+        // wasmtime 0.24 has no component model support, so components can't
+        // be instantiated until we move to a version with
+        // `wasmtime::component`
+        BinaryKind::Component => Err(anyhow::anyhow!(
+            "module is a WebAssembly component, but this provider can only run core WebAssembly modules"
+        )),
+        // Only the preview 1 snapshots are implemented by wasmtime-wasi 0.24
+        BinaryKind::Preview2Module(interfaces) => Err(anyhow::anyhow!(
+            "module imports WASI preview 2 interfaces ({}), but this provider only implements WASI preview 1",
+            interfaces.join(", ")
+        )),
+        // Threads share their module's memory, but the memories of wasmtime
+        // 0.24 can't be used from stores on other threads, so wasi-threads
+        // can't be implemented until we move to a version with shared
+        // memories and `wasmtime-wasi-threads`
+        BinaryKind::ThreadsModule => Err(anyhow::anyhow!(
+            "module uses threads (wasi-threads or shared memory), but this provider can only run single-threaded modules"
+        )),
+    }
+}
+
+/// The modules that the provider resolves imports from
+const PROVIDED_IMPORT_MODULES: &[&str] = &[
+    "wasi_snapshot_preview1",
+    "wasi_unstable",
+    crate::checkpoint::CHECKPOINT_MODULE,
+];
+/// The module that wasi-nn functions are imported from
+const WASI_NN_IMPORT_MODULE: &str = "wasi_ephemeral_nn";
+
+/// Validates the module in `data` against the WebAssembly spec, with the
+/// proposals that wasmtime enables, and checks that its imports can be
+/// resolved, so that malformed and misconfigured modules fail before they are
+/// compiled with a clear error.
+///
+/// * `simd` - whether the module is compiled with SIMD enabled
+/// * `wasi_nn` - whether wasi-nn is made available to the module
+pub(crate) fn validate(data: &[u8], simd: bool, wasi_nn: bool) -> anyhow::Result<()> {
+    // The proposals that wasmtime 0.24 enables by default, and SIMD if the
+    // pod opted in
+    let features = wasmparser::WasmFeatures {
+        reference_types: true,
+        bulk_memory: true,
+        multi_value: true,
+        simd,
+        ..Default::default()
+    };
+    wasmparser::Validator::new()
+        .wasm_features(features)
+        .validate_all(data)
+        .map_err(|e| anyhow::anyhow!("module is not valid WebAssembly: {}", e))?;
+
+    for (module, name) in sections(&data[8..])
+        .iter()
+        .filter(|(id, _)| *id == IMPORT_SECTION_ID)
+        .flat_map(|(_, contents)| import_names(contents))
+    {
+        if module == WASI_NN_IMPORT_MODULE {
+            if !wasi_nn {
+                return Err(anyhow::anyhow!(
+                    "module imports '{}::{}' but wasi-nn is not enabled. Request a backend with the {} annotation",
+                    module,
+                    name,
+                    crate::wasi_nn::WASI_NN_BACKEND_ANNOTATION
+                ));
+            }
+        } else if !PROVIDED_IMPORT_MODULES.contains(&module.as_str()) {
+            return Err(anyhow::anyhow!(
+                "module imports '{}::{}' but this provider only provides imports from {}",
+                module,
+                name,
+                PROVIDED_IMPORT_MODULES.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the names of the interfaces a component imports, such as
+/// `wasi:cli/environment@0.2.0`, whether it is a component or a core module
+/// built to be wrapped into one. Other binaries import no interfaces.
+pub(crate) fn imported_interfaces(data: &[u8]) -> Vec<String> {
+    let mut interfaces: Vec<String> = match binary_kind(data) {
+        BinaryKind::Component if &data[6..8] == COMPONENT_LAYER => sections(&data[8..])
+            .iter()
+            .filter(|(id, _)| *id == COMPONENT_IMPORT_SECTION_ID)
+            .flat_map(|(_, contents)| component_import_names(contents))
+            .filter(|name| name.contains(':'))
+            .collect(),
+        // The core module's imports are named after the interfaces they
+        // come from, as with modules importing preview 2 interfaces directly
+        BinaryKind::Component | BinaryKind::Preview2Module(_) => sections(&data[8..])
+            .iter()
+            .filter(|(id, _)| *id == IMPORT_SECTION_ID)
+            .flat_map(|(_, contents)| import_names(contents))
+            .map(|(module, _)| module)
+            .filter(|module| module.starts_with(PREVIEW2_IMPORT_PREFIX))
+            .collect(),
+        BinaryKind::CoreModule | BinaryKind::ThreadsModule => vec![],
+    };
+    interfaces.sort();
+    interfaces.dedup();
+    interfaces
+}
+
+/// Splits module sections into their ids and contents. Parsing stops at the
+/// first malformed section.
+fn sections(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut sections = vec![];
+    while let Some((&id, rest)) = data.split_first() {
+        let (size, rest) = match read_u32(rest) {
+            Some(r) => r,
+            None => break,
+        };
+        let size = size as usize;
+        if rest.len() < size {
+            break;
+        }
+        let (contents, rest) = rest.split_at(size);
+        sections.push((id, contents));
+        data = rest;
+    }
+    sections
+}
+
+/// Returns whether the module defines or imports a memory that is shared
+/// between threads
+fn has_shared_memory(sections: &[(u8, &[u8])]) -> bool {
+    let imported = sections
+        .iter()
+        .filter(|(id, _)| *id == IMPORT_SECTION_ID)
+        .flat_map(|(_, contents)| imports(contents))
+        .any(|(_, _, desc)| {
+            desc.first() == Some(&MEMORY_IMPORT_KIND)
+                && desc
+                    .get(1)
+                    .is_some_and(|flags| flags & SHARED_LIMITS_FLAG != 0)
+        });
+    imported
+        || sections
+            .iter()
+            .filter(|(id, _)| *id == MEMORY_SECTION_ID)
+            .any(|(_, contents)| defines_shared_memory(contents))
+}
+
+/// Returns whether a memory section defines a shared memory. Parsing stops at
+/// the first malformed memory.
+fn defines_shared_memory(contents: &[u8]) -> bool {
+    let (count, mut data) = match read_u32(contents) {
+        Some(r) => r,
+        None => return false,
+    };
+    for _ in 0..count {
+        match data.first() {
+            Some(flags) if flags & SHARED_LIMITS_FLAG != 0 => return true,
+            Some(_) => (),
+            None => break,
+        }
+        data = match skip_limits(data) {
+            Some(rest) => rest,
+            None => break,
+        };
+    }
+    false
+}
+
+/// Returns the module and field names of the imports in an import section.
+/// Parsing stops at the first malformed import.
+fn import_names(contents: &[u8]) -> Vec<(String, String)> {
+    imports(contents)
+        .into_iter()
+        .map(|(module, name, _)| (module, name))
+        .collect()
+}
+
+/// Returns the module and field names of the imports in an import section,
+/// with the bytes describing each import. Parsing stops at the first
+/// malformed import.
+fn imports(contents: &[u8]) -> Vec<(String, String, &[u8])> {
+    let mut names = vec![];
+    let (count, mut data) = match read_u32(contents) {
+        Some(r) => r,
+        None => return names,
+    };
+    for _ in 0..count {
+        let (module, rest) = match read_name_with_rest(data) {
+            Some(r) => r,
+            None => break,
+        };
+        let (name, desc) = match read_name_with_rest(rest) {
+            Some(r) => r,
+            None => break,
+        };
+        let rest = match skip_import_desc(desc) {
+            Some(rest) => rest,
+            None => break,
+        };
+        names.push((module, name, &desc[..desc.len() - rest.len()]));
+        data = rest;
+    }
+    names
+}
+
+/// Returns the names of the imports in a component import section. Parsing
+/// stops at the first malformed import.
+fn component_import_names(contents: &[u8]) -> Vec<String> {
+    let mut names = vec![];
+    let (count, mut data) = match read_u32(contents) {
+        Some(r) => r,
+        None => return names,
+    };
+    for _ in 0..count {
+        // Names are preceded by 0x00, or by 0x01 in older binaries that
+        // follow them with a version suffix
+        let (name, rest) = match data.split_first() {
+            Some((0x00, rest)) => match read_name_with_rest(rest) {
+                Some(r) => r,
+                None => break,
+            },
+            Some((0x01, rest)) => match read_name_with_rest(rest)
+                .and_then(|(name, rest)| Some((name, read_name_with_rest(rest)?.1)))
+            {
+                Some(r) => r,
+                None => break,
+            },
+            _ => break,
+        };
+        let rest = match skip_extern_desc(rest) {
+            Some(rest) => rest,
+            None => break,
+        };
+        names.push(name);
+        data = rest;
+    }
+    names
+}
+
+/// Skips over the description of a component import, returning the
+/// remaining bytes
+fn skip_extern_desc(data: &[u8]) -> Option<&[u8]> {
+    let (&sort, rest) = data.split_first()?;
+    match sort {
+        // core module: core sort followed by a type index
+        0x00 => read_u32(rest.get(1..)?).map(|(_, rest)| rest),
+        // function, component or instance: type index
+        0x01 | 0x04 | 0x05 => read_u32(rest).map(|(_, rest)| rest),
+        // value: a primitive type or a type index, as a signed LEB128
+        0x02 => read_leb(rest, 5).map(|(_, rest)| rest),
+        // type: equal to a type index, or a resource
+        0x03 => match rest.split_first()? {
+            (0x00, rest) => read_u32(rest).map(|(_, rest)| rest),
+            (0x01, rest) => Some(rest),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Skips over the description of an import, returning the remaining bytes
+fn skip_import_desc(data: &[u8]) -> Option<&[u8]> {
+    let (&kind, rest) = data.split_first()?;
+    match kind {
+        // function: type index
+        0x00 => read_u32(rest).map(|(_, rest)| rest),
+        // table: reference type followed by limits
+        0x01 => skip_limits(rest.get(1..)?),
+        // memory: limits
+        0x02 => skip_limits(rest),
+        // global: value type and mutability
+        0x03 => rest.get(2..),
+        // tag: attribute and type index
+        0x04 => read_u32(rest.get(1..)?).map(|(_, rest)| rest),
+        _ => None,
+    }
+}
+
+fn skip_limits(data: &[u8]) -> Option<&[u8]> {
+    let (&flags, rest) = data.split_first()?;
+    let rest = read_u64(rest)?.1;
+    if flags & 0x01 != 0 {
+        read_u64(rest).map(|(_, rest)| rest)
+    } else {
+        Some(rest)
+    }
+}
+
+/// Reads a length-prefixed name, such as the name of a custom section
+fn read_name(data: &[u8]) -> Option<String> {
+    read_name_with_rest(data).map(|(name, _)| name)
+}
+
+fn read_name_with_rest(data: &[u8]) -> Option<(String, &[u8])> {
+    let (len, rest) = read_u32(data)?;
+    let len = len as usize;
+    let name = rest.get(..len)?;
+    Some((String::from_utf8_lossy(name).into_owned(), &rest[len..]))
+}
+
+/// Reads an unsigned LEB128 encoded u32, returning it with the remaining bytes
+fn read_u32(data: &[u8]) -> Option<(u32, &[u8])> {
+    read_leb(data, 5).map(|(v, rest)| (v as u32, rest))
+}
+
+/// Reads an unsigned LEB128 encoded u64, returning it with the remaining bytes
+fn read_u64(data: &[u8]) -> Option<(u64, &[u8])> {
+    read_leb(data, 10)
+}
+
+fn read_leb(data: &[u8], max_bytes: usize) -> Option<(u64, &[u8])> {
+    let mut result: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(max_bytes) {
+        result |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((result, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_modules_are_compiled_to_binaries() {
+        let binary = module_binary(b"(module (func (export \"_start\")))".to_vec()).unwrap();
+        assert!(binary.starts_with(WASM_MAGIC));
+        assert_eq!(binary_kind(&binary), BinaryKind::CoreModule);
+        // Binaries are passed through untouched
+        assert_eq!(module_binary(binary.clone()).unwrap(), binary);
+        assert!(module_binary(b"not a module".to_vec()).is_err());
+    }
+
+    fn binary(text: &str) -> Vec<u8> {
+        wat::parse_str(text).unwrap()
+    }
+
+    /// A component importing the named instances, built by hand as wat
+    /// doesn't support the component model yet
+    fn component(imports: &[&str]) -> Vec<u8> {
+        let mut section = vec![imports.len() as u8];
+        for (i, name) in imports.iter().enumerate() {
+            section.push(0x00);
+            section.push(name.len() as u8);
+            section.extend_from_slice(name.as_bytes());
+            section.extend_from_slice(&[0x05, i as u8]);
+        }
+        let mut data = b"\0asm\x0d\x00\x01\x00".to_vec();
+        data.push(COMPONENT_IMPORT_SECTION_ID);
+        data.push(section.len() as u8);
+        data.extend(section);
+        data
+    }
+
+    /// `module` with a custom section named `name` appended
+    fn with_custom_section(mut module: Vec<u8>, name: &str) -> Vec<u8> {
+        let mut section = vec![name.len() as u8];
+        section.extend_from_slice(name.as_bytes());
+        section.extend_from_slice(b"payload");
+        module.push(CUSTOM_SECTION_ID);
+        module.push(section.len() as u8);
+        module.extend(section);
+        module
+    }
+
+    #[test]
+    fn components_are_rejected() {
+        let data = component(&["wasi:cli/environment@0.2.0"]);
+        assert_eq!(binary_kind(&data), BinaryKind::Component);
+        let err = ensure_runnable(&data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "module is a WebAssembly component, but this provider can only run core WebAssembly modules"
+        );
+    }
+
+    #[test]
+    fn modules_with_a_component_type_section_are_components() {
+        let module = binary(r#"(module (func (export "_start")))"#);
+        let wrapped = with_custom_section(module.clone(), "component-type:hello");
+        assert_eq!(binary_kind(&wrapped), BinaryKind::Component);
+        assert!(ensure_runnable(&wrapped).is_err());
+
+        // Other custom sections, such as names, are left alone
+        let named = with_custom_section(module, "name");
+        assert_eq!(binary_kind(&named), BinaryKind::CoreModule);
+        ensure_runnable(&named).unwrap();
+    }
+
+    #[test]
+    fn modules_importing_preview2_interfaces_are_rejected() {
+        let module = binary(
+            r#"(module
+                (import "wasi:io/streams@0.2.0" "write" (func (param i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (import "wasi:cli/stdout@0.2.0" "get-stdout" (func (result i32)))
+                (import "wasi:io/streams@0.2.0" "read" (func (param i32))))"#,
+        );
+        assert_eq!(
+            binary_kind(&module),
+            BinaryKind::Preview2Module(vec![
+                "wasi:cli/stdout@0.2.0".to_owned(),
+                "wasi:io/streams@0.2.0".to_owned(),
+            ])
+        );
+        let err = ensure_runnable(&module).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "module imports WASI preview 2 interfaces (wasi:cli/stdout@0.2.0, wasi:io/streams@0.2.0), but this provider only implements WASI preview 1"
+        );
+
+        // Preview 1 modules import from modules without a namespace
+        let module =
+            binary(r#"(module (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))"#);
+        assert_eq!(binary_kind(&module), BinaryKind::CoreModule);
+    }
+
+    #[test]
+    fn modules_using_threads_are_rejected() {
+        let spawning = binary(
+            r#"(module
+                (import "wasi" "thread-spawn" (func (param i32) (result i32)))
+                (memory 1))"#,
+        );
+        let sharing = binary(
+            r#"(module
+                (memory 1 1 shared)
+                (func (export "_start") (drop (i32.atomic.load (i32.const 0)))))"#,
+        );
+        let importing = binary(r#"(module (import "env" "memory" (memory 1 1 shared)))"#);
+        for module in &[spawning, sharing, importing] {
+            assert_eq!(binary_kind(module), BinaryKind::ThreadsModule);
+            let err = ensure_runnable(module).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "module uses threads (wasi-threads or shared memory), but this provider can only run single-threaded modules"
+            );
+        }
+
+        // Memories that aren't shared are left alone, whatever their limits
+        let module =
+            binary(r#"(module (import "env" "memory" (memory 1 2)) (memory 1) (memory 1 4))"#);
+        assert_eq!(binary_kind(&module), BinaryKind::CoreModule);
+    }
+
+    #[test]
+    fn interfaces_imported_by_components_are_listed() {
+        let data = component(&[
+            "wasi:io/streams@0.2.0",
+            "wasi:cli/environment@0.2.0",
+            "wasi:io/streams@0.2.0",
+        ]);
+        assert_eq!(binary_kind(&data), BinaryKind::Component);
+        assert_eq!(
+            imported_interfaces(&data),
+            vec!["wasi:cli/environment@0.2.0", "wasi:io/streams@0.2.0"]
+        );
+
+        let module =
+            binary(r#"(module (import "wasi:cli/stdout@0.2.0" "get-stdout" (func (result i32))))"#);
+        assert_eq!(imported_interfaces(&module), vec!["wasi:cli/stdout@0.2.0"]);
+
+        let module =
+            binary(r#"(module (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))"#);
+        assert!(imported_interfaces(&module).is_empty());
+    }
+
+    #[test]
+    fn valid_modules_pass_validation() {
+        let module = binary(
+            r#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (func (export "_start")))"#,
+        );
+        validate(&module, false, false).unwrap();
+    }
+
+    #[test]
+    fn malformed_modules_fail_validation() {
+        // Truncated in the middle of the function
+        let module = binary(r#"(module (func (export "_start") (drop (i32.const 1))))"#);
+        let err = validate(&module[..module.len() - 2], false, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("module is not valid WebAssembly"));
+    }
+
+    #[test]
+    fn simd_instructions_need_simd() {
+        let module = binary(r#"(module (func (export "_start") (drop (v128.const i64x2 0 0))))"#);
+        assert!(validate(&module, false, false).is_err());
+        validate(&module, true, false).unwrap();
+    }
+
+    #[test]
+    fn imports_must_be_provided() {
+        let module = binary(
+            r#"(module
+                (import "wasi_ephemeral_nn" "load" (func (param i32 i32 i32 i32 i32) (result i32))))"#,
+        );
+        let err = validate(&module, false, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("module imports 'wasi_ephemeral_nn::load' but wasi-nn is not enabled"),
+            "{}",
+            err
+        );
+        validate(&module, false, true).unwrap();
+
+        let module = binary(r#"(module (import "env" "print" (func)))"#);
+        let err = validate(&module, false, false).unwrap_err();
+        assert!(err.to_string().starts_with("module imports 'env::print'"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Property tests of the wasi provider's pod state machine, checking that it
+//! keeps to the graph its states list with random pods.
+
+use std::sync::Arc;
+
+use krator::ObjectStatus;
+use kubelet::config::Config;
+use kubelet::device_plugin_manager::DevicePluginManager;
+use kubelet::plugin_watcher::PluginRegistry;
+use kubelet::pod::{Pod, Status};
+use kubelet::provider::Provider;
+use kubelet::state::common::registered::Registered;
+use kubelet::state::common::GenericProvider;
+use kubelet::state::testing::{check_status, StateProperties, TestStateRunner};
+use proptest::prelude::*;
+use proptest::test_runner::{Config as ProptestConfig, TestRunner};
+use wasi_provider::WasiProvider;
+
+const WASI_SOCKETS_ANNOTATION: &str = "krustlet.dev/wasi-sockets";
+const WASI_HTTP_ANNOTATION: &str = "krustlet.dev/wasi-http";
+
+/// An API server that can't be reached, so that states that would call it
+/// carry on without it
+fn kubeconfig() -> kube::Config {
+    kube::Config::new("http://127.0.0.1:1".parse().unwrap())
+}
+
+async fn provider(data_dir: &std::path::Path) -> WasiProvider {
+    let config = Config {
+        data_dir: data_dir.to_owned(),
+        plugins_dir: data_dir.join("plugins"),
+        device_plugins_dir: data_dir.join("device_plugins"),
+        ..Default::default()
+    };
+    let device_plugin_manager = DevicePluginManager::new(
+        &config.device_plugins_dir,
+        kube::Client::new(kubeconfig()),
+        &config.node_name,
+    );
+    WasiProvider::new(
+        Arc::new(kubelet::store::fs::FileSystemStore {}),
+        &config,
+        kubeconfig(),
+        Arc::new(PluginRegistry::new(&config.plugins_dir)),
+        Arc::new(device_plugin_manager),
+    )
+    .await
+    .expect("provider should be created")
+}
+
+fn properties(provider: &WasiProvider) -> StateProperties<<WasiProvider as Provider>::PodState> {
+    StateProperties::new::<Registered<WasiProvider>>(TestStateRunner::with_shared(
+        provider.provider_state(),
+    ))
+}
+
+/// Pods with random containers and annotations, some of which ask for
+/// capabilities the provider doesn't have or run images it can't
+fn pods() -> impl Strategy<Value = Pod> {
+    let capability = proptest::option::of(prop_oneof![
+        Just("true"),
+        Just("false"),
+        Just("TRUE"),
+        Just("yes")
+    ]);
+    let image = prop_oneof![
+        Just("webassembly.azurecr.io/hello-wasm:v1"),
+        Just("fs/./module.wasm"),
+        Just("k8s.gcr.io/kube-proxy:v1.20.0"),
+    ];
+    (
+        "[a-z][a-z0-9-]{0,15}",
+        capability.clone(),
+        capability,
+        proptest::collection::btree_map("example.com/[a-z]{1,8}", "[a-z0-9]{0,8}", 0..3),
+        proptest::collection::vec(("[a-z][a-z0-9-]{0,7}", image), 1..4),
+    )
+        .prop_map(|(name, sockets, http, mut annotations, containers)| {
+            if let Some(sockets) = sockets {
+                annotations.insert(WASI_SOCKETS_ANNOTATION.to_owned(), sockets.to_owned());
+            }
+            if let Some(http) = http {
+                annotations.insert(WASI_HTTP_ANNOTATION.to_owned(), http.to_owned());
+            }
+            let containers: Vec<_> = containers
+                .into_iter()
+                .enumerate()
+                .map(|(i, (name, image))| {
+                    serde_json::json!({ "name": format!("{}-{}", name, i), "image": image })
+                })
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": {
+                    "name": name,
+                    "namespace": "default",
+                    "annotations": annotations,
+                },
+                "spec": { "containers": containers },
+            }))
+            .expect("should be a valid pod")
+        })
+}
+
+#[test]
+fn the_state_graph_reaches_the_wasi_states() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let provider = runtime.block_on(provider(data_dir.path()));
+    let properties = properties(&provider);
+
+    let states = properties.states();
+    for state in &[
+        "Registered",
+        "VolumeMount",
+        "Initializing",
+        "Starting",
+        "Running",
+        "Completed",
+        "UnsupportedInterfaces",
+    ] {
+        assert!(states.contains(state), "{} is not in {:?}", state, states);
+    }
+    assert_eq!(properties.transitions("Completed"), Some(&[][..]));
+    assert!(properties
+        .check_transition("VolumeMount", "Initializing")
+        .is_ok());
+    assert!(properties.check_transition("Running", "Running").is_err());
+}
+
+#[test]
+fn walks_of_the_state_graph_keep_to_it() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let provider = runtime.block_on(provider(data_dir.path()));
+    let properties = properties(&provider);
+
+    TestRunner::default()
+        .run(&properties.walks(), |walk| {
+            prop_assert_eq!(walk[0], "Registered");
+            properties.check_run(&walk)?;
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn registered_pods_are_validated() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let provider = runtime.block_on(provider(data_dir.path()));
+    let properties = properties(&provider).with_max_steps(1);
+
+    TestRunner::new(ProptestConfig::with_cases(32))
+        .run(&pods(), |pod| {
+            let run = runtime.block_on(async {
+                let mut pod_state = provider.initialize_pod_state(&pod).await.unwrap();
+                properties
+                    .check(Registered::<WasiProvider>::default(), &mut pod_state, &pod)
+                    .await
+            })?;
+            let expected = match WasiProvider::validate_pod_and_containers_runnable(&pod) {
+                Ok(()) => "ImagePull",
+                Err(_) => "Error",
+            };
+            prop_assert_eq!(run, vec!["Registered", expected]);
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn failed_statuses_are_json_objects() {
+    TestRunner::default()
+        .run(&".*", |message: String| {
+            check_status("Error", &Status::failed(&message))?;
+            Ok(())
+        })
+        .unwrap();
+}
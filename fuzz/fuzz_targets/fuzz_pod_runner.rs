@@ -0,0 +1,195 @@
+//! Fuzzes the generic pod states with arbitrary pods.
+//!
+//! Each input is parsed as a JSON pod and run through the state machine of
+//! a mock provider, from `Registered` until it completes or makes
+//! [`MAX_STEPS`] transitions. The mock provider pulls empty modules without
+//! a registry, never backs off, and completes pods as soon as they would
+//! run. Its client talks to an API server that can't be reached, so calls to
+//! it fail straight away. Runs that panic, leave the state graph or report
+//! statuses that aren't JSON objects are crashes.
+//!
+//! ```console
+//! $ cargo +nightly fuzz run fuzz_pod_runner
+//! ```
+#![no_main]
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use krator::Transitions;
+use kubelet::container::{Container, PullPolicy};
+use kubelet::pod::state::prelude::*;
+use kubelet::state::common::registered::Registered;
+use kubelet::state::common::{
+    BackoffSequence, GenericPodState, GenericProvider, GenericProviderState, ThresholdTrigger,
+};
+use kubelet::state::testing::{StateProperties, TestStateRunner};
+use kubelet::store::Store;
+use libfuzzer_sys::fuzz_target;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+/// The most transitions a pod's state machine makes before the run stops.
+/// Pods that fail are retried forever, so runs don't always complete.
+const MAX_STEPS: usize = 32;
+
+lazy_static::lazy_static! {
+    static ref RUNTIME: tokio::runtime::Runtime =
+        tokio::runtime::Runtime::new().expect("runtime should start");
+    static ref VOLUME_DIR: tempfile::TempDir =
+        tempfile::tempdir().expect("volume directory should be created");
+}
+
+/// Pulls an empty module for every image, without a registry
+struct MockStore;
+
+#[async_trait::async_trait]
+impl Store for MockStore {
+    async fn get(
+        &self,
+        _image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(vec![])
+    }
+}
+
+struct MockProviderState {
+    kubeconfig: kube::Config,
+    volume_path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl GenericProviderState for MockProviderState {
+    fn client(&self) -> kube::Client {
+        kube::Client::new(self.kubeconfig.clone())
+    }
+
+    fn store(&self) -> Arc<dyn Store + Sync + Send> {
+        Arc::new(MockStore)
+    }
+
+    fn volume_path(&self) -> PathBuf {
+        self.volume_path.clone()
+    }
+
+    async fn stop(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MockPodState {
+    errors: usize,
+}
+
+#[async_trait::async_trait]
+impl ObjectState for MockPodState {
+    type Manifest = Pod;
+    type Status = PodStatus;
+    type SharedState = MockProviderState;
+
+    async fn async_drop(self, _provider_state: &mut MockProviderState) {}
+}
+
+#[async_trait::async_trait]
+impl GenericPodState for MockPodState {
+    async fn set_modules(&mut self, _modules: HashMap<String, Vec<u8>>) {}
+
+    async fn set_volumes(&mut self, _volumes: HashMap<String, kubelet::volume::Ref>) {}
+
+    async fn backoff(&mut self, _sequence: BackoffSequence) {}
+
+    async fn reset_backoff(&mut self, _sequence: BackoffSequence) {}
+
+    async fn record_error(&mut self) -> ThresholdTrigger {
+        self.errors += 1;
+        if self.errors > 3 {
+            self.errors = 0;
+            ThresholdTrigger::Triggered
+        } else {
+            ThresholdTrigger::Untriggered
+        }
+    }
+}
+
+/// Completes pods as soon as they would run
+#[derive(Default, Debug, Transitions)]
+struct Running;
+
+#[async_trait::async_trait]
+impl State<MockPodState> for Running {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<MockProviderState>,
+        _pod_state: &mut MockPodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<MockPodState> {
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut MockPodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status(Phase::Succeeded, "Completed"))
+    }
+}
+
+struct MockProvider;
+
+impl GenericProvider for MockProvider {
+    type ProviderState = MockProviderState;
+    type PodState = MockPodState;
+    type RunState = Running;
+
+    fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn validate_container_runnable(container: &Container) -> anyhow::Result<()> {
+        container.image()?;
+        Ok(())
+    }
+}
+
+/// Parses `data` as a pod the API server could have scheduled to the node.
+/// Pods always have a name, and those with host path volumes are skipped,
+/// as mounting them would write to the fuzzing host.
+fn pod(data: &[u8]) -> Option<Pod> {
+    let pod: KubePod = serde_json::from_slice(data).ok()?;
+    pod.metadata.name.as_ref()?;
+    let host_paths = pod
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.volumes.as_ref())
+        .map(|volumes| volumes.iter().any(|volume| volume.host_path.is_some()))
+        .unwrap_or(false);
+    if host_paths {
+        return None;
+    }
+    Some(Pod::from(pod))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let pod = match pod(data) {
+        Some(pod) => pod,
+        None => return,
+    };
+    RUNTIME.block_on(async {
+        let provider_state = MockProviderState {
+            kubeconfig: kube::Config::new("http://127.0.0.1:1".parse().unwrap()),
+            volume_path: VOLUME_DIR.path().to_owned(),
+        };
+        let runner = TestStateRunner::new(provider_state);
+        let properties =
+            StateProperties::new::<Registered<MockProvider>>(runner).with_max_steps(MAX_STEPS);
+        let mut pod_state = MockPodState::default();
+        if let Err(violation) = properties
+            .check(Registered::<MockProvider>::default(), &mut pod_state, &pod)
+            .await
+        {
+            panic!("{}", violation);
+        }
+    });
+});
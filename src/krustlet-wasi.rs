@@ -1,16 +1,45 @@
 use kubelet::config::Config;
-use kubelet::plugin_watcher::PluginRegistry;
+use kubelet::device_plugin_manager::DevicePluginManager;
+use kubelet::plugin_watcher::{PluginRegistry, PluginType};
+use kubelet::state::common::registered::Registered;
+use kubelet::state::common::terminated::Terminated;
+use kubelet::state::record;
 use kubelet::store::composite::ComposableStore;
 use kubelet::store::oci::FileStore;
 use kubelet::Kubelet;
+use oci_distribution::client::ClientConfigSource;
 use std::sync::Arc;
 use wasi_provider::WasiProvider;
 
+/// `krustlet-wasi import <PATH> [FLAGS]` imports the images in an OCI image
+/// layout or image tarball into the module store and exits
+const IMPORT_COMMAND: &str = "import";
+
+/// `krustlet-wasi replay <FILE>` replays the states recorded with
+/// `--record-transitions` through the current state machine and exits
+const REPLAY_COMMAND: &str = "replay";
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
+    let mut args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some(IMPORT_COMMAND) {
+        if args.len() < 3 {
+            anyhow::bail!("usage: krustlet-wasi import <PATH> [FLAGS]");
+        }
+        let path = args.remove(2);
+        args.remove(1);
+        return import(path, args).await;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some(REPLAY_COMMAND) {
+        if args.len() != 3 {
+            anyhow::bail!("usage: krustlet-wasi replay <FILE>");
+        }
+        return replay(&args[2]).await;
+    }
+
     // The provider is responsible for all the "back end" logic. If you are creating
     // a new Kubelet, all you need to implement is a provider.
-    let config = Config::new_from_file_and_flags(env!("CARGO_PKG_VERSION"), None);
+    let config = Config::new_from_file_and_args(env!("CARGO_PKG_VERSION"), None, args);
 
     // Initialize the logger
     env_logger::init();
@@ -18,18 +47,83 @@ async fn main() -> anyhow::Result<()> {
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
     let store = make_store(&config);
-    let plugin_registry = Arc::new(PluginRegistry::new(&config.plugins_dir));
+    let device_plugin_manager = Arc::new(DevicePluginManager::new(
+        &config.device_plugins_dir,
+        kube::Client::new(kubeconfig.clone()),
+        &config.node_name,
+    ));
+    let plugin_registry = Arc::new(
+        PluginRegistry::new(&config.plugins_dir)
+            .with_handler(PluginType::DevicePlugin, device_plugin_manager.clone()),
+    );
 
-    let provider = WasiProvider::new(store, &config, kubeconfig.clone(), plugin_registry).await?;
+    let provider = WasiProvider::new(
+        store,
+        &config,
+        kubeconfig.clone(),
+        plugin_registry,
+        device_plugin_manager,
+    )
+    .await?;
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
-    let client = oci_distribution::Client::from_source(config);
+/// Imports the images at `path` into the module store of the node configured
+/// by `args`, so that pods can use them without pulling from a registry
+async fn import(path: String, args: Vec<String>) -> anyhow::Result<()> {
+    let config = Config::new_from_file_and_args(env!("CARGO_PKG_VERSION"), None, args);
+    env_logger::init();
+
+    let references = make_file_store(&config).import_oci_layout(&path).await?;
+    for reference in references {
+        println!("Imported {}", reference);
+    }
+    Ok(())
+}
+
+/// Replays the states recorded in the file at `path` for each pod through
+/// the WASI provider's state machine, and fails if any pod made a
+/// transition that the state machine no longer makes
+async fn replay(path: &str) -> anyhow::Result<()> {
+    let recording = record::read_recording(path)?;
+    let replays =
+        record::replay::<Registered<WasiProvider>, Terminated<WasiProvider>>(&recording).await;
+    let mut failed = 0;
+    for replay in &replays {
+        let result = match &replay.result {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => {
+                failed += 1;
+                format!("failed: {}", e)
+            }
+        };
+        println!(
+            "{}: {} (spec changed {} times): {}",
+            replay.pod,
+            replay.states.join(" -> "),
+            replay.spec_changes,
+            result
+        );
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} pods failed to replay", failed, replays.len());
+    }
+    Ok(())
+}
+
+fn make_file_store(config: &Config) -> FileStore<oci_distribution::Client> {
+    let mut client_config = config.client_config();
+    client_config.platforms = wasi_provider::supported_platforms();
+    client_config.accepted_media_types = wasi_provider::supported_media_types();
+    let client = oci_distribution::Client::new(client_config);
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
+    FileStore::new(client, &store_path)
+}
+
+fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
+    let file_store = Arc::new(make_file_store(config));
 
     if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
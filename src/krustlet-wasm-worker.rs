@@ -0,0 +1,7 @@
+// Runs a module for krustlet-wasi in a process of its own, for pods with the
+// krustlet.dev/isolate annotation. It is started by the provider, which talks
+// to it over the socket it passes as descriptor 3, and isn't meant to be run
+// by hand.
+fn main() {
+    std::process::exit(wasi_provider::run_worker())
+}
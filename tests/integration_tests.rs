@@ -20,13 +20,17 @@ fn in_ci_environment() -> bool {
 
 async fn verify_wasi_node(node: Node) {
     let node_status = node.status.expect("node reported no status");
-    assert_eq!(
-        node_status
-            .node_info
-            .expect("node reported no information")
-            .architecture,
-        "wasm-wasi",
-        "expected node to support the wasm-wasi architecture"
+    let node_info = node_status.node_info.expect("node reported no information");
+    assert!(
+        node_info
+            .container_runtime_version
+            .starts_with("mvp://krustlet-wasi-"),
+        "expected node to report the krustlet-wasi runtime, got {}",
+        node_info.container_runtime_version
+    );
+    assert!(
+        !node_info.architecture.is_empty() && !node_info.operating_system.is_empty(),
+        "expected node to report the architecture and OS of its host"
     );
 
     let node_meta = node.metadata;
@@ -85,6 +89,8 @@ const LOGGY_POD: &str = "loggy-pod";
 const INITY_WASI_POD: &str = "hello-wasi-with-inits";
 const FAILY_INITS_POD: &str = "faily-inits-pod";
 const PRIVATE_REGISTRY_POD: &str = "private-registry-pod";
+const READ_ONLY_ROOT_POD: &str = "read-only-root-pod";
+const SHUTDOWN_POD: &str = "shutdown-pod";
 
 async fn create_wasi_pod(
     client: kube::Client,
@@ -383,6 +389,36 @@ async fn create_faily_pod(
     .await
 }
 
+async fn create_read_only_root_pod(
+    client: kube::Client,
+    pods: &Api<Pod>,
+    resource_manager: &mut TestResourceManager,
+) -> anyhow::Result<()> {
+    let pod_name = READ_ONLY_ROOT_POD;
+
+    let containers = vec![WasmerciserContainerSpec::named(pod_name)
+        .with_args(&["write(lit:slats)to(file:/output.txt)"])
+        .read_only_root_filesystem()];
+
+    let volumes = vec![WasmerciserVolumeSpec {
+        volume_name: "root",
+        mount_path: "/",
+        source: WasmerciserVolumeSource::HostPath,
+    }];
+
+    wasmercise_wasi(
+        pod_name,
+        client,
+        pods,
+        vec![],
+        containers,
+        volumes,
+        OnFailure::Accept,
+        resource_manager,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn wasmercise_wasi(
     pod_name: &str,
@@ -524,6 +560,38 @@ async fn test_wasi_node_should_verify() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_deleted_node_is_registered_again() -> anyhow::Result<()> {
+    // Deleting the node disrupts whatever else runs on it, so this is only
+    // done in CI
+    if !in_ci_environment() {
+        return Ok(());
+    }
+
+    let client = kube::Client::try_default().await?;
+    let nodes: Api<Node> = Api::all(client);
+    let labels = nodes.get("krustlet-wasi").await?.metadata.labels;
+    nodes
+        .delete("krustlet-wasi", &kube::api::DeleteParams::default())
+        .await?;
+
+    // The lease is renewed every 10 seconds with the default lease duration,
+    // and the node is registered again as soon as a renewal finds it gone
+    let mut reregistered = None;
+    for _ in 0..20 {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        if let Ok(node) = nodes.get("krustlet-wasi").await {
+            reregistered = Some(node);
+            break;
+        }
+    }
+    let node = reregistered.expect("node was not registered again");
+    assert_eq!(node.metadata.labels, labels);
+    verify_wasi_node(node).await;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_pod_logs_and_mounts() -> anyhow::Result<()> {
     let test_ns = "wasi-e2e-pod-logs-and-mounts";
@@ -684,6 +752,18 @@ async fn test_module_exiting_with_error() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_read_only_root_filesystem() -> anyhow::Result<()> {
+    let test_ns = "wasi-e2e-read-only-root";
+    let (client, pods, mut resource_manager) = set_up_test(test_ns).await?;
+
+    create_read_only_root_pod(client.clone(), &pods, &mut resource_manager).await?;
+    assert::main_container_exited_with_failure(&pods, READ_ONLY_ROOT_POD).await?;
+    assert::pod_log_contains(&pods, READ_ONLY_ROOT_POD, r#"Permission denied"#).await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_init_containers() -> anyhow::Result<()> {
     let test_ns = "wasi-e2e-init-containers";
@@ -751,3 +831,153 @@ async fn test_pull_from_private_registry() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Whether the node is unschedulable and reported as ready
+async fn node_cordoned_and_ready(nodes: &Api<Node>) -> anyhow::Result<(bool, bool)> {
+    let node = nodes.get("krustlet-wasi").await?;
+    let cordoned = node
+        .spec
+        .and_then(|spec| spec.unschedulable)
+        .unwrap_or(false);
+    let ready = node
+        .status
+        .and_then(|status| status.conditions)
+        .unwrap_or_default()
+        .iter()
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True");
+    Ok((cordoned, ready))
+}
+
+// Shutting the node down stops the kubelet, so this is only run on its own,
+// after the rest of the suite, by oneclick, which passes the kubelet's
+// process ID in KRUSTLET_PID.
+#[tokio::test]
+#[ignore]
+async fn test_node_shutdown_drains_pods() -> anyhow::Result<()> {
+    let pid = std::env::var("KRUSTLET_PID").expect("KRUSTLET_PID must be set");
+    let test_ns = "wasi-e2e-node-shutdown";
+    let (client, pods, mut resource_manager) = set_up_test(test_ns).await?;
+    let nodes: Api<Node> = Api::all(client.clone());
+
+    // The preStop hook is sent to an address that never answers, so that it
+    // holds up the pod until its grace period runs out
+    let p = serde_json::from_value(json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": {
+            "name": SHUTDOWN_POD
+        },
+        "spec": {
+            "containers": [
+                {
+                    "name": SHUTDOWN_POD,
+                    "image": "webassembly.azurecr.io/simpleserver:v1.0.0",
+                    "lifecycle": {
+                        "preStop": {
+                            "httpGet": {
+                                "host": "192.0.2.1",
+                                "port": 80,
+                                "path": "/"
+                            }
+                        }
+                    }
+                },
+            ],
+            "terminationGracePeriodSeconds": 10,
+            "tolerations": [
+                {
+                    "effect": "NoExecute",
+                    "key": "kubernetes.io/arch",
+                    "operator": "Equal",
+                    "value": "wasm32-wasi"
+                },
+                {
+                    "effect": "NoSchedule",
+                    "key": "kubernetes.io/arch",
+                    "operator": "Equal",
+                    "value": "wasm32-wasi"
+                },
+            ],
+            "nodeSelector": {
+                "kubernetes.io/arch": "wasm32-wasi"
+            }
+        }
+    }))?;
+    pods.create(&PostParams::default(), &p).await?;
+    resource_manager.push(TestResource::Pod(SHUTDOWN_POD.to_owned()));
+
+    let mut running = false;
+    for _ in 0..30 {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        let phase = pods
+            .get(SHUTDOWN_POD)
+            .await?
+            .status
+            .and_then(|status| status.phase);
+        if phase.as_deref() == Some("Running") {
+            running = true;
+            break;
+        }
+    }
+    assert!(running, "pod {} never started running", SHUTDOWN_POD);
+
+    let killed = std::process::Command::new("kill")
+        .args(&["-TERM", &pid])
+        .status()?;
+    assert!(killed.success(), "unable to send SIGTERM to the kubelet");
+
+    // The node is cordoned before the pod is stopped, stays ready while the
+    // pod's preStop hook runs, and is only reported as not ready once the
+    // pod is gone
+    let mut cordoned_while_pod_ran = false;
+    let mut last_reason = None;
+    let mut pod_gone = false;
+    for _ in 0..60 {
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        let (cordoned, ready) = node_cordoned_and_ready(&nodes).await?;
+        match pods.get(SHUTDOWN_POD).await {
+            Ok(pod) => {
+                assert!(
+                    ready,
+                    "node was reported as not ready before its pods stopped"
+                );
+                cordoned_while_pod_ran |= cordoned;
+                last_reason = pod.status.and_then(|status| status.reason).or(last_reason);
+            }
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                pod_gone = true;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    assert!(
+        cordoned_while_pod_ran,
+        "node was not cordoned before its pods stopped"
+    );
+    assert!(pod_gone, "pod {} was not evicted", SHUTDOWN_POD);
+    assert_eq!(last_reason.as_deref(), Some("NodeShutdown"));
+
+    let mut not_ready = false;
+    for _ in 0..20 {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        if !node_cordoned_and_ready(&nodes).await?.1 {
+            not_ready = true;
+            break;
+        }
+    }
+    assert!(not_ready, "node was not reported as not ready");
+
+    // The node stays cordoned, which would keep pods off it when the kubelet
+    // is next started
+    let uncordon = json!({ "spec": { "unschedulable": false } });
+    nodes
+        .patch(
+            "krustlet-wasi",
+            &kube::api::PatchParams::default(),
+            &kube::api::Patch::Strategic(uncordon),
+        )
+        .await?;
+
+    Ok(())
+}
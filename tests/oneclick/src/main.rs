@@ -436,7 +436,9 @@ fn run_tests(readiness: BootstrapReadiness) -> anyhow::Result<()> {
         }
     };
 
-    let test_result = run_test_suite(&mut wasi_process);
+    // The shutdown test stops the kubelet, so it runs after the rest
+    let test_result =
+        run_test_suite(&mut wasi_process).and_then(|_| run_shutdown_test(&mut wasi_process));
 
     if matches!(test_result, Err(_)) {
         warn_if_premature_exit(&mut wasi_process, "krustlet-wasi");
@@ -513,6 +515,40 @@ fn run_test_suite(krustlet_process: &mut OwnedChildProcess) -> anyhow::Result<()
     }
 }
 
+fn run_shutdown_test(krustlet_process: &mut OwnedChildProcess) -> anyhow::Result<()> {
+    println!("Launching node shutdown test");
+    let stdout = std::fs::File::create(Path::new(LOG_DIR).join("shutdown_test.stdout"))?;
+    let stderr = std::fs::File::create(Path::new(LOG_DIR).join("shutdown_test.stderr"))?;
+
+    let result = std::process::Command::new("cargo")
+        .args(&[
+            "test",
+            "--test",
+            "integration_tests",
+            "--",
+            "--ignored",
+            "test_node_shutdown_drains_pods",
+        ])
+        .env("KRUSTLET_PID", krustlet_process.child.id().to_string())
+        .stdout(stdout)
+        .stderr(stderr)
+        .status()?;
+    if !result.success() {
+        println!("Node shutdown test FAILED");
+        anyhow::bail!("Node shutdown test FAILED");
+    }
+    println!("Node shutdown test PASSED");
+    // The kubelet exits once the node has shut down
+    let start = std::time::Instant::now();
+    while !krustlet_process.exited()? {
+        if start.elapsed().as_secs() > 60 {
+            anyhow::bail!("Krustlet did not exit after shutting down");
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+    Ok(())
+}
+
 fn capture_kubelet_logs(
     kubelet_name: &str,
     kubelet_process: &mut std::process::Child,
@@ -11,6 +11,7 @@ pub struct WasmerciserContainerSpec {
     name: &'static str,
     args: &'static [&'static str],
     use_private_registry: bool,
+    read_only_root_filesystem: bool,
 }
 
 impl WasmerciserContainerSpec {
@@ -19,6 +20,7 @@ impl WasmerciserContainerSpec {
             name,
             args: &[],
             use_private_registry: false,
+            read_only_root_filesystem: false,
         }
     }
 
@@ -31,6 +33,11 @@ impl WasmerciserContainerSpec {
         self.use_private_registry = true;
         self
     }
+
+    pub fn read_only_root_filesystem(mut self) -> Self {
+        self.read_only_root_filesystem = true;
+        self
+    }
 }
 
 pub struct WasmerciserVolumeSpec {
@@ -68,6 +75,9 @@ fn wasmerciser_container(
         "image": format!("{}.azurecr.io/wasmerciser:v0.2.0", registry),
         "args": spec.args,
         "volumeMounts": volume_mounts,
+        "securityContext": {
+            "readOnlyRootFilesystem": spec.read_only_root_filesystem,
+        },
     }))?;
     Ok(container)
 }